@@ -0,0 +1,109 @@
+//! Measures worker-stats ingestion throughput through `stats::put`.
+//!
+//! The coordinator has to sustain 10k events/sec under a single mutex,
+//! so the interesting number is the headroom over that target. For
+//! comparison, the same events are first applied the way they were
+//! before applying stopped cloning: each event and its worker's current
+//! state cloned on every apply.
+//!
+//! Run with `cargo bench --bench ingest`.
+
+use std::{hint::black_box, time::Instant};
+
+use snark_coordinator_rs::stats::{self, SnarkWorkerStatsPut, WorkerStats};
+
+const WORKERS: usize = 1_000;
+const JOBS_PER_WORKER: usize = 250;
+const TARGET_EVENTS_PER_SEC: f64 = 10_000.0;
+
+fn lifecycle(worker: usize, job: usize, time: u64) -> [SnarkWorkerStatsPut; 4] {
+    let ids = format!("{worker}-{job}");
+    [
+        SnarkWorkerStatsPut::JobGetInit { time, seq: None },
+        SnarkWorkerStatsPut::JobGetSuccess {
+            time: time + 1,
+            job_get_node_received_t: Some(time),
+            job_get_node_request_work_init_t: Some(time),
+            job_get_node_request_work_success_t: Some(time + 1),
+            ids: ids.clone(),
+            seq: None,
+        },
+        SnarkWorkerStatsPut::WorkCreateSuccess {
+            time: time + 2,
+            ids: ids.clone(),
+            seq: None,
+        },
+        SnarkWorkerStatsPut::WorkSubmitSuccess {
+            time: time + 3,
+            work_submit_node_received_t: Some(time + 3),
+            work_submit_node_add_work_init_t: Some(time + 3),
+            work_submit_node_add_work_success_t: Some(time + 3),
+            ids,
+            seq: None,
+        },
+    ]
+}
+
+/// Registers the workers and returns them with their interleaved events.
+fn events() -> (WorkerStats, Vec<(String, SnarkWorkerStatsPut)>) {
+    let mut stats = WorkerStats::new();
+    let worker_ids = (0..WORKERS)
+        .map(|_| {
+            stats::put(
+                &mut stats,
+                "bench".to_owned(),
+                SnarkWorkerStatsPut::Register {
+                    time: 0,
+                    metadata: None,
+                    resume: None,
+                    seq: None,
+                },
+            )
+        })
+        .collect::<Result<Vec<_>, _>>()
+        .unwrap();
+
+    // interleave workers so consecutive events hit different deques.
+    let mut events = Vec::with_capacity(WORKERS * JOBS_PER_WORKER * 4);
+    for job in 0..JOBS_PER_WORKER {
+        let time = job as u64 * 4;
+        let lifecycles = worker_ids
+            .iter()
+            .enumerate()
+            .map(|(worker, id)| (id, lifecycle(worker, job, time)))
+            .collect::<Vec<_>>();
+        for step in 0..4 {
+            for (id, lifecycle) in &lifecycles {
+                events.push((id.to_string(), lifecycle[step].clone()));
+            }
+        }
+    }
+    (stats, events)
+}
+
+/// Applies all events, returning the rate of events/sec.
+fn run(cloning: bool) -> f64 {
+    let (mut stats, events) = events();
+    let total = events.len();
+    let started = Instant::now();
+    for (worker_id, event) in events {
+        if cloning {
+            black_box(stats.latest(&worker_id).cloned());
+            black_box(event.clone());
+        }
+        stats::put(&mut stats, worker_id, event).unwrap();
+    }
+    total as f64 / started.elapsed().as_secs_f64()
+}
+
+fn main() {
+    let before = run(true);
+    let after = run(false);
+    let total = WORKERS * JOBS_PER_WORKER * 4;
+    println!("ingest, cloning: {total} events ({before:.0} events/sec)");
+    println!(
+        "ingest: {total} events ({after:.0} events/sec, {:.2}x cloning, {:.1}x the {TARGET_EVENTS_PER_SEC} events/sec target)",
+        after / before,
+        after / TARGET_EVENTS_PER_SEC,
+    );
+}
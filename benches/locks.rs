@@ -0,0 +1,75 @@
+//! Measures lock-job throughput through [`LockShards`] with the table in
+//! a single shard, as before it was sharded, and in several.
+//!
+//! Concurrent tasks each lock and release their own jobs, yielding while
+//! holding the shard as handlers do when mirroring a shared backend,
+//! while a reader snapshots the whole table as `GET /state/snapshot` and
+//! the reports do.
+//!
+//! Run with `cargo bench --bench locks`.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use snark_coordinator_rs::lock::{JobLock, LockShards, LockTable};
+
+const TASKS: usize = 64;
+const LOCKS_PER_TASK: usize = 2_000;
+const SHARDS: [usize; 3] = [1, 16, 64];
+const SNAPSHOT_INTERVAL: Duration = Duration::from_millis(5);
+
+async fn run(shards: usize) -> f64 {
+    let table = Arc::new(LockShards::new(LockTable::new(10_000), shards));
+    let reader = {
+        let table = table.clone();
+        tokio::spawn(async move {
+            loop {
+                tokio::time::sleep(SNAPSHOT_INTERVAL).await;
+                let snapshot = table.lock_all().await.snapshot(Instant::now());
+                std::hint::black_box(snapshot);
+            }
+        })
+    };
+
+    let started = Instant::now();
+    let tasks = (0..TASKS)
+        .map(|task| {
+            let table = table.clone();
+            tokio::spawn(async move {
+                for job in 0..LOCKS_PER_TASK {
+                    let key = format!("{task}-{job}");
+                    let now = Instant::now();
+                    let lock = JobLock::new(now + Duration::from_secs(60), None);
+                    let mut kv = table.lock_key(&key).await;
+                    kv.try_acquire_all(vec![key.clone()], lock, now).unwrap();
+                    tokio::task::yield_now().await;
+                    drop(kv);
+                    table.lock_key(&key).await.release(&key, None);
+                }
+            })
+        })
+        .collect::<Vec<_>>();
+    for task in tasks {
+        task.await.unwrap();
+    }
+    let elapsed = started.elapsed();
+    reader.abort();
+
+    (TASKS * LOCKS_PER_TASK) as f64 / elapsed.as_secs_f64()
+}
+
+#[tokio::main]
+async fn main() {
+    let mut baseline = None;
+    for shards in SHARDS {
+        let rate = run(shards).await;
+        let baseline = *baseline.get_or_insert(rate);
+        println!(
+            "locks: {shards} shard(s), {} locks ({rate:.0} locks/sec, {:.1}x a single shard)",
+            TASKS * LOCKS_PER_TASK,
+            rate / baseline,
+        );
+    }
+}
@@ -0,0 +1,186 @@
+//! Measures worker-stats scans against the ingest applier sharing one
+//! `RwLock<WorkerStats>`, as reports, summaries and exports do.
+//!
+//! Readers repeatedly summarize the whole fleet while a single writer
+//! applies events, as the ingest queue's applier does. Readers take the
+//! lock shared, and for comparison exclusively, as they did behind a
+//! `Mutex`. The interesting numbers are the scans/sec readers get, the
+//! events/sec the applier keeps up and the longest the applier waited
+//! for the lock, which a full scan bounds whether the lock is shared or
+//! not.
+//!
+//! Run with `cargo bench --bench stats`.
+
+use std::{
+    hint::black_box,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    thread,
+    time::{Duration, Instant},
+};
+
+use snark_coordinator_rs::{
+    stats::{self, SnarkWorkerStatsPut, WorkerStats},
+    summary,
+};
+use tokio::sync::RwLock;
+
+const WORKERS: usize = 500;
+const JOBS_PER_WORKER: usize = 200;
+const READERS: usize = 4;
+const EVENTS: usize = 200_000;
+
+fn lifecycle(worker: usize, job: usize, time: u64) -> [SnarkWorkerStatsPut; 4] {
+    let ids = format!("{worker}-{job}");
+    [
+        SnarkWorkerStatsPut::JobGetInit { time, seq: None },
+        SnarkWorkerStatsPut::JobGetSuccess {
+            time: time + 1,
+            job_get_node_received_t: Some(time),
+            job_get_node_request_work_init_t: Some(time),
+            job_get_node_request_work_success_t: Some(time + 1),
+            ids: ids.clone(),
+            seq: None,
+        },
+        SnarkWorkerStatsPut::WorkCreateSuccess {
+            time: time + 2,
+            ids: ids.clone(),
+            seq: None,
+        },
+        SnarkWorkerStatsPut::WorkSubmitSuccess {
+            time: time + 3,
+            work_submit_node_received_t: Some(time + 3),
+            work_submit_node_add_work_init_t: Some(time + 3),
+            work_submit_node_add_work_success_t: Some(time + 3),
+            ids,
+            seq: None,
+        },
+    ]
+}
+
+/// Registers the workers, completes `JOBS_PER_WORKER` jobs for each and
+/// returns them with `EVENTS` more of their events, interleaved.
+fn fleet() -> (WorkerStats, Vec<(String, SnarkWorkerStatsPut)>) {
+    let mut stats = WorkerStats::new();
+    let worker_ids = (0..WORKERS)
+        .map(|_| {
+            stats::put(
+                &mut stats,
+                "bench".to_owned(),
+                SnarkWorkerStatsPut::Register {
+                    time: 0,
+                    metadata: None,
+                    resume: None,
+                    seq: None,
+                },
+            )
+            .unwrap()
+        })
+        .collect::<Vec<_>>();
+
+    let mut events = Vec::with_capacity(WORKERS * JOBS_PER_WORKER * 4 + EVENTS);
+    for job in 0.. {
+        let time = job as u64 * 4;
+        for step in 0..4 {
+            for (worker, id) in worker_ids.iter().enumerate() {
+                let event = lifecycle(worker, job, time)[step].clone();
+                events.push((id.to_string(), event));
+            }
+        }
+        if events.len() >= WORKERS * JOBS_PER_WORKER * 4 + EVENTS {
+            break;
+        }
+    }
+    let pending = events.split_off(WORKERS * JOBS_PER_WORKER * 4);
+    for (worker_id, event) in events {
+        stats::put(&mut stats, worker_id, event).unwrap();
+    }
+    (stats, pending)
+}
+
+fn scan(stats: &WorkerStats) {
+    black_box(summary::summarize(
+        stats.iter(),
+        |id| stats.evicted(id),
+        |id| stats.seq(id),
+    ));
+}
+
+struct Run {
+    scans_per_sec: f64,
+    events_per_sec: f64,
+    max_write_wait: Duration,
+}
+
+fn run(shared: bool) -> Run {
+    let (stats, events) = fleet();
+    let stats = Arc::new(RwLock::new(stats));
+    let done = Arc::new(AtomicBool::new(false));
+
+    let started = Instant::now();
+    let readers = (0..READERS)
+        .map(|_| {
+            let stats = stats.clone();
+            let done = done.clone();
+            thread::spawn(move || {
+                let mut scans = 0;
+                while !done.load(Ordering::Relaxed) {
+                    match shared {
+                        true => scan(&stats.blocking_read()),
+                        false => scan(&stats.blocking_write()),
+                    }
+                    scans += 1;
+                }
+                scans
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut max_write_wait = Duration::ZERO;
+    for (worker_id, event) in events {
+        let waiting = Instant::now();
+        let mut stats = stats.blocking_write();
+        max_write_wait = max_write_wait.max(waiting.elapsed());
+        stats::put(&mut stats, worker_id, event).unwrap();
+    }
+    let elapsed = started.elapsed();
+    done.store(true, Ordering::Relaxed);
+    let scans = readers
+        .into_iter()
+        .map(|r| r.join().unwrap())
+        .sum::<usize>();
+
+    Run {
+        scans_per_sec: scans as f64 / elapsed.as_secs_f64(),
+        events_per_sec: EVENTS as f64 / elapsed.as_secs_f64(),
+        max_write_wait,
+    }
+}
+
+fn main() {
+    let (stats, _) = fleet();
+    let started = Instant::now();
+    scan(&stats);
+    println!(
+        "stats: one scan of {WORKERS} workers, {} states ({:.1?})",
+        stats.total_states(),
+        started.elapsed(),
+    );
+
+    let exclusive = run(false);
+    let shared = run(true);
+    for (name, run) in [("exclusive", &exclusive), ("shared", &shared)] {
+        println!(
+            "stats, {name} reads: {READERS} readers ({:.0} scans/sec), {EVENTS} events ({:.0} events/sec, waited at most {:.1?})",
+            run.scans_per_sec, run.events_per_sec, run.max_write_wait,
+        );
+    }
+    println!(
+        "stats: shared reads {:.2}x the scans/sec, {:.2}x the events/sec of exclusive ones ({} CPUs)",
+        shared.scans_per_sec / exclusive.scans_per_sec,
+        shared.events_per_sec / exclusive.events_per_sec,
+        thread::available_parallelism().map_or(1, |n| n.get()),
+    );
+}
@@ -0,0 +1,7 @@
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    // the vendored protoc, so building doesn't need one installed.
+    std::env::set_var("PROTOC", protoc_bin_vendored::protoc_bin_path()?);
+    // the client is only used by the round-trip tests.
+    tonic_build::configure().compile_protos(&["proto/coordinator.proto"], &["proto"])?;
+    Ok(())
+}
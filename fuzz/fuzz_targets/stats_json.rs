@@ -0,0 +1,35 @@
+//! Feeds arbitrary bodies to the JSON parsing of worker-facing endpoints:
+//! whatever a worker sends, parsing has to fail cleanly rather than
+//! panic, and whatever parses has to survive a round-trip.
+//!
+//! Run with `cargo +nightly fuzz run stats_json` from the repo root.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use serde::{de::DeserializeOwned, Serialize};
+use snark_coordinator_rs::{
+    handshake::HandshakeRequest,
+    stats::{SnarkWorkerState, SnarkWorkerStatsPut},
+};
+
+/// Parses `data` as `T`, and if it parses, checks what it serializes to
+/// parses too. Not compared, as timestamps in seconds come out as
+/// milliseconds, see `timestamp::normalize`.
+fn check<T: Serialize + DeserializeOwned>(data: &[u8]) {
+    let Ok(value) = serde_json::from_slice::<T>(data) else {
+        return;
+    };
+    let json = serde_json::to_vec(&value).unwrap();
+    serde_json::from_slice::<T>(&json).unwrap();
+}
+
+fuzz_target!(|data: &[u8]| {
+    // `PUT /worker-stats/{id}` and its batch variant.
+    check::<SnarkWorkerStatsPut>(data);
+    check::<Vec<serde_json::Value>>(data);
+    // `POST /handshake`.
+    check::<HandshakeRequest>(data);
+    // histories loaded from snapshots and journals.
+    check::<Vec<SnarkWorkerState>>(data);
+});
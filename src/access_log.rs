@@ -0,0 +1,276 @@
+//! Access log: one line per request with `--access-log`, in the common or
+//! combined log format or as JSON lines.
+//!
+//! A line is written once the response body has been sent (or the client
+//! went away), so the latency and byte counts cover the whole transfer.
+//! Lines are handed to a task writing them, so requests never wait on the
+//! log's I/O.
+
+use std::{io, net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+
+use chrono::DateTime;
+use futures_util::TryStreamExt;
+use serde::Serialize;
+use snark_coordinator_rs::timestamp;
+use tokio::{
+    fs::OpenOptions,
+    io::{AsyncWrite, AsyncWriteExt, BufWriter},
+    sync::mpsc,
+};
+use tracing::warn;
+use warp::hyper::{
+    header::{CONTENT_LENGTH, REFERER, USER_AGENT},
+    Body, HeaderMap, Request, Response, StatusCode,
+};
+
+use crate::throttle;
+
+/// Line format of `--access-log-format`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum AccessLogFormat {
+    Common,
+    #[default]
+    Combined,
+    Json,
+}
+
+impl FromStr for AccessLogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "common" => Ok(Self::Common),
+            "combined" => Ok(Self::Combined),
+            "json" => Ok(Self::Json),
+            _ => Err(format!(
+                "unknown access log format `{s}`, expected common, combined or json"
+            )),
+        }
+    }
+}
+
+pub struct AccessLog {
+    format: AccessLogFormat,
+    tx: mpsc::UnboundedSender<String>,
+}
+
+impl AccessLog {
+    /// Opens `path` for appending; `-` logs to stdout.
+    pub async fn open(path: &str, format: AccessLogFormat) -> io::Result<Self> {
+        let sink: Box<dyn AsyncWrite + Send + Unpin> = if path == "-" {
+            Box::new(tokio::io::stdout())
+        } else {
+            Box::new(
+                OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)
+                    .await?,
+            )
+        };
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write(BufWriter::new(sink), rx));
+        Ok(Self { format, tx })
+    }
+
+    /// Captures what is logged of `req`, once its path is rewritten to the
+    /// one routed. `target` is the path and query as requested.
+    pub fn begin(
+        self: &Arc<Self>,
+        req: &Request<Body>,
+        target: String,
+        remote_addr: Option<SocketAddr>,
+    ) -> Entry {
+        let headers = req.headers();
+        let uri = req.uri();
+        let path = uri.path();
+        let worker_id = throttle::worker_id(
+            path,
+            uri.query(),
+            headers
+                .get("x-worker-id")
+                .and_then(|v| v.to_str().ok())
+                .map(str::to_owned),
+        );
+        let key = path
+            .strip_prefix("/lock-job/")
+            .and_then(|rest| rest.split('/').next())
+            .filter(|key| !key.is_empty())
+            .map(str::to_owned);
+        Entry {
+            log: self.clone(),
+            started: Instant::now(),
+            time: timestamp::now(),
+            remote_addr,
+            method: req.method().to_string(),
+            target,
+            version: format!("{:?}", req.version()),
+            worker_id,
+            key,
+            user_agent: header(headers, USER_AGENT.as_str()),
+            referer: header(headers, REFERER.as_str()),
+            bytes_in: content_length(headers),
+            // Logged for requests dropped before a response, as nginx does.
+            status: StatusCode::from_u16(499).unwrap(),
+            bytes_out: 0,
+        }
+    }
+
+    fn write(&self, line: String) {
+        let _ = self.tx.send(line);
+    }
+}
+
+/// Writes lines as they come, flushing whenever none are queued.
+async fn write<W: AsyncWrite + Unpin>(
+    mut sink: BufWriter<W>,
+    mut rx: mpsc::UnboundedReceiver<String>,
+) {
+    while let Some(line) = rx.recv().await {
+        let mut next = Some(line);
+        while let Some(mut line) = next {
+            line.push('\n');
+            if let Err(err) = sink.write_all(line.as_bytes()).await {
+                warn!(%err, "failed to write access log");
+            }
+            next = rx.try_recv().ok();
+        }
+        if let Err(err) = sink.flush().await {
+            warn!(%err, "failed to flush access log");
+        }
+    }
+}
+
+fn header(headers: &HeaderMap, name: &str) -> Option<String> {
+    headers
+        .get(name)
+        .and_then(|v| v.to_str().ok())
+        .map(str::to_owned)
+}
+
+fn content_length(headers: &HeaderMap) -> Option<u64> {
+    headers
+        .get(CONTENT_LENGTH)
+        .and_then(|v| v.to_str().ok())
+        .and_then(|v| v.parse().ok())
+}
+
+/// A request in flight. The line is written when it is dropped.
+pub struct Entry {
+    log: Arc<AccessLog>,
+    started: Instant,
+    time: u64,
+    remote_addr: Option<SocketAddr>,
+    method: String,
+    target: String,
+    version: String,
+    worker_id: Option<String>,
+    key: Option<String>,
+    user_agent: Option<String>,
+    referer: Option<String>,
+    /// From the request's `Content-Length`, the body is read by the routes.
+    bytes_in: Option<u64>,
+    status: StatusCode,
+    bytes_out: u64,
+}
+
+impl Entry {
+    /// Records the status of `res` and counts its body as it is sent.
+    /// Upgrades are logged right away since their connection is handed
+    /// off.
+    pub fn finish(mut self, res: Response<Body>) -> Response<Body> {
+        self.status = res.status();
+        if self.status == StatusCode::SWITCHING_PROTOCOLS {
+            return res;
+        }
+        let (parts, body) = res.into_parts();
+        let body = body.map_ok(move |chunk| {
+            self.sent(chunk.len());
+            chunk
+        });
+        Response::from_parts(parts, Body::wrap_stream(body))
+    }
+
+    fn sent(&mut self, bytes: usize) {
+        self.bytes_out += bytes as u64;
+    }
+
+    fn line(&self) -> String {
+        let latency_ms = self.started.elapsed().as_secs_f64() * 1000.0;
+        let remote_addr = self.remote_addr.map(|addr| addr.ip().to_string());
+        match self.log.format {
+            AccessLogFormat::Json => {
+                let line = JsonLine {
+                    time: self.time,
+                    remote_addr: remote_addr.as_deref(),
+                    method: &self.method,
+                    target: &self.target,
+                    version: &self.version,
+                    status: self.status.as_u16(),
+                    latency_ms,
+                    bytes_in: self.bytes_in,
+                    bytes_out: self.bytes_out,
+                    worker_id: self.worker_id.as_deref(),
+                    key: self.key.as_deref(),
+                    user_agent: self.user_agent.as_deref(),
+                    referer: self.referer.as_deref(),
+                };
+                serde_json::to_string(&line).unwrap()
+            }
+            format => {
+                let time = DateTime::from_timestamp_millis(self.time as i64)
+                    .unwrap_or_default()
+                    .format("%d/%b/%Y:%H:%M:%S +0000");
+                let mut line = format!(
+                    "{} - {} [{time}] \"{} {} {}\" {} {}",
+                    remote_addr.as_deref().unwrap_or("-"),
+                    self.worker_id.as_deref().unwrap_or("-"),
+                    self.method,
+                    self.target,
+                    self.version,
+                    self.status.as_u16(),
+                    self.bytes_out,
+                );
+                if format == AccessLogFormat::Combined {
+                    line += &format!(
+                        " \"{}\" \"{}\"",
+                        self.referer.as_deref().unwrap_or("-"),
+                        self.user_agent.as_deref().unwrap_or("-"),
+                    );
+                }
+                // Extensions past the standard fields, as nginx and Apache
+                // custom formats do.
+                line += &format!(
+                    " {latency_ms:.3} {} {}",
+                    self.bytes_in
+                        .map_or_else(|| "-".to_owned(), |n| n.to_string()),
+                    self.key.as_deref().unwrap_or("-"),
+                );
+                line
+            }
+        }
+    }
+}
+
+impl Drop for Entry {
+    fn drop(&mut self) {
+        self.log.write(self.line());
+    }
+}
+
+#[derive(Serialize)]
+struct JsonLine<'a> {
+    time: u64,
+    remote_addr: Option<&'a str>,
+    method: &'a str,
+    target: &'a str,
+    version: &'a str,
+    status: u16,
+    latency_ms: f64,
+    bytes_in: Option<u64>,
+    bytes_out: u64,
+    worker_id: Option<&'a str>,
+    key: Option<&'a str>,
+    user_agent: Option<&'a str>,
+    referer: Option<&'a str>,
+}
@@ -0,0 +1,301 @@
+//! Subcommands for routine operations on a running coordinator over its
+//! API: listing workers, their states and the held locks, and removing a
+//! worker or a lock. Output is a line per entry, or the response bodies
+//! with `--json`.
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client, Method, RequestBuilder,
+};
+use serde::{de::DeserializeOwned, Deserialize};
+use snark_coordinator_rs::{
+    liveness::WorkerLiveness,
+    lock_namespaces,
+    stats::{Lease, SnarkWorkerState},
+    timestamp,
+};
+use structopt::StructOpt;
+
+// coordinator a subcommand talks to. Not a doc comment, which would
+// replace the about of the subcommands flattening it.
+#[derive(Debug, StructOpt)]
+pub struct Api {
+    #[structopt(long, default_value = "http://localhost:8080")]
+    url: String,
+    /// API key sent as `Authorization: Bearer <key>`.
+    #[structopt(long)]
+    api_key: Option<String>,
+}
+
+#[derive(Debug, StructOpt)]
+pub enum AdminCommand {
+    /// List the workers with their liveness.
+    Workers {
+        #[structopt(flatten)]
+        api: Api,
+        /// Print the response body as is.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Show the states of a worker, most recent first.
+    Stats {
+        #[structopt(flatten)]
+        api: Api,
+        #[structopt(long)]
+        worker: String,
+        /// Only states since then, a duration ago like `30m`, `2h` or
+        /// `1d`, or a timestamp.
+        #[structopt(long)]
+        since: Option<Since>,
+        /// Comma separated state kinds, e.g. `JobGetError,WorkCreateError`.
+        #[structopt(long)]
+        kinds: Option<String>,
+        /// Print the response body as is.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// List the held locks.
+    Locks {
+        #[structopt(flatten)]
+        api: Api,
+        /// Only locks in this namespace.
+        #[structopt(long)]
+        namespace: Option<String>,
+        /// Print the response body as is.
+        #[structopt(long)]
+        json: bool,
+    },
+    /// Remove a worker and its states, e.g. one which was decommissioned.
+    PurgeWorker {
+        #[structopt(flatten)]
+        api: Api,
+        worker_id: String,
+        /// Archive the worker's history instead of dropping it.
+        #[structopt(long)]
+        archive: bool,
+    },
+    /// Release a lock whoever holds it, e.g. one of a crashed worker.
+    /// Needs an admin key, or `--url` of the `--admin-port`.
+    DropLock {
+        #[structopt(flatten)]
+        api: Api,
+        key: String,
+        #[structopt(long)]
+        namespace: Option<String>,
+    },
+}
+
+/// Start of `--since`, in milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct Since(u64);
+
+impl FromStr for Since {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(t) = s.parse::<u64>() {
+            return Ok(Self(timestamp::normalize(t)));
+        }
+        let unit = match s.chars().last() {
+            Some('s') => 1000,
+            Some('m') => 60 * 1000,
+            Some('h') => 3600 * 1000,
+            Some('d') => 24 * 3600 * 1000,
+            _ => return Err(format!("invalid since {s:?}, expected e.g. `30m` or `2h`")),
+        };
+        let n = s[..s.len() - 1]
+            .parse::<u64>()
+            .map_err(|_| format!("invalid since {s:?}, expected e.g. `30m` or `2h`"))?;
+        Ok(Self(timestamp::now().saturating_sub(n * unit)))
+    }
+}
+
+/// Entry of `GET /workers?liveness=true&metadata=true`.
+#[derive(Deserialize)]
+struct WorkerInfo {
+    worker_id: String,
+    #[serde(flatten)]
+    liveness: Option<WorkerLiveness>,
+    name: Option<String>,
+}
+
+struct Admin {
+    client: Client,
+    url: String,
+}
+
+impl Admin {
+    fn new(api: Api) -> Result<Self, String> {
+        let mut headers = HeaderMap::new();
+        if let Some(key) = api.api_key {
+            let value = HeaderValue::from_str(&format!("Bearer {key}"))
+                .map_err(|err| format!("invalid api key: {err}"))?;
+            headers.insert(AUTHORIZATION, value);
+        }
+        let client = Client::builder()
+            .default_headers(headers)
+            .build()
+            .map_err(|err| format!("failed to build http client: {err}"))?;
+        Ok(Self {
+            client,
+            url: api.url.trim_end_matches('/').to_owned(),
+        })
+    }
+
+    fn request(&self, method: Method, path: &str) -> RequestBuilder {
+        self.client
+            .request(method, format!("{}/v1{path}", self.url))
+    }
+
+    /// Sends `req`, returning the body of a successful response.
+    async fn send(&self, req: RequestBuilder) -> Result<String, String> {
+        let res = req.send().await.map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            // coded errors, only their message is of interest.
+            let msg = serde_json::from_str::<serde_json::Value>(&body)
+                .ok()
+                .and_then(|v| Some(v.get("message")?.as_str()?.to_owned()))
+                .unwrap_or(body);
+            return Err(format!("{status}: {msg}"));
+        }
+        Ok(body)
+    }
+
+    async fn get<T: DeserializeOwned>(&self, path: &str, json: bool) -> Result<Option<T>, String> {
+        let body = self.send(self.request(Method::GET, path)).await?;
+        if json {
+            println!("{body}");
+            return Ok(None);
+        }
+        serde_json::from_str(&body).map_err(|err| err.to_string())
+    }
+}
+
+/// Runs `cmd`, printing its output or error. Returns `true` on success.
+pub async fn run(cmd: AdminCommand) -> bool {
+    match dispatch(cmd).await {
+        Ok(()) => true,
+        Err(err) => {
+            eprintln!("{err}");
+            false
+        }
+    }
+}
+
+async fn dispatch(cmd: AdminCommand) -> Result<(), String> {
+    match cmd {
+        AdminCommand::Workers { api, json } => workers(Admin::new(api)?, json).await,
+        AdminCommand::Stats {
+            api,
+            worker,
+            since,
+            kinds,
+            json,
+        } => stats(Admin::new(api)?, &worker, since, kinds, json).await,
+        AdminCommand::Locks {
+            api,
+            namespace,
+            json,
+        } => locks(Admin::new(api)?, namespace, json).await,
+        AdminCommand::PurgeWorker {
+            api,
+            worker_id,
+            archive,
+        } => purge_worker(Admin::new(api)?, &worker_id, archive).await,
+        AdminCommand::DropLock {
+            api,
+            key,
+            namespace,
+        } => drop_lock(Admin::new(api)?, &key, namespace).await,
+    }
+}
+
+fn format_t(t: u64) -> String {
+    match chrono::DateTime::from_timestamp_millis(t as i64) {
+        Some(t) => t.to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+        None => t.to_string(),
+    }
+}
+
+async fn workers(admin: Admin, json: bool) -> Result<(), String> {
+    let path = "/workers?liveness=true&metadata=true";
+    let Some(workers) = admin.get::<Vec<WorkerInfo>>(path, json).await? else {
+        return Ok(());
+    };
+    for worker in workers {
+        let (status, last_seen_t) = match &worker.liveness {
+            Some(l) => (format!("{:?}", l.status).to_lowercase(), l.last_seen_t),
+            None => ("unknown".to_owned(), None),
+        };
+        let last_seen = last_seen_t.map_or("-".to_owned(), format_t);
+        let name = worker.name.as_deref().unwrap_or("-");
+        println!(
+            "{:<48} {status:<7} {last_seen:<20} {name}",
+            worker.worker_id
+        );
+    }
+    Ok(())
+}
+
+async fn stats(
+    admin: Admin,
+    worker: &str,
+    since: Option<Since>,
+    kinds: Option<String>,
+    json: bool,
+) -> Result<(), String> {
+    let query =
+        serde_urlencoded::to_string([("from_t", since.map(|s| s.0.to_string())), ("kinds", kinds)])
+            .unwrap();
+    let path = format!("/worker-stats/{worker}?{query}");
+    let Some(states) = admin.get::<Vec<SnarkWorkerState>>(&path, json).await? else {
+        return Ok(());
+    };
+    for state in states {
+        let ids = state.ids().unwrap_or("-");
+        println!(
+            "{:<20} {:<20} {ids}",
+            format_t(state.start_time()),
+            state.kind()
+        );
+    }
+    Ok(())
+}
+
+async fn locks(admin: Admin, namespace: Option<String>, json: bool) -> Result<(), String> {
+    let query = serde_urlencoded::to_string([("namespace", namespace)]).unwrap();
+    let path = format!("/lock-jobs?{query}");
+    let Some(locks) = admin.get::<BTreeMap<String, Lease>>(&path, json).await? else {
+        return Ok(());
+    };
+    for (key, lease) in locks {
+        let holder = lease.holder.as_deref().unwrap_or("-");
+        let expires = format_t(lease.expires_t);
+        println!(
+            "{key:<48} {holder:<32} {expires:<20} {}",
+            lease.fencing_token
+        );
+    }
+    Ok(())
+}
+
+async fn purge_worker(admin: Admin, worker_id: &str, archive: bool) -> Result<(), String> {
+    let path = format!("/worker-stats/{worker_id}?archive={archive}");
+    admin.send(admin.request(Method::DELETE, &path)).await?;
+    println!("purged {worker_id}");
+    Ok(())
+}
+
+async fn drop_lock(admin: Admin, key: &str, namespace: Option<String>) -> Result<(), String> {
+    let mut req = admin.request(Method::DELETE, &format!("/admin/locks/{key}"));
+    if let Some(namespace) = namespace {
+        req = req.header(lock_namespaces::HEADER, namespace);
+    }
+    admin.send(req).await?;
+    println!("dropped {key}");
+    Ok(())
+}
@@ -0,0 +1,35 @@
+//! `--admin-port`: the admin, snapshot and metrics endpoints are served
+//! on a second port instead of the worker-facing one, so they can be
+//! firewalled on their own.
+
+use warp::{path::FullPath, Filter, Rejection};
+
+/// Top-level routes only served on the admin port.
+pub const ROUTES: &[&str] = &["admin", "snapshot", "metrics"];
+
+/// Extension of requests received on the admin port.
+#[derive(Debug, Clone, Copy)]
+pub struct AdminListener;
+
+/// Whether the unprefixed `path` is one of [`ROUTES`].
+pub fn is_admin_path(path: &str) -> bool {
+    let route = path.trim_start_matches('/').split('/').next();
+    route.is_some_and(|r| ROUTES.contains(&r))
+}
+
+/// With `enabled`, rejects admin routes as not found on the worker-facing
+/// port, and the other routes on the admin port. Requests made on behalf
+/// of a caller, by JSON-RPC or gRPC, count as worker-facing ones.
+pub fn filter(enabled: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::path::full()
+        .and(warp::ext::optional::<AdminListener>())
+        .and_then(
+            move |path: FullPath, listener: Option<AdminListener>| async move {
+                if enabled && is_admin_path(path.as_str()) != listener.is_some() {
+                    return Err(warp::reject::not_found());
+                }
+                Ok(())
+            },
+        )
+        .untuple_one()
+}
@@ -0,0 +1,92 @@
+//! Operator annotations of fleet events, e.g. deploys or incidents, so
+//! performance changes in reports can be attributed to them.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp;
+
+/// Max number of annotations kept, the oldest are dropped first.
+const MAX_ANNOTATIONS: usize = 10_000;
+
+/// Request body of `POST /annotations`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct AnnotationRequest {
+    /// When the event happened, now if not given.
+    #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+    pub time: Option<u64>,
+    /// End of events which took a while, e.g. an outage.
+    #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+    pub end_t: Option<u64>,
+    pub text: String,
+    #[serde(default)]
+    pub tags: Vec<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Annotation {
+    pub id: u64,
+    pub time: u64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub end_t: Option<u64>,
+    pub text: String,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub tags: Vec<String>,
+    pub created_t: u64,
+}
+
+impl Annotation {
+    /// Whether the event overlaps `from_t..=to_t`.
+    pub fn overlaps(&self, from_t: Option<u64>, to_t: Option<u64>) -> bool {
+        let end_t = self.end_t.unwrap_or(self.time);
+        from_t.is_none_or(|from_t| from_t <= end_t) && to_t.is_none_or(|to_t| self.time <= to_t)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct Annotations {
+    annotations: BTreeMap<u64, Annotation>,
+    last_id: u64,
+}
+
+impl Annotations {
+    pub fn add(&mut self, req: AnnotationRequest) -> Result<&Annotation, String> {
+        if req.text.trim().is_empty() {
+            return Err("annotation needs a text".to_owned());
+        }
+        let now = timestamp::now();
+        let time = req.time.unwrap_or(now);
+        if let Some(end_t) = req.end_t.filter(|end_t| *end_t < time) {
+            return Err(format!("end_t ({end_t}) is before time ({time})"));
+        }
+        if self.annotations.len() >= MAX_ANNOTATIONS {
+            self.annotations.pop_first();
+        }
+        self.last_id += 1;
+        let annotation = Annotation {
+            id: self.last_id,
+            time,
+            end_t: req.end_t,
+            text: req.text,
+            tags: req.tags,
+            created_t: now,
+        };
+        Ok(self.annotations.entry(annotation.id).or_insert(annotation))
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Annotation> {
+        self.annotations.remove(&id)
+    }
+
+    /// Annotations overlapping `from_t..=to_t`, ordered by time.
+    pub fn within(&self, from_t: Option<u64>, to_t: Option<u64>) -> Vec<&Annotation> {
+        let mut annotations = self
+            .annotations
+            .values()
+            .filter(|a| a.overlaps(from_t, to_t))
+            .collect::<Vec<_>>();
+        annotations.sort_by_key(|a| (a.time, a.id));
+        annotations
+    }
+}
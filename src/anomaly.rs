@@ -0,0 +1,271 @@
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{SnarkWorkerJobGetError, SnarkWorkerState};
+
+/// Number of finished anomalies to remember.
+const MAX_HISTORY: usize = 1000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct AnomalyConfig {
+    /// EWMA smoothing factor, in `(0, 1]`.
+    pub alpha: f64,
+    /// Absolute z-score above which a sample is anomalous.
+    pub threshold: f64,
+    /// Samples to collect before flagging anything.
+    pub warmup: u64,
+}
+
+/// Period during which a fleet series deviated from its moving average.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Anomaly {
+    pub series: String,
+    pub start_t: u64,
+    pub end_t: u64,
+    /// Most deviating sample within the period.
+    pub value: f64,
+    /// Moving average at the time of that sample.
+    pub mean: f64,
+    pub z_score: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SeriesStats {
+    pub samples: u64,
+    pub last: f64,
+    pub mean: f64,
+    pub stddev: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FleetAnomalyReport {
+    pub series: BTreeMap<String, SeriesStats>,
+    pub ongoing: Vec<Anomaly>,
+    /// Finished anomalies, most recent first.
+    pub anomalies: Vec<Anomaly>,
+}
+
+/// Events accumulated since the last sample.
+#[derive(Debug, Default)]
+struct Window {
+    successes: u64,
+    errors: u64,
+    job_get_ms: u64,
+    job_gets: u64,
+}
+
+#[derive(Debug, Default)]
+struct Ewma {
+    samples: u64,
+    last: f64,
+    mean: f64,
+    variance: f64,
+}
+
+/// Flags sudden shifts of fleet-wide series: `throughput` (completed
+/// proofs per minute), `error_rate` (share of failed jobs) and
+/// `job_get_latency` (seconds).
+#[derive(Debug)]
+pub struct FleetAnomalyDetector {
+    config: AnomalyConfig,
+    window: Window,
+    series: BTreeMap<&'static str, Ewma>,
+    ongoing: BTreeMap<&'static str, Anomaly>,
+    history: VecDeque<Anomaly>,
+}
+
+impl Ewma {
+    fn stddev(&self) -> f64 {
+        self.variance.sqrt()
+    }
+
+    fn update(&mut self, alpha: f64, x: f64) {
+        if self.samples == 0 {
+            self.mean = x;
+        } else {
+            let diff = x - self.mean;
+            let incr = alpha * diff;
+            self.mean += incr;
+            self.variance = (1.0 - alpha) * (self.variance + diff * incr);
+        }
+        self.samples += 1;
+        self.last = x;
+    }
+}
+
+impl FleetAnomalyDetector {
+    pub fn new(config: AnomalyConfig) -> Self {
+        Self {
+            config,
+            window: Window::default(),
+            series: BTreeMap::new(),
+            ongoing: BTreeMap::new(),
+            history: VecDeque::new(),
+        }
+    }
+
+    /// Feeds a worker state right after a transition into it.
+    pub fn observe(&mut self, state: &SnarkWorkerState) {
+        match state {
+            SnarkWorkerState::WorkSubmitSuccess { .. } => self.window.successes += 1,
+            SnarkWorkerState::JobGetError { error, .. }
+                if !matches!(error, SnarkWorkerJobGetError::NoAvailableJob) =>
+            {
+                self.window.errors += 1
+            }
+            SnarkWorkerState::WorkCreateError { .. } | SnarkWorkerState::WorkSubmitError { .. } => {
+                self.window.errors += 1
+            }
+            _ => {}
+        }
+        if let Some(("job_get", duration_ms)) = state.completed_phase() {
+            self.window.job_get_ms += duration_ms;
+            self.window.job_gets += 1;
+        }
+    }
+
+    /// Closes the current window of `interval_s` seconds ending at `now_t`
+    /// and returns anomalies which started with it.
+    pub fn sample(&mut self, now_t: u64, interval_s: u64) -> Vec<Anomaly> {
+        let window = std::mem::take(&mut self.window);
+        let finished = window.successes + window.errors;
+        let values = [
+            (
+                "throughput",
+                Some(window.successes as f64 * 60.0 / interval_s.max(1) as f64),
+            ),
+            (
+                "error_rate",
+                Some(window.errors as f64 / finished as f64).filter(|_| finished > 0),
+            ),
+            (
+                "job_get_latency",
+                Some(window.job_get_ms as f64 / 1000.0 / window.job_gets as f64)
+                    .filter(|_| window.job_gets > 0),
+            ),
+        ];
+
+        let mut started = Vec::new();
+        for (name, value) in values {
+            let Some(value) = value else {
+                continue;
+            };
+            let series = self.series.entry(name).or_default();
+            let stddev = series.stddev();
+            let z_score = match stddev > f64::EPSILON {
+                true => (value - series.mean) / stddev,
+                false => 0.0,
+            };
+            let is_anomalous =
+                series.samples >= self.config.warmup && z_score.abs() > self.config.threshold;
+            let mean = series.mean;
+            series.update(self.config.alpha, value);
+
+            match (is_anomalous, self.ongoing.remove(name)) {
+                (true, Some(mut anomaly)) => {
+                    anomaly.end_t = now_t;
+                    if z_score.abs() > anomaly.z_score.abs() {
+                        (anomaly.value, anomaly.mean, anomaly.z_score) = (value, mean, z_score);
+                    }
+                    self.ongoing.insert(name, anomaly);
+                }
+                (true, None) => {
+                    let anomaly = Anomaly {
+                        series: name.to_owned(),
+                        start_t: now_t.saturating_sub(interval_s * 1000),
+                        end_t: now_t,
+                        value,
+                        mean,
+                        z_score,
+                    };
+                    started.push(anomaly.clone());
+                    self.ongoing.insert(name, anomaly);
+                }
+                (false, Some(anomaly)) => {
+                    self.history.truncate(MAX_HISTORY - 1);
+                    self.history.push_front(anomaly);
+                }
+                (false, None) => {}
+            }
+        }
+        started
+    }
+
+    pub fn report(&self) -> FleetAnomalyReport {
+        FleetAnomalyReport {
+            series: self
+                .series
+                .iter()
+                .map(|(name, s)| {
+                    let stats = SeriesStats {
+                        samples: s.samples,
+                        last: s.last,
+                        mean: s.mean,
+                        stddev: s.stddev(),
+                    };
+                    (name.to_string(), stats)
+                })
+                .collect(),
+            ongoing: self.ongoing.values().cloned().collect(),
+            anomalies: self.history.iter().cloned().collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn succeeded() -> SnarkWorkerState {
+        serde_json::from_str(
+            r#"{"kind":"WorkSubmitSuccess","job_get_init_t":0,"job_get_success_t":0,
+                "work_create_success_t":0,"work_submit_success_t":0,"ids":"j"}"#,
+        )
+        .unwrap()
+    }
+
+    /// Samples a minute with `successes` completed proofs.
+    fn minute(detector: &mut FleetAnomalyDetector, t: u64, successes: u64) -> Vec<Anomaly> {
+        for _ in 0..successes {
+            detector.observe(&succeeded());
+        }
+        detector.sample(t * 60_000, 60)
+    }
+
+    #[test]
+    fn flags_throughput_shifts_after_warmup() {
+        let mut detector = FleetAnomalyDetector::new(AnomalyConfig {
+            alpha: 0.3,
+            threshold: 3.0,
+            warmup: 5,
+        });
+        // a spike within the warmup isn't flagged.
+        for t in 1..5 {
+            assert!(minute(&mut detector, t, 10 + t % 2 * 2).is_empty());
+        }
+        assert!(minute(&mut detector, 5, 100).is_empty());
+        for t in 6..30 {
+            assert!(minute(&mut detector, t, 10 + t % 2 * 2).is_empty());
+        }
+
+        let started = minute(&mut detector, 30, 100);
+        assert_eq!(started.len(), 1);
+        let anomaly = &started[0];
+        assert_eq!(anomaly.series, "throughput");
+        assert_eq!((anomaly.start_t, anomaly.end_t), (29 * 60_000, 30 * 60_000));
+        assert_eq!(anomaly.value, 100.0);
+        assert!(anomaly.z_score > 3.0 && anomaly.mean < 12.0);
+        assert_eq!(detector.report().ongoing.len(), 1);
+
+        // the anomaly ends once the series doesn't deviate anymore.
+        assert!(minute(&mut detector, 31, 11).is_empty());
+        let report = detector.report();
+        assert!(report.ongoing.is_empty());
+        assert_eq!(report.anomalies.len(), 1);
+        assert_eq!(report.anomalies[0].start_t, 29 * 60_000);
+        // no job failed, so the error rate never deviated.
+        assert_eq!(report.series["error_rate"].stddev, 0.0);
+        assert_eq!(report.series["throughput"].samples, 31);
+    }
+}
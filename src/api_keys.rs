@@ -0,0 +1,100 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::errors::ErrorCode;
+
+/// Keys allowed to call the coordinator, loaded from `--api-keys-file`.
+///
+/// ```json
+/// {
+///   "keys": {
+///     "secret-workers": { "name": "workers-eu" },
+///     "secret-dashboard": { "name": "dashboard", "access": "read" },
+///     "secret-ops": { "name": "ops", "access": "admin" }
+///   }
+/// }
+/// ```
+///
+/// Keys have write access unless stated otherwise. Only admin keys may
+/// call the admin, snapshot and metrics routes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ApiKeys {
+    pub keys: HashMap<String, ApiKey>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ApiKey {
+    /// Who the key was issued to, used in logs.
+    pub name: String,
+    #[serde(default)]
+    pub access: Access,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Access {
+    Read,
+    #[default]
+    Write,
+    Admin,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "error", content = "message", rename_all = "lowercase")]
+pub enum AuthError {
+    /// Missing or unknown key.
+    Unauthorized(String),
+    /// Valid key without the required access.
+    Forbidden(String),
+}
+
+impl AuthError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::Unauthorized(_) => ErrorCode::Unauthorized,
+            Self::Forbidden(_) => ErrorCode::Forbidden,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::Unauthorized(msg) | Self::Forbidden(msg) => msg,
+        }
+    }
+}
+
+impl ApiKeys {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        serde_json::from_str(&s).map_err(|err| format!("{path}: {err}"))
+    }
+
+    /// Checks the `Authorization` header (`Bearer <key>`) of a caller
+    /// which needs `access`.
+    pub fn authorize(
+        &self,
+        authorization: Option<&str>,
+        access: Access,
+    ) -> Result<&ApiKey, AuthError> {
+        let Some(key) = authorization else {
+            return Err(AuthError::Unauthorized("missing api key".to_owned()));
+        };
+        let key = key.strip_prefix("Bearer ").unwrap_or(key).trim();
+        let Some(api_key) = self.keys.get(key) else {
+            return Err(AuthError::Unauthorized("unknown api key".to_owned()));
+        };
+        if api_key.access < access {
+            let access = match access {
+                Access::Read => "read",
+                Access::Write => "write",
+                Access::Admin => "admin",
+            };
+            return Err(AuthError::Forbidden(format!(
+                "api key {:?} has no {access} access",
+                api_key.name
+            )));
+        }
+        Ok(api_key)
+    }
+}
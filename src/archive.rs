@@ -0,0 +1,63 @@
+//! History of deregistered workers, kept when they're removed with
+//! `archive` set, e.g. to still look into why a host was decommissioned.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+/// Request body of `DELETE /worker-stats`.
+#[derive(Deserialize, Debug, Clone)]
+pub struct DeregisterRequest {
+    pub workers: Vec<Deregistration>,
+    /// Archive the workers' history instead of dropping it.
+    #[serde(default)]
+    pub archive: bool,
+}
+
+#[derive(Deserialize, Debug, Clone)]
+pub struct Deregistration {
+    pub worker_id: String,
+    /// Session token of the worker, required with `--worker-tokens`.
+    pub token: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ArchivedWorker {
+    pub worker_id: String,
+    pub removed_t: u64,
+    /// Most recent state first, like in `GET /worker-stats`.
+    pub states: VecDeque<SnarkWorkerState>,
+}
+
+/// Most recently archived workers, oldest ones are dropped first.
+#[derive(Debug)]
+pub struct WorkerArchive {
+    workers: VecDeque<ArchivedWorker>,
+    capacity: usize,
+}
+
+impl WorkerArchive {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            workers: VecDeque::new(),
+            capacity,
+        }
+    }
+
+    pub fn add(&mut self, worker: ArchivedWorker) {
+        if self.workers.len() >= self.capacity {
+            self.workers.pop_front();
+        }
+        self.workers.push_back(worker);
+    }
+
+    /// Archived workers `filter` accepts the id of, oldest first.
+    pub fn list(&self, filter: impl Fn(&str) -> bool) -> Vec<&ArchivedWorker> {
+        self.workers
+            .iter()
+            .filter(|w| filter(&w.worker_id))
+            .collect()
+    }
+}
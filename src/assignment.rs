@@ -0,0 +1,198 @@
+//! Assignment policy loaded from `--assignment-policy`: an expression
+//! scoring how well a worker suits a job, so operators can iterate on
+//! assignment strategies without recompiling the coordinator.
+//!
+//! ```text
+//! if(job_class == "merge", worker_avg_end_to_end_ms < 30000, true) * 10
+//!     + worker_success_rate * 5
+//!     - if(worker_busy, 100, 0)
+//! ```
+//!
+//! Policies are [evalexpr] expressions: arithmetic, comparisons, `if` and
+//! the builtin math and string functions over the variables below. They
+//! can't touch the filesystem or network, loop, or change the variables,
+//! so a policy runs sandboxed and always terminates.
+//!
+//! The coordinator doesn't hand out jobs itself yet, workers still pick
+//! them and lock them with `lock-job`, so for now the policy only ranks
+//! workers for `GET /report/assignment`.
+//!
+//! [evalexpr]: https://docs.rs/evalexpr
+
+use std::collections::{BTreeMap, VecDeque};
+
+use evalexpr::{ContextWithMutableVariables, HashMapContext, Node, Value};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    durations,
+    groups::GroupsConfig,
+    stats::SnarkWorkerState,
+    summary::{self, LifecycleTotals},
+};
+
+/// Variables a policy can use, about the job and the worker scored.
+pub const VARIABLES: &[&str] = &[
+    "job_id",
+    "job_class",
+    "worker_id",
+    "worker_team",
+    "worker_succeeded",
+    "worker_failed",
+    "worker_success_rate",
+    "worker_avg_end_to_end_ms",
+    "worker_busy",
+];
+
+#[derive(Debug, Clone)]
+pub struct AssignmentPolicy {
+    script: String,
+    node: Node,
+}
+
+/// Context a policy scores a (job, worker) pair in.
+#[derive(Debug, Clone)]
+pub struct Candidate<'a> {
+    pub job_id: &'a str,
+    pub job_class: &'a str,
+    pub worker_id: &'a str,
+    /// Team owning the worker in `--groups-file`, empty if none does.
+    pub worker_team: &'a str,
+    pub totals: &'a LifecycleTotals,
+    /// Whether the worker is in the middle of a job lifecycle.
+    pub busy: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ScoredWorker {
+    pub worker_id: String,
+    /// `None` if the policy failed for the worker, see `error`.
+    pub score: Option<f64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Response body of `GET /report/assignment`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct AssignmentReport {
+    pub job_id: String,
+    pub job_class: String,
+    pub policy: String,
+    /// Highest score first, workers the policy failed for last.
+    pub workers: Vec<ScoredWorker>,
+}
+
+impl AssignmentPolicy {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let script = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        Self::parse(script).map_err(|err| format!("{path}: {err}"))
+    }
+
+    /// Compiles `script`, rejecting unknown variables up front rather
+    /// than when it first scores a worker.
+    pub fn parse(script: String) -> Result<Self, String> {
+        let node = evalexpr::build_operator_tree(&script).map_err(|err| err.to_string())?;
+        if let Some(unknown) = node
+            .iter_variable_identifiers()
+            .find(|var| !VARIABLES.contains(var))
+        {
+            let known = VARIABLES.join(", ");
+            return Err(format!(
+                "unknown variable {unknown}, expected one of: {known}"
+            ));
+        }
+        Ok(Self { script, node })
+    }
+
+    /// Score of `candidate`, higher is better suited.
+    pub fn score(&self, candidate: &Candidate) -> Result<f64, String> {
+        let totals = candidate.totals;
+        let failed = totals.failed.job_get + totals.failed.work_create + totals.failed.work_submit;
+        let finished = totals.succeeded + failed;
+        let success_rate = match finished {
+            0 => 1.0,
+            _ => totals.succeeded as f64 / finished as f64,
+        };
+        let mut context = HashMapContext::new();
+        let variables = [
+            ("job_id", Value::from(candidate.job_id)),
+            ("job_class", Value::from(candidate.job_class)),
+            ("worker_id", Value::from(candidate.worker_id)),
+            ("worker_team", Value::from(candidate.worker_team)),
+            ("worker_succeeded", Value::Int(totals.succeeded as i64)),
+            ("worker_failed", Value::Int(failed as i64)),
+            ("worker_success_rate", Value::Float(success_rate)),
+            (
+                "worker_avg_end_to_end_ms",
+                Value::Float(totals.avg_end_to_end_ms.unwrap_or_default()),
+            ),
+            ("worker_busy", Value::Boolean(candidate.busy)),
+        ];
+        for (name, value) in variables {
+            context
+                .set_value(name.to_owned(), value)
+                .map_err(|err| err.to_string())?;
+        }
+        match self.node.eval_with_context(&context) {
+            Ok(Value::Boolean(ok)) => Ok(if ok { 1.0 } else { 0.0 }),
+            Ok(value) => value.as_number().map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        }
+    }
+}
+
+/// Workers of `stats` ranked for `job_id` by `policy`.
+pub fn report<'a>(
+    policy: &AssignmentPolicy,
+    job_id: &str,
+    job_class_separator: &str,
+    groups: Option<&GroupsConfig>,
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+) -> AssignmentReport {
+    let stats = stats.into_iter().collect::<BTreeMap<_, _>>();
+    let summary = summary::summarize(stats.iter().map(|(&k, &v)| (k, v)), |_| None, |_| None);
+    let job_class = durations::job_class(job_id, job_class_separator);
+    let mut workers = summary
+        .workers
+        .iter()
+        .map(|(worker_id, totals)| {
+            let busy = stats[worker_id].back().is_some_and(|state| {
+                matches!(
+                    state,
+                    SnarkWorkerState::JobGetPending { .. }
+                        | SnarkWorkerState::WorkCreatePending { .. }
+                        | SnarkWorkerState::WorkSubmitPending { .. }
+                )
+            });
+            let candidate = Candidate {
+                job_id,
+                job_class,
+                worker_id,
+                worker_team: groups
+                    .and_then(|groups| groups.team_of(worker_id))
+                    .unwrap_or_default(),
+                totals,
+                busy,
+            };
+            let (score, error) = match policy.score(&candidate) {
+                Ok(score) => (Some(score), None),
+                Err(err) => (None, Some(err)),
+            };
+            ScoredWorker {
+                worker_id: worker_id.clone(),
+                score,
+                error,
+            }
+        })
+        .collect::<Vec<_>>();
+    workers.sort_by(|a, b| match (a.score, b.score) {
+        (Some(a), Some(b)) => b.total_cmp(&a),
+        (a, b) => b.is_some().cmp(&a.is_some()),
+    });
+    AssignmentReport {
+        job_id: job_id.to_owned(),
+        job_class: job_class.to_owned(),
+        policy: policy.script.clone(),
+        workers,
+    }
+}
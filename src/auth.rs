@@ -0,0 +1,198 @@
+use std::sync::Arc;
+
+use snark_coordinator_rs::{
+    api_keys::{Access, ApiKeys, AuthError},
+    errors::ApiError,
+    job_pool,
+};
+use warp::{
+    hyper::{Method, StatusCode},
+    path::FullPath,
+    reject::{Reject, Rejection},
+    reply::{with_status, Reply, Response},
+    Filter,
+};
+
+use crate::{
+    admin_port::{self, AdminListener},
+    graphql,
+};
+
+#[derive(Debug)]
+struct Denied(AuthError);
+
+impl Reject for Denied {}
+
+/// Requires an API key with admin access for the admin, snapshot and
+/// metrics routes, with write access for mutating requests, and with
+/// `protect_reads` any known key for the rest. Without `keys` everything
+/// passes but the admin routes, which are then only served on
+/// `--admin-port`.
+pub fn filter(
+    keys: Option<Arc<ApiKeys>>,
+    protect_reads: bool,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .map(|method: Method, path: FullPath| {
+            let admin = admin_port::is_admin_path(path.as_str());
+            (access_method(method, path.as_str()), admin)
+        })
+        .untuple_one()
+        .and(warp::ext::optional::<AdminListener>())
+        .and(warp::header::optional::<String>("authorization"))
+        .and_then(
+            move |method: Method,
+                  admin: bool,
+                  listener: Option<AdminListener>,
+                  authorization: Option<String>| {
+                let keys = keys.clone();
+                async move {
+                    let access = match method {
+                        _ if admin => Access::Admin,
+                        Method::GET | Method::HEAD | Method::OPTIONS if !protect_reads => {
+                            return Ok(())
+                        }
+                        Method::GET | Method::HEAD | Method::OPTIONS => Access::Read,
+                        _ => Access::Write,
+                    };
+                    let Some(keys) = keys else {
+                        if admin && listener.is_none() {
+                            let msg = "admin routes need an admin api key or --admin-port";
+                            let err = AuthError::Forbidden(msg.to_owned());
+                            return Err(warp::reject::custom(Denied(err)));
+                        }
+                        return Ok(());
+                    };
+                    match keys.authorize(authorization.as_deref(), access) {
+                        Ok(key) => {
+                            tracing::debug!(api_key = %key.name, "authorized");
+                            Ok(())
+                        }
+                        Err(err) => Err(warp::reject::custom(Denied(err))),
+                    }
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Method `method` requests to `path` are authorized as: GraphQL is
+/// queried with POST too, but only reads, while `GET /job` locks the job
+/// it hands out.
+pub fn access_method(method: Method, path: &str) -> Method {
+    match path {
+        graphql::PATH => Method::GET,
+        job_pool::PATH if method == Method::GET => Method::PUT,
+        _ => method,
+    }
+}
+
+/// Turns rejections of [`filter`] into 401/403 responses with a JSON body.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    let Some(Denied(err)) = rejection.find::<Denied>() else {
+        return Err(rejection);
+    };
+    let status = StatusCode::from_u16(err.code().status()).unwrap();
+    let body = ApiError::new(err.code(), err.message());
+    tracing::debug!(?err, "request denied");
+    Ok(with_status(serde_json::to_string(&body).unwrap(), status).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use snark_coordinator_rs::api_keys::ApiKey;
+
+    use super::*;
+
+    fn route(
+        keys: Option<ApiKeys>,
+    ) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        filter(keys.map(Arc::new), false)
+            .map(|| "served".into_response())
+            .recover(recover)
+            .unify()
+    }
+
+    async fn status(
+        route: &(impl Filter<Extract = (Response,), Error = Rejection> + Clone + 'static),
+        method: &str,
+        path: &str,
+        key: Option<&str>,
+    ) -> StatusCode {
+        let mut req = warp::test::request().method(method).path(path);
+        if let Some(key) = key {
+            req = req.header("authorization", format!("Bearer {key}"));
+        }
+        req.reply(route).await.status()
+    }
+
+    #[tokio::test]
+    async fn admin_routes_need_an_admin_key() {
+        let key = |access| ApiKey {
+            name: format!("{access:?}"),
+            access,
+        };
+        let keys = ApiKeys {
+            keys: HashMap::from([
+                ("r".to_owned(), key(Access::Read)),
+                ("w".to_owned(), key(Access::Write)),
+                ("a".to_owned(), key(Access::Admin)),
+            ]),
+        };
+        let route = route(Some(keys));
+        for (method, path) in [
+            ("POST", "/admin/reset"),
+            ("DELETE", "/admin/locks/j1"),
+            ("GET", "/admin/quarantine"),
+            ("GET", "/snapshot"),
+            ("GET", "/metrics"),
+        ] {
+            for (key, expected) in [("w", StatusCode::FORBIDDEN), ("a", StatusCode::OK)] {
+                let got = status(&route, method, path, Some(key)).await;
+                assert_eq!(got, expected, "{method} {path} with {key}");
+            }
+        }
+        assert_eq!(
+            status(&route, "GET", "/workers", None).await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&route, "PUT", "/lock-job/j1", Some("r")).await,
+            StatusCode::FORBIDDEN
+        );
+        assert_eq!(
+            status(&route, "PUT", "/lock-job/j1", Some("a")).await,
+            StatusCode::OK
+        );
+    }
+
+    #[tokio::test]
+    async fn admin_routes_without_keys_are_only_served_on_the_admin_port() {
+        let route = route(None);
+        assert_eq!(
+            status(&route, "GET", "/snapshot", None).await,
+            StatusCode::FORBIDDEN
+        );
+        let req = warp::test::request().path("/snapshot");
+        let res = req.extension(AdminListener).reply(&route).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            status(&route, "PUT", "/lock-job/j1", None).await,
+            StatusCode::OK
+        );
+    }
+
+    #[test]
+    fn job_handout_is_a_write() {
+        assert_eq!(access_method(Method::GET, job_pool::PATH), Method::PUT);
+        assert_eq!(access_method(Method::POST, graphql::PATH), Method::GET);
+        assert_eq!(access_method(Method::GET, "/workers"), Method::GET);
+        assert_eq!(
+            access_method(Method::DELETE, "/lock-job/j1"),
+            Method::DELETE
+        );
+    }
+}
@@ -0,0 +1,164 @@
+//! Availability of workers over the last day and week: the fraction of
+//! the window in which they were alive, see [`crate::liveness`], and not
+//! stuck in an error state. Maintenance windows don't count either way.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{liveness::Liveness, maintenance::MaintenanceWindows, stats::SnarkWorkerState};
+
+pub const DAY_MS: u64 = 24 * 3600 * 1000;
+pub const WEEK_MS: u64 = 7 * DAY_MS;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default)]
+pub struct Availability {
+    /// Fraction of the last day, from 0 to 1.
+    pub day: f64,
+    /// Fraction of the last week, from 0 to 1.
+    pub week: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct AvailabilityReport {
+    /// Start of the daily window, later than a day ago if the coordinator
+    /// started since, as nothing is known about the time before.
+    pub day_from_t: u64,
+    /// Start of the weekly window, see `day_from_t`.
+    pub week_from_t: u64,
+    pub to_t: u64,
+    /// Mean of all reported workers.
+    pub total: Availability,
+    /// Mean of the workers in each group of `--groups-file`.
+    pub groups: BTreeMap<String, Availability>,
+    pub workers: BTreeMap<String, Availability>,
+}
+
+/// Computes the availability of `workers` as of `now`. Groups without any
+/// of `workers` are left out.
+pub fn report<'a>(
+    workers: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    liveness: &Liveness,
+    maintenance: &MaintenanceWindows,
+    teams: &HashMap<String, Vec<String>>,
+    now: u64,
+) -> AvailabilityReport {
+    let day_from_t = now.saturating_sub(DAY_MS).max(liveness.started_t());
+    let week_from_t = now.saturating_sub(WEEK_MS).max(liveness.started_t());
+    let workers = workers
+        .into_iter()
+        .map(|(worker_id, states)| {
+            let alive = liveness.alive_periods(worker_id, now);
+            let errors = error_periods(states, week_from_t, now);
+            let excluded = maintenance.periods(worker_id);
+            let availability = Availability {
+                day: fraction(&alive, &errors, &excluded, day_from_t, now),
+                week: fraction(&alive, &errors, &excluded, week_from_t, now),
+            };
+            (worker_id.clone(), availability)
+        })
+        .collect::<BTreeMap<_, _>>();
+
+    let groups = teams
+        .iter()
+        .filter_map(|(team, prefixes)| {
+            let members = workers
+                .iter()
+                .filter(|(id, _)| prefixes.iter().any(|p| id.starts_with(p)))
+                .map(|(_, a)| a);
+            Some((team.clone(), mean(members)?))
+        })
+        .collect();
+
+    AvailabilityReport {
+        day_from_t,
+        week_from_t,
+        to_t: now,
+        total: mean(workers.values()).unwrap_or_default(),
+        groups,
+        workers,
+    }
+}
+
+fn mean<'a>(availabilities: impl Iterator<Item = &'a Availability>) -> Option<Availability> {
+    let (n, sum) = availabilities.fold((0, Availability::default()), |(n, sum), a| {
+        let sum = Availability {
+            day: sum.day + a.day,
+            week: sum.week + a.week,
+        };
+        (n + 1, sum)
+    });
+    (n > 0).then(|| Availability {
+        day: sum.day / n as f64,
+        week: sum.week / n as f64,
+    })
+}
+
+/// Periods spent in error states ending after `from_t`. An error state
+/// lasts until the next lifecycle starts, or `now` if it's the current
+/// state.
+fn error_periods(states: &VecDeque<SnarkWorkerState>, from_t: u64, now: u64) -> Vec<(u64, u64)> {
+    // most recent state first.
+    let next_starts = std::iter::once(now).chain(states.iter().map(|s| s.start_time()));
+    states
+        .iter()
+        .zip(next_starts)
+        .take_while(|(_, until)| *until > from_t)
+        .filter(|(state, _)| {
+            matches!(
+                state,
+                SnarkWorkerState::JobGetError { .. }
+                    | SnarkWorkerState::WorkCreateError { .. }
+                    | SnarkWorkerState::WorkSubmitError { .. }
+                    | SnarkWorkerState::JobGetTimeout { .. }
+                    | SnarkWorkerState::WorkCreateTimeout { .. }
+                    | SnarkWorkerState::WorkSubmitTimeout { .. }
+            )
+        })
+        .map(|(state, until)| (state.end_time(), until))
+        .filter(|(start, end)| start < end)
+        .collect()
+}
+
+fn overlap((a_start, a_end): (u64, u64), (b_start, b_end): (u64, u64)) -> u64 {
+    a_end.min(b_end).saturating_sub(a_start.max(b_start))
+}
+
+/// Time of `[from_t, to_t)` covered by `alive` but not `errors`.
+/// Periods within each list don't overlap.
+fn available_ms(alive: &[(u64, u64)], errors: &[(u64, u64)], from_t: u64, to_t: u64) -> u64 {
+    alive
+        .iter()
+        .map(|a| (a.0.max(from_t), a.1.min(to_t)))
+        .filter(|a| a.0 < a.1)
+        .map(|a| {
+            let erroring = errors.iter().map(|e| overlap(a, *e)).sum::<u64>();
+            (a.1 - a.0).saturating_sub(erroring)
+        })
+        .sum()
+}
+
+/// Fraction of `[from_t, to_t)` outside of `excluded` in which the
+/// worker was available. Workers under maintenance for the whole window
+/// are fully available.
+fn fraction(
+    alive: &[(u64, u64)],
+    errors: &[(u64, u64)],
+    excluded: &[(u64, u64)],
+    from_t: u64,
+    to_t: u64,
+) -> f64 {
+    let mut total_ms = to_t.saturating_sub(from_t);
+    let mut available = available_ms(alive, errors, from_t, to_t);
+    for e in excluded {
+        let (e_from, e_to) = (e.0.max(from_t), e.1.min(to_t));
+        if e_from < e_to {
+            total_ms -= e_to - e_from;
+            available -= available_ms(alive, errors, e_from, e_to);
+        }
+    }
+    if total_ms == 0 {
+        return 1.0;
+    }
+    available as f64 / total_ms as f64
+}
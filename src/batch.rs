@@ -0,0 +1,83 @@
+//! Per-item results of batch endpoints, so clients can retry just the
+//! items which failed.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::errors::{ApiError, ErrorCode};
+
+/// Response body of a batch request. The request itself succeeds even if
+/// some of its items don't.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct BatchResponse<T> {
+    pub summary: BatchSummary,
+    pub results: Vec<BatchItem<T>>,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, Default)]
+pub struct BatchSummary {
+    pub total: usize,
+    pub succeeded: usize,
+    pub failed: usize,
+}
+
+/// Outcome of a single item, `index` is its position in the request.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct BatchItem<T> {
+    pub index: usize,
+    /// HTTP status the item would have got as a single request.
+    pub status: u16,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<ApiError>,
+    /// Resulting state, also set for some failures (e.g. the current
+    /// holder of a lock).
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub result: Option<T>,
+}
+
+impl<T> BatchItem<T> {
+    pub fn succeeded(index: usize, status: u16, result: Option<T>) -> Self {
+        Self {
+            index,
+            status,
+            error: None,
+            result,
+        }
+    }
+
+    pub fn failed(index: usize, code: ErrorCode, message: String) -> Self {
+        Self::rejected(index, ApiError::new(code, message))
+    }
+
+    pub fn rejected(index: usize, error: ApiError) -> Self {
+        Self {
+            index,
+            status: error.code.status(),
+            error: Some(error),
+            result: None,
+        }
+    }
+
+    pub fn with_result(mut self, result: T) -> Self {
+        self.result = Some(result);
+        self
+    }
+
+    pub fn is_success(&self) -> bool {
+        self.error.is_none()
+    }
+}
+
+impl<T> BatchResponse<T> {
+    pub fn new(results: Vec<BatchItem<T>>) -> Self {
+        let succeeded = results.iter().filter(|item| item.is_success()).count();
+        Self {
+            summary: BatchSummary {
+                total: results.len(),
+                succeeded,
+                failed: results.len() - succeeded,
+            },
+            results,
+        }
+    }
+}
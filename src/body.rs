@@ -0,0 +1,122 @@
+use std::{fmt, io::Read};
+
+use flate2::read::GzDecoder;
+use serde::de::DeserializeOwned;
+use snark_coordinator_rs::errors::{ApiError, ErrorCode};
+use warp::{
+    hyper::{body::Bytes, StatusCode},
+    reject::{Reject, Rejection},
+    reply::{with_status, Reply, Response},
+    Filter,
+};
+
+use crate::formats::Format;
+
+#[derive(Debug)]
+pub enum BodyError {
+    TooLarge { max: u64 },
+    UnsupportedEncoding(String),
+    Invalid(String),
+}
+
+impl Reject for BodyError {}
+
+impl fmt::Display for BodyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TooLarge { max } => write!(f, "body too large! max: {max}"),
+            Self::UnsupportedEncoding(encoding) => {
+                write!(f, "unsupported content-encoding: {encoding}")
+            }
+            Self::Invalid(err) => write!(f, "invalid body: {err}"),
+        }
+    }
+}
+
+impl BodyError {
+    fn code(&self) -> ErrorCode {
+        match self {
+            Self::TooLarge { .. } => ErrorCode::BodyTooLarge,
+            Self::UnsupportedEncoding(_) => ErrorCode::UnsupportedEncoding,
+            Self::Invalid(_) => ErrorCode::InvalidBody,
+        }
+    }
+}
+
+/// JSON body, optionally with `Content-Encoding: gzip`, or MessagePack or
+/// CBOR by its `Content-Type`. `max_len` limits the decompressed size.
+pub fn json<T>(max_len: u64) -> impl Filter<Extract = (T,), Error = Rejection> + Clone
+where
+    T: DeserializeOwned + Send,
+{
+    bytes(max_len).and_then(|body: Bytes| async move {
+        serde_json::from_slice(&body)
+            .map_err(|err| warp::reject::custom(BodyError::Invalid(err.to_string())))
+    })
+}
+
+/// Decompressed body as JSON, see [`json`], for handlers which deal with
+/// invalid JSON themselves. `max_len` limits the decompressed size.
+pub fn bytes(max_len: u64) -> impl Filter<Extract = (Bytes,), Error = Rejection> + Clone {
+    warp::header::optional::<u64>("content-length")
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and_then(
+            move |len: Option<u64>, encoding: Option<String>| async move {
+                // compressed bodies can't be larger than their contents in
+                // any practical case, so oversized ones are refused unread.
+                match len {
+                    Some(len) if len > max_len => {
+                        Err(warp::reject::custom(BodyError::TooLarge { max: max_len }))
+                    }
+                    _ => Ok(encoding),
+                }
+            },
+        )
+        .and(warp::header::optional::<String>("content-type"))
+        .and(warp::body::bytes())
+        .and_then(
+            move |encoding: Option<String>, content_type: Option<String>, body: Bytes| async move {
+                let body =
+                    decode(encoding.as_deref(), body, max_len).map_err(warp::reject::custom)?;
+                match Format::of_body(content_type.as_deref()) {
+                    Some(format) => format
+                        .decode(&body)
+                        .map(Bytes::from)
+                        .map_err(|err| warp::reject::custom(BodyError::Invalid(err))),
+                    None => Ok(body),
+                }
+            },
+        )
+}
+
+fn decode(encoding: Option<&str>, body: Bytes, max_len: u64) -> Result<Bytes, BodyError> {
+    let body = match encoding.map(str::trim) {
+        None | Some("identity") => body,
+        Some(encoding) if encoding.eq_ignore_ascii_case("gzip") => {
+            let mut buf = Vec::with_capacity(body.len() * 4);
+            GzDecoder::new(&body[..])
+                .take(max_len + 1)
+                .read_to_end(&mut buf)
+                .map_err(|err| BodyError::Invalid(err.to_string()))?;
+            buf.into()
+        }
+        Some(encoding) => return Err(BodyError::UnsupportedEncoding(encoding.to_owned())),
+    };
+    if body.len() as u64 > max_len {
+        return Err(BodyError::TooLarge { max: max_len });
+    }
+    Ok(body)
+}
+
+/// Turns [`BodyError`] rejections into responses, other rejections are
+/// passed on.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    match rejection.find::<BodyError>() {
+        Some(err) => {
+            let status = StatusCode::from_u16(err.code().status()).unwrap();
+            let body = ApiError::new(err.code(), err.to_string());
+            Ok(with_status(serde_json::to_string(&body).unwrap(), status).into_response())
+        }
+        None => Err(rejection),
+    }
+}
@@ -0,0 +1,256 @@
+//! Typed client of the worker protocol, behind the `client` feature, for
+//! workers written in Rust. It sends and parses the same types the server
+//! does, so workers don't need their own copies of the events. Workers
+//! depending on the crate with `default-features = false` get reqwest
+//! only through this feature.
+
+use std::{collections::BTreeMap, fmt};
+
+use reqwest::{Client, RequestBuilder, Response, StatusCode};
+use serde::Serialize;
+
+use crate::{
+    errors::ApiError,
+    lock::{LockJobGranted, LockJobHeld},
+    lock_namespaces,
+    stats::{SnarkWorkerState, SnarkWorkerStatsPut},
+    worker_tokens,
+};
+
+#[derive(Debug, Clone)]
+pub struct SnarkCoordinatorClient {
+    http: Client,
+    /// E.g. `http://coordinator:8080`, without the `/v1` prefix.
+    base_url: String,
+    api_key: Option<String>,
+    namespace: Option<String>,
+}
+
+/// Query of [`SnarkCoordinatorClient::lock_job`].
+#[derive(Serialize, Debug, Clone, Default)]
+pub struct LockJobParams {
+    /// Lock TTL in milliseconds, the server's default if `None`.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub timeout_ms: Option<u64>,
+    /// Holder of the lock, shown to those who find it held.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub worker_id: Option<String>,
+    /// Seconds to wait for a held lock to become available.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub wait: Option<u16>,
+}
+
+#[derive(Debug, Clone)]
+pub enum LockJob {
+    Granted(LockJobGranted),
+    Held(LockJobHeld),
+}
+
+/// Result of a worker-stats event, acknowledged once queued unless it's a
+/// `Register`.
+#[derive(Debug, Clone, Default)]
+pub struct PutStats {
+    /// Session id assigned or resumed by a `Register`.
+    pub session_id: Option<String>,
+    /// Token to send with the session's next events, if the server
+    /// issues them.
+    pub token: Option<String>,
+}
+
+/// Filter of [`SnarkCoordinatorClient::get_stats`], everything if empty.
+#[derive(Debug, Clone, Default)]
+pub struct StatsQuery {
+    pub workers: Option<Vec<String>>,
+    /// Only states overlapping `from_t..to_t`.
+    pub from_t: Option<u64>,
+    pub to_t: Option<u64>,
+    /// State kinds, e.g. `JobGetError`.
+    pub kinds: Option<Vec<String>>,
+}
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request failed or the response couldn't be read.
+    Http(reqwest::Error),
+    /// The server answered with an error.
+    Api { status: StatusCode, error: ApiError },
+    /// Response which is neither what the call expects nor an error body.
+    Unexpected { status: StatusCode, message: String },
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Http(err) => write!(f, "{err}"),
+            Self::Api { status, error } => write!(f, "{status}: {}", error.message),
+            Self::Unexpected { status, message } => {
+                write!(f, "unexpected response {status}: {message}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> Self {
+        Self::Http(err)
+    }
+}
+
+impl SnarkCoordinatorClient {
+    pub fn new(base_url: impl Into<String>) -> Self {
+        Self {
+            http: Client::new(),
+            base_url: base_url.into().trim_end_matches('/').to_owned(),
+            api_key: None,
+            namespace: None,
+        }
+    }
+
+    /// Sends `Authorization: Bearer <key>` with every request.
+    pub fn with_api_key(mut self, api_key: impl Into<String>) -> Self {
+        self.api_key = Some(api_key.into());
+        self
+    }
+
+    /// Locks keys and reports stats in a lock namespace.
+    pub fn with_namespace(mut self, namespace: impl Into<String>) -> Self {
+        self.namespace = Some(namespace.into());
+        self
+    }
+
+    /// Uses `http`, e.g. with timeouts or TLS settings, instead of a
+    /// default client.
+    pub fn with_http_client(mut self, http: Client) -> Self {
+        self.http = http;
+        self
+    }
+
+    fn request(&self, method: reqwest::Method, path: &str) -> RequestBuilder {
+        let mut req = self
+            .http
+            .request(method, format!("{}{path}", self.base_url));
+        if let Some(api_key) = &self.api_key {
+            req = req.bearer_auth(api_key);
+        }
+        if let Some(namespace) = &self.namespace {
+            req = req.header(lock_namespaces::HEADER, namespace);
+        }
+        req
+    }
+
+    pub async fn lock_job(
+        &self,
+        key: &str,
+        params: &LockJobParams,
+    ) -> Result<LockJob, ClientError> {
+        let res = self
+            .request(reqwest::Method::PUT, &format!("/v1/lock-job/{key}"))
+            .query(params)
+            .send()
+            .await?;
+        match res.status() {
+            StatusCode::CREATED => Ok(LockJob::Granted(res.json().await?)),
+            StatusCode::OK => Ok(LockJob::Held(res.json().await?)),
+            _ => Err(error(res).await),
+        }
+    }
+
+    /// Releases the lock of `key` held under `fencing_token`.
+    pub async fn release_job(&self, key: &str, fencing_token: u64) -> Result<(), ClientError> {
+        let res = self
+            .request(reqwest::Method::DELETE, &format!("/v1/lock-job/{key}"))
+            .query(&[("fencing_token", fencing_token)])
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(error(res).await);
+        }
+        Ok(())
+    }
+
+    /// Reports an event of `worker_id`, with the session's `token` if the
+    /// server issued one on registration.
+    pub async fn put_stats(
+        &self,
+        worker_id: &str,
+        event: &SnarkWorkerStatsPut,
+        token: Option<&str>,
+    ) -> Result<PutStats, ClientError> {
+        let mut req = self
+            .request(
+                reqwest::Method::PUT,
+                &format!("/v1/worker-stats/{worker_id}"),
+            )
+            .json(event);
+        if let Some(token) = token {
+            req = req.header(worker_tokens::HEADER, token);
+        }
+        let res = req.send().await?;
+        if !res.status().is_success() {
+            return Err(error(res).await);
+        }
+        let token = res
+            .headers()
+            .get(worker_tokens::HEADER)
+            .and_then(|token| token.to_str().ok())
+            .map(str::to_owned);
+        let body = res.text().await?;
+        Ok(PutStats {
+            session_id: Some(body).filter(|body| !body.is_empty()),
+            token,
+        })
+    }
+
+    /// States of the workers matching `query`, most recent first.
+    pub async fn get_stats(
+        &self,
+        query: &StatsQuery,
+    ) -> Result<BTreeMap<String, Vec<SnarkWorkerState>>, ClientError> {
+        #[derive(Serialize)]
+        struct Query {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            workers: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            from_t: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            to_t: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            kinds: Option<String>,
+        }
+        let res = self
+            .request(reqwest::Method::GET, "/v1/worker-stats")
+            .query(&Query {
+                workers: query.workers.as_ref().map(|w| w.join(",")),
+                from_t: query.from_t,
+                to_t: query.to_t,
+                kinds: query.kinds.as_ref().map(|k| k.join(",")),
+            })
+            .send()
+            .await?;
+        if !res.status().is_success() {
+            return Err(error(res).await);
+        }
+        let body = res.bytes().await?;
+        serde_json::from_slice(&body).map_err(|err| ClientError::Unexpected {
+            status: StatusCode::OK,
+            message: format!("invalid body: {err}"),
+        })
+    }
+}
+
+async fn error(res: Response) -> ClientError {
+    let status = res.status();
+    let body = match res.text().await {
+        Ok(body) => body,
+        Err(err) => return ClientError::Http(err),
+    };
+    match serde_json::from_str(&body) {
+        Ok(error) => ClientError::Api { status, error },
+        Err(_) => ClientError::Unexpected {
+            status,
+            message: body,
+        },
+    }
+}
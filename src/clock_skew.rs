@@ -0,0 +1,94 @@
+//! Clocks of workers compared to the coordinator's, from the `time` their
+//! stats events report and when the coordinator received them. Unlike
+//! echoes, see [`crate::network`], this needs nothing from workers, but
+//! can't tell transit time apart from skew.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    str::FromStr,
+};
+
+use serde::{Deserialize, Serialize};
+
+/// Max number of events kept per worker to estimate its skew from.
+const MAX_SAMPLES: usize = 32;
+
+/// What `--server-time` does with the receive time of stats events.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ServerTime {
+    /// Only estimate the workers' clock skew from it.
+    Record,
+    /// Also store it as the event's `time`, in place of the reported one.
+    Substitute,
+}
+
+impl FromStr for ServerTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "record" => Ok(Self::Record),
+            "substitute" => Ok(Self::Substitute),
+            _ => Err(format!("expected `record` or `substitute`, found: {s}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct WorkerClockSkew {
+    /// Worker's clock minus the coordinator's, positive if the worker is
+    /// ahead. Transit only makes workers look behind, so it's the largest
+    /// difference of the recent events.
+    pub skew_ms: i64,
+    pub samples: usize,
+    /// `time` of the worker's latest event, as reported.
+    pub last_reported_t: u64,
+    /// When the coordinator received the worker's latest event.
+    pub last_received_t: u64,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    reported_t: u64,
+    received_t: u64,
+}
+
+/// Per-worker reported and receive times of recent events, most recent
+/// first.
+#[derive(Debug, Default)]
+pub struct ClockSkews {
+    samples: HashMap<String, VecDeque<Sample>>,
+}
+
+impl ClockSkews {
+    pub fn observe(&mut self, worker_id: &str, reported_t: u64, received_t: u64) {
+        let samples = match self.samples.get_mut(worker_id) {
+            Some(samples) => samples,
+            None => self.samples.entry(worker_id.to_owned()).or_default(),
+        };
+        samples.push_front(Sample {
+            reported_t,
+            received_t,
+        });
+        samples.truncate(MAX_SAMPLES);
+    }
+
+    pub fn get(&self, worker_id: &str) -> Option<WorkerClockSkew> {
+        let samples = self.samples.get(worker_id)?;
+        let last = samples.front()?;
+        let skew_ms = samples
+            .iter()
+            .map(|s| s.reported_t as i64 - s.received_t as i64)
+            .max()?;
+        Some(WorkerClockSkew {
+            skew_ms,
+            samples: samples.len(),
+            last_reported_t: last.reported_t,
+            last_received_t: last.received_t,
+        })
+    }
+
+    pub fn forget(&mut self, worker_id: &str) {
+        self.samples.remove(worker_id);
+    }
+}
@@ -0,0 +1,291 @@
+//! `--cluster` membership: campaigning for the leader lease, and while
+//! following another member, replicating its state from
+//! `GET /replication`, so a follower taking over has the leader's locks
+//! and worker histories.
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use snark_coordinator_rs::{journal::JournalEntry, leader_lease::LeaderLease};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+
+use crate::{failover::Role, StatsIngest};
+
+/// How long a follower waits before reconnecting to the leader, and how
+/// often it checks it still follows it while the stream is quiet.
+const FOLLOW_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Campaigns every third of the lease `ttl`, switching `role` to the
+/// outcome. A leader which can't renew its lease steps down before the
+/// lease may have run out, or once it did while a campaign hangs, so no
+/// two members lead at once.
+pub async fn elect(lease: Arc<LeaderLease>, role: Arc<Mutex<Role>>, ttl: Duration) {
+    let interval = ttl / 3;
+    let mut renewed_at = None::<Instant>;
+    loop {
+        let started = Instant::now();
+        // the lease counts from before the campaign, which may take a while.
+        let timeout = campaign_timeout(renewed_at, ttl, started);
+        let new_role = match tokio::time::timeout(timeout, lease.campaign()).await {
+            Ok(Ok(leader)) if leader == lease.id() => {
+                renewed_at = Some(started);
+                Some(Role::Primary)
+            }
+            Ok(Ok(leader)) => {
+                renewed_at = None;
+                Some(Role::Standby { primary: leader })
+            }
+            Ok(Err(err)) => {
+                warn!(%err, "failed to campaign for cluster leadership");
+                // the lease may run out before the next campaign.
+                let expiring = renewed_at.is_some_and(|t| t.elapsed() + interval >= ttl);
+                expiring.then_some(Role::Electing)
+            }
+            Err(_) => {
+                warn!(?timeout, "campaign for cluster leadership timed out");
+                // a leader's lease ran out meanwhile.
+                renewed_at.take().map(|_| Role::Electing)
+            }
+        };
+        if let Some(new_role) = new_role {
+            let mut role = role.lock().await;
+            if *role != new_role {
+                info!(from = ?*role, to = ?new_role, "cluster role changed");
+                *role = new_role;
+            }
+        }
+        tokio::time::sleep(interval.saturating_sub(started.elapsed())).await;
+    }
+}
+
+/// How long a campaign started at `now` may take: until the lease renewed
+/// at `renewed_at` runs out, or a whole `ttl` for a member not leading.
+fn campaign_timeout(renewed_at: Option<Instant>, ttl: Duration, now: Instant) -> Duration {
+    match renewed_at {
+        Some(t) => (t + ttl).saturating_duration_since(now),
+        None => ttl,
+    }
+}
+
+/// While `role` follows a leader, replicates its state into `ingest`,
+/// starting over from a snapshot whenever the stream breaks.
+pub async fn follow(ingest: StatsIngest, role: Arc<Mutex<Role>>, api_key: Option<String>) {
+    let client = reqwest::Client::new();
+    loop {
+        let primary = match &*role.lock().await {
+            Role::Standby { primary } => Some(primary.clone()),
+            Role::Primary | Role::Electing | Role::Replay => None,
+        };
+        if let Some(primary) = primary {
+            let res = replicate(&client, &ingest, &role, &primary, api_key.as_deref()).await;
+            match res {
+                Ok(()) => debug!(%primary, "stopped replicating"),
+                Err(err) => warn!(%err, %primary, "failed to replicate from leader"),
+            }
+        }
+        tokio::time::sleep(FOLLOW_RETRY_INTERVAL).await;
+    }
+}
+
+/// Applies the replication stream of `primary` until it ends or `role`
+/// stops following it.
+async fn replicate(
+    client: &reqwest::Client,
+    ingest: &StatsIngest,
+    role: &Mutex<Role>,
+    primary: &str,
+    api_key: Option<&str>,
+) -> Result<(), String> {
+    let url = format!("{}/replication", primary.trim_end_matches('/'));
+    let mut req = client.get(url);
+    if let Some(key) = api_key {
+        req = req.bearer_auth(key);
+    }
+    let mut res = req.send().await.map_err(|err| err.to_string())?;
+    if !res.status().is_success() {
+        return Err(format!("leader responded with {}", res.status()));
+    }
+    info!(%primary, "replicating from leader");
+    let mut buf = Vec::new();
+    let following = || async {
+        matches!(
+            &*role.lock().await,
+            Role::Standby { primary: p } if p == primary
+        )
+    };
+    loop {
+        let chunk = match tokio::time::timeout(FOLLOW_RETRY_INTERVAL, res.chunk()).await {
+            Ok(chunk) => chunk.map_err(|err| err.to_string())?,
+            Err(_) if following().await => continue,
+            Err(_) => return Ok(()),
+        };
+        let Some(chunk) = chunk else {
+            return Ok(());
+        };
+        buf.extend_from_slice(&chunk);
+        while let Some(end) = buf.windows(2).position(|w| w == b"\n\n") {
+            let event = buf.drain(..end + 2).collect::<Vec<_>>();
+            if !following().await {
+                return Ok(());
+            }
+            let event = String::from_utf8_lossy(&event);
+            let (kind, data) = parse_event(&event);
+            let (entries, reset) = match kind {
+                "snapshot" => (serde_json::from_str::<Vec<JournalEntry>>(&data), true),
+                "entry" => (
+                    serde_json::from_str::<JournalEntry>(&data).map(|e| vec![e]),
+                    false,
+                ),
+                // keep-alive comments.
+                _ => continue,
+            };
+            let entries = entries.map_err(|err| format!("invalid {kind} event: {err}"))?;
+            let rejected = ingest.replicate(entries, reset).await;
+            if rejected > 0 {
+                warn!(rejected, "replicated entries didn't apply");
+            }
+        }
+    }
+}
+
+/// Event type and data of a server-sent event.
+fn parse_event(event: &str) -> (&str, String) {
+    let mut kind = "message";
+    let mut data = vec![];
+    for line in event.lines() {
+        if let Some(value) = line.strip_prefix("event:") {
+            kind = value.trim();
+        } else if let Some(value) = line.strip_prefix("data:") {
+            data.push(value.strip_prefix(' ').unwrap_or(value));
+        }
+    }
+    if data.is_empty() {
+        kind = "";
+    }
+    (kind, data.join("\n"))
+}
+
+#[cfg(test)]
+mod tests {
+    use snark_coordinator_rs::{
+        journal::{self, JournalOp},
+        lock::{JobLock, LockShards, LockTable},
+        stats::{self, SnarkWorkerStatsPut, WorkerStats},
+        timestamp,
+    };
+
+    use super::*;
+
+    /// Server-sent event as served by `GET /replication`.
+    fn event(kind: &str, data: &impl serde::Serialize) -> String {
+        format!(
+            "event:{kind}\ndata:{}\n\n",
+            serde_json::to_string(data).unwrap()
+        )
+    }
+
+    #[test]
+    fn parses_replication_events() {
+        assert_eq!(
+            parse_event("event: entry\ndata: {\"a\":\ndata: 1}\n\n"),
+            ("entry", "{\"a\":\n1}".to_owned())
+        );
+        assert_eq!(parse_event("data:x\n\n"), ("message", "x".to_owned()));
+        // keep-alive comments have no data.
+        assert_eq!(parse_event(":\n\n").0, "");
+        assert_eq!(parse_event("event:entry\n\n").0, "");
+    }
+
+    #[tokio::test]
+    async fn followers_resync_from_snapshot_then_entries() {
+        let now = Instant::now();
+        let expires_t = timestamp::now() + 60_000;
+        let mut stats = WorkerStats::new();
+        let register = SnarkWorkerStatsPut::Register {
+            time: 1,
+            metadata: None,
+            resume: None,
+            seq: None,
+        };
+        let worker_id = stats::put(&mut stats, "w".to_owned(), register).unwrap();
+        let get = SnarkWorkerStatsPut::JobGetInit { time: 2, seq: None };
+        stats::put(&mut stats, worker_id.clone(), get).unwrap();
+        let leader = LockShards::new(LockTable::new(16), 4);
+        let lock = JobLock::new(now + Duration::from_secs(60), Some(worker_id.clone()));
+        let mut kv = leader.lock_all().await;
+        let token = kv.try_acquire_all(vec!["j1".into()], lock, now).unwrap();
+
+        // as built by the leader's replication feed.
+        let t = timestamp::now();
+        let snapshot = vec![
+            JournalEntry {
+                t,
+                op: JournalOp::RestoreStats {
+                    stats: stats.snapshot(),
+                },
+            },
+            JournalEntry {
+                t,
+                op: JournalOp::RestoreLocks {
+                    locks: kv.snapshot(now),
+                },
+            },
+        ];
+        let entry = JournalEntry {
+            t,
+            op: JournalOp::Acquire {
+                keys: vec!["j2".into()],
+                holder: None,
+                fencing_token: token + 1,
+                expires_t,
+            },
+        };
+        let stream = event("snapshot", &snapshot) + &event("entry", &entry);
+
+        let mut follower_stats = WorkerStats::new();
+        let follower = LockShards::new(LockTable::new(16), 2);
+        let mut follower_kv = follower.lock_all().await;
+        for event in stream.split_inclusive("\n\n") {
+            let entries = match parse_event(event) {
+                ("snapshot", data) => serde_json::from_str::<Vec<JournalEntry>>(&data).unwrap(),
+                ("entry", data) => vec![serde_json::from_str(&data).unwrap()],
+                other => panic!("unexpected event {other:?}"),
+            };
+            for entry in entries {
+                assert!(journal::apply(entry, &mut follower_stats, &mut follower_kv));
+            }
+        }
+
+        let now = Instant::now();
+        let lease = follower_kv.lease("j1", now).unwrap();
+        assert_eq!(lease.fencing_token, token);
+        assert_eq!(lease.holder.as_ref(), Some(&worker_id));
+        assert_eq!(
+            follower_kv.lease("j2", now).unwrap().fencing_token,
+            token + 1
+        );
+        let state = follower_stats.latest(&worker_id).unwrap();
+        assert_eq!(state.kind(), stats.latest(&worker_id).unwrap().kind());
+        // a follower taking over issues tokens after the replicated ones.
+        let lock = JobLock::new(now + Duration::from_secs(60), None);
+        let next = follower_kv.try_acquire_all(vec!["j3".into()], lock, now);
+        assert!(next.unwrap() > token + 1);
+    }
+
+    #[test]
+    fn leaders_campaign_until_their_lease_runs_out() {
+        let ttl = Duration::from_secs(9);
+        let renewed_at = Instant::now();
+        let now = renewed_at + Duration::from_secs(4);
+        assert_eq!(
+            campaign_timeout(Some(renewed_at), ttl, now),
+            Duration::from_secs(5)
+        );
+        let now = renewed_at + Duration::from_secs(10);
+        assert_eq!(campaign_timeout(Some(renewed_at), ttl, now), Duration::ZERO);
+        assert_eq!(campaign_timeout(None, ttl, now), ttl);
+    }
+}
@@ -0,0 +1,78 @@
+//! Hourly aggregates of the states compacted out of the workers'
+//! histories with `--compact-after`, so long-term trends outlive the
+//! states at bounded memory. Served at `GET /summary/hourly`.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+pub const HOUR_MS: u64 = 3_600_000;
+/// Hours of aggregates kept, the oldest dropped first.
+const MAX_HOURS: usize = 24 * 365;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct KindAggregate {
+    /// Lifecycles, counting collapsed errors.
+    pub count: u64,
+    /// Sum of the states' durations from start to end.
+    pub duration_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HourAggregate {
+    /// Start of the hour the states ended in.
+    pub hour_t: u64,
+    /// By state kind.
+    pub kinds: BTreeMap<String, KindAggregate>,
+}
+
+/// Compacted states of all workers per hour they ended in.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HourlyAggregates {
+    hours: BTreeMap<u64, BTreeMap<String, KindAggregate>>,
+}
+
+impl HourlyAggregates {
+    pub fn fold(&mut self, state: &SnarkWorkerState) {
+        let end_t = state.end_time();
+        let hour = self.hours.entry(end_t - end_t % HOUR_MS).or_default();
+        let kind = hour.entry(state.kind().to_owned()).or_default();
+        kind.count += state.count();
+        kind.duration_ms += end_t.saturating_sub(state.start_time());
+        if self.hours.len() > MAX_HOURS {
+            self.hours.pop_first();
+        }
+    }
+
+    /// Adds the aggregates of `other`, e.g. of a restored snapshot.
+    pub fn merge(&mut self, other: HourlyAggregates) {
+        for (hour_t, kinds) in other.hours {
+            let hour = self.hours.entry(hour_t).or_default();
+            for (kind, aggregate) in kinds {
+                let sum = hour.entry(kind).or_default();
+                sum.count += aggregate.count;
+                sum.duration_ms += aggregate.duration_ms;
+            }
+        }
+        while self.hours.len() > MAX_HOURS {
+            self.hours.pop_first();
+        }
+    }
+
+    /// Hours overlapping `from_t..=to_t`, oldest first.
+    pub fn range(&self, from_t: Option<u64>, to_t: Option<u64>) -> Vec<HourAggregate> {
+        let from_t = from_t.map_or(0, |t| t - t % HOUR_MS);
+        let to_t = to_t.unwrap_or(u64::MAX);
+        if from_t > to_t {
+            return vec![];
+        }
+        (self.hours.range(from_t..=to_t))
+            .map(|(hour_t, kinds)| HourAggregate {
+                hour_t: *hour_t,
+                kinds: kinds.clone(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,96 @@
+use std::{
+    collections::HashMap,
+    sync::atomic::{AtomicBool, Ordering},
+};
+
+use serde::Deserialize;
+
+/// Rewrites of legacy request paths and methods, loaded from
+/// `--compat-file`. Lets old worker scripts keep working against a newer
+/// coordinator.
+///
+/// ```json
+/// {
+///   "strip_trailing_slash": true,
+///   "aliases": [
+///     { "from": "/worker_stats", "to": "/worker-stats", "methods": { "POST": "PUT" } },
+///     { "from": "/lock-job", "to": "/lock-job", "methods": { "POST": "PUT" } }
+///   ]
+/// }
+/// ```
+///
+/// `from` matches whole path segments, so `/worker_stats` applies to
+/// `/worker_stats/{id}` but not to `/worker_statsx`. The first matching
+/// alias wins.
+#[derive(Deserialize, Debug, Default)]
+pub struct CompatConfig {
+    #[serde(default)]
+    pub strip_trailing_slash: bool,
+    #[serde(default)]
+    pub aliases: Vec<PathAlias>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct PathAlias {
+    pub from: String,
+    pub to: String,
+    /// Legacy method -> method to serve the request as.
+    #[serde(default)]
+    pub methods: HashMap<String, String>,
+    /// Whether the deprecation warning was already logged.
+    #[serde(skip)]
+    warned: AtomicBool,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Rewrite {
+    pub method: String,
+    pub path: String,
+}
+
+impl CompatConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        serde_json::from_str(&s).map_err(|err| format!("{path}: {err}"))
+    }
+
+    /// Returns the method and path the request should be served as, or
+    /// `None` if it doesn't need rewriting.
+    pub fn rewrite(&self, method: &str, path: &str) -> Option<Rewrite> {
+        let mut new_path = path;
+        if self.strip_trailing_slash && path.len() > 1 {
+            new_path = path.trim_end_matches('/');
+        }
+        let alias = self.aliases.iter().find_map(|alias| {
+            let rest = new_path.strip_prefix(alias.from.as_str())?;
+            (rest.is_empty() || rest.starts_with('/')).then_some((alias, rest))
+        });
+        let rewrite = match alias {
+            Some((alias, rest)) => {
+                let method = alias.methods.get(method).map_or(method, |m| m.as_str());
+                Rewrite {
+                    method: method.to_owned(),
+                    path: format!("{}{rest}", alias.to),
+                }
+            }
+            None => Rewrite {
+                method: method.to_owned(),
+                path: new_path.to_owned(),
+            },
+        };
+        if rewrite.method == method && rewrite.path == path {
+            return None;
+        }
+        if let Some((alias, _)) = alias {
+            if !alias.warned.swap(true, Ordering::Relaxed) {
+                tracing::warn!(
+                    from = %alias.from,
+                    to = %alias.to,
+                    "deprecated request path or method in use, further uses are logged at debug level"
+                );
+            }
+        }
+        tracing::debug!(%method, %path, ?rewrite, "rewriting legacy request");
+        Some(rewrite)
+    }
+}
@@ -0,0 +1,173 @@
+use std::io::Write;
+
+use flate2::{
+    write::{GzEncoder, ZlibEncoder},
+    Compression,
+};
+use warp::hyper::{
+    body::{to_bytes, Bytes, HttpBody},
+    header::{HeaderValue, ACCEPT_ENCODING, CONTENT_ENCODING, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+    Body, Method, Request, Response,
+};
+
+/// Responses smaller than this aren't worth compressing.
+const MIN_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Encoding {
+    Gzip,
+    Deflate,
+}
+
+impl Encoding {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Deflate => "deflate",
+        }
+    }
+}
+
+/// Encoding to compress the response to `req` with, based on its
+/// `Accept-Encoding`. Only GET responses are compressed, gzip is
+/// preferred over deflate at equal quality.
+pub fn negotiate(req: &Request<Body>) -> Option<Encoding> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let accept = req.headers().get(ACCEPT_ENCODING)?.to_str().ok()?;
+    let mut best: Option<(Encoding, f32)> = None;
+    for item in accept.split(',') {
+        let mut parts = item.split(';');
+        let encoding = match parts.next().unwrap_or("").trim() {
+            e if e.eq_ignore_ascii_case("gzip") || e == "*" => Encoding::Gzip,
+            e if e.eq_ignore_ascii_case("deflate") => Encoding::Deflate,
+            _ => continue,
+        };
+        let q = parts
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let better = match best {
+            None => true,
+            Some((best, best_q)) => q > best_q || (q == best_q && best == Encoding::Deflate),
+        };
+        if q > 0.0 && better {
+            best = Some((encoding, q));
+        }
+    }
+    best.map(|(encoding, _)| encoding)
+}
+
+/// Compresses the body of successful, not already encoded responses.
+/// Event streams are left alone, the encoder would hold back their events
+/// until enough of them arrived.
+pub async fn compress(res: Response<Body>, encoding: Encoding) -> Response<Body> {
+    let is_event_stream = res
+        .headers()
+        .get(CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().starts_with(b"text/event-stream"));
+    if !res.status().is_success() || res.headers().contains_key(CONTENT_ENCODING) || is_event_stream
+    {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    if body.size_hint().exact().is_none() {
+        parts
+            .headers
+            .append(VARY, HeaderValue::from_static("accept-encoding"));
+        parts.headers.remove(CONTENT_LENGTH);
+        parts.headers.insert(
+            CONTENT_ENCODING,
+            HeaderValue::from_static(encoding.as_str()),
+        );
+        return Response::from_parts(parts, compress_stream(body, encoding));
+    }
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::warn!(%err, "failed to read response body");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept-encoding"));
+    if body.len() < MIN_SIZE {
+        return Response::from_parts(parts, Body::from(body));
+    }
+    // large responses take a while to compress, keep that off the runtime.
+    let compressed = tokio::task::spawn_blocking(move || match encoding {
+        Encoding::Gzip => {
+            let mut encoder = GzEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(&body).and_then(|_| encoder.finish())
+        }
+        Encoding::Deflate => {
+            let mut encoder = ZlibEncoder::new(Vec::new(), Compression::fast());
+            encoder.write_all(&body).and_then(|_| encoder.finish())
+        }
+    })
+    .await
+    .expect("compression task panicked")
+    .expect("writing to a Vec can't fail");
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_ENCODING,
+        HeaderValue::from_static(encoding.as_str()),
+    );
+    Response::from_parts(parts, Body::from(compressed))
+}
+
+enum StreamEncoder {
+    Gzip(GzEncoder<Vec<u8>>),
+    Deflate(ZlibEncoder<Vec<u8>>),
+}
+
+impl StreamEncoder {
+    /// Compresses `chunk` and takes the output produced so far.
+    fn write(&mut self, chunk: &[u8]) -> Vec<u8> {
+        let out = match self {
+            Self::Gzip(encoder) => encoder.write_all(chunk).map(|_| encoder.get_mut()),
+            Self::Deflate(encoder) => encoder.write_all(chunk).map(|_| encoder.get_mut()),
+        };
+        std::mem::take(out.expect("writing to a Vec can't fail"))
+    }
+
+    fn finish(self) -> Vec<u8> {
+        match self {
+            Self::Gzip(encoder) => encoder.finish(),
+            Self::Deflate(encoder) => encoder.finish(),
+        }
+        .expect("writing to a Vec can't fail")
+    }
+}
+
+/// Compresses a streamed body chunk by chunk, keeping it streamed.
+/// Chunks are small, so they're compressed on the runtime.
+fn compress_stream(mut body: Body, encoding: Encoding) -> Body {
+    let mut encoder = match encoding {
+        Encoding::Gzip => StreamEncoder::Gzip(GzEncoder::new(Vec::new(), Compression::fast())),
+        Encoding::Deflate => {
+            StreamEncoder::Deflate(ZlibEncoder::new(Vec::new(), Compression::fast()))
+        }
+    };
+    let (mut tx, compressed) = Body::channel();
+    tokio::spawn(async move {
+        while let Some(chunk) = body.data().await {
+            let chunk = match chunk {
+                Ok(chunk) => chunk,
+                Err(err) => {
+                    tracing::warn!(%err, "failed to read response body");
+                    tx.abort();
+                    return;
+                }
+            };
+            let out = encoder.write(&chunk);
+            if !out.is_empty() && tx.send_data(Bytes::from(out)).await.is_err() {
+                return;
+            }
+        }
+        let _ = tx.send_data(Bytes::from(encoder.finish())).await;
+    });
+    compressed
+}
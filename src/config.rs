@@ -0,0 +1,254 @@
+//! Options from a `--config` TOML file and `SNARK_COORDINATOR_*`
+//! environment variables, so deployments can be managed declaratively.
+//! They're turned into the flags they stand for and put in front of the
+//! command line, whose flags override them as every option overrides
+//! earlier occurrences of itself.
+//!
+//! Keys are option names, with tables prefixing their keys:
+//!
+//! ```toml
+//! port = 8080
+//! max_key_len = 200
+//! strict = true
+//!
+//! [tls]
+//! cert = "/etc/coordinator/cert.pem"
+//! key = "/etc/coordinator/key.pem"
+//! ```
+//!
+//! is `--port 8080 --max-key-len 200 --strict --tls-cert .. --tls-key ..`,
+//! as are `SNARK_COORDINATOR_PORT=8080`, `SNARK_COORDINATOR_TLS_CERT=..`
+//! and so on, which in turn override the file. Flags are set by `true`
+//! and unset by `false`, on the command line by `--no-<flag>`. Keys and
+//! variables which aren't options of the server are errors.
+
+use std::{collections::BTreeMap, ffi::OsString, path::PathBuf};
+
+use structopt::clap::App;
+
+/// Prefix of environment variables setting options.
+pub const ENV_PREFIX: &str = "SNARK_COORDINATOR_";
+/// Environment variable with the path of the config file, if there's no
+/// `--config`.
+pub const CONFIG_ENV: &str = "SNARK_COORDINATOR_CONFIG";
+
+/// Value of an option set by the file or environment.
+#[derive(Debug, Clone)]
+enum Setting {
+    Flag(bool),
+    Value(OsString),
+}
+
+/// Command line arguments of the process, with flags for the options of
+/// `app` set by the config file and environment put in front.
+pub fn args(app: &App) -> Result<Vec<OsString>, String> {
+    let mut args = std::env::args_os();
+    let bin = args.next().unwrap_or_default();
+    let args = args.collect::<Vec<_>>();
+
+    let table = match config_path(&args) {
+        Some(path) => {
+            let s = std::fs::read_to_string(&path)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            let table = s
+                .parse::<toml::Table>()
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+            Some((path, table))
+        }
+        None => None,
+    };
+    let mut vars = std::env::vars()
+        .filter(|(name, _)| name.starts_with(ENV_PREFIX) && name != CONFIG_ENV)
+        .collect::<Vec<_>>();
+    vars.sort();
+
+    let options = options(app);
+    let mut settings = BTreeMap::new();
+    if let Some((path, table)) = &table {
+        for (key, value) in table {
+            push_value(&mut settings, &options, key, value)
+                .map_err(|err| format!("{}: {err}", path.display()))?;
+        }
+    }
+    for (name, value) in vars {
+        let key = name[ENV_PREFIX.len()..].to_lowercase();
+        let setting = match options.get(&key) {
+            None => return Err(format!("{name}: unknown option {key}")),
+            Some(true) => Setting::Value(value.into()),
+            Some(false) => match value.as_str() {
+                "true" => Setting::Flag(true),
+                "false" => Setting::Flag(false),
+                _ => return Err(format!("{name}: {key} is a flag, set by true or false")),
+            },
+        };
+        settings.insert(key, setting);
+    }
+    Ok(flags(bin, settings, &options, args))
+}
+
+/// Long names of the options of `app`, other than `config`, with whether
+/// they take a value.
+fn options(app: &App) -> BTreeMap<String, bool> {
+    // clap 2 has no public accessor of an app's arguments, `p` is public
+    // but hidden from its docs.
+    let flags = app.p.flags.iter().map(|f| (f.s.long, false));
+    let opts = app.p.opts.iter().map(|o| (o.s.long, true));
+    flags
+        .chain(opts)
+        .filter_map(|(long, takes_value)| Some((long?.replace('-', "_"), takes_value)))
+        .filter(|(key, _)| !["config", "help", "version"].contains(&key.as_str()))
+        .collect()
+}
+
+/// `args` behind the flags of `settings`. `--no-<flag>` in `args` unsets
+/// the flag instead of being passed on.
+fn flags(
+    bin: OsString,
+    mut settings: BTreeMap<String, Setting>,
+    options: &BTreeMap<String, bool>,
+    args: Vec<OsString>,
+) -> Vec<OsString> {
+    let mut rest = Vec::with_capacity(args.len());
+    let mut args = args.into_iter();
+    for arg in args.by_ref() {
+        if arg == "--" {
+            rest.push(arg);
+            break;
+        }
+        let unset = arg
+            .to_str()
+            .and_then(|arg| arg.strip_prefix("--no-"))
+            .map(|name| name.replace('-', "_"))
+            .filter(|key| options.get(key) == Some(&false));
+        match unset {
+            Some(key) => {
+                settings.insert(key, Setting::Flag(false));
+            }
+            None => rest.push(arg),
+        }
+    }
+    rest.extend(args);
+
+    let mut flags = vec![bin];
+    for (key, setting) in settings {
+        match setting {
+            Setting::Flag(true) => flags.push(flag(&key)),
+            Setting::Flag(false) => {}
+            Setting::Value(value) => flags.extend([flag(&key), value]),
+        }
+    }
+    flags.extend(rest);
+    flags
+}
+
+/// Path given by `--config`, or else by `SNARK_COORDINATOR_CONFIG`.
+fn config_path(args: &[OsString]) -> Option<PathBuf> {
+    let mut args = args.iter();
+    while let Some(arg) = args.next() {
+        let arg = arg.to_string_lossy();
+        if arg == "--config" {
+            return args.next().map(PathBuf::from);
+        }
+        if let Some(path) = arg.strip_prefix("--config=") {
+            return Some(path.into());
+        }
+    }
+    std::env::var_os(CONFIG_ENV).map(PathBuf::from)
+}
+
+fn push_value(
+    settings: &mut BTreeMap<String, Setting>,
+    options: &BTreeMap<String, bool>,
+    key: &str,
+    value: &toml::Value,
+) -> Result<(), String> {
+    if let toml::Value::Table(table) = value {
+        for (sub_key, value) in table {
+            push_value(settings, options, &format!("{key}_{sub_key}"), value)?;
+        }
+        return Ok(());
+    }
+    let Some(&takes_value) = options.get(key) else {
+        return Err(format!("unknown option {key}"));
+    };
+    let setting = match value {
+        toml::Value::Boolean(set) if !takes_value => Setting::Flag(*set),
+        _ if !takes_value => return Err(format!("{key} is a flag, set by true or false")),
+        toml::Value::String(s) => Setting::Value(s.into()),
+        toml::Value::Integer(n) => Setting::Value(n.to_string().into()),
+        toml::Value::Float(n) => Setting::Value(n.to_string().into()),
+        toml::Value::Boolean(_) | toml::Value::Datetime(_) | toml::Value::Array(_) => {
+            return Err(format!("unsupported value of {key}: {value}"));
+        }
+        toml::Value::Table(_) => unreachable!(),
+    };
+    settings.insert(key.to_owned(), setting);
+    Ok(())
+}
+
+fn flag(key: &str) -> OsString {
+    format!("--{}", key.replace('_', "-")).into()
+}
+
+#[cfg(test)]
+mod tests {
+    use structopt::clap::Arg;
+
+    use super::*;
+
+    fn app() -> App<'static, 'static> {
+        App::new("test")
+            .arg(Arg::with_name("config").long("config").takes_value(true))
+            .arg(Arg::with_name("port").long("port").takes_value(true))
+            .arg(
+                Arg::with_name("tls-cert")
+                    .long("tls-cert")
+                    .takes_value(true),
+            )
+            .arg(Arg::with_name("strict").long("strict"))
+    }
+
+    fn parse(toml: &str) -> Result<BTreeMap<String, Setting>, String> {
+        let options = options(&app());
+        let mut settings = BTreeMap::new();
+        for (key, value) in &toml.parse::<toml::Table>().unwrap() {
+            push_value(&mut settings, &options, key, value)?;
+        }
+        Ok(settings)
+    }
+
+    #[test]
+    fn maps_known_options_only() {
+        let settings = parse("port = 8080\nstrict = true\n[tls]\ncert = \"c.pem\"").unwrap();
+        let args = flags("bin".into(), settings, &options(&app()), vec![]);
+        let expected = ["bin", "--port", "8080", "--strict", "--tls-cert", "c.pem"];
+        assert_eq!(args, expected.map(OsString::from));
+
+        assert_eq!(parse("prot = 8080").unwrap_err(), "unknown option prot");
+        assert_eq!(
+            parse("config = \"a.toml\"").unwrap_err(),
+            "unknown option config"
+        );
+        assert!(parse("strict = 1").is_err());
+        assert!(parse("port = true").is_err());
+    }
+
+    #[test]
+    fn command_line_unsets_flags() {
+        let options = options(&app());
+        let settings = parse("strict = true\nport = 1").unwrap();
+        let args = ["--no-strict", "--port", "2"].map(OsString::from).to_vec();
+        let args = flags("bin".into(), settings, &options, args);
+        let expected = ["bin", "--port", "1", "--port", "2"];
+        assert_eq!(args, expected.map(OsString::from));
+
+        // not a flag, so left for clap to refuse.
+        let args = flags(
+            "bin".into(),
+            BTreeMap::new(),
+            &options,
+            vec!["--no-port".into()],
+        );
+        assert_eq!(args, ["bin", "--no-port"].map(OsString::from));
+    }
+}
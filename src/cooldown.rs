@@ -0,0 +1,76 @@
+//! Cooldowns of workers failing job after job with `--cooldown-errors`,
+//! e.g. a prover crash-looping on `WorkCreateError`. While cooling down a
+//! worker is refused locks and jobs, which go to healthy workers instead.
+
+use std::collections::{HashMap, VecDeque};
+
+use crate::stats::SnarkWorkerState;
+
+#[derive(Debug, Clone, Copy)]
+pub struct CooldownConfig {
+    /// Failures within `window_ms` starting a cooldown.
+    pub max_errors: usize,
+    pub window_ms: u64,
+    pub cooldown_ms: u64,
+}
+
+/// Recent failures of each worker and the cooldowns they started.
+#[derive(Debug)]
+pub struct Cooldowns {
+    config: CooldownConfig,
+    /// Times of failures within the window, oldest first.
+    errors: HashMap<String, VecDeque<u64>>,
+    /// End of cooldowns, which may have passed already.
+    until: HashMap<String, u64>,
+}
+
+/// Whether the state is a job lifecycle which failed after receiving
+/// its job.
+fn is_failure(state: &SnarkWorkerState) -> bool {
+    matches!(
+        state.kind(),
+        "WorkCreateError" | "WorkCreateTimeout" | "WorkSubmitError" | "WorkSubmitTimeout"
+    )
+}
+
+impl Cooldowns {
+    pub fn new(config: CooldownConfig) -> Self {
+        Self {
+            config,
+            errors: HashMap::new(),
+            until: HashMap::new(),
+        }
+    }
+
+    /// Notes the worker's latest state. Returns the end of the cooldown
+    /// it started, if it did.
+    pub fn observe(&mut self, worker_id: &str, state: &SnarkWorkerState, now: u64) -> Option<u64> {
+        if !is_failure(state) {
+            return None;
+        }
+        let errors = self.errors.entry(worker_id.to_owned()).or_default();
+        errors.push_back(now);
+        let min_t = now.saturating_sub(self.config.window_ms);
+        while errors.front().is_some_and(|t| *t < min_t) {
+            errors.pop_front();
+        }
+        if errors.len() < self.config.max_errors {
+            return None;
+        }
+        // failures so far are paid for by this cooldown.
+        errors.clear();
+        let until = now + self.config.cooldown_ms;
+        self.until.insert(worker_id.to_owned(), until);
+        Some(until)
+    }
+
+    /// End of the worker's cooldown, `None` if it isn't cooling down.
+    pub fn until(&self, worker_id: &str, now: u64) -> Option<u64> {
+        self.until.get(worker_id).copied().filter(|t| *t > now)
+    }
+
+    pub fn forget(&mut self, worker_id: &str) {
+        self.errors.remove(worker_id);
+        self.until.remove(worker_id);
+    }
+}
@@ -0,0 +1,143 @@
+//! CORS, so browser-based dashboards on other origins can read the API.
+//! Only reads are allowed unless `--cors-allow-writes`, in which case
+//! preflight requests of mutating ones pass too.
+
+use std::str::FromStr;
+
+use snark_coordinator_rs::{
+    errors::{ApiError, ErrorCode},
+    idempotency, lock_namespaces, worker_tokens,
+};
+use warp::{
+    cors::{Builder, CorsForbidden},
+    hyper::{Method, StatusCode, Uri},
+    path::FullPath,
+    reject::{Reject, Rejection},
+    reply::{with_status, Reply, Response},
+    Filter,
+};
+
+use crate::auth;
+
+/// Origins allowed by `--cors-origins`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CorsOrigins {
+    Any,
+    List(Vec<String>),
+}
+
+impl FromStr for CorsOrigins {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if s == "any" {
+            return Ok(Self::Any);
+        }
+        let origins = s
+            .split(',')
+            .map(str::trim)
+            .filter(|origin| !origin.is_empty())
+            .map(|origin| {
+                let uri = origin.parse::<Uri>().ok();
+                match uri.filter(|uri| {
+                    uri.scheme().is_some()
+                        && uri.authority().is_some()
+                        && !origin.ends_with('/')
+                        && uri.path_and_query().is_none_or(|p| p.as_str() == "/")
+                }) {
+                    Some(_) => Ok(origin.to_owned()),
+                    None => Err(format!(
+                        "invalid origin {origin:?}, expected e.g. `https://example.com`"
+                    )),
+                }
+            })
+            .collect::<Result<Vec<_>, _>>()?;
+        if origins.is_empty() {
+            return Err("expected `any` or comma separated origins".to_owned());
+        }
+        Ok(Self::List(origins))
+    }
+}
+
+/// Headers browsers may send, which are the ones of the worker protocol.
+const ALLOWED_HEADERS: &[&str] = &[
+    "authorization",
+    "content-type",
+    "content-encoding",
+    "if-none-match",
+    "traceparent",
+    "x-worker-id",
+    idempotency::HEADER,
+    lock_namespaces::HEADER,
+    worker_tokens::HEADER,
+];
+
+/// Response headers scripts may read.
+const EXPOSED_HEADERS: &[&str] = &[
+    "etag",
+    "x-api-version",
+    "x-stats-version",
+    "retry-after",
+    idempotency::REPLAYED_HEADER,
+    worker_tokens::HEADER,
+];
+
+pub fn cors(origins: &CorsOrigins, allow_writes: bool) -> Builder {
+    let cors = warp::cors()
+        .allow_methods([Method::GET, Method::HEAD])
+        .allow_headers(ALLOWED_HEADERS.iter().copied())
+        .expose_headers(EXPOSED_HEADERS.iter().copied());
+    let cors = match allow_writes {
+        true => cors.allow_methods([Method::PUT, Method::POST, Method::DELETE]),
+        false => cors,
+    };
+    match origins {
+        CorsOrigins::Any => cors.allow_any_origin(),
+        CorsOrigins::List(origins) => cors.allow_origins(origins.iter().map(String::as_str)),
+    }
+}
+
+/// Refuses cross-origin requests which are writes despite their method,
+/// see [`auth::access_method`], unless `allow_writes`. The [`cors`]
+/// filter only knows methods.
+pub fn writes(allow_writes: bool) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(warp::header::optional::<String>("origin"))
+        .and_then(
+            move |method: Method, path: FullPath, origin: Option<String>| async move {
+                let reads = matches!(method, Method::GET | Method::HEAD | Method::OPTIONS);
+                let method = auth::access_method(method, path.as_str());
+                let writes = !matches!(method, Method::GET | Method::HEAD | Method::OPTIONS);
+                match origin {
+                    Some(_) if reads && writes && !allow_writes => {
+                        Err(warp::reject::custom(WriteForbidden))
+                    }
+                    _ => Ok(()),
+                }
+            },
+        )
+        .untuple_one()
+}
+
+#[derive(Debug)]
+struct WriteForbidden;
+
+impl Reject for WriteForbidden {}
+
+/// Turns rejections of [`cors`] and [`writes`] into 403 responses with a
+/// JSON body.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    let msg = match (
+        rejection.find::<CorsForbidden>(),
+        rejection.find::<WriteForbidden>(),
+    ) {
+        (Some(err), _) => err.to_string(),
+        (None, Some(WriteForbidden)) => "cross-origin writes aren't allowed".to_owned(),
+        (None, None) => return Err(rejection),
+    };
+    tracing::debug!(%msg, "cross-origin request denied");
+    let body = ApiError::new(ErrorCode::Forbidden, msg);
+    let body = serde_json::to_string(&body).unwrap();
+    Ok(with_status(body, StatusCode::FORBIDDEN).into_response())
+}
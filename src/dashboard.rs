@@ -0,0 +1,14 @@
+//! Status dashboard served at `/`, so operators can see the fleet without
+//! curling JSON: workers and their current state, recent errors, held
+//! locks and throughput. It's a static page polling the API, which asks
+//! for an API key if reads need one.
+
+use warp::{reply::Response, Filter, Reply};
+
+const PAGE: &str = include_str!("dashboard.html");
+
+pub fn route() -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    warp::path::end().and(warp::get()).map(|| {
+        warp::reply::with_header(PAGE, "content-type", "text/html; charset=utf-8").into_response()
+    })
+}
@@ -0,0 +1,203 @@
+//! `diff` subcommand comparing two coordinators, or snapshots of them, to
+//! validate replication, migrations and blue-green swaps before cutover.
+
+use std::{collections::BTreeMap, fmt::Display, path::PathBuf};
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client,
+};
+use serde::{de::DeserializeOwned, Deserialize, Serialize};
+use snark_coordinator_rs::stats::{Lease, SnarkWorkerState};
+
+/// Differences listed per section, the rest are only counted.
+const MAX_LISTED: usize = 20;
+
+/// What's compared of a coordinator.
+#[derive(Serialize, Deserialize, Debug, Default)]
+struct Snapshot {
+    /// Current state of each worker.
+    workers: BTreeMap<String, SnarkWorkerState>,
+    /// Leases of the held locks, by key.
+    locks: BTreeMap<String, Lease>,
+}
+
+/// Response of worker-stats GET with `per_worker_limit`.
+#[derive(Deserialize)]
+struct StatsPage {
+    workers: BTreeMap<String, Vec<SnarkWorkerState>>,
+}
+
+/// Loads a snapshot of `source`, a coordinator's base URL or the path of
+/// a snapshot file.
+async fn load(client: &Client, source: &str) -> Result<Snapshot, String> {
+    if !source.starts_with("http://") && !source.starts_with("https://") {
+        let file = std::fs::read(source).map_err(|err| format!("{source}: {err}"))?;
+        return serde_json::from_slice(&file).map_err(|err| format!("{source}: {err}"));
+    }
+    let url = source.trim_end_matches('/');
+    let stats: StatsPage = get(client, &format!("{url}/worker-stats?per_worker_limit=1")).await?;
+    let workers = stats
+        .workers
+        .into_iter()
+        .filter_map(|(worker_id, states)| Some((worker_id, states.into_iter().next()?)))
+        .collect();
+    let locks = get(client, &format!("{url}/lock-jobs")).await?;
+    Ok(Snapshot { workers, locks })
+}
+
+async fn get<T: DeserializeOwned>(client: &Client, url: &str) -> Result<T, String> {
+    let res = client
+        .get(url)
+        .send()
+        .await
+        .map_err(|err| format!("{url}: {err}"))?;
+    let status = res.status();
+    let body = res.text().await.map_err(|err| format!("{url}: {err}"))?;
+    if !status.is_success() {
+        return Err(format!("{url}: unexpected status {status}: {body}"));
+    }
+    serde_json::from_str(&body).map_err(|err| format!("{url}: {err}"))
+}
+
+/// Prints a section of differences, returns their number.
+fn section(title: &str, diffs: Vec<impl Display>) -> usize {
+    if diffs.is_empty() {
+        return 0;
+    }
+    println!("{title}: {}", diffs.len());
+    for diff in diffs.iter().take(MAX_LISTED) {
+        println!("  {diff}");
+    }
+    if diffs.len() > MAX_LISTED {
+        println!("  ... and {} more", diffs.len() - MAX_LISTED);
+    }
+    diffs.len()
+}
+
+/// Entries which only one side has, and those both have but differ.
+struct Comparison<'a, T> {
+    only_left: Vec<&'a str>,
+    only_right: Vec<&'a str>,
+    differing: Vec<(&'a str, &'a T, &'a T)>,
+}
+
+/// Compares the entries of `left` and `right`, which differ unless `eq`.
+fn compare<'a, T>(
+    left: &'a BTreeMap<String, T>,
+    right: &'a BTreeMap<String, T>,
+    eq: impl Fn(&T, &T) -> bool,
+) -> Comparison<'a, T> {
+    let only = |a: &'a BTreeMap<String, T>, b: &BTreeMap<String, T>| {
+        a.keys()
+            .filter(|k| !b.contains_key(*k))
+            .map(String::as_str)
+            .collect()
+    };
+    Comparison {
+        only_left: only(left, right),
+        only_right: only(right, left),
+        differing: left
+            .iter()
+            .filter_map(|(k, l)| Some((k.as_str(), l, right.get(k)?)))
+            .filter(|(_, l, r)| !eq(l, r))
+            .collect(),
+    }
+}
+
+/// Compares `left` and `right` and prints their differences. Returns
+/// `true` if there are none.
+pub async fn run(
+    left: String,
+    right: String,
+    api_key: Option<String>,
+    save: Option<PathBuf>,
+) -> bool {
+    let mut headers = HeaderMap::new();
+    if let Some(key) = api_key {
+        match HeaderValue::from_str(&format!("Bearer {key}")) {
+            Ok(value) => headers.insert(AUTHORIZATION, value),
+            Err(err) => {
+                eprintln!("invalid api key: {err}");
+                return false;
+            }
+        };
+    }
+    let client = match Client::builder().default_headers(headers).build() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("failed to build http client: {err}");
+            return false;
+        }
+    };
+    let (l, r) = match tokio::try_join!(load(&client, &left), load(&client, &right)) {
+        Ok(snapshots) => snapshots,
+        Err(err) => {
+            eprintln!("failed to load snapshot: {err}");
+            return false;
+        }
+    };
+    if let Some(path) = save {
+        let saved = serde_json::to_vec(&l)
+            .map_err(|err| err.to_string())
+            .and_then(|json| std::fs::write(&path, json).map_err(|err| err.to_string()));
+        if let Err(err) = saved {
+            eprintln!("failed to save snapshot to {}: {err}", path.display());
+            return false;
+        }
+    }
+
+    println!(
+        "left:  {left} ({} workers, {} locks)",
+        l.workers.len(),
+        l.locks.len()
+    );
+    println!(
+        "right: {right} ({} workers, {} locks)",
+        r.workers.len(),
+        r.locks.len()
+    );
+    let mut diffs = 0;
+
+    // states have no `PartialEq`, their serialized forms are compared.
+    let workers = compare(&l.workers, &r.workers, |a, b| {
+        serde_json::to_value(a).ok() == serde_json::to_value(b).ok()
+    });
+    diffs += section("workers only in left", workers.only_left);
+    diffs += section("workers only in right", workers.only_right);
+    let differing = workers
+        .differing
+        .into_iter()
+        .map(|(worker_id, a, b)| {
+            let (a_t, b_t) = (a.end_time(), b.end_time());
+            format!(
+                "{worker_id}: {} at {a_t} vs {} at {b_t}",
+                a.kind(),
+                b.kind()
+            )
+        })
+        .collect();
+    diffs += section("workers in different states", differing);
+
+    // expiry differs with every renewal, so only who holds which lock
+    // under which token is compared.
+    let locks = compare(&l.locks, &r.locks, |a, b| {
+        a.holder == b.holder && a.fencing_token == b.fencing_token
+    });
+    diffs += section("locks only in left", locks.only_left);
+    diffs += section("locks only in right", locks.only_right);
+    let differing = locks
+        .differing
+        .into_iter()
+        .map(|(key, a, b)| {
+            format!(
+                "{key}: {:?} with token {} vs {:?} with token {}",
+                a.holder, a.fencing_token, b.holder, b.fencing_token
+            )
+        })
+        .collect();
+    diffs += section("locks held differently", differing);
+
+    println!("{diffs} differences");
+    diffs == 0
+}
@@ -0,0 +1,182 @@
+use std::collections::{BTreeMap, BTreeSet, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{SnarkWorkerState, WorkerMetadata};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct DomainStats {
+    pub workers: usize,
+    /// Distinct jobs received by workers of this domain.
+    pub jobs: usize,
+    /// Jobs which were also received by some other worker.
+    pub duplicate_jobs: usize,
+    /// Duplicate jobs which were received in more than one datacenter.
+    pub cross_datacenter_duplicate_jobs: usize,
+    /// Failed lock acquisition attempts by workers of this domain.
+    pub lock_conflicts: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FailureDomainReport {
+    pub duplicate_jobs: usize,
+    pub cross_datacenter_duplicate_jobs: usize,
+    pub datacenters: BTreeMap<String, DomainStats>,
+    pub hosts: BTreeMap<String, DomainStats>,
+}
+
+const UNKNOWN: &str = "unknown";
+
+/// Groups duplicate work and lock contention by the datacenter and host
+/// workers registered with in their [`WorkerMetadata`], `unknown` for
+/// workers which didn't tell.
+///
+/// Lock conflicts are keyed by the `worker_id` given on lock-job PUT,
+/// which is either a session id or the `name` its sessions share.
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, impl IntoIterator<Item = &'a SnarkWorkerState>)>,
+    name: impl Fn(&str) -> Option<&'a str>,
+    lock_conflicts: &HashMap<String, u64>,
+) -> FailureDomainReport {
+    let mut report = FailureDomainReport::default();
+    let mut domains = HashMap::<&str, (&str, &str)>::new();
+    let mut jobs = BTreeMap::<&str, BTreeSet<&str>>::new();
+
+    for (worker_id, states) in stats {
+        let mut metadata = None;
+        for state in states {
+            metadata = state.metadata().or(metadata);
+            if let Some(ids) = state.ids() {
+                jobs.entry(ids).or_default().insert(worker_id);
+            }
+        }
+        let (datacenter, host) = domain(metadata);
+        domains.insert(worker_id, (datacenter, host));
+        if let Some(name) = name(worker_id) {
+            domains.entry(name).or_insert((datacenter, host));
+        }
+        report
+            .datacenters
+            .entry(datacenter.to_owned())
+            .or_default()
+            .workers += 1;
+        report.hosts.entry(host.to_owned()).or_default().workers += 1;
+    }
+    let resolve = |worker_id: &str| {
+        domains
+            .get(worker_id)
+            .copied()
+            .unwrap_or((UNKNOWN, UNKNOWN))
+    };
+
+    for (requester, conflicts) in lock_conflicts {
+        let (datacenter, host) = resolve(requester);
+        let entries = [
+            report.datacenters.entry(datacenter.to_owned()),
+            report.hosts.entry(host.to_owned()),
+        ];
+        for entry in entries {
+            entry.or_default().lock_conflicts += conflicts;
+        }
+    }
+
+    for workers in jobs.values() {
+        let domains = workers
+            .iter()
+            .map(|worker_id| resolve(worker_id))
+            .collect::<BTreeSet<_>>();
+        let datacenters = domains.iter().map(|(dc, _)| *dc).collect::<BTreeSet<_>>();
+        let hosts = domains
+            .iter()
+            .map(|(_, host)| *host)
+            .collect::<BTreeSet<_>>();
+        let is_duplicate = workers.len() > 1;
+        let is_cross_datacenter = datacenters.len() > 1;
+        if is_duplicate {
+            report.duplicate_jobs += 1;
+        }
+        if is_cross_datacenter {
+            report.cross_datacenter_duplicate_jobs += 1;
+        }
+
+        let groups = [
+            (&mut report.datacenters, datacenters),
+            (&mut report.hosts, hosts),
+        ];
+        for (group, names) in groups {
+            for name in names {
+                let stats = group.entry(name.to_owned()).or_default();
+                stats.jobs += 1;
+                if is_duplicate {
+                    stats.duplicate_jobs += 1;
+                }
+                if is_cross_datacenter {
+                    stats.cross_datacenter_duplicate_jobs += 1;
+                }
+            }
+        }
+    }
+
+    report
+}
+
+/// `(datacenter, host)` of a worker registered with `metadata`.
+fn domain(metadata: Option<&WorkerMetadata>) -> (&str, &str) {
+    let datacenter = metadata.and_then(|m| m.datacenter.as_deref());
+    let host = metadata.and_then(|m| m.hostname.as_deref());
+    (datacenter.unwrap_or(UNKNOWN), host.unwrap_or(UNKNOWN))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn worker(datacenter: Option<&str>, host: &str, ids: &str) -> Vec<SnarkWorkerState> {
+        let metadata = WorkerMetadata {
+            hostname: Some(host.to_owned()),
+            datacenter: datacenter.map(str::to_owned),
+            ..Default::default()
+        };
+        vec![
+            SnarkWorkerState::Registered {
+                registered_t: 0,
+                metadata: Some(metadata),
+            },
+            SnarkWorkerState::WorkCreatePending {
+                job_get_init_t: 1,
+                job_get_node_received_t: None,
+                job_get_node_request_work_init_t: None,
+                job_get_node_request_work_success_t: None,
+                job_get_success_t: 2,
+                ids: ids.to_owned(),
+                lease: None,
+            },
+        ]
+    }
+
+    #[test]
+    fn groups_by_registered_datacenter_and_host() {
+        let workers = [
+            ("a".to_owned(), worker(Some("eu"), "h1", "1")),
+            ("b".to_owned(), worker(Some("us"), "h2", "1")),
+            ("c".to_owned(), worker(None, "h3", "2")),
+        ];
+        let names = HashMap::from([("c".to_owned(), "gpu")]);
+        let name = |id: &str| names.get(id).copied();
+        let conflicts = HashMap::from([
+            ("b".to_owned(), 3),
+            ("gpu".to_owned(), 2),
+            ("gone".to_owned(), 1),
+        ]);
+        let report = report(workers.iter().map(|(id, s)| (id, s)), name, &conflicts);
+
+        assert_eq!(report.duplicate_jobs, 1);
+        assert_eq!(report.cross_datacenter_duplicate_jobs, 1);
+        assert_eq!(report.datacenters["eu"].cross_datacenter_duplicate_jobs, 1);
+        assert_eq!(report.datacenters["us"].lock_conflicts, 3);
+        assert_eq!(report.datacenters[UNKNOWN].workers, 1);
+        assert_eq!(report.datacenters[UNKNOWN].lock_conflicts, 3);
+        assert_eq!(report.hosts["h3"].lock_conflicts, 2);
+        assert_eq!(report.hosts["h3"].duplicate_jobs, 0);
+    }
+}
@@ -0,0 +1,117 @@
+//! Human-readable state dumps written on SIGUSR1, so operators can
+//! capture what the coordinator holds during an incident without adding
+//! load with HTTP queries.
+
+use std::{
+    fmt::Write as _,
+    io,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use snark_coordinator_rs::timestamp;
+
+use crate::{Opts, StatsIngest};
+
+/// `opts` as given, with secrets redacted.
+pub fn config(opts: &Opts) -> String {
+    let mut config = format!("{opts:#?}");
+    let secrets = [
+        opts.push_influx_token.as_deref(),
+        opts.webhook_url.as_deref(),
+        opts.lock_backend.redis_url(),
+        opts.cluster.as_deref(),
+        opts.cluster_api_key.as_deref(),
+    ];
+    for secret in secrets.into_iter().flatten() {
+        config = config.replace(&format!("{secret:?}"), "\"<redacted>\"");
+    }
+    config
+}
+
+fn rfc3339(t: u64) -> String {
+    chrono::DateTime::from_timestamp_millis(t as i64)
+        .map(|t| t.to_rfc3339_opts(chrono::SecondsFormat::Millis, true))
+        .unwrap_or_else(|| t.to_string())
+}
+
+/// Writes a dump of the coordinator's state and `config` to a new file in
+/// `dir`, named after the time of the dump. Returns the file's path.
+pub async fn write(ingest: &StatsIngest, config: &str, dir: &Path) -> io::Result<PathBuf> {
+    let now = timestamp::now();
+    let dump = render(ingest, config, now).await;
+    let name = chrono::DateTime::from_timestamp_millis(now as i64)
+        .map(|t| t.format("%Y%m%dT%H%M%S%.3fZ").to_string())
+        .unwrap_or_else(|| now.to_string());
+    tokio::fs::create_dir_all(dir).await?;
+    let path = dir.join(format!("state-dump-{name}.txt"));
+    tokio::fs::write(&path, dump).await?;
+    Ok(path)
+}
+
+async fn render(ingest: &StatsIngest, config: &str, now: u64) -> String {
+    let mut out = String::new();
+    writeln!(out, "state dump at {}", rfc3339(now)).unwrap();
+
+    {
+        let kv = ingest.kv.lock_all().await;
+        let leases = kv.leases(Instant::now());
+        writeln!(out, "\n== locks ({}) ==", leases.len()).unwrap();
+        for (key, lease) in &leases {
+            let holder = lease.holder.as_deref().unwrap_or("-");
+            let expires = rfc3339(lease.expires_t);
+            let token = lease.fencing_token;
+            writeln!(
+                out,
+                "{key}  holder={holder} token={token} expires={expires}"
+            )
+            .unwrap();
+        }
+    }
+
+    let stats = ingest.stats.read().await;
+    let mut worker_ids = stats.keys().collect::<Vec<_>>();
+    worker_ids.sort();
+    let mut lifecycles_in_flight = 0;
+    writeln!(out, "\n== workers ({}) ==", worker_ids.len()).unwrap();
+    for worker_id in worker_ids {
+        let in_flight = stats.in_flight(worker_id).count();
+        lifecycles_in_flight += in_flight;
+        let Some(state) = stats.latest(worker_id) else {
+            writeln!(out, "{worker_id}  no states").unwrap();
+            continue;
+        };
+        let kind = state.kind();
+        let since = rfc3339(state.start_time());
+        let ids = state.ids().unwrap_or("-");
+        writeln!(
+            out,
+            "{worker_id}  {kind} since {since} ids={ids} in_flight={in_flight}"
+        )
+        .unwrap();
+    }
+
+    writeln!(out, "\n== queues ==").unwrap();
+    let stats_events = ingest.metrics.stats_events_in_flight.get();
+    writeln!(out, "stats events being applied: {stats_events}").unwrap();
+    writeln!(out, "lifecycles in flight: {lifecycles_in_flight}").unwrap();
+    let transitions = ingest.transitions.len();
+    writeln!(out, "live feed transitions unread: {transitions}").unwrap();
+    if let Some(jobs) = &ingest.completed_jobs {
+        let jobs = jobs.lock().await.len();
+        writeln!(out, "completed jobs to push: {jobs}").unwrap();
+    }
+    let states = stats.total_states();
+    let evictions = stats.evictions();
+    writeln!(out, "states kept: {states}").unwrap();
+    writeln!(
+        out,
+        "states evicted: {} per worker, {} total, {} retention",
+        evictions.max_states_per_worker, evictions.max_total_states, evictions.retention
+    )
+    .unwrap();
+    drop(stats);
+
+    writeln!(out, "\n== config ==\n{config}").unwrap();
+    out
+}
@@ -0,0 +1,91 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// Durations remembered per job class.
+const MAX_SAMPLES: usize = 1000;
+/// Samples needed before a TTL is suggested for a class.
+const MIN_SAMPLES: usize = 20;
+/// Suggested TTL is this many times the p95 duration.
+const TTL_MARGIN: f64 = 1.5;
+
+/// Class of the job behind a lock key: the part before `separator`, or
+/// `default` if there's none.
+pub fn job_class<'a>(key: &'a str, separator: &str) -> &'a str {
+    match key.split_once(separator) {
+        Some((class, _)) if !separator.is_empty() => class,
+        _ => "default",
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ClassDurations {
+    pub samples: usize,
+    pub mean_ms: u64,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p95_ms: u64,
+    pub p99_ms: u64,
+    /// `None` until enough samples were collected.
+    pub suggested_ttl_s: Option<u64>,
+    /// `suggested_ttl_s` before rounding up to whole seconds.
+    pub suggested_ttl_ms: Option<u64>,
+}
+
+/// Recent job durations per job class, used to suggest lock TTLs.
+#[derive(Debug, Default)]
+pub struct DurationModel {
+    classes: HashMap<String, VecDeque<u64>>,
+}
+
+impl DurationModel {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn observe(&mut self, class: &str, duration_ms: u64) {
+        let samples = match self.classes.get_mut(class) {
+            Some(samples) => samples,
+            None => self.classes.entry(class.to_owned()).or_default(),
+        };
+        if samples.len() >= MAX_SAMPLES {
+            samples.pop_back();
+        }
+        samples.push_front(duration_ms);
+    }
+
+    /// Lock TTL in ms covering nearly all jobs of the class.
+    pub fn suggest_ttl(&self, class: &str) -> Option<u64> {
+        self.stats(class).and_then(|stats| stats.suggested_ttl_ms)
+    }
+
+    pub fn stats(&self, class: &str) -> Option<ClassDurations> {
+        let samples = self.classes.get(class)?;
+        if samples.is_empty() {
+            return None;
+        }
+        let mut sorted = samples.iter().copied().collect::<Vec<_>>();
+        sorted.sort_unstable();
+        let percentile = |p: f64| sorted[((sorted.len() - 1) as f64 * p).round() as usize];
+        let p95_ms = percentile(0.95);
+        let suggested_ttl_ms = (sorted.len() >= MIN_SAMPLES)
+            .then(|| ((p95_ms as f64 * TTL_MARGIN).ceil() as u64).max(1));
+        Some(ClassDurations {
+            samples: sorted.len(),
+            mean_ms: sorted.iter().sum::<u64>() / sorted.len() as u64,
+            p50_ms: percentile(0.5),
+            p90_ms: percentile(0.9),
+            p95_ms,
+            p99_ms: percentile(0.99),
+            suggested_ttl_s: suggested_ttl_ms.map(|ms| ms.div_ceil(1000)),
+            suggested_ttl_ms,
+        })
+    }
+
+    pub fn report(&self) -> BTreeMap<String, ClassDurations> {
+        self.classes
+            .keys()
+            .filter_map(|class| Some((class.clone(), self.stats(class)?)))
+            .collect()
+    }
+}
@@ -0,0 +1,546 @@
+//! End-to-end run for `e2e`: starts a coordinator, a mock Mina node and
+//! simulated workers which run full job lifecycles against both, then
+//! checks the stats the coordinator ended up with. Which jobs fail only
+//! depends on their index, so the expected totals are known upfront no
+//! matter which worker ends up with which job.
+
+use std::{
+    collections::VecDeque,
+    process::Stdio,
+    sync::Arc,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use reqwest::{Client, StatusCode};
+use serde::{Deserialize, Serialize};
+use snark_coordinator_rs::{
+    lock::{LockJobGranted, LockJobHeld},
+    stats::{SnarkWorkerJobGetError, SnarkWorkerStatsPut},
+    summary::Summary,
+};
+use tokio::{process::Command, sync::Mutex};
+use warp::Filter;
+
+type StepResult = Result<(), String>;
+
+/// How long the coordinator gets to start listening.
+const STARTUP_DEADLINE: Duration = Duration::from_secs(10);
+/// TTL of the locks taken in the lock expiry race.
+const RACE_LOCK_TTL_MS: u64 = 200;
+
+/// Job `i` fails proving.
+fn fails_proving(i: usize) -> bool {
+    i % 7 == 6 && !rejected(i)
+}
+
+/// The node rejects the work of job `i`.
+fn rejected(i: usize) -> bool {
+    i % 5 == 4
+}
+
+#[derive(Serialize, Deserialize)]
+struct MockJob {
+    ids: String,
+    index: usize,
+}
+
+/// Node handing out `jobs` jobs once each and accepting their work,
+/// except for the jobs it rejects.
+struct MockNode {
+    jobs: Mutex<VecDeque<MockJob>>,
+    accepted: Mutex<Vec<String>>,
+}
+
+impl MockNode {
+    fn new(jobs: usize) -> Self {
+        let jobs = (0..jobs)
+            .map(|index| MockJob {
+                ids: format!("e2e:{index}"),
+                index,
+            })
+            .collect();
+        Self {
+            jobs: Mutex::new(jobs),
+            accepted: Mutex::new(vec![]),
+        }
+    }
+
+    /// Serves the node on an ephemeral local port, returns its base url.
+    fn serve(self: Arc<Self>) -> String {
+        let node = self.clone();
+        let job = warp::path!("snark-job").and(warp::post()).then(move || {
+            let node = node.clone();
+            async move {
+                match node.jobs.lock().await.pop_front() {
+                    Some(job) => warp::reply::with_status(
+                        serde_json::to_string(&job).unwrap(),
+                        warp::http::StatusCode::OK,
+                    ),
+                    None => {
+                        warp::reply::with_status(String::new(), warp::http::StatusCode::NOT_FOUND)
+                    }
+                }
+            }
+        });
+        let node = self;
+        let work = warp::path!("snark-work")
+            .and(warp::post())
+            .and(warp::body::json())
+            .then(move |job: MockJob| {
+                let node = node.clone();
+                async move {
+                    if rejected(job.index) {
+                        return warp::http::StatusCode::BAD_REQUEST;
+                    }
+                    node.accepted.lock().await.push(job.ids);
+                    warp::http::StatusCode::OK
+                }
+            });
+        let (addr, server) = warp::serve(job.or(work)).bind_ephemeral(([127, 0, 0, 1], 0));
+        tokio::spawn(server);
+        format!("http://{addr}")
+    }
+}
+
+/// Totals the coordinator must report once all workers are done.
+#[derive(Debug, PartialEq, Eq)]
+struct Expected {
+    succeeded: u64,
+    work_create_failed: u64,
+    work_submit_failed: u64,
+    no_available_job: u64,
+}
+
+impl Expected {
+    fn new(workers: usize, jobs: usize) -> Self {
+        let count = |f: fn(usize) -> bool| (0..jobs).filter(|i| f(*i)).count() as u64;
+        let work_create_failed = count(fails_proving);
+        let work_submit_failed = count(rejected);
+        Self {
+            succeeded: jobs as u64 - work_create_failed - work_submit_failed,
+            work_create_failed,
+            work_submit_failed,
+            // every worker stops once the node is out of jobs.
+            no_available_job: workers as u64,
+        }
+    }
+
+    fn of(summary: &Summary) -> Self {
+        Self {
+            succeeded: summary.total.succeeded,
+            work_create_failed: summary.total.failed.work_create,
+            work_submit_failed: summary.total.failed.work_submit,
+            no_available_job: summary.total.no_available_job,
+        }
+    }
+}
+
+struct E2e {
+    client: Client,
+    url: String,
+    node: Arc<MockNode>,
+    node_url: String,
+    workers: usize,
+    jobs: usize,
+}
+
+/// Runs the coordinator binary with `workers` simulated workers sharing
+/// `jobs` jobs, prints a pass/fail line per check and returns `true` if
+/// all passed.
+pub async fn run(workers: usize, jobs: usize) -> bool {
+    let port = match std::net::TcpListener::bind("127.0.0.1:0").and_then(|l| l.local_addr()) {
+        Ok(addr) => addr.port(),
+        Err(err) => {
+            eprintln!("failed to find a free port: {err}");
+            return false;
+        }
+    };
+    let exe = match std::env::current_exe() {
+        Ok(exe) => exe,
+        Err(err) => {
+            eprintln!("failed to find the coordinator binary: {err}");
+            return false;
+        }
+    };
+    // killed when dropped at the end of the run.
+    let coordinator = Command::new(exe)
+        .args(["--listen", &format!("127.0.0.1:{port}")])
+        .args(["--min-timeout-ms", "1"])
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .kill_on_drop(true)
+        .spawn();
+    let _coordinator = match coordinator {
+        Ok(child) => child,
+        Err(err) => {
+            eprintln!("failed to start the coordinator: {err}");
+            return false;
+        }
+    };
+
+    let node = Arc::new(MockNode::new(jobs));
+    let mut test = E2e {
+        client: Client::new(),
+        url: format!("http://127.0.0.1:{port}"),
+        node_url: node.clone().serve(),
+        node,
+        workers,
+        jobs,
+    };
+
+    println!(
+        "e2e with {workers} workers and {jobs} jobs against {}",
+        test.url
+    );
+    let mut failed = 0;
+    macro_rules! step {
+        ($name:literal, $f:ident) => {{
+            let started = Instant::now();
+            let res = test.$f().await;
+            let elapsed = started.elapsed().as_millis();
+            match res {
+                Ok(()) => println!("PASS {:<16} ({elapsed}ms)", $name),
+                Err(err) => {
+                    failed += 1;
+                    println!("FAIL {:<16} ({elapsed}ms): {err}", $name);
+                }
+            }
+        }};
+    }
+
+    step!("startup", startup);
+    if failed > 0 {
+        println!("{failed} failed");
+        return false;
+    }
+    step!("lifecycles", lifecycles);
+    step!("summary", summary);
+    step!("locks-released", locks_released);
+    step!("lock-expiry-race", lock_expiry_race);
+
+    println!("{failed} failed");
+    failed == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn expect_status(expected: StatusCode, found: StatusCode, body: &str) -> StepResult {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(format!("expected status {expected}, found {found}: {body}"))
+    }
+}
+
+/// A simulated worker, which gets jobs from the node until it runs out.
+struct Worker {
+    client: Client,
+    url: String,
+    node_url: String,
+    worker_id: String,
+    /// `x-stats-version` of the last stats PUT.
+    stats_version: u64,
+}
+
+impl Worker {
+    async fn put_stats(&mut self, req: SnarkWorkerStatsPut) -> Result<String, String> {
+        // only a `Register` is replied to once applied.
+        let expected = match req {
+            SnarkWorkerStatsPut::Register { .. } => StatusCode::OK,
+            _ => StatusCode::ACCEPTED,
+        };
+        let res = self
+            .client
+            .put(format!("{}/worker-stats/{}", self.url, self.worker_id))
+            .json(&req)
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let version = res.headers().get("x-stats-version");
+        if let Some(version) = version.and_then(|v| v.to_str().ok()?.parse().ok()) {
+            self.stats_version = version;
+        }
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(expected, status, &body)?;
+        Ok(body)
+    }
+
+    async fn register(&mut self) -> StepResult {
+        self.worker_id = self
+            .put_stats(SnarkWorkerStatsPut::Register {
+                time: now(),
+                metadata: None,
+                resume: None,
+                seq: None,
+            })
+            .await?;
+        Ok(())
+    }
+
+    async fn lock(&self, ids: &str) -> StepResult {
+        let res = self
+            .client
+            .put(format!("{}/lock-job/{ids}", self.url))
+            .query(&[("worker_id", self.worker_id.as_str()), ("timeout", "30")])
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(StatusCode::CREATED, status, &body)
+    }
+
+    /// Runs lifecycles until the node has no more jobs, returning the
+    /// stats version of the last event.
+    async fn run(mut self) -> Result<u64, String> {
+        self.register().await?;
+        loop {
+            self.put_stats(SnarkWorkerStatsPut::JobGetInit {
+                time: now(),
+                seq: None,
+            })
+            .await?;
+            let res = self
+                .client
+                .post(format!("{}/snark-job", self.node_url))
+                .send()
+                .await
+                .map_err(|err| err.to_string())?;
+            if res.status() == StatusCode::NOT_FOUND {
+                self.put_stats(SnarkWorkerStatsPut::JobGetError {
+                    time: now(),
+                    job_get_node_received_t: None,
+                    job_get_node_request_work_init_t: None,
+                    job_get_node_request_work_success_t: None,
+                    error: SnarkWorkerJobGetError::NoAvailableJob,
+                    seq: None,
+                })
+                .await?;
+                return Ok(self.stats_version);
+            }
+            let job: MockJob = res.json().await.map_err(|err| err.to_string())?;
+            self.lock(&job.ids).await?;
+            self.put_stats(SnarkWorkerStatsPut::JobGetSuccess {
+                time: now(),
+                job_get_node_received_t: None,
+                job_get_node_request_work_init_t: None,
+                job_get_node_request_work_success_t: None,
+                ids: job.ids.clone(),
+                seq: None,
+            })
+            .await?;
+
+            let ids = job.ids.clone();
+            if fails_proving(job.index) {
+                let error = "prover exited unexpectedly".to_owned();
+                self.put_stats(SnarkWorkerStatsPut::WorkCreateError {
+                    time: now(),
+                    ids,
+                    error,
+                    seq: None,
+                })
+                .await?;
+                continue;
+            }
+            self.put_stats(SnarkWorkerStatsPut::WorkCreateSuccess {
+                time: now(),
+                ids,
+                seq: None,
+            })
+            .await?;
+
+            let res = self
+                .client
+                .post(format!("{}/snark-work", self.node_url))
+                .json(&job)
+                .send()
+                .await
+                .map_err(|err| err.to_string())?;
+            let time = now();
+            let req = if res.status().is_success() {
+                SnarkWorkerStatsPut::WorkSubmitSuccess {
+                    time,
+                    work_submit_node_received_t: None,
+                    work_submit_node_add_work_init_t: None,
+                    work_submit_node_add_work_success_t: None,
+                    ids: job.ids,
+                    seq: None,
+                }
+            } else {
+                SnarkWorkerStatsPut::WorkSubmitError {
+                    time,
+                    work_submit_node_received_t: None,
+                    work_submit_node_add_work_init_t: None,
+                    work_submit_node_add_work_success_t: None,
+                    ids: job.ids,
+                    error: format!("node rejected work: {}", res.status()),
+                    seq: None,
+                }
+            };
+            self.put_stats(req).await?;
+        }
+    }
+}
+
+impl E2e {
+    async fn get(&self, path: &str) -> Result<String, String> {
+        let res = self
+            .client
+            .get(format!("{}{path}", self.url))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(StatusCode::OK, status, &body)?;
+        Ok(body)
+    }
+
+    async fn put_lock(
+        &self,
+        key: &str,
+        worker_id: &str,
+        timeout_ms: u64,
+    ) -> Result<(StatusCode, String), String> {
+        let res = self
+            .client
+            .put(format!("{}/lock-job/{key}", self.url))
+            .query(&[
+                ("worker_id", worker_id),
+                ("timeout_ms", &timeout_ms.to_string()),
+            ])
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        Ok((status, body))
+    }
+
+    async fn startup(&mut self) -> StepResult {
+        let deadline = Instant::now() + STARTUP_DEADLINE;
+        loop {
+            match self.get("/healthz").await {
+                Ok(_) => return Ok(()),
+                Err(err) if Instant::now() >= deadline => {
+                    return Err(format!("coordinator didn't start: {err}"))
+                }
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    }
+
+    async fn lifecycles(&mut self) -> StepResult {
+        let workers = (0..self.workers).map(|i| Worker {
+            client: self.client.clone(),
+            url: self.url.clone(),
+            node_url: self.node_url.clone(),
+            worker_id: format!("e2e-{i}"),
+            stats_version: 0,
+        });
+        let handles = workers
+            .map(|worker| tokio::spawn(worker.run()))
+            .collect::<Vec<_>>();
+        let mut stats_version = 0;
+        for handle in handles {
+            let version = handle.await.map_err(|err| err.to_string())??;
+            stats_version = stats_version.max(version);
+        }
+        // the checks after need every event applied.
+        self.get(&format!(
+            "/worker-stats?limit=1&min_version={stats_version}"
+        ))
+        .await?;
+        if !self.node.jobs.lock().await.is_empty() {
+            return Err("workers stopped before the node ran out of jobs".to_owned());
+        }
+        Ok(())
+    }
+
+    async fn summary(&mut self) -> StepResult {
+        let summary = self.get("/summary").await?;
+        let summary: Summary = serde_json::from_str(&summary).map_err(|err| err.to_string())?;
+        let expected = Expected::new(self.workers, self.jobs);
+        let found = Expected::of(&summary);
+        if found != expected {
+            return Err(format!("expected {expected:?}, found {found:?}"));
+        }
+        let accepted = self.node.accepted.lock().await.len() as u64;
+        if accepted != expected.succeeded {
+            return Err(format!(
+                "node accepted {accepted} works, coordinator counted {}",
+                expected.succeeded
+            ));
+        }
+        if summary.total.in_progress > 0 {
+            return Err(format!(
+                "{} lifecycles still in progress",
+                summary.total.in_progress
+            ));
+        }
+        Ok(())
+    }
+
+    /// Every lifecycle ended, so none of their locks may be held anymore.
+    async fn locks_released(&mut self) -> StepResult {
+        for i in 0..self.jobs {
+            let key = format!("e2e:{i}");
+            let (status, body) = self.put_lock(&key, "e2e-checker", 1000).await?;
+            expect_status(StatusCode::CREATED, status, &body)
+                .map_err(|err| format!("{key}: {err}"))?;
+        }
+        Ok(())
+    }
+
+    /// A worker whose lock expired loses the job to the next one asking,
+    /// and its fencing token goes stale.
+    async fn lock_expiry_race(&mut self) -> StepResult {
+        let key = format!("e2e-race:{}", now());
+        let (status, body) = self.put_lock(&key, "e2e-slow", RACE_LOCK_TTL_MS).await?;
+        expect_status(StatusCode::CREATED, status, &body)?;
+        let slow: LockJobGranted = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+
+        let (status, body) = self.put_lock(&key, "e2e-fast", RACE_LOCK_TTL_MS).await?;
+        expect_status(StatusCode::OK, status, &body)?;
+        let held: LockJobHeld = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        if held.holder.as_deref() != Some("e2e-slow") {
+            return Err(format!("unexpected lock holder: {:?}", held.holder));
+        }
+
+        tokio::time::sleep(Duration::from_millis(2 * RACE_LOCK_TTL_MS)).await;
+        let (status, body) = self.put_lock(&key, "e2e-fast", 30_000).await?;
+        expect_status(StatusCode::CREATED, status, &body)?;
+        let fast: LockJobGranted = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        if fast.fencing_token <= slow.fencing_token {
+            return Err(format!(
+                "fencing token didn't increase: {} after {}",
+                fast.fencing_token, slow.fencing_token
+            ));
+        }
+
+        let validate = |token: u64| {
+            self.client
+                .get(format!("{}/lock-job/{key}/validate", self.url))
+                .query(&[("fencing_token", token)])
+                .send()
+        };
+        let res = validate(slow.fencing_token)
+            .await
+            .map_err(|err| err.to_string())?;
+        if res.status().is_success() {
+            return Err("stale fencing token still validates".to_owned());
+        }
+        let res = validate(fast.fencing_token)
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(StatusCode::OK, status, &body)
+    }
+}
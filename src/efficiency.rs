@@ -0,0 +1,170 @@
+//! Fleet efficiency: the share of worker time spent on proofs which
+//! counted, with where the rest of the time went.
+//!
+//! A worker's time in a window runs from its first state (or the window's
+//! start) to the window's end. Lifecycles are attributed by the time they
+//! overlap the window:
+//! - productive: lifecycles submitted successfully, first for their job,
+//! - duplicate: lifecycles submitted successfully after another worker
+//!   already submitted the same job,
+//! - error: lifecycles which failed or timed out in any phase,
+//! - in progress: lifecycles which haven't finished yet, left out of the
+//!   score as they may still turn out either way,
+//! - idle: the rest, including job gets which found no job.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EfficiencyBreakdown {
+    pub worker_ms: u64,
+    pub productive_ms: u64,
+    pub duplicate_ms: u64,
+    pub error_ms: u64,
+    pub idle_ms: u64,
+    pub in_progress_ms: u64,
+    /// `productive_ms` per worker time which isn't in progress, `None`
+    /// without such time.
+    pub efficiency: Option<f64>,
+}
+
+impl EfficiencyBreakdown {
+    fn finish(&mut self) {
+        let busy = self.productive_ms + self.duplicate_ms + self.error_ms + self.in_progress_ms;
+        self.idle_ms = self.worker_ms.saturating_sub(busy);
+        let scored_ms = self.worker_ms.saturating_sub(self.in_progress_ms);
+        self.efficiency =
+            (scored_ms > 0).then(|| (self.productive_ms as f64 / scored_ms as f64).min(1.0));
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.worker_ms += other.worker_ms;
+        self.productive_ms += other.productive_ms;
+        self.duplicate_ms += other.duplicate_ms;
+        self.error_ms += other.error_ms;
+        self.in_progress_ms += other.in_progress_ms;
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EfficiencyWindow {
+    pub window_ms: u64,
+    pub from_t: u64,
+    pub to_t: u64,
+    #[serde(flatten)]
+    pub total: EfficiencyBreakdown,
+    pub workers: BTreeMap<String, EfficiencyBreakdown>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EfficiencyReport {
+    pub windows: Vec<EfficiencyWindow>,
+}
+
+enum Outcome {
+    Productive,
+    Duplicate,
+    Error,
+    InProgress,
+}
+
+/// Efficiency of the workers `worker_ids` matches over each of the
+/// `windows_ms` before `now`. Duplicates are found among all of `stats`.
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    worker_ids: impl Fn(&String) -> bool,
+    windows_ms: &[u64],
+    now: u64,
+) -> EfficiencyReport {
+    let stats = stats.into_iter().collect::<Vec<_>>();
+
+    // first successful submission of each job, the one which counted.
+    let mut first = HashMap::<&str, (u64, &String)>::new();
+    for &(worker_id, states) in &stats {
+        for state in states {
+            if let SnarkWorkerState::WorkSubmitSuccess { ids, .. } = state {
+                let end_t = state.end_time();
+                let entry = first.entry(ids).or_insert((end_t, worker_id));
+                if (end_t, worker_id) < *entry {
+                    *entry = (end_t, worker_id);
+                }
+            }
+        }
+    }
+
+    let outcome = |worker_id: &String, state: &SnarkWorkerState| {
+        Some(match state {
+            SnarkWorkerState::Registered { .. }
+            | SnarkWorkerState::Restarted { .. }
+            | SnarkWorkerState::JobUnavailable { .. } => return None,
+            SnarkWorkerState::JobGetPending { .. }
+            | SnarkWorkerState::WorkCreatePending { .. }
+            | SnarkWorkerState::WorkSubmitPending { .. } => Outcome::InProgress,
+            SnarkWorkerState::JobGetError { .. }
+            | SnarkWorkerState::WorkCreateError { .. }
+            | SnarkWorkerState::WorkSubmitError { .. }
+            | SnarkWorkerState::JobGetTimeout { .. }
+            | SnarkWorkerState::WorkCreateTimeout { .. }
+            | SnarkWorkerState::WorkSubmitTimeout { .. } => Outcome::Error,
+            SnarkWorkerState::WorkSubmitSuccess { ids, .. } => match first.get(ids.as_str()) {
+                Some(&(end_t, first_id)) if end_t == state.end_time() && first_id == worker_id => {
+                    Outcome::Productive
+                }
+                _ => Outcome::Duplicate,
+            },
+        })
+    };
+
+    let windows = windows_ms
+        .iter()
+        .map(|&window_ms| {
+            let from_t = now.saturating_sub(window_ms);
+            let mut window = EfficiencyWindow {
+                window_ms,
+                from_t,
+                to_t: now,
+                ..EfficiencyWindow::default()
+            };
+            for &(worker_id, states) in stats.iter().filter(|(id, _)| worker_ids(id)) {
+                let Some(first_t) = states.iter().map(|s| s.start_time()).min() else {
+                    continue;
+                };
+                if first_t >= now {
+                    continue;
+                }
+                let mut breakdown = EfficiencyBreakdown {
+                    worker_ms: now - first_t.max(from_t),
+                    ..EfficiencyBreakdown::default()
+                };
+                for state in states {
+                    let Some(outcome) = outcome(worker_id, state) else {
+                        continue;
+                    };
+                    let end_t = match outcome {
+                        Outcome::InProgress => now,
+                        _ => state.end_time(),
+                    };
+                    let ms = end_t
+                        .min(now)
+                        .saturating_sub(state.start_time().max(from_t));
+                    let component = match outcome {
+                        Outcome::Productive => &mut breakdown.productive_ms,
+                        Outcome::Duplicate => &mut breakdown.duplicate_ms,
+                        Outcome::Error => &mut breakdown.error_ms,
+                        Outcome::InProgress => &mut breakdown.in_progress_ms,
+                    };
+                    *component += ms;
+                }
+                breakdown.finish();
+                window.total.merge(&breakdown);
+                window.workers.insert(worker_id.clone(), breakdown);
+            }
+            window.total.finish();
+            window
+        })
+        .collect();
+    EfficiencyReport { windows }
+}
@@ -0,0 +1,93 @@
+//! Error states as a resumable event feed, for alerting scripts which
+//! follow `GET /events`.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast;
+
+use crate::{stats::SnarkWorkerState, timestamp};
+
+/// A `JobGetError`, `WorkCreateError` or `WorkSubmitError` state recorded
+/// for a worker.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorEvent {
+    /// Increases by one per event, sent as the SSE event id.
+    pub id: u64,
+    pub worker_id: String,
+    /// Kind of the error state.
+    pub kind: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub ids: Option<String>,
+    /// The state's `error`, as stored in it.
+    pub error: serde_json::Value,
+    /// When the worker hit the error.
+    pub time: u64,
+    pub recorded_t: u64,
+}
+
+/// Most recent error events, which subscribers resume from.
+#[derive(Debug)]
+pub struct ErrorEvents {
+    events: VecDeque<ErrorEvent>,
+    capacity: usize,
+    last_id: u64,
+    tx: broadcast::Sender<ErrorEvent>,
+}
+
+impl ErrorEvents {
+    /// Keeps the last `capacity` events for resumption.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            events: VecDeque::with_capacity(capacity),
+            capacity,
+            last_id: 0,
+            tx: broadcast::channel(capacity.max(1)).0,
+        }
+    }
+
+    /// Records `state` if it's an error state, returns whether it was.
+    pub fn record(&mut self, worker_id: &str, state: &SnarkWorkerState) -> bool {
+        let error = match state {
+            SnarkWorkerState::JobGetError { error, .. } => serde_json::to_value(error).unwrap(),
+            SnarkWorkerState::WorkCreateError { error, .. }
+            | SnarkWorkerState::WorkSubmitError { error, .. } => error.clone().into(),
+            _ => return false,
+        };
+        self.last_id += 1;
+        let event = ErrorEvent {
+            id: self.last_id,
+            worker_id: worker_id.to_owned(),
+            kind: state.kind().to_owned(),
+            ids: state.ids().map(str::to_owned),
+            error,
+            time: state.last_seen_t().unwrap_or(state.end_time()),
+            recorded_t: timestamp::now(),
+        };
+        if self.events.len() >= self.capacity {
+            self.events.pop_front();
+        }
+        self.events.push_back(event.clone());
+        let _ = self.tx.send(event);
+        true
+    }
+
+    /// Kept events after `last_id`, none if `None`, and a receiver of the
+    /// ones recorded from now on. Together they have no gaps or
+    /// duplicates, except for events dropped since `last_id`.
+    pub fn subscribe(
+        &self,
+        last_id: Option<u64>,
+    ) -> (Vec<ErrorEvent>, broadcast::Receiver<ErrorEvent>) {
+        let missed = match last_id {
+            Some(last_id) => self
+                .events
+                .iter()
+                .filter(|e| e.id > last_id)
+                .cloned()
+                .collect(),
+            None => vec![],
+        };
+        (missed, self.tx.subscribe())
+    }
+}
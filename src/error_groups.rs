@@ -0,0 +1,167 @@
+//! Error states of `GET /errors`, grouped by kind and message, to find
+//! the most common failure within a window. Unlike `GET /top/errors`, the
+//! counts are exact and cover the kept history only.
+
+use std::collections::{BTreeSet, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    stats::{SnarkWorkerJobGetError, SnarkWorkerState},
+    top,
+};
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorGroup {
+    /// `JobGetError`, `WorkCreateError` or `WorkSubmitError`.
+    pub kind: String,
+    /// Error message with numbers replaced by `#`.
+    pub message: String,
+    pub count: u64,
+    pub first_seen_t: u64,
+    pub last_seen_t: u64,
+    /// Latest message of the group.
+    pub example: String,
+    /// Workers which hit the error, sorted.
+    pub workers: Vec<String>,
+}
+
+/// Response body of `GET /errors`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorGroupsReport {
+    pub from_t: Option<u64>,
+    pub to_t: u64,
+    /// Errors within the window, including groups past `limit`.
+    pub total: u64,
+    /// Most frequent first, at most `limit`.
+    pub groups: Vec<ErrorGroup>,
+}
+
+/// Kind and message of an error state. Workers not finding a job isn't
+/// an error worth grouping.
+fn error(state: &SnarkWorkerState) -> Option<(&'static str, &str)> {
+    match state {
+        SnarkWorkerState::JobGetError {
+            error: SnarkWorkerJobGetError::Other { error },
+            ..
+        } => Some(("JobGetError", error)),
+        SnarkWorkerState::WorkCreateError { error, .. } => Some(("WorkCreateError", error)),
+        SnarkWorkerState::WorkSubmitError { error, .. } => Some(("WorkSubmitError", error)),
+        _ => None,
+    }
+}
+
+/// Error states within `from_t..=to_t`, grouped by kind and message.
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    from_t: Option<u64>,
+    to_t: u64,
+    limit: usize,
+) -> ErrorGroupsReport {
+    let mut groups = HashMap::<(&str, String), (ErrorGroup, BTreeSet<&str>)>::new();
+    let mut total = 0;
+    for (worker_id, states) in stats {
+        for state in states {
+            let t = state.end_time();
+            if from_t.is_some_and(|from_t| t < from_t) || t > to_t {
+                continue;
+            }
+            let Some((kind, message)) = error(state) else {
+                continue;
+            };
+            total += 1;
+            let class = top::error_class(message);
+            let (group, workers) = groups.entry((kind, class.clone())).or_insert_with(|| {
+                let group = ErrorGroup {
+                    kind: kind.to_owned(),
+                    message: class,
+                    count: 0,
+                    first_seen_t: t,
+                    last_seen_t: t,
+                    example: message.to_owned(),
+                    workers: vec![],
+                };
+                (group, BTreeSet::new())
+            });
+            group.count += 1;
+            group.first_seen_t = group.first_seen_t.min(t);
+            if t >= group.last_seen_t {
+                group.last_seen_t = t;
+                message.clone_into(&mut group.example);
+            }
+            workers.insert(worker_id);
+        }
+    }
+    let mut groups = (groups.into_values())
+        .map(|(group, workers)| ErrorGroup {
+            workers: workers.into_iter().map(str::to_owned).collect(),
+            ..group
+        })
+        .collect::<Vec<_>>();
+    groups.sort_unstable_by(|a, b| {
+        (b.count.cmp(&a.count))
+            .then_with(|| a.kind.cmp(&b.kind))
+            .then_with(|| a.message.cmp(&b.message))
+    });
+    groups.truncate(limit);
+    ErrorGroupsReport {
+        from_t,
+        to_t,
+        total,
+        groups,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(json: &str) -> SnarkWorkerState {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn submit_error(t: u64, message: &str) -> SnarkWorkerState {
+        state(&format!(
+            r#"{{"kind":"WorkSubmitError","job_get_init_t":0,"job_get_success_t":0,"work_create_success_t":0,
+                "work_submit_error_t":{t},"ids":"j","error":"{message}"}}"#
+        ))
+    }
+
+    #[test]
+    fn groups_errors_by_kind_and_message() {
+        let w1 = VecDeque::from([
+            submit_error(100, "stale work at slot 1"),
+            submit_error(300, "stale work at slot 3"),
+            state(
+                r#"{"kind":"JobGetError","job_get_init_t":0,"job_get_error_t":200,
+                    "error":{"kind":"NoAvailableJob"}}"#,
+            ),
+            state(
+                r#"{"kind":"WorkCreateError","job_get_init_t":0,"job_get_success_t":0,
+                    "work_create_error_t":400,"ids":"j","error":"stale work at slot 4"}"#,
+            ),
+        ]);
+        let w2 = VecDeque::from([
+            submit_error(200, "stale work at slot 2"),
+            submit_error(900, "stale work at slot 9"),
+        ]);
+        let stats = [("w2".to_owned(), w2), ("w1".to_owned(), w1)];
+        let report = report(stats.iter().map(|(k, v)| (k, v)), Some(100), 500, 10);
+
+        // no job being available isn't an error, w2's last one is past `to_t`.
+        assert_eq!(report.total, 4);
+        let stale = &report.groups[0];
+        assert_eq!(
+            (stale.kind.as_str(), stale.message.as_str(), stale.count),
+            ("WorkSubmitError", "stale work at slot #", 3)
+        );
+        assert_eq!((stale.first_seen_t, stale.last_seen_t), (100, 300));
+        assert_eq!(stale.example, "stale work at slot 3");
+        assert_eq!(stale.workers, ["w1", "w2"]);
+        assert_eq!(report.groups[1].kind, "WorkCreateError");
+
+        let top = super::report(stats.iter().map(|(k, v)| (k, v)), None, 1000, 1);
+        assert_eq!((top.total, top.groups.len()), (5, 1));
+        assert_eq!(top.groups[0].example, "stale work at slot 9");
+    }
+}
@@ -0,0 +1,277 @@
+//! Machine readable error codes. Error responses carry a stable `code`
+//! next to the human readable `message`, so clients don't need to match
+//! on the wording of messages.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::strict::Violation;
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ErrorCode {
+    Unauthorized,
+    Forbidden,
+    RateLimited,
+    NotLeader,
+    BodyTooLarge,
+    UnsupportedEncoding,
+    NotAcceptable,
+    InvalidBody,
+    InvalidParameter,
+    NotFound,
+    MethodNotAllowed,
+    KeyTooLong,
+    LockHeld,
+    LockNotFound,
+    LockNamespaceFull,
+    StaleFencingToken,
+    InvalidTransition,
+    OutOfOrderEvent,
+    StrictViolation,
+    WorkerQuotaExceeded,
+    UnknownWorker,
+    UnsupportedEvent,
+    VersionNotReached,
+    PinNotFound,
+    InvalidPin,
+    AnnotationNotFound,
+    InvalidAnnotation,
+    MaintenanceNotFound,
+    InvalidMaintenance,
+    TaskNotFound,
+    JobNotFound,
+    InvalidConfig,
+    ExportFailed,
+    InstanceNotEmpty,
+    ClockSkew,
+    UnsupportedSchema,
+    UnsupportedProverVersion,
+    LockBackendUnavailable,
+    NoLeader,
+    InvalidConfirmation,
+    NoAvailableJob,
+    LockQuotaExceeded,
+    WorkerCoolingDown,
+    PreconditionFailed,
+    ReadOnly,
+    IngestSaturated,
+    IngestStopped,
+}
+
+impl ErrorCode {
+    pub const ALL: &'static [Self] = &[
+        Self::Unauthorized,
+        Self::Forbidden,
+        Self::RateLimited,
+        Self::NotLeader,
+        Self::BodyTooLarge,
+        Self::UnsupportedEncoding,
+        Self::NotAcceptable,
+        Self::InvalidBody,
+        Self::InvalidParameter,
+        Self::NotFound,
+        Self::MethodNotAllowed,
+        Self::KeyTooLong,
+        Self::LockHeld,
+        Self::LockNotFound,
+        Self::LockNamespaceFull,
+        Self::StaleFencingToken,
+        Self::InvalidTransition,
+        Self::OutOfOrderEvent,
+        Self::StrictViolation,
+        Self::WorkerQuotaExceeded,
+        Self::UnknownWorker,
+        Self::UnsupportedEvent,
+        Self::VersionNotReached,
+        Self::PinNotFound,
+        Self::InvalidPin,
+        Self::AnnotationNotFound,
+        Self::InvalidAnnotation,
+        Self::MaintenanceNotFound,
+        Self::InvalidMaintenance,
+        Self::TaskNotFound,
+        Self::JobNotFound,
+        Self::InvalidConfig,
+        Self::ExportFailed,
+        Self::InstanceNotEmpty,
+        Self::ClockSkew,
+        Self::UnsupportedSchema,
+        Self::UnsupportedProverVersion,
+        Self::LockBackendUnavailable,
+        Self::NoLeader,
+        Self::InvalidConfirmation,
+        Self::NoAvailableJob,
+        Self::LockQuotaExceeded,
+        Self::WorkerCoolingDown,
+        Self::PreconditionFailed,
+        Self::ReadOnly,
+        Self::IngestSaturated,
+        Self::IngestStopped,
+    ];
+
+    /// HTTP status of responses with this code.
+    pub fn status(self) -> u16 {
+        match self {
+            Self::Unauthorized => 401,
+            Self::Forbidden => 403,
+            Self::RateLimited | Self::LockQuotaExceeded | Self::IngestSaturated => 429,
+            Self::NotLeader => 307,
+            Self::BodyTooLarge => 413,
+            Self::NotAcceptable => 406,
+            Self::UnsupportedEncoding => 415,
+            Self::InvalidBody
+            | Self::InvalidParameter
+            | Self::KeyTooLong
+            | Self::InvalidTransition
+            | Self::WorkerQuotaExceeded
+            | Self::UnsupportedEvent
+            | Self::InvalidPin
+            | Self::InvalidAnnotation
+            | Self::InvalidMaintenance
+            | Self::InvalidConfig
+            | Self::ClockSkew
+            | Self::UnsupportedSchema
+            | Self::UnsupportedProverVersion
+            | Self::InvalidConfirmation => 400,
+            Self::NotFound
+            | Self::LockNotFound
+            | Self::UnknownWorker
+            | Self::PinNotFound
+            | Self::AnnotationNotFound
+            | Self::MaintenanceNotFound
+            | Self::TaskNotFound
+            | Self::JobNotFound
+            | Self::NoAvailableJob => 404,
+            Self::MethodNotAllowed | Self::ReadOnly => 405,
+            Self::PreconditionFailed => 412,
+            Self::StrictViolation => 422,
+            Self::WorkerCoolingDown => 423,
+            Self::LockHeld
+            | Self::StaleFencingToken
+            | Self::LockNamespaceFull
+            | Self::OutOfOrderEvent
+            | Self::InstanceNotEmpty => 409,
+            Self::VersionNotReached
+            | Self::LockBackendUnavailable
+            | Self::NoLeader
+            | Self::IngestStopped => 503,
+            Self::ExportFailed => 500,
+        }
+    }
+
+    pub fn description(self) -> &'static str {
+        match self {
+            Self::Unauthorized => "Missing or unknown API key or worker token.",
+            Self::Forbidden => "The credentials don't grant access to this request.",
+            Self::RateLimited => "Too many requests, retry after `Retry-After` seconds.",
+            Self::NotLeader => "This instance is a standby, send writes to `Location`.",
+            Self::BodyTooLarge => "Request body exceeds the configured size limit.",
+            Self::UnsupportedEncoding => "Request body has an unsupported content-encoding.",
+            Self::NotAcceptable => {
+                "The response is streamed as JSON, which can't be sent in the `Accept`ed format."
+            }
+            Self::InvalidBody => "Request body isn't valid JSON of the expected shape.",
+            Self::InvalidParameter => "A query parameter has an invalid value.",
+            Self::NotFound => "No such route.",
+            Self::MethodNotAllowed => "The route doesn't support this method.",
+            Self::KeyTooLong => "Lock key exceeds the configured max length.",
+            Self::LockHeld => "The job is locked by another worker.",
+            Self::LockNotFound => "The job isn't locked.",
+            Self::LockNamespaceFull => "The lock namespace holds its max number of locks.",
+            Self::StaleFencingToken => "The fencing token isn't the one of the current lock.",
+            Self::InvalidTransition => {
+                "The stats event isn't a valid transition from the worker's state."
+            }
+            Self::OutOfOrderEvent => "The stats event's `seq` isn't past the last one seen.",
+            Self::StrictViolation => {
+                "The stats event breaks a rule of `--strict`, see `violation`."
+            }
+            Self::WorkerQuotaExceeded => "Too many workers registered under the same id.",
+            Self::UnknownWorker => "No stats for this worker.",
+            Self::UnsupportedEvent => "The stats event isn't supported by this endpoint.",
+            Self::VersionNotReached => "The requested stats version wasn't applied in time.",
+            Self::PinNotFound => "No such pin.",
+            Self::InvalidPin => "The pin has no conditions or an empty time range.",
+            Self::AnnotationNotFound => "No such annotation.",
+            Self::InvalidAnnotation => "The annotation has no text or ends before it starts.",
+            Self::MaintenanceNotFound => "No such maintenance window.",
+            Self::InvalidMaintenance => {
+                "The maintenance window has no workers, an unknown group or an empty time range."
+            }
+            Self::TaskNotFound => "No such background task.",
+            Self::JobNotFound => "No worker reported working on this job.",
+            Self::InvalidConfig => "The reloaded options are invalid, the current ones are kept.",
+            Self::ExportFailed => "Encoding the export failed.",
+            Self::InstanceNotEmpty => {
+                "Snapshots are only loaded into instances without locks or worker stats."
+            }
+            Self::ClockSkew => "The worker's clock is too far off the coordinator's.",
+            Self::UnsupportedSchema => "The worker speaks an unsupported stats schema version.",
+            Self::UnsupportedProverVersion => "The worker's prover version is too old.",
+            Self::LockBackendUnavailable => "The shared lock store can't be reached, retry later.",
+            Self::NoLeader => "The cluster has no leader elected yet, retry later.",
+            Self::InvalidConfirmation => "The confirmation token is unknown or expired.",
+            Self::NoAvailableJob => "All pending snark work of the node is locked.",
+            Self::LockQuotaExceeded => "The worker holds its max number of locks, see `held`.",
+            Self::WorkerCoolingDown => {
+                "The worker failed too many jobs and gets none until `cooldown_until_t`."
+            }
+            Self::PreconditionFailed => {
+                "The lock doesn't meet the request's `If-Match` or `If-None-Match`."
+            }
+            Self::ReadOnly => "This instance serves a replayed journal and takes no writes.",
+            Self::IngestSaturated => {
+                "Worker-stats events queue up faster than they're applied, retry after `Retry-After` seconds."
+            }
+            Self::IngestStopped => "Worker-stats events aren't applied anymore, retry later.",
+        }
+    }
+}
+
+/// Body of error responses.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct ApiError {
+    pub code: ErrorCode,
+    pub message: String,
+    /// Rule a `STRICT_VIOLATION` broke.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub violation: Option<Violation>,
+    /// Locks the worker holds, with `LOCK_QUOTA_EXCEEDED`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub held: Option<usize>,
+    /// End of the worker's cooldown, with `WORKER_COOLING_DOWN`.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cooldown_until_t: Option<u64>,
+}
+
+impl ApiError {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            violation: None,
+            held: None,
+            cooldown_until_t: None,
+        }
+    }
+}
+
+/// Entry of the `/errors/codes` registry.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorCodeInfo {
+    pub code: ErrorCode,
+    pub status: u16,
+    pub description: String,
+}
+
+pub fn registry() -> Vec<ErrorCodeInfo> {
+    ErrorCode::ALL
+        .iter()
+        .map(|&code| ErrorCodeInfo {
+            code,
+            status: code.status(),
+            description: code.description().to_owned(),
+        })
+        .collect()
+}
@@ -0,0 +1,287 @@
+//! Columnar export of worker lifecycles for offline analysis.
+
+use std::{borrow::Cow, fmt::Write, sync::Arc};
+
+use arrow_array::{ArrayRef, RecordBatch, StringArray, TimestampMillisecondArray, UInt64Array};
+use arrow_schema::{DataType, Field, Schema, TimeUnit};
+use parquet::{arrow::ArrowWriter, errors::ParquetError};
+use serde::Deserialize;
+
+use crate::{
+    availability::AvailabilityReport,
+    stats::{Lease, SnarkWorkerState},
+    summary::{LifecycleTotals, Summary},
+};
+
+/// Flattened [`SnarkWorkerState`], fields missing in a state are `None`.
+#[derive(Deserialize, Default)]
+#[serde(default)]
+struct LifecycleRow {
+    kind: String,
+    ids: Option<String>,
+    error: Option<serde_json::Value>,
+    lease: Option<Lease>,
+    registered_t: Option<u64>,
+    job_get_init_t: Option<u64>,
+    job_get_node_received_t: Option<u64>,
+    job_get_node_request_work_init_t: Option<u64>,
+    job_get_node_request_work_success_t: Option<u64>,
+    job_get_success_t: Option<u64>,
+    job_get_error_t: Option<u64>,
+    work_create_success_t: Option<u64>,
+    work_create_error_t: Option<u64>,
+    work_submit_node_received_t: Option<u64>,
+    work_submit_node_add_work_init_t: Option<u64>,
+    work_submit_node_add_work_success_t: Option<u64>,
+    work_submit_success_t: Option<u64>,
+    work_submit_error_t: Option<u64>,
+    timed_out_t: Option<u64>,
+}
+
+impl LifecycleRow {
+    fn new(state: &SnarkWorkerState) -> Self {
+        serde_json::to_value(state)
+            .and_then(serde_json::from_value)
+            .unwrap_or_default()
+    }
+
+    fn error(&self) -> Option<String> {
+        self.error.as_ref().map(|err| match err {
+            serde_json::Value::String(s) => s.clone(),
+            err => err.to_string(),
+        })
+    }
+
+    fn job_get_ms(&self) -> Option<u64> {
+        let end = self.job_get_success_t.or(self.job_get_error_t)?;
+        Some(end.saturating_sub(self.job_get_init_t?))
+    }
+
+    fn work_create_ms(&self) -> Option<u64> {
+        let end = self.work_create_success_t.or(self.work_create_error_t)?;
+        Some(end.saturating_sub(self.job_get_success_t?))
+    }
+
+    fn work_submit_ms(&self) -> Option<u64> {
+        let end = self.work_submit_success_t.or(self.work_submit_error_t)?;
+        Some(end.saturating_sub(self.work_create_success_t?))
+    }
+}
+
+/// Encodes job lifecycles as a Parquet file, one row per lifecycle.
+/// Timestamps are typed as millisecond timestamps, durations as `u64`
+/// milliseconds. `Registered` states aren't lifecycles and are skipped.
+pub fn lifecycles_parquet<'a>(
+    lifecycles: impl IntoIterator<Item = (&'a str, &'a SnarkWorkerState)>,
+) -> Result<Vec<u8>, ParquetError> {
+    let mut worker_ids = vec![];
+    let mut total_ms = vec![];
+    let mut rows = vec![];
+    for (worker_id, state) in lifecycles {
+        if let SnarkWorkerState::Registered { .. } = state {
+            continue;
+        }
+        worker_ids.push(worker_id);
+        total_ms.push(state.end_time().saturating_sub(state.start_time()));
+        rows.push(LifecycleRow::new(state));
+    }
+
+    let mut fields = vec![
+        Field::new("worker_id", DataType::Utf8, false),
+        Field::new("kind", DataType::Utf8, false),
+        Field::new("ids", DataType::Utf8, true),
+        Field::new("error", DataType::Utf8, true),
+        Field::new("fencing_token", DataType::UInt64, true),
+        Field::new("lease_holder", DataType::Utf8, true),
+    ];
+    let mut columns: Vec<ArrayRef> = vec![
+        Arc::new(StringArray::from(worker_ids)),
+        Arc::new(StringArray::from_iter_values(
+            rows.iter().map(|r| r.kind.as_str()),
+        )),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.ids.as_deref()),
+        )),
+        Arc::new(StringArray::from_iter(rows.iter().map(|r| r.error()))),
+        Arc::new(UInt64Array::from_iter(
+            rows.iter().map(|r| Some(r.lease.as_ref()?.fencing_token)),
+        )),
+        Arc::new(StringArray::from_iter(
+            rows.iter().map(|r| r.lease.as_ref()?.holder.as_deref()),
+        )),
+    ];
+
+    let mut timestamp = |name: &str, f: fn(&LifecycleRow) -> Option<u64>| {
+        let ty = DataType::Timestamp(TimeUnit::Millisecond, None);
+        fields.push(Field::new(name, ty, true));
+        columns.push(Arc::new(TimestampMillisecondArray::from_iter(
+            rows.iter().map(|r| f(r).map(|t| t as i64)),
+        )));
+    };
+    timestamp("lease_expires_t", |r| Some(r.lease.as_ref()?.expires_t));
+    timestamp("job_get_init_t", |r| r.job_get_init_t);
+    timestamp("job_get_node_received_t", |r| r.job_get_node_received_t);
+    timestamp("job_get_node_request_work_init_t", |r| {
+        r.job_get_node_request_work_init_t
+    });
+    timestamp("job_get_node_request_work_success_t", |r| {
+        r.job_get_node_request_work_success_t
+    });
+    timestamp("job_get_success_t", |r| r.job_get_success_t);
+    timestamp("job_get_error_t", |r| r.job_get_error_t);
+    timestamp("work_create_success_t", |r| r.work_create_success_t);
+    timestamp("work_create_error_t", |r| r.work_create_error_t);
+    timestamp("work_submit_node_received_t", |r| {
+        r.work_submit_node_received_t
+    });
+    timestamp("work_submit_node_add_work_init_t", |r| {
+        r.work_submit_node_add_work_init_t
+    });
+    timestamp("work_submit_node_add_work_success_t", |r| {
+        r.work_submit_node_add_work_success_t
+    });
+    timestamp("work_submit_success_t", |r| r.work_submit_success_t);
+    timestamp("work_submit_error_t", |r| r.work_submit_error_t);
+    timestamp("timed_out_t", |r| r.timed_out_t);
+
+    let mut duration = |name: &str, f: fn(&LifecycleRow) -> Option<u64>| {
+        fields.push(Field::new(name, DataType::UInt64, true));
+        columns.push(Arc::new(UInt64Array::from_iter(rows.iter().map(f))));
+    };
+    duration("job_get_ms", LifecycleRow::job_get_ms);
+    duration("work_create_ms", LifecycleRow::work_create_ms);
+    duration("work_submit_ms", LifecycleRow::work_submit_ms);
+    fields.push(Field::new("total_ms", DataType::UInt64, false));
+    columns.push(Arc::new(UInt64Array::from(total_ms)));
+
+    let batch = RecordBatch::try_new(Arc::new(Schema::new(fields)), columns)?;
+    let mut buf = Vec::with_capacity(32 * 1024);
+    let mut writer = ArrowWriter::try_new(&mut buf, batch.schema(), None)?;
+    writer.write(&batch)?;
+    writer.close()?;
+    Ok(buf)
+}
+
+type RowField = fn(&LifecycleRow) -> Option<u64>;
+
+/// Timestamp columns of [`lifecycles_csv`], after `worker_id`, `kind`,
+/// `ids`, `error`, `fencing_token` and `lease_holder`.
+const CSV_TIMESTAMPS: &[(&str, RowField)] = &[
+    ("registered_t", |r| r.registered_t),
+    ("lease_expires_t", |r| Some(r.lease.as_ref()?.expires_t)),
+    ("job_get_init_t", |r| r.job_get_init_t),
+    ("job_get_node_received_t", |r| r.job_get_node_received_t),
+    ("job_get_node_request_work_init_t", |r| {
+        r.job_get_node_request_work_init_t
+    }),
+    ("job_get_node_request_work_success_t", |r| {
+        r.job_get_node_request_work_success_t
+    }),
+    ("job_get_success_t", |r| r.job_get_success_t),
+    ("job_get_error_t", |r| r.job_get_error_t),
+    ("work_create_success_t", |r| r.work_create_success_t),
+    ("work_create_error_t", |r| r.work_create_error_t),
+    ("work_submit_node_received_t", |r| {
+        r.work_submit_node_received_t
+    }),
+    ("work_submit_node_add_work_init_t", |r| {
+        r.work_submit_node_add_work_init_t
+    }),
+    ("work_submit_node_add_work_success_t", |r| {
+        r.work_submit_node_add_work_success_t
+    }),
+    ("work_submit_success_t", |r| r.work_submit_success_t),
+    ("work_submit_error_t", |r| r.work_submit_error_t),
+    ("timed_out_t", |r| r.timed_out_t),
+];
+
+/// Quotes `field` if needed, see RFC 4180.
+fn csv_field(field: &str) -> Cow<'_, str> {
+    if field.contains([',', '"', '\n', '\r']) {
+        Cow::Owned(format!("\"{}\"", field.replace('"', "\"\"")))
+    } else {
+        Cow::Borrowed(field)
+    }
+}
+
+fn csv_opt<T: ToString>(v: Option<T>) -> String {
+    v.map_or_else(String::new, |v| v.to_string())
+}
+
+/// Encodes worker states as CSV, one row per state of any kind, with the
+/// columns of all kinds. Timestamps are unix milliseconds, columns a
+/// state doesn't have are empty.
+pub fn lifecycles_csv<'a>(
+    states: impl IntoIterator<Item = (&'a str, &'a SnarkWorkerState)>,
+) -> String {
+    let mut out = String::with_capacity(32 * 1024);
+    out.push_str("worker_id,kind,ids,error,fencing_token,lease_holder");
+    for (name, _) in CSV_TIMESTAMPS {
+        write!(out, ",{name}").unwrap();
+    }
+    out.push_str("\r\n");
+    for (worker_id, state) in states {
+        let row = LifecycleRow::new(state);
+        let error = row.error().unwrap_or_default();
+        let holder = row.lease.as_ref().and_then(|l| l.holder.as_deref());
+        write!(
+            out,
+            "{},{},{},{},{},{}",
+            csv_field(worker_id),
+            csv_field(&row.kind),
+            csv_field(row.ids.as_deref().unwrap_or_default()),
+            csv_field(&error),
+            csv_opt(row.lease.as_ref().map(|l| l.fencing_token)),
+            csv_field(holder.unwrap_or_default()),
+        )
+        .unwrap();
+        for (_, f) in CSV_TIMESTAMPS {
+            write!(out, ",{}", csv_opt(f(&row))).unwrap();
+        }
+        out.push_str("\r\n");
+    }
+    out
+}
+
+/// Encodes a [`Summary`] as CSV, one row per worker, along with the
+/// workers' availability. The first row is the fleet-wide total and has
+/// an empty `worker_id`.
+pub fn summary_csv(summary: &Summary, availability: &AvailabilityReport) -> String {
+    let mut out = String::from(
+        "worker_id,attempted,succeeded,failed_job_get,failed_work_create,\
+         failed_work_submit,no_available_job,in_progress,avg_end_to_end_ms,\
+         seq_gaps,events_lost,availability_day,availability_week\r\n",
+    );
+    let rows = std::iter::once(("", &summary.total))
+        .chain(summary.workers.iter().map(|(id, t)| (id.as_str(), t)));
+    for (worker_id, t) in rows {
+        let LifecycleTotals {
+            attempted,
+            succeeded,
+            failed,
+            no_available_job,
+            in_progress,
+            avg_end_to_end_ms,
+            seq_gaps,
+            events_lost,
+        } = t;
+        let availability = match worker_id {
+            "" => Some(&availability.total),
+            _ => availability.workers.get(worker_id),
+        };
+        write!(
+            out,
+            "{},{attempted},{succeeded},{},{},{},{no_available_job},{in_progress},{},\
+             {seq_gaps},{events_lost},{},{}\r\n",
+            csv_field(worker_id),
+            failed.job_get,
+            failed.work_create,
+            failed.work_submit,
+            csv_opt(*avg_end_to_end_ms),
+            csv_opt(availability.map(|a| a.day)),
+            csv_opt(availability.map(|a| a.week)),
+        )
+        .unwrap();
+    }
+    out
+}
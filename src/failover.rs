@@ -0,0 +1,179 @@
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+use snark_coordinator_rs::{
+    errors::{ApiError, ErrorCode},
+    job_pool,
+};
+use tokio::sync::Mutex;
+use warp::{
+    hyper::{header::LOCATION, Method, StatusCode},
+    path::FullPath,
+    reject::{Reject, Rejection},
+    reply::{with_header, with_status, Reply, Response},
+    Filter,
+};
+
+use crate::graphql;
+
+/// Path switching the role, which a standby has to serve itself.
+const ROLE_PATH: &str = "/admin/role";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum Role {
+    Primary,
+    /// Redirects writes to `primary`, a base URL like `http://host:8080`.
+    Standby {
+        primary: String,
+    },
+    /// In a cluster which has no known leader, rejects writes until one
+    /// is elected.
+    Electing,
+    /// Serves the state rebuilt by `replay`, rejecting all writes.
+    Replay,
+}
+
+#[derive(Debug)]
+struct NotLeader {
+    location: String,
+    primary: String,
+}
+
+impl Reject for NotLeader {}
+
+#[derive(Debug)]
+struct NoLeader;
+
+impl Reject for NoLeader {}
+
+#[derive(Debug)]
+struct ReadOnly;
+
+impl Reject for ReadOnly {}
+
+/// Rejects mutating requests while this instance is a standby, so they
+/// can be redirected to the primary, or replaying.
+pub fn filter(role: Arc<Mutex<Role>>) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .map(Some)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and_then(
+            move |method: Method, path: FullPath, query: Option<String>| {
+                let role = role.clone();
+                async move {
+                    // `GET /job` locks the job it hands out.
+                    let takes_lock = method == Method::GET && path.as_str() == job_pool::PATH;
+                    let is_read = matches!(method, Method::GET | Method::HEAD | Method::OPTIONS);
+                    if is_read && !takes_lock {
+                        return Ok(());
+                    }
+                    let primary = match &*role.lock().await {
+                        Role::Primary => return Ok(()),
+                        // a replay can't be switched to taking writes.
+                        Role::Replay => return Err(warp::reject::custom(ReadOnly)),
+                        _ if path.as_str() == ROLE_PATH || path.as_str() == graphql::PATH => {
+                            return Ok(());
+                        }
+                        Role::Standby { primary } => primary.clone(),
+                        Role::Electing => return Err(warp::reject::custom(NoLeader)),
+                    };
+                    let mut location =
+                        format!("{}{}", primary.trim_end_matches('/'), path.as_str());
+                    if let Some(query) = query {
+                        location = format!("{location}?{query}");
+                    }
+                    Err(warp::reject::custom(NotLeader { location, primary }))
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Turns rejections of [`filter`] into 307 redirects to the primary. 307
+/// keeps the method and body, so clients following redirects fail over
+/// transparently; the others get the primary from the error body. Without
+/// a leader, they get a 503 to retry, and while replaying a 405.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    if rejection.find::<ReadOnly>().is_some() {
+        let code = ErrorCode::ReadOnly;
+        let body = ApiError::new(code, "replaying a journal, writes are disabled");
+        let status = StatusCode::from_u16(code.status()).unwrap();
+        return Ok(with_status(serde_json::to_string(&body).unwrap(), status).into_response());
+    }
+    if rejection.find::<NoLeader>().is_some() {
+        let code = ErrorCode::NoLeader;
+        let body = ApiError::new(code, "no leader elected yet, retry later");
+        let status = StatusCode::from_u16(code.status()).unwrap();
+        return Ok(with_status(serde_json::to_string(&body).unwrap(), status).into_response());
+    }
+    let Some(NotLeader { location, primary }) = rejection.find::<NotLeader>() else {
+        return Err(rejection);
+    };
+    let code = ErrorCode::NotLeader;
+    let body = ApiError::new(code, format!("not leader, leader is {primary}"));
+    let reply = with_status(
+        serde_json::to_string(&body).unwrap(),
+        StatusCode::from_u16(code.status()).unwrap(),
+    );
+    Ok(with_header(reply, LOCATION, location.as_str()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn request(role: Role, method: &str, path: &str) -> Response {
+        let route = filter(Arc::new(Mutex::new(role)))
+            .map(|| "served")
+            .recover(recover);
+        let req = warp::test::request().method(method).path(path);
+        req.reply(&route).await.into_response()
+    }
+
+    #[tokio::test]
+    async fn standbys_redirect_writes_to_the_primary() {
+        let standby = || Role::Standby {
+            primary: "http://primary:8080/".to_owned(),
+        };
+        let res = request(standby(), "PUT", "/lock-job/j1?timeout=5").await;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+        assert_eq!(
+            res.headers()[LOCATION],
+            "http://primary:8080/lock-job/j1?timeout=5"
+        );
+        // handing out a job locks it.
+        let res = request(standby(), "GET", job_pool::PATH).await;
+        assert_eq!(res.status(), StatusCode::TEMPORARY_REDIRECT);
+
+        for (method, path) in [
+            ("GET", "/workers"),
+            ("PUT", ROLE_PATH),
+            ("POST", "/graphql"),
+        ] {
+            let res = request(standby(), method, path).await;
+            assert_eq!(res.status(), StatusCode::OK, "{method} {path}");
+        }
+        let res = request(Role::Primary, "PUT", "/lock-job/j1").await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+
+    #[tokio::test]
+    async fn writes_wait_for_a_leader_and_replays_take_none() {
+        let res = request(Role::Electing, "PUT", "/lock-job/j1").await;
+        assert_eq!(res.status(), StatusCode::SERVICE_UNAVAILABLE);
+        let res = request(Role::Electing, "GET", "/workers").await;
+        assert_eq!(res.status(), StatusCode::OK);
+
+        // not even switching the role.
+        let res = request(Role::Replay, "PUT", ROLE_PATH).await;
+        assert_eq!(res.status(), StatusCode::METHOD_NOT_ALLOWED);
+        let res = request(Role::Replay, "GET", ROLE_PATH).await;
+        assert_eq!(res.status(), StatusCode::OK);
+    }
+}
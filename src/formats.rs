@@ -0,0 +1,170 @@
+//! MessagePack and CBOR besides JSON, for workers reporting often enough
+//! for JSON's size and parsing to matter. The format is negotiated per
+//! request: request bodies by their `Content-Type`, and are turned into
+//! JSON before handlers see them, GET responses by `Accept`, and are
+//! turned from JSON. Either way the same serde types describe the data.
+//! Structs have to be encoded as maps with field names, e.g. with
+//! `rmp_serde::to_vec_named`.
+
+use snark_coordinator_rs::errors::ErrorCode;
+use warp::{
+    hyper::{
+        body::{to_bytes, HttpBody},
+        header::{HeaderValue, ACCEPT, CONTENT_LENGTH, CONTENT_TYPE, VARY},
+        Body, Method, Request, Response,
+    },
+    Reply,
+};
+
+use crate::error_reply;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    MessagePack,
+    Cbor,
+}
+
+impl Format {
+    pub fn content_type(self) -> &'static str {
+        match self {
+            Self::MessagePack => "application/msgpack",
+            Self::Cbor => "application/cbor",
+        }
+    }
+
+    /// Format of the media type, `None` for JSON and anything else.
+    fn from_media_type(media_type: &str) -> Option<Self> {
+        let media_type = media_type.split(';').next().unwrap_or("").trim();
+        match media_type.to_ascii_lowercase().as_str() {
+            "application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack" => {
+                Some(Self::MessagePack)
+            }
+            "application/cbor" => Some(Self::Cbor),
+            _ => None,
+        }
+    }
+
+    /// Format of a request body with the `Content-Type`.
+    pub fn of_body(content_type: Option<&str>) -> Option<Self> {
+        content_type.and_then(Self::from_media_type)
+    }
+
+    /// Transcodes `body` to JSON.
+    pub fn decode(self, body: &[u8]) -> Result<Vec<u8>, String> {
+        let value: serde_json::Value = match self {
+            Self::MessagePack => rmp_serde::from_slice(body).map_err(|err| err.to_string())?,
+            Self::Cbor => ciborium::from_reader(body).map_err(|err| err.to_string())?,
+        };
+        Ok(serde_json::to_vec(&value).unwrap())
+    }
+
+    /// Transcodes a JSON `body`, `None` if it isn't JSON.
+    fn encode(self, body: &[u8]) -> Option<Vec<u8>> {
+        let value = serde_json::from_slice::<serde_json::Value>(body).ok()?;
+        match self {
+            Self::MessagePack => rmp_serde::to_vec_named(&value).ok(),
+            Self::Cbor => {
+                let mut buf = vec![];
+                ciborium::into_writer(&value, &mut buf).ok()?;
+                Some(buf)
+            }
+        }
+    }
+}
+
+/// Format to encode the response to `req` with, based on its `Accept`.
+/// Only GET responses are encoded, and JSON is kept unless another format
+/// is preferred.
+pub fn negotiate(req: &Request<Body>) -> Option<Format> {
+    if req.method() != Method::GET {
+        return None;
+    }
+    let accept = req.headers().get(ACCEPT)?.to_str().ok()?;
+    let mut json_q = 0.0;
+    let mut best: Option<(Format, f32)> = None;
+    for item in accept.split(',') {
+        let q = item
+            .split(';')
+            .find_map(|p| p.trim().strip_prefix("q="))
+            .map_or(Some(1.0), |q| q.trim().parse::<f32>().ok())
+            .unwrap_or(0.0);
+        let media_type = item.split(';').next().unwrap_or("").trim();
+        match Format::from_media_type(media_type) {
+            Some(format) if best.is_none_or(|(_, best_q)| q > best_q) => best = Some((format, q)),
+            Some(_) => {}
+            None if media_type.eq_ignore_ascii_case("application/json") => json_q = q,
+            None => {}
+        }
+    }
+    best.filter(|&(_, q)| q > 0.0 && q >= json_q)
+        .map(|(format, _)| format)
+}
+
+/// Transcodes JSON response bodies. Others, e.g. CSV, are left alone.
+/// Routes streaming JSON find the format in the request's extensions and
+/// stream it encoded, see [`crate::stream::object`]. JSON streamed anyway
+/// can't be transcoded once sent, so it's refused with 406 rather than
+/// sent as what wasn't asked for.
+pub async fn transcode(res: Response<Body>, format: Format) -> Response<Body> {
+    // JSON is sent as text/plain by most routes.
+    let is_json = res.headers().get(CONTENT_TYPE).is_none_or(|v| {
+        v.as_bytes().starts_with(b"application/json") || v.as_bytes().starts_with(b"text/plain")
+    });
+    if !is_json {
+        return res;
+    }
+    if res.body().size_hint().exact().is_none() {
+        let msg = format!(
+            "response is streamed as JSON, not {}",
+            format.content_type()
+        );
+        let mut res = error_reply(ErrorCode::NotAcceptable, msg).into_response();
+        res.headers_mut()
+            .append(VARY, HeaderValue::from_static("accept"));
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    parts
+        .headers
+        .append(VARY, HeaderValue::from_static("accept"));
+    let body = match to_bytes(body).await {
+        Ok(body) => body,
+        Err(err) => {
+            tracing::warn!(%err, "failed to read response body");
+            return Response::from_parts(parts, Body::empty());
+        }
+    };
+    let Some(encoded) = format.encode(&body) else {
+        return Response::from_parts(parts, Body::from(body));
+    };
+    parts.headers.remove(CONTENT_LENGTH);
+    parts.headers.insert(
+        CONTENT_TYPE,
+        HeaderValue::from_static(format.content_type()),
+    );
+    Response::from_parts(parts, Body::from(encoded))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures_util::stream;
+    use warp::hyper::StatusCode;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn buffered_json_is_transcoded_and_streamed_json_refused() {
+        let res = Response::new(Body::from(r#"{"w1":[]}"#));
+        let res = transcode(res, Format::MessagePack).await;
+        assert_eq!(res.headers()[CONTENT_TYPE], "application/msgpack");
+        let body = to_bytes(res.into_body()).await.unwrap();
+        let value = rmp_serde::from_slice::<serde_json::Value>(&body).unwrap();
+        assert_eq!(value, serde_json::json!({ "w1": [] }));
+
+        let chunks = stream::iter([Ok::<_, std::io::Error>(r#"{"w1":[]}"#)]);
+        let res = Response::new(Body::wrap_stream(chunks));
+        let res = transcode(res, Format::Cbor).await;
+        assert_eq!(res.status(), StatusCode::NOT_ACCEPTABLE);
+        assert_eq!(res.headers()[VARY], "accept");
+    }
+}
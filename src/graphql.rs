@@ -0,0 +1,340 @@
+//! GraphQL queries over the worker stats and lock tables at `/graphql`,
+//! so dashboards can select just the fields and time ranges they need,
+//! e.g. the recent errors of a few workers, instead of fetching every
+//! state of the fleet. The schema is read-only, its POST requests are
+//! authorized and served like GET ones.
+
+use std::{sync::Arc, time::Instant};
+
+use async_graphql::{
+    http::parse_query_string, Context, EmptyMutation, EmptySubscription, Json, Object, Schema,
+};
+use snark_coordinator_rs::{
+    groups::{GroupsConfig, Scope},
+    lock::LockShards,
+    lock_namespaces,
+    stats::{self, Order, SnarkWorkerState, WorkerStats},
+    timestamp,
+};
+use tokio::sync::RwLock;
+use warp::{
+    hyper::{body::Bytes, StatusCode},
+    reply::{with_status, Reply, Response},
+    Filter,
+};
+
+use crate::{body, caller_scope, unauthorized_reply};
+
+/// Path of the endpoint. Its POST requests are queries.
+pub const PATH: &str = "/graphql";
+
+pub type CoordinatorSchema = Schema<Query, EmptyMutation, EmptySubscription>;
+
+pub fn schema(stats: Arc<RwLock<WorkerStats>>, locks: Arc<LockShards>) -> CoordinatorSchema {
+    Schema::build(Query, EmptyMutation, EmptySubscription)
+        .data(stats)
+        .data(locks)
+        .finish()
+}
+
+/// `GET /graphql?query=..` and `POST /graphql` with a JSON body.
+pub fn route(
+    schema: CoordinatorSchema,
+    groups: Option<Arc<GroupsConfig>>,
+    max_body_size: u64,
+) -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    let get = warp::get()
+        .and(warp::query::raw().or(warp::any().map(String::new)).unify())
+        .map(|query: String| parse_query_string(&query).map_err(|err| err.to_string()));
+    let post = warp::post()
+        .and(body::bytes(max_body_size))
+        .map(|body: Bytes| serde_json::from_slice(&body).map_err(|err| err.to_string()));
+    warp::path!("graphql")
+        .and(get.or(post).unify())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |request: Result<async_graphql::Request, String>,
+                  authorization: Option<String>| {
+                let schema = schema.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    let request = match request {
+                        Ok(request) => request,
+                        Err(msg) => {
+                            let body = serde_json::json!({ "errors": [{ "message": msg }] });
+                            return with_status(body.to_string(), StatusCode::BAD_REQUEST)
+                                .into_response();
+                        }
+                    };
+                    let res = schema.execute(request.data(scope)).await;
+                    warp::reply::with_header(
+                        serde_json::to_string(&res).unwrap(),
+                        "content-type",
+                        "application/json",
+                    )
+                    .into_response()
+                }
+            },
+        )
+}
+
+fn stats<'a>(ctx: &Context<'a>) -> &'a Arc<RwLock<WorkerStats>> {
+    ctx.data_unchecked()
+}
+
+pub struct Query;
+
+#[Object]
+impl Query {
+    /// Workers within the caller's scope, optionally only `ids` or the
+    /// sessions of the worker `name`.
+    async fn workers(
+        &self,
+        ctx: &Context<'_>,
+        ids: Option<Vec<String>>,
+        name: Option<String>,
+    ) -> Vec<Worker> {
+        let scope = ctx.data_unchecked::<Scope>();
+        let stats = stats(ctx).read().await;
+        let sessions = name.map(|name| stats.sessions(&name).map(str::to_owned).collect());
+        let mut workers = stats
+            .keys()
+            .filter(|k| scope.contains(k))
+            .filter(|k| ids.as_ref().is_none_or(|ids| ids.contains(k)))
+            .filter(|k| {
+                sessions
+                    .as_ref()
+                    .is_none_or(|s: &Vec<String>| s.contains(k))
+            })
+            .map(|k| Worker { id: k.clone() })
+            .collect::<Vec<_>>();
+        workers.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        workers
+    }
+
+    async fn worker(&self, ctx: &Context<'_>, id: String) -> Option<Worker> {
+        let scope = ctx.data_unchecked::<Scope>();
+        let stats = stats(ctx).read().await;
+        (scope.contains(&id) && stats.get(&id).is_some()).then_some(Worker { id })
+    }
+
+    /// Locks held now by workers within the caller's scope, optionally
+    /// only those in `namespace`, with the keys relative to it. Anonymous
+    /// locks are only listed to callers seeing every worker.
+    async fn locks(&self, ctx: &Context<'_>, namespace: Option<String>) -> Vec<Lock> {
+        let scope = ctx.data_unchecked::<Scope>();
+        let locks = ctx.data_unchecked::<Arc<LockShards>>();
+        let kv = locks.lock_all().await;
+        kv.leases(Instant::now())
+            .into_iter()
+            .filter(|(_, lease)| match (scope, &lease.holder) {
+                (Scope::All, _) => true,
+                (scope, holder) => holder.as_deref().is_some_and(|h| scope.contains(h)),
+            })
+            .filter_map(|(key, lease)| {
+                let key = match &namespace {
+                    Some(namespace) => lock_namespaces::strip(namespace, key)?,
+                    None => key,
+                };
+                Some(Lock {
+                    key: key.to_owned(),
+                    lease,
+                })
+            })
+            .collect()
+    }
+}
+
+pub struct Worker {
+    id: String,
+}
+
+#[Object]
+impl Worker {
+    async fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Name the worker registered with, for session ids.
+    async fn name(&self, ctx: &Context<'_>) -> Option<String> {
+        stats(ctx).read().await.name(&self.id).map(str::to_owned)
+    }
+
+    /// Metadata of its most recent registration.
+    async fn metadata(&self, ctx: &Context<'_>) -> Option<Metadata> {
+        let stats = stats(ctx).read().await;
+        let states = stats.get(&self.id)?;
+        states
+            .iter()
+            .find_map(|s| s.metadata())
+            .cloned()
+            .map(Metadata)
+    }
+
+    /// Most recent state.
+    async fn latest(&self, ctx: &Context<'_>) -> Option<State> {
+        stats(ctx).read().await.latest(&self.id).cloned().map(State)
+    }
+
+    /// States which ended within `fromT..toT`, `fromT` inclusive and `toT`
+    /// exclusive, most recent first, optionally only those of `kinds` and
+    /// at most `limit`.
+    async fn states(
+        &self,
+        ctx: &Context<'_>,
+        from_t: Option<u64>,
+        to_t: Option<u64>,
+        kinds: Option<Vec<String>>,
+        limit: Option<usize>,
+    ) -> Vec<State> {
+        let stats = stats(ctx).read().await;
+        let from_t = from_t.map(timestamp::normalize);
+        let to_t = to_t.map(timestamp::normalize);
+        stats
+            .in_range(&self.id, from_t, to_t, Order::Desc)
+            .map(|(_, s)| s)
+            .filter(|s| {
+                kinds
+                    .as_ref()
+                    .is_none_or(|k| k.iter().any(|k| k == s.kind()))
+            })
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|s| State(s.clone()))
+            .collect()
+    }
+
+    /// Locks held by the worker now.
+    async fn locks(&self, ctx: &Context<'_>) -> Vec<Lock> {
+        let locks = ctx.data_unchecked::<Arc<LockShards>>();
+        let kv = locks.lock_all().await;
+        kv.leases(Instant::now())
+            .into_iter()
+            .filter(|(_, lease)| lease.holder.as_ref() == Some(&self.id))
+            .map(|(key, lease)| Lock {
+                key: key.to_owned(),
+                lease,
+            })
+            .collect()
+    }
+}
+
+/// A job lifecycle or (re)registration of a worker.
+pub struct State(SnarkWorkerState);
+
+#[Object]
+impl State {
+    async fn kind(&self) -> &str {
+        self.0.kind()
+    }
+
+    async fn start_t(&self) -> u64 {
+        self.0.start_time()
+    }
+
+    async fn end_t(&self) -> u64 {
+        self.0.end_time()
+    }
+
+    /// Ids of the job, for states past getting one.
+    async fn ids(&self) -> Option<&str> {
+        self.0.ids()
+    }
+
+    /// Milliseconds from getting the job to submitting its work.
+    async fn job_duration(&self) -> Option<u64> {
+        self.0.job_duration()
+    }
+
+    /// Occurrences of a collapsed error.
+    async fn count(&self) -> u64 {
+        self.0.count()
+    }
+
+    async fn last_seen_t(&self) -> Option<u64> {
+        self.0.last_seen_t()
+    }
+
+    async fn lease(&self) -> Option<Lease> {
+        self.0.lease().cloned().map(Lease)
+    }
+
+    /// The state as served by `GET /worker-stats`.
+    async fn data(&self) -> Json<&SnarkWorkerState> {
+        Json(&self.0)
+    }
+}
+
+pub struct Metadata(stats::WorkerMetadata);
+
+#[Object]
+impl Metadata {
+    async fn hostname(&self) -> Option<&str> {
+        self.0.hostname.as_deref()
+    }
+
+    async fn datacenter(&self) -> Option<&str> {
+        self.0.datacenter.as_deref()
+    }
+
+    async fn prover_version(&self) -> Option<&str> {
+        self.0.prover_version.as_deref()
+    }
+
+    async fn threads(&self) -> Option<u32> {
+        self.0.threads
+    }
+
+    /// In nanomina.
+    async fn fee(&self) -> Option<u64> {
+        self.0.fee
+    }
+
+    async fn gpu(&self) -> Option<bool> {
+        self.0.gpu
+    }
+}
+
+pub struct Lease(stats::Lease);
+
+#[Object]
+impl Lease {
+    async fn fencing_token(&self) -> u64 {
+        self.0.fencing_token
+    }
+
+    async fn holder(&self) -> Option<&str> {
+        self.0.holder.as_deref()
+    }
+
+    async fn expires_t(&self) -> u64 {
+        self.0.expires_t
+    }
+}
+
+pub struct Lock {
+    key: String,
+    lease: stats::Lease,
+}
+
+#[Object]
+impl Lock {
+    async fn key(&self) -> &str {
+        &self.key
+    }
+
+    async fn fencing_token(&self) -> u64 {
+        self.lease.fencing_token
+    }
+
+    /// Worker id given when acquiring the lock.
+    async fn holder(&self) -> Option<&str> {
+        self.lease.holder.as_deref()
+    }
+
+    async fn expires_t(&self) -> u64 {
+        self.lease.expires_t
+    }
+}
@@ -0,0 +1,84 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::tenants;
+
+/// Team layout of the fleet, loaded from `--groups-file`.
+///
+/// ```json
+/// {
+///   "keys": {
+///     "secret-a": { "role": "team", "team": "alpha" },
+///     "secret-ops": { "role": "admin" }
+///   },
+///   "teams": { "alpha": ["alpha-", "gpu-alpha-"] }
+/// }
+/// ```
+///
+/// A team owns every worker whose id starts with one of its prefixes.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct GroupsConfig {
+    /// API key -> role of the caller presenting it.
+    pub keys: HashMap<String, Role>,
+    /// Team name -> worker id prefixes owned by the team.
+    pub teams: HashMap<String, Vec<String>>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "role", rename_all = "lowercase")]
+pub enum Role {
+    Admin,
+    Team {
+        team: String,
+    },
+    /// Added for the keys of `--tenants-file`.
+    Tenant {
+        tenant: String,
+    },
+}
+
+/// Set of workers visible to a caller.
+#[derive(Debug, Clone)]
+pub enum Scope {
+    All,
+    Prefixes(Vec<String>),
+}
+
+impl GroupsConfig {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        serde_json::from_str(&s).map_err(|err| format!("{path}: {err}"))
+    }
+
+    /// Team owning `worker_id`, if any.
+    pub fn team_of(&self, worker_id: &str) -> Option<&str> {
+        self.teams.iter().find_map(|(team, prefixes)| {
+            let owned = prefixes.iter().any(|p| worker_id.starts_with(p));
+            owned.then_some(team.as_str())
+        })
+    }
+
+    /// Resolves the scope of a caller from its `Authorization` header
+    /// (`Bearer <key>`). Returns `None` for missing or unknown keys.
+    pub fn scope(&self, authorization: Option<&str>) -> Option<Scope> {
+        let key = authorization?;
+        let key = key.strip_prefix("Bearer ").unwrap_or(key).trim();
+        match self.keys.get(key)? {
+            Role::Admin => Some(Scope::All),
+            Role::Team { team } => Some(Scope::Prefixes(
+                self.teams.get(team).cloned().unwrap_or_default(),
+            )),
+            Role::Tenant { tenant } => Some(Scope::Prefixes(vec![tenants::prefix(tenant)])),
+        }
+    }
+}
+
+impl Scope {
+    pub fn contains(&self, worker_id: &str) -> bool {
+        match self {
+            Self::All => true,
+            Self::Prefixes(prefixes) => prefixes.iter().any(|p| worker_id.starts_with(p)),
+        }
+    }
+}
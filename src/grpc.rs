@@ -0,0 +1,386 @@
+//! gRPC interface for tooling which is gRPC-native, served on its own
+//! port with `--grpc-listen`. The schema is `proto/coordinator.proto`.
+//! Like the JSON-RPC endpoint, calls are translated into the equivalent
+//! REST requests and served by the same routes, so behaviour, auth and
+//! metrics are identical. `WatchStats` streams the live feed of `GET /ws`.
+
+// `Status` is what the generated service trait returns.
+#![allow(clippy::result_large_err)]
+
+use std::{convert::Infallible, net::SocketAddr, pin::Pin, sync::Arc};
+
+use futures_util::{Stream, StreamExt};
+use serde::Serialize;
+use snark_coordinator_rs::{
+    api_keys::{Access, ApiKeys, AuthError},
+    errors::{ApiError, ErrorCode},
+    groups::GroupsConfig,
+    idempotency,
+    lock::{LockJobGranted, LockJobHeld},
+    lock_namespaces,
+    stats::Transition,
+    worker_tokens,
+};
+use tokio::sync::broadcast;
+use tokio_stream::wrappers::{errors::BroadcastStreamRecvError, BroadcastStream};
+use tonic::{metadata::MetadataMap, Code, Request, Response, Status, Streaming};
+use warp::hyper::{self, header::AUTHORIZATION, service::Service, Body, Method, StatusCode};
+
+use crate::{caller_scope, throttle::ClientAddr};
+
+pub mod proto {
+    tonic::include_proto!("snark_coordinator.v1");
+}
+
+use proto::{
+    coordinator_server::CoordinatorServer, lock_job_response, EventError, LockGranted, LockHeld,
+    LockJobRequest, LockJobResponse, PutWorkerStatsRequest, PutWorkerStatsResponse,
+    ReleaseJobRequest, ReleaseJobResponse, StatsTransition, WatchStatsRequest,
+};
+
+/// Internal REST request a call maps to.
+struct Call {
+    method: Method,
+    path: String,
+    headers: Vec<(&'static str, String)>,
+    body: Option<String>,
+}
+
+/// Response of the route a call was served by.
+struct Reply {
+    status: StatusCode,
+    headers: hyper::HeaderMap,
+    body: hyper::body::Bytes,
+}
+
+pub struct Coordinator<S> {
+    svc: S,
+    transitions: broadcast::Sender<Transition>,
+    groups: Option<Arc<GroupsConfig>>,
+    api_keys: Option<Arc<ApiKeys>>,
+    protect_reads: bool,
+}
+
+impl<S> Coordinator<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    pub fn new(
+        svc: S,
+        transitions: broadcast::Sender<Transition>,
+        groups: Option<Arc<GroupsConfig>>,
+        api_keys: Option<Arc<ApiKeys>>,
+        protect_reads: bool,
+    ) -> Self {
+        Self {
+            svc,
+            transitions,
+            groups,
+            api_keys,
+            protect_reads,
+        }
+    }
+
+    /// Serves the calls on `addr` until the process exits.
+    pub async fn serve(self, addr: SocketAddr) -> Result<(), tonic::transport::Error> {
+        tonic::transport::Server::builder()
+            .add_service(CoordinatorServer::new(self))
+            .serve(addr)
+            .await
+    }
+
+    async fn execute(
+        &self,
+        call: Call,
+        metadata: &MetadataMap,
+        remote_addr: Option<SocketAddr>,
+    ) -> Result<Reply, Status> {
+        let mut req = hyper::Request::builder().method(call.method).uri(call.path);
+        if let Some(addr) = remote_addr {
+            req = req.extension(ClientAddr(addr));
+        }
+        if let Some(authorization) = authorization(metadata) {
+            req = req.header(AUTHORIZATION, authorization);
+        }
+        for (name, value) in call.headers {
+            req = req.header(name, value);
+        }
+        let body = match call.body {
+            Some(body) => {
+                req = req.header("content-type", "application/json");
+                Body::from(body)
+            }
+            None => Body::empty(),
+        };
+        let req = req
+            .body(body)
+            .map_err(|err| Status::invalid_argument(err.to_string()))?;
+        let res = match self.svc.clone().call(req).await {
+            Ok(res) => res,
+            Err(never) => match never {},
+        };
+        let (parts, body) = res.into_parts();
+        let body = hyper::body::to_bytes(body)
+            .await
+            .map_err(|err| Status::internal(err.to_string()))?;
+        Ok(Reply {
+            status: parts.status,
+            headers: parts.headers,
+            body,
+        })
+    }
+}
+
+fn authorization(metadata: &MetadataMap) -> Option<&str> {
+    metadata.get("authorization")?.to_str().ok()
+}
+
+/// Path segment, refusing values which would change the request target.
+fn segment(s: &str) -> Result<&str, Status> {
+    if s.is_empty() || s.contains(['/', '?', '#', '%']) || s.contains(char::is_whitespace) {
+        return Err(Status::invalid_argument(format!(
+            "invalid path segment: {s:?}"
+        )));
+    }
+    Ok(s)
+}
+
+fn query(params: &impl Serialize) -> String {
+    match serde_urlencoded::to_string(params).unwrap_or_default() {
+        query if query.is_empty() => query,
+        query => format!("?{query}"),
+    }
+}
+
+/// Code and message of an error response. Some routes answer with a
+/// plain text body, which becomes the message.
+fn api_error(reply: &Reply) -> (Option<ErrorCode>, String) {
+    match serde_json::from_slice::<ApiError>(&reply.body) {
+        Ok(err) => (Some(err.code), err.message),
+        Err(_) if reply.body.is_empty() => (None, reply.status.to_string()),
+        Err(_) => (None, String::from_utf8_lossy(&reply.body).into_owned()),
+    }
+}
+
+/// Wire name of `code`, e.g. `LOCK_HELD`.
+fn code_name(code: ErrorCode) -> String {
+    serde_json::to_value(code)
+        .ok()
+        .and_then(|code| code.as_str().map(str::to_owned))
+        .unwrap_or_default()
+}
+
+/// Status closest to the HTTP one of an error response, with the error
+/// code in the `x-error-code` metadata.
+fn status(reply: &Reply) -> Status {
+    let (error_code, message) = api_error(reply);
+    let code = match reply.status.as_u16() {
+        400 | 413 | 415 | 422 => Code::InvalidArgument,
+        401 => Code::Unauthenticated,
+        403 => Code::PermissionDenied,
+        404 => Code::NotFound,
+        409 => Code::FailedPrecondition,
+        429 => Code::ResourceExhausted,
+        503 => Code::Unavailable,
+        _ => Code::Internal,
+    };
+    let mut status = Status::new(code, message);
+    if let Some(value) = error_code.and_then(|code| code_name(code).parse().ok()) {
+        status.metadata_mut().insert("x-error-code", value);
+    }
+    status
+}
+
+fn namespace_header(namespace: Option<String>) -> Vec<(&'static str, String)> {
+    namespace
+        .map(|ns| (lock_namespaces::HEADER, ns))
+        .into_iter()
+        .collect()
+}
+
+type TransitionStream = Pin<Box<dyn Stream<Item = Result<StatsTransition, Status>> + Send>>;
+
+#[tonic::async_trait]
+impl<S> proto::coordinator_server::Coordinator for Coordinator<S>
+where
+    S: Service<hyper::Request<Body>, Response = hyper::Response<Body>, Error = Infallible>
+        + Clone
+        + Send
+        + Sync
+        + 'static,
+    S::Future: Send,
+{
+    async fn lock_job(
+        &self,
+        request: Request<LockJobRequest>,
+    ) -> Result<Response<LockJobResponse>, Status> {
+        #[derive(Serialize)]
+        struct Query {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            timeout_ms: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            worker_id: Option<String>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            wait: Option<u16>,
+        }
+        let remote_addr = request.remote_addr();
+        let (metadata, _, req) = request.into_parts();
+        let query = query(&Query {
+            timeout_ms: req.timeout_ms,
+            worker_id: req.worker_id,
+            wait: req.wait.map(|wait| wait.min(u16::MAX.into()) as u16),
+        });
+        let call = Call {
+            method: Method::PUT,
+            path: format!("/lock-job/{}{query}", segment(&req.key)?),
+            headers: namespace_header(req.namespace),
+            body: None,
+        };
+        let reply = self.execute(call, &metadata, remote_addr).await?;
+        let result = match reply.status {
+            StatusCode::CREATED => {
+                let granted: LockJobGranted = serde_json::from_slice(&reply.body)
+                    .map_err(|err| Status::internal(err.to_string()))?;
+                lock_job_response::Result::Granted(LockGranted {
+                    fencing_token: granted.fencing_token,
+                })
+            }
+            StatusCode::OK => {
+                let held: LockJobHeld = serde_json::from_slice(&reply.body)
+                    .map_err(|err| Status::internal(err.to_string()))?;
+                lock_job_response::Result::Held(LockHeld {
+                    holder: held.holder,
+                    remaining_ttl_ms: held.remaining_ttl_ms,
+                })
+            }
+            _ => return Err(status(&reply)),
+        };
+        Ok(Response::new(LockJobResponse {
+            result: Some(result),
+        }))
+    }
+
+    async fn release_job(
+        &self,
+        request: Request<ReleaseJobRequest>,
+    ) -> Result<Response<ReleaseJobResponse>, Status> {
+        #[derive(Serialize)]
+        struct Query {
+            #[serde(skip_serializing_if = "Option::is_none")]
+            fencing_token: Option<u64>,
+            #[serde(skip_serializing_if = "Option::is_none")]
+            worker_id: Option<String>,
+        }
+        let remote_addr = request.remote_addr();
+        let (metadata, _, req) = request.into_parts();
+        let query = query(&Query {
+            fencing_token: req.fencing_token,
+            worker_id: req.worker_id,
+        });
+        let call = Call {
+            method: Method::DELETE,
+            path: format!("/lock-job/{}{query}", segment(&req.key)?),
+            headers: namespace_header(req.namespace),
+            body: None,
+        };
+        let reply = self.execute(call, &metadata, remote_addr).await?;
+        if !reply.status.is_success() {
+            return Err(status(&reply));
+        }
+        Ok(Response::new(ReleaseJobResponse {}))
+    }
+
+    async fn put_worker_stats(
+        &self,
+        request: Request<Streaming<PutWorkerStatsRequest>>,
+    ) -> Result<Response<PutWorkerStatsResponse>, Status> {
+        let remote_addr = request.remote_addr();
+        let (metadata, _, mut events) = request.into_parts();
+        let mut res = PutWorkerStatsResponse::default();
+        let mut index = 0;
+        while let Some(event) = events.message().await? {
+            let mut headers = namespace_header(event.namespace);
+            headers.extend(event.token.map(|token| (worker_tokens::HEADER, token)));
+            headers.extend(event.idempotency_key.map(|key| (idempotency::HEADER, key)));
+            let call = Call {
+                method: Method::PUT,
+                path: format!("/worker-stats/{}", segment(&event.worker_id)?),
+                headers,
+                body: Some(event.event_json),
+            };
+            let reply = self.execute(call, &metadata, remote_addr).await?;
+            if reply.status.is_success() {
+                res.applied += 1;
+                // registering answers with the session id.
+                if !reply.body.is_empty() {
+                    res.session_id = Some(String::from_utf8_lossy(&reply.body).into_owned());
+                    res.token = reply
+                        .headers
+                        .get(worker_tokens::HEADER)
+                        .and_then(|token| token.to_str().ok())
+                        .map(str::to_owned);
+                }
+            } else {
+                let (code, message) = api_error(&reply);
+                res.errors.push(EventError {
+                    index,
+                    code: code.map(code_name).unwrap_or_default(),
+                    message,
+                });
+            }
+            index += 1;
+        }
+        Ok(Response::new(res))
+    }
+
+    type WatchStatsStream = TransitionStream;
+
+    async fn watch_stats(
+        &self,
+        request: Request<WatchStatsRequest>,
+    ) -> Result<Response<Self::WatchStatsStream>, Status> {
+        let authorization = authorization(request.metadata());
+        if let Some(keys) = self.api_keys.as_deref().filter(|_| self.protect_reads) {
+            if let Err(err) = keys.authorize(authorization, Access::Read) {
+                let code = match err {
+                    AuthError::Unauthorized(_) => Code::Unauthenticated,
+                    AuthError::Forbidden(_) => Code::PermissionDenied,
+                };
+                return Err(Status::new(code, err.message()));
+            }
+        }
+        let Some(scope) = caller_scope(self.groups.as_deref(), authorization) else {
+            return Err(Status::unauthenticated("missing or unknown group key"));
+        };
+        let workers = request.into_inner().workers;
+        let stream = BroadcastStream::new(self.transitions.subscribe()).filter_map(move |t| {
+            let res = match t {
+                Ok(t) => {
+                    let wanted = scope.contains(&t.worker_id)
+                        && (workers.is_empty() || workers.contains(&t.worker_id));
+                    wanted.then(|| {
+                        Ok(StatsTransition {
+                            worker_id: t.worker_id,
+                            old_kind: t.old_kind,
+                            new_kind: t.new_kind,
+                            state_json: serde_json::to_string(&t.state).unwrap(),
+                            applied_t: t.applied_t,
+                        })
+                    })
+                }
+                // subscribers falling behind are ended rather than sent
+                // an incomplete feed.
+                Err(BroadcastStreamRecvError::Lagged(missed)) => Some(Err(Status::data_loss(
+                    format!("fell behind, missed {missed} transitions"),
+                ))),
+            };
+            std::future::ready(res)
+        });
+        Ok(Response::new(Box::pin(stream)))
+    }
+}
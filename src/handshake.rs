@@ -0,0 +1,152 @@
+//! `POST /handshake`, which workers call before registering to find out
+//! whether they're set up right: their clock, the stats schema they speak
+//! and their prover version. Auth is checked like for any write, so a
+//! worker with a bad key fails here already.
+
+use std::cmp::Ordering;
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    errors::{ApiError, ErrorCode},
+    stats::WorkerMetadata,
+    timestamp,
+};
+
+/// Version of the worker-stats schema this coordinator speaks, bumped on
+/// breaking changes to the events workers send.
+pub const SCHEMA_VERSION: u32 = 1;
+/// Oldest worker-stats schema version still accepted.
+pub const MIN_SCHEMA_VERSION: u32 = 1;
+
+fn default_schema_version() -> u32 {
+    MIN_SCHEMA_VERSION
+}
+
+/// Request body of `POST /handshake`.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct HandshakeRequest {
+    /// Name the worker is going to register under.
+    pub worker_id: String,
+    /// The worker's current time.
+    #[serde(deserialize_with = "timestamp::deserialize")]
+    pub time: u64,
+    /// Worker-stats schema version the worker speaks, the oldest one
+    /// supported if not given.
+    #[serde(default = "default_schema_version")]
+    pub schema_version: u32,
+    #[serde(default)]
+    pub metadata: Option<WorkerMetadata>,
+}
+
+/// Response body of `POST /handshake`, with status 200 if the worker may
+/// register and 400 otherwise.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct HandshakeResponse {
+    pub accepted: bool,
+    pub coordinator_version: String,
+    pub schema_version: u32,
+    pub min_schema_version: u32,
+    /// The coordinator's current time.
+    pub time: u64,
+    /// How far the worker's clock is ahead, negative if it's behind.
+    pub clock_skew_ms: i64,
+    /// Everything which keeps the worker from registering.
+    pub problems: Vec<ApiError>,
+}
+
+/// What the worker needs to pass.
+#[derive(Debug, Clone, Default)]
+pub struct HandshakePolicy {
+    pub max_clock_skew_ms: u64,
+    /// Lowest prover version accepted, compared by dot-separated numbers.
+    pub min_prover_version: Option<String>,
+}
+
+impl HandshakePolicy {
+    /// Checks `req` received at `now`. `sessions` is the number of
+    /// sessions registered under the worker's name already.
+    pub fn check(&self, req: &HandshakeRequest, now: u64, sessions: usize) -> HandshakeResponse {
+        let mut problems = vec![];
+        let clock_skew_ms = req.time as i64 - now as i64;
+        if clock_skew_ms.unsigned_abs() > self.max_clock_skew_ms {
+            let (by, direction) = (clock_skew_ms.unsigned_abs(), clock_skew_ms.signum());
+            let direction = if direction > 0 { "ahead" } else { "behind" };
+            problems.push(ApiError::new(
+                ErrorCode::ClockSkew,
+                format!(
+                    "worker clock is {by}ms {direction}, more than the {}ms allowed, sync it with NTP",
+                    self.max_clock_skew_ms
+                ),
+            ));
+        }
+        if !(MIN_SCHEMA_VERSION..=SCHEMA_VERSION).contains(&req.schema_version) {
+            problems.push(ApiError::new(
+                ErrorCode::UnsupportedSchema,
+                format!(
+                    "schema version {} isn't supported, expected {MIN_SCHEMA_VERSION} to {SCHEMA_VERSION}",
+                    req.schema_version
+                ),
+            ));
+        }
+        if let Some(min) = &self.min_prover_version {
+            let version = req
+                .metadata
+                .as_ref()
+                .and_then(|m| m.prover_version.as_deref());
+            let message = match version {
+                None => Some(format!(
+                    "prover version not given, at least {min} is required"
+                )),
+                Some(version) if compare_versions(version, min) == Ordering::Less => Some(format!(
+                    "prover version {version} is too old, at least {min} is required"
+                )),
+                Some(_) => None,
+            };
+            if let Some(message) = message {
+                problems.push(ApiError::new(ErrorCode::UnsupportedProverVersion, message));
+            }
+        }
+        if sessions >= crate::stats::MAX_SESSIONS_PER_NAME {
+            problems.push(ApiError::new(
+                ErrorCode::WorkerQuotaExceeded,
+                format!(
+                    "too many workers under same worker_id: {}, deregister stale ones",
+                    req.worker_id
+                ),
+            ));
+        }
+        HandshakeResponse {
+            accepted: problems.is_empty(),
+            coordinator_version: env!("CARGO_PKG_VERSION").to_owned(),
+            schema_version: SCHEMA_VERSION,
+            min_schema_version: MIN_SCHEMA_VERSION,
+            time: now,
+            clock_skew_ms,
+            problems,
+        }
+    }
+}
+
+/// Compares versions like `1.10.2` by their numeric parts, ignoring a
+/// leading `v` and anything after a `-` or `+`.
+fn compare_versions(a: &str, b: &str) -> Ordering {
+    let parts = |v: &str| {
+        v.trim_start_matches('v')
+            .split(['-', '+'])
+            .next()
+            .unwrap_or_default()
+            .split('.')
+            .map(|p| p.parse::<u64>().unwrap_or(0))
+            .collect::<Vec<_>>()
+    };
+    let (a, b) = (parts(a), parts(b));
+    (0..a.len().max(b.len()))
+        .map(|i| {
+            let part = |v: &[u64]| v.get(i).copied().unwrap_or(0);
+            part(&a).cmp(&part(&b))
+        })
+        .find(|o| o.is_ne())
+        .unwrap_or(Ordering::Equal)
+}
@@ -0,0 +1,83 @@
+//! Extension points for embedding custom logic, e.g. custom metrics or
+//! business rules, into the coordinator without changing its routes.
+//!
+//! Hooks run synchronously on the request path, some with the stats or
+//! lock table held, so they must return quickly. Slow side effects
+//! belong in a task the hook spawns.
+
+use std::sync::Arc;
+
+use crate::{
+    lock::LockFulfillment,
+    stats::{SnarkWorkerState, Transition},
+};
+
+/// Callbacks for coordinator events, all no-ops by default.
+pub trait CoordinatorHooks: Send + Sync {
+    /// A worker registered and got `worker_id` assigned.
+    fn on_worker_registered(&self, _worker_id: &str, _state: &SnarkWorkerState) {}
+
+    /// A worker was deregistered and its stats removed.
+    fn on_worker_deregistered(&self, _worker_id: &str) {}
+
+    /// A worker-stats event was applied, including `Register`.
+    fn on_state_transition(&self, _transition: &Transition) {}
+
+    /// `keys` were locked together under `fencing_token`.
+    fn on_lock_acquired(&self, _keys: &[String], _fencing_token: u64, _holder: Option<&str>) {}
+
+    /// `key` was unlocked, by the job completing if `fulfilled_by` is
+    /// set and explicitly otherwise. Expired locks aren't reported.
+    fn on_lock_released(&self, _key: &str, _fulfilled_by: Option<&LockFulfillment>) {}
+}
+
+/// Hooks registered with the coordinator, called in registration order.
+#[derive(Clone, Default)]
+pub struct Hooks(Vec<Arc<dyn CoordinatorHooks>>);
+
+impl Hooks {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with(mut self, hooks: impl CoordinatorHooks + 'static) -> Self {
+        self.0.push(Arc::new(hooks));
+        self
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+}
+
+impl CoordinatorHooks for Hooks {
+    fn on_worker_registered(&self, worker_id: &str, state: &SnarkWorkerState) {
+        self.0
+            .iter()
+            .for_each(|h| h.on_worker_registered(worker_id, state));
+    }
+
+    fn on_worker_deregistered(&self, worker_id: &str) {
+        self.0
+            .iter()
+            .for_each(|h| h.on_worker_deregistered(worker_id));
+    }
+
+    fn on_state_transition(&self, transition: &Transition) {
+        self.0
+            .iter()
+            .for_each(|h| h.on_state_transition(transition));
+    }
+
+    fn on_lock_acquired(&self, keys: &[String], fencing_token: u64, holder: Option<&str>) {
+        self.0
+            .iter()
+            .for_each(|h| h.on_lock_acquired(keys, fencing_token, holder));
+    }
+
+    fn on_lock_released(&self, key: &str, fulfilled_by: Option<&LockFulfillment>) {
+        self.0
+            .iter()
+            .for_each(|h| h.on_lock_released(key, fulfilled_by));
+    }
+}
@@ -0,0 +1,217 @@
+//! Periodic host samples reported by workers next to their lifecycle
+//! events, and their correlation with job durations. Slow proofs usually
+//! have a host-level cause.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{stats::SnarkWorkerState, timestamp};
+
+/// Max number of samples kept per worker, regardless of retention.
+const MAX_SAMPLES: usize = 10_000;
+
+/// Body of `PUT /worker-metrics/{id}`. All readings are optional, workers
+/// report what they can measure.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HostSample {
+    #[serde(deserialize_with = "timestamp::deserialize")]
+    pub time: u64,
+    /// Host CPU utilization, 0-100 per core summed up.
+    pub cpu_pct: Option<f64>,
+    pub mem_used_bytes: Option<u64>,
+    pub mem_total_bytes: Option<u64>,
+    /// 1 minute load average.
+    pub load1: Option<f64>,
+    /// Resident set size of the proof process.
+    pub prover_rss_bytes: Option<u64>,
+}
+
+/// Per-worker host samples, most recent first.
+#[derive(Debug, Default)]
+pub struct HostMetrics {
+    samples: HashMap<String, VecDeque<HostSample>>,
+    retention_ms: u64,
+}
+
+/// Mean host readings over a set of job lifecycles. Readings no sample
+/// reported are `None`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HostLoad {
+    pub lifecycles: usize,
+    pub mean_job_ms: Option<f64>,
+    pub cpu_pct: Option<f64>,
+    pub mem_used_bytes: Option<f64>,
+    pub load1: Option<f64>,
+    pub prover_rss_bytes: Option<f64>,
+}
+
+/// Host readings during successful jobs, comparing the slowest 10% of
+/// jobs to the rest.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct HostCorrelationReport {
+    /// Jobs taking at least this long count as slow.
+    pub slow_threshold_ms: Option<u64>,
+    pub slow: HostLoad,
+    pub normal: HostLoad,
+    pub workers: BTreeMap<String, HostLoad>,
+}
+
+#[derive(Default)]
+struct LoadAcc {
+    lifecycles: usize,
+    job_ms: Mean,
+    cpu_pct: Mean,
+    mem_used_bytes: Mean,
+    load1: Mean,
+    prover_rss_bytes: Mean,
+}
+
+#[derive(Default)]
+struct Mean {
+    sum: f64,
+    n: usize,
+}
+
+impl Mean {
+    fn add(&mut self, v: Option<f64>) {
+        if let Some(v) = v {
+            self.sum += v;
+            self.n += 1;
+        }
+    }
+
+    fn get(&self) -> Option<f64> {
+        (self.n > 0).then(|| self.sum / self.n as f64)
+    }
+}
+
+impl LoadAcc {
+    fn add(&mut self, job_ms: u64, samples: &[&HostSample]) {
+        self.lifecycles += 1;
+        self.job_ms.add(Some(job_ms as f64));
+        for s in samples {
+            self.cpu_pct.add(s.cpu_pct);
+            self.mem_used_bytes.add(s.mem_used_bytes.map(|v| v as f64));
+            self.load1.add(s.load1);
+            self.prover_rss_bytes
+                .add(s.prover_rss_bytes.map(|v| v as f64));
+        }
+    }
+
+    fn finish(&self) -> HostLoad {
+        HostLoad {
+            lifecycles: self.lifecycles,
+            mean_job_ms: self.job_ms.get(),
+            cpu_pct: self.cpu_pct.get(),
+            mem_used_bytes: self.mem_used_bytes.get(),
+            load1: self.load1.get(),
+            prover_rss_bytes: self.prover_rss_bytes.get(),
+        }
+    }
+}
+
+impl HostMetrics {
+    /// Creates a store keeping samples for `retention_ms`.
+    pub fn new(retention_ms: u64) -> Self {
+        Self {
+            retention_ms,
+            ..Self::default()
+        }
+    }
+
+    pub fn put(&mut self, worker_id: String, sample: HostSample) {
+        let samples = self.samples.entry(worker_id).or_default();
+        // keep the series ordered even if samples arrive late.
+        let pos = samples
+            .iter()
+            .position(|s| s.time <= sample.time)
+            .unwrap_or(samples.len());
+        samples.insert(pos, sample);
+        samples.truncate(MAX_SAMPLES);
+    }
+
+    /// Samples of the worker within `from_t..=to_t`, most recent first.
+    pub fn samples(
+        &self,
+        worker_id: &str,
+        from_t: Option<u64>,
+        to_t: Option<u64>,
+    ) -> Option<Vec<&HostSample>> {
+        let samples = self.samples.get(worker_id)?;
+        Some(
+            samples
+                .iter()
+                .skip_while(|s| to_t.is_some_and(|t| s.time > t))
+                .take_while(|s| from_t.is_none_or(|t| s.time >= t))
+                .collect(),
+        )
+    }
+
+    /// Drops samples older than the retention.
+    pub fn prune(&mut self, now: u64) {
+        let min_t = now.saturating_sub(self.retention_ms);
+        self.samples.retain(|_, samples| {
+            while samples.back().is_some_and(|s| s.time < min_t) {
+                samples.pop_back();
+            }
+            !samples.is_empty()
+        });
+    }
+
+    /// Correlates host samples taken while a job was worked on, from
+    /// receiving it until submitting the work, with the job's duration.
+    pub fn correlate<'a>(
+        &self,
+        stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    ) -> HostCorrelationReport {
+        let mut jobs = vec![];
+        for (worker_id, states) in stats {
+            for state in states {
+                let SnarkWorkerState::WorkSubmitSuccess {
+                    job_get_success_t,
+                    work_submit_success_t,
+                    ..
+                } = state
+                else {
+                    continue;
+                };
+                let samples = self
+                    .samples(
+                        worker_id,
+                        Some(*job_get_success_t),
+                        Some(*work_submit_success_t),
+                    )
+                    .unwrap_or_default();
+                if let Some(job_ms) = state.job_duration() {
+                    jobs.push((worker_id, job_ms, samples));
+                }
+            }
+        }
+
+        let mut durations = jobs.iter().map(|(_, ms, _)| *ms).collect::<Vec<_>>();
+        durations.sort_unstable();
+        let slow_threshold_ms = (!durations.is_empty())
+            .then(|| durations[(durations.len() * 9 / 10).min(durations.len() - 1)]);
+
+        let (mut slow, mut normal) = (LoadAcc::default(), LoadAcc::default());
+        let mut workers = BTreeMap::<&String, LoadAcc>::new();
+        for (worker_id, job_ms, samples) in &jobs {
+            if slow_threshold_ms.is_some_and(|t| *job_ms >= t) {
+                slow.add(*job_ms, samples);
+            } else {
+                normal.add(*job_ms, samples);
+            }
+            workers.entry(worker_id).or_default().add(*job_ms, samples);
+        }
+        HostCorrelationReport {
+            slow_threshold_ms,
+            slow: slow.finish(),
+            normal: normal.finish(),
+            workers: workers
+                .into_iter()
+                .map(|(id, acc)| (id.clone(), acc.finish()))
+                .collect(),
+        }
+    }
+}
@@ -0,0 +1,236 @@
+//! Lock keys which are persistently contended, refused far more often
+//! than they're acquired. A lock refusing another worker now and then is
+//! expected, but a key which keeps getting refused usually means workers
+//! don't partition the job set between them and race for the same jobs.
+
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// Attempts remembered per key, the oldest dropped first.
+const MAX_ATTEMPTS_PER_KEY: usize = 1000;
+/// Most requesters and holders listed per hot key.
+const MAX_WORKERS_PER_KEY: usize = 10;
+
+/// When a key counts as hot.
+#[derive(Debug, Clone, Copy)]
+pub struct HotKeyConfig {
+    /// How far back attempts count.
+    pub window_ms: u64,
+    /// Fewest refused attempts within the window.
+    pub min_conflicts: u64,
+    /// Fewest refused attempts per acquisition within the window.
+    pub min_conflicts_per_acquisition: f64,
+}
+
+impl Default for HotKeyConfig {
+    fn default() -> Self {
+        Self {
+            window_ms: 300_000,
+            min_conflicts: 20,
+            min_conflicts_per_acquisition: 5.0,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+struct Attempt {
+    t: u64,
+    /// Requester of the attempt.
+    worker_id: Option<String>,
+    granted: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HotKey {
+    pub key: String,
+    pub acquisitions: u64,
+    pub conflicts: u64,
+    /// `None` if the key wasn't acquired within the window at all.
+    pub conflicts_per_acquisition: Option<f64>,
+    /// Workers refused the key, most often refused first.
+    pub requesters: Vec<String>,
+    /// Workers granted the key, most often granted first.
+    pub holders: Vec<String>,
+    pub first_conflict_t: u64,
+    pub last_conflict_t: u64,
+    /// What likely makes the key hot, and how to fix it.
+    pub suggestion: String,
+}
+
+/// Response body of `GET /lock-stats/hot`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct HotKeysReport {
+    pub window_ms: u64,
+    pub min_conflicts: u64,
+    pub min_conflicts_per_acquisition: f64,
+    /// Most refused first.
+    pub keys: Vec<HotKey>,
+}
+
+impl HotKeysReport {
+    /// Adds the keys of `other`, e.g. the report of another shard.
+    pub fn merge(mut self, other: HotKeysReport) -> Self {
+        self.keys.extend(other.keys);
+        self.sorted()
+    }
+
+    fn sorted(mut self) -> Self {
+        self.keys
+            .sort_by(|a, b| b.conflicts.cmp(&a.conflicts).then(a.key.cmp(&b.key)));
+        self
+    }
+}
+
+/// Lock attempts per key within the window.
+#[derive(Debug, Default)]
+pub struct HotKeys {
+    config: HotKeyConfig,
+    attempts: HashMap<String, VecDeque<Attempt>>,
+    /// Keys reported by [`HotKeys::check`], while they stay hot.
+    reported: HashSet<String>,
+}
+
+impl HotKeys {
+    pub fn new(config: HotKeyConfig) -> Self {
+        Self {
+            config,
+            ..Self::default()
+        }
+    }
+
+    /// Records an attempt of `worker_id` to lock `key`.
+    pub fn record(&mut self, key: &str, worker_id: Option<&str>, granted: bool, now: u64) {
+        let attempts = self.attempts.entry(key.to_owned()).or_default();
+        attempts.push_back(Attempt {
+            t: now,
+            worker_id: worker_id.map(str::to_owned),
+            granted,
+        });
+        if attempts.len() > MAX_ATTEMPTS_PER_KEY {
+            attempts.pop_front();
+        }
+    }
+
+    /// Drops attempts which fell out of the window, and keys which were
+    /// only acquired, which aren't worth tracking.
+    pub fn prune(&mut self, now: u64) {
+        let from_t = now.saturating_sub(self.config.window_ms);
+        self.attempts.retain(|_, attempts| {
+            while attempts.front().is_some_and(|a| a.t < from_t) {
+                attempts.pop_front();
+            }
+            attempts.iter().any(|a| !a.granted)
+        });
+    }
+
+    /// Keys hot at `now`, most refused first.
+    pub fn report(&mut self, now: u64) -> HotKeysReport {
+        self.prune(now);
+        let keys = self
+            .attempts
+            .iter()
+            .filter_map(|(key, attempts)| self.hot_key(key, attempts))
+            .collect();
+        HotKeysReport {
+            window_ms: self.config.window_ms,
+            min_conflicts: self.config.min_conflicts,
+            min_conflicts_per_acquisition: self.config.min_conflicts_per_acquisition,
+            keys,
+        }
+        .sorted()
+    }
+
+    pub fn config(&self) -> HotKeyConfig {
+        self.config
+    }
+
+    /// Keys which became hot since the last check. A key is reported
+    /// again once it cooled down in between.
+    pub fn check(&mut self, now: u64) -> Vec<HotKey> {
+        let hot = self.report(now).keys;
+        let reported = hot.iter().map(|k| k.key.clone()).collect::<HashSet<_>>();
+        let new = hot
+            .into_iter()
+            .filter(|k| !self.reported.contains(&k.key))
+            .collect();
+        self.reported = reported;
+        new
+    }
+
+    fn hot_key(&self, key: &str, attempts: &VecDeque<Attempt>) -> Option<HotKey> {
+        let mut requesters = BTreeMap::<&str, u64>::new();
+        let mut holders = BTreeMap::<&str, u64>::new();
+        let (mut acquisitions, mut conflicts) = (0, 0);
+        let (mut first_conflict_t, mut last_conflict_t) = (u64::MAX, 0);
+        for attempt in attempts {
+            let worker_id = attempt.worker_id.as_deref().unwrap_or_default();
+            if attempt.granted {
+                acquisitions += 1;
+                *holders.entry(worker_id).or_default() += 1;
+            } else {
+                conflicts += 1;
+                *requesters.entry(worker_id).or_default() += 1;
+                first_conflict_t = first_conflict_t.min(attempt.t);
+                last_conflict_t = last_conflict_t.max(attempt.t);
+            }
+        }
+        let conflicts_per_acquisition =
+            (acquisitions > 0).then(|| conflicts as f64 / acquisitions as f64);
+        let is_hot = conflicts >= self.config.min_conflicts
+            && conflicts_per_acquisition
+                .is_none_or(|ratio| ratio >= self.config.min_conflicts_per_acquisition);
+        if !is_hot {
+            return None;
+        }
+        let requesters = most_frequent(requesters);
+        let holders = most_frequent(holders);
+        let suggestion = suggest(&requesters, &holders, acquisitions);
+        Some(HotKey {
+            key: key.to_owned(),
+            acquisitions,
+            conflicts,
+            conflicts_per_acquisition,
+            requesters,
+            holders,
+            first_conflict_t,
+            last_conflict_t,
+            suggestion,
+        })
+    }
+}
+
+/// Named workers of `counts`, most frequent first.
+fn most_frequent(counts: BTreeMap<&str, u64>) -> Vec<String> {
+    let mut counts = counts
+        .into_iter()
+        .filter(|(worker_id, _)| !worker_id.is_empty())
+        .collect::<Vec<_>>();
+    counts.sort_by_key(|&(_, count)| std::cmp::Reverse(count));
+    counts
+        .into_iter()
+        .take(MAX_WORKERS_PER_KEY)
+        .map(|(worker_id, _)| worker_id.to_owned())
+        .collect()
+}
+
+fn suggest(requesters: &[String], holders: &[String], acquisitions: u64) -> String {
+    match (requesters, holders) {
+        _ if acquisitions == 0 => "held for the whole window, check its holder is still \
+             making progress, or lower the lock TTL so a dead holder's lock expires sooner"
+            .to_owned(),
+        (_, [holder]) if acquisitions == 1 => format!(
+            "held by {holder} for most of the window, check it's still making progress, \
+             or lower the lock TTL so a dead holder's lock expires sooner"
+        ),
+        ([requester], _) => format!(
+            "{requester} keeps retrying while it's held, back off between attempts or \
+             wait for it with `wait` instead of polling"
+        ),
+        _ => format!(
+            "{} workers race for this job, partition the job set between them, e.g. by \
+             job id hash modulo the worker count, so each job has a single candidate",
+            requesters.len().max(2)
+        ),
+    }
+}
@@ -0,0 +1,108 @@
+//! Results of recent stats PUTs by their `Idempotency-Key`, so a worker
+//! retrying a PUT which timed out gets the original result back instead
+//! of the event being applied twice.
+
+use std::collections::{HashMap, VecDeque};
+
+/// Header carrying the key a worker sends its PUT under.
+pub const HEADER: &str = "idempotency-key";
+/// Header set on responses replayed for an already seen key.
+pub const REPLAYED_HEADER: &str = "x-idempotent-replayed";
+
+/// Keys remembered per worker, the oldest are forgotten first.
+pub const KEYS_PER_WORKER: usize = 64;
+/// Keys are forgotten this long after their PUT.
+pub const KEY_TTL_MS: u64 = 10 * 60 * 1000;
+
+struct Seen<T> {
+    key: String,
+    t: u64,
+    result: T,
+}
+
+pub struct IdempotencyKeys<T> {
+    workers: HashMap<String, VecDeque<Seen<T>>>,
+    last_prune_t: u64,
+}
+
+impl<T> Default for IdempotencyKeys<T> {
+    fn default() -> Self {
+        Self {
+            workers: HashMap::new(),
+            last_prune_t: 0,
+        }
+    }
+}
+
+impl<T> IdempotencyKeys<T> {
+    /// Result of the worker's PUT under `key`, unless it's forgotten.
+    pub fn get(&self, worker_id: &str, key: &str, now: u64) -> Option<&T> {
+        self.workers
+            .get(worker_id)?
+            .iter()
+            .find(|seen| seen.key == key && now.saturating_sub(seen.t) < KEY_TTL_MS)
+            .map(|seen| &seen.result)
+    }
+
+    pub fn insert(&mut self, worker_id: &str, key: String, result: T, now: u64) {
+        // workers which went away would keep their keys forever otherwise.
+        if now.saturating_sub(self.last_prune_t) >= KEY_TTL_MS {
+            self.prune(now);
+        }
+        let seen = self.workers.entry(worker_id.to_owned()).or_default();
+        if seen.len() >= KEYS_PER_WORKER {
+            seen.pop_front();
+        }
+        seen.push_back(Seen {
+            key,
+            t: now,
+            result,
+        });
+    }
+
+    fn prune(&mut self, now: u64) {
+        self.workers.retain(|_, seen| {
+            seen.retain(|seen| now.saturating_sub(seen.t) < KEY_TTL_MS);
+            !seen.is_empty()
+        });
+        self.last_prune_t = now;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn results_are_kept_per_worker_and_key() {
+        let mut keys = IdempotencyKeys::default();
+        keys.insert("w1", "k".to_owned(), 1, 0);
+        keys.insert("w2", "k".to_owned(), 2, 0);
+
+        assert_eq!(keys.get("w1", "k", 1), Some(&1));
+        assert_eq!(keys.get("w2", "k", 1), Some(&2));
+        assert_eq!(keys.get("w1", "other", 1), None);
+        assert_eq!(keys.get("w3", "k", 1), None);
+    }
+
+    #[test]
+    fn keys_are_forgotten_when_old_or_many() {
+        let mut keys = IdempotencyKeys::default();
+        keys.insert("w", "old".to_owned(), 0, 0);
+        assert_eq!(keys.get("w", "old", KEY_TTL_MS - 1), Some(&0));
+        assert_eq!(keys.get("w", "old", KEY_TTL_MS), None);
+
+        for i in 0..KEYS_PER_WORKER {
+            keys.insert("w", i.to_string(), i, 1);
+        }
+        assert_eq!(keys.get("w", "old", 1), None);
+        assert_eq!(keys.get("w", "0", 1), Some(&0));
+        keys.insert("w", "new".to_owned(), KEYS_PER_WORKER, 1);
+        assert_eq!(keys.get("w", "0", 1), None);
+        assert_eq!(keys.get("w", "new", 1), Some(&KEYS_PER_WORKER));
+
+        // inserting for another worker drops the keys which ran out.
+        keys.insert("other", "k".to_owned(), 0, KEY_TTL_MS + 1);
+        assert!(!keys.workers.contains_key("w"));
+    }
+}
@@ -0,0 +1,542 @@
+//! Queue between the `PUT /worker-stats` handlers and the task applying
+//! the events. Handlers only parse, authenticate and check events on
+//! their own before queueing them, and reply once they're queued, while
+//! the task applies them one at a time in arrival order, so concurrent
+//! PUTs don't contend for the stats and lock tables, and anything which
+//! has to see every event in order, e.g. journaling, has a single place to
+//! hook in.
+//!
+//! Releasing the locks of finished jobs from a shared lock store takes a
+//! network round trip, so those are left to a task of their own rather
+//! than holding up the events queued behind.
+
+use std::sync::{
+    atomic::{AtomicU64, AtomicUsize, Ordering},
+    Arc,
+};
+
+use snark_coordinator_rs::{
+    idempotency::IdempotencyKeys,
+    metrics::GaugeGuard,
+    otlp::TraceContext,
+    quarantine::Quarantine,
+    stats::{PutError, SnarkWorkerStatsPut},
+    strict, timestamp,
+};
+use tokio::{
+    sync::{mpsc, watch, Mutex},
+    time::Instant,
+};
+use tracing::warn;
+
+use crate::{Applied, StatsIngest};
+
+/// Events waiting to be applied, beyond which handlers wait for room.
+pub const CAPACITY: usize = 10_000;
+
+/// Thresholds beyond which worker-stats PUTs are refused with 429 rather
+/// than queued, so latencies don't grow unboundedly under load.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Backpressure {
+    /// Events queued but not yet applied.
+    pub max_pending: Option<usize>,
+    /// Time the latest event waited in the queue before being applied,
+    /// in milliseconds.
+    pub max_wait_ms: Option<u64>,
+}
+
+/// How far behind the applying task is.
+#[derive(Debug, Default)]
+struct Load {
+    pending: AtomicUsize,
+    wait_ms: AtomicU64,
+}
+
+/// The task applying events stopped, or the queue was closed, so no more
+/// can be queued.
+#[derive(Debug, Clone, Copy)]
+pub struct Stopped;
+
+/// Results of a request's events, in order.
+pub type Results = Vec<Result<Applied, PutError>>;
+
+/// A request whose events were queued.
+#[derive(Clone)]
+pub struct Ack {
+    /// Position of the request's last event in the queue. A worker-stats
+    /// GET with it as `min_version` reflects the events.
+    pub version: u64,
+    /// Whether the worker sent events under the request's
+    /// `Idempotency-Key` already, those being acknowledged instead.
+    pub replayed: bool,
+    results: watch::Receiver<Option<Results>>,
+}
+
+impl Ack {
+    /// Waits until the events are applied, for what's only known then,
+    /// e.g. the session id a `Register` results in.
+    pub async fn applied(mut self) -> Result<Results, Stopped> {
+        let results = self.results.wait_for(Option::is_some).await;
+        let results = results.map_err(|_| Stopped)?;
+        Ok(results.clone().expect("waited for the results"))
+    }
+}
+
+/// Events of a request, applied back to back.
+struct Events {
+    worker_id: String,
+    /// Events with the payloads they were parsed from, if rejected ones
+    /// are quarantined.
+    reqs: Vec<(SnarkWorkerStatsPut, Option<Vec<u8>>)>,
+    trace: Option<TraceContext>,
+    /// Lock namespace the events' jobs are locked in.
+    lock_namespace: Option<String>,
+    version: u64,
+    received_t: u64,
+    queued_at: Instant,
+    results: watch::Sender<Option<Results>>,
+    /// Counts the events as in flight until they're applied.
+    in_flight: Vec<GaugeGuard>,
+}
+
+/// Position of the last queued event, and the requests queued by
+/// `Idempotency-Key`.
+#[derive(Default)]
+struct Queued {
+    version: u64,
+    keys: IdempotencyKeys<Ack>,
+    /// Whether the queue was closed, refusing further events.
+    closed: bool,
+}
+
+#[derive(Clone)]
+pub struct IngestQueue {
+    ingest: StatsIngest,
+    tx: mpsc::Sender<Events>,
+    backpressure: Backpressure,
+    load: Arc<Load>,
+    /// Locked while an event is sent, so versions follow queue order.
+    queued: Arc<std::sync::Mutex<Queued>>,
+    /// Position of the last applied event.
+    applied: watch::Receiver<u64>,
+    quarantine: Option<Arc<Mutex<Quarantine>>>,
+}
+
+impl IngestQueue {
+    /// Spawns the task applying queued events to `ingest`, and the one
+    /// releasing their locks from the shared lock store. Rejected events
+    /// are kept in `quarantine` if they're invalid transitions.
+    pub fn spawn(
+        ingest: StatsIngest,
+        backpressure: Backpressure,
+        quarantine: Option<Arc<Mutex<Quarantine>>>,
+    ) -> Self {
+        let (tx, mut rx) = mpsc::channel::<Events>(CAPACITY);
+        let (releases, mut release_rx) = mpsc::channel(CAPACITY);
+        let releaser = ingest.clone();
+        tokio::spawn(async move {
+            while let Some(release) = release_rx.recv().await {
+                releaser.finish_release(release).await;
+            }
+        });
+        let (applied_tx, applied) = watch::channel(0);
+        let applier = ingest.clone();
+        let load = Arc::new(Load::default());
+        let applier_load = load.clone();
+        let applier_quarantine = quarantine.clone();
+        tokio::spawn(async move {
+            while let Some(events) = rx.recv().await {
+                let wait_ms = events.queued_at.elapsed().as_millis() as u64;
+                applier_load.wait_ms.store(wait_ms, Ordering::Relaxed);
+                let n = events.reqs.len();
+                let worker_id = &events.worker_id;
+                let namespace = events.lock_namespace.as_deref();
+                let mut results = Vec::with_capacity(n);
+                for (mut req, payload) in events.reqs {
+                    applier
+                        .receive(worker_id, &mut req, events.received_t)
+                        .await;
+                    let applied = applier
+                        .apply_deferred(worker_id, req, events.trace, namespace)
+                        .await;
+                    let applied = match applied {
+                        Ok((applied, release)) => {
+                            if let Some(release) = release {
+                                // only gone if the runtime shuts down.
+                                let _ = releases.send(release).await;
+                            }
+                            Ok(applied)
+                        }
+                        Err(err) => {
+                            warn!(worker_id, "{err}");
+                            let quarantine = applier_quarantine.as_deref();
+                            if let (PutError::InvalidTransition(msg), Some(payload)) =
+                                (&err, payload)
+                            {
+                                let code = err.code();
+                                crate::quarantine_payload(
+                                    quarantine, worker_id, code, msg, &payload,
+                                )
+                                .await;
+                            }
+                            Err(err)
+                        }
+                    };
+                    results.push(applied);
+                }
+                // nobody waits for most results.
+                events.results.send_replace(Some(results));
+                drop(events.in_flight);
+                applied_tx.send_replace(events.version);
+                applier_load.pending.fetch_sub(n, Ordering::Relaxed);
+            }
+        });
+        Self {
+            ingest,
+            tx,
+            backpressure,
+            load,
+            queued: Default::default(),
+            applied,
+            quarantine,
+        }
+    }
+
+    /// Seconds to retry after if the queue is past a [`Backpressure`]
+    /// threshold, counting the request as shed, `None` if events are
+    /// taken.
+    pub fn saturated(&self) -> Option<u64> {
+        let pending = self.load.pending.load(Ordering::Relaxed);
+        // the wait of the latest event tells nothing once the queue
+        // drained.
+        let wait_ms = match pending {
+            0 => 0,
+            _ => self.load.wait_ms.load(Ordering::Relaxed),
+        };
+        let Backpressure {
+            max_pending,
+            max_wait_ms,
+        } = self.backpressure;
+        let saturated = max_pending.is_some_and(|max| pending >= max)
+            || max_wait_ms.is_some_and(|max| wait_ms >= max);
+        if !saturated {
+            return None;
+        }
+        self.ingest.metrics.stats_events_shed.inc();
+        // by then, the events queued now should have been applied.
+        Some(wait_ms.div_ceil(1000).clamp(1, 60))
+    }
+
+    /// Position of the last queued event.
+    pub fn version(&self) -> u64 {
+        self.queued.lock().unwrap().version
+    }
+
+    /// Position of the last applied event, to wait for a version.
+    pub fn applied(&self) -> watch::Receiver<u64> {
+        self.applied.clone()
+    }
+
+    /// Stops queueing events, then waits until the ones queued are applied
+    /// or `deadline` passes. Returns how many weren't, counted as dropped.
+    pub async fn close(&self, deadline: Instant) -> u64 {
+        let last = {
+            let mut queued = self.queued.lock().unwrap();
+            queued.closed = true;
+            queued.version
+        };
+        let mut applied = self.applied();
+        let drained = applied.wait_for(|v| *v >= last);
+        // the applying task only stops if the runtime shuts down.
+        let _ = tokio::time::timeout_at(deadline, drained).await;
+        let dropped = last - *applied.borrow();
+        let metrics = &self.ingest.metrics;
+        metrics.stats_events_dropped_on_shutdown.inc_by(dropped);
+        dropped
+    }
+
+    /// Refuses `req` if it's invalid whatever the worker's state, so it's
+    /// rejected before it's queued rather than once it's applied.
+    pub fn check(&self, req: &SnarkWorkerStatsPut) -> Result<(), PutError> {
+        match self.ingest.strict {
+            true => strict::check(req, None).map_err(PutError::Strict),
+            false => Ok(()),
+        }
+    }
+
+    /// Queues `reqs` of `worker_id`, parsed from the payloads next to
+    /// them, to be applied in order with no other request's events in
+    /// between. Returns once they're queued, or with the ack of the events
+    /// the worker sent under `key` before.
+    pub async fn queue(
+        &self,
+        worker_id: &str,
+        key: Option<String>,
+        reqs: Vec<(SnarkWorkerStatsPut, &[u8])>,
+        trace: Option<TraceContext>,
+        lock_namespace: Option<&str>,
+    ) -> Result<Ack, Stopped> {
+        let in_flight = &self.ingest.metrics.stats_events_in_flight;
+        let in_flight = reqs.iter().map(|_| GaugeGuard::new(in_flight)).collect();
+        let n = reqs.len();
+        let reqs = reqs
+            .into_iter()
+            .map(|(req, payload)| (req, self.quarantine.as_ref().map(|_| payload.to_vec())))
+            .collect();
+        let (results, results_rx) = watch::channel(None);
+        let mut events = Events {
+            worker_id: worker_id.to_owned(),
+            reqs,
+            trace,
+            lock_namespace: lock_namespace.map(str::to_owned),
+            version: 0,
+            received_t: timestamp::now(),
+            queued_at: Instant::now(),
+            results,
+            in_flight,
+        };
+        let permit = self.tx.reserve().await.map_err(|_| Stopped)?;
+        let mut queued = self.queued.lock().unwrap();
+        if queued.closed {
+            return Err(Stopped);
+        }
+        let now = timestamp::now();
+        if let Some(ack) = key
+            .as_ref()
+            .and_then(|key| queued.keys.get(worker_id, key, now))
+        {
+            let ack = Ack {
+                replayed: true,
+                ..ack.clone()
+            };
+            return Ok(ack);
+        }
+        queued.version += n as u64;
+        events.version = queued.version;
+        let ack = Ack {
+            version: queued.version,
+            replayed: false,
+            results: results_rx,
+        };
+        if let Some(key) = key {
+            queued.keys.insert(worker_id, key, ack.clone(), now);
+        }
+        // counted once queued, a request dropped while waiting for room
+        // never is.
+        self.load.pending.fetch_add(n, Ordering::Relaxed);
+        permit.send(events);
+        Ok(ack)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use snark_coordinator_rs::{hooks::Hooks, stats::SnarkWorkerJobGetError};
+
+    use super::*;
+
+    fn register() -> SnarkWorkerStatsPut {
+        SnarkWorkerStatsPut::Register {
+            time: 1,
+            metadata: None,
+            resume: None,
+            seq: None,
+        }
+    }
+
+    /// Events of a job get which found no job, from `t` on.
+    fn no_job(t: u64) -> Vec<(SnarkWorkerStatsPut, &'static [u8])> {
+        let error = SnarkWorkerStatsPut::JobGetError {
+            time: t + 1,
+            job_get_node_received_t: None,
+            job_get_node_request_work_init_t: None,
+            job_get_node_request_work_success_t: None,
+            error: SnarkWorkerJobGetError::NoAvailableJob,
+            seq: None,
+        };
+        vec![
+            (SnarkWorkerStatsPut::JobGetInit { time: t, seq: None }, b""),
+            (error, b""),
+        ]
+    }
+
+    /// Registers `worker_id`, returning its session id.
+    async fn register_session(queue: &IngestQueue, worker_id: &str) -> String {
+        let ack = queue.queue(worker_id, None, vec![(register(), b"")], None, None);
+        let mut results = ack.await.unwrap().applied().await.unwrap();
+        results.pop().unwrap().unwrap().body
+    }
+
+    #[tokio::test]
+    async fn batches_are_queued_once_per_key() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let queue = IngestQueue::spawn(ingest.clone(), Backpressure::default(), None);
+        let session = register_session(&queue, "w").await;
+        let key = Some("k".to_owned());
+
+        let ack = queue.queue(&session, key.clone(), no_job(2), None, None);
+        let ack = ack.await.unwrap();
+        assert!(!ack.replayed);
+        assert_eq!(ack.version, 3);
+        let applied = ack.applied().await.unwrap();
+        assert!(applied.iter().all(Result::is_ok));
+
+        let replay = queue.queue(&session, key.clone(), no_job(2), None, None);
+        let replay = replay.await.unwrap();
+        assert!((replay.replayed, replay.version) == (true, 3));
+        assert_eq!(replay.applied().await.unwrap().len(), 2);
+        assert_eq!(queue.version(), 3);
+        assert_eq!(ingest.stats.read().await.get(&session).unwrap().len(), 2);
+
+        // the key is the worker's own.
+        let other = register_session(&queue, "v").await;
+        let ack = queue.queue(&other, key, no_job(2), None, None);
+        assert!(!ack.await.unwrap().replayed);
+    }
+
+    #[tokio::test]
+    async fn events_are_acked_before_theyre_applied() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let queue = IngestQueue::spawn(ingest.clone(), Backpressure::default(), None);
+        let session = register_session(&queue, "w").await;
+        let mut applied = queue.applied();
+        assert_eq!(*applied.borrow_and_update(), 1);
+
+        // the applying task is stuck while the stats are locked.
+        let stats = ingest.stats.write().await;
+        let ack = queue.queue(&session, None, no_job(2), None, None);
+        let ack = ack.await.unwrap();
+        assert_eq!(ack.version, 3);
+        assert_eq!(*applied.borrow(), 1);
+        assert_eq!(ingest.metrics.stats_events_in_flight.get(), 2);
+
+        drop(stats);
+        applied.wait_for(|v| *v == 3).await.unwrap();
+        assert_eq!(ingest.metrics.stats_events_in_flight.get(), 0);
+        assert_eq!(ingest.stats.read().await.get(&session).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn rejected_events_are_quarantined() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let quarantine = Arc::new(Mutex::new(Quarantine::new(16)));
+        let backpressure = Backpressure::default();
+        let queue = IngestQueue::spawn(ingest, backpressure, Some(quarantine.clone()));
+        let session = register_session(&queue, "w").await;
+
+        // a job get can't fail before it started.
+        let (error, _) = no_job(2).pop().unwrap();
+        let ack = queue.queue(&session, None, vec![(error, b"{}")], None, None);
+        let mut results = ack.await.unwrap().applied().await.unwrap();
+        assert!(matches!(
+            results.pop().unwrap(),
+            Err(PutError::InvalidTransition(_))
+        ));
+        let quarantine = quarantine.lock().await;
+        let payloads = quarantine.list(|_| true);
+        assert_eq!(payloads.len(), 1);
+        assert_eq!(payloads[0].payload, "{}");
+    }
+
+    #[tokio::test]
+    async fn stopped_queues_refuse_events() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let (tx, rx) = mpsc::channel(1);
+        drop(rx);
+        let queue = IngestQueue {
+            tx,
+            ..IngestQueue::spawn(ingest, Backpressure::default(), None)
+        };
+        let ack = queue.queue("w", None, vec![(register(), b"")], None, None);
+        assert!(ack.await.is_err());
+    }
+
+    #[tokio::test]
+    async fn closed_queues_count_events_not_applied_by_the_deadline() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let queue = IngestQueue::spawn(ingest.clone(), Backpressure::default(), None);
+        let session = register_session(&queue, "w").await;
+
+        // the applying task is stuck while the stats are locked.
+        let stats = ingest.stats.write().await;
+        let ack = queue.queue(&session, None, no_job(2), None, None);
+        ack.await.unwrap();
+        let deadline = Instant::now() + Duration::from_millis(10);
+        assert_eq!(queue.close(deadline).await, 2);
+        let metrics = &ingest.metrics;
+        assert_eq!(metrics.stats_events_dropped_on_shutdown.get(), 2);
+        let ack = queue.queue(&session, None, no_job(4), None, None);
+        assert!(ack.await.is_err());
+
+        drop(stats);
+        let deadline = Instant::now() + Duration::from_secs(10);
+        assert_eq!(queue.close(deadline).await, 0);
+        assert_eq!(*queue.applied().borrow(), 3);
+    }
+
+    /// Queues a registration of `worker_id` in the background.
+    fn spawn_register(queue: &IngestQueue, worker_id: &'static str) {
+        let queue = queue.clone();
+        tokio::spawn(async move { register_session(&queue, worker_id).await });
+    }
+
+    async fn until_pending(queue: &IngestQueue, n: usize) {
+        while queue.load.pending.load(Ordering::Relaxed) != n {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sheds_load_past_max_pending() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let backpressure = Backpressure {
+            max_pending: Some(2),
+            max_wait_ms: None,
+        };
+        let queue = IngestQueue::spawn(ingest.clone(), backpressure, None);
+
+        // the applying task is stuck while the stats are locked.
+        let stats = ingest.stats.write().await;
+        spawn_register(&queue, "a");
+        until_pending(&queue, 1).await;
+        assert_eq!(queue.saturated(), None);
+        spawn_register(&queue, "b");
+        until_pending(&queue, 2).await;
+        assert_eq!(queue.saturated(), Some(1));
+        assert_eq!(ingest.metrics.stats_events_shed.get(), 1);
+
+        drop(stats);
+        until_pending(&queue, 0).await;
+        assert_eq!(queue.saturated(), None);
+        assert_eq!(ingest.stats.read().await.len(), 2);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn sheds_load_past_max_wait() {
+        let ingest = crate::tests::ingest(None, Hooks::new());
+        let backpressure = Backpressure {
+            max_pending: None,
+            max_wait_ms: Some(20),
+        };
+        let queue = IngestQueue::spawn(ingest.clone(), backpressure, None);
+
+        let stats = ingest.stats.write().await;
+        spawn_register(&queue, "a");
+        spawn_register(&queue, "b");
+        until_pending(&queue, 2).await;
+        // `a` was taken right away.
+        assert_eq!(queue.saturated(), None);
+        tokio::time::advance(Duration::from_millis(30)).await;
+        drop(stats);
+        // locked again before `b`, which waited 30ms, is applied.
+        let stats = ingest.stats.write().await;
+        until_pending(&queue, 1).await;
+        assert_eq!(queue.saturated(), Some(1));
+
+        drop(stats);
+        until_pending(&queue, 0).await;
+        assert_eq!(queue.saturated(), None);
+    }
+}
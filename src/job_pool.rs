@@ -0,0 +1,257 @@
+//! Pending snark work polled from a Mina or Openmina node's GraphQL with
+//! `--node-graphql`, handed out to workers at `GET /job` instead of each
+//! of them querying the node. A handed out job is locked under its `ids`,
+//! the key workers lock it under with `/lock-job`, so jobs never overlap
+//! whichever way they were taken.
+
+use std::{collections::HashMap, str::FromStr};
+
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use utoipa::ToSchema;
+
+use crate::timestamp;
+
+/// Path jobs are handed out at.
+pub const PATH: &str = "/job";
+
+const PENDING_WORK_QUERY: &str = "query { pendingSnarkWork { workBundle { workId \
+    sourceFirstPassLedgerHash targetFirstPassLedgerHash sourceSecondPassLedgerHash \
+    targetSecondPassLedgerHash supplyIncrease } } snarkPool { fee workIds } }";
+
+/// A bundle of pending snark work, proved as one job.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct PendingJob {
+    /// Comma separated work ids of the bundle, its lock key.
+    pub ids: String,
+    /// Descriptions of the bundled work as reported by the node.
+    #[schema(value_type = Vec<Object>)]
+    pub work: Vec<Value>,
+    /// Fee in nanomina of the job's proof in the node's snark pool, if
+    /// it was proved already.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub pool_fee: Option<u64>,
+}
+
+/// Response body of `GET /job`, a job locked for the worker.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct JobGranted {
+    #[serde(flatten)]
+    pub job: PendingJob,
+    pub fencing_token: u64,
+}
+
+/// The node's pending work as of its latest poll, in the node's order.
+#[derive(Debug, Default)]
+pub struct JobPool {
+    jobs: Vec<PendingJob>,
+    polled_t: Option<u64>,
+}
+
+impl JobPool {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn jobs(&self) -> &[PendingJob] {
+        &self.jobs
+    }
+
+    /// When the node was last polled successfully.
+    pub fn polled_t(&self) -> Option<u64> {
+        self.polled_t
+    }
+
+    pub fn set(&mut self, jobs: Vec<PendingJob>) {
+        self.jobs = jobs;
+        self.polled_t = Some(timestamp::now());
+    }
+}
+
+/// How `GET /job` picks among the pending jobs, like the work selection
+/// of the Mina daemon. Whichever it picks, locked jobs are skipped.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SelectionPolicy {
+    /// In the node's order, the oldest work first.
+    #[default]
+    OldestFirst,
+    Random,
+    /// At random, weighted by the fee of the job's proof in the node's
+    /// snark pool, unproved jobs as much as the best paying proof. Jobs
+    /// proved for at most the worker's fee are skipped, it can't undercut
+    /// them.
+    FeeWeighted,
+    /// In the node's order, but jobs which failed recently go last.
+    AvoidFailed,
+}
+
+impl FromStr for SelectionPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "oldest-first" => Ok(Self::OldestFirst),
+            "random" => Ok(Self::Random),
+            "fee-weighted" => Ok(Self::FeeWeighted),
+            "avoid-failed" => Ok(Self::AvoidFailed),
+            _ => Err(format!(
+                "expected `oldest-first`, `random`, `fee-weighted` or `avoid-failed`, found: {s}"
+            )),
+        }
+    }
+}
+
+impl SelectionPolicy {
+    /// `jobs` in the order to try them in. `worker_fee` is the fee of the
+    /// requesting worker, `failed` tells whether a job's `ids` failed
+    /// recently.
+    pub fn order(
+        self,
+        jobs: &[PendingJob],
+        worker_fee: Option<u64>,
+        failed: impl Fn(&str) -> bool,
+    ) -> Vec<&PendingJob> {
+        let mut ordered = jobs.iter().collect::<Vec<_>>();
+        match self {
+            Self::OldestFirst => {}
+            Self::Random => ordered.shuffle(&mut rand::thread_rng()),
+            Self::FeeWeighted => {
+                ordered.retain(|job| {
+                    job.pool_fee
+                        .zip(worker_fee)
+                        .is_none_or(|(pool_fee, fee)| pool_fee > fee)
+                });
+                let max_fee = ordered.iter().filter_map(|job| job.pool_fee).max();
+                // weighted sampling without replacement: sorting by
+                // `u^(1/weight)` for uniform `u`.
+                let mut keyed = ordered
+                    .into_iter()
+                    .map(|job| {
+                        let weight = job.pool_fee.or(max_fee).unwrap_or(1).max(1) as f64;
+                        (rand::random::<f64>().powf(1.0 / weight), job)
+                    })
+                    .collect::<Vec<_>>();
+                keyed.sort_by(|a, b| b.0.total_cmp(&a.0));
+                ordered = keyed.into_iter().map(|(_, job)| job).collect();
+            }
+            // stable, so both parts stay in the node's order.
+            Self::AvoidFailed => ordered.sort_by_key(|job| failed(&job.ids)),
+        }
+        ordered
+    }
+}
+
+#[derive(Deserialize)]
+struct Response {
+    data: Option<PendingWorkData>,
+    #[serde(default)]
+    errors: Vec<GraphqlError>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct PendingWorkData {
+    pending_snark_work: Vec<WorkBundle>,
+    #[serde(default)]
+    snark_pool: Vec<CompletedWork>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct WorkBundle {
+    work_bundle: Vec<Value>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "camelCase")]
+struct CompletedWork {
+    /// Nanomina, as a string.
+    fee: Value,
+    work_ids: Vec<Value>,
+}
+
+/// `ids` of a bundle from its work ids, or its work descriptions
+/// carrying them as `workId`.
+fn bundle_ids(work: &[Value]) -> Option<String> {
+    let ids = work
+        .iter()
+        .map(|work| match work.get("workId").unwrap_or(work) {
+            Value::String(id) => Some(id.clone()),
+            Value::Number(id) => Some(id.to_string()),
+            _ => None,
+        })
+        .collect::<Option<Vec<_>>>()?;
+    Some(ids.join(","))
+}
+
+#[derive(Deserialize)]
+struct GraphqlError {
+    message: String,
+}
+
+/// Polls the pending snark work of a node.
+pub struct NodeClient {
+    client: reqwest::Client,
+    url: String,
+}
+
+impl NodeClient {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn pending_work(&self) -> Result<Vec<PendingJob>, String> {
+        let body = serde_json::json!({ "query": PENDING_WORK_QUERY });
+        let res = self
+            .client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map_err(|err| err.to_string())?;
+        let res = res
+            .json::<Response>()
+            .await
+            .map_err(|err| err.to_string())?;
+        if let Some(err) = res.errors.first() {
+            return Err(err.message.clone());
+        }
+        let Some(data) = res.data else {
+            return Err("no data in response".to_owned());
+        };
+        let pool_fees = data
+            .snark_pool
+            .iter()
+            .filter_map(|work| {
+                let fee = match &work.fee {
+                    Value::String(fee) => fee.parse().ok()?,
+                    fee => fee.as_u64()?,
+                };
+                Some((bundle_ids(&work.work_ids)?, fee))
+            })
+            .collect::<HashMap<String, u64>>();
+        let jobs = data
+            .pending_snark_work
+            .into_iter()
+            .filter_map(|bundle| {
+                let ids = bundle_ids(&bundle.work_bundle)?;
+                Some(PendingJob {
+                    pool_fee: pool_fees.get(&ids).copied(),
+                    ids,
+                    work: bundle.work_bundle,
+                })
+            })
+            .filter(|job| !job.ids.is_empty())
+            .collect();
+        Ok(jobs)
+    }
+}
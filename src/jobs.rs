@@ -0,0 +1,219 @@
+//! Jobs parsed out of the `ids` of stats events, and an index of which
+//! workers worked on each of them. Locks, see `/lock-jobs`, exist so a
+//! job is only proved once; the index shows when it was proved anyway.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fmt,
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+/// Max number of jobs indexed, the least recently received are forgotten
+/// first.
+pub const MAX_JOBS: usize = 100_000;
+
+/// Id of a single job. The `ids` of a stats event is a comma separated
+/// bundle of them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+#[serde(transparent)]
+pub struct JobId(String);
+
+impl JobId {
+    pub fn new(id: &str) -> Self {
+        Self(id.trim().to_owned())
+    }
+
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Display for JobId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.0)
+    }
+}
+
+/// Ids of the jobs bundled in `ids`, skipping empty parts.
+pub fn parse_ids(ids: &str) -> Vec<JobId> {
+    ids.split(',')
+        .filter(|id| !id.trim().is_empty())
+        .map(JobId::new)
+        .collect()
+}
+
+/// A worker's attempt at a job.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct JobWork {
+    pub worker_id: String,
+    /// `ids` of the bundle the job was received in.
+    pub ids: String,
+    pub job_get_init_t: u64,
+    /// Kind of the attempt's latest state.
+    pub state: String,
+    /// When the proof was created, if it was.
+    pub proved_t: Option<u64>,
+    /// When the proof was accepted by the node, if it was.
+    pub submitted_t: Option<u64>,
+}
+
+impl JobWork {
+    fn update(&mut self, state: &SnarkWorkerState) {
+        self.state = state.kind().to_owned();
+        match state {
+            SnarkWorkerState::WorkSubmitPending {
+                work_create_success_t,
+                ..
+            }
+            | SnarkWorkerState::WorkSubmitError {
+                work_create_success_t,
+                ..
+            }
+            | SnarkWorkerState::WorkSubmitTimeout {
+                work_create_success_t,
+                ..
+            } => self.proved_t = Some(*work_create_success_t),
+            SnarkWorkerState::WorkSubmitSuccess {
+                work_create_success_t,
+                work_submit_success_t,
+                ..
+            } => {
+                self.proved_t = Some(*work_create_success_t);
+                self.submitted_t = Some(*work_submit_success_t);
+            }
+            _ => {}
+        }
+    }
+}
+
+/// A job which was proved more than once.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicateJob {
+    pub job_id: JobId,
+    /// Number of proofs beyond the first.
+    pub wasted_proofs: usize,
+    /// Attempts which created a proof, first proved first.
+    pub proofs: Vec<JobWork>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct DuplicatesReport {
+    pub jobs_indexed: usize,
+    pub wasted_proofs: usize,
+    /// Most wasteful first.
+    pub duplicates: Vec<DuplicateJob>,
+}
+
+#[derive(Debug, Default)]
+pub struct JobIndex {
+    jobs: HashMap<JobId, Vec<JobWork>>,
+    /// Jobs in the order they were first received.
+    order: VecDeque<JobId>,
+}
+
+impl JobIndex {
+    /// Notes the worker's latest state, if it refers to a job.
+    pub fn observe(&mut self, worker_id: &str, state: &SnarkWorkerState) {
+        let Some(ids) = state.ids() else {
+            return;
+        };
+        let job_get_init_t = state.start_time();
+        for job_id in parse_ids(ids) {
+            let works = match self.jobs.get_mut(&job_id) {
+                Some(works) => works,
+                None => {
+                    if self.order.len() >= MAX_JOBS {
+                        if let Some(oldest) = self.order.pop_front() {
+                            self.jobs.remove(&oldest);
+                        }
+                    }
+                    self.order.push_back(job_id.clone());
+                    self.jobs.entry(job_id).or_default()
+                }
+            };
+            let pos = works
+                .iter()
+                .position(|w| w.worker_id == worker_id && w.job_get_init_t == job_get_init_t);
+            let work = match pos {
+                Some(pos) => &mut works[pos],
+                None => {
+                    works.push(JobWork {
+                        worker_id: worker_id.to_owned(),
+                        ids: ids.to_owned(),
+                        job_get_init_t,
+                        state: String::new(),
+                        proved_t: None,
+                        submitted_t: None,
+                    });
+                    works.last_mut().unwrap()
+                }
+            };
+            work.update(state);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Attempts at the job, in the order they were received.
+    pub fn get(&self, job_id: &JobId) -> Option<&[JobWork]> {
+        self.jobs.get(job_id).map(Vec::as_slice)
+    }
+
+    /// Whether an attempt at the job received since `min_t` failed to
+    /// create or submit its work.
+    pub fn failed_since(&self, job_id: &JobId, min_t: u64) -> bool {
+        self.get(job_id).is_some_and(|works| {
+            works.iter().any(|work| {
+                work.job_get_init_t >= min_t
+                    && matches!(
+                        work.state.as_str(),
+                        "WorkCreateError"
+                            | "WorkCreateTimeout"
+                            | "WorkSubmitError"
+                            | "WorkSubmitTimeout"
+                    )
+            })
+        })
+    }
+
+    /// Jobs with more than one proof by the workers `include` accepts.
+    pub fn duplicates(&self, include: impl Fn(&str) -> bool) -> DuplicatesReport {
+        let mut duplicates: Vec<_> = self
+            .jobs
+            .iter()
+            .filter_map(|(job_id, works)| {
+                let mut proofs: Vec<_> = works
+                    .iter()
+                    .filter(|w| w.proved_t.is_some() && include(&w.worker_id))
+                    .cloned()
+                    .collect();
+                if proofs.len() < 2 {
+                    return None;
+                }
+                proofs.sort_by_key(|w| w.proved_t);
+                Some(DuplicateJob {
+                    job_id: job_id.clone(),
+                    wasted_proofs: proofs.len() - 1,
+                    proofs,
+                })
+            })
+            .collect();
+        duplicates.sort_by(|a, b| {
+            (b.wasted_proofs.cmp(&a.wasted_proofs)).then_with(|| a.job_id.cmp(&b.job_id))
+        });
+        DuplicatesReport {
+            jobs_indexed: self.jobs.len(),
+            wasted_proofs: duplicates.iter().map(|d| d.wasted_proofs).sum(),
+            duplicates,
+        }
+    }
+}
@@ -0,0 +1,566 @@
+//! Append-only NDJSON journal of everything which changes the worker
+//! stats or the lock table, for `--journal`. Replaying it at startup
+//! rebuilds the state the coordinator had, and it doubles as an audit
+//! trail of what workers reported.
+//!
+//! Entries are written by a background task in the order they're
+//! recorded. Compaction replaces the journal with snapshots of the
+//! current state, which are replayed like any other entry.
+//!
+//! A journal can also feed its entries to subscribers, which is how a
+//! cluster leader replicates its state to followers.
+//!
+//! The first line of a journal is a header with the [`VERSION`] of the
+//! format its entries are in, e.g. `{"journal_version":1}`. Journals of
+//! newer versions are refused rather than misread, those written before
+//! there was a header are read as version 1. Older ones are upgraded by
+//! [`migrations`] before they're replayed.
+
+use std::{
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::{
+    fs::{File, OpenOptions},
+    io::{AsyncWriteExt, BufWriter},
+    sync::{broadcast, mpsc},
+};
+use tracing::warn;
+
+use crate::{
+    lock::{JobLock, LockFulfillment, LockTableSnapshot, LockedShards},
+    migrations,
+    stats::{self, Lease, SnarkWorkerStatsPut, WorkerStats, WorkerStatsSnapshot},
+    timestamp,
+};
+
+/// Version of the format of journal entries, bumped whenever older builds
+/// couldn't replay them correctly.
+pub const VERSION: u32 = 1;
+
+/// First line of a journal.
+#[derive(Serialize, Deserialize, Debug)]
+struct JournalHeader {
+    journal_version: u32,
+}
+
+/// A change to the worker stats or the lock table.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(tag = "op", rename_all = "snake_case")]
+pub enum JournalOp {
+    /// An accepted worker-stats event.
+    Stats {
+        worker_id: String,
+        req: SnarkWorkerStatsPut,
+        /// Session id a `Register` resulted in.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        session_id: Option<String>,
+        /// Lease of the job lock a `JobGetSuccess` was given.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+    /// A worker's stats were removed.
+    Deregister { worker_id: String },
+    /// A worker's states but its most recent one were dropped.
+    ClearHistory { worker_id: String },
+    /// All worker stats were dropped.
+    Reset,
+    /// Pending states timed out with `--pending-timeout`.
+    TimeOut { timeout_ms: u64, now: u64 },
+    /// States ended before `min_t` were pruned with `--stats-retention`,
+    /// or a tenant's `retention_ms` for the workers with its `prefix`.
+    Prune {
+        min_t: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        prefix: Option<String>,
+    },
+    /// States ended before `min_t` were compacted with `--compact-after`.
+    Compact { min_t: u64 },
+    /// Worker stats were loaded from a snapshot.
+    RestoreStats { stats: WorkerStatsSnapshot },
+    /// Locks were loaded from a snapshot, their TTLs counting from the
+    /// entry's time.
+    RestoreLocks { locks: LockTableSnapshot },
+    /// `keys` were locked together.
+    Acquire {
+        keys: Vec<String>,
+        holder: Option<String>,
+        fencing_token: u64,
+        expires_t: u64,
+    },
+    /// The lock of `key` was released, or `purged` by an operator.
+    Release {
+        key: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        fulfilled_by: Option<LockFulfillment>,
+        #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+        purged: bool,
+    },
+}
+
+/// Line of the journal.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct JournalEntry {
+    /// When the change was made.
+    pub t: u64,
+    #[serde(flatten)]
+    pub op: JournalOp,
+}
+
+#[derive(Debug)]
+enum Command {
+    Append(Box<JournalEntry>),
+    /// Replace the journal with these entries.
+    Compact(Vec<JournalEntry>),
+}
+
+/// Handle recording entries into the journal, cheap to clone. Without a
+/// file or feed, entries go nowhere.
+#[derive(Debug, Clone, Default)]
+pub struct Journal {
+    /// Set if entries are written to a file.
+    tx: Option<mpsc::UnboundedSender<Command>>,
+    /// Set if entries are fed to subscribers.
+    feed: Option<broadcast::Sender<JournalEntry>>,
+}
+
+impl Journal {
+    /// Opens the journal at `path` for appending, creating it if missing,
+    /// and starts its writer task.
+    pub async fn open(path: PathBuf) -> io::Result<Self> {
+        let mut file = append(&path).await?;
+        if file.get_ref().metadata().await?.len() == 0 {
+            write_header(&mut file).await?;
+            file.flush().await?;
+        }
+        let (tx, rx) = mpsc::unbounded_channel();
+        tokio::spawn(write(path, file, rx));
+        Ok(Self {
+            tx: Some(tx),
+            feed: None,
+        })
+    }
+
+    /// Feeds entries to subscribers too, each of which may fall up to
+    /// `capacity` entries behind.
+    pub fn with_feed(mut self, capacity: usize) -> Self {
+        self.feed = Some(broadcast::channel(capacity).0);
+        self
+    }
+
+    /// Entries recorded from now on, `None` without a feed.
+    pub fn subscribe(&self) -> Option<broadcast::Receiver<JournalEntry>> {
+        self.feed.as_ref().map(broadcast::Sender::subscribe)
+    }
+
+    pub fn record(&self, op: JournalOp) {
+        self.append(JournalEntry {
+            t: timestamp::now(),
+            op,
+        });
+    }
+
+    /// Records `entry` as it is, e.g. as replicated from a leader.
+    pub fn append(&self, entry: JournalEntry) {
+        if let Some(feed) = &self.feed {
+            let _ = feed.send(entry.clone());
+        }
+        if let Some(tx) = &self.tx {
+            let _ = tx.send(Command::Append(Box::new(entry)));
+        }
+    }
+
+    /// Replaces the journal's file with `ops`, which must capture all
+    /// entries recorded so far. Entries recorded afterwards are appended
+    /// to them.
+    pub fn compact(&self, ops: Vec<JournalOp>) {
+        let Some(tx) = &self.tx else {
+            return;
+        };
+        let t = timestamp::now();
+        let entries = ops.into_iter().map(|op| JournalEntry { t, op }).collect();
+        let _ = tx.send(Command::Compact(entries));
+    }
+}
+
+async fn append(path: &Path) -> io::Result<BufWriter<File>> {
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .await?;
+    Ok(BufWriter::new(file))
+}
+
+async fn write_header(file: &mut BufWriter<File>) -> io::Result<()> {
+    let header = JournalHeader {
+        journal_version: VERSION,
+    };
+    let mut line = serde_json::to_vec(&header).map_err(io::Error::other)?;
+    line.push(b'\n');
+    file.write_all(&line).await
+}
+
+async fn write_entry(file: &mut BufWriter<File>, entry: &JournalEntry) -> io::Result<()> {
+    let mut line = serde_json::to_vec(entry).map_err(io::Error::other)?;
+    line.push(b'\n');
+    file.write_all(&line).await
+}
+
+/// Writes compacted `entries` next to `path` and moves them over it.
+async fn compact(path: &Path, entries: &[JournalEntry]) -> io::Result<BufWriter<File>> {
+    let tmp = path.with_extension("compacting");
+    let mut file = BufWriter::new(File::create(&tmp).await?);
+    write_header(&mut file).await?;
+    for entry in entries {
+        write_entry(&mut file, entry).await?;
+    }
+    file.flush().await?;
+    file.get_ref().sync_all().await?;
+    tokio::fs::rename(&tmp, path).await?;
+    append(path).await
+}
+
+/// Writes commands as they come, flushing whenever none are queued.
+async fn write(path: PathBuf, mut file: BufWriter<File>, mut rx: mpsc::UnboundedReceiver<Command>) {
+    while let Some(command) = rx.recv().await {
+        let mut next = Some(command);
+        while let Some(command) = next {
+            let res = match command {
+                Command::Append(entry) => write_entry(&mut file, &entry).await,
+                Command::Compact(entries) => match file.flush().await {
+                    Ok(()) => compact(&path, &entries).await.map(|f| file = f),
+                    Err(err) => Err(err),
+                },
+            };
+            if let Err(err) = res {
+                warn!(%err, path = %path.display(), "failed to write journal");
+            }
+            next = rx.try_recv().ok();
+        }
+        if let Err(err) = file.flush().await {
+            warn!(%err, path = %path.display(), "failed to flush journal");
+        }
+    }
+}
+
+/// Outcome of [`replay`].
+#[derive(Debug, Default)]
+pub struct Replayed {
+    pub entries: usize,
+    /// Stats events which didn't apply, e.g. as limits changed since.
+    pub rejected: usize,
+}
+
+/// Applies the entries of the journal at `path` to `stats` and `kv`, in
+/// order. A missing journal has none. A last line which was cut off, e.g.
+/// by a crash, is skipped and truncated, so entries appended next don't
+/// run into it. Journals of a newer [`VERSION`] are refused.
+pub fn replay(path: &Path, stats: &mut WorkerStats, kv: &mut LockedShards) -> io::Result<Replayed> {
+    let journal = match std::fs::read_to_string(path) {
+        Ok(journal) => journal,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Replayed::default()),
+        Err(err) => return Err(err),
+    };
+    let mut replayed = Replayed::default();
+    let mut offset = 0;
+    for (i, line) in journal.split_inclusive('\n').enumerate() {
+        let start = offset;
+        offset += line.len();
+        if line.trim().is_empty() {
+            continue;
+        }
+        if start == 0 && is_header(line)? {
+            continue;
+        }
+        let entry = match serde_json::from_str::<JournalEntry>(line) {
+            Ok(entry) => {
+                if !line.ends_with('\n') {
+                    std::fs::OpenOptions::new()
+                        .append(true)
+                        .open(path)?
+                        .write_all(b"\n")?;
+                }
+                entry
+            }
+            Err(err) if offset == journal.len() => {
+                warn!(%err, "skipped cut off last journal entry");
+                truncate(path, start)?;
+                break;
+            }
+            Err(err) => {
+                let msg = format!("invalid journal entry on line {}: {err}", i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        };
+        if !apply(entry, stats, kv) {
+            replayed.rejected += 1;
+        }
+        replayed.entries += 1;
+    }
+    Ok(replayed)
+}
+
+/// Entries of a journal's contents, in order. A last line which was cut
+/// off is skipped.
+pub fn parse(journal: &str) -> io::Result<Vec<JournalEntry>> {
+    let mut entries = vec![];
+    let lines = journal.lines().enumerate();
+    let mut lines = lines.filter(|(_, line)| !line.trim().is_empty()).peekable();
+    while let Some((i, line)) = lines.next() {
+        if i == 0 && is_header(line)? {
+            continue;
+        }
+        match serde_json::from_str::<JournalEntry>(line) {
+            Ok(entry) => entries.push(entry),
+            Err(err) if lines.peek().is_none() => {
+                warn!(%err, "skipped cut off last journal entry");
+            }
+            Err(err) => {
+                let msg = format!("invalid journal entry on line {}: {err}", i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        }
+    }
+    Ok(entries)
+}
+
+/// Whether `line` is a journal header, erring if it's of a newer version.
+fn is_header(line: &str) -> io::Result<bool> {
+    let Ok(header) = serde_json::from_str::<JournalHeader>(line) else {
+        return Ok(false);
+    };
+    migrations::check_version("journal", header.journal_version, VERSION)?;
+    Ok(true)
+}
+
+/// Format version of the contents of a journal, by its header. Journals
+/// written before there was one are of version 1.
+pub fn version(journal: &str) -> u32 {
+    let header = journal.lines().find(|line| !line.trim().is_empty());
+    header
+        .and_then(|line| serde_json::from_str::<JournalHeader>(line).ok())
+        .map_or(1, |header| header.journal_version)
+}
+
+fn truncate(path: &Path, len: usize) -> io::Result<()> {
+    std::fs::OpenOptions::new()
+        .write(true)
+        .open(path)?
+        .set_len(len as u64)
+}
+
+/// Applies `entry`, returns whether it applied. Lock operations aren't
+/// journaled again.
+pub fn apply(entry: JournalEntry, stats: &mut WorkerStats, kv: &mut LockedShards) -> bool {
+    apply_at(entry, stats, kv, timestamp::now())
+}
+
+/// Applies `entry` as if it was `now_t`, e.g. to rebuild the state of a
+/// past time: locks held at `now_t` are held for the rest of their TTL
+/// from now on.
+pub fn apply_at(
+    entry: JournalEntry,
+    stats: &mut WorkerStats,
+    kv: &mut LockedShards,
+    now_t: u64,
+) -> bool {
+    let journal = kv.take_journal();
+    let applied = apply_unjournaled(entry, stats, kv, now_t);
+    if let Some(journal) = journal {
+        kv.set_journal(journal);
+    }
+    applied
+}
+
+fn apply_unjournaled(
+    entry: JournalEntry,
+    stats: &mut WorkerStats,
+    kv: &mut LockedShards,
+    now_t: u64,
+) -> bool {
+    let now = Instant::now();
+    // unix time to an instant, `None` if it's past.
+    let instant = |t: u64| {
+        t.checked_sub(now_t)
+            .map(|ms| now + Duration::from_millis(ms))
+    };
+    match entry.op {
+        JournalOp::Stats {
+            worker_id,
+            req,
+            session_id,
+            lease,
+        } => {
+            if stats::put_with_session_id(stats, worker_id.clone(), req, session_id).is_err() {
+                return false;
+            }
+            if let (Some(lease), Some(state)) = (lease, stats.latest_mut(&worker_id)) {
+                state.set_lease(lease);
+            }
+        }
+        JournalOp::Deregister { worker_id } => {
+            stats.remove(&worker_id);
+        }
+        JournalOp::ClearHistory { worker_id } => {
+            stats.clear_history(&worker_id);
+        }
+        JournalOp::Reset => stats.clear(),
+        JournalOp::TimeOut { timeout_ms, now } => {
+            stats.time_out_pending(timeout_ms, now);
+        }
+        JournalOp::Prune { min_t, prefix } => {
+            stats.prune(min_t, prefix.as_deref());
+        }
+        JournalOp::Compact { min_t } => {
+            stats.compact(min_t);
+        }
+        JournalOp::RestoreStats { stats: snapshot } => stats.restore(snapshot),
+        JournalOp::RestoreLocks { mut locks } => {
+            let elapsed_ms = now_t.saturating_sub(entry.t);
+            locks.locks.retain(|_, lock| {
+                lock.remaining_ttl_ms = lock.remaining_ttl_ms.saturating_sub(elapsed_ms);
+                lock.remaining_ttl_ms > 0
+            });
+            kv.restore(locks, now);
+        }
+        JournalOp::Acquire {
+            keys,
+            holder,
+            fencing_token,
+            expires_t,
+        } => {
+            let expires_at = instant(expires_t);
+            let lock = expires_at.map(|expires_at| JobLock {
+                expires_at,
+                holder,
+                fencing_token,
+                acquired_t: None,
+            });
+            kv.replay_acquire(keys, fencing_token, lock);
+        }
+        JournalOp::Release {
+            key,
+            fulfilled_by,
+            purged,
+        } => {
+            match purged {
+                true => kv.purge(&key),
+                false => kv.release(&key, fulfilled_by),
+            };
+        }
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lock::{LockShards, LockTable};
+
+    use super::*;
+
+    fn path(name: &str) -> PathBuf {
+        let name = format!("journal-{name}-{}.ndjson", std::process::id());
+        std::env::temp_dir().join(name)
+    }
+
+    fn acquire(key: &str, fencing_token: u64) -> String {
+        let entry = JournalEntry {
+            t: timestamp::now(),
+            op: JournalOp::Acquire {
+                keys: vec![key.to_owned()],
+                holder: Some("w".to_owned()),
+                fencing_token,
+                expires_t: timestamp::now() + 60_000,
+            },
+        };
+        serde_json::to_string(&entry).unwrap() + "\n"
+    }
+
+    #[tokio::test]
+    async fn replay_truncates_a_cut_off_last_entry() {
+        let release = JournalEntry {
+            t: timestamp::now(),
+            op: JournalOp::Release {
+                key: "j1".to_owned(),
+                fulfilled_by: None,
+                purged: false,
+            },
+        };
+        let entries = acquire("j1", 1) + &serde_json::to_string(&release).unwrap() + "\n";
+        let cut = &acquire("j2", 2)[..20];
+        let path = path("cut");
+        std::fs::write(&path, entries.clone() + cut).unwrap();
+
+        let shards = LockShards::new(LockTable::new(16), 1);
+        let mut kv = shards.lock_all().await;
+        let replayed = replay(&path, &mut WorkerStats::new(), &mut kv).unwrap();
+        assert_eq!((replayed.entries, replayed.rejected), (2, 0));
+        assert!(kv.lease("j1", Instant::now()).is_none());
+        assert!(kv.lease("j2", Instant::now()).is_none());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), entries);
+
+        // but a broken entry before the last one is an error.
+        std::fs::write(&path, format!("{cut}\n{entries}")).unwrap();
+        let err = replay(&path, &mut WorkerStats::new(), &mut kv).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    async fn replay_str(name: &str, journal: &str) -> io::Result<(Replayed, u64)> {
+        let path = path(name);
+        std::fs::write(&path, journal).unwrap();
+        let shards = LockShards::new(LockTable::new(16), 1);
+        let mut kv = shards.lock_all().await;
+        let replayed = replay(&path, &mut WorkerStats::new(), &mut kv);
+        let lease = kv.lease("j2", Instant::now()).map(|l| l.fencing_token);
+        std::fs::remove_file(&path).unwrap();
+        replayed.map(|replayed| (replayed, lease.unwrap_or(0)))
+    }
+
+    #[tokio::test]
+    async fn replays_versioned_and_older_journals() {
+        let entries = acquire("j1", 1) + &acquire("j2", 2);
+        let header = format!("{{\"journal_version\":{VERSION}}}\n");
+        let (replayed, token) = replay_str("versioned", &(header + &entries)).await.unwrap();
+        assert_eq!((replayed.entries, token), (2, 2));
+
+        let (replayed, token) = replay_str("unversioned", &entries).await.unwrap();
+        assert_eq!((replayed.entries, token), (2, 2));
+        assert_eq!(parse(&entries).unwrap().len(), 2);
+    }
+
+    #[tokio::test]
+    async fn refuses_newer_journals() {
+        let journal = format!("{{\"journal_version\":{}}}\n", VERSION + 1) + &acquire("j1", 1);
+        let err = replay_str("newer", &journal).await.unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(parse(&journal).is_err());
+    }
+
+    #[tokio::test]
+    async fn new_and_compacted_journals_start_with_a_header() {
+        let path = path("open");
+        let _ = std::fs::remove_file(&path);
+        let journal = Journal::open(path.clone()).await.unwrap();
+        let header = format!("{{\"journal_version\":{VERSION}}}\n");
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), header);
+
+        journal.compact(vec![JournalOp::Reset]);
+        drop(journal);
+        for _ in 0..100 {
+            let written = std::fs::read_to_string(&path).unwrap();
+            if written.lines().count() == 2 {
+                assert!(written.starts_with(&header));
+                assert_eq!(parse(&written).unwrap().len(), 1);
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        assert_eq!(std::fs::read_to_string(&path).unwrap().lines().count(), 2);
+        std::fs::remove_file(&path).unwrap();
+    }
+}
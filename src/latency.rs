@@ -0,0 +1,467 @@
+//! Latency percentiles per lifecycle phase of successful jobs, including
+//! the node-side sub-phases workers report.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum Phase {
+    /// Requesting a job until receiving it.
+    JobGet,
+    /// Node receiving the job request until it got the work.
+    JobGetNode,
+    /// Node requesting the work until getting it.
+    JobGetNodeRequestWork,
+    /// Receiving the job until the proof was created.
+    WorkCreate,
+    /// Proof created until the work was submitted.
+    WorkSubmit,
+    /// Node receiving the work until it was added.
+    WorkSubmitNode,
+    /// Node adding the work until it succeeded.
+    WorkSubmitNodeAddWork,
+    /// Requesting the job until the work was submitted.
+    EndToEnd,
+}
+
+impl Phase {
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Self::JobGet => "job_get",
+            Self::JobGetNode => "job_get_node",
+            Self::JobGetNodeRequestWork => "job_get_node_request_work",
+            Self::WorkCreate => "work_create",
+            Self::WorkSubmit => "work_submit",
+            Self::WorkSubmitNode => "work_submit_node",
+            Self::WorkSubmitNodeAddWork => "work_submit_node_add_work",
+            Self::EndToEnd => "end_to_end",
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PhaseLatency {
+    pub samples: usize,
+    pub p50_ms: u64,
+    pub p90_ms: u64,
+    pub p99_ms: u64,
+    pub max_ms: u64,
+}
+
+pub type PhaseLatencies = BTreeMap<Phase, PhaseLatency>;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LatencyWindow {
+    /// Start of the window, lifecycles are bucketed by their end.
+    pub start_t: u64,
+    pub phases: PhaseLatencies,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LatencyReport {
+    pub total: PhaseLatencies,
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub workers: BTreeMap<String, PhaseLatencies>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub windows: Vec<LatencyWindow>,
+}
+
+/// Which lifecycles to include and how to bucket them.
+#[derive(Debug, Clone, Default)]
+pub struct LatencyQuery {
+    /// Only lifecycles ending within `from_t..=to_t`.
+    pub from_t: Option<u64>,
+    pub to_t: Option<u64>,
+    pub by_worker: bool,
+    /// Also bucket lifecycles into windows of this length.
+    pub window_ms: Option<u64>,
+}
+
+#[derive(Default)]
+struct Samples(BTreeMap<Phase, Vec<u64>>);
+
+impl Samples {
+    fn add(&mut self, phases: &[(Phase, u64)]) {
+        for &(phase, ms) in phases {
+            self.0.entry(phase).or_default().push(ms);
+        }
+    }
+
+    fn finish(mut self) -> PhaseLatencies {
+        self.0
+            .iter_mut()
+            .map(|(phase, sorted)| {
+                sorted.sort_unstable();
+                let latency = PhaseLatency {
+                    samples: sorted.len(),
+                    p50_ms: percentile(sorted, 0.5),
+                    p90_ms: percentile(sorted, 0.9),
+                    p99_ms: percentile(sorted, 0.99),
+                    max_ms: sorted[sorted.len() - 1],
+                };
+                (*phase, latency)
+            })
+            .collect()
+    }
+}
+
+/// Nearest-rank percentile `p` of non-empty `sorted` samples.
+pub fn percentile(sorted: &[u64], p: f64) -> u64 {
+    sorted[((sorted.len() - 1) as f64 * p).round() as usize]
+}
+
+/// Phase durations of a successful lifecycle. Node-side phases are only
+/// included if the worker reported their timestamps.
+pub fn phases(state: &SnarkWorkerState) -> Option<Vec<(Phase, u64)>> {
+    let SnarkWorkerState::WorkSubmitSuccess { .. } = state else {
+        return None;
+    };
+    Some(durations(state).into_iter().collect())
+}
+
+/// Timestamps a lifecycle got to, `None` for the ones it didn't.
+#[derive(Default)]
+struct Marks {
+    job_get_init_t: Option<u64>,
+    job_get_node_received_t: Option<u64>,
+    job_get_node_request_work_init_t: Option<u64>,
+    job_get_node_request_work_success_t: Option<u64>,
+    /// Got or failed to get a job.
+    job_get_end_t: Option<u64>,
+    /// Created or failed to create the work.
+    work_create_end_t: Option<u64>,
+    /// Submitted or failed to submit the work.
+    work_submit_end_t: Option<u64>,
+    work_submit_node_received_t: Option<u64>,
+    work_submit_node_add_work_init_t: Option<u64>,
+    work_submit_node_add_work_success_t: Option<u64>,
+    /// End of a lifecycle which is over, not counting timeouts.
+    end_t: Option<u64>,
+}
+
+impl Marks {
+    fn of(state: &SnarkWorkerState) -> Self {
+        match *state {
+            SnarkWorkerState::Registered { .. }
+            | SnarkWorkerState::Restarted { .. }
+            | SnarkWorkerState::JobGetPending { .. }
+            | SnarkWorkerState::JobGetTimeout { .. } => Self::default(),
+            SnarkWorkerState::JobUnavailable {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t: end_t,
+            }
+            | SnarkWorkerState::JobGetError {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_error_t: end_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(end_t),
+                end_t: Some(end_t),
+                ..Self::default()
+            },
+            SnarkWorkerState::WorkCreatePending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                ..
+            }
+            | SnarkWorkerState::WorkCreateTimeout {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(job_get_success_t),
+                ..Self::default()
+            },
+            SnarkWorkerState::WorkCreateError {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_error_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(job_get_success_t),
+                work_create_end_t: Some(work_create_error_t),
+                end_t: Some(work_create_error_t),
+                ..Self::default()
+            },
+            SnarkWorkerState::WorkSubmitPending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                ..
+            }
+            | SnarkWorkerState::WorkSubmitTimeout {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(job_get_success_t),
+                work_create_end_t: Some(work_create_success_t),
+                ..Self::default()
+            },
+            SnarkWorkerState::WorkSubmitError {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                work_submit_error_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(job_get_success_t),
+                work_create_end_t: Some(work_create_success_t),
+                work_submit_end_t: Some(work_submit_error_t),
+                end_t: Some(work_submit_error_t),
+                ..Self::default()
+            },
+            SnarkWorkerState::WorkSubmitSuccess {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                work_submit_node_received_t,
+                work_submit_node_add_work_init_t,
+                work_submit_node_add_work_success_t,
+                work_submit_success_t,
+                ..
+            } => Self {
+                job_get_init_t: Some(job_get_init_t),
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_end_t: Some(job_get_success_t),
+                work_create_end_t: Some(work_create_success_t),
+                work_submit_end_t: Some(work_submit_success_t),
+                work_submit_node_received_t,
+                work_submit_node_add_work_init_t,
+                work_submit_node_add_work_success_t,
+                end_t: Some(work_submit_success_t),
+            },
+        }
+    }
+}
+
+/// Durations of the phases `state` got through, whether they succeeded
+/// or failed. Pending phases are left out, and so are node-side phases
+/// the worker didn't report the timestamps of. `EndToEnd` is only
+/// included once the lifecycle is over.
+pub fn durations(state: &SnarkWorkerState) -> BTreeMap<Phase, u64> {
+    let marks = Marks::of(state);
+    let span = |from: Option<u64>, to: Option<u64>| Some(to?.saturating_sub(from?));
+    let phases = [
+        (
+            Phase::JobGet,
+            span(marks.job_get_init_t, marks.job_get_end_t),
+        ),
+        (
+            Phase::JobGetNode,
+            span(
+                marks.job_get_node_received_t,
+                marks.job_get_node_request_work_success_t,
+            ),
+        ),
+        (
+            Phase::JobGetNodeRequestWork,
+            span(
+                marks.job_get_node_request_work_init_t,
+                marks.job_get_node_request_work_success_t,
+            ),
+        ),
+        (
+            Phase::WorkCreate,
+            span(marks.job_get_end_t, marks.work_create_end_t),
+        ),
+        (
+            Phase::WorkSubmit,
+            span(marks.work_create_end_t, marks.work_submit_end_t),
+        ),
+        (
+            Phase::WorkSubmitNode,
+            span(
+                marks.work_submit_node_received_t,
+                marks.work_submit_node_add_work_success_t,
+            ),
+        ),
+        (
+            Phase::WorkSubmitNodeAddWork,
+            span(
+                marks.work_submit_node_add_work_init_t,
+                marks.work_submit_node_add_work_success_t,
+            ),
+        ),
+        (Phase::EndToEnd, span(marks.job_get_init_t, marks.end_t)),
+    ];
+    phases
+        .into_iter()
+        .filter_map(|(phase, ms)| Some((phase, ms?)))
+        .collect()
+}
+
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    query: &LatencyQuery,
+) -> LatencyReport {
+    let mut total = Samples::default();
+    let mut workers = BTreeMap::<&String, Samples>::new();
+    let mut windows = BTreeMap::<u64, Samples>::new();
+    for (worker_id, states) in stats {
+        for state in states {
+            let end_t = state.end_time();
+            if query.from_t.is_some_and(|t| end_t < t) || query.to_t.is_some_and(|t| end_t > t) {
+                continue;
+            }
+            let Some(phases) = phases(state) else {
+                continue;
+            };
+            total.add(&phases);
+            if query.by_worker {
+                workers.entry(worker_id).or_default().add(&phases);
+            }
+            if let Some(window_ms) = query.window_ms.filter(|ms| *ms > 0) {
+                let start_t = end_t - end_t % window_ms;
+                windows.entry(start_t).or_default().add(&phases);
+            }
+        }
+    }
+    LatencyReport {
+        total: total.finish(),
+        workers: workers
+            .into_iter()
+            .map(|(id, samples)| (id.clone(), samples.finish()))
+            .collect(),
+        windows: windows
+            .into_iter()
+            .map(|(start_t, samples)| LatencyWindow {
+                start_t,
+                phases: samples.finish(),
+            })
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(json: &str) -> SnarkWorkerState {
+        serde_json::from_str(json).unwrap()
+    }
+
+    /// A lifecycle taking 10ms to get the job, `create_ms` to create the
+    /// work and 5ms to submit it.
+    fn succeeded(init_t: u64, create_ms: u64) -> SnarkWorkerState {
+        let got_t = init_t + 10;
+        let created_t = got_t + create_ms;
+        state(&format!(
+            r#"{{"kind":"WorkSubmitSuccess","job_get_init_t":{init_t},"job_get_success_t":{got_t},
+                "work_create_success_t":{created_t},"work_submit_success_t":{},"ids":"j"}}"#,
+            created_t + 5
+        ))
+    }
+
+    #[test]
+    fn failed_lifecycles_have_durations_up_to_the_failure() {
+        let failed = state(
+            r#"{"kind":"WorkCreateError","job_get_init_t":0,"job_get_success_t":10,
+                "work_create_error_t":40,"ids":"j","error":"oom"}"#,
+        );
+        assert_eq!(
+            durations(&failed).into_iter().collect::<Vec<_>>(),
+            [
+                (Phase::JobGet, 10),
+                (Phase::WorkCreate, 30),
+                (Phase::EndToEnd, 40)
+            ]
+        );
+        // only successful lifecycles are sampled.
+        assert!(phases(&failed).is_none());
+
+        // nor is the end-to-end duration known before the lifecycle is over.
+        let pending = state(
+            r#"{"kind":"WorkCreatePending","job_get_init_t":0,"job_get_success_t":10,"ids":"j"}"#,
+        );
+        assert_eq!(
+            durations(&pending).keys().collect::<Vec<_>>(),
+            [&Phase::JobGet]
+        );
+    }
+
+    #[test]
+    fn reports_percentiles_per_phase_worker_and_window() {
+        let w1 = (1..=100).map(|i| succeeded(i * 1000, i)).collect();
+        let w2 = VecDeque::from([succeeded(500_000, 1000)]);
+        let stats = [("w1".to_owned(), w1), ("w2".to_owned(), w2)];
+        let query = LatencyQuery {
+            from_t: Some(1000),
+            to_t: Some(200_000),
+            by_worker: true,
+            window_ms: Some(50_000),
+        };
+        let report = report(stats.iter().map(|(k, v)| (k, v)), &query);
+
+        // w2's lifecycle ended past `to_t`.
+        let create = &report.total[&Phase::WorkCreate];
+        assert_eq!(create.samples, 100);
+        assert_eq!((create.p50_ms, create.p90_ms), (51, 90));
+        assert_eq!((create.p99_ms, create.max_ms), (99, 100));
+        assert_eq!(report.total[&Phase::JobGet].max_ms, 10);
+        // node-side phases weren't reported.
+        assert!(!report.total.contains_key(&Phase::JobGetNode));
+        assert_eq!(report.workers.keys().collect::<Vec<_>>(), ["w1"]);
+
+        let windows = report
+            .windows
+            .iter()
+            .map(|w| (w.start_t, w.phases[&Phase::EndToEnd].samples))
+            .collect::<Vec<_>>();
+        assert_eq!(windows, [(0, 49), (50_000, 50), (100_000, 1)]);
+    }
+}
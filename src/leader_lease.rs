@@ -0,0 +1,74 @@
+//! Leader election for `--cluster`, with a lease in Redis. Each member
+//! campaigns periodically: the lease is taken if it's vacant and renewed
+//! if the member holds it, so the leader keeps it for as long as it keeps
+//! renewing, and another member takes over within a TTL once it stops.
+
+use redis::{aio::ConnectionManager, RedisResult, Script};
+
+/// Key of the lease, holding the leader's advertised URL.
+const KEY: &str = "snark-coordinator:leader";
+
+/// Takes `KEYS[1]` for `ARGV[1]` with a TTL of `ARGV[2]` ms if it's vacant
+/// or held by `ARGV[1]` already. Returns the holder.
+const CAMPAIGN: &str = r"
+local leader = redis.call('GET', KEYS[1])
+if not leader or leader == ARGV[1] then
+    redis.call('SET', KEYS[1], ARGV[1], 'PX', ARGV[2])
+    return ARGV[1]
+end
+return leader
+";
+
+/// Deletes `KEYS[1]` if it's held by `ARGV[1]`.
+const RESIGN: &str = r"
+if redis.call('GET', KEYS[1]) == ARGV[1] then
+    return redis.call('DEL', KEYS[1])
+end
+return 0
+";
+
+pub struct LeaderLease {
+    conn: ConnectionManager,
+    campaign: Script,
+    resign: Script,
+    /// URL other members reach this one at, which identifies it.
+    id: String,
+    ttl_ms: u64,
+}
+
+impl LeaderLease {
+    pub async fn connect(url: &str, id: String, ttl_ms: u64) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            conn: client.get_tokio_connection_manager().await?,
+            campaign: Script::new(CAMPAIGN),
+            resign: Script::new(RESIGN),
+            id,
+            ttl_ms,
+        })
+    }
+
+    pub fn id(&self) -> &str {
+        &self.id
+    }
+
+    /// Takes or renews the lease if possible, returns the leader's URL.
+    pub async fn campaign(&self) -> RedisResult<String> {
+        self.campaign
+            .key(KEY)
+            .arg(&self.id)
+            .arg(self.ttl_ms.max(1))
+            .invoke_async(&mut self.conn.clone())
+            .await
+    }
+
+    /// Gives up the lease if held, so another member takes over without
+    /// waiting for it to run out.
+    pub async fn resign(&self) -> RedisResult<()> {
+        self.resign
+            .key(KEY)
+            .arg(&self.id)
+            .invoke_async(&mut self.conn.clone())
+            .await
+    }
+}
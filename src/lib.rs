@@ -0,0 +1,63 @@
+pub mod annotations;
+pub mod anomaly;
+pub mod api_keys;
+pub mod archive;
+pub mod assignment;
+pub mod availability;
+pub mod batch;
+#[cfg(feature = "client")]
+pub mod client;
+pub mod clock_skew;
+pub mod compaction;
+pub mod compat;
+pub mod cooldown;
+pub mod domains;
+pub mod durations;
+pub mod efficiency;
+pub mod error_events;
+pub mod error_groups;
+pub mod errors;
+pub mod export;
+pub mod groups;
+pub mod handshake;
+pub mod hooks;
+pub mod host_metrics;
+pub mod hot_keys;
+pub mod idempotency;
+#[cfg(feature = "server")]
+pub mod job_pool;
+pub mod jobs;
+pub mod journal;
+pub mod latency;
+pub mod leader_lease;
+pub mod liveness;
+pub mod lock;
+pub mod lock_namespaces;
+pub mod lock_stats;
+pub mod lock_ttl;
+pub mod maintenance;
+pub mod metrics;
+pub mod migrations;
+pub mod network;
+#[cfg(feature = "server")]
+pub mod otlp;
+pub mod outliers;
+pub mod pins;
+pub mod profile;
+#[cfg(feature = "server")]
+pub mod push;
+pub mod quarantine;
+pub mod rate_limit;
+pub mod redis_locks;
+pub mod reset;
+pub mod stats;
+pub mod strict;
+pub mod stuck;
+pub mod summary;
+pub mod tenants;
+pub mod throughput;
+pub mod timestamp;
+pub mod top;
+#[cfg(feature = "server")]
+pub mod webhook;
+pub mod worker_tokens;
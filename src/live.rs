@@ -0,0 +1,122 @@
+use std::convert::Infallible;
+
+use futures_util::{stream, SinkExt, Stream, StreamExt};
+use serde::Deserialize;
+use snark_coordinator_rs::{
+    error_events::ErrorEvent, groups::Scope, journal::JournalEntry, stats::Transition,
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::{
+    sse::Event,
+    ws::{Message, WebSocket},
+};
+
+/// Message a subscriber sends to change its worker filter.
+#[derive(Deserialize, Debug)]
+struct Subscribe {
+    /// Worker ids to stream transitions of, all if `None`.
+    workers: Option<Vec<String>>,
+}
+
+/// Streams `transitions` of workers within `scope` and `workers` to the
+/// socket as JSON text messages, until either side goes away.
+pub async fn feed(
+    socket: WebSocket,
+    mut transitions: broadcast::Receiver<Transition>,
+    scope: Scope,
+    mut workers: Option<Vec<String>>,
+) {
+    let (mut tx, mut rx) = socket.split();
+    loop {
+        let msg = tokio::select! {
+            msg = rx.next() => match msg {
+                Some(Ok(msg)) if msg.is_text() => {
+                    match serde_json::from_str::<Subscribe>(msg.to_str().unwrap_or_default()) {
+                        Ok(subscribe) => workers = subscribe.workers,
+                        Err(err) => tracing::debug!(%err, "invalid subscribe message"),
+                    }
+                    continue;
+                }
+                Some(Ok(msg)) if msg.is_close() => break,
+                Some(Ok(_)) => continue,
+                Some(Err(err)) => {
+                    tracing::debug!(%err, "live feed socket failed");
+                    break;
+                }
+                None => break,
+            },
+            transition = transitions.recv() => match transition {
+                Ok(t) => {
+                    let wanted = scope.contains(&t.worker_id)
+                        && workers.as_ref().is_none_or(|w| w.contains(&t.worker_id));
+                    if !wanted {
+                        continue;
+                    }
+                    serde_json::to_string(&t).unwrap()
+                }
+                // subscribers falling behind are told how much they missed
+                // rather than disconnected.
+                Err(RecvError::Lagged(missed)) => serde_json::json!({ "lagged": missed }).to_string(),
+                Err(RecvError::Closed) => break,
+            },
+        };
+        if tx.send(Message::text(msg)).await.is_err() {
+            break;
+        }
+    }
+    let _ = tx.close().await;
+}
+
+/// Server-sent events of `missed` followed by those from `events`, of
+/// workers within `scope`. Ends when the subscriber falls behind, so its
+/// client reconnects and resumes from the last event it got.
+pub fn error_events(
+    missed: Vec<ErrorEvent>,
+    events: broadcast::Receiver<ErrorEvent>,
+    scope: Scope,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let live = stream::unfold(events, |mut events| async move {
+        match events.recv().await {
+            Ok(event) => Some((event, events)),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::debug!(missed, "error events subscriber fell behind");
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    stream::iter(missed)
+        .chain(live)
+        .filter(move |event| std::future::ready(scope.contains(&event.worker_id)))
+        .map(|event| {
+            let sse = Event::default()
+                .id(event.id.to_string())
+                .event(event.kind.as_str());
+            Ok(sse.json_data(&event).unwrap())
+        })
+}
+
+/// Server-sent events replicating state to a follower: a `snapshot` of
+/// the entries which rebuild it, then each `entry` recorded since. Ends
+/// when the follower falls behind, so it reconnects and starts over.
+pub fn replication(
+    snapshot: Vec<JournalEntry>,
+    entries: broadcast::Receiver<JournalEntry>,
+) -> impl Stream<Item = Result<Event, Infallible>> {
+    let live = stream::unfold(entries, |mut entries| async move {
+        match entries.recv().await {
+            Ok(entry) => Some((entry, entries)),
+            Err(RecvError::Lagged(missed)) => {
+                tracing::warn!(missed, "replication follower fell behind");
+                None
+            }
+            Err(RecvError::Closed) => None,
+        }
+    });
+    let snapshot = Event::default()
+        .event("snapshot")
+        .json_data(&snapshot)
+        .unwrap();
+    stream::once(async { Ok(snapshot) })
+        .chain(live.map(|entry| Ok(Event::default().event("entry").json_data(&entry).unwrap())))
+}
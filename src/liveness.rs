@@ -0,0 +1,106 @@
+//! When workers were last heard from, via heartbeats or any stats event,
+//! and whether that makes them look alive.
+
+use std::collections::{HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+/// How long uptime periods are kept, enough for weekly availability.
+const UPTIME_RETENTION_MS: u64 = 8 * 24 * 3600 * 1000;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Alive,
+    /// Missed a few heartbeats, might just be busy or briefly offline.
+    Stale,
+    Dead,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct WorkerLiveness {
+    /// `None` if the worker wasn't heard from since the coordinator
+    /// started.
+    pub last_seen_t: Option<u64>,
+    pub status: Status,
+}
+
+#[derive(Debug)]
+pub struct Liveness {
+    /// Periods in which each worker was heard from without going stale,
+    /// as first and last time seen, oldest first.
+    uptime: HashMap<String, VecDeque<(u64, u64)>>,
+    stale_after_ms: u64,
+    dead_after_ms: u64,
+    started_t: u64,
+}
+
+impl Liveness {
+    /// Workers not heard from for `stale_after_ms` are stale, for
+    /// `dead_after_ms` dead.
+    pub fn new(stale_after_ms: u64, dead_after_ms: u64) -> Self {
+        Self {
+            uptime: HashMap::new(),
+            stale_after_ms,
+            dead_after_ms: dead_after_ms.max(stale_after_ms),
+            started_t: crate::timestamp::now(),
+        }
+    }
+
+    pub fn seen(&mut self, worker_id: &str, t: u64) {
+        let stale_after_ms = self.stale_after_ms;
+        let periods = self.uptime.entry(worker_id.to_owned()).or_default();
+        match periods.back_mut() {
+            Some((_, last_seen)) if t < last_seen.saturating_add(stale_after_ms) => {
+                *last_seen = (*last_seen).max(t);
+            }
+            _ => periods.push_back((t, t)),
+        }
+        while periods
+            .front()
+            .is_some_and(|(_, end)| t.saturating_sub(*end) > UPTIME_RETENTION_MS)
+        {
+            periods.pop_front();
+        }
+    }
+
+    /// Drops what's known about a deregistered worker.
+    pub fn forget(&mut self, worker_id: &str) {
+        self.uptime.remove(worker_id);
+    }
+
+    pub fn get(&self, worker_id: &str, now: u64) -> WorkerLiveness {
+        let last_seen_t = self
+            .uptime
+            .get(worker_id)
+            .and_then(|periods| periods.back())
+            .map(|(_, last_seen)| *last_seen);
+        let status = match last_seen_t.map(|t| now.saturating_sub(t)) {
+            Some(age) if age < self.stale_after_ms => Status::Alive,
+            Some(age) if age < self.dead_after_ms => Status::Stale,
+            _ => Status::Dead,
+        };
+        WorkerLiveness {
+            last_seen_t,
+            status,
+        }
+    }
+
+    /// When tracking started, nothing is known about uptime before.
+    pub fn started_t(&self) -> u64 {
+        self.started_t
+    }
+
+    /// Periods in which the worker was alive, i.e. until it went stale,
+    /// oldest first, cut off at `now`.
+    pub fn alive_periods(&self, worker_id: &str, now: u64) -> Vec<(u64, u64)> {
+        let Some(periods) = self.uptime.get(worker_id) else {
+            return vec![];
+        };
+        periods
+            .iter()
+            .map(|(start, end)| (*start, end.saturating_add(self.stale_after_ms).min(now)))
+            .filter(|(start, end)| start < end)
+            .collect()
+    }
+}
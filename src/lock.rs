@@ -0,0 +1,1301 @@
+use std::{
+    collections::{hash_map::DefaultHasher, BTreeMap, HashMap, VecDeque},
+    hash::{Hash, Hasher},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Weak,
+    },
+    time::{Duration, Instant},
+};
+
+use serde::{Deserialize, Serialize};
+use tokio::sync::{Mutex, MutexGuard, Notify};
+use utoipa::ToSchema;
+
+use crate::{
+    hot_keys::{HotKey, HotKeyConfig, HotKeys, HotKeysReport},
+    journal::{Journal, JournalOp},
+    lock_namespaces,
+    lock_stats::{LockEvent, LockEventKind, LockEvents},
+    pins::{Pin, PinRequest, Pins},
+    stats::Lease,
+    timestamp,
+};
+
+#[derive(Debug, Clone)]
+pub struct JobLock {
+    pub expires_at: Instant,
+    pub holder: Option<String>,
+    /// Assigned by the [LockTable] when the lock is granted.
+    pub fencing_token: u64,
+    /// When it was requested, unknown for locks restored from a snapshot
+    /// or journal.
+    pub acquired_t: Option<u64>,
+}
+
+/// Response body of a lock-job PUT which granted the lock.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct LockJobGranted {
+    pub fencing_token: u64,
+}
+
+/// Response body of a lock-job PUT for a key that is already locked.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+pub struct LockJobHeld {
+    pub holder: Option<String>,
+    pub remaining_ttl_ms: u64,
+    /// Long-polling acquirers waiting for the key ahead of the requester.
+    /// They get it first, in arrival order, even once it's released.
+    #[serde(default)]
+    pub queued: usize,
+}
+
+/// Precondition of a lock-job PUT, which without one locks the key if
+/// it's unheld, or else reports it held.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockCondition {
+    /// `If-None-Match: *`: the key is unheld.
+    Unheld,
+    /// `If-Match: {fencing_token}`: the key is held under the fencing
+    /// token, and gets taken over under a new one.
+    TakeOver(u64),
+    /// `If-Match: {fencing_token}` with `bump`: the key is unheld, or
+    /// held under the fencing token, whose lock gets extended keeping it.
+    Bump(u64),
+}
+
+impl LockCondition {
+    /// Condition of a lock-job PUT from its `If-Match` and `If-None-Match`
+    /// headers and `bump` parameter, `None` if it's unconditional.
+    pub fn from_request(
+        if_match: Option<&str>,
+        if_none_match: Option<&str>,
+        bump: bool,
+    ) -> Result<Option<Self>, String> {
+        match (if_match, if_none_match) {
+            (Some(_), Some(_)) => Err("If-Match and If-None-Match exclude each other".to_owned()),
+            (Some(tag), None) => {
+                let token = (tag.trim().trim_matches('"').parse())
+                    .map_err(|_| format!("If-Match isn't a fencing token: {tag}"))?;
+                Ok(Some(match bump {
+                    true => Self::Bump(token),
+                    false => Self::TakeOver(token),
+                }))
+            }
+            (None, _) if bump => Err("bump needs the lock's fencing token in If-Match".to_owned()),
+            (None, Some(tag)) if tag.trim() == "*" => Ok(Some(Self::Unheld)),
+            (None, Some(tag)) => Err(format!("If-None-Match only supports `*`, found: {tag}")),
+            (None, None) => Ok(None),
+        }
+    }
+}
+
+/// Response body of a lock-jobs PUT when some of the keys are already
+/// locked. None of the requested keys get locked in that case.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default)]
+pub struct LockJobsConflict {
+    pub conflicts: BTreeMap<String, LockJobHeld>,
+}
+
+/// Lock which is no longer held.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockRecord {
+    pub key: String,
+    pub holder: Option<String>,
+    pub fencing_token: u64,
+    pub expires_t: u64,
+    /// Set if the lock was released before it expired.
+    pub released_t: Option<u64>,
+    /// Lifecycle whose completion released the lock.
+    pub fulfilled_by: Option<LockFulfillment>,
+    /// Why the lock was removed from the table.
+    pub removal_reason: RemovalReason,
+    /// When the lock was removed from the table. Expired locks are
+    /// removed once swept or taken over, so this can be after `expires_t`.
+    pub removed_t: u64,
+}
+
+/// Why a lock stopped being held.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RemovalReason {
+    /// Its TTL ran out.
+    Expired,
+    /// Released with a lock-job DELETE.
+    Released,
+    /// Released by the lifecycle of its job ending.
+    Completed,
+    /// Dropped by an operator with `DELETE /admin/locks/{key}`.
+    Purged,
+    /// Replaced while held by a lock under another fencing token, e.g.
+    /// granted with `If-Match` or restored from a snapshot.
+    TakenOver,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockFulfillment {
+    pub worker_id: String,
+    pub job_get_init_t: u64,
+}
+
+/// Lock held when a [`LockTableSnapshot`] was taken.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockSnapshot {
+    pub holder: Option<String>,
+    pub fencing_token: u64,
+    /// TTL left, which the lock is restored with.
+    pub remaining_ttl_ms: u64,
+}
+
+/// Held locks and the fencing token counter of a [`LockTable`], for
+/// moving it to another instance.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LockTableSnapshot {
+    pub last_fencing_token: u64,
+    pub locks: BTreeMap<String, LockSnapshot>,
+}
+
+/// Job lock table. Keys are job ids, values expire at `expires_at`.
+#[derive(Debug, Default)]
+pub struct LockTable {
+    locks: HashMap<String, JobLock>,
+    /// Long-polling acquirers of contended keys, notified whenever the
+    /// key is released or expires so that they can retry right away.
+    waiters: HashMap<String, Waiters>,
+    /// Number of failed acquisition attempts per requesting worker.
+    conflicts: HashMap<String, u64>,
+    /// Recent acquisition attempts of contended keys.
+    hot_keys: HotKeys,
+    /// Recent acquisitions, conflicts and removals of all keys.
+    events: LockEvents,
+    /// Last issued fencing token. Tokens come from a single counter,
+    /// shared by the shards of a [`LockShards`], so they keep increasing
+    /// per key even after the key's entry has been swept.
+    last_fencing_token: Arc<AtomicU64>,
+    /// Most recent lock records first.
+    history: VecDeque<LockRecord>,
+    max_history: usize,
+    pins: Pins,
+    /// Records evicted from `history` but kept because they're pinned,
+    /// oldest first.
+    pinned_history: Vec<LockRecord>,
+    /// Set if lock operations are journaled.
+    journal: Option<Journal>,
+}
+
+/// Place of a long-polling acquirer in the queue of a contended key.
+/// Dropping it leaves the queue, waking the acquirers behind it.
+#[derive(Debug)]
+pub struct WaitTicket {
+    notify: Arc<Notify>,
+}
+
+impl WaitTicket {
+    /// Notified when the key is released or expires, or an acquirer
+    /// leaves its queue.
+    pub fn notify(&self) -> &Notify {
+        &self.notify
+    }
+}
+
+impl Drop for WaitTicket {
+    fn drop(&mut self) {
+        self.notify.notify_waiters();
+    }
+}
+
+/// Long-polling acquirers of a key, in arrival order.
+#[derive(Debug, Default)]
+struct Waiters {
+    notify: Arc<Notify>,
+    /// Tickets of the acquirers, including dropped ones until pruned.
+    queue: VecDeque<Weak<WaitTicket>>,
+}
+
+impl Waiters {
+    /// Forgets the acquirers which left. Returns whether any are left.
+    fn prune(&mut self) -> bool {
+        self.queue.retain(|ticket| ticket.strong_count() > 0);
+        !self.queue.is_empty()
+    }
+
+    /// Number of acquirers queued ahead of `ticket`, all of them if it's
+    /// not queued.
+    fn ahead(&mut self, ticket: Option<&Arc<WaitTicket>>) -> usize {
+        self.prune();
+        let position = ticket.and_then(|ticket| {
+            (self.queue.iter()).position(|t| std::ptr::eq(t.as_ptr(), Arc::as_ptr(ticket)))
+        });
+        position.unwrap_or(self.queue.len())
+    }
+}
+
+impl JobLock {
+    pub fn new(expires_at: Instant, holder: Option<String>) -> Self {
+        Self {
+            expires_at,
+            holder,
+            fencing_token: 0,
+            acquired_t: Some(timestamp::now()),
+        }
+    }
+
+    pub fn held(&self, now: Instant) -> LockJobHeld {
+        LockJobHeld {
+            holder: self.holder.clone(),
+            remaining_ttl_ms: self.expires_at.saturating_duration_since(now).as_millis() as u64,
+            queued: 0,
+        }
+    }
+}
+
+impl LockRecord {
+    /// Time the lock stopped being held.
+    pub fn end_t(&self) -> u64 {
+        self.released_t.unwrap_or(self.expires_t)
+    }
+
+    fn is_pinned(&self, pins: &Pins) -> bool {
+        pins.is_pinned(self.holder.as_deref(), self.end_t())
+    }
+}
+
+impl LockTable {
+    /// Creates a table which remembers up to `max_history` past locks.
+    pub fn new(max_history: usize) -> Self {
+        Self {
+            max_history,
+            ..Self::default()
+        }
+    }
+
+    /// Journals acquisitions and releases of locks into `journal`.
+    pub fn with_journal(mut self, journal: Journal) -> Self {
+        self.journal = Some(journal);
+        self
+    }
+
+    /// Reports keys as hot by `config`.
+    pub fn with_hot_keys(mut self, config: HotKeyConfig) -> Self {
+        self.hot_keys = HotKeys::new(config);
+        self
+    }
+
+    pub(crate) fn take_journal(&mut self) -> Option<Journal> {
+        self.journal.take()
+    }
+
+    pub(crate) fn set_journal(&mut self, journal: Journal) {
+        self.journal = Some(journal);
+    }
+
+    fn journal(&self, op: impl FnOnce() -> JournalOp) {
+        if let Some(journal) = &self.journal {
+            journal.record(op());
+        }
+    }
+
+    /// Records the removal of `lock`. `reason` only applies if the lock
+    /// hadn't expired by then.
+    fn record(
+        &mut self,
+        key: String,
+        lock: JobLock,
+        reason: RemovalReason,
+        fulfilled_by: Option<LockFulfillment>,
+    ) {
+        let now = timestamp::now();
+        let expired = lock.expires_at <= Instant::now();
+        let expires_t = timestamp::from_instant(lock.expires_at);
+        let end_t = if expired { expires_t } else { now };
+        let held_ms = lock.acquired_t.map(|t| end_t.saturating_sub(t));
+        self.events
+            .record(now, &key, LockEventKind::Removed { held_ms, expired });
+        self.history.push_front(LockRecord {
+            key,
+            holder: lock.holder,
+            fencing_token: lock.fencing_token,
+            expires_t,
+            released_t: Some(now).filter(|_| !expired),
+            fulfilled_by,
+            removal_reason: if expired {
+                RemovalReason::Expired
+            } else {
+                reason
+            },
+            removed_t: now,
+        });
+        while self.history.len() > self.max_history {
+            let Some(evicted) = self.history.pop_back() else {
+                break;
+            };
+            if evicted.is_pinned(&self.pins) {
+                self.pinned_history.push(evicted);
+            }
+        }
+    }
+
+    fn next_fencing_token(&mut self) -> u64 {
+        self.last_fencing_token.fetch_add(1, Ordering::Relaxed) + 1
+    }
+
+    fn advance_fencing_token(&mut self, fencing_token: u64) {
+        self.last_fencing_token
+            .fetch_max(fencing_token, Ordering::Relaxed);
+    }
+
+    /// Locks of `keys` held at `now`.
+    fn held<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a String>,
+        now: Instant,
+    ) -> BTreeMap<String, LockJobHeld> {
+        keys.into_iter()
+            .filter_map(|key| Some((key, self.locks.get(key)?)))
+            .filter(|(_, lock)| lock.expires_at > now)
+            .map(|(key, lock)| (key.clone(), lock.held(now)))
+            .collect()
+    }
+
+    /// Locks `key` with `lock`, which was granted already, recording the
+    /// lock it replaces as removed for `reason` unless it expired.
+    fn insert(&mut self, key: String, lock: JobLock, reason: RemovalReason, now: Instant) {
+        self.record_attempt(&key, lock.holder.as_deref(), true, timestamp::now());
+        if let Some(old) = self.locks.insert(key.clone(), lock) {
+            let reason = match old.expires_at <= now {
+                true => RemovalReason::Expired,
+                false => reason,
+            };
+            self.record(key, old, reason, None);
+        }
+    }
+
+    /// Puts back `lock` of `key`, granted before, recording the lock it
+    /// replaces unless it's the same one.
+    fn put_back(&mut self, key: String, lock: JobLock) {
+        let fencing_token = lock.fencing_token;
+        match self.locks.insert(key.clone(), lock) {
+            Some(old) if old.fencing_token != fencing_token => {
+                self.record(key, old, RemovalReason::TakenOver, None);
+            }
+            _ => {}
+        }
+    }
+
+    /// Locks `keys` under a new fencing token, whether they're held or
+    /// not, returning the token.
+    fn grant_new(&mut self, keys: Vec<String>, mut lock: JobLock, now: Instant) -> u64 {
+        lock.fencing_token = self.next_fencing_token();
+        self.journal(|| JournalOp::Acquire {
+            keys: keys.clone(),
+            holder: lock.holder.clone(),
+            fencing_token: lock.fencing_token,
+            expires_t: timestamp::from_instant(lock.expires_at),
+        });
+        for key in keys {
+            self.insert(key, lock.clone(), RemovalReason::TakenOver, now);
+        }
+        lock.fencing_token
+    }
+
+    /// Locks `key` if `condition` holds, returning the fencing token of
+    /// the grant. Otherwise returns the current lock, `None` if unheld.
+    pub fn try_acquire_if(
+        &mut self,
+        key: String,
+        mut lock: JobLock,
+        condition: LockCondition,
+        now: Instant,
+    ) -> Result<u64, Option<LockJobHeld>> {
+        let current = (self.locks.get(&key))
+            .filter(|current| current.expires_at > now)
+            .map(|current| (current.fencing_token, current.held(now)));
+        let kept_token = match (condition, &current) {
+            (LockCondition::Unheld | LockCondition::Bump(_), None) => None,
+            (LockCondition::TakeOver(t), Some((token, _))) if *token == t => None,
+            (LockCondition::Bump(t), Some((token, _))) if *token == t => Some(t),
+            _ => {
+                self.add_conflict([&key], lock.holder.as_deref());
+                return Err(current.map(|(_, held)| held));
+            }
+        };
+        let Some(fencing_token) = kept_token else {
+            return Ok(self.grant_new(vec![key], lock, now));
+        };
+        lock.fencing_token = fencing_token;
+        self.extend(key, lock, now);
+        Ok(fencing_token)
+    }
+
+    /// Extends the lock of `key` held under `lock`'s fencing token to
+    /// `lock`, locking it with `lock` if it isn't held under that token.
+    pub fn extend(&mut self, key: String, lock: JobLock, now: Instant) {
+        self.journal(|| JournalOp::Acquire {
+            keys: vec![key.clone()],
+            holder: lock.holder.clone(),
+            fencing_token: lock.fencing_token,
+            expires_t: timestamp::from_instant(lock.expires_at),
+        });
+        match self.locks.get_mut(&key) {
+            Some(current) if current.fencing_token == lock.fencing_token => {
+                current.expires_at = lock.expires_at;
+                current.holder = lock.holder.or(current.holder.take());
+            }
+            _ => self.insert(key, lock, RemovalReason::TakenOver, now),
+        }
+    }
+
+    /// Counts a failed acquisition attempt of `requester`, refused as
+    /// `keys` were held, e.g. by a shared lock store.
+    pub fn add_conflict<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a String>,
+        requester: Option<&str>,
+    ) {
+        self.count_conflict(requester);
+        self.record_refused(keys, requester);
+    }
+
+    fn count_conflict(&mut self, requester: Option<&str>) {
+        if let Some(requester) = requester {
+            *self.conflicts.entry(requester.to_owned()).or_default() += 1;
+        }
+    }
+
+    fn record_refused<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a String>,
+        requester: Option<&str>,
+    ) {
+        let t = timestamp::now();
+        for key in keys {
+            self.record_attempt(key, requester, false, t);
+        }
+    }
+
+    /// Records an attempt of `requester` to lock `key`.
+    fn record_attempt(&mut self, key: &str, requester: Option<&str>, granted: bool, t: u64) {
+        self.hot_keys.record(key, requester, granted, t);
+        let kind = match granted {
+            true => LockEventKind::Acquired,
+            false => LockEventKind::Conflict,
+        };
+        self.events.record(t, key, kind);
+    }
+
+    /// Whether `fencing_token` belongs to the current, unexpired lock of
+    /// `key`. A worker whose lock expired and was granted to someone else
+    /// will fail this check.
+    pub fn validate(&self, key: &str, fencing_token: u64, now: Instant) -> bool {
+        self.locks
+            .get(key)
+            .is_some_and(|lock| lock.expires_at > now && lock.fencing_token == fencing_token)
+    }
+
+    /// Releases the lock of `key`, optionally noting the lifecycle which
+    /// completed the job.
+    pub fn release(&mut self, key: &str, fulfilled_by: Option<LockFulfillment>) -> bool {
+        self.remove(key, fulfilled_by, false)
+    }
+
+    /// Releases the lock of `key` on an operator's behalf, whoever holds
+    /// it.
+    pub fn purge(&mut self, key: &str) -> bool {
+        self.remove(key, None, true)
+    }
+
+    fn remove(&mut self, key: &str, fulfilled_by: Option<LockFulfillment>, purged: bool) -> bool {
+        let Some((key, lock)) = self.locks.remove_entry(key) else {
+            return false;
+        };
+        self.journal(|| JournalOp::Release {
+            key: key.clone(),
+            fulfilled_by: fulfilled_by.clone(),
+            purged,
+        });
+        self.wake(&key);
+        let reason = match (&fulfilled_by, purged) {
+            (_, true) => RemovalReason::Purged,
+            (Some(_), false) => RemovalReason::Completed,
+            (None, false) => RemovalReason::Released,
+        };
+        self.record(key, lock, reason, fulfilled_by);
+        true
+    }
+
+    /// Releases the lock of `key` if it's still held under `fencing_token`,
+    /// so a lifecycle whose lock expired and was granted to someone else
+    /// can't release the new holder's lock.
+    pub fn release_if(
+        &mut self,
+        key: &str,
+        fencing_token: u64,
+        fulfilled_by: Option<LockFulfillment>,
+        now: Instant,
+    ) -> bool {
+        self.validate(key, fencing_token, now) && self.release(key, fulfilled_by)
+    }
+
+    /// Lease of the current lock of `key`.
+    pub fn lease(&self, key: &str, now: Instant) -> Option<Lease> {
+        let lock = self.locks.get(key).filter(|lock| lock.expires_at > now)?;
+        Some(Lease {
+            fencing_token: lock.fencing_token,
+            holder: lock.holder.clone(),
+            expires_t: timestamp::from_instant(lock.expires_at),
+            key: None,
+        })
+    }
+
+    /// Leases of the locks held at `now`, by key.
+    pub fn leases(&self, now: Instant) -> BTreeMap<&str, Lease> {
+        self.locks
+            .keys()
+            .filter_map(|key| Some((key.as_str(), self.lease(key, now)?)))
+            .collect()
+    }
+
+    /// Locks held at `now` and the fencing token counter.
+    pub fn snapshot(&self, now: Instant) -> LockTableSnapshot {
+        let locks = self
+            .locks
+            .iter()
+            .filter(|(_, lock)| lock.expires_at > now)
+            .map(|(key, lock)| {
+                let lock = LockSnapshot {
+                    holder: lock.holder.clone(),
+                    fencing_token: lock.fencing_token,
+                    remaining_ttl_ms: lock.expires_at.duration_since(now).as_millis() as u64,
+                };
+                (key.clone(), lock)
+            })
+            .collect();
+        LockTableSnapshot {
+            last_fencing_token: self.last_fencing_token.load(Ordering::Relaxed),
+            locks,
+        }
+    }
+
+    /// Past locks, most recent first, followed by pinned locks which would
+    /// have been evicted otherwise.
+    pub fn history(&self) -> impl Iterator<Item = &LockRecord> {
+        self.history.iter().chain(self.pinned_history.iter().rev())
+    }
+
+    /// Exempts matching records from history eviction.
+    pub fn pin(&mut self, req: PinRequest) -> Result<Pin, String> {
+        self.pins.add(req).cloned()
+    }
+
+    /// Removes the pin, records only it was keeping are dropped.
+    pub fn unpin(&mut self, id: u64) -> Option<Pin> {
+        let pin = self.pins.remove(id)?;
+        let pins = &self.pins;
+        self.pinned_history.retain(|record| record.is_pinned(pins));
+        Some(pin)
+    }
+
+    pub fn pins(&self) -> &Pins {
+        &self.pins
+    }
+
+    /// Drops expired locks.
+    pub fn sweep(&mut self, now: Instant) {
+        let expired = self
+            .locks
+            .iter()
+            .filter(|(_, lock)| lock.expires_at <= now)
+            .map(|(key, _)| key.clone())
+            .collect::<Vec<_>>();
+        for key in expired {
+            if let Some(lock) = self.locks.remove(&key) {
+                self.record(key, lock, RemovalReason::Expired, None);
+            }
+        }
+        self.hot_keys.prune(timestamp::now());
+        self.events.prune(timestamp::now());
+        let locks = &self.locks;
+        self.waiters.retain(|key, waiters| {
+            let held = locks.contains_key(key);
+            if !held {
+                waiters.notify.notify_waiters();
+            }
+            waiters.prune() || held
+        });
+    }
+
+    /// Drops all locks, e.g. before taking over another instance's. The
+    /// fencing token counter and history stay.
+    pub fn clear(&mut self) {
+        self.locks.clear();
+        self.waiters.retain(|_, waiters| {
+            waiters.notify.notify_waiters();
+            waiters.prune()
+        });
+    }
+
+    /// Wakes the acquirers waiting for `key`, forgetting its queue once
+    /// they're all gone.
+    fn wake(&mut self, key: &str) {
+        if let Some(waiters) = self.waiters.get_mut(key) {
+            waiters.notify.notify_waiters();
+            if !waiters.prune() {
+                self.waiters.remove(key);
+            }
+        }
+    }
+
+    /// Number of currently held locks, including expired ones which
+    /// haven't been swept yet.
+    pub fn len(&self) -> usize {
+        self.locks.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.locks.is_empty()
+    }
+
+    /// Number of locks held at `now`, leaving out expired ones which
+    /// haven't been swept yet.
+    pub fn count_unexpired(&self, now: Instant) -> usize {
+        (self.locks.values())
+            .filter(|lock| lock.expires_at > now)
+            .count()
+    }
+
+    /// Number of locks held at `now` in the lock namespace.
+    pub fn count_held(&self, namespace: &str, now: Instant) -> usize {
+        (self.locks.iter())
+            .filter(|(key, lock)| {
+                lock.expires_at > now && lock_namespaces::strip(namespace, key).is_some()
+            })
+            .count()
+    }
+
+    /// Number of locks held at `now` by `holder`.
+    pub fn count_held_by(&self, holder: &str, now: Instant) -> usize {
+        (self.locks.values())
+            .filter(|lock| lock.expires_at > now && lock.holder.as_deref() == Some(holder))
+            .count()
+    }
+
+    /// Failed acquisition attempts per requesting worker.
+    pub fn conflicts(&self) -> &HashMap<String, u64> {
+        &self.conflicts
+    }
+
+    /// Keys hot at `now`.
+    pub fn hot_keys(&mut self, now: u64) -> HotKeysReport {
+        self.hot_keys.report(now)
+    }
+
+    /// Keys which became hot since the last check.
+    pub fn check_hot_keys(&mut self, now: u64) -> Vec<HotKey> {
+        self.hot_keys.check(now)
+    }
+
+    /// Recent acquisitions, conflicts and removals, oldest first.
+    pub fn lock_events(&self) -> impl Iterator<Item = &LockEvent> {
+        self.events.iter()
+    }
+
+    /// Queues a long-polling acquirer of `key`, which gets it once the
+    /// acquirers queued before it got it or left.
+    pub fn enqueue(&mut self, key: &str) -> Arc<WaitTicket> {
+        let waiters = self.waiters.entry(key.to_owned()).or_default();
+        let ticket = Arc::new(WaitTicket {
+            notify: waiters.notify.clone(),
+        });
+        waiters.queue.push_back(Arc::downgrade(&ticket));
+        ticket
+    }
+
+    /// Number of acquirers queued for `key` ahead of `ticket`, or of a
+    /// requester which isn't queued.
+    pub fn queued_ahead(&mut self, key: &str, ticket: Option<&Arc<WaitTicket>>) -> usize {
+        let Some(waiters) = self.waiters.get_mut(key) else {
+            return 0;
+        };
+        waiters.ahead(ticket)
+    }
+}
+
+/// [`LockTable`] split into shards by key, each behind its own mutex, so
+/// requests for different jobs don't queue up behind each other or behind
+/// reads of the whole table. Fencing tokens still come from one counter
+/// shared by all shards, and pins apply to all of them.
+#[derive(Debug)]
+pub struct LockShards {
+    shards: Box<[Mutex<LockTable>]>,
+}
+
+/// Index of the shard of `key` among `n`.
+fn shard_index(key: &str, n: usize) -> usize {
+    let mut hasher = DefaultHasher::new();
+    key.hash(&mut hasher);
+    (hasher.finish() % n as u64) as usize
+}
+
+impl LockShards {
+    /// Splits `table` into `n` shards, each remembering its share of the
+    /// table's `max_history` past locks.
+    pub fn new(table: LockTable, n: usize) -> Self {
+        let n = n.max(1);
+        let mut shards = (0..n)
+            .map(|_| LockTable {
+                max_history: table.max_history.div_ceil(n),
+                last_fencing_token: table.last_fencing_token.clone(),
+                hot_keys: HotKeys::new(table.hot_keys.config()),
+                pins: table.pins.clone(),
+                journal: table.journal.clone(),
+                ..LockTable::default()
+            })
+            .collect::<Vec<_>>();
+        for (key, lock) in table.locks {
+            shards[shard_index(&key, n)].locks.insert(key, lock);
+        }
+        for record in table.history {
+            shards[shard_index(&record.key, n)]
+                .history
+                .push_back(record);
+        }
+        for record in table.pinned_history {
+            let shard = &mut shards[shard_index(&record.key, n)];
+            shard.pinned_history.push(record);
+        }
+        shards[0].conflicts = table.conflicts;
+        Self {
+            shards: shards.into_iter().map(Mutex::new).collect(),
+        }
+    }
+
+    /// Locks the shards of `keys`, or the first shard without keys.
+    pub async fn lock_keys<'a>(
+        &self,
+        keys: impl IntoIterator<Item = &'a String>,
+    ) -> LockedShards<'_> {
+        let n = self.shards.len();
+        let mut indices = keys
+            .into_iter()
+            .map(|key| shard_index(key, n))
+            .collect::<Vec<_>>();
+        if indices.is_empty() {
+            indices.push(0);
+        }
+        self.lock_shards(indices).await
+    }
+
+    /// Locks the shard of `key`.
+    pub async fn lock_key(&self, key: &str) -> LockedShards<'_> {
+        let index = shard_index(key, self.shards.len());
+        self.lock_shards(vec![index]).await
+    }
+
+    /// Locks all shards, for reading or changing the whole table at once.
+    pub async fn lock_all(&self) -> LockedShards<'_> {
+        self.lock_shards((0..self.shards.len()).collect()).await
+    }
+
+    async fn lock_shards(&self, mut indices: Vec<usize>) -> LockedShards<'_> {
+        // always in the same order, so lockers of several can't deadlock.
+        indices.sort_unstable();
+        indices.dedup();
+        let mut guards = Vec::with_capacity(indices.len());
+        for index in indices {
+            guards.push((index, self.shards[index].lock().await));
+        }
+        LockedShards {
+            n: self.shards.len(),
+            guards,
+        }
+    }
+
+    /// Drops expired locks, one shard at a time.
+    pub async fn sweep(&self, now: Instant) {
+        for shard in self.shards.iter() {
+            shard.lock().await.sweep(now);
+        }
+    }
+
+    /// Number of locks held at `now`, counted one shard at a time.
+    pub async fn count_locks(&self, now: Instant) -> usize {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.lock().await.count_unexpired(now);
+        }
+        len
+    }
+
+    /// Number of locks held at `now` in the lock namespace, counted one
+    /// shard at a time.
+    pub async fn count_held(&self, namespace: &str, now: Instant) -> usize {
+        let mut len = 0;
+        for shard in self.shards.iter() {
+            len += shard.lock().await.count_held(namespace, now);
+        }
+        len
+    }
+
+    /// Keys hot at `now`, collected one shard at a time.
+    pub async fn hot_keys(&self, now: u64) -> HotKeysReport {
+        let mut report: Option<HotKeysReport> = None;
+        for shard in self.shards.iter() {
+            let shard_report = shard.lock().await.hot_keys(now);
+            report = Some(match report {
+                Some(report) => report.merge(shard_report),
+                None => shard_report,
+            });
+        }
+        report.expect("at least one shard")
+    }
+
+    /// Keys which became hot since the last check, one shard at a time.
+    pub async fn check_hot_keys(&self, now: u64) -> Vec<HotKey> {
+        let mut hot = vec![];
+        for shard in self.shards.iter() {
+            hot.extend(shard.lock().await.check_hot_keys(now));
+        }
+        hot
+    }
+}
+
+/// Shards of a [`LockShards`] locked together. Operations on keys need
+/// the key's shard locked, operations on the whole table cover the
+/// locked shards.
+pub struct LockedShards<'a> {
+    n: usize,
+    /// By shard index, ascending.
+    guards: Vec<(usize, MutexGuard<'a, LockTable>)>,
+}
+
+impl<'g> LockedShards<'g> {
+    fn shard(&mut self, key: &str) -> &mut LockTable {
+        let index = shard_index(key, self.n);
+        let (_, shard) = self
+            .guards
+            .iter_mut()
+            .find(|(i, _)| *i == index)
+            .expect("shard of key is locked");
+        shard
+    }
+
+    fn shard_ref(&self, key: &str) -> &LockTable {
+        let index = shard_index(key, self.n);
+        let (_, shard) = self
+            .guards
+            .iter()
+            .find(|(i, _)| *i == index)
+            .expect("shard of key is locked");
+        shard
+    }
+
+    fn shards(&self) -> impl Iterator<Item = &LockTable> {
+        self.guards.iter().map(|(_, shard)| &**shard)
+    }
+
+    fn shards_mut(&mut self) -> impl Iterator<Item = &mut LockTable> + use<'_, 'g> {
+        self.guards.iter_mut().map(|(_, shard)| &mut **shard)
+    }
+
+    fn first(&mut self) -> &mut LockTable {
+        &mut self.guards[0].1
+    }
+
+    /// Locks all `keys` or, if any of them is held, none of them.
+    pub fn try_acquire_all(
+        &mut self,
+        keys: Vec<String>,
+        mut lock: JobLock,
+        now: Instant,
+    ) -> Result<u64, LockJobsConflict> {
+        let conflicts = self.held(&keys, now);
+        if !conflicts.is_empty() {
+            self.add_conflict(conflicts.keys(), lock.holder.as_deref());
+            return Err(LockJobsConflict { conflicts });
+        }
+        lock.fencing_token = self.first().next_fencing_token();
+        self.first().journal(|| JournalOp::Acquire {
+            keys: keys.clone(),
+            holder: lock.holder.clone(),
+            fencing_token: lock.fencing_token,
+            expires_t: timestamp::from_instant(lock.expires_at),
+        });
+        for key in keys {
+            let shard = self.shard(&key);
+            shard.insert(key, lock.clone(), RemovalReason::TakenOver, now);
+        }
+        Ok(lock.fencing_token)
+    }
+
+    /// Current locks of those of `keys` which are held.
+    pub fn held(&self, keys: &[String], now: Instant) -> BTreeMap<String, LockJobHeld> {
+        keys.iter()
+            .flat_map(|key| self.shard_ref(key).held([key], now))
+            .collect()
+    }
+
+    /// See [`LockTable::try_acquire_if`].
+    pub fn try_acquire_if(
+        &mut self,
+        key: String,
+        lock: JobLock,
+        condition: LockCondition,
+        now: Instant,
+    ) -> Result<u64, Option<LockJobHeld>> {
+        self.shard(&key).try_acquire_if(key, lock, condition, now)
+    }
+
+    /// See [`LockTable::extend`].
+    pub fn extend(&mut self, key: String, lock: JobLock, now: Instant) {
+        self.shard(&key).extend(key, lock, now);
+    }
+
+    /// Takes over `keys` granted by a lock store shared with other
+    /// instances, under the fencing token `lock` was given there. Locks of
+    /// the keys still held here were released through another instance.
+    pub fn grant(&mut self, keys: Vec<String>, lock: JobLock, now: Instant) {
+        self.first().advance_fencing_token(lock.fencing_token);
+        self.first().journal(|| JournalOp::Acquire {
+            keys: keys.clone(),
+            holder: lock.holder.clone(),
+            fencing_token: lock.fencing_token,
+            expires_t: timestamp::from_instant(lock.expires_at),
+        });
+        for key in keys {
+            let shard = self.shard(&key);
+            shard.insert(key, lock.clone(), RemovalReason::Released, now);
+        }
+    }
+
+    /// See [`LockTable::add_conflict`].
+    pub fn add_conflict<'a>(
+        &mut self,
+        keys: impl IntoIterator<Item = &'a String>,
+        requester: Option<&str>,
+    ) {
+        self.first().count_conflict(requester);
+        for key in keys {
+            self.shard(key).record_refused([key], requester);
+        }
+    }
+
+    pub fn validate(&self, key: &str, fencing_token: u64, now: Instant) -> bool {
+        self.shard_ref(key).validate(key, fencing_token, now)
+    }
+
+    pub fn release(&mut self, key: &str, fulfilled_by: Option<LockFulfillment>) -> bool {
+        self.shard(key).release(key, fulfilled_by)
+    }
+
+    pub fn purge(&mut self, key: &str) -> bool {
+        self.shard(key).purge(key)
+    }
+
+    pub fn release_if(
+        &mut self,
+        key: &str,
+        fencing_token: u64,
+        fulfilled_by: Option<LockFulfillment>,
+        now: Instant,
+    ) -> bool {
+        self.shard(key)
+            .release_if(key, fencing_token, fulfilled_by, now)
+    }
+
+    pub fn lease(&self, key: &str, now: Instant) -> Option<Lease> {
+        self.shard_ref(key).lease(key, now)
+    }
+
+    /// Number of locks held at `now` in the lock namespace, in the locked
+    /// shards only.
+    pub fn count_held(&self, namespace: &str, now: Instant) -> usize {
+        (self.guards.iter())
+            .map(|(_, shard)| shard.count_held(namespace, now))
+            .sum()
+    }
+
+    /// Number of locks held at `now` by `holder`, in the locked shards
+    /// only.
+    pub fn count_held_by(&self, holder: &str, now: Instant) -> usize {
+        (self.guards.iter())
+            .map(|(_, shard)| shard.count_held_by(holder, now))
+            .sum()
+    }
+
+    pub fn enqueue(&mut self, key: &str) -> Arc<WaitTicket> {
+        self.shard(key).enqueue(key)
+    }
+
+    /// Keys of `keys` for which acquirers are queued ahead of `ticket`,
+    /// with their locks at `now` if any.
+    pub fn queued_ahead(
+        &mut self,
+        keys: &[String],
+        ticket: Option<&Arc<WaitTicket>>,
+        now: Instant,
+    ) -> BTreeMap<String, LockJobHeld> {
+        let mut queued = BTreeMap::new();
+        for key in keys {
+            let shard = self.shard(key);
+            let n = shard.queued_ahead(key, ticket);
+            if n == 0 {
+                continue;
+            }
+            let held = (shard.locks.get(key))
+                .filter(|lock| lock.expires_at > now)
+                .map(|lock| lock.held(now));
+            let held = held.unwrap_or(LockJobHeld {
+                holder: None,
+                remaining_ttl_ms: 0,
+                queued: 0,
+            });
+            queued.insert(key.clone(), LockJobHeld { queued: n, ..held });
+        }
+        queued
+    }
+
+    /// Takes over `keys` locked under `fencing_token` before, e.g. when
+    /// replaying a journal. `lock` is `None` if it expired since, which
+    /// still advances the fencing token counter.
+    pub fn replay_acquire(&mut self, keys: Vec<String>, fencing_token: u64, lock: Option<JobLock>) {
+        self.first().advance_fencing_token(fencing_token);
+        let Some(lock) = lock else {
+            return;
+        };
+        for key in keys {
+            self.shard(&key).put_back(key, lock.clone());
+        }
+    }
+
+    pub fn leases(&self, now: Instant) -> BTreeMap<&str, Lease> {
+        self.shards().flat_map(|shard| shard.leases(now)).collect()
+    }
+
+    pub fn snapshot(&self, now: Instant) -> LockTableSnapshot {
+        let mut snapshot = LockTableSnapshot::default();
+        for shard in self.shards() {
+            let shard = shard.snapshot(now);
+            snapshot.last_fencing_token = shard.last_fencing_token;
+            snapshot.locks.extend(shard.locks);
+        }
+        snapshot
+    }
+
+    /// Takes over the locks of `snapshot`, their remaining TTLs counting
+    /// from `now`. Fencing tokens continue from the snapshot's counter,
+    /// unless this table's is further.
+    pub fn restore(&mut self, snapshot: LockTableSnapshot, now: Instant) {
+        self.first().journal(|| JournalOp::RestoreLocks {
+            locks: snapshot.clone(),
+        });
+        let highest = snapshot.locks.values().map(|l| l.fencing_token).max();
+        let last_fencing_token = snapshot.last_fencing_token.max(highest.unwrap_or_default());
+        self.first().advance_fencing_token(last_fencing_token);
+        for (key, lock) in snapshot.locks {
+            let lock = JobLock {
+                expires_at: now + Duration::from_millis(lock.remaining_ttl_ms),
+                holder: lock.holder,
+                fencing_token: lock.fencing_token,
+                acquired_t: None,
+            };
+            self.shard(&key).put_back(key, lock);
+        }
+    }
+
+    /// Recent acquisitions, conflicts and removals of the locked shards,
+    /// by shard.
+    pub fn lock_events(&self) -> impl Iterator<Item = &LockEvent> {
+        self.shards().flat_map(|shard| shard.lock_events())
+    }
+
+    /// Past locks, most recently removed first.
+    pub fn history(&self) -> impl Iterator<Item = &LockRecord> {
+        let mut history = self
+            .shards()
+            .flat_map(|shard| shard.history())
+            .collect::<Vec<_>>();
+        history.sort_by_key(|r| std::cmp::Reverse(r.removed_t));
+        history.into_iter()
+    }
+
+    pub fn pins(&self) -> &Pins {
+        self.guards[0].1.pins()
+    }
+
+    /// See [`LockTable::pin`].
+    pub fn pin(&mut self, req: PinRequest) -> Result<Pin, String> {
+        let pin = self.first().pin(req)?;
+        for shard in self.shards_mut().skip(1) {
+            shard.pins.insert(pin.clone());
+        }
+        Ok(pin)
+    }
+
+    /// See [`LockTable::unpin`].
+    pub fn unpin(&mut self, id: u64) -> Option<Pin> {
+        let mut pin = None;
+        for shard in self.shards_mut() {
+            pin = shard.unpin(id).or(pin);
+        }
+        pin
+    }
+
+    pub fn clear(&mut self) {
+        self.shards_mut().for_each(LockTable::clear);
+    }
+
+    pub fn len(&self) -> usize {
+        self.shards().map(LockTable::len).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.shards().all(LockTable::is_empty)
+    }
+
+    /// Failed acquisition attempts per requesting worker.
+    pub fn conflicts(&self) -> HashMap<String, u64> {
+        let mut conflicts = HashMap::<String, u64>::new();
+        for shard in self.shards() {
+            for (requester, count) in shard.conflicts() {
+                *conflicts.entry(requester.clone()).or_default() += count;
+            }
+        }
+        conflicts
+    }
+
+    pub(crate) fn take_journal(&mut self) -> Option<Journal> {
+        self.shards_mut()
+            .fold(None, |_, shard| shard.take_journal())
+    }
+
+    /// Journals acquisitions and releases of locks into `journal`.
+    pub fn set_journal(&mut self, journal: Journal) {
+        for shard in self.shards_mut() {
+            shard.set_journal(journal.clone());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    fn lock(holder: &str, expires_at: Instant) -> JobLock {
+        JobLock::new(expires_at, Some(holder.to_owned()))
+    }
+
+    #[tokio::test]
+    async fn conditional_locks_check_the_fencing_token() {
+        let shards = LockShards::new(LockTable::new(16), 4);
+        let mut kv = shards.lock_all().await;
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(10);
+        let acquire = |kv: &mut LockedShards, holder, condition| {
+            kv.try_acquire_if("k".into(), lock(holder, expires_at), condition, now)
+        };
+
+        let token = acquire(&mut kv, "a", LockCondition::Unheld).unwrap();
+        let held = acquire(&mut kv, "b", LockCondition::Unheld).unwrap_err();
+        assert_eq!(held.unwrap().holder.as_deref(), Some("a"));
+
+        // renewing keeps the token, only under the current one.
+        assert!(acquire(&mut kv, "a", LockCondition::Bump(token + 1)).is_err());
+        assert_eq!(
+            acquire(&mut kv, "a", LockCondition::Bump(token)).unwrap(),
+            token
+        );
+
+        // taking over needs the current token and issues a new one.
+        assert!(acquire(&mut kv, "b", LockCondition::TakeOver(token + 1)).is_err());
+        let taken = acquire(&mut kv, "b", LockCondition::TakeOver(token)).unwrap();
+        assert!(taken > token);
+        assert!(!kv.validate("k", token, now));
+        assert!(acquire(&mut kv, "a", LockCondition::Bump(token)).is_err());
+        assert_eq!(kv.lease("k", now).unwrap().holder.as_deref(), Some("b"));
+    }
+
+    #[tokio::test]
+    async fn stale_completion_keeps_regranted_lock() {
+        let shards = LockShards::new(LockTable::new(16), 4);
+        let mut kv = shards.lock_all().await;
+        let t0 = Instant::now();
+        let ttl = Duration::from_millis(10);
+        let old = kv
+            .try_acquire_all(vec!["k".into()], lock("a", t0 + ttl), t0)
+            .unwrap();
+        // expired, then granted to another worker.
+        let t1 = t0 + 2 * ttl;
+        let new = kv
+            .try_acquire_all(vec!["k".into()], lock("b", t1 + ttl), t1)
+            .unwrap();
+        assert!(new > old);
+        assert!(!kv.validate("k", old, t1));
+
+        assert!(!kv.release_if("k", old, None, t1));
+        let lease = kv.lease("k", t1).unwrap();
+        assert_eq!(
+            (lease.fencing_token, lease.holder.as_deref()),
+            (new, Some("b"))
+        );
+
+        assert!(kv.release_if("k", new, None, t1));
+        assert!(kv.lease("k", t1).is_none());
+    }
+
+    #[tokio::test]
+    async fn history_records_why_locks_were_removed() {
+        let shards = LockShards::new(LockTable::new(16), 4);
+        let mut kv = shards.lock_all().await;
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(10);
+        let reasons = |kv: &LockedShards| {
+            let history = kv.history().map(|r| (r.holder.clone(), r.removal_reason));
+            history.collect::<Vec<_>>()
+        };
+
+        let token = kv
+            .try_acquire_all(vec!["k".into()], lock("a", expires_at), now)
+            .unwrap();
+        let snapshot = kv.snapshot(now);
+        let condition = LockCondition::TakeOver(token);
+        kv.try_acquire_if("k".into(), lock("b", expires_at), condition, now)
+            .unwrap();
+        assert!(kv.purge("k"));
+        // restoring over a lock under another token replaces it.
+        kv.try_acquire_all(vec!["k".into()], lock("c", expires_at), now)
+            .unwrap();
+        kv.restore(snapshot.clone(), now);
+        kv.restore(snapshot, now);
+
+        let holder = |h: &str| Some(h.to_owned());
+        assert_eq!(
+            reasons(&kv),
+            [
+                (holder("c"), RemovalReason::TakenOver),
+                (holder("b"), RemovalReason::Purged),
+                (holder("a"), RemovalReason::TakenOver),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn namespaces_are_counted_across_shards() {
+        let shards = LockShards::new(LockTable::new(16), 4);
+        let now = Instant::now();
+        let expires_at = now + Duration::from_secs(10);
+        let keys = ["devnet/j1", "devnet/j2", "devnet/j3", "j1", "mainnet/j1"];
+        for key in keys {
+            let mut kv = shards.lock_key(key).await;
+            let key = vec![key.to_owned()];
+            kv.try_acquire_all(key, lock("a", expires_at), now).unwrap();
+        }
+
+        let kv = shards.lock_all().await;
+        assert_eq!(kv.count_held("devnet", now), 3);
+        assert_eq!(kv.count_held(lock_namespaces::DEFAULT, now), 1);
+        assert_eq!(kv.count_held("mainnet", now), 1);
+        assert_eq!(kv.count_held("devnet", expires_at), 0);
+    }
+}
@@ -0,0 +1,142 @@
+//! Lock namespaces, so fleets of different networks can share a
+//! coordinator without their job keys colliding. Requests pick one with
+//! the `X-Lock-Namespace` header, its keys are kept prefixed with it as
+//! `{namespace}/{key}`. Keys of requests without one are kept as they are,
+//! unless they contain a `/`, which would make them look namespaced, so
+//! they're kept as `/{key}` then, a prefix namespaces can't have.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// Header naming the namespace of a request's lock keys.
+pub const HEADER: &str = "x-lock-namespace";
+/// Namespace of keys of requests without the header.
+pub const DEFAULT: &str = "default";
+pub const MAX_NAME_LEN: usize = 64;
+
+/// Limits of namespaces, loaded from `--lock-namespaces-file`.
+///
+/// ```json
+/// { "devnet": { "default_timeout_ms": 60000, "max_timeout_ms": 600000, "max_keys": 1000 } }
+/// ```
+///
+/// Namespaces which aren't listed get the global limits.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct LockNamespaces {
+    namespaces: HashMap<String, NamespaceLimits>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+pub struct NamespaceLimits {
+    /// TTL of locks requested without a timeout, instead of the global
+    /// default.
+    #[serde(default)]
+    pub default_timeout_ms: Option<u64>,
+    /// Upper bound of lock TTLs, instead of `--max-timeout-ms`.
+    #[serde(default)]
+    pub max_timeout_ms: Option<u64>,
+    /// Max number of locks held in the namespace at once.
+    #[serde(default)]
+    pub max_keys: Option<usize>,
+}
+
+impl LockNamespaces {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let namespaces: Self = serde_json::from_str(&s).map_err(|err| format!("{path}: {err}"))?;
+        for name in namespaces.namespaces.keys() {
+            parse(Some(name)).map_err(|err| format!("{path}: {err}"))?;
+        }
+        Ok(namespaces)
+    }
+
+    /// Limits of the namespace, `None` being the default one.
+    pub fn limits(&self, namespace: Option<&str>) -> NamespaceLimits {
+        let name = namespace.unwrap_or(DEFAULT);
+        self.namespaces.get(name).cloned().unwrap_or_default()
+    }
+
+    /// Limits of `namespace`, added if it isn't listed.
+    pub fn limits_mut(&mut self, namespace: &str) -> &mut NamespaceLimits {
+        self.namespaces.entry(namespace.to_owned()).or_default()
+    }
+}
+
+/// Namespace named by a request's header, `None` for the default one.
+pub fn parse(header: Option<&str>) -> Result<Option<&str>, String> {
+    let Some(name) = header.filter(|name| *name != DEFAULT) else {
+        return Ok(None);
+    };
+    let valid = |c: char| c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.');
+    if name.is_empty() || name.len() > MAX_NAME_LEN || !name.chars().all(valid) {
+        return Err(format!(
+            "invalid lock namespace {name:?}, expected up to {MAX_NAME_LEN} letters, digits, `-`, `_` or `.`"
+        ));
+    }
+    Ok(Some(name))
+}
+
+/// Key `key` is kept under in `namespace`.
+pub fn qualify(namespace: Option<&str>, key: &str) -> String {
+    match namespace {
+        Some(namespace) => format!("{namespace}/{key}"),
+        None if key.contains('/') => format!("/{key}"),
+        None => key.to_owned(),
+    }
+}
+
+/// Prefix of the keys kept in `namespace`, `None` for the default one,
+/// whose keys only have one if they contain a `/`.
+pub fn prefix(namespace: &str) -> Option<String> {
+    (namespace != DEFAULT).then(|| format!("{namespace}/"))
+}
+
+/// Key as requested in the namespace, if `key` is kept in it.
+pub fn strip<'a>(namespace: &str, key: &'a str) -> Option<&'a str> {
+    match prefix(namespace) {
+        Some(prefix) => key.strip_prefix(&prefix),
+        None => match key.strip_prefix('/') {
+            Some(key) => Some(key),
+            None => (!key.contains('/')).then_some(key),
+        },
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn qualified_keys_stay_in_their_namespace() {
+        let key = qualify(Some("devnet"), "j1");
+        assert_eq!(key, "devnet/j1");
+        assert_eq!(strip("devnet", &key), Some("j1"));
+        assert_eq!(strip("mainnet", &key), None);
+        assert_eq!(strip(DEFAULT, &key), None);
+        assert_eq!(strip(DEFAULT, &qualify(None, "j1")), Some("j1"));
+    }
+
+    #[test]
+    fn keys_with_slashes_stay_in_their_namespace() {
+        // would be taken for devnet's `j1` if kept as it is.
+        let key = qualify(None, "devnet/j1");
+        assert_ne!(key, qualify(Some("devnet"), "j1"));
+        assert_eq!(strip(DEFAULT, &key), Some("devnet/j1"));
+        assert_eq!(strip("devnet", &key), None);
+
+        let key = qualify(Some("devnet"), "a/b");
+        assert_eq!(strip("devnet", &key), Some("a/b"));
+        assert_eq!(strip(DEFAULT, &key), None);
+    }
+
+    #[test]
+    fn default_namespace_is_unnamed() {
+        assert_eq!(parse(None), Ok(None));
+        assert_eq!(parse(Some(DEFAULT)), Ok(None));
+        assert_eq!(parse(Some("devnet")), Ok(Some("devnet")));
+        assert!(parse(Some("dev/net")).is_err());
+        assert!(parse(Some("")).is_err());
+    }
+}
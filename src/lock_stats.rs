@@ -0,0 +1,177 @@
+//! Lock contention statistics of `GET /lock-stats`, for tuning lock
+//! timeouts: how often keys are acquired and refused, how long locks are
+//! held, and how many of them expire instead of being released.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::durations;
+
+/// How far back events are kept.
+pub const MAX_WINDOW_MS: u64 = 24 * 3600 * 1000;
+/// Events kept per lock table shard, the oldest dropped first.
+const MAX_EVENTS: usize = 100_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum LockEventKind {
+    Acquired,
+    /// Refused as the key was held.
+    Conflict,
+    /// Stopped being held, after `held_ms` if known.
+    Removed {
+        held_ms: Option<u64>,
+        expired: bool,
+    },
+}
+
+#[derive(Debug, Clone)]
+pub struct LockEvent {
+    pub t: u64,
+    pub key: String,
+    pub kind: LockEventKind,
+}
+
+/// Recent lock events of a lock table.
+#[derive(Debug, Default)]
+pub struct LockEvents {
+    events: VecDeque<LockEvent>,
+}
+
+impl LockEvents {
+    pub fn record(&mut self, t: u64, key: &str, kind: LockEventKind) {
+        self.events.push_back(LockEvent {
+            t,
+            key: key.to_owned(),
+            kind,
+        });
+        if self.events.len() > MAX_EVENTS {
+            self.events.pop_front();
+        }
+    }
+
+    /// Drops events older than [`MAX_WINDOW_MS`].
+    pub fn prune(&mut self, now: u64) {
+        let from_t = now.saturating_sub(MAX_WINDOW_MS);
+        while self.events.front().is_some_and(|e| e.t < from_t) {
+            self.events.pop_front();
+        }
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &LockEvent> {
+        self.events.iter()
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LockStatsSummary {
+    pub acquisitions: u64,
+    /// Refused acquisition attempts, answered with a 200.
+    pub conflicts: u64,
+    /// Locks released, by a lock-job DELETE or their job's lifecycle
+    /// ending.
+    pub released: u64,
+    pub expired: u64,
+    /// Share of the removed locks which expired instead of being
+    /// released, `None` if none was removed.
+    pub expired_ratio: Option<f64>,
+    /// Mean time locks were held until released or expired.
+    pub avg_hold_ms: Option<u64>,
+    #[serde(skip)]
+    held_ms: u64,
+    #[serde(skip)]
+    held: u64,
+}
+
+impl LockStatsSummary {
+    fn add(&mut self, kind: LockEventKind) {
+        match kind {
+            LockEventKind::Acquired => self.acquisitions += 1,
+            LockEventKind::Conflict => self.conflicts += 1,
+            LockEventKind::Removed { held_ms, expired } => {
+                match expired {
+                    true => self.expired += 1,
+                    false => self.released += 1,
+                }
+                if let Some(held_ms) = held_ms {
+                    self.held_ms += held_ms;
+                    self.held += 1;
+                }
+            }
+        }
+    }
+
+    fn finish(mut self) -> Self {
+        let removed = self.released + self.expired;
+        self.expired_ratio = (removed > 0).then(|| self.expired as f64 / removed as f64);
+        self.avg_hold_ms = (self.held > 0).then(|| self.held_ms / self.held);
+        self
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ContendedKey {
+    pub key: String,
+    pub acquisitions: u64,
+    pub conflicts: u64,
+}
+
+/// Response body of `GET /lock-stats`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct LockStatsReport {
+    pub from_t: u64,
+    pub to_t: u64,
+    pub total: LockStatsSummary,
+    /// By job class of the key, see `--job-class-separator`.
+    pub prefixes: BTreeMap<String, LockStatsSummary>,
+    /// Keys refused most often within the window, most refused first.
+    pub most_contended: Vec<ContendedKey>,
+}
+
+impl LockStatsReport {
+    /// Stats of `events` since `from_t`, listing up to `limit` most
+    /// contended keys.
+    pub fn new<'a>(
+        events: impl IntoIterator<Item = &'a LockEvent>,
+        from_t: u64,
+        to_t: u64,
+        separator: &str,
+        limit: usize,
+    ) -> Self {
+        let mut total = LockStatsSummary::default();
+        let mut prefixes = BTreeMap::<String, LockStatsSummary>::new();
+        let mut keys = HashMap::<&str, ContendedKey>::new();
+        for event in events.into_iter().filter(|e| e.t >= from_t) {
+            total.add(event.kind);
+            let class = durations::job_class(&event.key, separator);
+            prefixes
+                .entry(class.to_owned())
+                .or_default()
+                .add(event.kind);
+            let key = keys.entry(&event.key).or_insert_with(|| ContendedKey {
+                key: event.key.clone(),
+                acquisitions: 0,
+                conflicts: 0,
+            });
+            match event.kind {
+                LockEventKind::Acquired => key.acquisitions += 1,
+                LockEventKind::Conflict => key.conflicts += 1,
+                LockEventKind::Removed { .. } => {}
+            }
+        }
+        let mut most_contended = (keys.into_values())
+            .filter(|key| key.conflicts > 0)
+            .collect::<Vec<_>>();
+        most_contended.sort_by(|a, b| b.conflicts.cmp(&a.conflicts).then(a.key.cmp(&b.key)));
+        most_contended.truncate(limit);
+        Self {
+            from_t,
+            to_t,
+            total: total.finish(),
+            prefixes: (prefixes.into_iter())
+                .map(|(class, summary)| (class, summary.finish()))
+                .collect(),
+            most_contended,
+        }
+    }
+}
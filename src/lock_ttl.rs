@@ -0,0 +1,155 @@
+//! Controller for `--dynamic-ttl`, which adjusts the TTL of locks
+//! requested without a `timeout` to the backlog of available jobs, as
+//! workers find it when getting jobs from their node. The deeper the
+//! backlog, the shorter the TTL, so stuck locks hold back less work; the
+//! shallower, the longer, so locks churn less.
+
+use std::{collections::VecDeque, str::FromStr};
+
+use serde::Serialize;
+
+use crate::stats::SnarkWorkerState;
+
+/// Job gets needed since the last adjustment before adjusting again.
+const MIN_JOB_GETS: u64 = 10;
+/// Share of the way to the target TTL the TTL moves per adjustment.
+const STEP: f64 = 0.5;
+/// Number of decisions remembered.
+const MAX_DECISIONS: usize = 100;
+
+/// Bounds of the TTL, given as `MIN..MAX` in seconds.
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TtlBounds {
+    pub min_ms: u64,
+    pub max_ms: u64,
+}
+
+impl FromStr for TtlBounds {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parse = |s: &str| {
+            s.trim()
+                .parse::<u64>()
+                .map(|secs| secs * 1000)
+                .map_err(|err| format!("invalid seconds {s:?}: {err}"))
+        };
+        let (min, max) = s
+            .split_once("..")
+            .ok_or_else(|| format!("expected `MIN..MAX` in seconds, found: {s}"))?;
+        let (min_ms, max_ms) = (parse(min)?, parse(max)?);
+        if min_ms > max_ms {
+            return Err(format!("min is above max: {s}"));
+        }
+        Ok(Self { min_ms, max_ms })
+    }
+}
+
+/// A TTL adjustment, and what it was based on.
+#[derive(Serialize, Debug, Clone)]
+pub struct TtlDecision {
+    pub decided_t: u64,
+    /// Job gets which found a job since the previous decision.
+    pub jobs_found: u64,
+    /// Job gets which found no job since the previous decision.
+    pub jobs_unavailable: u64,
+    /// Jobs completed since the previous decision.
+    pub jobs_completed: u64,
+    /// Share of job gets which found a job, how deep the backlog is.
+    pub backlog: f64,
+    pub target_ms: u64,
+    pub old_ms: u64,
+    pub new_ms: u64,
+    /// Whether shortening was held back, because fewer than half the jobs
+    /// found were completed and they may just take longer.
+    pub held: bool,
+}
+
+/// Response of `GET /lock-ttl`.
+#[derive(Serialize, Debug)]
+pub struct TtlReport {
+    pub bounds: TtlBounds,
+    pub ttl_ms: u64,
+    /// Most recent first.
+    pub decisions: Vec<TtlDecision>,
+}
+
+#[derive(Debug)]
+pub struct LockTtlController {
+    bounds: TtlBounds,
+    ttl_ms: u64,
+    jobs_found: u64,
+    jobs_unavailable: u64,
+    jobs_completed: u64,
+    decisions: VecDeque<TtlDecision>,
+}
+
+impl LockTtlController {
+    /// Starts at `ttl_ms`, within `bounds`.
+    pub fn new(bounds: TtlBounds, ttl_ms: u64) -> Self {
+        Self {
+            bounds,
+            ttl_ms: ttl_ms.clamp(bounds.min_ms, bounds.max_ms),
+            jobs_found: 0,
+            jobs_unavailable: 0,
+            jobs_completed: 0,
+            decisions: VecDeque::new(),
+        }
+    }
+
+    /// Current TTL of locks requested without a `timeout`.
+    pub fn ttl_ms(&self) -> u64 {
+        self.ttl_ms
+    }
+
+    /// Counts the job get or completion `state` was just moved into by.
+    pub fn observe(&mut self, state: &SnarkWorkerState) {
+        match state {
+            SnarkWorkerState::WorkCreatePending { .. } => self.jobs_found += 1,
+            SnarkWorkerState::JobUnavailable { .. } => self.jobs_unavailable += 1,
+            SnarkWorkerState::WorkSubmitSuccess { .. } => self.jobs_completed += 1,
+            _ => {}
+        }
+    }
+
+    /// Moves the TTL towards the one for the backlog observed since the
+    /// previous decision. `None` if too few job gets were observed.
+    pub fn adjust(&mut self, now: u64) -> Option<&TtlDecision> {
+        let job_gets = self.jobs_found + self.jobs_unavailable;
+        if job_gets < MIN_JOB_GETS {
+            return None;
+        }
+        let TtlBounds { min_ms, max_ms } = self.bounds;
+        let backlog = self.jobs_found as f64 / job_gets as f64;
+        let target_ms = max_ms - ((max_ms - min_ms) as f64 * backlog).round() as u64;
+        let held = target_ms < self.ttl_ms && self.jobs_completed * 2 < self.jobs_found;
+        let old_ms = self.ttl_ms;
+        if !held {
+            let step = (target_ms as f64 - old_ms as f64) * STEP;
+            self.ttl_ms = (old_ms as f64 + step).round() as u64;
+        }
+        if self.decisions.len() >= MAX_DECISIONS {
+            self.decisions.pop_back();
+        }
+        self.decisions.push_front(TtlDecision {
+            decided_t: now,
+            jobs_found: std::mem::take(&mut self.jobs_found),
+            jobs_unavailable: std::mem::take(&mut self.jobs_unavailable),
+            jobs_completed: std::mem::take(&mut self.jobs_completed),
+            backlog,
+            target_ms,
+            old_ms,
+            new_ms: self.ttl_ms,
+            held,
+        });
+        self.decisions.front()
+    }
+
+    pub fn report(&self) -> TtlReport {
+        TtlReport {
+            bounds: self.bounds,
+            ttl_ms: self.ttl_ms,
+            decisions: self.decisions.iter().cloned().collect(),
+        }
+    }
+}
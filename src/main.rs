@@ -1,545 +1,3999 @@
+// the route tree is deeper than the default limit allows.
+#![recursion_limit = "256"]
+
 use std::{
-    collections::{hash_map::Entry, HashMap, VecDeque},
+    collections::{BTreeMap, HashMap, HashSet, VecDeque},
+    convert::Infallible,
+    io,
+    net::SocketAddr,
+    path::PathBuf,
     sync::Arc,
     time::{Duration, Instant},
 };
 
-use serde::{Deserialize, Serialize, Serializer};
-use structopt::StructOpt;
-use tokio::sync::Mutex;
-use warp::{hyper::StatusCode, reply::with_status, Filter};
+use access_log::{AccessLog, AccessLogFormat};
+use admin::AdminCommand;
+use admin_port::AdminListener;
+use cors::CorsOrigins;
+use failover::Role;
+use futures_util::{FutureExt, Stream, StreamExt};
+use ingest_queue::{Backpressure, IngestQueue};
+use listener::{Connection, ListenAddr};
+use redis::{RedisError, RedisResult};
+use reload::{Reloader, SetLogFilter};
+use replay::ReplayOpts;
+use scheduler::Scheduler;
+use serde::{Deserialize, Serialize};
+use simulate::SimulateOpts;
+use snark_coordinator_rs::{
+    annotations::{Annotation, Annotations},
+    anomaly::{AnomalyConfig, FleetAnomalyDetector},
+    api_keys::{Access, ApiKey, ApiKeys},
+    archive::{ArchivedWorker, WorkerArchive},
+    assignment::AssignmentPolicy,
+    batch::{BatchItem, BatchResponse, BatchSummary},
+    clock_skew::{ClockSkews, ServerTime, WorkerClockSkew},
+    compat::CompatConfig,
+    cooldown::{CooldownConfig, Cooldowns},
+    durations::{self, DurationModel},
+    error_events::ErrorEvents,
+    errors::{ApiError, ErrorCode},
+    groups::{self, GroupsConfig, Scope},
+    handshake::HandshakePolicy,
+    hooks::{CoordinatorHooks, Hooks},
+    host_metrics::HostMetrics,
+    hot_keys::HotKeyConfig,
+    idempotency,
+    job_pool::{JobPool, NodeClient, SelectionPolicy},
+    jobs::JobIndex,
+    journal::{self, Journal, JournalEntry, JournalOp},
+    latency::{self, Phase},
+    leader_lease::LeaderLease,
+    liveness::{Liveness, Status, WorkerLiveness},
+    lock::{
+        JobLock, LockCondition, LockFulfillment, LockJobGranted, LockJobHeld, LockJobsConflict,
+        LockShards, LockTable, LockTableSnapshot, LockedShards, RemovalReason, WaitTicket,
+    },
+    lock_namespaces::{self, LockNamespaces, NamespaceLimits},
+    lock_ttl::{LockTtlController, TtlBounds},
+    maintenance::MaintenanceWindows,
+    metrics::Metrics,
+    migrations,
+    network::NetworkProbes,
+    otlp::{OtlpExporter, TraceContext, Tracer},
+    outliers::Threshold,
+    profile::{Limits, Profile},
+    push::{CompletedJob, JobBuffer, MetricsPusher, PushTarget},
+    quarantine::{self, Quarantine},
+    rate_limit::RateLimiter,
+    redis_locks::{LockBackend, RedisLocks},
+    stats::{
+        self, Order, PutError, SnarkWorkerState, SnarkWorkerStatsPut, Transition, WorkerMetadata,
+        WorkerStats, WorkerStatsSnapshot,
+    },
+    strict,
+    stuck::StuckDetector,
+    summary::{self, LifecycleTotals},
+    tenants::{self, Tenants},
+    timestamp,
+    top::TopK,
+    webhook::Webhook,
+    worker_tokens::WorkerTokens,
+};
+use structopt::{clap::AppSettings, StructOpt};
+use throttle::{ClientAddr, RateLimitBy};
+use tokio::{
+    signal::unix::{signal, SignalKind},
+    sync::{broadcast, watch, Mutex, Notify, RwLock},
+};
+use tracing::{debug, error, info, info_span, warn, Instrument};
+use tracing_subscriber::EnvFilter;
+use utoipa::IntoParams;
+use warp::{
+    hyper::{
+        body::Buf,
+        header::HeaderValue,
+        service::{make_service_fn, service_fn, Service},
+        Body, Method, Request, Server, StatusCode, Uri,
+    },
+    reply::{with_status, WithStatus},
+    Filter, Reply,
+};
+
+mod access_log;
+mod admin;
+mod admin_port;
+mod auth;
+mod body;
+mod cluster;
+mod compression;
+mod config;
+mod cors;
+mod dashboard;
+mod diff;
+mod dump;
+mod e2e;
+mod failover;
+mod formats;
+mod graphql;
+mod grpc;
+mod ingest_queue;
+mod listener;
+mod live;
+mod openapi;
+mod reload;
+mod replay;
+mod routes;
+mod rpc;
+mod scheduler;
+mod self_test;
+mod simulate;
+mod stream;
+mod synthetic;
+mod tenancy;
+mod throttle;
 
 #[derive(Debug, StructOpt)]
-#[structopt(name = "example", about = "An example of StructOpt usage.")]
+#[structopt(
+    name = "snark-coordinator-rs",
+    about = "Coordinates snark workers: hands out job locks and collects their stats.",
+    global_settings = &[AppSettings::AllArgsOverrideSelf],
+)]
 struct Opts {
+    /// TOML file setting options by name, e.g. `port = 8080`, with
+    /// `SNARK_COORDINATOR_*` environment variables overriding it and
+    /// flags overriding both, `--no-<flag>` unsetting flags. See
+    /// `config.rs`.
+    #[structopt(long, parse(from_os_str))]
+    config: Option<PathBuf>,
+
     #[structopt(short, long, default_value = "8080")]
     port: u16,
+    /// Address to listen on, e.g. `127.0.0.1:8080` or `[::]:8080`.
+    /// Defaults to `0.0.0.0:{port}`.
+    #[structopt(long, conflicts_with = "unix-socket")]
+    listen: Option<SocketAddr>,
+    /// Listen on a unix domain socket at this path instead of TCP.
+    #[structopt(long, parse(from_os_str), conflicts_with = "tls-cert")]
+    unix_socket: Option<PathBuf>,
+    /// Port to serve the admin, snapshot and metrics endpoints on, instead
+    /// of the main one. Needed for them without `--api-keys-file`.
+    #[structopt(long)]
+    admin_port: Option<u16>,
+    /// Listen on `127.0.0.1` only for `--admin-port`.
+    #[structopt(long, requires = "admin-port")]
+    admin_localhost: bool,
 
     #[structopt(long, default_value = "400")]
     default_timeout: u16,
-    #[structopt(long, default_value = "3000")]
-    max_timeout: u16,
+    /// Instead of `--default-timeout`, give locks requested without a
+    /// `timeout` a TTL learned from past job durations of their class.
+    #[structopt(long)]
+    auto_ttl: bool,
+    /// Adjust the TTL of locks requested without a `timeout` between
+    /// these bounds, `MIN..MAX` in seconds, to the backlog of jobs workers
+    /// find: shorter the more often they find one. Replaces
+    /// `--default-timeout`, while `--auto-ttl` still takes precedence.
+    #[structopt(long)]
+    dynamic_ttl: Option<TtlBounds>,
+    /// A lock key's job class is the part before this separator.
+    #[structopt(long, default_value = ":")]
+    job_class_separator: String,
+    /// Upper bound of lock TTLs in milliseconds, longer timeouts are
+    /// lowered to it. 3000000 unless set.
+    #[structopt(long, conflicts_with = "max-timeout")]
+    max_timeout_ms: Option<u64>,
+    /// Deprecated, `--max-timeout-ms` in seconds.
+    #[structopt(long)]
+    max_timeout: Option<u16>,
+    /// Lower bound of lock TTLs in milliseconds, shorter timeouts are
+    /// raised to it. Can't exceed `--max-timeout-ms`.
+    #[structopt(long, default_value = "100")]
+    min_timeout_ms: u64,
+    /// Milliseconds between sweeps of expired locks. Expired locks are
+    /// granted again right away whether swept or not, so this bounds
+    /// how long they linger in lock listings and history.
+    #[structopt(long, default_value = "2000")]
+    sweep_interval_ms: u64,
+    /// Seconds without a heartbeat or stats event after which a worker
+    /// is reported stale.
+    #[structopt(long, default_value = "60")]
+    worker_stale_after: u64,
+    /// Seconds without a heartbeat or stats event after which a worker
+    /// is reported dead.
+    #[structopt(long, default_value = "300")]
+    worker_dead_after: u64,
+    /// Number of released or expired locks to remember, 10000 unless set
+    /// by `--profile`.
+    #[structopt(long)]
+    lock_history_len: Option<usize>,
+    /// Number of shards the lock table is split into by key, each locked
+    /// on its own, so requests for different jobs don't wait for each
+    /// other.
+    #[structopt(long, default_value = "16")]
+    lock_shards: usize,
+    /// Where job locks are kept: `memory`, or a `redis://` URL to share
+    /// them between coordinator replicas. Lock history, snapshots and the
+    /// journal only cover the locks this replica granted.
+    #[structopt(long, default_value = "memory")]
+    lock_backend: LockBackend,
+    /// How long to keep host samples sent to worker-metrics PUT, in
+    /// seconds.
+    #[structopt(long, default_value = "86400")]
+    worker_metrics_retention: u64,
+    /// Upper bound for the `wait` parameter of lock-job PUT, in seconds.
+    #[structopt(long, default_value = "60")]
+    max_wait: u16,
+    /// Max number of locks a worker may hold at once, by the `worker_id`
+    /// it locks with. Unlimited if unset.
+    #[structopt(long)]
+    max_locks_per_worker: Option<usize>,
+    /// Number of failed jobs within `--cooldown-window` after which a
+    /// worker is refused locks and jobs for `--cooldown`. Off if unset.
+    #[structopt(long)]
+    cooldown_errors: Option<usize>,
+    /// Seconds within which `--cooldown-errors` failures start a cooldown.
+    #[structopt(long, default_value = "300")]
+    cooldown_window: u64,
+    /// Seconds a cooldown lasts.
+    #[structopt(long, default_value = "300")]
+    cooldown: u64,
 
     #[structopt(long, default_value = "100")]
     max_key_len: usize,
+    /// Max size of a request body in bytes, after decompression, 4 MiB
+    /// unless set by `--profile`.
+    #[structopt(long, alias = "max-body-bytes")]
+    max_body_size: Option<u64>,
+    /// Max length in bytes of the job ids and error messages kept in a
+    /// worker's state, longer ones are truncated.
+    #[structopt(long, default_value = "4096")]
+    max_stats_field_len: usize,
+    /// Refuse worker-stats PUTs with 429 while this many events are queued
+    /// but not yet applied.
+    #[structopt(long)]
+    ingest_max_pending: Option<usize>,
+    /// Refuse worker-stats PUTs with 429 while events wait this many
+    /// milliseconds in the queue before being applied.
+    #[structopt(long)]
+    ingest_max_wait_ms: Option<u64>,
+
+    /// JSON file describing teams and their API keys. When set, read
+    /// endpoints only return the workers of the caller's team.
+    #[structopt(long)]
+    groups_file: Option<String>,
+
+    /// JSON file with API keys. When set, PUT and DELETE requests need
+    /// an `Authorization: Bearer <key>` header with a write key, and the
+    /// admin, snapshot and metrics endpoints one with an admin key.
+    /// Without it, those are only served on `--admin-port`.
+    #[structopt(long)]
+    api_keys_file: Option<String>,
+    /// Require an API key for GET requests too.
+    #[structopt(long, requires = "api-keys-file")]
+    protect_reads: bool,
+    /// JSON file with tenants sharing the coordinator, each with its own
+    /// API key, lock namespace, worker stats and quotas. Every request
+    /// needs an API key then, a tenant's or one of `--api-keys-file`.
+    #[structopt(long)]
+    tenants_file: Option<String>,
+
+    /// Origins browsers may read the API from, comma separated, e.g.
+    /// `https://dashboard.example.com`, or `any`.
+    #[structopt(long)]
+    cors_origins: Option<CorsOrigins>,
+    /// Let those origins make mutating requests too.
+    #[structopt(long, requires = "cors-origins")]
+    cors_allow_writes: bool,
+
+    /// Hand out a session token on registration and require it, in the
+    /// `X-Worker-Token` header, on further stats of that worker.
+    #[structopt(long)]
+    worker_tokens: bool,
+
+    /// Milliseconds a worker's clock may be off in `POST /handshake`.
+    #[structopt(long, default_value = "5000")]
+    max_clock_skew_ms: u64,
+    /// Note when stats events are received, to estimate the workers'
+    /// clock skew shown by `GET /workers?clock_skew=true`. With
+    /// `substitute`, the receive time is also stored as the events'
+    /// `time`, for fleets with unreliable clocks.
+    #[structopt(long)]
+    server_time: Option<ServerTime>,
+    /// Reject stats events with timestamps going backwards or out of
+    /// order, or malformed ids, with `422 STRICT_VIOLATION` naming the
+    /// broken rule.
+    #[structopt(long)]
+    strict: bool,
+    /// Lowest prover version workers may report in `POST /handshake`,
+    /// e.g. `1.4.0`.
+    #[structopt(long)]
+    min_prover_version: Option<String>,
+
+    /// Limits sized for a `small`, `medium` or `large` fleet: state caps
+    /// and retention, rate limits, body size and lock history, which
+    /// options given explicitly override.
+    #[structopt(long)]
+    profile: Option<Profile>,
+    /// Collapse identical errors a worker hits in a row into a single
+    /// state with a `count`, so crash loops don't flood its history.
+    #[structopt(long)]
+    collapse_errors: bool,
+    /// Max number of states kept per worker, the oldest ones are evicted
+    /// beyond it. Unbounded if neither given nor set by `--profile`.
+    #[structopt(long)]
+    max_states_per_worker: Option<usize>,
+    /// Count evicted states in `/summary` totals, which otherwise only
+    /// cover the states kept.
+    #[structopt(long)]
+    fold_evicted_states: bool,
+    /// Max number of states kept across all workers, the oldest completed
+    /// ones of any worker are evicted beyond it. Unbounded if neither
+    /// given nor set by `--profile`.
+    #[structopt(long)]
+    max_total_states: Option<usize>,
+    /// Seconds after which states are dropped, counting from their end.
+    /// Workers without states left which weren't heard from within it
+    /// either are removed. States are kept forever if neither given nor
+    /// set by `--profile`.
+    #[structopt(long)]
+    stats_retention: Option<u64>,
+    /// Seconds after which states are folded into hourly aggregates at
+    /// `/summary/hourly` and dropped, counting from their end. Each
+    /// worker's most recent state stays. States evicted otherwise are
+    /// folded too. Off if unset.
+    #[structopt(long)]
+    compact_after: Option<u64>,
+    /// Append every change to the worker stats and locks to this NDJSON
+    /// file, and rebuild them from it on startup.
+    #[structopt(long, parse(from_os_str))]
+    journal: Option<PathBuf>,
+    /// Seconds between rewrites of `--journal` as snapshots of the current
+    /// state, which keep it from growing forever.
+    #[structopt(long, default_value = "3600")]
+    journal_compact_interval: u64,
+
+    /// Keep up to this many stats payloads rejected as malformed or
+    /// invalid transitions, for inspection at `GET /admin/quarantine`.
+    #[structopt(long)]
+    quarantine: Option<usize>,
+
+    /// Sustained PUT/DELETE requests per second allowed per client,
+    /// unlimited if neither given nor set by `--profile`.
+    #[structopt(long)]
+    rate_limit: Option<f64>,
+    /// Requests a client may burst above `--rate-limit`, 20 unless set by
+    /// `--profile`.
+    #[structopt(long)]
+    rate_limit_burst: Option<f64>,
+    /// Rate limit clients by `worker_id` or by `ip`. Requests without a
+    /// worker id are always limited by IP.
+    #[structopt(long, default_value = "worker_id")]
+    rate_limit_by: RateLimitBy,
+
+    /// Starts as a standby of the primary at this base URL, e.g.
+    /// `http://coordinator-a:8080`. Writes are redirected there until
+    /// the role is switched with `PUT /admin/role`.
+    #[structopt(long, conflicts_with = "cluster")]
+    standby_of: Option<String>,
+    /// Joins a cluster which elects its leader with a lease in Redis at
+    /// this URL, e.g. `redis://redis:6379`. The leader serves writes and
+    /// replicates its state to the other members, which redirect writes
+    /// to it.
+    #[structopt(long, requires = "advertise-url")]
+    cluster: Option<String>,
+    /// Base URL other cluster members reach this instance at, e.g.
+    /// `http://coordinator-a:8080`.
+    #[structopt(long)]
+    advertise_url: Option<String>,
+    /// Seconds the cluster leader lease lasts without being renewed.
+    #[structopt(long, default_value = "10")]
+    leader_lease_ttl: u64,
+    /// API key cluster members send to the leader's `GET /replication`,
+    /// needed with `--protect-reads`.
+    #[structopt(long)]
+    cluster_api_key: Option<String>,
+
+    /// JSON file with default and max lock timeouts and key limits of
+    /// lock namespaces, picked by requests with `X-Lock-Namespace`.
+    #[structopt(long)]
+    lock_namespaces_file: Option<String>,
+
+    /// PEM certificate chain. Together with `--tls-key`, serves HTTPS.
+    #[structopt(long, requires = "tls-key")]
+    tls_cert: Option<String>,
+    /// PEM private key of `--tls-cert`.
+    #[structopt(long, requires = "tls-cert")]
+    tls_key: Option<String>,
+    /// PEM CA certificates. When set, workers must authenticate with a
+    /// client certificate signed by one of them.
+    #[structopt(long, requires = "tls-cert")]
+    tls_client_ca: Option<String>,
+
+    /// Serve JSON-RPC 2.0 at `/rpc`, with methods `lockJob`, `releaseJob`,
+    /// `putWorkerStats` and `getWorkerStats`.
+    #[structopt(long)]
+    rpc: bool,
+    /// Address to serve gRPC on, e.g. `127.0.0.1:50051`, with the calls of
+    /// `proto/coordinator.proto`.
+    #[structopt(long)]
+    grpc_listen: Option<SocketAddr>,
+
+    /// Seconds to wait for in-flight requests on shutdown.
+    #[structopt(long, default_value = "30")]
+    shutdown_deadline: u64,
+    /// Directory state dumps are written to on SIGUSR1.
+    #[structopt(long, parse(from_os_str), default_value = ".")]
+    data_dir: PathBuf,
+
+    /// JSON file with path aliases and method mappings for legacy worker
+    /// scripts.
+    #[structopt(long)]
+    compat_file: Option<String>,
+
+    /// File with an expression scoring how well a worker suits a job,
+    /// served ranked by `GET /report/assignment`. See `assignment.rs`
+    /// for the variables it can use.
+    #[structopt(long)]
+    assignment_policy: Option<String>,
+
+    /// URL which receives JSON notifications, e.g. about fleet anomalies.
+    #[structopt(long)]
+    webhook_url: Option<String>,
+    /// minijinja template file formatting webhook request bodies, e.g.
+    /// for Slack or Discord.
+    #[structopt(long)]
+    webhook_template: Option<String>,
+    /// Send webhook notifications as Slack messages.
+    #[structopt(long, requires = "webhook-url", conflicts_with = "webhook-template")]
+    webhook_slack: bool,
+    /// Seconds a worker may stay in `WorkCreatePending` or
+    /// `WorkSubmitPending` before the webhook is notified it's stuck.
+    #[structopt(long, default_value = "600")]
+    webhook_stuck_threshold: u64,
+    /// Seconds after which pending states time out, releasing their job's
+    /// lock, so workers which died mid-proof don't hold on to it.
+    #[structopt(long)]
+    pending_timeout: Option<u64>,
+
+    /// Pushgateway base URL to push metrics to, for deployments which
+    /// can't scrape `/metrics`.
+    #[structopt(long)]
+    push_gateway: Option<String>,
+    /// Job label metrics are pushed to the Pushgateway under.
+    #[structopt(long, default_value = "snark_coordinator")]
+    push_job: String,
+    /// Prometheus remote-write endpoint to push metrics and completed
+    /// jobs to.
+    #[structopt(long)]
+    push_remote_write: Option<String>,
+    /// InfluxDB write URL to push metrics and completed jobs to as line
+    /// protocol, e.g. `http://influx:8086/api/v2/write?org=o&bucket=b`.
+    #[structopt(long)]
+    push_influx: Option<String>,
+    /// InfluxDB API token, sent as `Authorization: Token <token>`.
+    #[structopt(long, env = "PUSH_INFLUX_TOKEN", hide_env_values = true)]
+    push_influx_token: Option<String>,
+    /// StatsD `host:port` to push metrics and completed job phase timers
+    /// to over UDP, labels as DogStatsD-style tags.
+    #[structopt(long)]
+    push_statsd: Option<String>,
+    /// OTLP/HTTP collector base URL to push metrics to, e.g.
+    /// `http://collector:4318`, under `--otlp-service-name`.
+    #[structopt(long)]
+    push_otlp: Option<String>,
+    /// Seconds between pushes.
+    #[structopt(long, default_value = "15")]
+    push_interval: u64,
+
+    /// OTLP/HTTP collector base URL to export job lifecycles to as
+    /// traces, e.g. `http://collector:4318`.
+    #[structopt(long)]
+    otlp_endpoint: Option<String>,
+    /// `service.name` of exported traces and `--push-otlp` metrics.
+    #[structopt(long, default_value = "snark-coordinator")]
+    otlp_service_name: String,
+
+    /// GraphQL URL of a Mina or Openmina node, e.g.
+    /// `http://node:3085/graphql`, to poll for pending snark work and hand
+    /// it out to workers at `GET /job`.
+    #[structopt(long)]
+    node_graphql: Option<String>,
+    /// Seconds between polls of `--node-graphql`.
+    #[structopt(long, default_value = "5")]
+    node_poll_interval: u64,
+    /// How `GET /job` picks among the pending jobs: `oldest-first`,
+    /// `random`, `fee-weighted` by the fees of their proofs in the node's
+    /// snark pool, or `avoid-failed` putting recently failed jobs last.
+    #[structopt(long, default_value = "oldest-first")]
+    selection_policy: SelectionPolicy,
+
+    /// Seconds between fleet anomaly detector samples.
+    #[structopt(long, default_value = "60")]
+    anomaly_interval: u64,
+    /// EWMA smoothing factor of the fleet anomaly detector.
+    #[structopt(long, default_value = "0.1")]
+    anomaly_alpha: f64,
+    /// Z-score above which a fleet series sample is anomalous.
+    #[structopt(long, default_value = "3")]
+    anomaly_threshold: f64,
+    /// Samples to collect before flagging fleet anomalies.
+    #[structopt(long, default_value = "10")]
+    anomaly_warmup: u64,
+
+    /// Seconds of lock attempts hot key detection looks at.
+    #[structopt(long, default_value = "300")]
+    hot_key_window: u64,
+    /// Fewest refused lock attempts within the window for a key to be hot.
+    #[structopt(long, default_value = "20")]
+    hot_key_min_conflicts: u64,
+    /// Fewest refused lock attempts per acquisition within the window for
+    /// a key to be hot.
+    #[structopt(long, default_value = "5")]
+    hot_key_ratio: f64,
+
+    /// Populate the coordinator with this many fake workers going
+    /// through job lifecycles, for developing against a busy coordinator.
+    #[structopt(long)]
+    synthetic_fleet: Option<usize>,
+    /// Seed of the synthetic fleet's random choices.
+    #[structopt(long, default_value = "0")]
+    synthetic_seed: u64,
+
+    /// Log filter, e.g. `info` or `snark_coordinator_rs=debug,warp=info`.
+    #[structopt(long, env = "RUST_LOG", default_value = "info")]
+    log_level: String,
+    /// Emit logs as JSON lines.
+    #[structopt(long)]
+    log_json: bool,
+    /// Write an access log line per request to this file, or `-` for
+    /// stdout.
+    #[structopt(long)]
+    access_log: Option<String>,
+    /// Access log format: `common`, `combined` or `json`.
+    #[structopt(long, default_value = "combined")]
+    access_log_format: AccessLogFormat,
+
+    #[structopt(subcommand)]
+    cmd: Option<Command>,
 }
 
-#[derive(Serialize, Deserialize, Default)]
+impl Opts {
+    /// Limits of `--profile`, or the defaults, with the options given
+    /// explicitly taking precedence.
+    fn limits(&self) -> Limits {
+        let limits = self.profile.map(Profile::limits).unwrap_or_default();
+        Limits {
+            max_states_per_worker: self.max_states_per_worker.or(limits.max_states_per_worker),
+            max_total_states: self.max_total_states.or(limits.max_total_states),
+            stats_retention: self.stats_retention.or(limits.stats_retention),
+            rate_limit: self.rate_limit.or(limits.rate_limit),
+            rate_limit_burst: self.rate_limit_burst.unwrap_or(limits.rate_limit_burst),
+            max_body_size: self.max_body_size.unwrap_or(limits.max_body_size),
+            lock_history_len: self.lock_history_len.unwrap_or(limits.lock_history_len),
+        }
+    }
+
+    /// `--max-timeout-ms`, or the deprecated `--max-timeout` in seconds.
+    fn max_timeout_ms(&self) -> u64 {
+        match (self.max_timeout_ms, self.max_timeout) {
+            (Some(max_timeout_ms), _) => max_timeout_ms,
+            (None, Some(max_timeout)) => u64::from(max_timeout) * 1000,
+            (None, None) => 3_000_000,
+        }
+    }
+
+    /// Error if the bounds of lock TTLs are the wrong way round.
+    fn check_timeouts(&self) -> Result<(), String> {
+        let (min_timeout_ms, max_timeout_ms) = (self.min_timeout_ms, self.max_timeout_ms());
+        match min_timeout_ms <= max_timeout_ms {
+            true => Ok(()),
+            false => Err(format!(
+                "--min-timeout-ms {min_timeout_ms} exceeds --max-timeout-ms {max_timeout_ms}"
+            )),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+enum Command {
+    /// Run the coordinator, as without a subcommand. Its options go
+    /// before `serve`.
+    Serve,
+    #[structopt(flatten)]
+    Admin(AdminCommand),
+    /// Run a scripted smoke test against a live coordinator.
+    SelfTest {
+        #[structopt(long, default_value = "http://localhost:8080")]
+        url: String,
+        /// API key sent as `Authorization: Bearer <key>`.
+        #[structopt(long)]
+        api_key: Option<String>,
+    },
+    /// Compare the workers, their current states and the held locks of
+    /// two coordinators, e.g. before cutting over to a new deployment.
+    /// Exits with 1 if they differ.
+    Diff {
+        /// Base URL of a coordinator or path of a snapshot file.
+        left: String,
+        /// Base URL of a coordinator or path of a snapshot file.
+        right: String,
+        /// API key sent as `Authorization: Bearer <key>` to both
+        /// coordinators.
+        #[structopt(long)]
+        api_key: Option<String>,
+        /// Save a snapshot of `left` to this file, to diff against later.
+        #[structopt(long)]
+        save: Option<PathBuf>,
+    },
+    /// Run a coordinator, a mock node and simulated workers end to end
+    /// and check the resulting stats.
+    E2e {
+        /// Number of simulated workers.
+        #[structopt(long, default_value = "4")]
+        workers: usize,
+        /// Number of jobs the mock node hands out.
+        #[structopt(long, default_value = "50")]
+        jobs: usize,
+    },
+    /// Load test a live coordinator with simulated workers running job
+    /// lifecycles and taking job locks, and report request rates and
+    /// latencies. Exits with 1 if any request failed or event was
+    /// rejected.
+    Simulate(SimulateOpts),
+    /// Rebuild the state of a coordinator from its journal or a
+    /// snapshot, optionally up to a point in time, and serve the API over
+    /// it read-only. Its options go before `replay`.
+    Replay(ReplayOpts),
+}
+
+#[derive(Serialize, Deserialize, Default, IntoParams)]
+#[into_params(parameter_in = Query)]
 struct LockJobQueryParams {
+    /// Lock TTL in seconds.
     timeout: Option<u16>,
+    /// Lock TTL in milliseconds, takes precedence over `timeout`.
+    timeout_ms: Option<u64>,
+    worker_id: Option<String>,
+    /// Seconds to wait for the lock to become available before giving up.
+    wait: Option<u16>,
+    /// Lock-jobs only: lock each key independently and report per-key
+    /// results instead of locking all or none. Namespace and worker lock
+    /// limits refuse just the keys beyond them.
+    partial: Option<bool>,
+    /// Lock-job only: with `If-Match`, extend the lock held under its
+    /// fencing token instead of taking it over, or lock the key if it's
+    /// unheld.
+    bump: Option<bool>,
+}
+
+impl LockJobQueryParams {
+    /// Requested lock TTL in milliseconds.
+    fn timeout_ms(&self) -> Option<u64> {
+        self.timeout_ms
+            .or(self.timeout.map(|s| u64::from(s) * 1000))
+    }
+}
+
+#[derive(Serialize, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+struct LockJobValidateParams {
+    fencing_token: u64,
+}
+
+#[derive(Serialize, Deserialize, IntoParams, Default)]
+#[into_params(parameter_in = Query)]
+struct LockJobDeleteParams {
+    /// Fencing token the lock is held under.
+    fencing_token: Option<u64>,
+    /// Worker holding the lock, instead of its fencing token.
+    worker_id: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LockJobsGetParams {
+    /// Only locks of this lock namespace, by their key within it.
+    namespace: Option<String>,
+}
+
+/// Body of `GET /snapshot` and `POST /snapshot`.
+#[derive(Serialize, Deserialize)]
+struct StateSnapshot {
+    /// [`journal::VERSION`] of the format the snapshot is in. Snapshots
+    /// taken before it was recorded are of version 1.
+    #[serde(default = "first_snapshot_version")]
+    snapshot_version: u32,
+    taken_t: u64,
+    locks: LockTableSnapshot,
+    stats: WorkerStatsSnapshot,
+}
+
+fn first_snapshot_version() -> u32 {
+    1
+}
+
+impl StateSnapshot {
+    /// Reads the snapshot of `value`, migrating it from an older format
+    /// version and refusing one of a newer version, which it may not be
+    /// read correctly as.
+    fn from_value(mut value: serde_json::Value) -> io::Result<Self> {
+        migrations::migrate_snapshot(&mut value)?;
+        serde_json::from_value(value).map_err(io::Error::other)
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LockHistoryGetParams {
+    /// Only locks of this lock namespace, `key` being within it.
+    namespace: Option<String>,
+    key: Option<String>,
+    /// Only locks removed for this reason, e.g. `expired`.
+    reason: Option<RemovalReason>,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LockStatsGetParams {
+    /// Seconds before now events count in, 5 minutes if not given, at
+    /// most a day.
+    window: Option<u64>,
+    /// Number of most contended keys, 10 if not given.
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LifecyclesGetParams {
+    workers: Option<String>,
+    lease_expired_before_submit: Option<bool>,
+    format: Option<ExportFormat>,
 }
 
 #[derive(Serialize, Deserialize, Default)]
 struct WorkerStatsGetParams {
     workers: Option<String>,
+    /// States which ended at or after this time.
     from_t: Option<u64>,
+    /// States which ended before this time.
     to_t: Option<u64>,
+    /// Order of each worker's states by end time, most recent first if
+    /// not given.
+    order: Option<Order>,
+    time_format: Option<TimeFormat>,
+    format: Option<ExportFormat>,
+    /// `x-stats-version` of a previous PUT the response has to reflect.
+    min_version: Option<u64>,
+    /// Max number of states in the response. Setting it, `cursor` or
+    /// `per_worker_limit` pages the JSON response, see [`StatsPage`].
+    limit: Option<usize>,
+    /// Max number of most recent states per worker.
+    per_worker_limit: Option<usize>,
+    /// Comma separated state kinds to include, e.g.
+    /// `WorkSubmitError,JobGetError`.
+    kinds: Option<String>,
+    /// `next` of the previous page.
+    cursor: Option<String>,
+    /// Add the `durations_ms` of each state's phases, see [`StateView`].
+    durations: Option<bool>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "kind")]
-enum SnarkWorkerJobGetError {
-    NoAvailableJob,
-    Other { error: String },
+/// Worker state of a worker-stats GET response.
+#[derive(Serialize)]
+struct StateView {
+    #[serde(flatten)]
+    state: SnarkWorkerState,
+    /// Milliseconds spent in each phase the lifecycle got through, by
+    /// phase, with `durations=true`. Computed here so clients don't have
+    /// to tell which timestamps a state has.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    durations_ms: Option<BTreeMap<Phase, u64>>,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "kind")]
-enum SnarkWorkerStatsPut {
-    Register {
-        time: u64,
-    },
-    JobGetInit {
-        time: u64,
-    },
-    JobGetError {
-        time: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        error: SnarkWorkerJobGetError,
-    },
-    JobGetSuccess {
-        time: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        ids: String,
-    },
-    WorkCreateError {
-        time: u64,
-        ids: String,
-        error: String,
-    },
-    WorkCreateSuccess {
-        time: u64,
-        ids: String,
-    },
-    WorkSubmitError {
-        time: u64,
-        work_submit_node_received_t: Option<u64>,
-        work_submit_node_add_work_init_t: Option<u64>,
-        work_submit_node_add_work_success_t: Option<u64>,
-        ids: String,
-        error: String,
-    },
-    WorkSubmitSuccess {
-        time: u64,
-        work_submit_node_received_t: Option<u64>,
-        work_submit_node_add_work_init_t: Option<u64>,
-        work_submit_node_add_work_success_t: Option<u64>,
-        ids: String,
-    },
+impl StateView {
+    fn new(state: SnarkWorkerState, durations: bool) -> Self {
+        Self {
+            durations_ms: durations.then(|| latency::durations(&state)),
+            state,
+        }
+    }
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone)]
-#[serde(tag = "kind")]
-enum SnarkWorkerState {
-    Registered {
-        registered_t: u64,
-    },
-    JobGetPending {
-        job_get_init_t: u64,
-    },
-    // TODO(binier): add separate `SnarkWorkerStatsPut` for it.
-    JobUnavailable {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-    },
-    JobGetError {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_error_t: u64,
-        error: SnarkWorkerJobGetError,
-    },
-    WorkCreatePending {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-        ids: String,
-    },
-    WorkCreateError {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-        work_create_error_t: u64,
-        ids: String,
-        error: String,
-    },
-    WorkSubmitPending {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-        work_create_success_t: u64,
-        ids: String,
-    },
-    WorkSubmitError {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-        work_create_success_t: u64,
-        work_submit_error_t: u64,
-        ids: String,
-        error: String,
-    },
-    WorkSubmitSuccess {
-        job_get_init_t: u64,
-        job_get_node_received_t: Option<u64>,
-        job_get_node_request_work_init_t: Option<u64>,
-        job_get_node_request_work_success_t: Option<u64>,
-        job_get_success_t: u64,
-        work_create_success_t: u64,
-        work_submit_node_received_t: Option<u64>,
-        work_submit_node_add_work_init_t: Option<u64>,
-        work_submit_node_add_work_success_t: Option<u64>,
-        work_submit_success_t: u64,
-        ids: String,
-    },
+/// Paginated worker-stats response, workers are ordered by id.
+#[derive(Serialize)]
+struct StatsPage {
+    workers: BTreeMap<String, Vec<StateView>>,
+    /// Cursor of the next page, `None` on the last one.
+    next: Option<String>,
 }
 
-impl SnarkWorkerState {
-    fn init(time: u64) -> Self {
-        Self::JobGetPending {
-            job_get_init_t: time,
-        }
-    }
-
-    fn start_time(&self) -> u64 {
-        match self {
-            Self::Registered { registered_t } => *registered_t,
-            Self::JobGetPending { job_get_init_t }
-            | Self::JobUnavailable { job_get_init_t, .. }
-            | Self::JobGetError { job_get_init_t, .. }
-            | Self::WorkCreatePending { job_get_init_t, .. }
-            | Self::WorkCreateError { job_get_init_t, .. }
-            | Self::WorkSubmitPending { job_get_init_t, .. }
-            | Self::WorkSubmitError { job_get_init_t, .. }
-            | Self::WorkSubmitSuccess { job_get_init_t, .. } => *job_get_init_t,
-        }
-    }
-
-    fn end_time(&self) -> u64 {
-        match self {
-            Self::Registered { registered_t } => *registered_t,
-            Self::JobGetPending { job_get_init_t } => *job_get_init_t,
-            Self::JobUnavailable {
-                job_get_success_t, ..
-            } => *job_get_success_t,
-            Self::JobGetError {
-                job_get_error_t, ..
-            } => *job_get_error_t,
-            Self::WorkCreatePending {
-                job_get_success_t, ..
-            } => *job_get_success_t,
-            Self::WorkCreateError {
-                work_create_error_t,
-                ..
-            } => *work_create_error_t,
-            Self::WorkSubmitPending {
-                work_create_success_t,
-                ..
-            } => *work_create_success_t,
-            Self::WorkSubmitError {
-                work_submit_error_t,
-                ..
-            } => *work_submit_error_t,
-            Self::WorkSubmitSuccess {
-                work_submit_success_t,
-                ..
-            } => *work_submit_success_t,
-        }
-    }
-
-    fn apply(&mut self, v: SnarkWorkerStatsPut) -> bool {
-        match self.clone() {
-            Self::JobGetPending { job_get_init_t } => {
-                *self = match v {
-                    SnarkWorkerStatsPut::JobGetError {
-                        time,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        error,
-                    } => match error {
-                        SnarkWorkerJobGetError::NoAvailableJob => Self::JobUnavailable {
-                            job_get_init_t,
-                            job_get_node_received_t,
-                            job_get_node_request_work_init_t,
-                            job_get_node_request_work_success_t,
-                            job_get_success_t: time,
-                        },
-                        error => Self::JobGetError {
-                            job_get_init_t,
-                            job_get_node_received_t,
-                            job_get_node_request_work_init_t,
-                            job_get_node_request_work_success_t,
-                            job_get_error_t: time,
-                            error,
-                        },
-                    },
-                    SnarkWorkerStatsPut::JobGetSuccess {
-                        time,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        ids,
-                    } => Self::WorkCreatePending {
-                        job_get_init_t,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        job_get_success_t: time,
-                        ids,
-                    },
-                    _ => return false,
-                }
-            }
-            Self::WorkCreatePending {
-                job_get_init_t,
-                job_get_node_received_t,
-                job_get_node_request_work_init_t,
-                job_get_node_request_work_success_t,
-                job_get_success_t,
-                ids: expected_ids,
-            } => {
-                *self = match v {
-                    SnarkWorkerStatsPut::WorkCreateError {
-                        time, error, ids, ..
-                    } if ids == expected_ids => Self::WorkCreateError {
-                        job_get_init_t,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        job_get_success_t,
-                        work_create_error_t: time,
-                        ids,
-                        error,
-                    },
-                    SnarkWorkerStatsPut::WorkCreateSuccess { time, ids } if ids == expected_ids => {
-                        Self::WorkSubmitPending {
-                            job_get_init_t,
-                            job_get_node_received_t,
-                            job_get_node_request_work_init_t,
-                            job_get_node_request_work_success_t,
-                            job_get_success_t,
-                            work_create_success_t: time,
-                            ids,
-                        }
-                    }
-                    _ => return false,
-                };
-            }
-            Self::WorkSubmitPending {
-                job_get_init_t,
-                job_get_node_received_t,
-                job_get_node_request_work_init_t,
-                job_get_node_request_work_success_t,
-                job_get_success_t,
-                work_create_success_t,
-                ids: expected_ids,
-            } => {
-                *self = match v {
-                    SnarkWorkerStatsPut::WorkSubmitError {
-                        time, error, ids, ..
-                    } if ids == expected_ids => Self::WorkSubmitError {
-                        job_get_init_t,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        job_get_success_t,
-                        work_create_success_t,
-                        work_submit_error_t: time,
-                        ids,
-                        error,
-                    },
-                    SnarkWorkerStatsPut::WorkSubmitSuccess {
-                        time,
-                        work_submit_node_received_t,
-                        work_submit_node_add_work_init_t,
-                        work_submit_node_add_work_success_t,
-                        ids,
-                    } if ids == expected_ids => Self::WorkSubmitSuccess {
-                        job_get_init_t,
-                        job_get_node_received_t,
-                        job_get_node_request_work_init_t,
-                        job_get_node_request_work_success_t,
-                        job_get_success_t,
-                        work_create_success_t,
-                        work_submit_node_received_t,
-                        work_submit_node_add_work_init_t,
-                        work_submit_node_add_work_success_t,
-                        work_submit_success_t: time,
-                        ids,
-                    },
-                    _ => return false,
-                };
-            }
-            _ => return false,
+#[derive(Serialize, Deserialize, Default)]
+struct LiveFeedParams {
+    /// Comma separated worker ids to stream transitions of, all if not
+    /// given. Subscribers can change it with a `{"workers": [..]}`
+    /// message.
+    workers: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ErrorsGetParams {
+    /// Seconds before now errors must have happened in, all kept ones if
+    /// not given.
+    window: Option<u64>,
+    /// Number of groups, 20 if not given.
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ErrorEventsParams {
+    /// Id of the last event seen, for clients which can't send the
+    /// `Last-Event-ID` header.
+    last_event_id: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WorkerStatsDeleteParams {
+    /// Archive the worker's history instead of dropping it.
+    archive: Option<bool>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ArchivedWorkersGetParams {
+    workers: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct QuarantineGetParams {
+    workers: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ResetParams {
+    /// Token of a previous request without it.
+    confirm: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct WorkersGetParams {
+    /// List each worker with its liveness instead of just its id.
+    liveness: Option<bool>,
+    /// List each worker with the name and metadata it registered with
+    /// instead of just its id.
+    metadata: Option<bool>,
+    /// List each worker with its clock skew, needs `--server-time`.
+    clock_skew: Option<bool>,
+    /// Only list the sessions registered under this name.
+    name: Option<String>,
+    /// List each worker with what it's doing: its current state, last
+    /// event time, jobs in flight and lifecycle totals, along with its
+    /// liveness and metadata.
+    summary: Option<bool>,
+    /// Only list workers whose current state is one of these
+    /// comma-separated kinds.
+    state: Option<String>,
+    /// Only list workers with one of these comma-separated liveness
+    /// statuses, `alive`, `stale` or `dead`.
+    status: Option<String>,
+    /// Order of the workers, one of [`WORKERS_SORT_KEYS`], descending if
+    /// prefixed with `-`. By id if not given.
+    sort: Option<String>,
+}
+
+/// Keys `GET /workers` can be sorted by.
+const WORKERS_SORT_KEYS: &[&str] = &["worker_id", "last_event_t", "state", "succeeded", "failed"];
+
+/// Entry of `GET /workers` with `liveness`, `metadata`, `clock_skew` or
+/// `summary` set.
+#[derive(Serialize)]
+struct WorkerInfo<'a> {
+    worker_id: &'a str,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    liveness: Option<WorkerLiveness>,
+    /// Name the worker registered under.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    name: Option<&'a str>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    metadata: Option<&'a WorkerMetadata>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    clock_skew: Option<WorkerClockSkew>,
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    summary: Option<WorkerSummary<'a>>,
+}
+
+/// What a worker is doing, for `GET /workers?summary=true`.
+#[derive(Serialize)]
+struct WorkerSummary<'a> {
+    /// Kind of the state the worker's latest event was applied to.
+    state: Option<&'static str>,
+    last_event_t: Option<u64>,
+    /// Ids of the jobs the worker has in flight.
+    in_flight: Vec<&'a str>,
+    totals: LifecycleTotals,
+}
+
+impl<'a> WorkerSummary<'a> {
+    fn new(
+        stats: &'a WorkerStats,
+        worker_id: &'a str,
+        states: &'a VecDeque<SnarkWorkerState>,
+    ) -> Self {
+        let latest = stats.latest(worker_id);
+        let summary = summary::summarize(
+            [(&worker_id.to_owned(), states)],
+            |id| stats.evicted(id),
+            |id| stats.seq(id),
+        );
+        Self {
+            state: latest.map(|state| state.kind()),
+            last_event_t: latest.map(|state| state.last_seen_t().unwrap_or(state.end_time())),
+            in_flight: stats
+                .in_flight(worker_id)
+                .filter_map(|state| state.ids())
+                .collect(),
+            totals: summary.workers.into_values().next().unwrap_or_default(),
         }
+    }
 
-        true
+    fn failed(&self) -> u64 {
+        let failed = &self.totals.failed;
+        failed.job_get + failed.work_create + failed.work_submit
     }
 }
 
-impl Default for SnarkWorkerState {
-    fn default() -> Self {
-        Self::JobGetPending { job_get_init_t: 0 }
+/// Parses the `sort` of `GET /workers` into its key and whether it's
+/// descending.
+fn parse_workers_sort(sort: Option<&str>) -> Result<(&str, bool), String> {
+    let Some(sort) = sort else {
+        return Ok(("worker_id", false));
+    };
+    let (key, desc) = match sort.strip_prefix('-') {
+        Some(key) => (key, true),
+        None => (sort, false),
+    };
+    if !WORKERS_SORT_KEYS.contains(&key) {
+        return Err(format!(
+            "unknown sort key: {key}, expected one of: {}",
+            WORKERS_SORT_KEYS.join(",")
+        ));
     }
+    Ok((key, desc))
 }
 
-#[tokio::main]
-async fn main() {
-    let opts = Opts::from_args();
-    let default_timeout = opts.default_timeout;
-    let max_timeout = opts.max_timeout;
-    let max_key_len = opts.max_key_len;
+/// Parses the comma-separated liveness `statuses` of `GET /workers`.
+fn parse_statuses(statuses: Option<&str>) -> Result<Option<Vec<Status>>, String> {
+    let Some(statuses) = statuses else {
+        return Ok(None);
+    };
+    statuses
+        .split(',')
+        .map(|status| {
+            let status = status.trim();
+            serde_json::from_value(serde_json::Value::String(status.to_owned()))
+                .map_err(|_| format!("unknown status: {status}, expected one of: alive,stale,dead"))
+        })
+        .collect::<Result<_, _>>()
+        .map(Some)
+}
 
-    let table = Arc::new(Mutex::new(HashMap::new()));
-    let worker_stats = Arc::new(Mutex::new(HashMap::new()));
+#[derive(Serialize, Deserialize, Default)]
+struct WorkerStatsGetOneParams {
+    /// States which ended at or after this time.
+    from_t: Option<u64>,
+    /// States which ended before this time.
+    to_t: Option<u64>,
+    /// Order of the states by end time, most recent first if not given.
+    order: Option<Order>,
+    /// Max number of states.
+    limit: Option<usize>,
+    time_format: Option<TimeFormat>,
+    kinds: Option<String>,
+    /// Add the `durations_ms` of each state's phases.
+    durations: Option<bool>,
+}
 
-    let kv = table.clone();
-    tokio::spawn(async move {
-        loop {
-            tokio::time::sleep(Duration::from_secs(2)).await;
+#[derive(Serialize, Deserialize, Default)]
+struct WorkerMetricsGetParams {
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+    time_format: Option<TimeFormat>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct AnnotationsGetParams {
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+}
+
+/// Report with the operator annotations of its time range, so changes in
+/// it can be attributed to known events.
+#[derive(Serialize)]
+struct Annotated<'a, T> {
+    #[serde(flatten)]
+    report: T,
+    #[serde(skip_serializing_if = "Vec::is_empty")]
+    annotations: Vec<&'a Annotation>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct LatencyGetParams {
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+    by_worker: Option<bool>,
+    /// Bucket lifecycles into windows of this many seconds.
+    window: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct OutliersGetParams {
+    stage: Phase,
+    /// p99 if not given.
+    over: Option<Threshold>,
+    /// Seconds before now lifecycles must have ended in, a day if not
+    /// given.
+    window: Option<u64>,
+    /// Number of outliers, 100 if not given, at most
+    /// [`outliers::MAX_OUTLIERS`].
+    limit: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct EfficiencyGetParams {
+    /// Comma separated window lengths in seconds, `3600,86400` if not
+    /// given.
+    windows: Option<String>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct AssignmentGetParams {
+    /// Job ids to rank workers for, e.g. as locked with `lock-job`.
+    job: String,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct TopGetParams {
+    /// Number of entries, 10 if not given, at most [`top::MAX_K`].
+    k: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct ThroughputGetParams {
+    /// Bucket length in seconds, 60 if not given.
+    bucket: Option<u64>,
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy)]
+#[serde(rename_all = "lowercase")]
+enum TimeFormat {
+    #[default]
+    Unix,
+    Iso8601,
+}
 
-            let mut kv = kv.lock().await;
-            let now = Instant::now();
-            kv.retain(|_, t| *t > now);
-            drop(kv);
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum ExportFormat {
+    #[default]
+    Json,
+    Parquet,
+    Csv,
+}
+
+impl ExportFormat {
+    /// The `format` parameter, or CSV if the `Accept` header asks for it.
+    fn negotiate(format: Option<Self>, accept: Option<&str>) -> Option<Self> {
+        format.or_else(|| {
+            accept
+                .is_some_and(|accept| accept.contains("text/csv"))
+                .then_some(Self::Csv)
+        })
+    }
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct SummaryGetParams {
+    format: Option<ExportFormat>,
+}
+
+#[derive(Serialize, Deserialize, Default)]
+struct HourlySummaryGetParams {
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+}
+
+/// Error response with a machine readable code, see [`errors::ErrorCode`].
+fn error_reply(code: ErrorCode, message: impl Into<String>) -> WithStatus<String> {
+    api_error_reply(&ApiError::new(code, message))
+}
+
+fn api_error_reply(err: &ApiError) -> WithStatus<String> {
+    let body = serde_json::to_string(err).unwrap();
+    with_status(body, StatusCode::from_u16(err.code.status()).unwrap())
+}
+
+/// Turns warp's own rejections into coded errors. Runs last, so every
+/// rejection of the coordinator's filters is handled already.
+async fn recover_unmatched(
+    rejection: warp::Rejection,
+) -> Result<warp::reply::Response, warp::Rejection> {
+    let reply = if rejection.is_not_found() {
+        error_reply(ErrorCode::NotFound, "no such route")
+    } else if let Some(err) = rejection.find::<warp::reject::InvalidQuery>() {
+        error_reply(ErrorCode::InvalidParameter, err.to_string())
+    } else if let Some(err) = rejection.find::<warp::reject::MethodNotAllowed>() {
+        error_reply(ErrorCode::MethodNotAllowed, err.to_string())
+    } else {
+        return Err(rejection);
+    };
+    Ok(reply.into_response())
+}
+
+/// Response to callers without a group scope, see [`caller_scope`].
+fn unauthorized_reply() -> WithStatus<String> {
+    error_reply(ErrorCode::Unauthorized, "missing or unknown group key")
+}
+
+fn csv_reply(body: String) -> warp::reply::Response {
+    warp::reply::with_header(body, "content-type", "text/csv; charset=utf-8").into_response()
+}
+
+fn parquet_reply(res: Result<Vec<u8>, parquet::errors::ParquetError>) -> warp::reply::Response {
+    match res {
+        Ok(buf) => warp::reply::with_header(buf, "content-type", "application/vnd.apache.parquet")
+            .into_response(),
+        Err(err) => {
+            warn!(%err, "parquet export failed");
+            error_reply(ErrorCode::ExportFailed, err.to_string()).into_response()
         }
-    });
+    }
+}
 
-    let kv = table.clone();
-    let lock_job_put = warp::path!("lock-job" / String)
-        .and(warp::put())
-        .and(
-            warp::filters::query::query::<LockJobQueryParams>()
-                .or(warp::any().map(LockJobQueryParams::default))
-                .unify(),
-        )
-        .then(move |key: String, query: LockJobQueryParams| {
-            let kv = kv.clone();
-            async move {
-                let len = key.len();
-                if len > max_key_len {
-                    let msg = format!("key too long! max: {max_key_len}, found: {len}");
-                    return with_status(msg, StatusCode::from_u16(400).unwrap());
-                }
+/// Name of the sweeper task, whose runs `/readyz` checks.
+const SWEEP_TASK: &str = "lock-sweep";
+/// Transitions buffered for live subscribers falling behind.
+const TRANSITIONS_CAPACITY: usize = 1024;
+/// Error events kept for `/events` subscribers resuming after a reconnect.
+const ERROR_EVENTS_CAPACITY: usize = 1024;
+/// Number of deregistered workers whose history is kept.
+const ARCHIVE_CAPACITY: usize = 1024;
+/// How long ago a job may have failed to be put last by the
+/// `avoid-failed` selection policy.
+const RECENT_FAILURE_WINDOW_MS: u64 = 10 * 60 * 1000;
+/// How often workers are checked for being stuck.
+const STUCK_CHECK_INTERVAL: Duration = Duration::from_secs(10);
+/// How often stats older than `--stats-retention` are pruned.
+const STATS_PRUNE_INTERVAL: Duration = Duration::from_secs(60);
+/// How often pending states are checked for timing out.
+const PENDING_TIMEOUT_INTERVAL: Duration = Duration::from_secs(5);
+/// How often lock keys are checked for becoming hot.
+const HOT_KEY_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+/// How often `--dynamic-ttl` adjusts the lock TTL.
+const LOCK_TTL_INTERVAL: Duration = Duration::from_secs(30);
+/// Longest a lock-job PUT waits before retrying a key held in the shared
+/// lock store, as releases through other replicas aren't notified.
+const SHARED_LOCK_POLL_INTERVAL: Duration = Duration::from_millis(500);
+/// Number of journal entries a cluster follower may fall behind before
+/// it has to start over from a snapshot.
+const REPLICATION_FEED_CAPACITY: usize = 4096;
+/// How often buffered spans are exported to the OTLP collector.
+const OTLP_EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+/// How long `/readyz` waits for each state mutex.
+const READINESS_DEADLINE: Duration = Duration::from_secs(1);
+/// How long a GET with `min_version` waits for that version to be applied.
+const CONSISTENCY_DEADLINE: Duration = Duration::from_secs(5);
+/// Response header of worker-stats PUTs with the position of their last
+/// event in the ingest queue. Passing it as `min_version` to a
+/// worker-stats GET guarantees the response reflects the write.
+const STATS_VERSION_HEADER: &str = "x-stats-version";
+/// Prefix of the canonical routes. The unprefixed ones are kept as
+/// aliases for existing workers, and serve the same responses.
+const API_PREFIX: &str = "/v1";
+/// Response header with the version of the API which served the request,
+/// so clients can tell when response shapes change under a new prefix.
+const API_VERSION_HEADER: &str = "x-api-version";
+const API_VERSION: &str = "1";
 
-                let timeout_s = query.timeout.unwrap_or(default_timeout).min(max_timeout);
-                let mut kv = kv.lock().await;
-                if let Entry::Vacant(v) = kv.entry(key) {
-                    let t = Instant::now() + Duration::from_secs(timeout_s as u64);
-                    v.insert(t);
-                    with_status("".to_owned(), StatusCode::from_u16(201).unwrap())
-                } else {
-                    with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
-                }
-            }
-        });
+#[derive(Serialize, Debug)]
+struct Health {
+    ready: bool,
+    version: &'static str,
+    uptime_s: u64,
+    /// Time since the sweeper last ran, `None` if it never did.
+    sweeper_last_run_ms: Option<u64>,
+    /// State sizes, `None` if the mutex wasn't acquired within the deadline.
+    active_locks: Option<usize>,
+    workers: Option<usize>,
+    lock_history: Option<usize>,
+}
 
-    let stats = worker_stats.clone();
-    let worker_stats_put = warp::path!("worker-stats" / String)
-        .and(warp::put())
-        .and(warp::filters::body::json())
-        .then(move |worker_id: String, req: SnarkWorkerStatsPut| {
-            let stats = stats.clone();
-            async move {
-                let mut stats = stats.lock().await;
-
-                match &req {
-                    SnarkWorkerStatsPut::Register { time } => {
-                        for i in 1..4096 {
-                            let id = format!("{worker_id}_{i}");
-                            match stats.entry(id) {
-                                Entry::Vacant(stats) => {
-                                    let registered = SnarkWorkerState::Registered {
-                                        registered_t: *time,
-                                    };
-                                    let id = stats.key().clone();
-                                    stats.insert(std::iter::once(registered).collect());
-                                    return with_status(id, StatusCode::from_u16(200).unwrap());
-                                }
-                                _ => continue,
-                            }
-                        }
-                        let err = format!("too many workers under same worker_id: {worker_id}");
-                        eprintln!("{}", err);
-                        return with_status(err, StatusCode::from_u16(400).unwrap());
-                    }
-                    _ => {}
-                }
+/// Normalizes `from_t` and `to_t` of a time-range query, which selects
+/// states by end time, `from_t` inclusive and `to_t` exclusive.
+fn time_range(
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+) -> Result<(Option<u64>, Option<u64>), String> {
+    let (from_t, to_t) = (
+        from_t.map(timestamp::normalize),
+        to_t.map(timestamp::normalize),
+    );
+    match (from_t, to_t) {
+        (Some(from), Some(to)) if from > to => Err(format!("from_t {from} is after to_t {to}")),
+        range => Ok(range),
+    }
+}
 
-                match stats.entry(worker_id) {
-                    Entry::Vacant(v) => match req {
-                        SnarkWorkerStatsPut::JobGetInit { time } => {
-                            let mut val = VecDeque::new();
-                            val.push_front(SnarkWorkerState::init(time));
-                            v.insert(val);
-                        }
-                        req => {
-                            let err = format!(
-                                "unexpected worker_stats/put\nstate: None\nrequest: {:?}",
-                                req
-                            );
-                            eprintln!("{}", err);
-                            return with_status(err, StatusCode::from_u16(400).unwrap());
-                        }
-                    },
-                    Entry::Occupied(v) => {
-                        let v = v.into_mut();
-                        match req {
-                            SnarkWorkerStatsPut::JobGetInit { time } => {
-                                v.push_front(SnarkWorkerState::init(time));
-                            }
-                            req => {
-                                if v.front_mut()
-                                    .map(|v| !v.apply(req.clone()))
-                                    .unwrap_or(false)
-                                {
-                                    let err = format!(
-                                        "unexpected worker_stats/put\nstate: {:?}\nrequest: {:?}",
-                                        v, req
-                                    );
-                                    eprintln!("{}", err);
-                                    return with_status(err, StatusCode::from_u16(400).unwrap());
-                                }
-                            }
+/// Parses a comma separated `kinds` filter of state kinds.
+fn parse_kinds(kinds: Option<&str>) -> Result<Option<Vec<&str>>, String> {
+    let Some(kinds) = kinds else {
+        return Ok(None);
+    };
+    let kinds = kinds.split(',').map(str::trim).collect::<Vec<_>>();
+    if let Some(kind) = kinds
+        .iter()
+        .find(|kind| !SnarkWorkerState::KINDS.contains(kind))
+    {
+        return Err(format!(
+            "unknown kind: {kind}, expected one of: {}",
+            SnarkWorkerState::KINDS.join(",")
+        ));
+    }
+    Ok(Some(kinds))
+}
+
+/// Rewrites every timestamp field (`time` or `*_t`) inside `value` into
+/// an RFC3339 string. Timestamps are unix milliseconds.
+fn localize_timestamps(value: &mut serde_json::Value) {
+    match value {
+        serde_json::Value::Array(items) => items.iter_mut().for_each(localize_timestamps),
+        serde_json::Value::Object(fields) => {
+            for (key, v) in fields.iter_mut() {
+                let is_timestamp = key == "time" || key.ends_with("_t");
+                match v.as_u64() {
+                    Some(t) if is_timestamp => {
+                        if let Some(t) = chrono::DateTime::from_timestamp_millis(t as i64) {
+                            *v = t
+                                .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+                                .into();
                         }
                     }
+                    _ => localize_timestamps(v),
                 }
-                with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
             }
-        });
-
-    let stats = worker_stats.clone();
-    let workers_get = warp::path!("workers").and(warp::get()).then(move || {
-        let stats = stats.clone();
-        async move {
-            let stats = stats.lock().await;
-            with_status(
-                serde_json::to_string(&stats.keys().collect::<Vec<_>>()).unwrap(),
-                StatusCode::from_u16(200).unwrap(),
-            )
         }
-    });
+        _ => {}
+    }
+}
 
-    let stats = worker_stats.clone();
-    let worker_stats_get = warp::path!("worker-stats")
-        .and(
-            warp::filters::query::query::<WorkerStatsGetParams>()
-                .or(warp::any().map(WorkerStatsGetParams::default))
-                .unify(),
-        )
-        .and(warp::get())
-        .then(move |params: WorkerStatsGetParams| {
-            let stats = stats.clone();
-            async move {
-                let stats = stats.lock().await;
-                let workers_filter = params
-                    .workers
-                    .map(|s| s.split(",").map(|s| s.to_owned()).collect::<Vec<_>>());
-                let start_t_filter = params.from_t;
-                let end_t_filter = params.to_t;
-
-                let iter = stats
-                    .iter()
-                    .filter(|(k, _)| workers_filter.as_ref().map_or(true, |f| f.contains(k)))
-                    .map(|(k, states)| {
-                        let v = states
-                            .iter()
-                            .skip_while(|v| end_t_filter.map_or(false, |f| f < v.end_time()))
-                            .take_while(|v| start_t_filter.map_or(true, |f| v.start_time() >= f))
-                            .collect::<Vec<_>>();
-                        (k, v)
-                    });
-                let mut buf = Vec::with_capacity(32 * 1024);
-                let mut ser = serde_json::Serializer::new(&mut buf);
-                ser.collect_map(iter).unwrap();
+/// First path segments of the served routes, used as metric labels.
+const ROUTES: &[&str] = &[
+    "admin",
+    "annotations",
+    "anomalies",
+    "archived-workers",
+    "availability",
+    "docs",
+    "durations",
+    "echo",
+    "errors",
+    "events",
+    "graphql",
+    "handshake",
+    "healthz",
+    "job",
+    "jobs",
+    "latency",
+    "lifecycles",
+    "lock-history",
+    "lock-job",
+    "lock-jobs",
+    "lock-stats",
+    "lock-ttl",
+    "metrics",
+    "openapi.json",
+    "outliers",
+    "readyz",
+    "replication",
+    "report",
+    "snapshot",
+    "summary",
+    "throughput",
+    "top",
+    "worker-heartbeat",
+    "worker-metrics",
+    "worker-stats",
+    "worker-stats-stream",
+    "workers",
+    "ws",
+];
 
-                with_status(
-                    String::from_utf8(buf).unwrap(),
-                    StatusCode::from_u16(200).unwrap(),
-                )
-            }
-        });
+/// Installs the log subscriber, returning how to change its filter.
+fn init_logging(filter: &str, json: bool) -> SetLogFilter {
+    let filter = EnvFilter::try_new(filter).unwrap_or_else(|err| {
+        eprintln!("invalid log filter {filter:?}: {err}");
+        std::process::exit(2);
+    });
+    let subscriber = tracing_subscriber::fmt().with_env_filter(filter);
+    if json {
+        let subscriber = subscriber.json().with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        subscriber.init();
+        Box::new(move |filter| handle.reload(filter).map_err(|err| err.to_string()))
+    } else {
+        let subscriber = subscriber.with_filter_reloading();
+        let handle = subscriber.reload_handle();
+        subscriber.init();
+        Box::new(move |filter| handle.reload(filter).map_err(|err| err.to_string()))
+    }
+}
+
+/// Everything a worker-stats event updates besides the worker's state.
+#[derive(Clone)]
+struct StatsIngest {
+    kv: Arc<LockShards>,
+    stats: Arc<RwLock<WorkerStats>>,
+    metrics: Arc<Metrics>,
+    anomalies: Arc<Mutex<FleetAnomalyDetector>>,
+    durations: Arc<Mutex<DurationModel>>,
+    /// Set with `--dynamic-ttl`.
+    lock_ttl: Option<Arc<Mutex<LockTtlController>>>,
+    top: Arc<Mutex<TopK>>,
+    /// Set if a push target stores completed jobs.
+    completed_jobs: Option<Arc<Mutex<JobBuffer>>>,
+    /// Set if traces are exported.
+    tracer: Option<Arc<Mutex<Tracer>>>,
+    job_class_separator: Arc<String>,
+    max_field_len: usize,
+    /// Number of applied events.
+    version: Arc<watch::Sender<u64>>,
+    /// Applied events, for live subscribers.
+    transitions: broadcast::Sender<Transition>,
+    /// Error states, for `/events` subscribers.
+    error_events: Arc<Mutex<ErrorEvents>>,
+    liveness: Arc<Mutex<Liveness>>,
+    /// History of deregistered workers.
+    archive: Arc<Mutex<WorkerArchive>>,
+    hooks: Arc<Hooks>,
+    /// Set with `--journal`.
+    journal: Option<Journal>,
+    /// Set with `--lock-backend redis://..`.
+    shared_locks: Option<Arc<RedisLocks>>,
+    /// Set with `--strict`.
+    strict: bool,
+    /// Set with `--server-time`.
+    server_time: Option<ServerTime>,
+    clock_skews: Arc<Mutex<ClockSkews>>,
+    /// Workers which worked on each job.
+    job_index: Arc<Mutex<JobIndex>>,
+    cooldowns: Option<Arc<Mutex<Cooldowns>>>,
+    /// Set with `--tenants-file`.
+    tenants: Option<Arc<Tenants>>,
+}
+
+#[derive(Clone)]
+struct Applied {
+    /// Response body of [`stats::put`].
+    body: String,
+}
+
+/// Lock released from the lock table by the end of a job's lifecycle,
+/// still to be released from the shared lock store.
+struct LifecycleRelease {
+    key: String,
+    fencing_token: u64,
+    /// Whether the lock table held it.
+    released: bool,
+    fulfilled_by: Option<LockFulfillment>,
+}
+
+impl StatsIngest {
+    /// Refuses `req` if it would add a worker to a tenant which has
+    /// `max_workers` already.
+    fn check_tenant_quota(
+        &self,
+        stats: &WorkerStats,
+        worker_id: &str,
+        req: &SnarkWorkerStatsPut,
+    ) -> Result<(), PutError> {
+        let Some(tenants) = self.tenants.as_deref() else {
+            return Ok(());
+        };
+        let adds_worker = match req {
+            SnarkWorkerStatsPut::Register { resume, .. } => {
+                resume.as_deref().is_none_or(|id| !stats.contains_key(id))
+            }
+            _ => !stats.contains_key(worker_id),
+        };
+        if !adds_worker {
+            return Ok(());
+        }
+        (tenants.check_new_worker(worker_id, stats.keys())).map_err(PutError::TooManyWorkers)
+    }
+
+    /// Notes that `req` was received at `received_t`, which replaces its
+    /// `time` with `--server-time substitute`.
+    async fn receive(&self, worker_id: &str, req: &mut SnarkWorkerStatsPut, received_t: u64) {
+        let Some(server_time) = self.server_time else {
+            return;
+        };
+        // the worker id of a `Register` is its name, not its session.
+        if !matches!(req, SnarkWorkerStatsPut::Register { .. }) {
+            let mut skews = self.clock_skews.lock().await;
+            skews.observe(worker_id, req.time(), received_t);
+        }
+        if server_time == ServerTime::Substitute {
+            *req.time_mut() = received_t;
+        }
+    }
+
+    /// Applies `req` to the worker's state. `trace` is the context the
+    /// worker traces the lifecycle under, if any, `lock_namespace` the
+    /// one its job is locked in.
+    async fn apply(
+        &self,
+        worker_id: &str,
+        req: SnarkWorkerStatsPut,
+        trace: Option<TraceContext>,
+        lock_namespace: Option<&str>,
+    ) -> Result<Applied, PutError> {
+        let (applied, release) = self
+            .apply_deferred(worker_id, req, trace, lock_namespace)
+            .await?;
+        if let Some(release) = release {
+            self.finish_release(release).await;
+        }
+        Ok(applied)
+    }
+
+    /// Like [`Self::apply`], but leaves releasing the lock of a finished
+    /// job from the shared lock store to the caller.
+    async fn apply_deferred(
+        &self,
+        worker_id: &str,
+        mut req: SnarkWorkerStatsPut,
+        trace: Option<TraceContext>,
+        lock_namespace: Option<&str>,
+    ) -> Result<(Applied, Option<LifecycleRelease>), PutError> {
+        if req.truncate(self.max_field_len) {
+            debug!(
+                max_len = self.max_field_len,
+                "truncated oversized stats fields"
+            );
+        }
+        let kind = req.kind();
+        let assigned_key = match &req {
+            SnarkWorkerStatsPut::JobGetSuccess { ids, .. } => {
+                Some(lock_namespaces::qualify(lock_namespace, ids))
+            }
+            _ => None,
+        };
+        let release_key = req
+            .terminal_ids()
+            .map(|ids| lock_namespaces::qualify(lock_namespace, ids));
+        let journaled = self.journal.as_ref().map(|_| req.clone());
+
+        let mut stats = self.stats.write().await;
+        let old_state = match &req {
+            SnarkWorkerStatsPut::Register { resume, .. } => resume
+                .as_deref()
+                .and_then(|id| stats.get(id))
+                .and_then(|v| v.front()),
+            SnarkWorkerStatsPut::JobGetInit { .. } => stats.get(worker_id).and_then(|v| v.front()),
+            req => stats.target(worker_id, req),
+        };
+        let old_kind = old_state.map(|s| s.kind());
+        // a resumed session starts over, so its old state doesn't matter.
+        let checked_state = old_state.filter(|_| kind != "Register");
+        let res = match self.strict {
+            true => strict::check(&req, checked_state).map_err(PutError::Strict),
+            false => Ok(()),
+        };
+        let res = res.and_then(|()| self.check_tenant_quota(&stats, worker_id, &req));
+        let res = res.and_then(|()| stats::put(&mut stats, worker_id.to_owned(), req));
+        let result = if res.is_ok() { "accepted" } else { "rejected" };
+        self.metrics
+            .stats_events
+            .with_label_values(&[kind, result])
+            .inc();
+        let body = res?;
+        let seen = if kind == "Register" { &body } else { worker_id };
+        self.liveness.lock().await.seen(seen, timestamp::now());
+        // bumped while holding the lock, so readers of a version see
+        // every write it includes.
+        self.version.send_modify(|v| *v += 1);
+        let Some(state) = stats.latest_mut(worker_id) else {
+            if let Some(req) = journaled {
+                self.journal(|| JournalOp::Stats {
+                    worker_id: worker_id.to_owned(),
+                    req,
+                    session_id: Some(body.clone()),
+                    lease: None,
+                });
+            }
+            // `Register` applies to the worker id it assigns.
+            if let Some(state) = stats.get(&body).and_then(|v| v.front()) {
+                if let SnarkWorkerState::Registered { .. } = state {
+                    self.hooks.on_worker_registered(&body, state);
+                }
+                let old_kind = old_kind.filter(|_| state.kind() == "Restarted");
+                self.publish(&body, old_kind, state);
+            }
+            return Ok((Applied { body }, None));
+        };
+        self.anomalies.lock().await.observe(state);
+        self.top.lock().await.observe(worker_id, state);
+        self.job_index.lock().await.observe(worker_id, state);
+        if let Some(cooldowns) = &self.cooldowns {
+            let started = cooldowns
+                .lock()
+                .await
+                .observe(worker_id, state, timestamp::now());
+            if let Some(until_t) = started {
+                warn!(
+                    worker_id,
+                    until_t, "worker failed too many jobs, cooling down"
+                );
+            }
+        }
+        if let Some(lock_ttl) = &self.lock_ttl {
+            lock_ttl.lock().await.observe(state);
+        }
+        if let Some(jobs) = &self.completed_jobs {
+            if let Some(job) = CompletedJob::new(worker_id, state) {
+                jobs.lock().await.add(job);
+            }
+        }
+        if let Some(tracer) = &self.tracer {
+            tracer.lock().await.observe(worker_id, state, trace);
+        }
+        if let (Some(ids), Some(duration_ms)) = (state.ids(), state.job_duration()) {
+            let class = durations::job_class(ids, &self.job_class_separator);
+            self.durations.lock().await.observe(class, duration_ms);
+        }
+        if let Some((phase, duration_ms)) = state.completed_phase() {
+            self.metrics
+                .job_phase_duration
+                .with_label_values(&[phase])
+                .observe(duration_ms as f64 / 1000.0);
+        }
+        // the key the lock was taken under, whatever the namespace of this
+        // request.
+        let release_key = release_key.map(|key| {
+            let lease_key = state.lease().and_then(|lease| lease.key.clone());
+            lease_key.unwrap_or(key)
+        });
+        let mut release = None;
+        if assigned_key.is_some() || release_key.is_some() {
+            let keys = assigned_key.iter().chain(&release_key);
+            let mut kv = self.kv.lock_keys(keys).await;
+            if let Some(key) = assigned_key {
+                if let Some(mut lease) = kv.lease(&key, Instant::now()) {
+                    lease.key = Some(key);
+                    state.set_lease(lease);
+                }
+            }
+            // job lifecycle is over, so the lock is no longer needed.
+            if let Some(key) = release_key {
+                let fulfilled_by = LockFulfillment {
+                    worker_id: worker_id.to_owned(),
+                    job_get_init_t: state.start_time(),
+                };
+                let now = Instant::now();
+                // only under the lifecycle's own lease: had it expired and
+                // been granted to another worker, that one holds it now.
+                let fencing_token = match state.lease() {
+                    Some(lease) => Some(lease.fencing_token),
+                    None => kv
+                        .lease(&key, now)
+                        .filter(|lease| lease.holder.as_deref() == Some(worker_id))
+                        .map(|lease| lease.fencing_token),
+                };
+                release = fencing_token.map(|fencing_token| LifecycleRelease {
+                    released: kv.release_if(&key, fencing_token, Some(fulfilled_by.clone()), now),
+                    key,
+                    fencing_token,
+                    fulfilled_by: Some(fulfilled_by),
+                });
+            }
+        }
+        if let Some(req) = journaled {
+            self.journal(|| JournalOp::Stats {
+                worker_id: worker_id.to_owned(),
+                req,
+                session_id: None,
+                lease: state.lease().cloned(),
+            });
+        }
+        self.error_events.lock().await.record(worker_id, state);
+        self.publish(worker_id, old_kind, state);
+        drop(stats);
+        Ok((Applied { body }, release))
+    }
+
+    /// Releases the lock of `release` in the shared lock store too, and
+    /// tells the hooks if it was released from either. Called once the
+    /// stats and shards are unlocked, so a slow store doesn't hold up
+    /// everything else.
+    async fn finish_release(&self, release: LifecycleRelease) {
+        let LifecycleRelease {
+            key,
+            fencing_token,
+            mut released,
+            fulfilled_by,
+        } = release;
+        if let Some(shared) = &self.shared_locks {
+            match shared.release(&key, Some(fencing_token)).await {
+                Ok(shared_released) => released |= shared_released,
+                Err(err) => warn!(%err, %key, "failed to release shared lock"),
+            }
+        }
+        if released {
+            debug!(%key, "lock released");
+            self.hooks.on_lock_released(&key, fulfilled_by.as_ref());
+        }
+    }
+
+    /// Removes the worker's stats, into the archive if `archive` is set.
+    /// Returns whether the worker was known.
+    async fn deregister(&self, worker_id: &str, archive: bool) -> bool {
+        let mut stats = self.stats.write().await;
+        let Some(states) = stats.remove(worker_id) else {
+            return false;
+        };
+        self.journal(|| JournalOp::Deregister {
+            worker_id: worker_id.to_owned(),
+        });
+        self.liveness.lock().await.forget(worker_id);
+        self.clock_skews.lock().await.forget(worker_id);
+        if let Some(cooldowns) = &self.cooldowns {
+            cooldowns.lock().await.forget(worker_id);
+        }
+        self.version.send_modify(|v| *v += 1);
+        if archive {
+            self.archive.lock().await.add(ArchivedWorker {
+                worker_id: worker_id.to_owned(),
+                removed_t: timestamp::now(),
+                states,
+            });
+        }
+        self.hooks.on_worker_deregistered(worker_id);
+        true
+    }
+
+    /// Drops the worker's states but its most recent one. Returns the
+    /// number of dropped states, `None` for an unknown worker.
+    async fn clear_history(&self, worker_id: &str) -> Option<usize> {
+        let mut stats = self.stats.write().await;
+        let dropped = stats.clear_history(worker_id)?;
+        self.journal(|| JournalOp::ClearHistory {
+            worker_id: worker_id.to_owned(),
+        });
+        self.version.send_modify(|v| *v += 1);
+        Some(dropped)
+    }
+
+    /// Removes every worker's stats, leaving the locks. Returns the
+    /// number of removed workers.
+    async fn reset(&self) -> usize {
+        let mut stats = self.stats.write().await;
+        let worker_ids = stats.keys().cloned().collect::<Vec<_>>();
+        stats.clear();
+        self.journal(|| JournalOp::Reset);
+        let mut liveness = self.liveness.lock().await;
+        let mut clock_skews = self.clock_skews.lock().await;
+        for worker_id in &worker_ids {
+            liveness.forget(worker_id);
+            clock_skews.forget(worker_id);
+        }
+        drop((liveness, clock_skews));
+        if let Some(cooldowns) = &self.cooldowns {
+            let mut cooldowns = cooldowns.lock().await;
+            for worker_id in &worker_ids {
+                cooldowns.forget(worker_id);
+            }
+        }
+        self.version.send_modify(|v| *v += 1);
+        for worker_id in &worker_ids {
+            self.hooks.on_worker_deregistered(worker_id);
+        }
+        worker_ids.len()
+    }
+
+    /// Entries rebuilding the current state, and a receiver of those
+    /// recorded after them. `None` if the journal has no feed.
+    async fn replication_feed(
+        &self,
+    ) -> Option<(Vec<JournalEntry>, broadcast::Receiver<JournalEntry>)> {
+        // holding both locks, no entry is recorded in between.
+        let stats = self.stats.read().await;
+        let kv = self.kv.lock_all().await;
+        let entries = self.journal.as_ref()?.subscribe()?;
+        let t = timestamp::now();
+        let snapshot = vec![
+            JournalEntry {
+                t,
+                op: JournalOp::RestoreStats {
+                    stats: stats.snapshot(),
+                },
+            },
+            JournalEntry {
+                t,
+                op: JournalOp::RestoreLocks {
+                    locks: kv.snapshot(Instant::now()),
+                },
+            },
+        ];
+        Some((snapshot, entries))
+    }
+
+    /// Applies an entry of `replay --speed` as of its own time, returns
+    /// whether it applied.
+    async fn replay(&self, entry: JournalEntry) -> bool {
+        let mut stats = self.stats.write().await;
+        let mut kv = self.kv.lock_all().await;
+        let seen = match &entry.op {
+            JournalOp::Stats {
+                worker_id,
+                session_id,
+                ..
+            } => Some(session_id.clone().unwrap_or(worker_id.clone())),
+            _ => None,
+        };
+        let t = entry.t;
+        let applied = journal::apply_at(entry, &mut stats, &mut kv, t);
+        drop(kv);
+        if let Some(worker_id) = seen.filter(|_| applied) {
+            self.liveness.lock().await.seen(&worker_id, t);
+        }
+        self.version.send_modify(|v| *v += 1);
+        applied
+    }
+
+    /// Applies `entries` replicated from the cluster leader, dropping all
+    /// state first if `reset`. Returns the number of entries which didn't
+    /// apply.
+    async fn replicate(&self, entries: Vec<JournalEntry>, reset: bool) -> usize {
+        let mut stats = self.stats.write().await;
+        let mut kv = self.kv.lock_all().await;
+        if reset {
+            stats.clear();
+            kv.clear();
+            if let Some(journal) = &self.journal {
+                journal.compact(entries.iter().map(|e| e.op.clone()).collect());
+            }
+        }
+        let mut seen = vec![];
+        let mut rejected = 0;
+        for entry in entries {
+            if let JournalOp::Stats {
+                worker_id,
+                session_id,
+                ..
+            } = &entry.op
+            {
+                seen.push((session_id.clone().unwrap_or(worker_id.clone()), entry.t));
+            }
+            if !reset {
+                if let Some(journal) = &self.journal {
+                    journal.append(entry.clone());
+                }
+            }
+            if !journal::apply(entry, &mut stats, &mut kv) {
+                rejected += 1;
+            }
+        }
+        drop(kv);
+        let mut liveness = self.liveness.lock().await;
+        for (worker_id, t) in seen {
+            liveness.seen(&worker_id, t);
+        }
+        self.version.send_modify(|v| *v += 1);
+        rejected
+    }
+
+    /// Moves states pending since before `now - timeout_ms` into their
+    /// timeout states and releases their job's lock, unless it was taken
+    /// over already.
+    async fn time_out_pending(&self, timeout_ms: u64) {
+        let mut stats = self.stats.write().await;
+        let now = timestamp::now();
+        let timed_out = stats.time_out_pending(timeout_ms, now);
+        if timed_out.is_empty() {
+            return;
+        }
+        self.journal(|| JournalOp::TimeOut { timeout_ms, now });
+        self.version.send_modify(|v| *v += 1);
+        let mut releases = vec![];
+        for (worker_id, old_kind, state) in timed_out {
+            warn!(worker_id, kind = old_kind, "pending state timed out");
+            if let (Some(ids), Some(lease)) = (state.ids(), state.lease()) {
+                // leases recorded before their key was are of the default
+                // namespace.
+                let ids = lease.key.as_deref().unwrap_or(ids);
+                let mut kv = self.kv.lock_key(ids).await;
+                releases.push(LifecycleRelease {
+                    key: ids.to_owned(),
+                    fencing_token: lease.fencing_token,
+                    released: kv.release_if(ids, lease.fencing_token, None, Instant::now()),
+                    fulfilled_by: None,
+                });
+            }
+            self.publish(&worker_id, Some(old_kind), &state);
+        }
+        drop(stats);
+        for release in releases {
+            self.finish_release(release).await;
+        }
+    }
+
+    /// Drops states which ended more than `retention_ms` ago, and workers
+    /// left without states which weren't heard from within it either. With
+    /// `prefix`, only of the workers whose id starts with it.
+    async fn prune(&self, retention_ms: u64, prefix: Option<&str>) {
+        let min_t = timestamp::now().saturating_sub(retention_ms);
+        let pruned = {
+            let mut stats = self.stats.write().await;
+            let pruned = stats.prune(min_t, prefix);
+            if pruned.states > 0 {
+                self.journal(|| JournalOp::Prune {
+                    min_t,
+                    prefix: prefix.map(str::to_owned),
+                });
+                self.version.send_modify(|v| *v += 1);
+            }
+            pruned
+        };
+        let mut removed = 0;
+        for worker_id in pruned.emptied {
+            let last_seen_t = self
+                .liveness
+                .lock()
+                .await
+                .get(&worker_id, min_t)
+                .last_seen_t;
+            if last_seen_t.is_none_or(|t| t < min_t) && self.deregister(&worker_id, false).await {
+                removed += 1;
+            }
+        }
+        if pruned.states > 0 {
+            info!(
+                states = pruned.states,
+                workers = removed,
+                "pruned old stats"
+            );
+        }
+    }
+
+    /// Folds states which ended more than `after_ms` ago into hourly
+    /// aggregates.
+    async fn compact(&self, after_ms: u64) {
+        let min_t = timestamp::now().saturating_sub(after_ms);
+        let mut stats = self.stats.write().await;
+        let compacted = stats.compact(min_t);
+        if compacted > 0 {
+            self.journal(|| JournalOp::Compact { min_t });
+            self.version.send_modify(|v| *v += 1);
+            info!(states = compacted, "compacted old stats");
+        }
+    }
+
+    fn journal(&self, op: impl FnOnce() -> JournalOp) {
+        if let Some(journal) = &self.journal {
+            journal.record(op());
+        }
+    }
+
+    fn publish(&self, worker_id: &str, old_kind: Option<&str>, state: &SnarkWorkerState) {
+        if self.transitions.receiver_count() == 0 && self.hooks.is_empty() {
+            return;
+        }
+        let transition = Transition {
+            worker_id: worker_id.to_owned(),
+            old_kind: old_kind.map(str::to_owned),
+            new_kind: state.kind().to_owned(),
+            state: state.clone(),
+            applied_t: timestamp::now(),
+        };
+        self.hooks.on_state_transition(&transition);
+        let _ = self.transitions.send(transition);
+    }
+}
+
+/// Entity tag of a stats GET response computed at stats `version`. Every
+/// mutation bumps the version, so an unchanged tag means an unchanged
+/// response for the same query and format.
+fn stats_etag(version: u64, format: Option<ExportFormat>) -> String {
+    match format {
+        None | Some(ExportFormat::Json) => format!("\"{version}\""),
+        Some(ExportFormat::Csv) => format!("\"{version}-csv\""),
+        Some(ExportFormat::Parquet) => format!("\"{version}-parquet\""),
+    }
+}
+
+/// Whether an `If-None-Match` header matches `etag`, so the GET can be
+/// answered with a 304. Weak tags compare equal to strong ones.
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    let Some(if_none_match) = if_none_match else {
+        return false;
+    };
+    (if_none_match.split(','))
+        .map(|tag| tag.trim())
+        .any(|tag| tag == "*" || tag.trim_start_matches("W/") == etag)
+}
+
+/// Body-less 304 with the current `etag`.
+fn not_modified_reply(etag: String) -> warp::reply::Response {
+    let reply = with_status(String::new(), StatusCode::NOT_MODIFIED);
+    warp::reply::with_header(reply, "etag", etag).into_response()
+}
+
+/// Waits until the ingest queue applied the event at `min_version`,
+/// returns `false` if it doesn't within [`CONSISTENCY_DEADLINE`].
+async fn wait_for_version(mut version: watch::Receiver<u64>, min_version: u64) -> bool {
+    let reached = async {
+        loop {
+            if *version.borrow_and_update() >= min_version {
+                return true;
+            }
+            if version.changed().await.is_err() {
+                return false;
+            }
+        }
+    };
+    tokio::time::timeout(CONSISTENCY_DEADLINE, reached)
+        .await
+        .unwrap_or(false)
+}
+
+/// Whether this instance serves writes, which standbys leave background
+/// changes to the primary for.
+async fn is_primary(role: &Mutex<Role>) -> bool {
+    *role.lock().await == Role::Primary
+}
+
+/// Locks all `keys` in the shared lock store if there is one, mirroring
+/// the grant into `kv`, or in `kv` alone otherwise.
+async fn acquire_all(
+    kv: &mut LockedShards<'_>,
+    shared: Option<&RedisLocks>,
+    keys: Vec<String>,
+    lock: JobLock,
+    ticket: Option<&Arc<WaitTicket>>,
+    now: Instant,
+) -> RedisResult<Result<u64, LockJobsConflict>> {
+    // only long-polls retrying after being refused hold a ticket, their
+    // conflict was counted with the first attempt.
+    let retry = ticket.is_some();
+    let queued = kv.queued_ahead(&keys, ticket, now);
+    if !queued.is_empty() {
+        if !retry {
+            kv.add_conflict(queued.keys(), lock.holder.as_deref());
+        }
+        return Ok(Err(LockJobsConflict { conflicts: queued }));
+    }
+    let Some(shared) = shared else {
+        let conflicts = kv.held(&keys, now);
+        if retry && !conflicts.is_empty() {
+            return Ok(Err(LockJobsConflict { conflicts }));
+        }
+        return Ok(kv.try_acquire_all(keys, lock, now));
+    };
+    let ttl_ms = lock.expires_at.saturating_duration_since(now).as_millis() as u64;
+    match shared
+        .acquire(&keys, lock.holder.as_deref(), ttl_ms)
+        .await?
+    {
+        Ok(fencing_token) => {
+            kv.grant(
+                keys,
+                JobLock {
+                    fencing_token,
+                    ..lock
+                },
+                now,
+            );
+            Ok(Ok(fencing_token))
+        }
+        Err(conflicts) => {
+            if !retry {
+                kv.add_conflict(conflicts.keys(), lock.holder.as_deref());
+            }
+            Ok(Err(LockJobsConflict { conflicts }))
+        }
+    }
+}
+
+/// Locks `key` if `condition` holds, see [`LockedShards::try_acquire_if`].
+/// Only holders of the lock skip the acquirers waiting for the key.
+async fn acquire_if(
+    kv: &mut LockedShards<'_>,
+    shared: Option<&RedisLocks>,
+    key: &str,
+    lock: JobLock,
+    condition: LockCondition,
+    now: Instant,
+) -> RedisResult<Result<u64, Option<LockJobHeld>>> {
+    if condition == LockCondition::Unheld {
+        let keys = [key.to_owned()];
+        if let Some((_, held)) = kv.queued_ahead(&keys, None, now).pop_first() {
+            kv.add_conflict(&keys, lock.holder.as_deref());
+            return Ok(Err(Some(held)));
+        }
+    }
+    let Some(shared) = shared else {
+        return Ok(kv.try_acquire_if(key.to_owned(), lock, condition, now));
+    };
+    let ttl_ms = lock.expires_at.saturating_duration_since(now).as_millis() as u64;
+    let holder = lock.holder.as_deref();
+    match shared.acquire_if(key, holder, ttl_ms, condition).await? {
+        Ok(fencing_token) => {
+            let lock = JobLock {
+                fencing_token,
+                ..lock
+            };
+            match condition {
+                LockCondition::Bump(t) if t == fencing_token => {
+                    kv.extend(key.to_owned(), lock, now)
+                }
+                _ => kv.grant(vec![key.to_owned()], lock, now),
+            }
+            Ok(Ok(fencing_token))
+        }
+        Err(held) => {
+            kv.add_conflict([&key.to_owned()], holder);
+            Ok(Err(held))
+        }
+    }
+}
+
+/// Drops the lock of `key` whoever holds it, recording it as purged.
+/// Returns whether it was locked.
+async fn purge_lock(
+    kv: &LockShards,
+    shared: Option<&RedisLocks>,
+    hooks: &Hooks,
+    key: &str,
+) -> Result<bool, RedisError> {
+    let mut kv = kv.lock_key(key).await;
+    let mut released = kv.purge(key);
+    if let Some(shared) = shared {
+        released |= shared.release(key, None).await?;
+    }
+    drop(kv);
+    if released {
+        hooks.on_lock_released(key, None);
+    }
+    Ok(released)
+}
+
+/// Who asks to release a lock.
+enum LockOwner {
+    FencingToken(u64),
+    Holder(String),
+}
+
+/// Releases the lock of `key` if `owner` holds it, or replies why not.
+async fn release_owned_lock(
+    kv: &LockShards,
+    shared: Option<&RedisLocks>,
+    hooks: &Hooks,
+    key: &str,
+    owner: &LockOwner,
+) -> Result<Result<(), WithStatus<String>>, RedisError> {
+    let mut kv = kv.lock_key(key).await;
+    let now = Instant::now();
+    let lease = kv.lease(key, now);
+    let fencing_token = match (owner, &lease) {
+        (LockOwner::FencingToken(token), _) => *token,
+        (LockOwner::Holder(holder), Some(lease)) if lease.holder.as_ref() == Some(holder) => {
+            lease.fencing_token
+        }
+        (LockOwner::Holder(_), Some(lease)) => {
+            let msg = match &lease.holder {
+                Some(holder) => format!("{key} is held by {holder}"),
+                None => format!("{key} is held"),
+            };
+            return Ok(Err(error_reply(ErrorCode::LockHeld, msg)));
+        }
+        (LockOwner::Holder(_), None) if shared.is_none() => {
+            return Ok(Err(error_reply(
+                ErrorCode::LockNotFound,
+                format!("{key} isn't locked"),
+            )));
+        }
+        (LockOwner::Holder(_), None) => {
+            let msg = format!("{key} isn't locked here, release it by its fencing_token");
+            return Ok(Err(error_reply(ErrorCode::LockNotFound, msg)));
+        }
+    };
+    let mut released = kv.release_if(key, fencing_token, None, now);
+    if let Some(shared) = shared {
+        released |= shared.release(key, Some(fencing_token)).await?;
+    }
+    drop(kv);
+    if released {
+        hooks.on_lock_released(key, None);
+        return Ok(Ok(()));
+    }
+    Ok(Err(match lease {
+        Some(_) => {
+            let msg = format!("stale fencing token for {key}: {fencing_token}");
+            error_reply(ErrorCode::StaleFencingToken, msg)
+        }
+        None => error_reply(ErrorCode::LockNotFound, format!("{key} isn't locked")),
+    }))
+}
+
+fn lock_backend_reply(err: RedisError) -> WithStatus<String> {
+    warn!(%err, "lock backend unavailable");
+    let msg = format!("lock backend unavailable: {err}");
+    error_reply(ErrorCode::LockBackendUnavailable, msg)
+}
+
+/// 429 with `Retry-After` if the ingest queue is saturated, see
+/// [`Backpressure`].
+fn saturated_reply(queue: &IngestQueue) -> Option<warp::reply::Response> {
+    let retry_after_s = queue.saturated()?;
+    debug!(retry_after_s, "ingestion saturated, shedding load");
+    let reply = error_reply(ErrorCode::IngestSaturated, "ingestion saturated");
+    let reply = warp::reply::with_header(reply, "retry-after", retry_after_s.to_string());
+    Some(reply.into_response())
+}
+
+/// 503 for events which can't be queued, the task applying them having
+/// stopped.
+fn ingest_stopped_reply() -> warp::reply::Response {
+    warn!("ingest task stopped");
+    error_reply(ErrorCode::IngestStopped, "ingest task stopped").into_response()
+}
+
+/// Queues `events` of `worker_id` to be applied in order, with no other
+/// request's events in between, replying with whether each was queued. A
+/// batch retried under its `idempotency_key` gets the reply of the
+/// original.
+async fn put_batch(
+    queue: &IngestQueue,
+    quarantine: Option<&Mutex<Quarantine>>,
+    worker_id: &str,
+    events: Vec<serde_json::Value>,
+    trace: Option<TraceContext>,
+    lock_namespace: Option<&str>,
+    idempotency_key: Option<String>,
+) -> warp::reply::Response {
+    let mut results = Vec::with_capacity(events.len());
+    let mut queued = vec![];
+    for (index, event) in events.into_iter().enumerate() {
+        match SnarkWorkerStatsPut::deserialize(&event) {
+            Err(err) => {
+                let msg = err.to_string();
+                let code = ErrorCode::InvalidBody;
+                let payload = event.to_string();
+                quarantine_payload(quarantine, worker_id, code, &msg, payload.as_bytes()).await;
+                results.push(BatchItem::failed(index, code, msg));
+            }
+            Ok(SnarkWorkerStatsPut::Register { .. }) => {
+                let code = ErrorCode::UnsupportedEvent;
+                let msg = "Register can't be batched".to_owned();
+                results.push(BatchItem::failed(index, code, msg));
+            }
+            Ok(req) => match queue.check(&req) {
+                Ok(()) => {
+                    results.push(BatchItem::succeeded(index, 202, None));
+                    queued.push((req, event.to_string()));
+                }
+                Err(err) => results.push(BatchItem::rejected(index, err.to_api_error())),
+            },
+        }
+    }
+    let reqs = queued
+        .iter()
+        .map(|(req, payload)| (req.clone(), payload.as_bytes()))
+        .collect();
+    let ack = queue
+        .queue(worker_id, idempotency_key, reqs, trace, lock_namespace)
+        .await;
+    let Ok(ack) = ack else {
+        return ingest_stopped_reply();
+    };
+    let res = BatchResponse::<SnarkWorkerState>::new(results);
+    if res.summary.failed > 0 {
+        debug!(failed = res.summary.failed, "batch partially rejected");
+    }
+    let reply = with_status(
+        serde_json::to_string(&res).unwrap(),
+        StatusCode::from_u16(200).unwrap(),
+    );
+    let reply = warp::reply::with_header(reply, STATS_VERSION_HEADER, ack.version.to_string());
+    if !ack.replayed {
+        return reply.into_response();
+    }
+    debug!("replayed the ack of the idempotency key");
+    warp::reply::with_header(reply, idempotency::REPLAYED_HEADER, "true").into_response()
+}
 
-    let routes = lock_job_put
-        .or(worker_stats_put)
-        .or(workers_get)
-        .or(worker_stats_get);
-    warp::serve(routes).run(([0, 0, 0, 0], opts.port)).await;
+/// Queues the NDJSON events of `body` as their lines arrive. Only failed
+/// lines are listed in the reply, so long-lived streams don't pile up an
+/// item per event, `index` being the line's position among non-empty ones.
+async fn put_stream<S, B>(
+    queue: &IngestQueue,
+    quarantine: Option<&Mutex<Quarantine>>,
+    worker_id: &str,
+    mut body: S,
+    max_line_len: u64,
+    trace: Option<TraceContext>,
+    lock_namespace: Option<&str>,
+) -> warp::reply::Response
+where
+    S: Stream<Item = Result<B, warp::Error>> + Unpin,
+    B: Buf,
+{
+    let mut version = queue.version();
+    let mut summary = BatchSummary::default();
+    let mut failed = vec![];
+    let mut buf = Vec::new();
+    let mut done = false;
+    while !done {
+        match body.next().await {
+            Some(Ok(mut chunk)) => {
+                while chunk.has_remaining() {
+                    let bytes = chunk.chunk();
+                    buf.extend_from_slice(bytes);
+                    let len = bytes.len();
+                    chunk.advance(len);
+                }
+            }
+            Some(Err(err)) => {
+                // the events up to here are queued, so the client learns
+                // which ones if it's still listening.
+                warn!(%err, "stats stream broke off");
+                done = true;
+            }
+            None => {
+                // the last line needn't end with a newline.
+                buf.push(b'\n');
+                done = true;
+            }
+        }
+        let mut start = 0;
+        while let Some(end) = buf[start..].iter().position(|b| *b == b'\n') {
+            let line = buf[start..start + end].trim_ascii();
+            start += end + 1;
+            if line.is_empty() {
+                continue;
+            }
+            let index = summary.total;
+            summary.total += 1;
+            let item = match serde_json::from_slice::<SnarkWorkerStatsPut>(line) {
+                Err(err) => {
+                    let msg = err.to_string();
+                    let code = ErrorCode::InvalidBody;
+                    quarantine_payload(quarantine, worker_id, code, &msg, line).await;
+                    BatchItem::failed(index, code, msg)
+                }
+                Ok(SnarkWorkerStatsPut::Register { .. }) => {
+                    let code = ErrorCode::UnsupportedEvent;
+                    let msg = "Register can't be streamed".to_owned();
+                    BatchItem::failed(index, code, msg)
+                }
+                Ok(req) => match queue.check(&req) {
+                    Ok(()) => {
+                        let reqs = vec![(req, line)];
+                        let ack = queue.queue(worker_id, None, reqs, trace, lock_namespace);
+                        let Ok(ack) = ack.await else {
+                            return ingest_stopped_reply();
+                        };
+                        version = ack.version;
+                        summary.succeeded += 1;
+                        continue;
+                    }
+                    Err(err) => BatchItem::rejected(index, err.to_api_error()),
+                },
+            };
+            summary.failed += 1;
+            failed.push(item);
+        }
+        buf.drain(..start);
+        if buf.len() as u64 > max_line_len {
+            let index = summary.total;
+            summary.total += 1;
+            summary.failed += 1;
+            let msg = format!("line too long! max: {max_line_len}");
+            failed.push(BatchItem::failed(index, ErrorCode::BodyTooLarge, msg));
+            // the rest of the line can't be told from the next ones.
+            break;
+        }
+    }
+    if summary.failed > 0 {
+        debug!(failed = summary.failed, "stream partially rejected");
+    }
+    let res = BatchResponse::<serde_json::Value> {
+        summary,
+        results: failed,
+    };
+    let reply = with_status(
+        serde_json::to_string(&res).unwrap(),
+        StatusCode::from_u16(200).unwrap(),
+    );
+    warp::reply::with_header(reply, STATS_VERSION_HEADER, version.to_string()).into_response()
+}
+
+/// Locks each of `keys` independently, for lock-jobs with `partial=true`.
+/// Keys are checked against the namespace's `max_keys` and the worker's
+/// `quota` one by one, so those within them are granted. `kv` has to hold
+/// the shards [lock_for_limits] locks.
+#[allow(clippy::too_many_arguments)]
+async fn lock_each(
+    kv: &mut LockedShards<'_>,
+    shared: Option<&RedisLocks>,
+    metrics: &Metrics,
+    hooks: &Hooks,
+    namespace: Option<&str>,
+    limits: &NamespaceLimits,
+    quota: Option<(&str, usize)>,
+    keys: Vec<String>,
+    holder: Option<String>,
+    timeout: Duration,
+    max_key_len: usize,
+) -> BatchResponse<serde_json::Value> {
+    let now = Instant::now();
+    let mut results = Vec::with_capacity(keys.len());
+    for (index, key) in keys.into_iter().enumerate() {
+        let len = key.len();
+        if len > max_key_len {
+            let msg = format!("key too long! max: {max_key_len}, found: {len}");
+            results.push(BatchItem::failed(index, ErrorCode::KeyTooLong, msg));
+            continue;
+        }
+        let key = lock_namespaces::qualify(namespace, &key);
+        let keys = std::slice::from_ref(&key);
+        let checked = check_worker_lock_quota(kv, quota, keys, now)
+            .and_then(|()| check_namespace_limit(kv, namespace, limits, keys, now));
+        if let Err(err) = checked {
+            results.push(BatchItem::rejected(index, err));
+            continue;
+        }
+        let lock = JobLock::new(now + timeout, holder.clone());
+        let item = match acquire_all(kv, shared, vec![key.clone()], lock, None, now).await {
+            Ok(Ok(fencing_token)) => {
+                metrics.lock_acquisitions.inc();
+                hooks.on_lock_acquired(&[key], fencing_token, holder.as_deref());
+                let granted = LockJobGranted { fencing_token };
+                BatchItem::succeeded(index, 201, serde_json::to_value(granted).ok())
+            }
+            Ok(Err(mut conflict)) => {
+                metrics.lock_conflicts.inc();
+                let held = conflict.conflicts.remove(&key);
+                let msg = match held.as_ref().and_then(|held| held.holder.as_ref()) {
+                    Some(holder) => format!("{key} is locked by {holder}"),
+                    None => format!("{key} is locked"),
+                };
+                BatchItem::failed(index, ErrorCode::LockHeld, msg)
+                    .with_result(serde_json::to_value(held).unwrap())
+            }
+            Err(err) => {
+                let msg = format!("lock backend unavailable: {err}");
+                BatchItem::failed(index, ErrorCode::LockBackendUnavailable, msg)
+            }
+        };
+        results.push(item);
+    }
+    BatchResponse::new(results)
+}
+
+/// Updates gauges and counters which are only computed on export.
+async fn refresh_gauges(kv: &LockShards, stats: &RwLock<WorkerStats>, metrics: &Metrics) {
+    {
+        let stats = stats.read().await;
+        metrics.registered_workers.set(stats.len() as i64);
+        metrics.worker_states.set(stats.total_states() as i64);
+        let evictions = stats.evictions();
+        for (reason, evicted) in [
+            ("max_states_per_worker", evictions.max_states_per_worker),
+            ("max_total_states", evictions.max_total_states),
+            ("retention", evictions.retention),
+            ("compacted", evictions.compacted),
+        ] {
+            let counter = metrics.evicted_states.with_label_values(&[reason]);
+            counter.inc_by(evicted.saturating_sub(counter.get()));
+        }
+    }
+    // expired locks count as released before they're swept.
+    let active_locks = kv.count_locks(Instant::now()).await;
+    metrics.active_locks.set(active_locks as i64);
+}
+
+/// Error response if `token` isn't the session token of the worker.
+async fn check_worker_token(
+    tokens: Option<&Mutex<WorkerTokens>>,
+    worker_id: &str,
+    token: Option<&str>,
+) -> Result<(), warp::reply::Response> {
+    let Some(tokens) = tokens else {
+        return Ok(());
+    };
+    tokens.lock().await.verify(worker_id, token).map_err(|err| {
+        debug!(?err, "worker token rejected");
+        error_reply(err.code(), err.message()).into_response()
+    })
+}
+
+/// Keeps a rejected stats payload if `--quarantine` is set, returns the
+/// id it's kept under.
+async fn quarantine_payload(
+    quarantine: Option<&Mutex<Quarantine>>,
+    worker_id: &str,
+    code: ErrorCode,
+    error: &str,
+    payload: &[u8],
+) -> Option<u64> {
+    let id = quarantine?
+        .lock()
+        .await
+        .add(worker_id, code, error, payload);
+    debug!(id, "stats payload quarantined");
+    Some(id)
+}
+
+/// Error response telling where the rejected payload was quarantined.
+fn quarantined_reply(err: ApiError, id: Option<u64>) -> warp::reply::Response {
+    let reply = api_error_reply(&err);
+    match id {
+        Some(id) => {
+            warp::reply::with_header(reply, quarantine::HEADER, id.to_string()).into_response()
+        }
+        None => reply.into_response(),
+    }
+}
+
+/// Lock namespace named by the request's header, or an error response.
+fn lock_namespace(header: Option<&str>) -> Result<Option<&str>, WithStatus<String>> {
+    lock_namespaces::parse(header).map_err(|msg| {
+        debug!("{msg}");
+        error_reply(ErrorCode::InvalidParameter, msg)
+    })
+}
+
+/// Error response if `keys` of a lock-jobs request are empty, which would
+/// take a fencing token for nothing, or name a key twice.
+fn check_lock_keys(keys: &[String]) -> Result<(), WithStatus<String>> {
+    let mut seen = HashSet::new();
+    let msg = match keys.iter().find(|key| !seen.insert(*key)) {
+        _ if keys.is_empty() => "no keys to lock".to_owned(),
+        Some(key) => format!("duplicate key {key}"),
+        None => return Ok(()),
+    };
+    debug!("{msg}");
+    Err(error_reply(ErrorCode::InvalidParameter, msg))
+}
+
+/// Locks the shards of `keys`, or all of them if `limits` has a
+/// `max_keys` or the worker a `quota`, which count the locks of every
+/// shard.
+async fn lock_for_limits<'a>(
+    kv: &'a LockShards,
+    keys: &[String],
+    limits: &NamespaceLimits,
+    quota: Option<(&str, usize)>,
+) -> LockedShards<'a> {
+    match (limits.max_keys, quota) {
+        (None, None) => kv.lock_keys(keys).await,
+        _ => kv.lock_all().await,
+    }
+}
+
+/// Error if locking `keys` would exceed the `max_keys` of `namespace`.
+///
+/// Keys held already don't count, they're refused or kept rather than
+/// added. `kv` has to hold the shards [lock_for_limits] locks, so no lock
+/// can be taken between the check and the acquisition.
+fn check_namespace_limit(
+    kv: &LockedShards,
+    namespace: Option<&str>,
+    limits: &NamespaceLimits,
+    keys: &[String],
+    now: Instant,
+) -> Result<(), ApiError> {
+    let Some(max_keys) = limits.max_keys else {
+        return Ok(());
+    };
+    let namespace = namespace.unwrap_or(lock_namespaces::DEFAULT);
+    let held = kv.count_held(namespace, now);
+    let added = keys
+        .iter()
+        .filter(|key| kv.lease(key, now).is_none())
+        .count();
+    if held + added <= max_keys {
+        return Ok(());
+    }
+    let msg = format!("lock namespace {namespace} holds {held} of max {max_keys} locks");
+    debug!("{msg}");
+    Err(ApiError::new(ErrorCode::LockNamespaceFull, msg))
+}
+
+/// Worker and `--max-locks-per-worker` to check locks by `holder`
+/// against. Anonymous locks aren't counted.
+fn worker_lock_quota(holder: Option<&str>, max_locks: Option<usize>) -> Option<(&str, usize)> {
+    holder.zip(max_locks)
+}
+
+/// Refuses locking `keys` to the worker of `quota` if it would hold more
+/// than its max with them. Keys it holds already don't count, they're
+/// renewed or refused rather than added. Like [check_namespace_limit],
+/// `kv` has to hold the shards [lock_for_limits] locks.
+fn check_worker_lock_quota(
+    kv: &LockedShards,
+    quota: Option<(&str, usize)>,
+    keys: &[String],
+    now: Instant,
+) -> Result<(), ApiError> {
+    let Some((holder, max_locks)) = quota else {
+        return Ok(());
+    };
+    let held = kv.count_held_by(holder, now);
+    let added = (keys.iter().collect::<HashSet<_>>().into_iter())
+        .filter(|key| {
+            let lease = kv.lease(key, now);
+            lease.is_none_or(|lease| lease.holder.as_deref() != Some(holder))
+        })
+        .count();
+    if held + added <= max_locks {
+        return Ok(());
+    }
+    let msg = format!("{holder} holds {held} of max {max_locks} locks");
+    debug!("{msg}");
+    let mut err = ApiError::new(ErrorCode::LockQuotaExceeded, msg);
+    err.held = Some(held);
+    Err(err)
+}
+
+/// Refuses locks to `holder` while it, or a session of its name, cools
+/// down after failing too many jobs.
+async fn check_cooldown(
+    cooldowns: Option<&Mutex<Cooldowns>>,
+    stats: &RwLock<WorkerStats>,
+    holder: Option<&str>,
+) -> Result<(), WithStatus<String>> {
+    let (Some(cooldowns), Some(holder)) = (cooldowns, holder) else {
+        return Ok(());
+    };
+    let sessions = stats
+        .read()
+        .await
+        .sessions(holder)
+        .map(str::to_owned)
+        .collect::<Vec<_>>();
+    let now = timestamp::now();
+    let cooldowns = cooldowns.lock().await;
+    let until_t = std::iter::once(holder)
+        .chain(sessions.iter().map(String::as_str))
+        .filter_map(|worker_id| cooldowns.until(worker_id, now))
+        .max();
+    let Some(until_t) = until_t else {
+        return Ok(());
+    };
+    let msg = format!("{holder} is cooling down after failing too many jobs");
+    debug!("{msg}");
+    let mut err = ApiError::new(ErrorCode::WorkerCoolingDown, msg);
+    err.cooldown_until_t = Some(until_t);
+    Err(api_error_reply(&err))
+}
+
+/// TTL in ms of a lock requested for `keys` without a timeout. With
+/// `auto_ttl`, the largest TTL suggested for the keys' job classes, if any.
+/// Otherwise the one `lock_ttl` settled on, if set.
+async fn default_lock_ttl(
+    durations: &Mutex<DurationModel>,
+    lock_ttl: Option<&Mutex<LockTtlController>>,
+    auto_ttl: bool,
+    separator: &str,
+    keys: &[String],
+    default_timeout_ms: u64,
+) -> u64 {
+    let default_timeout_ms = match lock_ttl {
+        Some(lock_ttl) => lock_ttl.lock().await.ttl_ms(),
+        None => default_timeout_ms,
+    };
+    if !auto_ttl {
+        return default_timeout_ms;
+    }
+    let durations = durations.lock().await;
+    keys.iter()
+        .filter_map(|key| durations.suggest_ttl(durations::job_class(key, separator)))
+        .max()
+        .unwrap_or(default_timeout_ms)
+}
+
+/// Gives the keys of `tenants` write access confined to their workers and
+/// lock namespace. Without `--groups-file` the keys of `--api-keys-file`
+/// keep seeing every worker.
+fn add_tenants(
+    tenants: &Tenants,
+    groups_config: &mut Option<GroupsConfig>,
+    api_keys: &mut Option<ApiKeys>,
+    namespaces: &mut LockNamespaces,
+) -> Result<(), String> {
+    let api_keys = api_keys.get_or_insert_with(ApiKeys::default);
+    let groups_config = groups_config.get_or_insert_with(|| GroupsConfig {
+        keys: api_keys
+            .keys
+            .keys()
+            .map(|key| (key.clone(), groups::Role::Admin))
+            .collect(),
+        teams: HashMap::new(),
+    });
+    for (name, tenant) in tenants.iter() {
+        let key = tenant.api_key.trim().to_owned();
+        if api_keys.keys.contains_key(&key) || groups_config.keys.contains_key(&key) {
+            return Err(format!("api key of tenant {name} is already in use"));
+        }
+        let api_key = ApiKey {
+            name: format!("tenant {name}"),
+            access: Access::Write,
+        };
+        api_keys.keys.insert(key.clone(), api_key);
+        let tenant_role = groups::Role::Tenant {
+            tenant: name.to_owned(),
+        };
+        groups_config.keys.insert(key, tenant_role);
+        if let Some(max_keys) = tenant.max_keys {
+            namespaces.limits_mut(name).max_keys = Some(max_keys);
+        }
+    }
+    Ok(())
+}
+
+/// Workers visible to the caller. Everything is visible when no groups
+/// are configured; `None` means the caller couldn't be authenticated.
+fn caller_scope(groups: Option<&GroupsConfig>, authorization: Option<&str>) -> Option<Scope> {
+    match groups {
+        Some(groups) => groups.scope(authorization),
+        None => Some(Scope::All),
+    }
+}
+
+#[tokio::main]
+async fn main() {
+    let args = config::args(&Opts::clap()).unwrap_or_else(|err| {
+        eprintln!("failed to load config: {err}");
+        std::process::exit(2);
+    });
+    let mut opts = Opts::from_iter(args);
+    let set_log_filter = init_logging(&opts.log_level, opts.log_json);
+    if let Some(path) = &opts.config {
+        info!(path = %path.display(), "loaded options from config file");
+    }
+    if let Err(err) = opts.check_timeouts() {
+        eprintln!("{err}");
+        std::process::exit(2);
+    }
+    if opts.max_timeout.is_some() {
+        warn!("--max-timeout is deprecated, use --max-timeout-ms");
+    }
+    let replay = match opts.cmd.take() {
+        Some(Command::Admin(cmd)) => {
+            let ok = admin::run(cmd).await;
+            std::process::exit(if ok { 0 } else { 1 });
+        }
+        Some(Command::SelfTest { url, api_key }) => {
+            let passed = self_test::run(url, api_key).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(Command::Diff {
+            left,
+            right,
+            api_key,
+            save,
+        }) => {
+            let identical = diff::run(left, right, api_key, save).await;
+            std::process::exit(if identical { 0 } else { 1 });
+        }
+        Some(Command::E2e { workers, jobs }) => {
+            let passed = e2e::run(workers, jobs).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(Command::Simulate(opts)) => {
+            let passed = simulate::run(opts).await;
+            std::process::exit(if passed { 0 } else { 1 });
+        }
+        Some(Command::Replay(replay)) => {
+            if opts.journal.is_some() || opts.cluster.is_some() || opts.standby_of.is_some() {
+                eprintln!("replay can't be combined with --journal, --cluster or --standby-of");
+                std::process::exit(2);
+            }
+            if replay
+                .speed
+                .is_some_and(|speed| speed.is_nan() || speed <= 0.0)
+            {
+                eprintln!("replay --speed must be positive");
+                std::process::exit(2);
+            }
+            Some(replay)
+        }
+        Some(Command::Serve) | None => None,
+    };
+
+    let max_wait = opts.max_wait;
+    let max_locks_per_worker = opts.max_locks_per_worker;
+    let max_key_len = opts.max_key_len;
+    let auto_ttl = opts.auto_ttl;
+    let job_class_separator = Arc::new(opts.job_class_separator.clone());
+    let limits = opts.limits();
+    info!(profile = ?opts.profile, ?limits, "limits");
+    let max_body_size = limits.max_body_size;
+    let mut groups_config = opts
+        .groups_file
+        .as_deref()
+        .map(GroupsConfig::load)
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load groups file: {err}"));
+    let mut api_keys = opts
+        .api_keys_file
+        .as_deref()
+        .map(ApiKeys::load)
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load api keys file: {err}"));
+    let tenants = opts
+        .tenants_file
+        .as_deref()
+        .map(|path| Tenants::load(path).map(Arc::new))
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load tenants file: {err}"));
+    let mut lock_namespaces = opts
+        .lock_namespaces_file
+        .as_deref()
+        .map(LockNamespaces::load)
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load lock namespaces file: {err}"))
+        .unwrap_or_default();
+    if let Some(tenants) = &tenants {
+        add_tenants(
+            tenants,
+            &mut groups_config,
+            &mut api_keys,
+            &mut lock_namespaces,
+        )
+        .unwrap_or_else(|err| panic!("failed to load tenants file: {err}"));
+        opts.protect_reads = true;
+        info!(tenants = tenants.iter().count(), "loaded tenants");
+    }
+    let groups_config = groups_config.map(Arc::new);
+    let api_keys = api_keys.map(Arc::new);
+    let lock_namespaces = Arc::new(lock_namespaces);
+    let compat = opts
+        .compat_file
+        .as_deref()
+        .map(CompatConfig::load)
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load compat file: {err}"))
+        .map(Arc::new);
+    let assignment_policy = opts
+        .assignment_policy
+        .as_deref()
+        .map(AssignmentPolicy::load)
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load assignment policy: {err}"))
+        .map(Arc::new);
+
+    let lock_table = LockTable::new(limits.lock_history_len).with_hot_keys(HotKeyConfig {
+        window_ms: opts.hot_key_window.saturating_mul(1000),
+        min_conflicts: opts.hot_key_min_conflicts,
+        min_conflicts_per_acquisition: opts.hot_key_ratio,
+    });
+    let table = Arc::new(LockShards::new(lock_table, opts.lock_shards));
+    let mut stats = WorkerStats::new()
+        .with_collapsed_errors(opts.collapse_errors)
+        .with_max_states(limits.max_states_per_worker, opts.fold_evicted_states)
+        .with_compaction(opts.compact_after.is_some())
+        .with_max_total_states(limits.max_total_states);
+    // entries `replay --speed` applies while serving.
+    let mut replay_entries = vec![];
+    if let Some(replay) = &replay {
+        let until = replay.until.map(|until| until.0);
+        let entries = replay::load(&replay.path, until)
+            .unwrap_or_else(|err| panic!("failed to load replay: {err}"));
+        match replay.speed {
+            Some(_) => replay_entries = entries,
+            None => {
+                let mut kv = table.lock_all().await;
+                let replayed = replay::apply(entries, &mut stats, &mut kv);
+                info!(
+                    entries = replayed.entries,
+                    rejected = replayed.rejected,
+                    workers = stats.len(),
+                    locks = kv.len(),
+                    "replayed journal"
+                );
+            }
+        }
+    }
+    let mut journal = match &opts.journal {
+        Some(path) => {
+            let migrated = migrations::migrate_journal(path)
+                .unwrap_or_else(|err| panic!("failed to migrate journal: {err}"));
+            if let Some(backup) = migrated {
+                info!(backup = %backup.display(), "migrated journal");
+            }
+            let mut kv = table.lock_all().await;
+            let replayed = journal::replay(path, &mut stats, &mut kv)
+                .unwrap_or_else(|err| panic!("failed to replay journal: {err}"));
+            info!(
+                entries = replayed.entries,
+                rejected = replayed.rejected,
+                workers = stats.len(),
+                locks = kv.len(),
+                "replayed journal"
+            );
+            let journal = Journal::open(path.clone())
+                .await
+                .unwrap_or_else(|err| panic!("failed to open journal: {err}"));
+            Some(journal)
+        }
+        None => None,
+    };
+    if opts.cluster.is_some() {
+        let fed = journal.unwrap_or_default();
+        journal = Some(fed.with_feed(REPLICATION_FEED_CAPACITY));
+    }
+    if let Some(journal) = &journal {
+        table.lock_all().await.set_journal(journal.clone());
+    }
+    let shared_locks = match &opts.lock_backend {
+        LockBackend::Memory => None,
+        LockBackend::Redis(url) => {
+            let shared = RedisLocks::connect(url)
+                .await
+                .unwrap_or_else(|err| panic!("failed to connect to lock backend: {err}"));
+            Some(Arc::new(shared))
+        }
+    };
+    let worker_stats = Arc::new(RwLock::new(stats));
+    let metrics_registry = Arc::new(Metrics::new());
+    let webhook = opts
+        .webhook_url
+        .clone()
+        .map(|url| match &opts.webhook_template {
+            Some(path) => Webhook::new(url).with_template(path),
+            None if opts.webhook_slack => Ok(Webhook::new(url).slack()),
+            None => Ok(Webhook::new(url)),
+        })
+        .transpose()
+        .unwrap_or_else(|err| panic!("failed to load webhook template: {err}"));
+    let rate_limiter = limits
+        .rate_limit
+        .map(|rate| Arc::new(Mutex::new(RateLimiter::new(rate, limits.rate_limit_burst))));
+    let reloader = Arc::new(Reloader::new(
+        &opts,
+        rate_limiter.clone(),
+        webhook.clone(),
+        set_log_filter,
+    ));
+    let quarantine = opts
+        .quarantine
+        .map(|capacity| Arc::new(Mutex::new(Quarantine::new(capacity))));
+    let worker_tokens = opts
+        .worker_tokens
+        .then(|| Arc::new(Mutex::new(WorkerTokens::new())));
+    let job_durations = Arc::new(Mutex::new(DurationModel::new()));
+    let lock_ttl = opts.dynamic_ttl.map(|bounds| {
+        let default_timeout_ms = reloader.settings().borrow().default_timeout_ms;
+        Arc::new(Mutex::new(LockTtlController::new(
+            bounds,
+            default_timeout_ms,
+        )))
+    });
+    let host_metrics = Arc::new(Mutex::new(HostMetrics::new(
+        opts.worker_metrics_retention.saturating_mul(1000),
+    )));
+    // embedders building their own server register their hooks here.
+    let coordinator_hooks = Arc::new(Hooks::new());
+    let network_probes = Arc::new(Mutex::new(NetworkProbes::new(
+        opts.worker_metrics_retention.saturating_mul(1000),
+    )));
+    let annotations = Arc::new(Mutex::new(Annotations::default()));
+    let maintenance = Arc::new(Mutex::new(MaintenanceWindows::default()));
+    let top_k = Arc::new(Mutex::new(TopK::new()));
+    let role = Arc::new(Mutex::new(match opts.standby_of.clone() {
+        _ if replay.is_some() => Role::Replay,
+        Some(primary) => Role::Standby { primary },
+        None if opts.cluster.is_some() => Role::Electing,
+        None => Role::Primary,
+    }));
+    let fleet_anomalies = Arc::new(Mutex::new(FleetAnomalyDetector::new(AnomalyConfig {
+        alpha: opts.anomaly_alpha,
+        threshold: opts.anomaly_threshold,
+        warmup: opts.anomaly_warmup,
+    })));
+
+    let scheduler = Arc::new(Scheduler::default());
+
+    let anomalies = fleet_anomalies.clone();
+    let anomaly_interval = opts.anomaly_interval.max(1);
+    let anomaly_webhook = webhook.clone();
+    scheduler.every(
+        "anomaly-sample",
+        Duration::from_secs(anomaly_interval),
+        move || {
+            let anomalies = anomalies.clone();
+            let anomaly_webhook = anomaly_webhook.clone();
+            async move {
+                let started = anomalies
+                    .lock()
+                    .await
+                    .sample(timestamp::now(), anomaly_interval);
+                for anomaly in started {
+                    warn!(?anomaly, "fleet anomaly");
+                    if let Some(webhook) = &anomaly_webhook {
+                        webhook.send(&serde_json::json!({
+                            "event": "fleet_anomaly",
+                            "anomaly": anomaly,
+                        }));
+                    }
+                }
+                Ok(())
+            }
+        },
+    );
+
+    let push_targets = opts
+        .push_gateway
+        .clone()
+        .map(|url| PushTarget::Pushgateway {
+            url,
+            job: opts.push_job.clone(),
+        })
+        .into_iter()
+        .chain(
+            opts.push_remote_write
+                .clone()
+                .map(|url| PushTarget::RemoteWrite { url }),
+        )
+        .chain(opts.push_influx.clone().map(|url| PushTarget::Influx {
+            url,
+            token: opts.push_influx_token.clone(),
+        }))
+        .chain(
+            opts.push_statsd
+                .clone()
+                .map(|addr| PushTarget::Statsd { addr }),
+        )
+        .chain(opts.push_otlp.as_deref().map(|url| PushTarget::Otlp {
+            url: format!("{}/v1/metrics", url.trim_end_matches('/')),
+            service_name: opts.otlp_service_name.clone(),
+        }))
+        .map(MetricsPusher::new)
+        .collect::<Vec<_>>();
+    let completed_jobs = push_targets
+        .iter()
+        .any(|pusher| pusher.target().takes_jobs())
+        .then(|| Arc::new(Mutex::new(JobBuffer::default())));
+    if !push_targets.is_empty() {
+        let (kv, stats, metrics, completed_jobs) = (
+            table.clone(),
+            worker_stats.clone(),
+            metrics_registry.clone(),
+            completed_jobs.clone(),
+        );
+        let push_targets = Arc::new(push_targets);
+        let push_interval = Duration::from_secs(opts.push_interval.max(1));
+        scheduler.every("metrics-push", push_interval, move || {
+            let (kv, stats, metrics, completed_jobs, push_targets) = (
+                kv.clone(),
+                stats.clone(),
+                metrics.clone(),
+                completed_jobs.clone(),
+                push_targets.clone(),
+            );
+            async move {
+                refresh_gauges(&kv, &stats, &metrics).await;
+                let families = metrics.registry.gather();
+                let (jobs, dropped) = match &completed_jobs {
+                    Some(buf) => buf.lock().await.take(),
+                    None => Default::default(),
+                };
+                if dropped > 0 {
+                    warn!(
+                        dropped,
+                        "completed jobs dropped before they could be pushed"
+                    );
+                }
+                let mut failed = vec![];
+                for pusher in push_targets.iter() {
+                    let jobs = if pusher.target().takes_jobs() {
+                        &jobs[..]
+                    } else {
+                        &[]
+                    };
+                    if let Err(err) = pusher.push(&families, jobs).await {
+                        failed.push(format!("{}: {err}", pusher.target().url()));
+                    }
+                }
+                match failed.is_empty() {
+                    true => Ok(()),
+                    false => Err(failed.join(", ")),
+                }
+            }
+        });
+    }
+
+    let tracer = opts.otlp_endpoint.as_deref().map(|endpoint| {
+        let exporter = OtlpExporter::new(endpoint, opts.otlp_service_name.clone());
+        let tracer = Arc::new(Mutex::new(Tracer::new()));
+        let exporter = Arc::new(exporter);
+        let tracer_ = tracer.clone();
+        scheduler.every("trace-export", OTLP_EXPORT_INTERVAL, move || {
+            let (exporter, tracer) = (exporter.clone(), tracer_.clone());
+            async move {
+                let (spans, dropped) = tracer.lock().await.take();
+                if dropped > 0 {
+                    warn!(dropped, "spans dropped before they could be exported");
+                }
+                if spans.is_empty() {
+                    return Ok(());
+                }
+                let res = exporter.export(&spans).await;
+                res.map_err(|err| format!("{}: {err}", exporter.url()))
+            }
+        });
+        tracer
+    });
+
+    let job_pool = opts.node_graphql.clone().map(|url| {
+        let node = Arc::new(NodeClient::new(url));
+        let pool = Arc::new(Mutex::new(JobPool::new()));
+        let pool_ = pool.clone();
+        let interval = Duration::from_secs(opts.node_poll_interval.max(1));
+        scheduler.every("node-poll", interval, move || {
+            let (node, pool) = (node.clone(), pool_.clone());
+            async move {
+                let jobs = node
+                    .pending_work()
+                    .await
+                    .map_err(|err| format!("{}: {err}", node.url()))?;
+                pool.lock().await.set(jobs);
+                Ok(())
+            }
+        });
+        pool
+    });
+
+    let started_at = Instant::now();
+
+    let sweep_interval = Duration::from_millis(opts.sweep_interval_ms.max(1));
+    let kv = table.clone();
+    let limiter = rate_limiter.clone();
+    let hosts = host_metrics.clone();
+    let probes = network_probes.clone();
+    scheduler.every(SWEEP_TASK, sweep_interval, move || {
+        let (kv, limiter, hosts, probes) =
+            (kv.clone(), limiter.clone(), hosts.clone(), probes.clone());
+        async move {
+            kv.sweep(Instant::now()).await;
+            if let Some(limiter) = &limiter {
+                limiter.lock().await.sweep(Instant::now());
+            }
+            hosts.lock().await.prune(timestamp::now());
+            probes.lock().await.prune(timestamp::now());
+            Ok(())
+        }
+    });
+
+    let kv = table.clone();
+    let metrics = metrics_registry.clone();
+    let hot_key_webhook = webhook.clone();
+    scheduler.every("hot-key-check", HOT_KEY_CHECK_INTERVAL, move || {
+        let (kv, metrics, hot_key_webhook) = (kv.clone(), metrics.clone(), hot_key_webhook.clone());
+        async move {
+            let now = timestamp::now();
+            let hot = kv.check_hot_keys(now).await;
+            metrics
+                .hot_lock_keys
+                .set(kv.hot_keys(now).await.keys.len() as i64);
+            for hot_key in hot {
+                warn!(?hot_key, "lock key hot");
+                if let Some(webhook) = &hot_key_webhook {
+                    webhook.send(&serde_json::json!({
+                        "event": "lock_key_hot",
+                        "hot_key": hot_key,
+                    }));
+                }
+            }
+            Ok(())
+        }
+    });
+
+    let job_index = Arc::new(Mutex::new(JobIndex::default()));
+    let cooldowns = opts.cooldown_errors.map(|max_errors| {
+        Arc::new(Mutex::new(Cooldowns::new(CooldownConfig {
+            max_errors: max_errors.max(1),
+            window_ms: opts.cooldown_window.saturating_mul(1000),
+            cooldown_ms: opts.cooldown.saturating_mul(1000),
+        })))
+    });
+
+    let ingest = StatsIngest {
+        kv: table.clone(),
+        stats: worker_stats.clone(),
+        metrics: metrics_registry.clone(),
+        anomalies: fleet_anomalies.clone(),
+        durations: job_durations.clone(),
+        lock_ttl: lock_ttl.clone(),
+        top: top_k.clone(),
+        completed_jobs,
+        tracer,
+        job_class_separator: job_class_separator.clone(),
+        max_field_len: opts.max_stats_field_len,
+        shared_locks: shared_locks.clone(),
+        version: Arc::new(watch::channel(0).0),
+        transitions: broadcast::channel(TRANSITIONS_CAPACITY).0,
+        error_events: Arc::new(Mutex::new(ErrorEvents::new(ERROR_EVENTS_CAPACITY))),
+        liveness: Arc::new(Mutex::new(Liveness::new(
+            opts.worker_stale_after.saturating_mul(1000),
+            opts.worker_dead_after.saturating_mul(1000),
+        ))),
+        archive: Arc::new(Mutex::new(WorkerArchive::new(ARCHIVE_CAPACITY))),
+        hooks: coordinator_hooks.clone(),
+        journal: journal.clone(),
+        strict: opts.strict,
+        server_time: opts.server_time,
+        clock_skews: Arc::new(Mutex::new(ClockSkews::default())),
+        job_index: job_index.clone(),
+        cooldowns: cooldowns.clone(),
+        tenants: tenants.clone(),
+    };
+    let backpressure = Backpressure {
+        max_pending: opts.ingest_max_pending,
+        max_wait_ms: opts.ingest_max_wait_ms,
+    };
+    let ingest_queue = IngestQueue::spawn(ingest.clone(), backpressure, quarantine.clone());
+
+    let config = dump::config(&opts);
+    let data_dir = opts.data_dir.clone();
+    let mut sigusr1 =
+        signal(SignalKind::user_defined1()).expect("failed to install SIGUSR1 handler");
+    let dumped = ingest.clone();
+    tokio::spawn(async move {
+        while sigusr1.recv().await.is_some() {
+            match dump::write(&dumped, &config, &data_dir).await {
+                Ok(path) => info!(path = %path.display(), "wrote state dump"),
+                Err(err) => warn!(%err, "failed to write state dump"),
+            }
+        }
+    });
+
+    let mut sighup = signal(SignalKind::hangup()).expect("failed to install SIGHUP handler");
+    let reloader_ = reloader.clone();
+    tokio::spawn(async move {
+        while sighup.recv().await.is_some() {
+            if let Err(err) = reloader_.reload().await {
+                warn!(%err, "failed to reload options, keeping the current ones");
+            }
+        }
+    });
+
+    if let Some(size) = opts.synthetic_fleet {
+        tokio::spawn(synthetic::run(ingest.clone(), size, opts.synthetic_seed));
+    }
+
+    let mut election = None;
+    if let Some(cluster) = &opts.cluster {
+        let id = opts.advertise_url.clone().unwrap_or_default();
+        let ttl = Duration::from_secs(opts.leader_lease_ttl.max(1));
+        let lease = LeaderLease::connect(cluster, id, ttl.as_millis() as u64)
+            .await
+            .unwrap_or_else(|err| panic!("failed to connect to cluster lease: {err}"));
+        let lease = Arc::new(lease);
+        let task = tokio::spawn(cluster::elect(lease.clone(), role.clone(), ttl));
+        election = Some((lease, task));
+        let api_key = opts.cluster_api_key.clone();
+        tokio::spawn(cluster::follow(ingest.clone(), role.clone(), api_key));
+    }
+    if let Some(speed) = replay.as_ref().and_then(|replay| replay.speed) {
+        tokio::spawn(replay::run(ingest.clone(), replay_entries, speed));
+    }
+
+    // scheduled without retention too, in case a reload sets it.
+    let ingest_ = ingest.clone();
+    let role_ = role.clone();
+    let settings = reloader.settings();
+    let tenants_ = tenants.clone();
+    scheduler.every("stats-retention", STATS_PRUNE_INTERVAL, move || {
+        let (ingest, role, tenants) = (ingest_.clone(), role_.clone(), tenants_.clone());
+        let retention_ms = settings.borrow().stats_retention_ms;
+        async move {
+            // standbys get pruned along with their primary.
+            if !is_primary(&role).await {
+                return Ok(());
+            }
+            if let Some(retention_ms) = retention_ms {
+                ingest.prune(retention_ms, None).await;
+            }
+            for (name, tenant) in tenants.iter().flat_map(|t| t.iter()) {
+                if let Some(retention_ms) = tenant.retention_ms {
+                    let prefix = tenants::prefix(name);
+                    ingest.prune(retention_ms, Some(&prefix)).await;
+                }
+            }
+            Ok(())
+        }
+    });
+
+    if let Some(compact_after) = opts.compact_after {
+        let ingest = ingest.clone();
+        let role = role.clone();
+        scheduler.every("stats-compaction", STATS_PRUNE_INTERVAL, move || {
+            let (ingest, role) = (ingest.clone(), role.clone());
+            async move {
+                // standbys get compacted along with their primary.
+                if is_primary(&role).await {
+                    ingest.compact(compact_after.saturating_mul(1000)).await;
+                }
+                Ok(())
+            }
+        });
+    }
+
+    if let Some(lock_ttl) = lock_ttl.clone() {
+        scheduler.every("lock-ttl", LOCK_TTL_INTERVAL, move || {
+            let lock_ttl = lock_ttl.clone();
+            async move {
+                let mut lock_ttl = lock_ttl.lock().await;
+                let Some(decision) = lock_ttl.adjust(timestamp::now()) else {
+                    return Ok(());
+                };
+                if decision.new_ms != decision.old_ms {
+                    info!(
+                        backlog = decision.backlog,
+                        completed = decision.jobs_completed,
+                        old_ms = decision.old_ms,
+                        new_ms = decision.new_ms,
+                        "adjusted default lock ttl"
+                    );
+                } else {
+                    debug!(
+                        backlog = decision.backlog,
+                        held = decision.held,
+                        ttl_ms = decision.new_ms,
+                        "kept default lock ttl"
+                    );
+                }
+                Ok(())
+            }
+        });
+    }
+
+    if let Some(journal) = journal.clone().filter(|_| opts.journal.is_some()) {
+        let ingest = ingest.clone();
+        let interval = Duration::from_secs(opts.journal_compact_interval.max(1));
+        scheduler.every("journal-compact", interval, move || {
+            let (ingest, journal) = (ingest.clone(), journal.clone());
+            async move {
+                let stats = ingest.stats.read().await;
+                let kv = ingest.kv.lock_all().await;
+                journal.compact(vec![
+                    JournalOp::RestoreStats {
+                        stats: stats.snapshot(),
+                    },
+                    JournalOp::RestoreLocks {
+                        locks: kv.snapshot(Instant::now()),
+                    },
+                ]);
+                debug!(workers = stats.len(), locks = kv.len(), "compacted journal");
+                Ok(())
+            }
+        });
+    }
+
+    if let Some(timeout) = opts.pending_timeout {
+        let ingest = ingest.clone();
+        let role = role.clone();
+        let timeout_ms = timeout.saturating_mul(1000);
+        scheduler.every("pending-timeout", PENDING_TIMEOUT_INTERVAL, move || {
+            let (ingest, role) = (ingest.clone(), role.clone());
+            async move {
+                if is_primary(&role).await {
+                    ingest.time_out_pending(timeout_ms).await;
+                }
+                Ok(())
+            }
+        });
+    }
+
+    if let Some(webhook) = webhook.clone() {
+        let (_, mut failures) = ingest.error_events.lock().await.subscribe(None);
+        let failure_webhook = webhook.clone();
+        let windows = maintenance.clone();
+        tokio::spawn(async move {
+            loop {
+                match failures.recv().await {
+                    Ok(failure) => {
+                        let windows = windows.lock().await;
+                        if windows.is_under_maintenance(&failure.worker_id, failure.time) {
+                            debug!(?failure, "worker error during maintenance not sent");
+                            continue;
+                        }
+                        failure_webhook.send(&serde_json::json!({
+                            "event": "worker_error",
+                            "failure": failure,
+                        }));
+                    }
+                    Err(broadcast::error::RecvError::Lagged(missed)) => {
+                        warn!(missed, "worker errors not sent to the webhook");
+                    }
+                    Err(broadcast::error::RecvError::Closed) => break,
+                }
+            }
+        });
+
+        let stats = worker_stats.clone();
+        let windows = maintenance.clone();
+        let role = role.clone();
+        let detector = Arc::new(Mutex::new(StuckDetector::new(
+            opts.webhook_stuck_threshold.saturating_mul(1000),
+        )));
+        scheduler.every("stuck-check", STUCK_CHECK_INTERVAL, move || {
+            let (stats, windows, role, detector, webhook) = (
+                stats.clone(),
+                windows.clone(),
+                role.clone(),
+                detector.clone(),
+                webhook.clone(),
+            );
+            async move {
+                if !is_primary(&role).await {
+                    return Ok(());
+                }
+
+                let now = timestamp::now();
+                let stuck = detector.lock().await.check(&*stats.read().await, now);
+                let windows = windows.lock().await;
+                for stuck in stuck {
+                    if windows.is_under_maintenance(&stuck.worker_id, now) {
+                        debug!(?stuck, "worker stuck during maintenance");
+                        continue;
+                    }
+                    warn!(?stuck, "worker stuck");
+                    webhook.send(&serde_json::json!({
+                        "event": "worker_stuck",
+                        "stuck": stuck,
+                    }));
+                }
+                Ok(())
+            }
+        });
+    }
+
+    let ctx = routes::Context {
+        table: table.clone(),
+        shared_locks: shared_locks.clone(),
+        worker_stats: worker_stats.clone(),
+        metrics_registry: metrics_registry.clone(),
+        coordinator_hooks: coordinator_hooks.clone(),
+        reloader: reloader.clone(),
+        job_pool,
+        job_index,
+        cooldowns,
+        lock_namespaces,
+        job_durations,
+        lock_ttl,
+        job_class_separator,
+        ingest: ingest.clone(),
+        ingest_queue: ingest_queue.clone(),
+        worker_tokens,
+        quarantine,
+        groups_config: groups_config.clone(),
+        host_metrics,
+        network_probes,
+        annotations,
+        maintenance,
+        top_k,
+        role: role.clone(),
+        fleet_anomalies,
+        scheduler: scheduler.clone(),
+        assignment_policy,
+        max_body_size,
+        max_wait,
+        max_locks_per_worker,
+        max_key_len,
+        auto_ttl,
+        selection_policy: opts.selection_policy,
+        handshake_policy: Arc::new(HandshakePolicy {
+            max_clock_skew_ms: opts.max_clock_skew_ms,
+            min_prover_version: opts.min_prover_version.clone(),
+        }),
+        started_at,
+        sweep_interval,
+    };
+    let metrics = metrics_registry.clone();
+    // probes stay open so orchestrators don't need a key, as do the API
+    // description and the dashboard page, whose requests carry one.
+    let routes = routes::health::routes(&ctx)
+        .or(openapi::routes())
+        .or(dashboard::route())
+        .or(admin_port::filter(opts.admin_port.is_some())
+            .and(throttle::filter(
+                rate_limiter,
+                opts.rate_limit_by,
+                metrics.clone(),
+            ))
+            .and(failover::filter(role))
+            .and(auth::filter(api_keys.clone(), opts.protect_reads))
+            .and(routes::api(&ctx)))
+        .recover(throttle::recover)
+        .recover(failover::recover)
+        .recover(body::recover)
+        .recover(auth::recover)
+        .recover(recover_unmatched);
+    let routes = match &opts.cors_origins {
+        Some(origins) => cors::writes(opts.cors_allow_writes)
+            .and(routes)
+            .with(cors::cors(origins, opts.cors_allow_writes))
+            .recover(cors::recover)
+            .map(Reply::into_response)
+            .boxed(),
+        None => routes.map(Reply::into_response).boxed(),
+    };
+    let routes = routes.with(warp::log::custom(move |info| {
+        let route = info.path().trim_start_matches('/').split('/').next();
+        let route = route.filter(|r| ROUTES.contains(r)).unwrap_or("other");
+        metrics
+            .http_request_duration
+            .with_label_values(&[info.method().as_str(), route, info.status().as_str()])
+            .observe(info.elapsed().as_secs_f64());
+    }));
+    let routes = routes.with(warp::trace::request());
+
+    let svc = warp::service(routes);
+    if let Some(addr) = opts.grpc_listen {
+        // calls are confined to the tenant of their key like HTTP requests.
+        let (svc, tenants) = (svc.clone(), tenants.clone());
+        let svc = service_fn(move |req| {
+            tenancy::serve(svc.clone(), tenants.clone(), req).map(Ok::<_, Infallible>)
+        });
+        let coordinator = grpc::Coordinator::new(
+            svc,
+            ingest.transitions.clone(),
+            groups_config.clone(),
+            api_keys.clone(),
+            opts.protect_reads,
+        );
+        info!(%addr, "serving grpc");
+        tokio::spawn(async move {
+            // a panic here would only end this task, leaving the HTTP
+            // server up without gRPC.
+            if let Err(err) = coordinator.serve(addr).await {
+                error!(%err, "grpc server error");
+                std::process::exit(1);
+            }
+        });
+    }
+    let rpc_enabled = opts.rpc;
+    let access_log = match opts.access_log.as_deref() {
+        Some(path) => {
+            let log = AccessLog::open(path, opts.access_log_format).await;
+            let log = log.unwrap_or_else(|err| panic!("failed to open access log {path}: {err}"));
+            Some(Arc::new(log))
+        }
+        None => None,
+    };
+    // `admin` marks the requests of the `--admin-port` listener.
+    let make_svc = move |admin: bool| {
+        let compat = compat.clone();
+        let svc = svc.clone();
+        let access_log = access_log.clone();
+        let tenants = tenants.clone();
+        make_service_fn(move |conn: &Connection| {
+            let remote_addr = conn.remote_addr();
+            let span = info_span!("conn", remote.addr = remote_addr.map(display));
+            let compat = compat.clone();
+            let svc = svc.clone();
+            let access_log = access_log.clone();
+            let tenants = tenants.clone();
+            async move {
+                Ok::<_, Infallible>(service_fn(move |mut req: Request<Body>| {
+                    let target = req.uri().path_and_query().map(|pq| pq.to_string());
+                    strip_api_prefix(&mut req);
+                    if let Some(compat) = &compat {
+                        rewrite_legacy_request(compat, &mut req);
+                    }
+                    // worker ids and keys are found in the routed path.
+                    let entry = access_log.as_ref().map(|log| {
+                        let target = target.unwrap_or_else(|| req.uri().path().to_owned());
+                        log.begin(&req, target, remote_addr)
+                    });
+                    if let Some(addr) = remote_addr {
+                        req.extensions_mut().insert(ClientAddr(addr));
+                    }
+                    if admin {
+                        req.extensions_mut().insert(AdminListener);
+                    }
+                    let (svc, tenants) = (svc.clone(), tenants.clone());
+                    // JSON-RPC calls are confined to the tenant one by one.
+                    let mut svc = service_fn(move |req| {
+                        tenancy::serve(svc.clone(), tenants.clone(), req).map(Ok::<_, Infallible>)
+                    });
+                    let is_rpc = rpc_enabled && req.uri().path() == "/rpc";
+                    let format = formats::negotiate(&req);
+                    if let Some(format) = format {
+                        // for routes streaming their responses in it.
+                        req.extensions_mut().insert(format);
+                    }
+                    let encoding = compression::negotiate(&req);
+                    async move {
+                        let mut res = if is_rpc {
+                            rpc::handle(req, svc, max_body_size).await
+                        } else {
+                            let mut res = svc.call(req).await?;
+                            if let Some(format) = format {
+                                res = formats::transcode(res, format).await;
+                            }
+                            match encoding {
+                                Some(encoding) => compression::compress(res, encoding).await,
+                                None => res,
+                            }
+                        };
+                        let version = HeaderValue::from_static(API_VERSION);
+                        res.headers_mut().insert(API_VERSION_HEADER, version);
+                        if let Some(entry) = entry {
+                            res = entry.finish(res);
+                        }
+                        Ok::<_, Infallible>(res)
+                    }
+                    .instrument(span.clone())
+                }))
+            }
+        })
+    };
+    let tls = match (&opts.tls_cert, &opts.tls_key) {
+        (Some(cert), Some(key)) => Some(
+            listener::tls_config(cert, key, opts.tls_client_ca.as_deref())
+                .unwrap_or_else(|err| panic!("failed to load tls config: {err}")),
+        ),
+        _ => None,
+    };
+    let tls_enabled = tls.is_some();
+    let shutdown = Arc::new(Notify::new());
+    if let Some(port) = opts.admin_port {
+        let ip = if opts.admin_localhost {
+            [127, 0, 0, 1]
+        } else {
+            [0, 0, 0, 0]
+        };
+        let addr = ListenAddr::Tcp((ip, port).into());
+        let acceptor = listener::bind(&addr, tls.clone())
+            .await
+            .unwrap_or_else(|err| panic!("failed to bind {addr}: {err}"));
+        info!(%addr, tls = tls_enabled, "listening for admin requests");
+        let server = Server::builder(acceptor)
+            .serve(make_svc(true))
+            .with_graceful_shutdown({
+                let shutdown = shutdown.clone();
+                async move { shutdown.notified().await }
+            });
+        tokio::spawn(async move {
+            // as for gRPC, the process exits rather than running on
+            // without its admin listener.
+            if let Err(err) = server.await {
+                error!(%err, "admin server error");
+                std::process::exit(1);
+            }
+        });
+    }
+    let addr = match (opts.unix_socket, opts.listen) {
+        (Some(path), _) => ListenAddr::Unix(path),
+        (None, Some(addr)) => ListenAddr::Tcp(addr),
+        (None, None) => ListenAddr::Tcp(([0, 0, 0, 0], opts.port).into()),
+    };
+    let acceptor = listener::bind(&addr, tls)
+        .await
+        .unwrap_or_else(|err| panic!("failed to bind {addr}: {err}"));
+    info!(%addr, tls = tls_enabled, "listening");
+    let server = Server::builder(acceptor)
+        .serve(make_svc(false))
+        .with_graceful_shutdown({
+            let shutdown = shutdown.clone();
+            async move { shutdown.notified().await }
+        });
+    tokio::pin!(server);
+    tokio::select! {
+        res = &mut server => {
+            if let Err(err) = res {
+                panic!("server error: {err}");
+            }
+            return;
+        }
+        _ = shutdown_signal() => {}
+    }
+
+    // stop accepting connections and let in-flight requests finish, then
+    // stop queueing stats events and apply the ones acked already, all
+    // within the deadline.
+    let stats_in_flight = metrics_registry.stats_events_in_flight.get();
+    let applied = ingest_queue.applied();
+    let applied_before = *applied.borrow();
+    info!(
+        deadline_s = opts.shutdown_deadline,
+        stats_in_flight, "shutting down"
+    );
+    let deadline = tokio::time::Instant::now() + Duration::from_secs(opts.shutdown_deadline);
+    shutdown.notify_waiters();
+    // hand over leadership right away rather than once the lease runs out.
+    if let Some((lease, task)) = election {
+        task.abort();
+        match lease.resign().await {
+            Ok(()) => info!("resigned cluster leadership"),
+            Err(err) => warn!(%err, "failed to resign cluster leadership"),
+        }
+    }
+    match tokio::time::timeout_at(deadline, server).await {
+        Ok(Ok(())) => info!("drained all connections"),
+        Ok(Err(err)) => warn!(%err, "server error while draining"),
+        Err(_) => warn!("shutdown deadline exceeded, dropping remaining connections"),
+    }
+    let dropped = ingest_queue.close(deadline).await;
+    let flushed = *applied.borrow() - applied_before;
+    if dropped > 0 {
+        warn!(flushed, dropped, "stats events dropped on shutdown");
+    } else {
+        info!(flushed, "stats events drained");
+    }
+}
+
+/// Resolves on SIGTERM or SIGINT.
+async fn shutdown_signal() {
+    let mut sigterm = signal(SignalKind::terminate()).expect("failed to install SIGTERM handler");
+    tokio::select! {
+        _ = sigterm.recv() => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+}
+
+/// Routes requests to `/v1/..` as the unprefixed routes they're served by.
+fn strip_api_prefix(req: &mut Request<Body>) {
+    let Some(path) = req.uri().path().strip_prefix(API_PREFIX) else {
+        return;
+    };
+    if path.is_empty() || path.starts_with('/') {
+        let path = if path.is_empty() { "/" } else { path };
+        set_path(req, path.to_owned());
+    }
+}
+
+fn rewrite_legacy_request(compat: &CompatConfig, req: &mut Request<Body>) {
+    let Some(rewrite) = compat.rewrite(req.method().as_str(), req.uri().path()) else {
+        return;
+    };
+    if let Ok(method) = Method::from_bytes(rewrite.method.as_bytes()) {
+        *req.method_mut() = method;
+    }
+    set_path(req, rewrite.path);
+}
+
+/// Replaces the path of the request's URI, keeping its query.
+fn set_path(req: &mut Request<Body>, path: String) {
+    let path_and_query = match req.uri().query() {
+        Some(query) => format!("{path}?{query}"),
+        None => path,
+    };
+    set_path_and_query(req, path_and_query);
+}
+
+/// Replaces the path and query of the request's URI.
+fn set_path_and_query(req: &mut Request<Body>, path_and_query: String) {
+    let mut parts = req.uri().clone().into_parts();
+    match path_and_query.parse() {
+        Ok(path_and_query) => parts.path_and_query = Some(path_and_query),
+        Err(err) => return warn!(%err, "invalid rewritten path"),
+    }
+    if let Ok(uri) = Uri::from_parts(parts) {
+        *req.uri_mut() = uri;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    pub(crate) fn ingest(shared_locks: Option<Arc<RedisLocks>>, hooks: Hooks) -> StatsIngest {
+        StatsIngest {
+            kv: Arc::new(LockShards::new(LockTable::new(16), 4)),
+            stats: Arc::new(RwLock::new(WorkerStats::default())),
+            metrics: Arc::new(Metrics::new()),
+            anomalies: Arc::new(Mutex::new(FleetAnomalyDetector::new(AnomalyConfig {
+                alpha: 0.3,
+                threshold: 3.0,
+                warmup: 10,
+            }))),
+            durations: Arc::new(Mutex::new(DurationModel::new())),
+            lock_ttl: None,
+            top: Arc::new(Mutex::new(TopK::new())),
+            completed_jobs: None,
+            tracer: None,
+            job_class_separator: Arc::new("-".to_owned()),
+            max_field_len: 1024,
+            version: Arc::new(watch::channel(0).0),
+            transitions: broadcast::channel(TRANSITIONS_CAPACITY).0,
+            error_events: Arc::new(Mutex::new(ErrorEvents::new(ERROR_EVENTS_CAPACITY))),
+            liveness: Arc::new(Mutex::new(Liveness::new(60_000, 120_000))),
+            archive: Arc::new(Mutex::new(WorkerArchive::new(ARCHIVE_CAPACITY))),
+            hooks: Arc::new(hooks),
+            journal: None,
+            shared_locks,
+            strict: false,
+            server_time: None,
+            clock_skews: Arc::new(Mutex::new(ClockSkews::default())),
+            job_index: Arc::new(Mutex::new(JobIndex::default())),
+            cooldowns: None,
+            tenants: None,
+        }
+    }
+
+    /// Keys the hook was told were released.
+    #[derive(Clone, Default)]
+    struct Released(Arc<std::sync::Mutex<Vec<String>>>);
+
+    impl CoordinatorHooks for Released {
+        fn on_lock_released(&self, key: &str, _fulfilled_by: Option<&LockFulfillment>) {
+            self.0.lock().unwrap().push(key.to_owned());
+        }
+    }
+
+    /// URL of a Redis server which answers every command with `1`, but
+    /// only after `delay`.
+    async fn slow_redis(delay: Duration) -> String {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        tokio::spawn(async move {
+            while let Ok((mut conn, _)) = listener.accept().await {
+                tokio::spawn(async move {
+                    let mut buf = vec![];
+                    let mut chunk = [0; 1024];
+                    while let Ok(n @ 1..) = conn.read(&mut chunk).await {
+                        buf.extend_from_slice(&chunk[..n]);
+                        while let Some(len) = command_len(&buf) {
+                            buf.drain(..len);
+                            tokio::time::sleep(delay).await;
+                            if conn.write_all(b":1\r\n").await.is_err() {
+                                return;
+                            }
+                        }
+                    }
+                });
+            }
+        });
+        url
+    }
+
+    /// Length of the command `buf` starts with, if it's all there.
+    fn command_len(buf: &[u8]) -> Option<usize> {
+        // number following the type byte of the line at `at`, and where
+        // the next line starts.
+        let line = |at: usize| -> Option<(usize, usize)> {
+            let end = at + buf.get(at..)?.windows(2).position(|w| w == b"\r\n")?;
+            let n = std::str::from_utf8(buf.get(at + 1..end)?).ok()?;
+            Some((n.parse().ok()?, end + 2))
+        };
+        let (n, mut at) = line(0)?;
+        for _ in 0..n {
+            let (len, start) = line(at)?;
+            at = start + len + 2;
+        }
+        (at <= buf.len()).then_some(at)
+    }
+
+    /// Locks `keys` for `holder` the way `PUT /lock-jobs` does, with a
+    /// quota of `max_locks`.
+    async fn lock_within_quota(
+        kv: &LockShards,
+        keys: &[&str],
+        holder: &str,
+        max_locks: usize,
+    ) -> Result<u64, StatusCode> {
+        let keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        let quota = worker_lock_quota(Some(holder), Some(max_locks));
+        let limits = NamespaceLimits::default();
+        let mut kv = lock_for_limits(kv, &keys, &limits, quota).await;
+        let now = Instant::now();
+        check_worker_lock_quota(&kv, quota, &keys, now)
+            .map_err(|err| StatusCode::from_u16(err.code.status()).unwrap())?;
+        // let the other acquirers run, as a shared lock store would.
+        tokio::task::yield_now().await;
+        let lock = JobLock::new(now + Duration::from_secs(60), Some(holder.to_owned()));
+        let acquired = acquire_all(&mut kv, None, keys, lock, None, now).await;
+        acquired.unwrap().map_err(|_| StatusCode::CONFLICT)
+    }
+
+    #[tokio::test]
+    async fn worker_lock_quota_holds_for_concurrent_acquires() {
+        let kv = Arc::new(LockShards::new(LockTable::new(16), 16));
+        let acquires = (0..8).map(|i| {
+            let kv = kv.clone();
+            tokio::spawn(async move { lock_within_quota(&kv, &[&format!("{i}")], "w", 3).await })
+        });
+        let mut granted = 0;
+        for acquire in acquires {
+            match acquire.await.unwrap() {
+                Ok(_) => granted += 1,
+                Err(status) => assert_eq!(status, StatusCode::TOO_MANY_REQUESTS),
+            }
+        }
+        assert_eq!(granted, 3);
+        let kv = kv.lock_all().await;
+        assert_eq!(kv.count_held_by("w", Instant::now()), 3);
+    }
+
+    #[tokio::test]
+    async fn worker_at_lock_quota_renews_its_locks() {
+        let kv = LockShards::new(LockTable::new(16), 4);
+        lock_within_quota(&kv, &["a", "b"], "w", 2).await.unwrap();
+
+        let quota = worker_lock_quota(Some("w"), Some(2));
+        let limits = NamespaceLimits::default();
+        let keys = ["a", "b", "a"].map(str::to_owned);
+        let kv_ = lock_for_limits(&kv, &keys, &limits, quota).await;
+        assert!(check_worker_lock_quota(&kv_, quota, &keys, Instant::now()).is_ok());
+        drop(kv_);
+
+        let refused = lock_within_quota(&kv, &["a", "c"], "w", 2).await;
+        assert_eq!(refused.unwrap_err(), StatusCode::TOO_MANY_REQUESTS);
+        // others' locks don't count against the worker.
+        lock_within_quota(&kv, &["c"], "v", 2).await.unwrap();
+        let kv = kv.lock_all().await;
+        assert_eq!(kv.count_held_by("w", Instant::now()), 2);
+    }
+
+    /// Locks `keys` for `holder` the way `PUT /lock-jobs?partial=true`
+    /// does, with a quota of 2 locks, returns the statuses of the keys.
+    async fn lock_partially(
+        kv: &LockShards,
+        namespace: Option<&str>,
+        limits: &NamespaceLimits,
+        holder: &str,
+        keys: &[&str],
+    ) -> Vec<u16> {
+        let keys = keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        let quota = worker_lock_quota(Some(holder), Some(2));
+        let mut kv = lock_for_limits(kv, &keys, limits, quota).await;
+        let (metrics, hooks) = (Metrics::new(), Hooks::new());
+        let holder = Some(holder.to_owned());
+        let timeout = Duration::from_secs(60);
+        let res = lock_each(
+            &mut kv, None, &metrics, &hooks, namespace, limits, quota, keys, holder, timeout, 100,
+        )
+        .await;
+        res.results.iter().map(|item| item.status).collect()
+    }
+
+    #[tokio::test]
+    async fn partial_locks_grant_the_keys_within_limits() {
+        let kv = LockShards::new(LockTable::new(16), 4);
+        let limits = NamespaceLimits::default();
+        let statuses = lock_partially(&kv, None, &limits, "w", &["a", "b", "c"]).await;
+        assert_eq!(statuses, [201, 201, 429]);
+
+        let limits = NamespaceLimits {
+            max_keys: Some(1),
+            ..NamespaceLimits::default()
+        };
+        let statuses = lock_partially(&kv, Some("devnet"), &limits, "v", &["a", "b"]).await;
+        assert_eq!(statuses, [201, 409]);
+    }
+
+    #[test]
+    fn max_timeout_is_set_in_ms_or_deprecated_seconds() {
+        let opts = |args: &[&str]| {
+            let args = ["snark-coordinator-rs"].iter().chain(args);
+            Opts::from_iter_safe(args)
+        };
+        assert_eq!(opts(&[]).unwrap().max_timeout_ms(), 3_000_000);
+        let opts_ = opts(&["--max-timeout", "2"]).unwrap();
+        assert_eq!(opts_.max_timeout_ms(), 2000);
+        let opts_ = opts(&["--max-timeout-ms", "1500"]).unwrap();
+        assert_eq!(opts_.max_timeout_ms(), 1500);
+        assert!(opts(&["--max-timeout-ms", "1500", "--max-timeout", "2"]).is_err());
+
+        let opts_ = opts(&["--max-timeout-ms", "1500", "--min-timeout-ms", "1500"]).unwrap();
+        assert!(opts_.check_timeouts().is_ok());
+        let opts_ = opts(&["--max-timeout-ms", "1500", "--min-timeout-ms", "2000"]).unwrap();
+        assert!(opts_.check_timeouts().is_err());
+    }
+
+    #[test]
+    fn lock_jobs_need_distinct_keys() {
+        let keys = |keys: &[&str]| keys.iter().map(|key| key.to_string()).collect::<Vec<_>>();
+        assert!(check_lock_keys(&keys(&["a", "b"])).is_ok());
+        for keys in [keys(&[]), keys(&["a", "b", "a"])] {
+            let status = check_lock_keys(&keys).unwrap_err().into_response().status();
+            assert_eq!(status, StatusCode::BAD_REQUEST, "{keys:?}");
+        }
+    }
+
+    #[test]
+    fn snapshots_of_newer_versions_are_refused() {
+        let snapshot = |version: Option<u32>| {
+            let mut snapshot = serde_json::json!({
+                "taken_t": 1,
+                "locks": LockTableSnapshot::default(),
+                "stats": WorkerStatsSnapshot::default(),
+            });
+            if let Some(version) = version {
+                snapshot["snapshot_version"] = version.into();
+            }
+            snapshot
+        };
+        let read = StateSnapshot::from_value(snapshot(None)).unwrap();
+        assert_eq!(read.snapshot_version, 1);
+        assert!(StateSnapshot::from_value(snapshot(Some(journal::VERSION))).is_ok());
+        let newer = StateSnapshot::from_value(snapshot(Some(journal::VERSION + 1)));
+        assert!(newer.is_err_and(|err| err.kind() == io::ErrorKind::InvalidData));
+
+        // nor are they replayed.
+        let path = std::env::temp_dir().join(format!("snapshot-{}.json", std::process::id()));
+        let newer = snapshot(Some(journal::VERSION + 1)).to_string();
+        std::fs::write(&path, newer).unwrap();
+        assert!(replay::load(&path, None).is_err());
+        std::fs::write(&path, snapshot(None).to_string()).unwrap();
+        assert_eq!(replay::load(&path, None).unwrap().len(), 2);
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[tokio::test]
+    async fn releases_shared_locks_after_unlocking_stats() {
+        let url = slow_redis(Duration::from_millis(500)).await;
+        let shared = Arc::new(RedisLocks::connect(&url).await.unwrap());
+        let released = Released::default();
+        let ingest = ingest(Some(shared), Hooks::new().with(released.clone()));
+
+        let register = SnarkWorkerStatsPut::Register {
+            time: 1,
+            metadata: None,
+            resume: None,
+            seq: None,
+        };
+        let session = ingest.apply("w", register, None, None).await.unwrap().body;
+        let now = Instant::now();
+        let lock = JobLock::new(now + Duration::from_secs(60), Some(session.clone()));
+        let mut kv = ingest.kv.lock_key("1").await;
+        kv.try_acquire_all(vec!["1".to_owned()], lock, now).unwrap();
+        drop(kv);
+        let reqs = [
+            SnarkWorkerStatsPut::JobGetInit { time: 2, seq: None },
+            SnarkWorkerStatsPut::JobGetSuccess {
+                time: 3,
+                job_get_node_received_t: None,
+                job_get_node_request_work_init_t: None,
+                job_get_node_request_work_success_t: None,
+                ids: "1".to_owned(),
+                seq: None,
+            },
+        ];
+        for req in reqs {
+            ingest.apply(&session, req, None, None).await.unwrap();
+        }
+
+        let done = SnarkWorkerStatsPut::WorkCreateError {
+            time: 4,
+            ids: "1".to_owned(),
+            error: "failed".to_owned(),
+            seq: None,
+        };
+        let apply = tokio::spawn({
+            let ingest = ingest.clone();
+            async move { ingest.apply(&session, done, None, None).await }
+        });
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        // Redis is still answering, but the stats and shard are free and
+        // the lock is released locally.
+        let wait = Duration::from_millis(100);
+        let stats = tokio::time::timeout(wait, ingest.stats.read()).await;
+        assert_eq!(stats.unwrap().len(), 1);
+        let kv = tokio::time::timeout(wait, ingest.kv.lock_key("1")).await;
+        assert!(kv.unwrap().lease("1", Instant::now()).is_none());
+        assert!(released.0.lock().unwrap().is_empty());
+
+        apply.await.unwrap().unwrap();
+        assert_eq!(*released.0.lock().unwrap(), ["1"]);
+    }
 }
@@ -0,0 +1,147 @@
+//! Planned maintenance of workers, during which they're expected to be
+//! down: their alerts are suppressed and the time doesn't count against
+//! their availability.
+
+use std::collections::{BTreeMap, HashMap};
+
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp;
+
+/// Request body of `POST /admin/maintenance`. At least one worker or
+/// group has to be given.
+#[derive(Deserialize, Debug, Clone)]
+pub struct MaintenanceRequest {
+    #[serde(default)]
+    pub workers: Vec<String>,
+    /// Teams of `--groups-file`, covering every worker they own.
+    #[serde(default)]
+    pub groups: Vec<String>,
+    #[serde(deserialize_with = "timestamp::deserialize")]
+    pub from_t: u64,
+    #[serde(deserialize_with = "timestamp::deserialize")]
+    pub to_t: u64,
+    /// Free form note, e.g. what's being upgraded.
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MaintenanceWindow {
+    pub id: u64,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub workers: Vec<String>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub groups: Vec<String>,
+    pub from_t: u64,
+    pub to_t: u64,
+    pub reason: Option<String>,
+    /// Annotation marking the window on report timelines.
+    pub annotation_id: Option<u64>,
+    pub created_t: u64,
+    /// Worker id prefixes of `groups` when the window was scheduled.
+    #[serde(skip)]
+    prefixes: Vec<String>,
+}
+
+impl MaintenanceWindow {
+    pub fn covers_worker(&self, worker_id: &str) -> bool {
+        self.workers.iter().any(|w| w == worker_id)
+            || self.prefixes.iter().any(|p| worker_id.starts_with(p))
+    }
+
+    /// Whether the worker is under maintenance at time `t`.
+    pub fn covers(&self, worker_id: &str, t: u64) -> bool {
+        self.from_t <= t && t < self.to_t && self.covers_worker(worker_id)
+    }
+
+    /// Text of the window's annotation.
+    pub fn describe(&self) -> String {
+        let targets = self
+            .workers
+            .iter()
+            .cloned()
+            .chain(self.groups.iter().map(|g| format!("group {g}")))
+            .collect::<Vec<_>>()
+            .join(", ");
+        match &self.reason {
+            Some(reason) => format!("maintenance of {targets}: {reason}"),
+            None => format!("maintenance of {targets}"),
+        }
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MaintenanceWindows {
+    windows: BTreeMap<u64, MaintenanceWindow>,
+    last_id: u64,
+}
+
+impl MaintenanceWindows {
+    /// Schedules a window, resolving its groups with `teams`.
+    pub fn add(
+        &mut self,
+        req: MaintenanceRequest,
+        teams: &HashMap<String, Vec<String>>,
+    ) -> Result<&mut MaintenanceWindow, String> {
+        if req.workers.is_empty() && req.groups.is_empty() {
+            return Err("maintenance needs workers or groups".to_owned());
+        }
+        if req.from_t >= req.to_t {
+            let (from_t, to_t) = (req.from_t, req.to_t);
+            return Err(format!("from_t ({from_t}) isn't before to_t ({to_t})"));
+        }
+        let mut prefixes = vec![];
+        for group in &req.groups {
+            match teams.get(group) {
+                Some(team) => prefixes.extend(team.iter().cloned()),
+                None => return Err(format!("unknown group: {group}")),
+            }
+        }
+        self.last_id += 1;
+        let window = MaintenanceWindow {
+            id: self.last_id,
+            workers: req.workers,
+            groups: req.groups,
+            from_t: req.from_t,
+            to_t: req.to_t,
+            reason: req.reason,
+            annotation_id: None,
+            created_t: timestamp::now(),
+            prefixes,
+        };
+        Ok(self.windows.entry(window.id).or_insert(window))
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<MaintenanceWindow> {
+        self.windows.remove(&id)
+    }
+
+    /// Windows, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &MaintenanceWindow> {
+        self.windows.values()
+    }
+
+    pub fn is_under_maintenance(&self, worker_id: &str, t: u64) -> bool {
+        self.windows.values().any(|w| w.covers(worker_id, t))
+    }
+
+    /// Maintenance periods of the worker, merged where they overlap,
+    /// ordered by time.
+    pub fn periods(&self, worker_id: &str) -> Vec<(u64, u64)> {
+        let mut periods = self
+            .windows
+            .values()
+            .filter(|w| w.covers_worker(worker_id))
+            .map(|w| (w.from_t, w.to_t))
+            .collect::<Vec<_>>();
+        periods.sort_unstable();
+        let mut merged: Vec<(u64, u64)> = Vec::with_capacity(periods.len());
+        for (from_t, to_t) in periods {
+            match merged.last_mut() {
+                Some(last) if from_t <= last.1 => last.1 = last.1.max(to_t),
+                _ => merged.push((from_t, to_t)),
+            }
+        }
+        merged
+    }
+}
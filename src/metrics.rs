@@ -0,0 +1,162 @@
+use prometheus::{
+    Encoder, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge, Opts, Registry,
+    TextEncoder,
+};
+
+/// Coordinator metrics, exported in the Prometheus text format.
+pub struct Metrics {
+    pub registry: Registry,
+    pub lock_acquisitions: IntCounter,
+    pub lock_conflicts: IntCounter,
+    /// Requests refused with 429.
+    pub rate_limited: IntCounter,
+    pub active_locks: IntGauge,
+    /// Lock keys hot by the `--hot-key-*` thresholds at the last check.
+    pub hot_lock_keys: IntGauge,
+    pub registered_workers: IntGauge,
+    /// States kept across all workers.
+    pub worker_states: IntGauge,
+    /// Labels: `reason` (`max_states_per_worker`, `max_total_states`,
+    /// `retention`).
+    pub evicted_states: IntCounterVec,
+    /// Labels: `kind`, `result` (`accepted`/`rejected`).
+    pub stats_events: IntCounterVec,
+    /// Worker-stats requests received but not yet applied.
+    pub stats_events_in_flight: IntGauge,
+    /// Worker-stats requests refused with 429 as ingestion was saturated.
+    pub stats_events_shed: IntCounter,
+    /// Worker-stats events acked but not applied by the shutdown deadline.
+    pub stats_events_dropped_on_shutdown: IntCounter,
+    /// Labels: `phase` (`job_get`, `work_create`, `work_submit`).
+    pub job_phase_duration: HistogramVec,
+    /// Labels: `method`, `route`, `status`.
+    pub http_request_duration: HistogramVec,
+}
+
+const PHASE_BUCKETS: &[f64] = &[
+    0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0, 30.0, 60.0, 120.0, 300.0, 600.0,
+];
+
+impl Metrics {
+    pub fn new() -> Self {
+        let registry = Registry::new_custom(Some("snark_coordinator".to_owned()), None).unwrap();
+        let metrics = Self {
+            lock_acquisitions: IntCounter::new("lock_acquisitions_total", "Granted job locks.")
+                .unwrap(),
+            lock_conflicts: IntCounter::new(
+                "lock_conflicts_total",
+                "Lock requests for already locked jobs.",
+            )
+            .unwrap(),
+            rate_limited: IntCounter::new(
+                "rate_limited_total",
+                "Requests refused by the rate limiter.",
+            )
+            .unwrap(),
+            active_locks: IntGauge::new("active_locks", "Currently held job locks.").unwrap(),
+            hot_lock_keys: IntGauge::new(
+                "hot_lock_keys",
+                "Lock keys refused far more often than acquired.",
+            )
+            .unwrap(),
+            registered_workers: IntGauge::new("registered_workers", "Workers with stats history.")
+                .unwrap(),
+            worker_states: IntGauge::new("worker_states", "States kept across all workers.")
+                .unwrap(),
+            evicted_states: IntCounterVec::new(
+                Opts::new(
+                    "evicted_states_total",
+                    "States dropped from worker histories by reason.",
+                ),
+                &["reason"],
+            )
+            .unwrap(),
+            stats_events: IntCounterVec::new(
+                Opts::new("stats_events_total", "Worker-stats events by kind."),
+                &["kind", "result"],
+            )
+            .unwrap(),
+            stats_events_in_flight: IntGauge::new(
+                "stats_events_in_flight",
+                "Worker-stats events received but not yet applied.",
+            )
+            .unwrap(),
+            stats_events_shed: IntCounter::new(
+                "stats_events_shed_total",
+                "Worker-stats requests refused as ingestion was saturated.",
+            )
+            .unwrap(),
+            stats_events_dropped_on_shutdown: IntCounter::new(
+                "stats_events_dropped_on_shutdown_total",
+                "Worker-stats events acked but not applied by the shutdown deadline.",
+            )
+            .unwrap(),
+            job_phase_duration: HistogramVec::new(
+                HistogramOpts::new(
+                    "job_phase_duration_seconds",
+                    "Job phase durations derived from worker state transitions.",
+                )
+                .buckets(PHASE_BUCKETS.to_vec()),
+                &["phase"],
+            )
+            .unwrap(),
+            http_request_duration: HistogramVec::new(
+                HistogramOpts::new("http_request_duration_seconds", "HTTP request latencies."),
+                &["method", "route", "status"],
+            )
+            .unwrap(),
+            registry,
+        };
+
+        let collectors: [Box<dyn prometheus::core::Collector>; 14] = [
+            Box::new(metrics.lock_acquisitions.clone()),
+            Box::new(metrics.lock_conflicts.clone()),
+            Box::new(metrics.rate_limited.clone()),
+            Box::new(metrics.active_locks.clone()),
+            Box::new(metrics.hot_lock_keys.clone()),
+            Box::new(metrics.registered_workers.clone()),
+            Box::new(metrics.worker_states.clone()),
+            Box::new(metrics.evicted_states.clone()),
+            Box::new(metrics.stats_events.clone()),
+            Box::new(metrics.stats_events_in_flight.clone()),
+            Box::new(metrics.stats_events_shed.clone()),
+            Box::new(metrics.stats_events_dropped_on_shutdown.clone()),
+            Box::new(metrics.job_phase_duration.clone()),
+            Box::new(metrics.http_request_duration.clone()),
+        ];
+        for collector in collectors {
+            metrics.registry.register(collector).unwrap();
+        }
+        metrics
+    }
+
+    pub fn encode(&self) -> String {
+        let mut buf = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut buf)
+            .unwrap();
+        String::from_utf8(buf).unwrap()
+    }
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// Keeps `gauge` incremented until dropped.
+pub struct GaugeGuard(IntGauge);
+
+impl GaugeGuard {
+    pub fn new(gauge: &IntGauge) -> Self {
+        gauge.inc();
+        Self(gauge.clone())
+    }
+}
+
+impl Drop for GaugeGuard {
+    fn drop(&mut self) {
+        self.0.dec();
+    }
+}
@@ -0,0 +1,263 @@
+//! Upgrades of state persisted by older builds, journals and snapshots,
+//! to the format this build reads. Both are versioned by
+//! [`journal::VERSION`], journals in their header and snapshots in their
+//! `snapshot_version`, either missing in those written before there was
+//! one, which are of version 1.
+//!
+//! Bumping the version adds a [`Migration`] from the previous one to
+//! [`MIGRATIONS`]. At startup, a `--journal` of an older version is
+//! copied to a backup next to it and rewritten by the migrations of each
+//! version in turn. Snapshots and journals which are only read, e.g. by
+//! `replay`, are migrated as they're read. State of a newer version than
+//! this build's is refused rather than misread, so a rollback doesn't
+//! corrupt it.
+
+use std::{
+    ffi::OsString,
+    io::{self, Write as _},
+    path::{Path, PathBuf},
+};
+
+use serde_json::Value;
+use tracing::warn;
+
+use crate::journal;
+
+/// Rewrites an entry or snapshot of one version as of the next.
+pub type Rewrite = fn(&mut Value) -> Result<(), String>;
+
+/// Upgrade from format version `from` to `from + 1`.
+pub struct Migration {
+    pub from: u32,
+    /// Rewrites a journal entry.
+    pub entry: Rewrite,
+    /// Rewrites a snapshot.
+    pub snapshot: Rewrite,
+}
+
+/// Migrations from every version before [`journal::VERSION`], in order.
+pub const MIGRATIONS: &[Migration] = &[];
+
+/// Error if `version` of the format of `what`, e.g. a snapshot, is newer
+/// than `latest`.
+pub fn check_version(what: &str, version: u32, latest: u32) -> io::Result<()> {
+    if version <= latest {
+        return Ok(());
+    }
+    let msg = format!(
+        "{what} format version {version} is newer than {latest}, the latest this build reads"
+    );
+    Err(io::Error::new(io::ErrorKind::InvalidData, msg))
+}
+
+/// Migrates the journal at `path` to [`journal::VERSION`] in place,
+/// returning the path of the backup of the old one if it was migrated.
+/// A missing journal has nothing to migrate.
+pub fn migrate_journal(path: &Path) -> io::Result<Option<PathBuf>> {
+    migrate_journal_to(path, MIGRATIONS, journal::VERSION)
+}
+
+/// Contents of `journal` as of [`journal::VERSION`], `None` if it's of
+/// that version already.
+pub fn migrate_journal_contents(journal: &str) -> io::Result<Option<String>> {
+    migrate_contents(journal, MIGRATIONS, journal::VERSION)
+}
+
+/// Migrates `snapshot` to [`journal::VERSION`].
+pub fn migrate_snapshot(snapshot: &mut Value) -> io::Result<()> {
+    migrate_snapshot_to(snapshot, MIGRATIONS, journal::VERSION)
+}
+
+fn migrate_journal_to(
+    path: &Path,
+    migrations: &[Migration],
+    latest: u32,
+) -> io::Result<Option<PathBuf>> {
+    let contents = match std::fs::read_to_string(path) {
+        Ok(contents) => contents,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    let Some(migrated) = migrate_contents(&contents, migrations, latest)? else {
+        return Ok(None);
+    };
+    let version = journal::version(&contents);
+    let mut backup = OsString::from(path);
+    backup.push(format!(".v{version}.bak"));
+    let backup = PathBuf::from(backup);
+    std::fs::copy(path, &backup)?;
+
+    let tmp = path.with_extension("migrating");
+    let mut file = std::fs::File::create(&tmp)?;
+    file.write_all(migrated.as_bytes())?;
+    file.sync_all()?;
+    std::fs::rename(&tmp, path)?;
+    Ok(Some(backup))
+}
+
+fn migrate_contents(
+    contents: &str,
+    migrations: &[Migration],
+    latest: u32,
+) -> io::Result<Option<String>> {
+    let version = journal::version(contents);
+    check_version("journal", version, latest)?;
+    if version == latest {
+        return Ok(None);
+    }
+    let mut migrated = format!("{{\"journal_version\":{latest}}}\n");
+    let lines = contents.lines().enumerate();
+    let mut lines = lines.filter(|(_, line)| !line.trim().is_empty()).peekable();
+    let mut first = true;
+    while let Some((i, line)) = lines.next() {
+        let mut entry = match serde_json::from_str::<Value>(line) {
+            Ok(entry) => entry,
+            Err(err) if lines.peek().is_none() => {
+                warn!(%err, "dropped cut off last journal entry");
+                break;
+            }
+            Err(err) => {
+                let msg = format!("invalid journal entry on line {}: {err}", i + 1);
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+        };
+        if std::mem::take(&mut first) && entry.get("journal_version").is_some() {
+            continue;
+        }
+        run(&mut entry, version, latest, migrations, |m| m.entry).map_err(|msg| {
+            let msg = format!("failed to migrate journal entry on line {}: {msg}", i + 1);
+            io::Error::new(io::ErrorKind::InvalidData, msg)
+        })?;
+        migrated.push_str(&entry.to_string());
+        migrated.push('\n');
+    }
+    Ok(Some(migrated))
+}
+
+fn migrate_snapshot_to(
+    snapshot: &mut Value,
+    migrations: &[Migration],
+    latest: u32,
+) -> io::Result<()> {
+    let invalid = |msg: String| io::Error::new(io::ErrorKind::InvalidData, msg);
+    let version = match snapshot.get("snapshot_version") {
+        None => 1,
+        Some(version) => version
+            .as_u64()
+            .and_then(|version| u32::try_from(version).ok())
+            .ok_or_else(|| invalid(format!("invalid snapshot_version: {version}")))?,
+    };
+    check_version("snapshot", version, latest)?;
+    if version == latest {
+        return Ok(());
+    }
+    run(snapshot, version, latest, migrations, |m| m.snapshot)
+        .map_err(|msg| invalid(format!("failed to migrate snapshot: {msg}")))?;
+    snapshot["snapshot_version"] = latest.into();
+    Ok(())
+}
+
+/// Applies the rewrites of the migrations from `version` up to `latest`
+/// to `value`, in order.
+fn run(
+    value: &mut Value,
+    version: u32,
+    latest: u32,
+    migrations: &[Migration],
+    rewrite: impl Fn(&Migration) -> Rewrite,
+) -> Result<(), String> {
+    for from in version..latest {
+        let Some(migration) = migrations.iter().find(|m| m.from == from) else {
+            return Err(format!("no migration from version {from}"));
+        };
+        rewrite(migration)(value)?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Renames `op` `acquire` to `lock` from version 1 to 2, then adds
+    /// `v3` to everything, which has to be of version 2 by then.
+    const TEST_MIGRATIONS: &[Migration] = &[
+        Migration {
+            from: 2,
+            entry: |entry| {
+                if entry["op"] == "acquire" {
+                    return Err("acquire is of version 1".to_owned());
+                }
+                entry["v3"] = true.into();
+                Ok(())
+            },
+            snapshot: |snapshot| {
+                snapshot["v3"] = true.into();
+                Ok(())
+            },
+        },
+        Migration {
+            from: 1,
+            entry: |entry| {
+                if entry["op"] == "acquire" {
+                    entry["op"] = "lock".into();
+                }
+                Ok(())
+            },
+            snapshot: |_| Ok(()),
+        },
+    ];
+
+    #[test]
+    fn journals_are_migrated_in_order_after_a_backup() {
+        let dir = std::env::temp_dir().join(format!("migrations-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("journal.ndjson");
+        let old = "{\"t\":1,\"op\":\"acquire\"}\n{\"t\":2,\"op\":\"reset\"}\n{\"t\":3,";
+        std::fs::write(&path, old).unwrap();
+
+        let backup = migrate_journal_to(&path, TEST_MIGRATIONS, 3).unwrap();
+        let backup = backup.unwrap();
+        assert_eq!(backup, dir.join("journal.ndjson.v1.bak"));
+        assert_eq!(std::fs::read_to_string(&backup).unwrap(), old);
+        let migrated = std::fs::read_to_string(&path).unwrap();
+        let lines = migrated.lines().collect::<Vec<_>>();
+        assert_eq!(
+            lines,
+            [
+                "{\"journal_version\":3}",
+                "{\"op\":\"lock\",\"t\":1,\"v3\":true}",
+                "{\"op\":\"reset\",\"t\":2,\"v3\":true}",
+            ]
+        );
+
+        // once migrated, it's left alone.
+        assert_eq!(migrate_journal_to(&path, TEST_MIGRATIONS, 3).unwrap(), None);
+        // a rollback refuses it, leaving it as it is.
+        let err = migrate_journal_to(&path, TEST_MIGRATIONS, 2).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), migrated);
+        // without a way from its version, neither is it touched.
+        std::fs::write(&path, old).unwrap();
+        assert!(migrate_journal_to(&path, &TEST_MIGRATIONS[..1], 3).is_err());
+        assert_eq!(std::fs::read_to_string(&path).unwrap(), old);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn snapshots_are_migrated_to_the_latest_version() {
+        let mut snapshot = serde_json::json!({ "taken_t": 1 });
+        migrate_snapshot_to(&mut snapshot, TEST_MIGRATIONS, 3).unwrap();
+        assert_eq!(
+            snapshot,
+            serde_json::json!({ "taken_t": 1, "v3": true, "snapshot_version": 3 })
+        );
+
+        let mut newer = serde_json::json!({ "snapshot_version": 4 });
+        assert!(migrate_snapshot_to(&mut newer, TEST_MIGRATIONS, 3).is_err());
+        let mut current = serde_json::json!({ "snapshot_version": 1 });
+        migrate_snapshot(&mut current).unwrap();
+        assert_eq!(current, serde_json::json!({ "snapshot_version": 1 }));
+    }
+}
@@ -0,0 +1,129 @@
+//! Round trips and clock skew between workers and the coordinator, from
+//! the echo requests workers send periodically.
+//!
+//! An echo returns the coordinator's receive and respond timestamps next
+//! to the worker's send timestamp, NTP style. The worker reports the
+//! round trip it measured with its next echo, so the coordinator can
+//! tell apart network latency from worker clocks running off.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp;
+
+/// Max number of echoes kept per worker, regardless of retention.
+const MAX_SAMPLES: usize = 1_000;
+
+/// Body of `POST /echo`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EchoRequest {
+    /// Worker's clock when sending the request.
+    #[serde(deserialize_with = "timestamp::deserialize")]
+    pub client_t: u64,
+    /// Echoes without a worker id are answered but not recorded.
+    pub worker_id: Option<String>,
+    /// Round trip of the worker's previous echo, excluding the time
+    /// between `received_t` and `responded_t`.
+    pub rtt_ms: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct EchoResponse {
+    pub client_t: u64,
+    pub received_t: u64,
+    pub responded_t: u64,
+}
+
+#[derive(Debug, Clone)]
+struct EchoSample {
+    received_t: u64,
+    /// `received_t - client_t`, one-way latency minus the worker's skew.
+    offset_ms: i64,
+    rtt_ms: Option<u64>,
+}
+
+/// Per-worker echoes, most recent first.
+#[derive(Debug, Default)]
+pub struct NetworkProbes {
+    samples: HashMap<String, VecDeque<EchoSample>>,
+    retention_ms: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WorkerNetwork {
+    pub samples: usize,
+    pub last_echo_t: u64,
+    pub rtt_last_ms: Option<u64>,
+    pub rtt_min_ms: Option<u64>,
+    pub rtt_mean_ms: Option<f64>,
+    /// Worker's clock minus the coordinator's, estimated from the echo
+    /// with the shortest round trip. Positive if the worker is ahead.
+    pub clock_skew_ms: Option<i64>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct NetworkReport {
+    pub workers: BTreeMap<String, WorkerNetwork>,
+}
+
+impl NetworkProbes {
+    pub fn new(retention_ms: u64) -> Self {
+        Self {
+            retention_ms,
+            ..Self::default()
+        }
+    }
+
+    pub fn put(&mut self, worker_id: String, req: &EchoRequest, received_t: u64) {
+        let samples = self.samples.entry(worker_id).or_default();
+        if let Some(prev) = samples.front_mut().filter(|s| s.rtt_ms.is_none()) {
+            prev.rtt_ms = req.rtt_ms;
+        }
+        samples.push_front(EchoSample {
+            received_t,
+            offset_ms: received_t as i64 - req.client_t as i64,
+            rtt_ms: None,
+        });
+        samples.truncate(MAX_SAMPLES);
+    }
+
+    /// Drops echoes older than the retention.
+    pub fn prune(&mut self, now: u64) {
+        let min_t = now.saturating_sub(self.retention_ms);
+        self.samples.retain(|_, samples| {
+            while samples.back().is_some_and(|s| s.received_t < min_t) {
+                samples.pop_back();
+            }
+            !samples.is_empty()
+        });
+    }
+
+    pub fn report(&self, worker_ids: impl Fn(&String) -> bool) -> NetworkReport {
+        let workers = self
+            .samples
+            .iter()
+            .filter(|(worker_id, _)| worker_ids(worker_id))
+            .filter_map(|(worker_id, samples)| {
+                let last = samples.front()?;
+                let rtts = samples
+                    .iter()
+                    .filter_map(|s| Some((s.rtt_ms?, s)))
+                    .collect::<Vec<_>>();
+                let fastest = rtts.iter().min_by_key(|(rtt, _)| *rtt);
+                let network = WorkerNetwork {
+                    samples: samples.len(),
+                    last_echo_t: last.received_t,
+                    rtt_last_ms: rtts.first().map(|(rtt, _)| *rtt),
+                    rtt_min_ms: fastest.map(|(rtt, _)| *rtt),
+                    rtt_mean_ms: (!rtts.is_empty()).then(|| {
+                        rtts.iter().map(|(rtt, _)| *rtt as f64).sum::<f64>() / rtts.len() as f64
+                    }),
+                    clock_skew_ms: fastest.map(|(rtt, s)| *rtt as i64 / 2 - s.offset_ms),
+                };
+                Some((worker_id.clone(), network))
+            })
+            .collect();
+        NetworkReport { workers }
+    }
+}
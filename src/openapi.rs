@@ -0,0 +1,296 @@
+//! OpenAPI description of the worker protocol, served at
+//! `GET /openapi.json`, so worker implementers needn't read the routes to
+//! learn it. Schemas are derived from the request and response types
+//! themselves, paths are described by the stubs below. With the
+//! `swagger-ui` feature a bundled Swagger UI serves it at `/docs/`.
+
+use snark_coordinator_rs::{
+    batch::BatchSummary,
+    errors::{ApiError, ErrorCode},
+    handshake::{HandshakeRequest, HandshakeResponse},
+    job_pool::JobGranted,
+    lock::{LockJobGranted, LockJobHeld, LockJobsConflict},
+    stats::{Lease, SnarkWorkerJobGetError, SnarkWorkerState, SnarkWorkerStatsPut, WorkerMetadata},
+    strict::{Rule, Violation},
+};
+use utoipa::{openapi::server::Server, OpenApi};
+use warp::{filters::BoxedFilter, reply::Response, Filter, Reply};
+
+use crate::API_PREFIX;
+
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "snark-coordinator-rs",
+        description = "Job locks and stats reporting of SNARK workers."
+    ),
+    paths(
+        paths::lock_job_put,
+        paths::lock_job_delete,
+        paths::lock_job_validate,
+        paths::lock_jobs_put,
+        paths::job_get,
+        paths::handshake_post,
+        paths::worker_stats_put,
+        paths::worker_stats_batch_put,
+        paths::worker_stats_get_one,
+    ),
+    components(schemas(
+        ApiError,
+        BatchSummary,
+        ErrorCode,
+        HandshakeRequest,
+        HandshakeResponse,
+        JobGranted,
+        Lease,
+        LockJobGranted,
+        LockJobHeld,
+        LockJobsConflict,
+        Rule,
+        SnarkWorkerJobGetError,
+        SnarkWorkerState,
+        SnarkWorkerStatsPut,
+        Violation,
+        WorkerMetadata,
+    ))
+)]
+struct ApiDoc;
+
+/// The document served at `GET /openapi.json`.
+pub fn spec() -> String {
+    let mut doc = ApiDoc::openapi();
+    // the unprefixed paths are served too, but only for old workers.
+    doc.servers = Some(vec![Server::new(API_PREFIX)]);
+    doc.to_pretty_json().unwrap()
+}
+
+/// `GET /openapi.json`, and `GET /docs/` with the `swagger-ui` feature.
+pub fn routes() -> BoxedFilter<(Response,)> {
+    let spec = spec();
+    let spec_get = warp::path!("openapi.json").and(warp::get()).map(move || {
+        warp::reply::with_header(spec.clone(), "content-type", "application/json").into_response()
+    });
+    #[cfg(feature = "swagger-ui")]
+    let spec_get = spec_get.or(swagger_ui()).unify();
+    spec_get.boxed()
+}
+
+#[cfg(feature = "swagger-ui")]
+fn swagger_ui() -> impl Filter<Extract = (Response,), Error = warp::Rejection> + Clone {
+    use std::sync::Arc;
+
+    use warp::{
+        hyper::Uri,
+        path::{FullPath, Tail},
+    };
+
+    let config = Arc::new(utoipa_swagger_ui::Config::from("/openapi.json"));
+    warp::path("docs")
+        .and(warp::get())
+        .and(warp::path::full())
+        .and(warp::path::tail())
+        .and_then(move |full: FullPath, tail: Tail| {
+            let config = config.clone();
+            async move {
+                if full.as_str() == "/docs" {
+                    let uri = Uri::from_static("/docs/");
+                    return Ok(warp::redirect::found(uri).into_response());
+                }
+                match utoipa_swagger_ui::serve(tail.as_str(), config) {
+                    Ok(Some(file)) => Ok(warp::reply::with_header(
+                        file.bytes.into_owned(),
+                        "content-type",
+                        file.content_type,
+                    )
+                    .into_response()),
+                    _ => Err(warp::reject::not_found()),
+                }
+            }
+        })
+}
+
+/// Stubs carrying the descriptions of the paths, which are served by the
+/// routes of the same names.
+#[allow(dead_code)]
+mod paths {
+    use snark_coordinator_rs::{
+        batch::BatchResponse,
+        errors::ApiError,
+        handshake::{HandshakeRequest, HandshakeResponse},
+        job_pool::JobGranted,
+        lock::{LockJobGranted, LockJobHeld, LockJobsConflict},
+        stats::{SnarkWorkerState, SnarkWorkerStatsPut},
+    };
+
+    use crate::{LockJobDeleteParams, LockJobQueryParams, LockJobValidateParams};
+
+    /// Locks a job so other workers don't work on it too.
+    #[utoipa::path(
+        put,
+        path = "/lock-job/{key}",
+        params(
+            ("key" = String, Path, description = "Id of the job."),
+            LockJobQueryParams,
+            ("x-worker-id" = Option<String>, Header, description = "Holder of the lock, unless given by `worker_id`."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the key."),
+            ("if-match" = Option<String>, Header, description = "Fencing token the key must be held under, to take it over, or with `bump` to extend its lock."),
+            ("if-none-match" = Option<String>, Header, description = "`*`: the key must be unheld, answering 412 otherwise."),
+        ),
+        responses(
+            (status = 201, description = "Locked.", body = LockJobGranted),
+            (status = 200, description = "Held by another worker.", body = LockJobHeld),
+            (status = 412, description = "The `If-Match` or `If-None-Match` condition doesn't hold.", body = ApiError),
+            (status = "4XX", description = "Rejected, e.g. the key is too long.", body = ApiError),
+        )
+    )]
+    fn lock_job_put() {}
+
+    /// Releases a job's lock, given the fencing token it's held under or
+    /// its holder.
+    #[utoipa::path(
+        delete,
+        path = "/lock-job/{key}",
+        params(
+            ("key" = String, Path, description = "Id of the job."),
+            LockJobDeleteParams,
+            ("x-worker-id" = Option<String>, Header, description = "Holder of the lock, unless given by `worker_id`."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the key."),
+        ),
+        responses(
+            (status = 200, description = "Released."),
+            (status = 400, description = "Neither the fencing token nor the holder is given.", body = ApiError),
+            (status = 404, description = "The job isn't locked.", body = ApiError),
+            (status = 409, description = "Held by another worker, or under another fencing token.", body = ApiError),
+        )
+    )]
+    fn lock_job_delete() {}
+
+    /// Checks a fencing token is still the one of the job's lock, before
+    /// acting on the job.
+    #[utoipa::path(
+        get,
+        path = "/lock-job/{key}/validate",
+        params(
+            ("key" = String, Path, description = "Id of the job."),
+            LockJobValidateParams,
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the key."),
+        ),
+        responses(
+            (status = 200, description = "The lock is still held with the token."),
+            (status = 409, description = "The token is stale.", body = ApiError),
+        )
+    )]
+    fn lock_job_validate() {}
+
+    /// Locks all of the jobs or, if any is held already, none of them.
+    #[utoipa::path(
+        put,
+        path = "/lock-jobs",
+        params(
+            LockJobQueryParams,
+            ("x-worker-id" = Option<String>, Header, description = "Holder of the locks, unless given by `worker_id`."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the keys."),
+        ),
+        request_body(content = Vec<String>, description = "Ids of the jobs."),
+        responses(
+            (status = 201, description = "Locked all of them.", body = LockJobGranted),
+            (status = 200, description = "Some are held by other workers, or the per-key results with `partial`.", body = LockJobsConflict),
+            (status = "4XX", description = "Rejected, e.g. no keys, a duplicate or a key too long.", body = ApiError),
+        )
+    )]
+    fn lock_jobs_put() {}
+
+    /// Hands out a job of the node's pending snark work nobody holds the
+    /// lock of, locked for the worker. Only with `--node-graphql`.
+    #[utoipa::path(
+        get,
+        path = "/job",
+        params(
+            LockJobQueryParams,
+            ("x-worker-id" = Option<String>, Header, description = "Holder of the lock, unless given by `worker_id`."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace the job is locked in."),
+        ),
+        responses(
+            (status = 200, description = "Locked for the worker.", body = JobGranted),
+            (status = 409, description = "The lock namespace holds its max number of locks.", body = ApiError),
+            (status = 404, description = "All of the pending work is locked, or job distribution isn't enabled.", body = ApiError),
+        )
+    )]
+    fn job_get() {}
+
+    /// Checks the worker is set up right before it registers.
+    #[utoipa::path(
+        post,
+        path = "/handshake",
+        request_body = HandshakeRequest,
+        responses(
+            (status = 200, description = "The worker may register.", body = HandshakeResponse),
+            (status = 400, description = "The worker may not register, see `problems`.", body = HandshakeResponse),
+        )
+    )]
+    fn handshake_post() {}
+
+    /// Reports a change of the worker's state. Events are acknowledged once
+    /// queued, and applied in the order they were queued in. `Register` waits
+    /// to be applied and responds with the session id to report under, and the
+    /// worker token if tokens are required. A JSON array of events is queued
+    /// as a batch.
+    #[utoipa::path(
+        put,
+        path = "/worker-stats/{worker_id}",
+        params(
+            ("worker_id" = String, Path, description = "Name, or the session id given by `Register`."),
+            ("x-worker-token" = Option<String>, Header, description = "Token issued on `Register`, if tokens are required."),
+            ("traceparent" = Option<String>, Header, description = "W3C trace context of the event."),
+            ("idempotency-key" = Option<String>, Header, description = "Retries with the same key are applied once."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the event's jobs."),
+        ),
+        request_body = SnarkWorkerStatsPut,
+        responses(
+            (status = 200, description = "`Register` applied. The body is the session id.", body = String),
+            (status = 202, description = "Queued. `x-stats-version` is the event's position in the queue."),
+            (status = "4XX", description = "Rejected, e.g. a `--strict` violation or an invalid transition of a `Register`.", body = ApiError),
+            (status = 503, description = "Events aren't applied anymore.", body = ApiError),
+        )
+    )]
+    fn worker_stats_put() {}
+
+    /// Reports several changes of the worker's state, applied in order.
+    #[utoipa::path(
+        put,
+        path = "/worker-stats/{worker_id}/batch",
+        params(
+            ("worker_id" = String, Path, description = "Session id given by `Register`."),
+            ("x-worker-token" = Option<String>, Header, description = "Token issued on `Register`, if tokens are required."),
+            ("traceparent" = Option<String>, Header, description = "W3C trace context of the events."),
+            ("idempotency-key" = Option<String>, Header, description = "Retries of the batch with the same key are applied once."),
+            ("x-lock-namespace" = Option<String>, Header, description = "Lock namespace of the events' jobs."),
+        ),
+        request_body(content = Vec<SnarkWorkerStatsPut>, description = "Events other than `Register`."),
+        responses(
+            (status = 200, description = "Whether each event was queued.", body = BatchResponse<SnarkWorkerState>),
+            (status = 503, description = "Events aren't applied anymore.", body = ApiError),
+        )
+    )]
+    fn worker_stats_batch_put() {}
+
+    /// States of the worker by end time, most recent first.
+    #[utoipa::path(
+        get,
+        path = "/worker-stats/{worker_id}",
+        params(
+            ("worker_id" = String, Path, description = "Session id given by `Register`."),
+            ("from_t" = Option<u64>, Query, description = "Only states which ended at or after this time."),
+            ("to_t" = Option<u64>, Query, description = "Only states which ended before this time."),
+            ("order" = Option<String>, Query, description = "`asc` or `desc` by end time, `desc` if not given."),
+            ("limit" = Option<usize>, Query, description = "Max number of states."),
+            ("kinds" = Option<String>, Query, description = "Only states of these comma separated kinds."),
+        ),
+        responses(
+            (status = 200, description = "The states.", body = Vec<SnarkWorkerState>),
+            (status = 400, description = "Unknown kind, or `from_t` after `to_t`.", body = ApiError),
+            (status = 404, description = "Unknown worker.", body = ApiError),
+        )
+    )]
+    fn worker_stats_get_one() {}
+}
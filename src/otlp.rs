@@ -0,0 +1,337 @@
+//! Exports job lifecycles as OpenTelemetry traces over OTLP/HTTP with
+//! JSON encoding. Each job becomes a trace with spans for its phases and
+//! the node-side sub-phases workers report timestamps for.
+
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    fmt::Write,
+    hash::{Hash, Hasher},
+};
+
+use rand::RngCore;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+use crate::stats::SnarkWorkerState;
+
+/// Spans buffered between exports, the oldest are dropped first.
+const MAX_PENDING_SPANS: usize = 100_000;
+const SPAN_KIND_INTERNAL: u8 = 1;
+const STATUS_CODE_ERROR: u8 = 2;
+
+/// Trace context a worker passed in a W3C `traceparent` header.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TraceContext {
+    pub trace_id: [u8; 16],
+    pub parent_span_id: [u8; 8],
+}
+
+impl TraceContext {
+    /// Parses `00-{trace-id}-{parent-id}-{flags}`. Invalid or all-zero
+    /// ids are rejected, as the spec requires.
+    pub fn parse(traceparent: &str) -> Option<Self> {
+        let mut parts = traceparent.trim().split('-');
+        let version = parts.next()?;
+        let (trace_id, parent_span_id) = (parts.next()?, parts.next()?);
+        parts.next()?;
+        if version.len() != 2 || version == "ff" {
+            return None;
+        }
+        let ctx = Self {
+            trace_id: unhex(trace_id)?,
+            parent_span_id: unhex(parent_span_id)?,
+        };
+        (ctx.trace_id != [0; 16] && ctx.parent_span_id != [0; 8]).then_some(ctx)
+    }
+}
+
+fn unhex<const N: usize>(s: &str) -> Option<[u8; N]> {
+    if s.len() != N * 2 {
+        return None;
+    }
+    let mut out = [0; N];
+    for (i, b) in out.iter_mut().enumerate() {
+        *b = u8::from_str_radix(s.get(i * 2..i * 2 + 2)?, 16).ok()?;
+    }
+    Some(out)
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::new(), |mut s, b| {
+        write!(s, "{b:02x}").unwrap();
+        s
+    })
+}
+
+/// Trace id of jobs without a trace context, derived from the job's ids
+/// so every attempt of a job ends up in the same trace.
+fn job_trace_id(ids: &str) -> [u8; 16] {
+    let mut id = [0; 16];
+    for (i, chunk) in id.chunks_mut(8).enumerate() {
+        let mut hasher = DefaultHasher::new();
+        (i, ids).hash(&mut hasher);
+        chunk.copy_from_slice(&hasher.finish().to_be_bytes());
+    }
+    id
+}
+
+fn span_id() -> [u8; 8] {
+    let mut id = [0; 8];
+    rand::thread_rng().fill_bytes(&mut id);
+    id
+}
+
+#[derive(Serialize, Debug, Clone)]
+#[serde(rename_all = "camelCase")]
+pub struct Span {
+    trace_id: String,
+    span_id: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    parent_span_id: Option<String>,
+    name: &'static str,
+    kind: u8,
+    start_time_unix_nano: String,
+    end_time_unix_nano: String,
+    attributes: Vec<Value>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    status: Option<Value>,
+}
+
+pub(crate) fn attribute(key: &str, value: &str) -> Value {
+    json!({ "key": key, "value": { "stringValue": value } })
+}
+
+/// Spans of a finished lifecycle: a `job` root span with a child per
+/// phase, and node-side sub-spans where their timestamps were reported.
+/// Lifecycles which didn't get a job are only traced with a context.
+pub fn lifecycle_spans(
+    worker_id: &str,
+    state: &SnarkWorkerState,
+    ctx: Option<TraceContext>,
+) -> Vec<Span> {
+    let trace_id = match (ctx, state.ids()) {
+        (Some(ctx), _) => ctx.trace_id,
+        (None, Some(ids)) => job_trace_id(ids),
+        (None, None) => return vec![],
+    };
+    let trace_id = hex(&trace_id);
+    let fields = serde_json::to_value(state).unwrap_or_default();
+    let t = |name: &str| fields.get(name).and_then(Value::as_u64);
+    let error = match &fields["error"] {
+        Value::String(s) => Some(s.clone()),
+        Value::Object(err) => Some(
+            err.get("error")
+                .and_then(Value::as_str)
+                .unwrap_or("no available job")
+                .to_owned(),
+        ),
+        _ => None,
+    };
+
+    let mut spans = vec![];
+    let mut span = |name: &'static str,
+                    parent: Option<&str>,
+                    start: Option<u64>,
+                    end: Option<u64>,
+                    attributes: Vec<Value>,
+                    failed: bool| {
+        let (Some(start), Some(end)) = (start, end) else {
+            return None;
+        };
+        let id = hex(&span_id());
+        spans.push(Span {
+            trace_id: trace_id.clone(),
+            span_id: id.clone(),
+            parent_span_id: parent.map(str::to_owned),
+            name,
+            kind: SPAN_KIND_INTERNAL,
+            start_time_unix_nano: (start * 1_000_000).to_string(),
+            end_time_unix_nano: (end.max(start) * 1_000_000).to_string(),
+            attributes,
+            status: failed.then(|| {
+                json!({ "code": STATUS_CODE_ERROR, "message": error.clone().unwrap_or_default() })
+            }),
+        });
+        Some(id)
+    };
+
+    let mut attributes = vec![
+        attribute("worker_id", worker_id),
+        attribute("outcome", state.kind()),
+    ];
+    if let Some(ids) = state.ids() {
+        attributes.push(attribute("ids", ids));
+    }
+    let parent = ctx.map(|ctx| hex(&ctx.parent_span_id));
+    let failed = error.is_some();
+    let root = span(
+        "job",
+        parent.as_deref(),
+        Some(state.start_time()),
+        Some(state.end_time()),
+        attributes,
+        failed,
+    );
+    let root = root.as_deref();
+
+    let job_get_end = t("job_get_success_t").or(t("job_get_error_t"));
+    let job_get = span(
+        "job_get",
+        root,
+        t("job_get_init_t"),
+        job_get_end,
+        vec![],
+        t("job_get_error_t").is_some(),
+    );
+    let node = span(
+        "job_get.node",
+        job_get.as_deref(),
+        t("job_get_node_received_t"),
+        t("job_get_node_request_work_success_t"),
+        vec![],
+        false,
+    );
+    span(
+        "job_get.node.request_work",
+        node.as_deref().or(job_get.as_deref()),
+        t("job_get_node_request_work_init_t"),
+        t("job_get_node_request_work_success_t"),
+        vec![],
+        false,
+    );
+    span(
+        "work_create",
+        root,
+        t("job_get_success_t"),
+        t("work_create_success_t").or(t("work_create_error_t")),
+        vec![],
+        t("work_create_error_t").is_some(),
+    );
+    let work_submit = span(
+        "work_submit",
+        root,
+        t("work_create_success_t"),
+        t("work_submit_success_t").or(t("work_submit_error_t")),
+        vec![],
+        t("work_submit_error_t").is_some(),
+    );
+    let node = span(
+        "work_submit.node",
+        work_submit.as_deref(),
+        t("work_submit_node_received_t"),
+        t("work_submit_node_add_work_success_t"),
+        vec![],
+        false,
+    );
+    span(
+        "work_submit.node.add_work",
+        node.as_deref().or(work_submit.as_deref()),
+        t("work_submit_node_add_work_init_t"),
+        t("work_submit_node_add_work_success_t"),
+        vec![],
+        false,
+    );
+    spans
+}
+
+/// Trace contexts of in-progress lifecycles and spans of finished ones
+/// waiting to be exported.
+#[derive(Debug, Default)]
+pub struct Tracer {
+    contexts: HashMap<String, TraceContext>,
+    spans: Vec<Span>,
+    dropped: u64,
+}
+
+impl Tracer {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a worker's state right after it changed. `ctx` applies to
+    /// the worker's current lifecycle from then on.
+    pub fn observe(
+        &mut self,
+        worker_id: &str,
+        state: &SnarkWorkerState,
+        ctx: Option<TraceContext>,
+    ) {
+        if let Some(ctx) = ctx {
+            self.contexts.insert(worker_id.to_owned(), ctx);
+        }
+        let finished = matches!(
+            state,
+            SnarkWorkerState::JobUnavailable { .. }
+                | SnarkWorkerState::JobGetError { .. }
+                | SnarkWorkerState::WorkCreateError { .. }
+                | SnarkWorkerState::WorkSubmitError { .. }
+                | SnarkWorkerState::WorkSubmitSuccess { .. }
+        );
+        if !finished {
+            return;
+        }
+        let ctx = self.contexts.remove(worker_id);
+        let spans = lifecycle_spans(worker_id, state, ctx);
+        let overflow = (self.spans.len() + spans.len()).saturating_sub(MAX_PENDING_SPANS);
+        if overflow > 0 {
+            let overflow = overflow.min(self.spans.len());
+            self.spans.drain(..overflow);
+            self.dropped += overflow as u64;
+        }
+        self.spans.extend(spans);
+    }
+
+    /// Takes the pending spans and the number of spans dropped since the
+    /// last call.
+    pub fn take(&mut self) -> (Vec<Span>, u64) {
+        (
+            std::mem::take(&mut self.spans),
+            std::mem::take(&mut self.dropped),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct OtlpExporter {
+    client: reqwest::Client,
+    url: String,
+    service_name: String,
+}
+
+impl OtlpExporter {
+    /// `endpoint` is the collector's OTLP/HTTP base URL, e.g.
+    /// `http://collector:4318`.
+    pub fn new(endpoint: &str, service_name: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: format!("{}/v1/traces", endpoint.trim_end_matches('/')),
+            service_name,
+        }
+    }
+
+    pub fn url(&self) -> &str {
+        &self.url
+    }
+
+    pub async fn export(&self, spans: &[Span]) -> Result<(), String> {
+        let body = json!({
+            "resourceSpans": [{
+                "resource": {
+                    "attributes": [attribute("service.name", &self.service_name)],
+                },
+                "scopeSpans": [{
+                    "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                    "spans": spans,
+                }],
+            }],
+        });
+        self.client
+            .post(&self.url)
+            .json(&body)
+            .send()
+            .await
+            .and_then(|res| res.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
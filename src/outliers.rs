@@ -0,0 +1,163 @@
+//! Lifecycles whose phase took longer than most, as concrete examples to
+//! start performance investigations from.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    latency::{self, Phase},
+    stats::SnarkWorkerState,
+};
+
+/// Most outliers reported at once, the slowest ones.
+pub const MAX_OUTLIERS: usize = 1000;
+
+/// Percentile of a phase's durations outliers exceed.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Default)]
+#[serde(rename_all = "lowercase")]
+pub enum Threshold {
+    P50,
+    P90,
+    #[default]
+    P99,
+}
+
+impl Threshold {
+    fn quantile(self) -> f64 {
+        match self {
+            Self::P50 => 0.5,
+            Self::P90 => 0.9,
+            Self::P99 => 0.99,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Outlier {
+    pub worker_id: String,
+    pub ids: String,
+    pub duration_ms: u64,
+    pub job_get_init_t: u64,
+    pub work_submit_success_t: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct OutlierReport {
+    pub stage: Phase,
+    pub over: Threshold,
+    pub from_t: u64,
+    pub to_t: u64,
+    /// Successful lifecycles in the window with a duration for `stage`.
+    pub samples: usize,
+    /// `over` percentile of the durations, `None` without samples.
+    pub threshold_ms: Option<u64>,
+    /// Lifecycles exceeding `threshold_ms`, slowest first, at most
+    /// `limit`.
+    pub outliers: Vec<Outlier>,
+}
+
+/// Successful lifecycles ending within `from_t..=to_t` whose `stage` took
+/// longer than the `over` percentile of all of them.
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    stage: Phase,
+    over: Threshold,
+    from_t: u64,
+    to_t: u64,
+    limit: usize,
+) -> OutlierReport {
+    let mut lifecycles = vec![];
+    for (worker_id, states) in stats {
+        for state in states {
+            let end_t = state.end_time();
+            if end_t < from_t || end_t > to_t {
+                continue;
+            }
+            let duration_ms = latency::phases(state)
+                .into_iter()
+                .flatten()
+                .find_map(|(phase, ms)| (phase == stage).then_some(ms));
+            if let Some(duration_ms) = duration_ms {
+                lifecycles.push((duration_ms, worker_id, state));
+            }
+        }
+    }
+
+    let mut durations = lifecycles.iter().map(|(ms, ..)| *ms).collect::<Vec<_>>();
+    durations.sort_unstable();
+    let threshold_ms =
+        (!durations.is_empty()).then(|| latency::percentile(&durations, over.quantile()));
+    let mut outliers = lifecycles
+        .into_iter()
+        .filter(|(ms, ..)| threshold_ms.is_some_and(|t| *ms > t))
+        .map(|(duration_ms, worker_id, state)| Outlier {
+            worker_id: worker_id.clone(),
+            ids: state.ids().unwrap_or_default().to_owned(),
+            duration_ms,
+            job_get_init_t: state.start_time(),
+            work_submit_success_t: state.end_time(),
+        })
+        .collect::<Vec<_>>();
+    outliers.sort_unstable_by(|a, b| {
+        b.duration_ms
+            .cmp(&a.duration_ms)
+            .then_with(|| a.worker_id.cmp(&b.worker_id))
+    });
+    outliers.truncate(limit.min(MAX_OUTLIERS));
+    OutlierReport {
+        stage,
+        over,
+        from_t,
+        to_t,
+        samples: durations.len(),
+        threshold_ms,
+        outliers,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn succeeded(ids: usize, init_t: u64, create_ms: u64) -> SnarkWorkerState {
+        let created_t = init_t + create_ms;
+        serde_json::from_str(&format!(
+            r#"{{"kind":"WorkSubmitSuccess","job_get_init_t":{init_t},"job_get_success_t":{init_t},
+                "work_create_success_t":{created_t},"work_submit_success_t":{created_t},"ids":"{ids}"}}"#
+        ))
+        .unwrap()
+    }
+
+    #[test]
+    fn reports_lifecycles_over_the_percentile_slowest_first() {
+        // w1 creates work in 1..=100ms, w2 in 1000ms once within the
+        // window and once past it.
+        let w1 = (1..=100).map(|i| succeeded(i, 0, i as u64)).collect();
+        let w2 = VecDeque::from([succeeded(0, 0, 1000), succeeded(1, 10_000, 1000)]);
+        let stats = [("w1".to_owned(), w1), ("w2".to_owned(), w2)];
+        let stats = || stats.iter().map(|(k, v)| (k, v));
+
+        let p90 = report(stats(), Phase::WorkCreate, Threshold::P90, 0, 5000, 5);
+        assert_eq!((p90.samples, p90.threshold_ms), (101, Some(91)));
+        let outliers = (p90.outliers.iter())
+            .map(|o| (o.worker_id.as_str(), o.duration_ms))
+            .collect::<Vec<_>>();
+        assert_eq!(
+            outliers,
+            [
+                ("w2", 1000),
+                ("w1", 100),
+                ("w1", 99),
+                ("w1", 98),
+                ("w1", 97)
+            ]
+        );
+        assert_eq!(p90.outliers[0].ids, "0");
+
+        // phases with no samples have no threshold, nor outliers.
+        let node = report(stats(), Phase::JobGetNode, Threshold::P50, 0, 5000, 5);
+        assert_eq!((node.samples, node.threshold_ms), (0, None));
+        assert!(node.outliers.is_empty());
+    }
+}
@@ -0,0 +1,90 @@
+//! Pins exempting lock records from history eviction, e.g. while an
+//! incident involving them is being investigated.
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::timestamp;
+
+/// Request body of `POST /admin/pin`. At least one of `worker_id` and
+/// the time window has to be given; all given conditions must match.
+#[derive(Deserialize, Debug, Clone)]
+pub struct PinRequest {
+    pub worker_id: Option<String>,
+    #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+    pub from_t: Option<u64>,
+    #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+    pub to_t: Option<u64>,
+    /// Free form note, e.g. an incident link.
+    pub reason: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Pin {
+    pub id: u64,
+    pub worker_id: Option<String>,
+    pub from_t: Option<u64>,
+    pub to_t: Option<u64>,
+    pub reason: Option<String>,
+    pub created_t: u64,
+}
+
+impl Pin {
+    /// Whether a record of `worker_id` at time `t` is covered by the pin.
+    pub fn matches(&self, worker_id: Option<&str>, t: u64) -> bool {
+        self.worker_id
+            .as_deref()
+            .is_none_or(|pinned| Some(pinned) == worker_id)
+            && self.from_t.is_none_or(|from_t| from_t <= t)
+            && self.to_t.is_none_or(|to_t| t <= to_t)
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct Pins {
+    pins: BTreeMap<u64, Pin>,
+    last_id: u64,
+}
+
+impl Pins {
+    pub fn add(&mut self, req: PinRequest) -> Result<&Pin, String> {
+        if req.worker_id.is_none() && req.from_t.is_none() && req.to_t.is_none() {
+            return Err("pin needs a worker_id, from_t or to_t".to_owned());
+        }
+        if let (Some(from_t), Some(to_t)) = (req.from_t, req.to_t) {
+            if from_t > to_t {
+                return Err(format!("from_t ({from_t}) is after to_t ({to_t})"));
+            }
+        }
+        self.last_id += 1;
+        let pin = Pin {
+            id: self.last_id,
+            worker_id: req.worker_id,
+            from_t: req.from_t,
+            to_t: req.to_t,
+            reason: req.reason,
+            created_t: timestamp::now(),
+        };
+        Ok(self.pins.entry(pin.id).or_insert(pin))
+    }
+
+    /// Adds `pin` added to another set of pins before, keeping its id.
+    pub fn insert(&mut self, pin: Pin) {
+        self.last_id = self.last_id.max(pin.id);
+        self.pins.insert(pin.id, pin);
+    }
+
+    pub fn remove(&mut self, id: u64) -> Option<Pin> {
+        self.pins.remove(&id)
+    }
+
+    /// Pins, oldest first.
+    pub fn iter(&self) -> impl Iterator<Item = &Pin> {
+        self.pins.values()
+    }
+
+    pub fn is_pinned(&self, worker_id: Option<&str>, t: u64) -> bool {
+        self.pins.values().any(|pin| pin.matches(worker_id, t))
+    }
+}
@@ -0,0 +1,96 @@
+//! Limit profiles for `--profile`, each a consistent set of the limits
+//! which depend on each other and on the size of the fleet: how many
+//! states are kept and for how long, how fast clients may write, and how
+//! large their requests may be. Options given explicitly still override
+//! the profile's value.
+
+use std::str::FromStr;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Profile {
+    /// Up to ~50 workers, e.g. a testnet or a single operator.
+    Small,
+    /// Up to ~500 workers.
+    Medium,
+    /// Thousands of workers.
+    Large,
+}
+
+impl FromStr for Profile {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "small" => Ok(Self::Small),
+            "medium" => Ok(Self::Medium),
+            "large" => Ok(Self::Large),
+            _ => Err(format!("expected small, medium or large, found: {s}")),
+        }
+    }
+}
+
+/// Limits in effect, see the options of the same name.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Limits {
+    pub max_states_per_worker: Option<usize>,
+    pub max_total_states: Option<usize>,
+    /// Seconds.
+    pub stats_retention: Option<u64>,
+    /// Requests per second per client.
+    pub rate_limit: Option<f64>,
+    pub rate_limit_burst: f64,
+    /// Bytes.
+    pub max_body_size: u64,
+    pub lock_history_len: usize,
+}
+
+/// Without a profile, history is unbounded and clients aren't limited.
+impl Default for Limits {
+    fn default() -> Self {
+        Self {
+            max_states_per_worker: None,
+            max_total_states: None,
+            stats_retention: None,
+            rate_limit: None,
+            rate_limit_burst: 20.0,
+            max_body_size: 4 * 1024 * 1024,
+            lock_history_len: 10_000,
+        }
+    }
+}
+
+impl Profile {
+    /// The total state cap leaves every worker its full history at the
+    /// profile's fleet size.
+    pub fn limits(self) -> Limits {
+        match self {
+            Self::Small => Limits {
+                max_states_per_worker: Some(2_000),
+                max_total_states: Some(100_000),
+                stats_retention: Some(7 * 86_400),
+                rate_limit: Some(50.0),
+                rate_limit_burst: 100.0,
+                max_body_size: 1024 * 1024,
+                lock_history_len: 1_000,
+            },
+            Self::Medium => Limits {
+                max_states_per_worker: Some(1_000),
+                max_total_states: Some(500_000),
+                stats_retention: Some(3 * 86_400),
+                rate_limit: Some(20.0),
+                rate_limit_burst: 40.0,
+                max_body_size: 4 * 1024 * 1024,
+                lock_history_len: 10_000,
+            },
+            Self::Large => Limits {
+                max_states_per_worker: Some(500),
+                max_total_states: Some(2_500_000),
+                stats_retention: Some(86_400),
+                rate_limit: Some(10.0),
+                rate_limit_burst: 20.0,
+                max_body_size: 16 * 1024 * 1024,
+                lock_history_len: 100_000,
+            },
+        }
+    }
+}
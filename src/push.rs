@@ -0,0 +1,561 @@
+//! Pushes coordinator metrics for environments which can't scrape
+//! `/metrics`, to a Prometheus Pushgateway, a remote-write endpoint,
+//! InfluxDB, StatsD or an OTLP/HTTP collector. All get the same metric
+//! families as `/metrics`; remote-write, InfluxDB and StatsD also get a
+//! record of every completed job, for retention beyond the coordinator's
+//! memory.
+
+use std::{
+    collections::HashMap,
+    fmt::Write,
+    sync::{Arc, Mutex},
+};
+
+use prometheus::proto::{MetricFamily, MetricType};
+use prost::Message;
+use serde_json::{json, Value};
+
+use crate::{
+    latency::{self, Phase},
+    otlp::attribute,
+    stats::SnarkWorkerState,
+    timestamp,
+};
+
+/// Completed jobs buffered between pushes, the oldest are dropped first.
+const MAX_PENDING_JOBS: usize = 100_000;
+/// Max StatsD datagram size, to stay within a typical MTU.
+const STATSD_PACKET_SIZE: usize = 1432;
+
+#[derive(Debug, Clone)]
+pub enum PushTarget {
+    /// Pushgateway base URL, metrics are PUT under `/metrics/job/{job}`.
+    Pushgateway { url: String, job: String },
+    /// Prometheus remote-write (v1) endpoint.
+    RemoteWrite { url: String },
+    /// InfluxDB write endpoint, v1 `/write?db=..` or v2
+    /// `/api/v2/write?org=..&bucket=..`, getting line protocol.
+    Influx { url: String, token: Option<String> },
+    /// StatsD `host:port` getting UDP datagrams, with labels as
+    /// DogStatsD-style tags.
+    Statsd { addr: String },
+    /// OTLP/HTTP collector metrics URL, e.g.
+    /// `http://collector:4318/v1/metrics`, getting JSON.
+    Otlp { url: String, service_name: String },
+}
+
+impl PushTarget {
+    pub fn url(&self) -> &str {
+        match self {
+            Self::Pushgateway { url, .. }
+            | Self::RemoteWrite { url }
+            | Self::Influx { url, .. }
+            | Self::Otlp { url, .. } => url,
+            Self::Statsd { addr } => addr,
+        }
+    }
+
+    /// Whether the target stores [`CompletedJob`]s. The Pushgateway only
+    /// keeps the latest value of each series, so it doesn't, and OTLP
+    /// collectors get job lifecycles as traces with `--otlp-endpoint`.
+    pub fn takes_jobs(&self) -> bool {
+        !matches!(self, Self::Pushgateway { .. } | Self::Otlp { .. })
+    }
+}
+
+/// Record of a successful job lifecycle.
+#[derive(Debug, Clone)]
+pub struct CompletedJob {
+    pub worker_id: String,
+    pub ids: String,
+    /// When the work was submitted.
+    pub time: u64,
+    pub phases: Vec<(Phase, u64)>,
+}
+
+impl CompletedJob {
+    pub fn new(worker_id: &str, state: &SnarkWorkerState) -> Option<Self> {
+        Some(Self {
+            worker_id: worker_id.to_owned(),
+            ids: state.ids()?.to_owned(),
+            time: state.end_time(),
+            phases: latency::phases(state)?,
+        })
+    }
+}
+
+/// Jobs completed since the last push.
+#[derive(Debug, Default)]
+pub struct JobBuffer {
+    jobs: Vec<CompletedJob>,
+    dropped: u64,
+}
+
+impl JobBuffer {
+    pub fn add(&mut self, job: CompletedJob) {
+        if self.jobs.len() >= MAX_PENDING_JOBS {
+            self.jobs.remove(0);
+            self.dropped += 1;
+        }
+        self.jobs.push(job);
+    }
+
+    pub fn len(&self) -> usize {
+        self.jobs.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.jobs.is_empty()
+    }
+
+    /// Takes the buffered jobs and the number of jobs dropped since the
+    /// last call.
+    pub fn take(&mut self) -> (Vec<CompletedJob>, u64) {
+        (
+            std::mem::take(&mut self.jobs),
+            std::mem::take(&mut self.dropped),
+        )
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct MetricsPusher {
+    client: reqwest::Client,
+    target: PushTarget,
+    /// When the pusher was created, as the start of OTLP cumulative sums.
+    started_t: u64,
+    /// Last pushed value of each StatsD counter, which are sent as deltas.
+    statsd_counters: Arc<Mutex<HashMap<String, f64>>>,
+}
+
+impl MetricsPusher {
+    pub fn new(target: PushTarget) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            target,
+            started_t: timestamp::now(),
+            statsd_counters: Default::default(),
+        }
+    }
+
+    pub fn target(&self) -> &PushTarget {
+        &self.target
+    }
+
+    pub async fn push(
+        &self,
+        families: &[MetricFamily],
+        jobs: &[CompletedJob],
+    ) -> Result<(), String> {
+        let req = match &self.target {
+            PushTarget::Pushgateway { url, job } => {
+                let mut body = Vec::new();
+                prometheus::Encoder::encode(&prometheus::TextEncoder::new(), families, &mut body)
+                    .map_err(|err| err.to_string())?;
+                let url = format!("{}/metrics/job/{job}", url.trim_end_matches('/'));
+                self.client
+                    .put(url)
+                    .header("content-type", prometheus::TEXT_FORMAT)
+                    .body(body)
+                    .send()
+            }
+            PushTarget::RemoteWrite { url } => {
+                let body = write_request(families, jobs, timestamp::now() as i64).encode_to_vec();
+                self.client
+                    .post(url)
+                    .header("content-type", "application/x-protobuf")
+                    .header("content-encoding", "snappy")
+                    .header("x-prometheus-remote-write-version", "0.1.0")
+                    .body(snappy_literal(&body))
+                    .send()
+            }
+            PushTarget::Influx { url, token } => {
+                let body = line_protocol(families, jobs, timestamp::now());
+                // timestamps are in milliseconds.
+                let req = self
+                    .client
+                    .post(url)
+                    .query(&[("precision", "ms")])
+                    .header("content-type", "text/plain; charset=utf-8")
+                    .body(body);
+                match token {
+                    Some(token) => req.header("authorization", format!("Token {token}")),
+                    None => req,
+                }
+                .send()
+            }
+            PushTarget::Statsd { addr } => {
+                let lines = statsd_lines(families, jobs, &mut self.statsd_counters.lock().unwrap());
+                return send_statsd(addr, &lines)
+                    .await
+                    .map_err(|err| err.to_string());
+            }
+            PushTarget::Otlp { url, service_name } => {
+                let body = otlp_metrics(families, service_name, self.started_t, timestamp::now());
+                self.client.post(url).json(&body).send()
+            }
+        };
+        req.await
+            .and_then(|res| res.error_for_status())
+            .map(|_| ())
+            .map_err(|err| err.to_string())
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct WriteRequest {
+    #[prost(message, repeated, tag = "1")]
+    timeseries: Vec<TimeSeries>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct TimeSeries {
+    #[prost(message, repeated, tag = "1")]
+    labels: Vec<Label>,
+    #[prost(message, repeated, tag = "2")]
+    samples: Vec<Sample>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Label {
+    #[prost(string, tag = "1")]
+    name: String,
+    #[prost(string, tag = "2")]
+    value: String,
+}
+
+#[derive(Clone, PartialEq, Message)]
+struct Sample {
+    #[prost(double, tag = "1")]
+    value: f64,
+    #[prost(int64, tag = "2")]
+    timestamp: i64,
+}
+
+/// Sample of a flattened metric family.
+struct FlatSample<'a> {
+    name: String,
+    labels: Vec<(&'a str, String)>,
+    value: f64,
+    /// Whether the value only grows, like counters and histogram series.
+    cumulative: bool,
+}
+
+/// Flattens metric families into series the way Prometheus would when
+/// scraping them, e.g. histograms into `_bucket`, `_sum` and `_count`
+/// series.
+fn flatten(families: &[MetricFamily]) -> Vec<FlatSample<'_>> {
+    let mut samples = vec![];
+    for family in families {
+        let name = family.get_name();
+        for metric in family.get_metric() {
+            let labels = metric
+                .get_label()
+                .iter()
+                .map(|l| (l.get_name(), l.get_value().to_owned()))
+                .collect::<Vec<_>>();
+            let cumulative = family.get_field_type() != MetricType::GAUGE;
+            let mut series = |suffix: &str, extra: Option<(&'static str, String)>, value: f64| {
+                let mut labels = labels.clone();
+                labels.extend(extra);
+                samples.push(FlatSample {
+                    name: format!("{name}{suffix}"),
+                    cumulative: cumulative && !labels.iter().any(|(l, _)| *l == "quantile"),
+                    labels,
+                    value,
+                });
+            };
+            match family.get_field_type() {
+                MetricType::COUNTER => series("", None, metric.get_counter().get_value()),
+                MetricType::GAUGE => series("", None, metric.get_gauge().get_value()),
+                // none of the coordinator's collectors are untyped.
+                MetricType::UNTYPED => {}
+                MetricType::HISTOGRAM => {
+                    let h = metric.get_histogram();
+                    for bucket in h.get_bucket() {
+                        let le = bucket.get_upper_bound().to_string();
+                        let count = bucket.get_cumulative_count() as f64;
+                        series("_bucket", Some(("le", le)), count);
+                    }
+                    let count = h.get_sample_count() as f64;
+                    series("_bucket", Some(("le", "+Inf".to_owned())), count);
+                    series("_sum", None, h.get_sample_sum());
+                    series("_count", None, count);
+                }
+                MetricType::SUMMARY => {
+                    let s = metric.get_summary();
+                    for q in s.get_quantile() {
+                        let quantile = q.get_quantile().to_string();
+                        series("", Some(("quantile", quantile)), q.get_value());
+                    }
+                    series("_sum", None, s.get_sample_sum());
+                    series("_count", None, s.get_sample_count() as f64);
+                }
+            }
+        }
+    }
+    samples
+}
+
+fn write_request(families: &[MetricFamily], jobs: &[CompletedJob], timestamp: i64) -> WriteRequest {
+    let series = |name: String, labels: Vec<(&str, String)>, value: f64, timestamp: i64| {
+        let mut labels = std::iter::once(("__name__", name))
+            .chain(labels)
+            .collect::<Vec<_>>();
+        labels.sort_by(|a, b| a.0.cmp(b.0));
+        let labels = labels
+            .into_iter()
+            .map(|(name, value)| Label {
+                name: name.to_owned(),
+                value,
+            })
+            .collect();
+        TimeSeries {
+            labels,
+            samples: vec![Sample { value, timestamp }],
+        }
+    };
+    let mut timeseries = flatten(families)
+        .into_iter()
+        .map(|s| series(s.name, s.labels, s.value, timestamp))
+        .collect::<Vec<_>>();
+    for job in jobs {
+        for (phase, ms) in &job.phases {
+            let labels = vec![
+                ("phase", phase.as_str().to_owned()),
+                ("worker_id", job.worker_id.clone()),
+            ];
+            let name = "snark_coordinator_job_phase_ms".to_owned();
+            timeseries.push(series(name, labels, *ms as f64, job.time as i64));
+        }
+    }
+    WriteRequest { timeseries }
+}
+
+/// Escapes measurement names, tag keys and tag values.
+fn influx_escape(s: &str) -> String {
+    s.replace('\\', "\\\\")
+        .replace(',', "\\,")
+        .replace('=', "\\=")
+        .replace(' ', "\\ ")
+}
+
+/// InfluxDB line protocol with millisecond timestamps. Metrics become
+/// one measurement per series with a `value` field, completed jobs the
+/// `snark_job` measurement with a field per phase.
+fn line_protocol(families: &[MetricFamily], jobs: &[CompletedJob], timestamp: u64) -> String {
+    let mut out = String::new();
+    for sample in flatten(families) {
+        if !sample.value.is_finite() {
+            continue;
+        }
+        out.push_str(&influx_escape(&sample.name));
+        for (name, value) in &sample.labels {
+            write!(out, ",{}={}", influx_escape(name), influx_escape(value)).unwrap();
+        }
+        writeln!(out, " value={} {timestamp}", sample.value).unwrap();
+    }
+    for job in jobs {
+        write!(
+            out,
+            "snark_job,worker_id={} ",
+            influx_escape(&job.worker_id)
+        )
+        .unwrap();
+        for (phase, ms) in &job.phases {
+            write!(out, "{}_ms={ms}i,", phase.as_str()).unwrap();
+        }
+        let ids = job.ids.replace('\\', "\\\\").replace('"', "\\\"");
+        writeln!(out, "ids=\"{ids}\" {}", job.time).unwrap();
+    }
+    out
+}
+
+/// Replaces the characters StatsD uses as separators.
+fn statsd_escape(s: &str) -> String {
+    s.replace([':', '|', '@', ',', '#', '\n'], "_")
+}
+
+/// StatsD lines: gauges as `g`, counters and histogram series as `c`
+/// deltas since the values in `sent`, which are updated, and completed
+/// job phases as `ms` timers.
+fn statsd_lines(
+    families: &[MetricFamily],
+    jobs: &[CompletedJob],
+    sent: &mut HashMap<String, f64>,
+) -> Vec<String> {
+    let mut lines = vec![];
+    for sample in flatten(families) {
+        if !sample.value.is_finite() {
+            continue;
+        }
+        let mut line = statsd_escape(&sample.name);
+        let tags = (sample.labels.iter())
+            .map(|(name, value)| format!("{}:{}", statsd_escape(name), statsd_escape(value)))
+            .collect::<Vec<_>>();
+        let tags = match tags.is_empty() {
+            true => String::new(),
+            false => format!("|#{}", tags.join(",")),
+        };
+        if sample.cumulative {
+            let last = sent.insert(format!("{line}{tags}"), sample.value);
+            // counters reset with the process, anything pushed before is
+            // accounted for already.
+            let delta = match last {
+                Some(last) if last <= sample.value => sample.value - last,
+                _ => sample.value,
+            };
+            if delta == 0.0 {
+                continue;
+            }
+            write!(line, ":{delta}|c{tags}").unwrap();
+        } else {
+            write!(line, ":{}|g{tags}", sample.value).unwrap();
+        }
+        lines.push(line);
+    }
+    for job in jobs {
+        for (phase, ms) in &job.phases {
+            lines.push(format!(
+                "snark_coordinator_job_phase:{ms}|ms|#phase:{}",
+                phase.as_str()
+            ));
+        }
+    }
+    lines
+}
+
+/// Sends `lines` to `addr` in as few datagrams as fit them.
+async fn send_statsd(addr: &str, lines: &[String]) -> std::io::Result<()> {
+    let socket = tokio::net::UdpSocket::bind("0.0.0.0:0").await?;
+    socket.connect(addr).await?;
+    let mut packet = String::new();
+    for line in lines {
+        if !packet.is_empty() && packet.len() + 1 + line.len() > STATSD_PACKET_SIZE {
+            socket.send(packet.as_bytes()).await?;
+            packet.clear();
+        }
+        if !packet.is_empty() {
+            packet.push('\n');
+        }
+        packet.push_str(line);
+    }
+    if !packet.is_empty() {
+        socket.send(packet.as_bytes()).await?;
+    }
+    Ok(())
+}
+
+/// OTLP `ExportMetricsServiceRequest` in JSON encoding. Counters become
+/// cumulative monotonic sums since `started_t`, and histograms explicit
+/// bucket histograms.
+fn otlp_metrics(families: &[MetricFamily], service_name: &str, started_t: u64, t: u64) -> Value {
+    let (start, time) = (
+        (started_t * 1_000_000).to_string(),
+        (t * 1_000_000).to_string(),
+    );
+    let metrics = families
+        .iter()
+        .filter_map(|family| {
+            let points = family.get_metric().iter().map(|metric| {
+                let attributes = (metric.get_label().iter())
+                    .map(|l| attribute(l.get_name(), l.get_value()))
+                    .collect::<Vec<_>>();
+                let mut point = json!({
+                    "attributes": attributes,
+                    "startTimeUnixNano": start,
+                    "timeUnixNano": time,
+                });
+                match family.get_field_type() {
+                    MetricType::COUNTER => {
+                        point["asDouble"] = json!(metric.get_counter().get_value());
+                    }
+                    MetricType::GAUGE => {
+                        point["asDouble"] = json!(metric.get_gauge().get_value());
+                    }
+                    _ => {
+                        let h = metric.get_histogram();
+                        let mut counts = vec![];
+                        let mut below = 0;
+                        for bucket in h.get_bucket() {
+                            counts.push((bucket.get_cumulative_count() - below).to_string());
+                            below = bucket.get_cumulative_count();
+                        }
+                        counts.push((h.get_sample_count() - below).to_string());
+                        let bounds = (h.get_bucket().iter())
+                            .map(|bucket| bucket.get_upper_bound())
+                            .collect::<Vec<_>>();
+                        point["count"] = json!(h.get_sample_count().to_string());
+                        point["sum"] = json!(h.get_sample_sum());
+                        point["bucketCounts"] = json!(counts);
+                        point["explicitBounds"] = json!(bounds);
+                    }
+                }
+                point
+            });
+            let points = points.collect::<Vec<_>>();
+            let data = match family.get_field_type() {
+                MetricType::COUNTER => (
+                    "sum",
+                    json!({
+                        "dataPoints": points,
+                        "aggregationTemporality": 2,
+                        "isMonotonic": true,
+                    }),
+                ),
+                MetricType::GAUGE => ("gauge", json!({ "dataPoints": points })),
+                MetricType::HISTOGRAM => (
+                    "histogram",
+                    json!({ "dataPoints": points, "aggregationTemporality": 2 }),
+                ),
+                // none of the coordinator's collectors are untyped or
+                // summaries.
+                MetricType::UNTYPED | MetricType::SUMMARY => return None,
+            };
+            let mut metric = json!({
+                "name": family.get_name(),
+                "description": family.get_help(),
+            });
+            metric[data.0] = data.1;
+            Some(metric)
+        })
+        .collect::<Vec<_>>();
+    json!({
+        "resourceMetrics": [{
+            "resource": {
+                "attributes": [attribute("service.name", service_name)],
+            },
+            "scopeMetrics": [{
+                "scope": { "name": env!("CARGO_PKG_NAME"), "version": env!("CARGO_PKG_VERSION") },
+                "metrics": metrics,
+            }],
+        }],
+    })
+}
+
+/// Snappy block format made of literals only. Valid for any decoder and
+/// the payloads are small, so actual compression isn't worth a dependency.
+fn snappy_literal(data: &[u8]) -> Vec<u8> {
+    /// Max literal length encodable with a 2 byte length.
+    const CHUNK: usize = 1 << 16;
+
+    let mut out = Vec::with_capacity(data.len() + data.len() / CHUNK * 3 + 8);
+    let mut len = data.len() as u64;
+    while len >= 0x80 {
+        out.push(len as u8 | 0x80);
+        len >>= 7;
+    }
+    out.push(len as u8);
+    for chunk in data.chunks(CHUNK) {
+        let n = chunk.len() - 1;
+        if n < 60 {
+            out.push((n as u8) << 2);
+        } else if n < 1 << 8 {
+            out.extend([60 << 2, n as u8]);
+        } else {
+            out.extend([61 << 2, n as u8, (n >> 8) as u8]);
+        }
+        out.extend_from_slice(chunk);
+    }
+    out
+}
@@ -0,0 +1,75 @@
+//! Stats payloads rejected as malformed or invalid transitions, kept
+//! verbatim with `--quarantine` so client bugs can be diagnosed from
+//! what the workers actually sent.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use crate::{errors::ErrorCode, timestamp};
+
+/// Header carrying the id a rejected payload was quarantined under.
+pub const HEADER: &str = "x-quarantine-id";
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct QuarantinedPayload {
+    pub id: u64,
+    pub worker_id: String,
+    /// `INVALID_BODY` or `INVALID_TRANSITION`.
+    pub code: ErrorCode,
+    /// Why the payload was rejected.
+    pub error: String,
+    /// The payload as received, invalid UTF-8 replaced.
+    pub payload: String,
+    pub received_t: u64,
+}
+
+/// Most recent quarantined payloads, the oldest are dropped first.
+#[derive(Debug)]
+pub struct Quarantine {
+    payloads: VecDeque<QuarantinedPayload>,
+    capacity: usize,
+    last_id: u64,
+}
+
+impl Quarantine {
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            payloads: VecDeque::new(),
+            capacity,
+            last_id: 0,
+        }
+    }
+
+    /// Keeps `payload`, returns the id it's kept under.
+    pub fn add(&mut self, worker_id: &str, code: ErrorCode, error: &str, payload: &[u8]) -> u64 {
+        if self.payloads.len() >= self.capacity {
+            self.payloads.pop_front();
+        }
+        self.last_id += 1;
+        self.payloads.push_back(QuarantinedPayload {
+            id: self.last_id,
+            worker_id: worker_id.to_owned(),
+            code,
+            error: error.to_owned(),
+            payload: String::from_utf8_lossy(payload).into_owned(),
+            received_t: timestamp::now(),
+        });
+        self.last_id
+    }
+
+    /// Quarantined payloads of workers `filter` accepts, oldest first.
+    pub fn list(&self, filter: impl Fn(&str) -> bool) -> Vec<&QuarantinedPayload> {
+        self.payloads
+            .iter()
+            .filter(|p| filter(&p.worker_id))
+            .collect()
+    }
+
+    /// Drops all payloads, returns how many there were.
+    pub fn clear(&mut self) -> usize {
+        let n = self.payloads.len();
+        self.payloads.clear();
+        n
+    }
+}
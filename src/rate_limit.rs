@@ -0,0 +1,74 @@
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+/// Token bucket rate limiter, one bucket per client.
+#[derive(Debug)]
+pub struct RateLimiter {
+    /// Tokens added per second.
+    rate: f64,
+    /// Bucket capacity, i.e. the largest allowed burst.
+    burst: f64,
+    buckets: HashMap<String, Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimiter {
+    pub fn new(rate: f64, burst: f64) -> Self {
+        Self {
+            rate,
+            burst: burst.max(1.0),
+            buckets: HashMap::new(),
+        }
+    }
+
+    /// Changes the rate and burst, keeping the clients' buckets.
+    pub fn set_limits(&mut self, rate: f64, burst: f64) {
+        self.rate = rate;
+        self.burst = burst.max(1.0);
+    }
+
+    /// Takes a token from the client's bucket. If it's empty, returns
+    /// how long until a token becomes available.
+    pub fn check(&mut self, client: &str, now: Instant) -> Result<(), Duration> {
+        let bucket = match self.buckets.get_mut(client) {
+            Some(bucket) => bucket,
+            None => self.buckets.entry(client.to_owned()).or_insert(Bucket {
+                tokens: self.burst,
+                updated: now,
+            }),
+        };
+        let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            return Ok(());
+        }
+        Err(Duration::from_secs_f64((1.0 - bucket.tokens) / self.rate))
+    }
+
+    /// Drops buckets which refilled completely, they are the same as new
+    /// ones.
+    pub fn sweep(&mut self, now: Instant) {
+        let (rate, burst) = (self.rate, self.burst);
+        self.buckets.retain(|_, bucket| {
+            let elapsed = now.saturating_duration_since(bucket.updated).as_secs_f64();
+            bucket.tokens + elapsed * rate < burst
+        });
+    }
+
+    pub fn len(&self) -> usize {
+        self.buckets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.buckets.is_empty()
+    }
+}
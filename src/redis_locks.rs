@@ -0,0 +1,422 @@
+//! Lock store shared between coordinator replicas for
+//! `--lock-backend redis://..`. Keys are claimed with `SET NX PX`
+//! semantics, all of a request's keys in one script so they're granted
+//! together or not at all, and fencing tokens come from a counter in
+//! Redis, so they keep increasing across replicas.
+//!
+//! Each replica still mirrors the locks it granted into its [`LockTable`]
+//! for history, leases and snapshots. Those only cover the replica's own
+//! grants.
+//!
+//! [`LockTable`]: crate::lock::LockTable
+
+use std::{collections::BTreeMap, str::FromStr};
+
+use redis::{aio::ConnectionManager, RedisResult, Script, Value};
+
+use crate::lock::{LockCondition, LockJobHeld};
+
+/// Prefix of the keys the coordinator uses in Redis.
+const PREFIX: &str = "snark-coordinator:";
+
+/// Claims all `KEYS[2..]` for `ARGV[1]` with a TTL of `ARGV[2]` ms under
+/// a fencing token drawn from `KEYS[1]`. If any is held, returns the
+/// index, value and remaining TTL of each held key instead.
+const ACQUIRE: &str = r"
+local held = {}
+for i = 2, #KEYS do
+    local value = redis.call('GET', KEYS[i])
+    if value then
+        table.insert(held, i - 2)
+        table.insert(held, value)
+        table.insert(held, redis.call('PTTL', KEYS[i]))
+    end
+end
+if #held > 0 then
+    return held
+end
+local token = redis.call('INCR', KEYS[1])
+for i = 2, #KEYS do
+    redis.call('SET', KEYS[i], token .. ':' .. ARGV[1], 'PX', ARGV[2])
+end
+return token
+";
+
+/// Claims `KEYS[2]` for `ARGV[1]` with a TTL of `ARGV[2]` ms if it's
+/// unheld and `ARGV[4]` is `bump` or `ARGV[3]` is empty, or if it's held
+/// under the fencing token `ARGV[3]`, which it keeps if `ARGV[4]` is
+/// `bump`. New fencing tokens are drawn from `KEYS[1]`. Otherwise returns
+/// the value and remaining TTL of the key, nothing if it's unheld.
+const ACQUIRE_IF: &str = r"
+local value = redis.call('GET', KEYS[2])
+local expected = ARGV[3]
+if value then
+    if expected == '' or string.sub(value, 1, #expected + 1) ~= expected .. ':' then
+        return {value, redis.call('PTTL', KEYS[2])}
+    end
+    if ARGV[4] == 'bump' then
+        local holder = ARGV[1]
+        if holder == '' then
+            holder = string.sub(value, #expected + 2)
+        end
+        redis.call('SET', KEYS[2], expected .. ':' .. holder, 'PX', ARGV[2])
+        return tonumber(expected)
+    end
+elseif expected ~= '' and ARGV[4] ~= 'bump' then
+    return {}
+end
+local token = redis.call('INCR', KEYS[1])
+redis.call('SET', KEYS[2], token .. ':' .. ARGV[1], 'PX', ARGV[2])
+return token
+";
+
+/// Deletes `KEYS[1]`, only if it's held under the fencing token `ARGV[1]`
+/// unless that's empty.
+const RELEASE: &str = r"
+local value = redis.call('GET', KEYS[1])
+if not value then
+    return 0
+end
+if ARGV[1] ~= '' and string.sub(value, 1, #ARGV[1] + 1) ~= ARGV[1] .. ':' then
+    return 0
+end
+return redis.call('DEL', KEYS[1])
+";
+
+/// Where job locks are kept, given as `memory` or a `redis://` URL.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum LockBackend {
+    /// In this process only.
+    #[default]
+    Memory,
+    Redis(String),
+}
+
+impl LockBackend {
+    /// URL of the Redis backend, which may contain a password.
+    pub fn redis_url(&self) -> Option<&str> {
+        match self {
+            Self::Memory => None,
+            Self::Redis(url) => Some(url),
+        }
+    }
+}
+
+impl FromStr for LockBackend {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "memory" => Ok(Self::Memory),
+            _ if s.starts_with("redis://") || s.starts_with("redis+unix://") => {
+                Ok(Self::Redis(s.to_owned()))
+            }
+            _ => Err(format!("expected `memory` or a `redis://` URL, found: {s}")),
+        }
+    }
+}
+
+/// Lock holder and fencing token stored as `{fencing_token}:{holder}`.
+fn parse_value(value: &str) -> Option<(u64, Option<String>)> {
+    let (token, holder) = value.split_once(':')?;
+    let holder = Some(holder.to_owned()).filter(|h| !h.is_empty());
+    Some((token.parse().ok()?, holder))
+}
+
+pub struct RedisLocks {
+    conn: ConnectionManager,
+    acquire: Script,
+    acquire_if: Script,
+    release: Script,
+}
+
+impl RedisLocks {
+    /// Connects to `url`, reconnecting by itself if the connection drops
+    /// later on.
+    pub async fn connect(url: &str) -> RedisResult<Self> {
+        let client = redis::Client::open(url)?;
+        Ok(Self {
+            conn: client.get_tokio_connection_manager().await?,
+            acquire: Script::new(ACQUIRE),
+            acquire_if: Script::new(ACQUIRE_IF),
+            release: Script::new(RELEASE),
+        })
+    }
+
+    fn key(key: &str) -> String {
+        format!("{PREFIX}lock:{key}")
+    }
+
+    /// Locks all `keys` for `ttl_ms`, returning the fencing token of the
+    /// grant, or if any of them is held, none of them, returning the held
+    /// ones.
+    pub async fn acquire(
+        &self,
+        keys: &[String],
+        holder: Option<&str>,
+        ttl_ms: u64,
+    ) -> RedisResult<Result<u64, BTreeMap<String, LockJobHeld>>> {
+        let mut invocation = self.acquire.key(format!("{PREFIX}fencing-token"));
+        for key in keys {
+            invocation.key(Self::key(key));
+        }
+        invocation
+            .arg(holder.unwrap_or_default())
+            .arg(ttl_ms.max(1));
+        let items = match invocation.invoke_async(&mut self.conn.clone()).await? {
+            Value::Int(token) => return Ok(Ok(token as u64)),
+            Value::Bulk(items) => items,
+            value => return Err(unexpected(value)),
+        };
+        let mut held = BTreeMap::new();
+        for item in items.chunks(3) {
+            let [index, value, pttl] = item else {
+                return Err(unexpected(Value::Bulk(item.to_vec())));
+            };
+            let index: usize = redis::from_redis_value(index)?;
+            let value: String = redis::from_redis_value(value)?;
+            let pttl: i64 = redis::from_redis_value(pttl)?;
+            let Some(key) = keys.get(index) else {
+                continue;
+            };
+            let holder = parse_value(&value).and_then(|(_, holder)| holder);
+            let remaining_ttl_ms = pttl.max(0) as u64;
+            held.insert(
+                key.clone(),
+                LockJobHeld {
+                    holder,
+                    remaining_ttl_ms,
+                    queued: 0,
+                },
+            );
+        }
+        Ok(Err(held))
+    }
+
+    /// Locks `key` for `ttl_ms` if `condition` holds, returning the
+    /// fencing token of the grant. Otherwise returns the current lock,
+    /// `None` if unheld.
+    pub async fn acquire_if(
+        &self,
+        key: &str,
+        holder: Option<&str>,
+        ttl_ms: u64,
+        condition: LockCondition,
+    ) -> RedisResult<Result<u64, Option<LockJobHeld>>> {
+        let (expected, mode) = match condition {
+            LockCondition::Unheld => (None, ""),
+            LockCondition::TakeOver(token) => (Some(token), ""),
+            LockCondition::Bump(token) => (Some(token), "bump"),
+        };
+        let value = self
+            .acquire_if
+            .key(format!("{PREFIX}fencing-token"))
+            .key(Self::key(key))
+            .arg(holder.unwrap_or_default())
+            .arg(ttl_ms.max(1))
+            .arg(expected.map(|t| t.to_string()).unwrap_or_default())
+            .arg(mode)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        let items = match value {
+            Value::Int(token) => return Ok(Ok(token as u64)),
+            Value::Bulk(items) => items,
+            value => return Err(unexpected(value)),
+        };
+        let [value, pttl] = &items[..] else {
+            return match items.is_empty() {
+                true => Ok(Err(None)),
+                false => Err(unexpected(Value::Bulk(items))),
+            };
+        };
+        let value: String = redis::from_redis_value(value)?;
+        let pttl: i64 = redis::from_redis_value(pttl)?;
+        Ok(Err(Some(LockJobHeld {
+            holder: parse_value(&value).and_then(|(_, holder)| holder),
+            remaining_ttl_ms: pttl.max(0) as u64,
+            queued: 0,
+        })))
+    }
+
+    /// Releases the lock of `key`, only if it's held under `fencing_token`
+    /// if given. Returns whether it was released.
+    pub async fn release(&self, key: &str, fencing_token: Option<u64>) -> RedisResult<bool> {
+        let token = fencing_token.map(|t| t.to_string()).unwrap_or_default();
+        let deleted: u64 = self
+            .release
+            .key(Self::key(key))
+            .arg(token)
+            .invoke_async(&mut self.conn.clone())
+            .await?;
+        Ok(deleted > 0)
+    }
+
+    /// Whether `fencing_token` belongs to the current lock of `key`.
+    pub async fn validate(&self, key: &str, fencing_token: u64) -> RedisResult<bool> {
+        let value: Option<String> = redis::cmd("GET")
+            .arg(Self::key(key))
+            .query_async(&mut self.conn.clone())
+            .await?;
+        let token = value.as_deref().and_then(parse_value).map(|(t, _)| t);
+        Ok(token == Some(fencing_token))
+    }
+}
+
+fn unexpected(value: Value) -> redis::RedisError {
+    let msg = format!("{value:?}");
+    (
+        redis::ErrorKind::TypeError,
+        "unexpected script response",
+        msg,
+    )
+        .into()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::{Arc, Mutex};
+
+    use tokio::{
+        io::{AsyncReadExt, AsyncWriteExt},
+        net::TcpListener,
+    };
+
+    use super::*;
+
+    type Commands = Arc<Mutex<Vec<Vec<String>>>>;
+
+    /// URL of a Redis server answering the commands it's sent with
+    /// `replies` in order, and the commands it was sent.
+    async fn fake_redis(replies: Vec<&'static str>) -> (String, Commands) {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let url = format!("redis://{}", listener.local_addr().unwrap());
+        let commands = Commands::default();
+        let mut replies = replies.into_iter();
+        let sent = commands.clone();
+        tokio::spawn(async move {
+            let (mut conn, _) = listener.accept().await.unwrap();
+            let mut buf = vec![];
+            let mut chunk = [0; 1024];
+            while let Ok(n @ 1..) = conn.read(&mut chunk).await {
+                buf.extend_from_slice(&chunk[..n]);
+                while let Some((command, len)) = parse_command(&buf) {
+                    buf.drain(..len);
+                    sent.lock().unwrap().push(command);
+                    let reply = replies.next().expect("a reply left");
+                    conn.write_all(reply.as_bytes()).await.unwrap();
+                }
+            }
+        });
+        (url, commands)
+    }
+
+    /// Arguments of the command `buf` starts with, and its length, if
+    /// it's all there.
+    fn parse_command(buf: &[u8]) -> Option<(Vec<String>, usize)> {
+        // number following the type byte of the line at `at`, and where
+        // the next line starts.
+        let line = |at: usize| -> Option<(usize, usize)> {
+            let end = at + buf.get(at..)?.windows(2).position(|w| w == b"\r\n")?;
+            let n = std::str::from_utf8(buf.get(at + 1..end)?).ok()?;
+            Some((n.parse().ok()?, end + 2))
+        };
+        let (n, mut at) = line(0)?;
+        let mut args = Vec::with_capacity(n);
+        for _ in 0..n {
+            let (len, start) = line(at)?;
+            let arg = buf.get(start..start + len)?;
+            args.push(String::from_utf8_lossy(arg).into_owned());
+            at = start + len + 2;
+        }
+        (at <= buf.len()).then_some((args, at))
+    }
+
+    #[test]
+    fn backends_are_memory_or_redis_urls() {
+        assert_eq!("memory".parse(), Ok(LockBackend::Memory));
+        let redis = "redis://:secret@host:6379".parse::<LockBackend>().unwrap();
+        assert_eq!(redis.redis_url(), Some("redis://:secret@host:6379"));
+        assert!("redis+unix:///run/redis.sock"
+            .parse::<LockBackend>()
+            .is_ok());
+        assert!("http://host".parse::<LockBackend>().is_err());
+
+        assert_eq!(parse_value("3:w1"), Some((3, Some("w1".to_owned()))));
+        assert_eq!(parse_value("3:"), Some((3, None)));
+        assert_eq!(parse_value("w1"), None);
+    }
+
+    #[tokio::test]
+    async fn acquire_grants_all_keys_or_reports_the_held_ones() {
+        let held = "*3\r\n:1\r\n$4\r\n3:w2\r\n:500\r\n";
+        let (url, commands) = fake_redis(vec![":7\r\n", held]).await;
+        let locks = RedisLocks::connect(&url).await.unwrap();
+        let keys = ["a".to_owned(), "b".to_owned()];
+
+        let granted = locks.acquire(&keys, Some("w1"), 1000).await.unwrap();
+        assert_eq!(granted.unwrap(), 7);
+        let conflicts = locks.acquire(&keys, None, 0).await.unwrap().unwrap_err();
+        assert_eq!(conflicts.keys().collect::<Vec<_>>(), ["b"]);
+        assert_eq!(conflicts["b"].holder.as_deref(), Some("w2"));
+        assert_eq!(conflicts["b"].remaining_ttl_ms, 500);
+
+        let commands = commands.lock().unwrap();
+        let args = |i: usize| commands[i][2..].to_vec();
+        assert_eq!(commands[0][0], "EVALSHA");
+        let lock_keys = [
+            "3",
+            "snark-coordinator:fencing-token",
+            "snark-coordinator:lock:a",
+            "snark-coordinator:lock:b",
+        ];
+        assert_eq!(args(0), [&lock_keys[..], &["w1", "1000"]].concat());
+        // no TTL would make the keys never expire.
+        assert_eq!(args(1), [&lock_keys[..], &["", "1"]].concat());
+    }
+
+    #[tokio::test]
+    async fn conditions_and_releases_pass_the_fencing_token() {
+        let (url, commands) = fake_redis(vec![
+            "*0\r\n",
+            "*2\r\n$4\r\n5:w2\r\n:250\r\n",
+            "-NOSCRIPT No matching script.\r\n",
+            "+0123456789abcdef\r\n",
+            ":1\r\n",
+            ":0\r\n",
+        ])
+        .await;
+        let locks = RedisLocks::connect(&url).await.unwrap();
+
+        let bump = LockCondition::Bump(5);
+        let unheld = locks.acquire_if("a", Some("w1"), 100, bump).await.unwrap();
+        assert!(unheld.unwrap_err().is_none());
+        let take_over = LockCondition::TakeOver(4);
+        let held = locks.acquire_if("a", None, 100, take_over).await.unwrap();
+        let held = held.unwrap_err().unwrap();
+        assert_eq!(held.holder.as_deref(), Some("w2"));
+        assert_eq!(held.remaining_ttl_ms, 250);
+        // the script is loaded if Redis doesn't have it.
+        assert!(locks.release("a", Some(5)).await.unwrap());
+        assert!(!locks.release("a", None).await.unwrap());
+
+        let commands = commands.lock().unwrap();
+        let commands = commands.iter().map(|c| &c[..]).collect::<Vec<_>>();
+        let lock_keys = [
+            "2",
+            "snark-coordinator:fencing-token",
+            "snark-coordinator:lock:a",
+        ];
+        assert_eq!(
+            commands[0][2..],
+            [&lock_keys[..], &["w1", "100", "5", "bump"]].concat()
+        );
+        assert_eq!(
+            commands[1][2..],
+            [&lock_keys[..], &["", "100", "4", ""]].concat()
+        );
+        assert_eq!(commands[3][..2], ["SCRIPT", "LOAD"]);
+        assert_eq!(commands[2][2..], ["1", "snark-coordinator:lock:a", "5"]);
+        assert_eq!(commands[4], commands[2]);
+        assert_eq!(commands[5][2..], ["1", "snark-coordinator:lock:a", ""]);
+    }
+}
@@ -0,0 +1,177 @@
+//! Reloading settings on SIGHUP or `POST /admin/reload`, without the
+//! restart which would lose the in-memory state. Options are parsed again,
+//! from the `--config` file and environment as at startup, and those which
+//! can change at runtime applied: lock timeouts, stats retention, rate
+//! limits, the log filter and the webhook URL. The others only take effect
+//! on restart.
+
+use std::sync::Arc;
+
+use serde::Serialize;
+use snark_coordinator_rs::{rate_limit::RateLimiter, webhook::Webhook};
+use structopt::StructOpt;
+use tokio::sync::{watch, Mutex};
+use tracing::info;
+use tracing_subscriber::EnvFilter;
+
+use crate::{config, Opts};
+
+/// Replaces the filter of the installed log subscriber.
+pub type SetLogFilter = Box<dyn Fn(EnvFilter) -> Result<(), String> + Send + Sync>;
+
+/// Settings read where they're used, so they can change at runtime.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Settings {
+    pub default_timeout_ms: u64,
+    pub min_timeout_ms: u64,
+    pub max_timeout_ms: u64,
+    pub stats_retention_ms: Option<u64>,
+}
+
+impl Settings {
+    pub fn new(opts: &Opts) -> Self {
+        Self {
+            default_timeout_ms: u64::from(opts.default_timeout) * 1000,
+            min_timeout_ms: opts.min_timeout_ms,
+            max_timeout_ms: opts.max_timeout_ms(),
+            stats_retention_ms: (opts.limits().stats_retention).map(|s| s.saturating_mul(1000)),
+        }
+    }
+}
+
+#[derive(Serialize, Debug, Default)]
+pub struct ReloadReport {
+    /// Options whose new values were applied.
+    pub changed: Vec<&'static str>,
+    /// Options which changed but need a restart, e.g. turning rate
+    /// limiting on or off.
+    pub restart_required: Vec<&'static str>,
+}
+
+/// Values of the options applied by other means than [`Settings`]. Those
+/// needing a restart keep their startup value.
+#[derive(Debug)]
+struct Applied {
+    rate_limit: Option<f64>,
+    rate_limit_burst: f64,
+    log_level: String,
+    webhook_url: Option<String>,
+}
+
+impl Applied {
+    fn new(opts: &Opts) -> Self {
+        let limits = opts.limits();
+        Self {
+            rate_limit: limits.rate_limit,
+            rate_limit_burst: limits.rate_limit_burst,
+            log_level: opts.log_level.clone(),
+            webhook_url: opts.webhook_url.clone(),
+        }
+    }
+}
+
+pub struct Reloader {
+    settings: watch::Sender<Settings>,
+    applied: Mutex<Applied>,
+    rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+    webhook: Option<Webhook>,
+    set_log_filter: SetLogFilter,
+}
+
+impl Reloader {
+    pub fn new(
+        opts: &Opts,
+        rate_limiter: Option<Arc<Mutex<RateLimiter>>>,
+        webhook: Option<Webhook>,
+        set_log_filter: SetLogFilter,
+    ) -> Self {
+        let (settings, _) = watch::channel(Settings::new(opts));
+        Self {
+            settings,
+            applied: Mutex::new(Applied::new(opts)),
+            rate_limiter,
+            webhook,
+            set_log_filter,
+        }
+    }
+
+    pub fn settings(&self) -> watch::Receiver<Settings> {
+        self.settings.subscribe()
+    }
+
+    /// Parses the options again and applies those which changed. Nothing
+    /// is applied if they're invalid.
+    pub async fn reload(&self) -> Result<ReloadReport, String> {
+        let args = config::args(&Opts::clap())?;
+        let opts = Opts::from_iter_safe(args).map_err(|err| err.message)?;
+        opts.check_timeouts()?;
+        let mut report = ReloadReport::default();
+
+        let mut applied = self.applied.lock().await;
+        let new = Applied::new(&opts);
+        let log_filter = match new.log_level != applied.log_level {
+            true => Some(EnvFilter::try_new(&new.log_level).map_err(|err| err.to_string())?),
+            false => None,
+        };
+
+        let settings = Settings::new(&opts);
+        self.settings.send_if_modified(|old| {
+            for (name, changed) in [
+                (
+                    "default_timeout",
+                    old.default_timeout_ms != settings.default_timeout_ms,
+                ),
+                (
+                    "min_timeout_ms",
+                    old.min_timeout_ms != settings.min_timeout_ms,
+                ),
+                (
+                    "max_timeout_ms",
+                    old.max_timeout_ms != settings.max_timeout_ms,
+                ),
+                (
+                    "stats_retention",
+                    old.stats_retention_ms != settings.stats_retention_ms,
+                ),
+            ] {
+                if changed {
+                    report.changed.push(name);
+                }
+            }
+            let modified = *old != settings;
+            *old = settings;
+            modified
+        });
+
+        if (new.rate_limit, new.rate_limit_burst) != (applied.rate_limit, applied.rate_limit_burst)
+        {
+            match (&self.rate_limiter, new.rate_limit) {
+                (Some(limiter), Some(rate)) => {
+                    limiter.lock().await.set_limits(rate, new.rate_limit_burst);
+                    report.changed.push("rate_limit");
+                    (applied.rate_limit, applied.rate_limit_burst) =
+                        (new.rate_limit, new.rate_limit_burst);
+                }
+                (None, None) => applied.rate_limit_burst = new.rate_limit_burst,
+                _ => report.restart_required.push("rate_limit"),
+            }
+        }
+        if let Some(filter) = log_filter {
+            (self.set_log_filter)(filter)?;
+            report.changed.push("log_level");
+            applied.log_level = new.log_level;
+        }
+        if new.webhook_url != applied.webhook_url {
+            match (&self.webhook, new.webhook_url) {
+                (Some(webhook), Some(url)) => {
+                    webhook.set_url(url.clone());
+                    report.changed.push("webhook_url");
+                    applied.webhook_url = Some(url);
+                }
+                _ => report.restart_required.push("webhook_url"),
+            }
+        }
+        info!(?report, "reloaded options");
+        Ok(report)
+    }
+}
@@ -0,0 +1,135 @@
+//! `replay` subcommand: rebuilds the state of a coordinator from its
+//! `--journal`, or a `GET /snapshot` body, and serves the read-only API
+//! over it, to look into past incidents offline. Entries are applied as
+//! of their own time, so the locks held are those of the time replayed
+//! to, while liveness and windowed reports still count back from now.
+
+use std::{
+    io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use snark_coordinator_rs::{
+    journal::{self, JournalEntry, JournalOp, Replayed},
+    lock::LockedShards,
+    migrations,
+    stats::WorkerStats,
+    timestamp,
+};
+use structopt::StructOpt;
+use tracing::info;
+
+use crate::{StateSnapshot, StatsIngest};
+
+// what to replay. Not a doc comment, which would replace the about of
+// the subcommand flattening it.
+#[derive(Debug, StructOpt)]
+pub struct ReplayOpts {
+    /// Journal written with `--journal`, or a `GET /snapshot` body.
+    #[structopt(parse(from_os_str))]
+    pub path: PathBuf,
+    /// Stop at this time, unix milliseconds or RFC 3339 like
+    /// `2024-05-01T14:32:00Z`.
+    #[structopt(long)]
+    pub until: Option<ReplayTime>,
+    /// Apply the entries while serving, this many times faster than they
+    /// were recorded, e.g. `60` for an hour a minute. All of them are
+    /// applied before serving if unset.
+    #[structopt(long)]
+    pub speed: Option<f64>,
+}
+
+/// Time of `--until`, in milliseconds.
+#[derive(Debug, Clone, Copy)]
+pub struct ReplayTime(pub u64);
+
+impl FromStr for ReplayTime {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        if let Ok(t) = s.parse::<u64>() {
+            return Ok(Self(timestamp::normalize(t)));
+        }
+        match chrono::DateTime::parse_from_rfc3339(s) {
+            Ok(t) => Ok(Self(t.timestamp_millis().max(0) as u64)),
+            Err(_) => Err(format!(
+                "invalid time {s:?}, expected unix milliseconds or RFC 3339"
+            )),
+        }
+    }
+}
+
+/// Entries of the journal or snapshot at `path` up to `until`. A
+/// snapshot is restored as of when it was taken. Either is refused if
+/// it's of a newer format version than this build reads.
+pub fn load(path: &Path, until: Option<u64>) -> io::Result<Vec<JournalEntry>> {
+    let data = std::fs::read_to_string(path)?;
+    let snapshot = serde_json::from_str::<serde_json::Value>(&data)
+        .ok()
+        .filter(|value| value.get("taken_t").is_some());
+    let entries = match snapshot.map(StateSnapshot::from_value).transpose()? {
+        Some(snapshot) => vec![
+            JournalEntry {
+                t: snapshot.taken_t,
+                op: JournalOp::RestoreLocks {
+                    locks: snapshot.locks,
+                },
+            },
+            JournalEntry {
+                t: snapshot.taken_t,
+                op: JournalOp::RestoreStats {
+                    stats: snapshot.stats,
+                },
+            },
+        ],
+        None => match migrations::migrate_journal_contents(&data)? {
+            Some(migrated) => journal::parse(&migrated)?,
+            None => journal::parse(&data)?,
+        },
+    };
+    Ok((entries.into_iter())
+        .filter(|entry| until.is_none_or(|until| entry.t <= until))
+        .collect())
+}
+
+/// Applies `entries` at once, each as of its own time.
+pub fn apply(
+    entries: Vec<JournalEntry>,
+    stats: &mut WorkerStats,
+    kv: &mut LockedShards,
+) -> Replayed {
+    let mut replayed = Replayed::default();
+    for entry in entries {
+        let t = entry.t;
+        if !journal::apply_at(entry, stats, kv, t) {
+            replayed.rejected += 1;
+        }
+        replayed.entries += 1;
+    }
+    replayed
+}
+
+/// Applies `entries` to `ingest` at `speed` times the pace they were
+/// recorded at.
+pub async fn run(ingest: StatsIngest, entries: Vec<JournalEntry>, speed: f64) {
+    let Some(first_t) = entries.first().map(|entry| entry.t) else {
+        return;
+    };
+    let started = tokio::time::Instant::now();
+    let mut replayed = Replayed::default();
+    for entry in entries {
+        let elapsed_ms = entry.t.saturating_sub(first_t) as f64 / speed;
+        tokio::time::sleep_until(started + Duration::from_secs_f64(elapsed_ms / 1000.0)).await;
+        if !ingest.replay(entry).await {
+            replayed.rejected += 1;
+        }
+        replayed.entries += 1;
+    }
+    info!(
+        entries = replayed.entries,
+        rejected = replayed.rejected,
+        "replay finished"
+    );
+}
@@ -0,0 +1,42 @@
+use rand::RngCore;
+
+/// Milliseconds a confirmation token of a reset stays valid.
+pub const CONFIRMATION_TTL_MS: u64 = 60 * 1000;
+
+/// Token to repeat in a reset request, so resetting every worker's stats
+/// takes two deliberate requests. Only the latest token is valid.
+#[derive(Debug, Default)]
+pub struct ResetConfirmations {
+    /// Token and its expiry in milliseconds.
+    pending: Option<(String, u64)>,
+}
+
+impl ResetConfirmations {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token, replacing the previous one.
+    pub fn issue(&mut self, now: u64) -> String {
+        let mut bytes = [0u8; 16];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        self.pending = Some((token.clone(), now + CONFIRMATION_TTL_MS));
+        token
+    }
+
+    /// Whether `token` is the pending unexpired one, which is used up.
+    pub fn confirm(&mut self, token: &str, now: u64) -> bool {
+        let valid = self.pending.as_ref().is_some_and(|(pending, expires_t)| {
+            now < *expires_t && constant_time_eq(pending.as_bytes(), token.as_bytes())
+        });
+        if valid {
+            self.pending = None;
+        }
+        valid
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,440 @@
+//! Operator routes: failover role, annotations, snapshots, maintenance
+//! windows, scheduled tasks, reloads, the quarantine and resets.
+
+use std::{sync::Arc, time::Instant};
+
+use snark_coordinator_rs::{
+    annotations::AnnotationRequest,
+    errors::{self, ErrorCode},
+    journal::{self, JournalOp},
+    maintenance::MaintenanceRequest,
+    reset::{self, ResetConfirmations},
+    timestamp,
+};
+use tokio::sync::Mutex;
+use tracing::{debug, info, warn};
+use warp::{
+    filters::BoxedFilter,
+    hyper::StatusCode,
+    reply::{with_status, Response},
+    Filter, Reply,
+};
+
+use super::Context;
+use crate::{
+    body, error_reply, failover::Role, live, AnnotationsGetParams, QuarantineGetParams,
+    ResetParams, StateSnapshot,
+};
+
+/// `/admin/*` less pins and locks, `/annotations`, `/snapshot` and
+/// `/replication`.
+pub fn routes(ctx: &Context) -> BoxedFilter<(Response,)> {
+    let Context {
+        reloader,
+        ingest,
+        quarantine,
+        groups_config,
+        annotations,
+        maintenance,
+        role,
+        scheduler,
+        max_body_size,
+        ..
+    } = ctx.clone();
+
+    let role_ = role.clone();
+    let admin_role_get = warp::path!("admin" / "role")
+        .and(warp::get())
+        .then(move || {
+            let role = role_.clone();
+            async move {
+                with_status(
+                    serde_json::to_string(&*role.lock().await).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let role_ = role.clone();
+    let admin_role_put = warp::path!("admin" / "role")
+        .and(warp::put())
+        .and(body::json(max_body_size))
+        .then(move |new_role: Role| {
+            let role = role_.clone();
+            async move {
+                let mut role = role.lock().await;
+                info!(from = ?*role, to = ?new_role, "role switched");
+                *role = new_role;
+                with_status(
+                    serde_json::to_string(&*role).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let notes = annotations.clone();
+    let annotations_post = warp::path!("annotations")
+        .and(warp::post())
+        .and(body::json(max_body_size))
+        .then(move |req: AnnotationRequest| {
+            let notes = notes.clone();
+            async move {
+                match notes.lock().await.add(req) {
+                    Ok(annotation) => {
+                        info!(?annotation, "annotation added");
+                        with_status(
+                            serde_json::to_string(&annotation).unwrap(),
+                            StatusCode::from_u16(201).unwrap(),
+                        )
+                    }
+                    Err(err) => error_reply(ErrorCode::InvalidAnnotation, err),
+                }
+            }
+        });
+
+    let notes = annotations.clone();
+    let annotations_get = warp::path!("annotations")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<AnnotationsGetParams>()
+                .or(warp::any().map(AnnotationsGetParams::default))
+                .unify(),
+        )
+        .then(move |params: AnnotationsGetParams| {
+            let notes = notes.clone();
+            async move {
+                let from_t = params.from_t.map(timestamp::normalize);
+                let to_t = params.to_t.map(timestamp::normalize);
+                let notes = notes.lock().await;
+                with_status(
+                    serde_json::to_string(&notes.within(from_t, to_t)).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let notes = annotations.clone();
+    let annotation_delete =
+        warp::path!("annotations" / u64)
+            .and(warp::delete())
+            .then(move |id: u64| {
+                let notes = notes.clone();
+                async move {
+                    match notes.lock().await.remove(id) {
+                        Some(annotation) => {
+                            info!(?annotation, "annotation removed");
+                            with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+                        }
+                        None => error_reply(
+                            ErrorCode::AnnotationNotFound,
+                            format!("no such annotation: {id}"),
+                        ),
+                    }
+                }
+            });
+
+    let error_codes_get = warp::path!("errors" / "codes").and(warp::get()).map(|| {
+        with_status(
+            serde_json::to_string(&errors::registry()).unwrap(),
+            StatusCode::from_u16(200).unwrap(),
+        )
+    });
+
+    let ingest_ = ingest.clone();
+    let replication_get = warp::path!("replication").and(warp::get()).then(move || {
+        let ingest = ingest_.clone();
+        async move {
+            let Some((snapshot, entries)) = ingest.replication_feed().await else {
+                let msg = "replication is only served with --cluster";
+                return error_reply(ErrorCode::NotFound, msg).into_response();
+            };
+            let events = live::replication(snapshot, entries);
+            warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+        }
+    });
+
+    let ingest_ = ingest.clone();
+    let snapshot_get = warp::path!("snapshot").and(warp::get()).then(move || {
+        let ingest = ingest_.clone();
+        async move {
+            let stats = ingest.stats.read().await;
+            let kv = ingest.kv.lock_all().await;
+            let snapshot = StateSnapshot {
+                snapshot_version: journal::VERSION,
+                taken_t: timestamp::now(),
+                locks: kv.snapshot(Instant::now()),
+                stats: stats.snapshot(),
+            };
+            with_status(
+                serde_json::to_string(&snapshot).unwrap(),
+                StatusCode::from_u16(200).unwrap(),
+            )
+        }
+    });
+
+    // snapshots of large fleets may need a higher `--max-body-size`.
+    let ingest_ = ingest.clone();
+    let snapshot_post = warp::path!("snapshot")
+        .and(warp::post())
+        .and(body::json(max_body_size))
+        .then(move |snapshot: serde_json::Value| {
+            let ingest = ingest_.clone();
+            async move {
+                let snapshot = match StateSnapshot::from_value(snapshot) {
+                    Ok(snapshot) => snapshot,
+                    Err(err) => {
+                        let msg = format!("invalid snapshot: {err}");
+                        debug!("{msg}");
+                        return error_reply(ErrorCode::InvalidBody, msg);
+                    }
+                };
+                let mut stats = ingest.stats.write().await;
+                let mut kv = ingest.kv.lock_all().await;
+                if !stats.is_empty() || !kv.is_empty() {
+                    let msg = "instance already holds locks or worker stats";
+                    return error_reply(ErrorCode::InstanceNotEmpty, msg);
+                }
+                let (locks, workers) = (snapshot.locks.locks.len(), snapshot.stats.workers.len());
+                kv.restore(snapshot.locks, Instant::now());
+                ingest.journal(|| JournalOp::RestoreStats {
+                    stats: snapshot.stats.clone(),
+                });
+                stats.restore(snapshot.stats);
+                ingest.version.send_modify(|v| *v += 1);
+                info!(
+                    locks,
+                    workers,
+                    taken_t = snapshot.taken_t,
+                    "snapshot loaded"
+                );
+                with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+            }
+        });
+
+    let quarantine_ = quarantine.clone();
+    let admin_quarantine_get = warp::path!("admin" / "quarantine")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<QuarantineGetParams>()
+                .or(warp::any().map(QuarantineGetParams::default))
+                .unify(),
+        )
+        .then(move |params: QuarantineGetParams| {
+            let quarantine = quarantine_.clone();
+            async move {
+                let Some(quarantine) = quarantine else {
+                    let msg = "quarantine isn't enabled, see --quarantine";
+                    return error_reply(ErrorCode::NotFound, msg);
+                };
+                let workers_filter = params
+                    .workers
+                    .map(|s| s.split(',').map(|s| s.to_owned()).collect::<Vec<_>>());
+                let quarantine = quarantine.lock().await;
+                let payloads = quarantine.list(|worker_id| {
+                    workers_filter
+                        .as_ref()
+                        .is_none_or(|f| f.iter().any(|w| w == worker_id))
+                });
+                with_status(
+                    serde_json::to_string(&payloads).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let quarantine_ = quarantine.clone();
+    let admin_quarantine_delete = warp::path!("admin" / "quarantine")
+        .and(warp::delete())
+        .then(move || {
+            let quarantine = quarantine_.clone();
+            async move {
+                if let Some(quarantine) = quarantine {
+                    let cleared = quarantine.lock().await.clear();
+                    info!(cleared, "quarantine cleared");
+                }
+                with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+            }
+        });
+
+    // without `confirm`, only a token to confirm the reset with is issued.
+    let ingest_ = ingest.clone();
+    let confirmations = Arc::new(Mutex::new(ResetConfirmations::new()));
+    let admin_reset_post = warp::path!("admin" / "reset")
+        .and(warp::post())
+        .and(
+            warp::filters::query::query::<ResetParams>()
+                .or(warp::any().map(ResetParams::default))
+                .unify(),
+        )
+        .then(move |params: ResetParams| {
+            let ingest = ingest_.clone();
+            let confirmations = confirmations.clone();
+            async move {
+                let now = timestamp::now();
+                let Some(token) = params.confirm else {
+                    let token = confirmations.lock().await.issue(now);
+                    let workers = ingest.stats.read().await.keys().count();
+                    let body = serde_json::json!({
+                        "confirmation_token": token,
+                        "expires_in_s": reset::CONFIRMATION_TTL_MS / 1000,
+                        "workers": workers,
+                    });
+                    return with_status(body.to_string(), StatusCode::from_u16(202).unwrap());
+                };
+                if !confirmations.lock().await.confirm(&token, now) {
+                    let msg = "unknown or expired confirmation token, request a new one";
+                    return error_reply(ErrorCode::InvalidConfirmation, msg);
+                }
+                let workers = ingest.reset().await;
+                warn!(workers, "worker stats reset");
+                with_status(
+                    serde_json::json!({ "workers": workers }).to_string(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let windows = maintenance.clone();
+    let notes = annotations.clone();
+    let admin_maintenance_post = warp::path!("admin" / "maintenance")
+        .and(warp::post())
+        .and(body::json(max_body_size))
+        .then(move |req: MaintenanceRequest| {
+            let windows = windows.clone();
+            let notes = notes.clone();
+            let teams = groups
+                .as_deref()
+                .map(|g| g.teams.clone())
+                .unwrap_or_default();
+            async move {
+                let mut windows = windows.lock().await;
+                let window = match windows.add(req, &teams) {
+                    Ok(window) => window,
+                    Err(err) => return error_reply(ErrorCode::InvalidMaintenance, err),
+                };
+                let note = AnnotationRequest {
+                    time: Some(window.from_t),
+                    end_t: Some(window.to_t),
+                    text: window.describe(),
+                    tags: vec!["maintenance".to_owned()],
+                };
+                window.annotation_id = notes.lock().await.add(note).ok().map(|a| a.id);
+                info!(?window, "maintenance scheduled");
+                with_status(
+                    serde_json::to_string(&window).unwrap(),
+                    StatusCode::from_u16(201).unwrap(),
+                )
+            }
+        });
+
+    let windows = maintenance.clone();
+    let admin_maintenance_get =
+        warp::path!("admin" / "maintenance")
+            .and(warp::get())
+            .then(move || {
+                let windows = windows.clone();
+                async move {
+                    let windows = windows.lock().await;
+                    let windows = windows.iter().collect::<Vec<_>>();
+                    with_status(
+                        serde_json::to_string(&windows).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            });
+
+    let windows = maintenance.clone();
+    let notes = annotations.clone();
+    let admin_maintenance_delete = warp::path!("admin" / "maintenance" / u64)
+        .and(warp::delete())
+        .then(move |id: u64| {
+            let windows = windows.clone();
+            let notes = notes.clone();
+            async move {
+                let Some(window) = windows.lock().await.remove(id) else {
+                    let msg = format!("no such maintenance window: {id}");
+                    return error_reply(ErrorCode::MaintenanceNotFound, msg);
+                };
+                if let Some(annotation_id) = window.annotation_id {
+                    notes.lock().await.remove(annotation_id);
+                }
+                info!(?window, "maintenance cancelled");
+                with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+            }
+        });
+
+    let reloader_ = reloader.clone();
+    let admin_reload_post = warp::path!("admin" / "reload")
+        .and(warp::post())
+        .then(move || {
+            let reloader = reloader_.clone();
+            async move {
+                match reloader.reload().await {
+                    Ok(report) => with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    ),
+                    Err(err) => {
+                        warn!(%err, "failed to reload options, keeping the current ones");
+                        error_reply(ErrorCode::InvalidConfig, err)
+                    }
+                }
+            }
+        });
+
+    let tasks = scheduler.clone();
+    let admin_tasks_get = warp::path!("admin" / "tasks")
+        .and(warp::get())
+        .map(move || {
+            with_status(
+                serde_json::to_string(&tasks.tasks()).unwrap(),
+                StatusCode::from_u16(200).unwrap(),
+            )
+        });
+
+    let tasks = scheduler.clone();
+    let admin_task_post = warp::path!("admin" / "tasks" / String / String)
+        .and(warp::post())
+        .map(move |name: String, action: String| {
+            let status = match action.as_str() {
+                "trigger" => tasks.trigger(&name),
+                "pause" => tasks.set_paused(&name, true),
+                "resume" => tasks.set_paused(&name, false),
+                _ => {
+                    let msg = format!("unknown task action: {action}");
+                    return error_reply(ErrorCode::NotFound, msg);
+                }
+            };
+            let Some(status) = status else {
+                return error_reply(ErrorCode::TaskNotFound, format!("no such task: {name}"));
+            };
+            info!(?status, action, "task updated");
+            let code = if action == "trigger" { 202 } else { 200 };
+            with_status(
+                serde_json::to_string(&status).unwrap(),
+                StatusCode::from_u16(code).unwrap(),
+            )
+        });
+
+    admin_role_get
+        .or(admin_role_put)
+        .or(annotations_post)
+        .or(annotations_get)
+        .or(annotation_delete)
+        .or(error_codes_get)
+        .or(snapshot_get)
+        .or(snapshot_post)
+        .or(replication_get)
+        .or(admin_maintenance_post)
+        .or(admin_maintenance_get)
+        .or(admin_maintenance_delete)
+        .or(admin_tasks_get)
+        .or(admin_task_post)
+        .or(admin_reload_post)
+        .or(admin_quarantine_get)
+        .or(admin_quarantine_delete)
+        .or(admin_reset_post)
+        .map(Reply::into_response)
+        .boxed()
+}
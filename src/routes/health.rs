@@ -0,0 +1,80 @@
+//! Liveness and readiness probes.
+
+use snark_coordinator_rs::timestamp;
+use tracing::warn;
+use warp::{
+    filters::BoxedFilter,
+    hyper::StatusCode,
+    reply::{with_status, Response},
+    Filter, Reply,
+};
+
+use super::Context;
+use crate::{Health, READINESS_DEADLINE, SWEEP_TASK};
+
+/// `GET /healthz` and `GET /readyz`.
+pub fn routes(ctx: &Context) -> BoxedFilter<(Response,)> {
+    let Context {
+        table,
+        worker_stats,
+        scheduler,
+        started_at,
+        sweep_interval,
+        ..
+    } = ctx.clone();
+
+    let healthz = warp::path!("healthz").and(warp::get()).map(move || {
+        let body = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "uptime_s": started_at.elapsed().as_secs(),
+        });
+        with_status(body.to_string(), StatusCode::from_u16(200).unwrap())
+    });
+
+    let kv = table.clone();
+    let stats = worker_stats.clone();
+    let tasks = scheduler.clone();
+    let readyz = warp::path!("readyz").and(warp::get()).then(move || {
+        let kv = kv.clone();
+        let stats = stats.clone();
+        let last_run = tasks.status(SWEEP_TASK).and_then(|s| s.last_run_t);
+        async move {
+            let (active_locks, lock_history) =
+                match tokio::time::timeout(READINESS_DEADLINE, kv.lock_all()).await {
+                    Ok(kv) => (Some(kv.len()), Some(kv.history().count())),
+                    Err(_) => (None, None),
+                };
+            let workers = tokio::time::timeout(READINESS_DEADLINE, stats.read())
+                .await
+                .ok()
+                .map(|stats| stats.len());
+            let sweeper_last_run_ms =
+                last_run.map(|last_run| timestamp::now().saturating_sub(last_run));
+            // the sweeper counts as alive until it missed a few runs. Before
+            // its first run, the process uptime stands in for the gap.
+            let sweeper_gap_ms =
+                sweeper_last_run_ms.unwrap_or_else(|| started_at.elapsed().as_millis() as u64);
+            let sweeper_alive = sweeper_gap_ms < 3 * sweep_interval.as_millis() as u64;
+
+            let health = Health {
+                ready: sweeper_alive && active_locks.is_some() && workers.is_some(),
+                version: env!("CARGO_PKG_VERSION"),
+                uptime_s: started_at.elapsed().as_secs(),
+                sweeper_last_run_ms,
+                active_locks,
+                workers,
+                lock_history,
+            };
+            let status = if health.ready { 200 } else { 503 };
+            if !health.ready {
+                warn!(?health, "not ready");
+            }
+            with_status(
+                serde_json::to_string(&health).unwrap(),
+                StatusCode::from_u16(status).unwrap(),
+            )
+        }
+    });
+
+    healthz.or(readyz).map(Reply::into_response).boxed()
+}
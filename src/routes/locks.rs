@@ -0,0 +1,873 @@
+//! Job locks: taking, validating and releasing them, their history and
+//! statistics, and pinning keys.
+
+use std::time::{Duration, Instant};
+
+use snark_coordinator_rs::{
+    errors::ErrorCode,
+    groups::Scope,
+    hooks::CoordinatorHooks,
+    job_pool::JobGranted,
+    jobs,
+    lock::{JobLock, LockCondition, LockJobGranted, LockJobHeld},
+    lock_namespaces,
+    lock_stats::{self, LockStatsReport},
+    pins::PinRequest,
+    timestamp,
+};
+use tracing::{debug, info, info_span, Instrument};
+use warp::{
+    filters::BoxedFilter,
+    hyper::StatusCode,
+    reply::{with_status, Response},
+    Filter, Reply,
+};
+
+use super::Context;
+use crate::{
+    acquire_all, acquire_if, api_error_reply, body, caller_scope, check_cooldown, check_lock_keys,
+    check_namespace_limit, check_worker_lock_quota, default_lock_ttl, error_reply,
+    lock_backend_reply, lock_each, lock_for_limits, lock_namespace, purge_lock, release_owned_lock,
+    reload::Settings, unauthorized_reply, worker_lock_quota, LockHistoryGetParams,
+    LockJobDeleteParams, LockJobQueryParams, LockJobValidateParams, LockJobsGetParams, LockOwner,
+    LockStatsGetParams, RECENT_FAILURE_WINDOW_MS, SHARED_LOCK_POLL_INTERVAL,
+};
+
+/// `/job`, `/lock-job(s)`, `/lock-history`, `/lock-stats`, `/lock-ttl` and
+/// the admin routes for pins and locks.
+pub fn routes(ctx: &Context) -> BoxedFilter<(Response,)> {
+    let Context {
+        table,
+        shared_locks,
+        worker_stats,
+        metrics_registry,
+        coordinator_hooks,
+        reloader,
+        job_pool,
+        job_index,
+        cooldowns,
+        lock_namespaces,
+        job_durations,
+        lock_ttl,
+        job_class_separator,
+        groups_config,
+        max_body_size,
+        max_wait,
+        max_locks_per_worker,
+        max_key_len,
+        auto_ttl,
+        selection_policy,
+        ..
+    } = ctx.clone();
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let metrics = metrics_registry.clone();
+    let hooks = coordinator_hooks.clone();
+    let settings = reloader.settings();
+    let pool = job_pool.clone();
+    let stats = worker_stats.clone();
+    let index = job_index.clone();
+    let cooldowns_ = cooldowns.clone();
+    let namespaces = lock_namespaces.clone();
+    let job_get = warp::path!("job")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LockJobQueryParams>()
+                .or(warp::any().map(LockJobQueryParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-worker-id"))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .then(
+            move |query: LockJobQueryParams,
+                  worker_id: Option<String>,
+                  namespace: Option<String>| {
+                let kv = kv.clone();
+                let shared = shared.clone();
+                let metrics = metrics.clone();
+                let hooks = hooks.clone();
+                let pool = pool.clone();
+                let stats = stats.clone();
+                let index = index.clone();
+                let cooldowns = cooldowns_.clone();
+                let namespaces = namespaces.clone();
+                let Settings {
+                    default_timeout_ms,
+                    min_timeout_ms,
+                    max_timeout_ms,
+                    ..
+                } = *settings.borrow();
+                let holder = query.worker_id.clone().or(worker_id);
+                let span = info_span!("job_get", ?namespace, worker_id = ?holder);
+                async move {
+                    let Some(pool) = pool else {
+                        let msg = "job distribution isn't enabled, see --node-graphql";
+                        return error_reply(ErrorCode::NotFound, msg);
+                    };
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply,
+                    };
+                    let limits = namespaces.limits(namespace);
+                    let timeout_ms = (query.timeout_ms())
+                        .or(limits.default_timeout_ms)
+                        .unwrap_or(default_timeout_ms);
+                    let max_timeout_ms = limits.max_timeout_ms.unwrap_or(max_timeout_ms);
+                    let timeout = Duration::from_millis(
+                        timeout_ms.clamp(min_timeout_ms, max_timeout_ms.max(min_timeout_ms)),
+                    );
+                    let cooling = check_cooldown(cooldowns.as_deref(), &stats, holder.as_deref());
+                    if let Err(reply) = cooling.await {
+                        return reply;
+                    }
+                    let quota = worker_lock_quota(holder.as_deref(), max_locks_per_worker);
+                    let worker_fee = match &holder {
+                        Some(holder) => stats.read().await.fee(holder),
+                        None => None,
+                    };
+                    let min_t = timestamp::now().saturating_sub(RECENT_FAILURE_WINDOW_MS);
+                    let jobs = {
+                        let (pool, index) = (pool.lock().await, index.lock().await);
+                        let failed = |ids: &str| {
+                            jobs::parse_ids(ids)
+                                .iter()
+                                .any(|id| index.failed_since(id, min_t))
+                        };
+                        selection_policy
+                            .order(pool.jobs(), worker_fee, failed)
+                            .into_iter()
+                            .cloned()
+                            .collect::<Vec<_>>()
+                    };
+                    for job in jobs {
+                        let now = Instant::now();
+                        let lock = JobLock::new(now + timeout, holder.clone());
+                        let keys = vec![lock_namespaces::qualify(namespace, &job.ids)];
+                        let mut kv = lock_for_limits(&kv, &keys, &limits, quota).await;
+                        if let Err(err) = check_worker_lock_quota(&kv, quota, &keys, now) {
+                            return api_error_reply(&err);
+                        }
+                        if let Err(err) = check_namespace_limit(&kv, namespace, &limits, &keys, now)
+                        {
+                            return api_error_reply(&err);
+                        }
+                        let acquired =
+                            acquire_all(&mut kv, shared.as_deref(), keys.clone(), lock, None, now);
+                        match acquired.await {
+                            Ok(Ok(fencing_token)) => {
+                                metrics.lock_acquisitions.inc();
+                                debug!(ids = job.ids, fencing_token, "job handed out");
+                                hooks.on_lock_acquired(&keys, fencing_token, holder.as_deref());
+                                let granted = JobGranted { job, fencing_token };
+                                return with_status(
+                                    serde_json::to_string(&granted).unwrap(),
+                                    StatusCode::from_u16(200).unwrap(),
+                                );
+                            }
+                            Ok(Err(_)) => continue,
+                            Err(err) => return lock_backend_reply(err),
+                        }
+                    }
+                    let msg = "no pending snark work is left unlocked";
+                    error_reply(ErrorCode::NoAvailableJob, msg)
+                }
+                .instrument(span)
+            },
+        );
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let metrics = metrics_registry.clone();
+    let hooks = coordinator_hooks.clone();
+    let durations = job_durations.clone();
+    let dynamic_ttl = lock_ttl.clone();
+    let separator = job_class_separator.clone();
+    let namespaces = lock_namespaces.clone();
+    let stats = worker_stats.clone();
+    let cooldowns_ = cooldowns.clone();
+    let settings = reloader.settings();
+    let lock_job_put = warp::path!("lock-job" / String)
+        .and(warp::put())
+        .and(
+            warp::filters::query::query::<LockJobQueryParams>()
+                .or(warp::any().map(LockJobQueryParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-worker-id"))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .and(warp::header::optional::<String>("if-match"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .then(
+            move |key: String,
+                  query: LockJobQueryParams,
+                  worker_id: Option<String>,
+                  namespace: Option<String>,
+                  if_match: Option<String>,
+                  if_none_match: Option<String>| {
+                let kv = kv.clone();
+                let shared = shared.clone();
+                let metrics = metrics.clone();
+                let hooks = hooks.clone();
+                let durations = durations.clone();
+                let dynamic_ttl = dynamic_ttl.clone();
+                let separator = separator.clone();
+                let namespaces = namespaces.clone();
+                let stats = stats.clone();
+                let cooldowns = cooldowns_.clone();
+                let Settings {
+                    default_timeout_ms,
+                    min_timeout_ms,
+                    max_timeout_ms,
+                    ..
+                } = *settings.borrow();
+                let holder = query.worker_id.clone().or(worker_id);
+                let span = info_span!("lock_job_put", %key, ?namespace, worker_id = ?holder);
+                async move {
+                    let len = key.len();
+                    if len > max_key_len {
+                        let msg = format!("key too long! max: {max_key_len}, found: {len}");
+                        debug!("{msg}");
+                        return error_reply(ErrorCode::KeyTooLong, msg);
+                    }
+                    let condition = LockCondition::from_request(
+                        if_match.as_deref(),
+                        if_none_match.as_deref(),
+                        query.bump.unwrap_or(false),
+                    );
+                    let condition = match condition {
+                        Ok(condition) => condition,
+                        Err(msg) => return error_reply(ErrorCode::InvalidParameter, msg),
+                    };
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply,
+                    };
+                    let limits = namespaces.limits(namespace);
+
+                    let timeout_ms = match query.timeout_ms().or(limits.default_timeout_ms) {
+                        Some(timeout_ms) => timeout_ms,
+                        None => {
+                            let keys = std::slice::from_ref(&key);
+                            default_lock_ttl(
+                                &durations,
+                                dynamic_ttl.as_deref(),
+                                auto_ttl,
+                                &separator,
+                                keys,
+                                default_timeout_ms,
+                            )
+                            .await
+                        }
+                    };
+                    let max_timeout_ms = limits.max_timeout_ms.unwrap_or(max_timeout_ms);
+                    let timeout = Duration::from_millis(
+                        timeout_ms.clamp(min_timeout_ms, max_timeout_ms.max(min_timeout_ms)),
+                    );
+                    let cooling = check_cooldown(cooldowns.as_deref(), &stats, holder.as_deref());
+                    if let Err(reply) = cooling.await {
+                        return reply;
+                    }
+                    let key = lock_namespaces::qualify(namespace, &key);
+                    let quota = worker_lock_quota(holder.as_deref(), max_locks_per_worker);
+                    if let Some(condition) = condition {
+                        let now = Instant::now();
+                        let lock = JobLock::new(now + timeout, holder.clone());
+                        let keys = std::slice::from_ref(&key);
+                        let mut kv = lock_for_limits(&kv, keys, &limits, quota).await;
+                        if let Err(err) = check_worker_lock_quota(&kv, quota, keys, now) {
+                            return api_error_reply(&err);
+                        }
+                        if let Err(err) = check_namespace_limit(&kv, namespace, &limits, keys, now)
+                        {
+                            return api_error_reply(&err);
+                        }
+                        let acquired =
+                            acquire_if(&mut kv, shared.as_deref(), &key, lock, condition, now);
+                        let fencing_token = match acquired.await {
+                            Ok(Ok(fencing_token)) => fencing_token,
+                            Ok(Err(held)) => {
+                                metrics.lock_conflicts.inc();
+                                let msg = match held.map(|held| held.holder) {
+                                    Some(Some(holder)) => format!("{key} is held by {holder}"),
+                                    Some(None) => format!("{key} is held"),
+                                    None => format!("{key} isn't held"),
+                                };
+                                debug!(?condition, "{msg}");
+                                return error_reply(ErrorCode::PreconditionFailed, msg);
+                            }
+                            Err(err) => return lock_backend_reply(err),
+                        };
+                        if condition != LockCondition::Bump(fencing_token) {
+                            metrics.lock_acquisitions.inc();
+                            let keys = std::slice::from_ref(&key);
+                            hooks.on_lock_acquired(keys, fencing_token, holder.as_deref());
+                        }
+                        debug!(fencing_token, ?condition, "lock granted");
+                        let granted = LockJobGranted { fencing_token };
+                        return with_status(
+                            serde_json::to_string(&granted).unwrap(),
+                            StatusCode::from_u16(201).unwrap(),
+                        );
+                    }
+                    let wait = Duration::from_secs(query.wait.unwrap_or(0).min(max_wait) as u64);
+                    let deadline = Instant::now() + wait;
+                    // place among the acquirers waiting for the key, once
+                    // it's found held.
+                    let mut ticket = None;
+                    loop {
+                        let now = Instant::now();
+                        let lock = JobLock::new(now + timeout, holder.clone());
+                        let keys = vec![key.clone()];
+                        let mut kv = lock_for_limits(&kv, &keys, &limits, quota).await;
+                        // other locks of the worker may have been granted
+                        // while it waited.
+                        if let Err(err) = check_worker_lock_quota(&kv, quota, &keys, now) {
+                            return api_error_reply(&err);
+                        }
+                        if let Err(err) = check_namespace_limit(&kv, namespace, &limits, &keys, now)
+                        {
+                            return api_error_reply(&err);
+                        }
+                        let acquired = acquire_all(
+                            &mut kv,
+                            shared.as_deref(),
+                            keys,
+                            lock,
+                            ticket.as_ref(),
+                            now,
+                        );
+                        let (held, expires_at) = match acquired.await {
+                            Ok(Ok(fencing_token)) => {
+                                metrics.lock_acquisitions.inc();
+                                debug!(fencing_token, "lock granted");
+                                let keys = std::slice::from_ref(&key);
+                                hooks.on_lock_acquired(keys, fencing_token, holder.as_deref());
+                                let granted = LockJobGranted { fencing_token };
+                                return with_status(
+                                    serde_json::to_string(&granted).unwrap(),
+                                    StatusCode::from_u16(201).unwrap(),
+                                );
+                            }
+                            Ok(Err(mut conflict)) => {
+                                let held = conflict.conflicts.remove(&key).unwrap_or(LockJobHeld {
+                                    holder: None,
+                                    remaining_ttl_ms: 0,
+                                    queued: 0,
+                                });
+                                // a vacant key waits for the acquirers ahead.
+                                let expires_at = match held.remaining_ttl_ms {
+                                    0 => deadline,
+                                    ttl => now + Duration::from_millis(ttl),
+                                };
+                                (held, expires_at)
+                            }
+                            Err(err) => return lock_backend_reply(err),
+                        };
+                        if now >= deadline {
+                            metrics.lock_conflicts.inc();
+                            debug!(holder = ?held.holder, "lock held by another worker");
+                            return with_status(
+                                serde_json::to_string(&held).unwrap(),
+                                StatusCode::from_u16(200).unwrap(),
+                            );
+                        }
+
+                        // wait until the lock is released or expires, or an
+                        // acquirer ahead leaves the queue.
+                        let ticket = ticket.get_or_insert_with(|| kv.enqueue(&key));
+                        let released = ticket.notify().notified();
+                        tokio::pin!(released);
+                        released.as_mut().enable();
+                        drop(kv);
+                        let mut until = expires_at.min(deadline);
+                        if shared.is_some() {
+                            until = until.min(now + SHARED_LOCK_POLL_INTERVAL);
+                        }
+                        let until = tokio::time::Instant::from_std(until);
+                        let _ = tokio::time::timeout_at(until, released).await;
+                    }
+                }
+                .instrument(span)
+            },
+        );
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let metrics = metrics_registry.clone();
+    let hooks = coordinator_hooks.clone();
+    let durations = job_durations.clone();
+    let dynamic_ttl = lock_ttl.clone();
+    let separator = job_class_separator.clone();
+    let namespaces = lock_namespaces.clone();
+    let stats = worker_stats.clone();
+    let cooldowns_ = cooldowns.clone();
+    let settings = reloader.settings();
+    let lock_jobs_put = warp::path!("lock-jobs")
+        .and(warp::put())
+        .and(
+            warp::filters::query::query::<LockJobQueryParams>()
+                .or(warp::any().map(LockJobQueryParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-worker-id"))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .and(body::json(max_body_size))
+        .then(
+            move |query: LockJobQueryParams,
+                  worker_id: Option<String>,
+                  namespace: Option<String>,
+                  keys: Vec<String>| {
+                let kv = kv.clone();
+                let shared = shared.clone();
+                let metrics = metrics.clone();
+                let hooks = hooks.clone();
+                let durations = durations.clone();
+                let dynamic_ttl = dynamic_ttl.clone();
+                let separator = separator.clone();
+                let namespaces = namespaces.clone();
+                let stats = stats.clone();
+                let cooldowns = cooldowns_.clone();
+                let Settings {
+                    default_timeout_ms,
+                    min_timeout_ms,
+                    max_timeout_ms,
+                    ..
+                } = *settings.borrow();
+                let holder = query.worker_id.clone().or(worker_id);
+                let span = info_span!("lock_jobs_put", ?keys, ?namespace, worker_id = ?holder);
+                async move {
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply,
+                    };
+                    if let Err(reply) = check_lock_keys(&keys) {
+                        return reply;
+                    }
+                    let limits = namespaces.limits(namespace);
+                    let timeout_ms = match query.timeout_ms().or(limits.default_timeout_ms) {
+                        Some(timeout_ms) => timeout_ms,
+                        None => {
+                            default_lock_ttl(
+                                &durations,
+                                dynamic_ttl.as_deref(),
+                                auto_ttl,
+                                &separator,
+                                &keys,
+                                default_timeout_ms,
+                            )
+                            .await
+                        }
+                    };
+                    let max_timeout_ms = limits.max_timeout_ms.unwrap_or(max_timeout_ms);
+                    let timeout = Duration::from_millis(
+                        timeout_ms.clamp(min_timeout_ms, max_timeout_ms.max(min_timeout_ms)),
+                    );
+                    let quota = worker_lock_quota(holder.as_deref(), max_locks_per_worker);
+                    let cooling = check_cooldown(cooldowns.as_deref(), &stats, holder.as_deref());
+                    if let Err(reply) = cooling.await {
+                        return reply;
+                    }
+                    if query.partial.unwrap_or(false) {
+                        let qualified = (keys.iter())
+                            .map(|key| lock_namespaces::qualify(namespace, key))
+                            .collect::<Vec<_>>();
+                        let mut kv = lock_for_limits(&kv, &qualified, &limits, quota).await;
+                        let res = lock_each(
+                            &mut kv,
+                            shared.as_deref(),
+                            &metrics,
+                            &hooks,
+                            namespace,
+                            &limits,
+                            quota,
+                            keys,
+                            holder.clone(),
+                            timeout,
+                            max_key_len,
+                        )
+                        .await;
+                        debug!(?res.summary, "locks requested independently");
+                        return with_status(
+                            serde_json::to_string(&res).unwrap(),
+                            StatusCode::from_u16(200).unwrap(),
+                        );
+                    }
+                    if let Some(key) = keys.iter().find(|key| key.len() > max_key_len) {
+                        let len = key.len();
+                        let msg = format!("key too long! max: {max_key_len}, found: {len}");
+                        debug!("{msg}");
+                        return error_reply(ErrorCode::KeyTooLong, msg);
+                    }
+                    let keys = keys
+                        .iter()
+                        .map(|key| lock_namespaces::qualify(namespace, key))
+                        .collect::<Vec<_>>();
+                    let now = Instant::now();
+                    let lock = JobLock::new(now + timeout, holder.clone());
+                    let mut kv = lock_for_limits(&kv, &keys, &limits, quota).await;
+                    if let Err(err) = check_worker_lock_quota(&kv, quota, &keys, now) {
+                        return api_error_reply(&err);
+                    }
+                    if let Err(err) = check_namespace_limit(&kv, namespace, &limits, &keys, now) {
+                        return api_error_reply(&err);
+                    }
+                    match acquire_all(&mut kv, shared.as_deref(), keys.clone(), lock, None, now)
+                        .await
+                    {
+                        Ok(Ok(fencing_token)) => {
+                            metrics.lock_acquisitions.inc();
+                            debug!(fencing_token, "locks granted");
+                            hooks.on_lock_acquired(&keys, fencing_token, holder.as_deref());
+                            with_status(
+                                serde_json::to_string(&LockJobGranted { fencing_token }).unwrap(),
+                                StatusCode::from_u16(201).unwrap(),
+                            )
+                        }
+                        Ok(Err(conflict)) => {
+                            metrics.lock_conflicts.inc();
+                            debug!(conflicts = ?conflict.conflicts.keys(), "locks held by others");
+                            with_status(
+                                serde_json::to_string(&conflict).unwrap(),
+                                StatusCode::from_u16(200).unwrap(),
+                            )
+                        }
+                        Err(err) => lock_backend_reply(err),
+                    }
+                }
+                .instrument(span)
+            },
+        );
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let lock_job_validate = warp::path!("lock-job" / String / "validate")
+        .and(warp::get())
+        .and(warp::filters::query::query::<LockJobValidateParams>())
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .then(
+            move |key: String, query: LockJobValidateParams, namespace: Option<String>| {
+                let kv = kv.clone();
+                let shared = shared.clone();
+                let span = info_span!("lock_job_validate", %key, ?namespace, query.fencing_token);
+                async move {
+                    let key = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => lock_namespaces::qualify(namespace, &key),
+                        Err(reply) => return reply,
+                    };
+                    let valid = match &shared {
+                        Some(shared) => match shared.validate(&key, query.fencing_token).await {
+                            Ok(valid) => valid,
+                            Err(err) => return lock_backend_reply(err),
+                        },
+                        None => {
+                            let kv = kv.lock_key(&key).await;
+                            kv.validate(&key, query.fencing_token, Instant::now())
+                        }
+                    };
+                    if valid {
+                        with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+                    } else {
+                        let msg = format!("stale fencing token for {key}: {}", query.fencing_token);
+                        debug!("{msg}");
+                        error_reply(ErrorCode::StaleFencingToken, msg)
+                    }
+                }
+                .instrument(span)
+            },
+        );
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let hooks = coordinator_hooks.clone();
+    let lock_job_delete = warp::path!("lock-job" / String)
+        .and(warp::delete())
+        .and(
+            warp::filters::query::query::<LockJobDeleteParams>()
+                .or(warp::any().map(LockJobDeleteParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-worker-id"))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .then(
+            move |key: String,
+                  query: LockJobDeleteParams,
+                  worker_id: Option<String>,
+                  namespace: Option<String>| {
+                let kv = kv.clone();
+                let shared = shared.clone();
+                let hooks = hooks.clone();
+                let holder = query.worker_id.or(worker_id);
+                let span = info_span!(
+                    "lock_job_delete",
+                    %key,
+                    ?namespace,
+                    query.fencing_token,
+                    worker_id = ?holder
+                );
+                async move {
+                    let key = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => lock_namespaces::qualify(namespace, &key),
+                        Err(reply) => return reply,
+                    };
+                    let owner = match (query.fencing_token, holder) {
+                        (Some(token), _) => LockOwner::FencingToken(token),
+                        (None, Some(holder)) => LockOwner::Holder(holder),
+                        (None, None) => {
+                            let msg = "releasing a lock needs its fencing_token or worker_id";
+                            return error_reply(ErrorCode::InvalidParameter, msg);
+                        }
+                    };
+                    match release_owned_lock(&kv, shared.as_deref(), &hooks, &key, &owner).await {
+                        Ok(Ok(())) => {
+                            debug!("lock released");
+                            with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+                        }
+                        Ok(Err(reply)) => reply,
+                        Err(err) => lock_backend_reply(err),
+                    }
+                }
+                .instrument(span)
+            },
+        );
+
+    let kv = table.clone();
+    let groups = groups_config.clone();
+    let lock_jobs_get = warp::path!("lock-jobs")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LockJobsGetParams>()
+                .or(warp::any().map(LockJobsGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: LockJobsGetParams, authorization: Option<String>| {
+                let kv = kv.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let kv = kv.lock_all().await;
+                    let mut leases = kv.leases(Instant::now());
+                    leases.retain(|_, lease| match (&scope, &lease.holder) {
+                        (Scope::All, _) => true,
+                        (scope, holder) => holder.as_deref().is_some_and(|h| scope.contains(h)),
+                    });
+                    if let Some(namespace) = &params.namespace {
+                        leases = (leases.into_iter())
+                            .filter_map(|(key, lease)| {
+                                Some((lock_namespaces::strip(namespace, key)?, lease))
+                            })
+                            .collect();
+                    }
+                    with_status(
+                        serde_json::to_string(&leases).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let kv = table.clone();
+    let groups = groups_config.clone();
+    let lock_history_get = warp::path!("lock-history")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LockHistoryGetParams>()
+                .or(warp::any().map(LockHistoryGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: LockHistoryGetParams, authorization: Option<String>| {
+                let kv = kv.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let kv = kv.lock_all().await;
+                    let records = kv
+                        .history()
+                        .filter(|r| match (&scope, &r.holder) {
+                            (Scope::All, _) => true,
+                            (scope, holder) => holder.as_deref().is_some_and(|h| scope.contains(h)),
+                        })
+                        .filter(|r| {
+                            let key = match &params.namespace {
+                                Some(namespace) => lock_namespaces::strip(namespace, &r.key),
+                                None => Some(r.key.as_str()),
+                            };
+                            key.is_some_and(|key| params.key.as_ref().is_none_or(|k| k == key))
+                        })
+                        .filter(|r| {
+                            params
+                                .reason
+                                .is_none_or(|reason| r.removal_reason == reason)
+                        })
+                        .take(params.limit.unwrap_or(usize::MAX))
+                        .collect::<Vec<_>>();
+                    with_status(
+                        serde_json::to_string(&records).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let kv = table.clone();
+    let admin_pin_post = warp::path!("admin" / "pin")
+        .and(warp::post())
+        .and(body::json(max_body_size))
+        .then(move |req: PinRequest| {
+            let kv = kv.clone();
+            async move {
+                match kv.lock_all().await.pin(req) {
+                    Ok(pin) => {
+                        info!(?pin, "lock history pinned");
+                        with_status(
+                            serde_json::to_string(&pin).unwrap(),
+                            StatusCode::from_u16(201).unwrap(),
+                        )
+                    }
+                    Err(err) => error_reply(ErrorCode::InvalidPin, err),
+                }
+            }
+        });
+
+    let kv = table.clone();
+    let admin_pins_get = warp::path!("admin" / "pins")
+        .and(warp::get())
+        .then(move || {
+            let kv = kv.clone();
+            async move {
+                let kv = kv.lock_all().await;
+                let pins = kv.pins().iter().collect::<Vec<_>>();
+                with_status(
+                    serde_json::to_string(&pins).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let kv = table.clone();
+    let admin_pin_delete =
+        warp::path!("admin" / "pin" / u64)
+            .and(warp::delete())
+            .then(move |id: u64| {
+                let kv = kv.clone();
+                async move {
+                    match kv.lock_all().await.unpin(id) {
+                        Some(pin) => {
+                            info!(?pin, "lock history unpinned");
+                            with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+                        }
+                        None => error_reply(ErrorCode::PinNotFound, format!("no such pin: {id}")),
+                    }
+                }
+            });
+
+    let kv = table.clone();
+    let shared = shared_locks.clone();
+    let hooks = coordinator_hooks.clone();
+    let admin_lock_delete = warp::path!("admin" / "locks" / String)
+        .and(warp::delete())
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .then(move |key: String, namespace: Option<String>| {
+            let kv = kv.clone();
+            let shared = shared.clone();
+            let hooks = hooks.clone();
+            async move {
+                let key = match lock_namespace(namespace.as_deref()) {
+                    Ok(namespace) => lock_namespaces::qualify(namespace, &key),
+                    Err(reply) => return reply,
+                };
+                match purge_lock(&kv, shared.as_deref(), &hooks, &key).await {
+                    Ok(true) => {
+                        info!(key, "lock dropped");
+                        with_status("".to_owned(), StatusCode::from_u16(200).unwrap())
+                    }
+                    Ok(false) => {
+                        error_reply(ErrorCode::LockNotFound, format!("{key} isn't locked"))
+                    }
+                    Err(err) => lock_backend_reply(err),
+                }
+            }
+        });
+
+    let kv = table.clone();
+    let separator = job_class_separator.clone();
+    let lock_stats_get = warp::path!("lock-stats")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LockStatsGetParams>()
+                .or(warp::any().map(LockStatsGetParams::default))
+                .unify(),
+        )
+        .then(move |params: LockStatsGetParams| {
+            let kv = kv.clone();
+            let separator = separator.clone();
+            async move {
+                let window_ms = params.window.unwrap_or(300).saturating_mul(1000);
+                let to_t = timestamp::now();
+                let from_t = to_t.saturating_sub(window_ms.min(lock_stats::MAX_WINDOW_MS));
+                let limit = params.limit.unwrap_or(10);
+                let kv = kv.lock_all().await;
+                let report =
+                    LockStatsReport::new(kv.lock_events(), from_t, to_t, &separator, limit);
+                drop(kv);
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let kv = table.clone();
+    let hot_keys_get = warp::path!("lock-stats" / "hot")
+        .and(warp::get())
+        .then(move || {
+            let kv = kv.clone();
+            async move {
+                let report = kv.hot_keys(timestamp::now()).await;
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let dynamic_ttl = lock_ttl.clone();
+    let lock_ttl_get = warp::path!("lock-ttl").and(warp::get()).then(move || {
+        let dynamic_ttl = dynamic_ttl.clone();
+        async move {
+            let Some(dynamic_ttl) = dynamic_ttl else {
+                return error_reply(ErrorCode::NotFound, "--dynamic-ttl is not enabled");
+            };
+            let report = dynamic_ttl.lock().await.report();
+            with_status(
+                serde_json::to_string(&report).unwrap(),
+                StatusCode::from_u16(200).unwrap(),
+            )
+        }
+    });
+
+    lock_job_put
+        .or(job_get)
+        .or(lock_jobs_put)
+        .or(lock_job_validate)
+        .or(lock_job_delete)
+        .or(lock_jobs_get)
+        .or(lock_history_get)
+        .or(admin_pin_post)
+        .or(admin_pins_get)
+        .or(admin_pin_delete)
+        .or(admin_lock_delete)
+        .or(lock_ttl_get)
+        .or(lock_stats_get)
+        .or(hot_keys_get)
+        .map(Reply::into_response)
+        .boxed()
+}
@@ -0,0 +1,109 @@
+//! The HTTP API's routes, built by area from the coordinator's shared
+//! state: [`locks`] for job locks, [`stats`] for worker stats reporting,
+//! [`reports`] for fleet reports and [`admin`] for operators. [`health`]
+//! holds the probes, which stay open outside the API's filters.
+
+pub mod admin;
+pub mod health;
+pub mod locks;
+pub mod reports;
+pub mod stats;
+
+use std::{
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use snark_coordinator_rs::{
+    annotations::Annotations,
+    anomaly::FleetAnomalyDetector,
+    assignment::AssignmentPolicy,
+    cooldown::Cooldowns,
+    durations::DurationModel,
+    groups::GroupsConfig,
+    handshake::HandshakePolicy,
+    hooks::Hooks,
+    host_metrics::HostMetrics,
+    job_pool::{JobPool, SelectionPolicy},
+    jobs::JobIndex,
+    lock::LockShards,
+    lock_namespaces::LockNamespaces,
+    lock_ttl::LockTtlController,
+    maintenance::MaintenanceWindows,
+    metrics::Metrics,
+    network::NetworkProbes,
+    quarantine::Quarantine,
+    redis_locks::RedisLocks,
+    stats::WorkerStats,
+    top::TopK,
+    worker_tokens::WorkerTokens,
+};
+use tokio::sync::{Mutex, RwLock};
+use warp::{filters::BoxedFilter, reply::Response, Filter};
+
+use crate::{
+    failover::Role, ingest_queue::IngestQueue, reload::Reloader, scheduler::Scheduler, StatsIngest,
+};
+
+/// The state routes share, set up by `main`.
+#[derive(Clone)]
+pub struct Context {
+    pub table: Arc<LockShards>,
+    /// Set with `--lock-backend redis://..`.
+    pub shared_locks: Option<Arc<RedisLocks>>,
+    pub worker_stats: Arc<RwLock<WorkerStats>>,
+    pub metrics_registry: Arc<Metrics>,
+    pub coordinator_hooks: Arc<Hooks>,
+    pub reloader: Arc<Reloader>,
+    /// Set with `--node-graphql`.
+    pub job_pool: Option<Arc<Mutex<JobPool>>>,
+    pub job_index: Arc<Mutex<JobIndex>>,
+    /// Set with `--cooldown-errors`.
+    pub cooldowns: Option<Arc<Mutex<Cooldowns>>>,
+    pub lock_namespaces: Arc<LockNamespaces>,
+    pub job_durations: Arc<Mutex<DurationModel>>,
+    /// Set with `--dynamic-ttl`.
+    pub lock_ttl: Option<Arc<Mutex<LockTtlController>>>,
+    pub job_class_separator: Arc<String>,
+    pub ingest: StatsIngest,
+    pub ingest_queue: IngestQueue,
+    /// Set with `--worker-tokens`.
+    pub worker_tokens: Option<Arc<Mutex<WorkerTokens>>>,
+    /// Set with `--quarantine`.
+    pub quarantine: Option<Arc<Mutex<Quarantine>>>,
+    pub groups_config: Option<Arc<GroupsConfig>>,
+    pub host_metrics: Arc<Mutex<HostMetrics>>,
+    pub network_probes: Arc<Mutex<NetworkProbes>>,
+    pub annotations: Arc<Mutex<Annotations>>,
+    pub maintenance: Arc<Mutex<MaintenanceWindows>>,
+    pub top_k: Arc<Mutex<TopK>>,
+    pub role: Arc<Mutex<Role>>,
+    pub fleet_anomalies: Arc<Mutex<FleetAnomalyDetector>>,
+    pub scheduler: Arc<Scheduler>,
+    /// Set with `--assignment-policy`.
+    pub assignment_policy: Option<Arc<AssignmentPolicy>>,
+    pub max_body_size: u64,
+    pub max_wait: u16,
+    pub max_locks_per_worker: Option<usize>,
+    pub max_key_len: usize,
+    pub auto_ttl: bool,
+    pub selection_policy: SelectionPolicy,
+    pub handshake_policy: Arc<HandshakePolicy>,
+    pub started_at: Instant,
+    /// How often expired locks are swept, for readiness.
+    pub sweep_interval: Duration,
+}
+
+/// The API's routes, less the probes.
+pub fn api(ctx: &Context) -> BoxedFilter<(Response,)> {
+    // areas are boxed separately, a single nested route future overflows
+    // the stack of debug builds.
+    locks::routes(ctx)
+        .or(stats::routes(ctx))
+        .unify()
+        .or(reports::routes(ctx))
+        .unify()
+        .or(admin::routes(ctx))
+        .unify()
+        .boxed()
+}
@@ -0,0 +1,755 @@
+//! Reports on the fleet's stats, and the GraphQL endpoint over them.
+
+use std::collections::HashMap;
+
+use snark_coordinator_rs::{
+    assignment, availability, domains, efficiency, error_groups,
+    errors::ErrorCode,
+    export,
+    groups::Scope,
+    jobs::JobId,
+    latency::{self, LatencyQuery},
+    outliers, summary, throughput, timestamp,
+};
+use warp::{
+    filters::BoxedFilter,
+    hyper::StatusCode,
+    reply::{with_status, Response},
+    Filter, Reply,
+};
+
+use super::Context;
+use crate::{
+    caller_scope, csv_reply, error_reply, graphql, parquet_reply, refresh_gauges,
+    unauthorized_reply, Annotated, AssignmentGetParams, EfficiencyGetParams, ErrorsGetParams,
+    ExportFormat, HourlySummaryGetParams, LatencyGetParams, LifecyclesGetParams, OutliersGetParams,
+    SummaryGetParams, ThroughputGetParams, TopGetParams,
+};
+
+/// `/report/*`, `/summary`, `/latency` and the other fleet reports.
+pub fn routes(ctx: &Context) -> BoxedFilter<(Response,)> {
+    let Context {
+        table,
+        worker_stats,
+        metrics_registry,
+        job_durations,
+        job_class_separator,
+        ingest,
+        groups_config,
+        host_metrics,
+        network_probes,
+        annotations,
+        maintenance,
+        top_k,
+        fleet_anomalies,
+        assignment_policy,
+        max_body_size,
+        ..
+    } = ctx.clone();
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let hosts = host_metrics.clone();
+    let notes = annotations.clone();
+    let host_correlation_report = warp::path!("report" / "host-correlation")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |authorization: Option<String>| {
+            let stats = stats.clone();
+            let hosts = hosts.clone();
+            let notes = notes.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let stats = stats.read().await;
+                let hosts = hosts.lock().await;
+                let report = hosts.correlate(stats.iter().filter(|(k, _)| scope.contains(k)));
+                let notes = notes.lock().await;
+                let report = Annotated {
+                    report,
+                    annotations: notes.within(None, None),
+                };
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let notes = annotations.clone();
+    let efficiency_report = warp::path!("report" / "efficiency")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<EfficiencyGetParams>()
+                .or(warp::any().map(EfficiencyGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: EfficiencyGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let notes = notes.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let windows = params.windows.as_deref().unwrap_or("3600,86400");
+                    let windows_ms = match windows
+                        .split(',')
+                        .map(|s| {
+                            s.trim()
+                                .parse::<u64>()
+                                .map(|secs| secs.saturating_mul(1000))
+                        })
+                        .collect::<Result<Vec<_>, _>>()
+                    {
+                        Ok(windows_ms) => windows_ms,
+                        Err(err) => {
+                            let msg = format!("invalid windows {windows:?}: {err}");
+                            return error_reply(ErrorCode::InvalidParameter, msg);
+                        }
+                    };
+                    let now = timestamp::now();
+                    let stats = stats.read().await;
+                    let report =
+                        efficiency::report(&*stats, |k| scope.contains(k), &windows_ms, now);
+                    let widest_ms = windows_ms.iter().max().copied().unwrap_or_default();
+                    let notes = notes.lock().await;
+                    let report = Annotated {
+                        report,
+                        annotations: notes.within(Some(now.saturating_sub(widest_ms)), None),
+                    };
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let policy = assignment_policy.clone();
+    let separator = job_class_separator.clone();
+    let assignment_report = warp::path!("report" / "assignment")
+        .and(warp::get())
+        .and(warp::filters::query::query::<AssignmentGetParams>())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: AssignmentGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let policy = policy.clone();
+                let separator = separator.clone();
+                let groups = groups.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let Some(policy) = policy else {
+                        let msg = "no assignment policy, set --assignment-policy";
+                        return error_reply(ErrorCode::NotFound, msg.to_owned());
+                    };
+                    let stats = stats.read().await;
+                    let report = assignment::report(
+                        &policy,
+                        &params.job,
+                        &separator,
+                        groups.as_deref(),
+                        stats.iter().filter(|(k, _)| scope.contains(k)),
+                    );
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let probes = network_probes.clone();
+    let notes = annotations.clone();
+    let network_report = warp::path!("report" / "network")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |authorization: Option<String>| {
+            let probes = probes.clone();
+            let notes = notes.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let report = probes.lock().await.report(|k| scope.contains(k));
+                let notes = notes.lock().await;
+                let report = Annotated {
+                    report,
+                    annotations: notes.within(None, None),
+                };
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let index = ingest.job_index.clone();
+    let jobs_get = warp::path!("jobs" / String)
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |job_id: String, authorization: Option<String>| {
+            let index = index.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let job_id = JobId::new(&job_id);
+                let index = index.lock().await;
+                let works: Vec<_> = (index.get(&job_id).unwrap_or_default().iter())
+                    .filter(|w| scope.contains(&w.worker_id))
+                    .collect();
+                if works.is_empty() {
+                    let msg = format!("no work seen on job {job_id}");
+                    return error_reply(ErrorCode::JobNotFound, msg);
+                }
+                let body = serde_json::json!({ "job_id": job_id, "works": works });
+                with_status(body.to_string(), StatusCode::from_u16(200).unwrap())
+            }
+        });
+
+    let groups = groups_config.clone();
+    let index = ingest.job_index.clone();
+    let duplicates_report = warp::path!("report" / "duplicates")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |authorization: Option<String>| {
+            let index = index.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let report = index.lock().await.duplicates(|k| scope.contains(k));
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let liveness = ingest.liveness.clone();
+    let windows = maintenance.clone();
+    let availability_get = warp::path!("availability")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |authorization: Option<String>| {
+            let stats = stats.clone();
+            let liveness = liveness.clone();
+            let windows = windows.clone();
+            let groups = groups.clone();
+            async move {
+                let Some(scope) = caller_scope(groups.as_deref(), authorization.as_deref()) else {
+                    return unauthorized_reply();
+                };
+                let stats = stats.read().await;
+                let report = availability::report(
+                    stats.iter().filter(|(k, _)| scope.contains(k)),
+                    &*liveness.lock().await,
+                    &*windows.lock().await,
+                    &groups
+                        .as_deref()
+                        .map(|g| g.teams.clone())
+                        .unwrap_or_default(),
+                    timestamp::now(),
+                );
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let hourly_summary_get = warp::path!("summary" / "hourly")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<HourlySummaryGetParams>()
+                .or(warp::any().map(HourlySummaryGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: HourlySummaryGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    match scope {
+                        None => return unauthorized_reply(),
+                        // aggregates aren't kept per worker.
+                        Some(Scope::Prefixes(_)) => {
+                            let msg = "hourly summaries are fleet-wide, a group key can't see them";
+                            return error_reply(ErrorCode::Forbidden, msg);
+                        }
+                        Some(Scope::All) => {}
+                    }
+                    let from_t = params.from_t.map(timestamp::normalize);
+                    let to_t = params.to_t.map(timestamp::normalize);
+                    let hours = stats.read().await.compacted().range(from_t, to_t);
+                    with_status(
+                        serde_json::to_string(&hours).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let liveness = ingest.liveness.clone();
+    let windows = maintenance.clone();
+    let notes = annotations.clone();
+    let summary_get = warp::path!("summary")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<SummaryGetParams>()
+                .or(warp::any().map(SummaryGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("accept"))
+        .then(
+            move |params: SummaryGetParams,
+                  authorization: Option<String>,
+                  accept: Option<String>| {
+                let stats = stats.clone();
+                let liveness = liveness.clone();
+                let windows = windows.clone();
+                let notes = notes.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                let teams = groups
+                    .as_deref()
+                    .map(|g| g.teams.clone())
+                    .unwrap_or_default();
+                let format = ExportFormat::negotiate(params.format, accept.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    let stats = stats.read().await;
+                    let summary = summary::summarize(
+                        stats.iter().filter(|(k, _)| scope.contains(k)),
+                        |k| stats.evicted(k),
+                        |k| stats.seq(k),
+                    );
+                    match format {
+                        Some(ExportFormat::Csv) => {
+                            let availability = availability::report(
+                                stats.iter().filter(|(k, _)| scope.contains(k)),
+                                &*liveness.lock().await,
+                                &*windows.lock().await,
+                                &teams,
+                                timestamp::now(),
+                            );
+                            return csv_reply(export::summary_csv(&summary, &availability));
+                        }
+                        Some(ExportFormat::Parquet) => {
+                            let msg = "parquet isn't supported for summaries";
+                            return error_reply(ErrorCode::InvalidParameter, msg).into_response();
+                        }
+                        Some(ExportFormat::Json) | None => {}
+                    }
+                    let notes = notes.lock().await;
+                    let summary = Annotated {
+                        report: summary,
+                        annotations: notes.within(None, None),
+                    };
+                    with_status(
+                        serde_json::to_string(&summary).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                    .into_response()
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let notes = annotations.clone();
+    let latency_get = warp::path!("latency")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LatencyGetParams>()
+                .or(warp::any().map(LatencyGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: LatencyGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let notes = notes.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let query = LatencyQuery {
+                        from_t: params.from_t.map(timestamp::normalize),
+                        to_t: params.to_t.map(timestamp::normalize),
+                        by_worker: params.by_worker.unwrap_or(false),
+                        window_ms: params.window.map(|s| s.saturating_mul(1000)),
+                    };
+                    let stats = stats.read().await;
+                    let report =
+                        latency::report(stats.iter().filter(|(k, _)| scope.contains(k)), &query);
+                    let notes = notes.lock().await;
+                    let report = Annotated {
+                        report,
+                        annotations: notes.within(query.from_t, query.to_t),
+                    };
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let outliers_get = warp::path!("outliers")
+        .and(warp::get())
+        .and(warp::filters::query::query::<OutliersGetParams>())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: OutliersGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let to_t = timestamp::now();
+                    let window_ms = params.window.unwrap_or(86400).saturating_mul(1000);
+                    let stats = stats.read().await;
+                    let report = outliers::report(
+                        stats.iter().filter(|(k, _)| scope.contains(k)),
+                        params.stage,
+                        params.over.unwrap_or_default(),
+                        to_t.saturating_sub(window_ms),
+                        to_t,
+                        params.limit.unwrap_or(100),
+                    );
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let errors_get = warp::path!("errors")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<ErrorsGetParams>()
+                .or(warp::any().map(ErrorsGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: ErrorsGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let to_t = timestamp::now();
+                    let from_t = (params.window)
+                        .map(|window| to_t.saturating_sub(window.saturating_mul(1000)));
+                    let stats = stats.read().await;
+                    let report = error_groups::report(
+                        stats.iter().filter(|(k, _)| scope.contains(k)),
+                        from_t,
+                        to_t,
+                        params.limit.unwrap_or(20),
+                    );
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let throughput_get = warp::path!("throughput")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<ThroughputGetParams>()
+                .or(warp::any().map(ThroughputGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: ThroughputGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let bucket_ms = params.bucket.unwrap_or(60).saturating_mul(1000);
+                    let from_t = params.from_t.map(timestamp::normalize);
+                    let to_t = params.to_t.map(timestamp::normalize);
+                    let stats = stats.read().await;
+                    let report = throughput::report(
+                        stats.iter().filter(|(k, _)| scope.contains(k)),
+                        bucket_ms,
+                        from_t,
+                        to_t,
+                    );
+                    with_status(
+                        serde_json::to_string(&report).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let top = top_k.clone();
+    let top_errors_get = warp::path!("top" / "errors")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<TopGetParams>()
+                .or(warp::any().map(TopGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |params: TopGetParams, authorization: Option<String>| {
+            let top = top.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                match scope {
+                    None => return unauthorized_reply(),
+                    // error classes aren't tracked per worker.
+                    Some(Scope::Prefixes(_)) => {
+                        let msg = "top errors are fleet-wide, a group key can't see them";
+                        return error_reply(ErrorCode::Forbidden, msg);
+                    }
+                    Some(Scope::All) => {}
+                }
+                let top = top.lock().await;
+                let errors = top.top_errors(params.k.unwrap_or(10));
+                with_status(
+                    serde_json::to_string(&errors).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let top = top_k.clone();
+    let top_slow_jobs_get = warp::path!("top" / "slow-jobs")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<TopGetParams>()
+                .or(warp::any().map(TopGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |params: TopGetParams, authorization: Option<String>| {
+            let top = top.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let top = top.lock().await;
+                // jobs are tracked fleet-wide, so a group can get fewer than k.
+                let jobs =
+                    top.slowest_jobs(params.k.unwrap_or(10), |job| scope.contains(&job.worker_id));
+                with_status(
+                    serde_json::to_string(&jobs).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let kv = table.clone();
+    let stats = worker_stats.clone();
+    let notes = annotations.clone();
+    let failure_domains_report = warp::path!("report" / "failure-domains")
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(move |authorization: Option<String>| {
+            let notes = notes.clone();
+            let kv = kv.clone();
+            let stats = stats.clone();
+            let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+            async move {
+                let Some(scope) = scope else {
+                    return unauthorized_reply();
+                };
+                let stats = stats.read().await;
+                let kv = kv.lock_all().await;
+                let conflicts = kv
+                    .conflicts()
+                    .iter()
+                    .filter(|(k, _)| scope.contains(k))
+                    .map(|(k, v)| (k.clone(), *v))
+                    .collect();
+                let workers = stats.iter().filter(|(k, _)| scope.contains(k));
+                let report = domains::report(workers, |k| stats.name(k), &conflicts);
+                let notes = notes.lock().await;
+                let report = Annotated {
+                    report,
+                    annotations: notes.within(None, None),
+                };
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let lifecycles_get = warp::path!("lifecycles")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<LifecyclesGetParams>()
+                .or(warp::any().map(LifecyclesGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: LifecyclesGetParams, authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    let stats = stats.read().await;
+                    let workers_filter = params
+                        .workers
+                        .map(|s| s.split(',').map(|s| s.to_owned()).collect::<Vec<_>>());
+                    let expired_filter = params.lease_expired_before_submit;
+
+                    let lifecycles = stats
+                        .iter()
+                        .filter(|(k, _)| scope.contains(k))
+                        .filter(|(k, _)| workers_filter.as_ref().is_none_or(|f| f.contains(k)))
+                        .map(|(k, states)| {
+                            let v = states
+                                .iter()
+                                .filter(|v| v.lease().is_some())
+                                .filter(|v| {
+                                    expired_filter
+                                        .is_none_or(|f| v.lease_expired_before_submit() == f)
+                                })
+                                .collect::<Vec<_>>();
+                            (k, v)
+                        })
+                        .filter(|(_, v)| !v.is_empty())
+                        .collect::<HashMap<_, _>>();
+                    let rows = lifecycles
+                        .iter()
+                        .flat_map(|(k, v)| v.iter().map(|v| (k.as_str(), *v)));
+                    match params.format {
+                        Some(ExportFormat::Parquet) => {
+                            return parquet_reply(export::lifecycles_parquet(rows));
+                        }
+                        Some(ExportFormat::Csv) => return csv_reply(export::lifecycles_csv(rows)),
+                        Some(ExportFormat::Json) | None => {}
+                    }
+                    with_status(
+                        serde_json::to_string(&lifecycles).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                    .into_response()
+                }
+            },
+        );
+
+    let kv = table.clone();
+    let stats = worker_stats.clone();
+    let metrics = metrics_registry.clone();
+    let metrics_get = warp::path!("metrics").and(warp::get()).then(move || {
+        let kv = kv.clone();
+        let stats = stats.clone();
+        let metrics = metrics.clone();
+        async move {
+            refresh_gauges(&kv, &stats, &metrics).await;
+            with_status(metrics.encode(), StatusCode::from_u16(200).unwrap())
+        }
+    });
+
+    let durations = job_durations.clone();
+    let job_durations_get = warp::path!("durations").and(warp::get()).then(move || {
+        let durations = durations.clone();
+        async move {
+            let report = durations.lock().await.report();
+            with_status(
+                serde_json::to_string(&report).unwrap(),
+                StatusCode::from_u16(200).unwrap(),
+            )
+        }
+    });
+
+    let anomalies = fleet_anomalies.clone();
+    let fleet_anomalies_get = warp::path!("anomalies" / "fleet")
+        .and(warp::get())
+        .then(move || {
+            let anomalies = anomalies.clone();
+            async move {
+                let report = anomalies.lock().await.report();
+                with_status(
+                    serde_json::to_string(&report).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    let schema = graphql::schema(worker_stats.clone(), table.clone());
+    let graphql = graphql::route(schema, groups_config.clone(), max_body_size);
+
+    host_correlation_report
+        .or(efficiency_report)
+        .or(assignment_report)
+        .or(network_report)
+        .or(duplicates_report)
+        .or(jobs_get)
+        .or(summary_get)
+        .or(hourly_summary_get)
+        .or(availability_get)
+        .or(latency_get)
+        .or(outliers_get)
+        .or(errors_get)
+        .or(throughput_get)
+        .or(top_errors_get)
+        .or(top_slow_jobs_get)
+        .or(failure_domains_report)
+        .or(lifecycles_get)
+        .or(metrics_get)
+        .or(fleet_anomalies_get)
+        .or(job_durations_get)
+        .or(graphql)
+        .map(Reply::into_response)
+        .boxed()
+}
@@ -0,0 +1,1049 @@
+//! Worker stats reporting: workers putting events, heartbeats and host
+//! metrics, and reads of their stats.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    ops::Bound,
+};
+
+use snark_coordinator_rs::{
+    archive::DeregisterRequest,
+    batch::{BatchItem, BatchResponse},
+    errors::{ApiError, ErrorCode},
+    export,
+    handshake::HandshakeRequest,
+    host_metrics::HostSample,
+    idempotency, lock_namespaces,
+    network::{EchoRequest, EchoResponse},
+    otlp::TraceContext,
+    stats::{Order, SnarkWorkerState, SnarkWorkerStatsPut, StatsCursor, WorkerStats},
+    timestamp, worker_tokens,
+};
+use tracing::{debug, info, info_span, warn, Instrument};
+use warp::{
+    filters::BoxedFilter,
+    hyper::{body::Bytes, StatusCode},
+    reply::{with_status, Response},
+    Filter, Reply,
+};
+
+use super::Context;
+use crate::{
+    api_error_reply, body, caller_scope, check_worker_token, csv_reply, error_reply, etag_matches,
+    formats, ingest_stopped_reply, live, localize_timestamps, lock_namespace, not_modified_reply,
+    parquet_reply, parse_kinds, parse_statuses, parse_workers_sort, put_batch, put_stream,
+    quarantine_payload, quarantined_reply, saturated_reply, stats_etag, stream, time_range,
+    unauthorized_reply, wait_for_version, Applied, ArchivedWorkersGetParams, ErrorEventsParams,
+    ExportFormat, LiveFeedParams, StateView, StatsPage, TimeFormat, WorkerInfo,
+    WorkerMetricsGetParams, WorkerStatsDeleteParams, WorkerStatsGetOneParams, WorkerStatsGetParams,
+    WorkerSummary, WorkersGetParams, STATS_VERSION_HEADER,
+};
+
+/// `/worker-stats`, `/workers`, `/worker-metrics` and the other routes
+/// workers report to, with the reads of what they reported.
+pub fn routes(ctx: &Context) -> BoxedFilter<(Response,)> {
+    let Context {
+        worker_stats,
+        ingest,
+        ingest_queue,
+        worker_tokens,
+        quarantine,
+        groups_config,
+        host_metrics,
+        network_probes,
+        max_body_size,
+        handshake_policy,
+        ..
+    } = ctx.clone();
+
+    let queue = ingest_queue.clone();
+    let tokens = worker_tokens.clone();
+    let quarantine_ = quarantine.clone();
+    let worker_stats_put = warp::path!("worker-stats" / String)
+        .and(warp::put())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .and(warp::header::optional::<String>("traceparent"))
+        .and(warp::header::optional::<String>(idempotency::HEADER))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .and(body::bytes(max_body_size))
+        .then(
+            move |worker_id: String,
+                  token: Option<String>,
+                  traceparent: Option<String>,
+                  idempotency_key: Option<String>,
+                  namespace: Option<String>,
+                  payload: Bytes| {
+                let queue = queue.clone();
+                let tokens = tokens.clone();
+                let quarantine = quarantine_.clone();
+                let req = serde_json::from_slice::<SnarkWorkerStatsPut>(&payload);
+                let span = match &req {
+                    Ok(req) => info_span!(
+                        "worker_stats_put",
+                        %worker_id,
+                        kind = req.kind(),
+                        ids = req.ids()
+                    ),
+                    Err(_) => info_span!("worker_stats_put", %worker_id),
+                };
+                async move {
+                    if let Some(res) = saturated_reply(&queue) {
+                        return res;
+                    }
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply.into_response(),
+                    };
+                    // an array of events is applied as a batch.
+                    if payload.trim_ascii_start().starts_with(b"[") {
+                        let events = match serde_json::from_slice(&payload) {
+                            Ok(events) => events,
+                            Err(err) => {
+                                let msg = format!("invalid body: {err}");
+                                return error_reply(ErrorCode::InvalidBody, msg).into_response();
+                            }
+                        };
+                        if let Err(res) =
+                            check_worker_token(tokens.as_deref(), &worker_id, token.as_deref())
+                                .await
+                        {
+                            return res;
+                        }
+                        let trace = traceparent.as_deref().and_then(TraceContext::parse);
+                        let q = quarantine.as_deref();
+                        let key = idempotency_key;
+                        return put_batch(&queue, q, &worker_id, events, trace, namespace, key)
+                            .await;
+                    }
+                    let mut req = match req {
+                        Ok(req) => req,
+                        Err(err) => {
+                            let msg = format!("invalid body: {err}");
+                            let id = quarantine_payload(
+                                quarantine.as_deref(),
+                                &worker_id,
+                                ErrorCode::InvalidBody,
+                                &msg,
+                                &payload,
+                            )
+                            .await;
+                            let err = ApiError::new(ErrorCode::InvalidBody, msg);
+                            return quarantined_reply(err, id);
+                        }
+                    };
+                    let is_register = matches!(req, SnarkWorkerStatsPut::Register { .. });
+                    if let (Some(tokens), SnarkWorkerStatsPut::Register { resume, .. }) =
+                        (&tokens, &mut req)
+                    {
+                        // sessions are resumed with their token, which
+                        // wins over a `resume` id not matching it.
+                        let tokens = tokens.lock().await;
+                        let session = token.as_deref().and_then(|t| tokens.session(t));
+                        *resume = session
+                            .filter(|s| resume.as_deref().is_none_or(|r| r == *s))
+                            .map(str::to_owned);
+                    }
+                    if !is_register {
+                        if let Err(res) =
+                            check_worker_token(tokens.as_deref(), &worker_id, token.as_deref())
+                                .await
+                        {
+                            return res;
+                        }
+                    }
+                    if let Err(err) = queue.check(&req) {
+                        warn!("{err}");
+                        return api_error_reply(&err.to_api_error()).into_response();
+                    }
+                    let trace = traceparent.as_deref().and_then(TraceContext::parse);
+                    let reqs = vec![(req, &payload[..])];
+                    let ack = queue
+                        .queue(&worker_id, idempotency_key, reqs, trace, namespace)
+                        .await;
+                    let Ok(ack) = ack else {
+                        return ingest_stopped_reply();
+                    };
+                    let (version, replayed) = (ack.version, ack.replayed);
+                    // only a `Register` waits to be applied, its reply being
+                    // the session the worker goes on under.
+                    let res = match is_register {
+                        false => with_status(String::new(), StatusCode::from_u16(202).unwrap())
+                            .into_response(),
+                        true => {
+                            let Ok(mut results) = ack.applied().await else {
+                                return ingest_stopped_reply();
+                            };
+                            match results.pop().expect("one result per event") {
+                                Ok(Applied { body, .. }) => {
+                                    let token = match &tokens {
+                                        Some(tokens) => Some(tokens.lock().await.issue(&body)),
+                                        None => None,
+                                    };
+                                    let reply =
+                                        with_status(body, StatusCode::from_u16(200).unwrap());
+                                    match token {
+                                        Some(token) => warp::reply::with_header(
+                                            reply,
+                                            worker_tokens::HEADER,
+                                            token,
+                                        )
+                                        .into_response(),
+                                        None => reply.into_response(),
+                                    }
+                                }
+                                Err(err) => api_error_reply(&err.to_api_error()).into_response(),
+                            }
+                        }
+                    };
+                    let res =
+                        warp::reply::with_header(res, STATS_VERSION_HEADER, version.to_string());
+                    if !replayed {
+                        return res.into_response();
+                    }
+                    debug!("replayed the ack of the idempotency key");
+                    warp::reply::with_header(res, idempotency::REPLAYED_HEADER, "true")
+                        .into_response()
+                }
+                .instrument(span)
+            },
+        );
+
+    let queue = ingest_queue.clone();
+    let tokens = worker_tokens.clone();
+    let quarantine_ = quarantine.clone();
+    let worker_stats_batch_put = warp::path!("worker-stats" / String / "batch")
+        .and(warp::put())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .and(warp::header::optional::<String>("traceparent"))
+        .and(warp::header::optional::<String>(idempotency::HEADER))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .and(body::json(max_body_size))
+        .then(
+            move |worker_id: String,
+                  token: Option<String>,
+                  traceparent: Option<String>,
+                  idempotency_key: Option<String>,
+                  namespace: Option<String>,
+                  events: Vec<serde_json::Value>| {
+                let queue = queue.clone();
+                let tokens = tokens.clone();
+                let quarantine = quarantine_.clone();
+                let span = info_span!("worker_stats_batch_put", %worker_id, events = events.len());
+                async move {
+                    if let Some(res) = saturated_reply(&queue) {
+                        return res;
+                    }
+                    if let Err(res) =
+                        check_worker_token(tokens.as_deref(), &worker_id, token.as_deref()).await
+                    {
+                        return res;
+                    }
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply.into_response(),
+                    };
+                    let trace = traceparent.as_deref().and_then(TraceContext::parse);
+                    let q = quarantine.as_deref();
+                    let key = idempotency_key;
+                    put_batch(&queue, q, &worker_id, events, trace, namespace, key).await
+                }
+                .instrument(span)
+            },
+        );
+
+    let queue = ingest_queue.clone();
+    let tokens = worker_tokens.clone();
+    let quarantine_ = quarantine.clone();
+    let worker_stats_stream_post = warp::path!("worker-stats-stream" / String)
+        .and(warp::post())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .and(warp::header::optional::<String>("traceparent"))
+        .and(warp::header::optional::<String>("content-encoding"))
+        .and(warp::header::optional::<String>(lock_namespaces::HEADER))
+        .and(warp::body::stream())
+        .then(
+            move |worker_id: String,
+                  token: Option<String>,
+                  traceparent: Option<String>,
+                  encoding: Option<String>,
+                  namespace: Option<String>,
+                  body| {
+                let queue = queue.clone();
+                let tokens = tokens.clone();
+                let quarantine = quarantine_.clone();
+                let span = info_span!("worker_stats_stream_post", %worker_id);
+                async move {
+                    if let Some(encoding) = encoding.filter(|e| e.trim() != "identity") {
+                        let msg = format!("unsupported content-encoding: {encoding}");
+                        return error_reply(ErrorCode::UnsupportedEncoding, msg).into_response();
+                    }
+                    if let Err(res) =
+                        check_worker_token(tokens.as_deref(), &worker_id, token.as_deref()).await
+                    {
+                        return res;
+                    }
+                    let namespace = match lock_namespace(namespace.as_deref()) {
+                        Ok(namespace) => namespace,
+                        Err(reply) => return reply.into_response(),
+                    };
+                    let trace = traceparent.as_deref().and_then(TraceContext::parse);
+                    let q = quarantine.as_deref();
+                    let body = Box::pin(body);
+                    let max_len = max_body_size;
+                    put_stream(&queue, q, &worker_id, body, max_len, trace, namespace).await
+                }
+                .instrument(span)
+            },
+        );
+
+    let ingest_ = ingest.clone();
+    let tokens = worker_tokens.clone();
+    let worker_stats_delete = warp::path!("worker-stats" / String)
+        .and(warp::delete())
+        .and(
+            warp::filters::query::query::<WorkerStatsDeleteParams>()
+                .or(warp::any().map(WorkerStatsDeleteParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .then(
+            move |worker_id: String, params: WorkerStatsDeleteParams, token: Option<String>| {
+                let ingest = ingest_.clone();
+                let tokens = tokens.clone();
+                let archive = params.archive.unwrap_or(false);
+                let span = info_span!("worker_stats_delete", %worker_id, archive);
+                async move {
+                    if let Err(res) =
+                        check_worker_token(tokens.as_deref(), &worker_id, token.as_deref()).await
+                    {
+                        return res;
+                    }
+                    if !ingest.deregister(&worker_id, archive).await {
+                        let msg = format!("unknown worker: {worker_id}");
+                        return error_reply(ErrorCode::UnknownWorker, msg).into_response();
+                    }
+                    if let Some(tokens) = &tokens {
+                        tokens.lock().await.revoke(&worker_id);
+                    }
+                    info!("worker deregistered");
+                    with_status("".to_owned(), StatusCode::from_u16(200).unwrap()).into_response()
+                }
+                .instrument(span)
+            },
+        );
+
+    let ingest_ = ingest.clone();
+    let tokens = worker_tokens.clone();
+    let worker_stats_bulk_delete = warp::path!("worker-stats")
+        .and(warp::delete())
+        .and(body::json(max_body_size))
+        .then(move |req: DeregisterRequest| {
+            let ingest = ingest_.clone();
+            let tokens = tokens.clone();
+            let span = info_span!(
+                "worker_stats_bulk_delete",
+                workers = req.workers.len(),
+                archive = req.archive
+            );
+            async move {
+                let mut results = Vec::with_capacity(req.workers.len());
+                for (index, item) in req.workers.iter().enumerate() {
+                    let worker_id = &item.worker_id;
+                    if let Some(tokens) = &tokens {
+                        if let Err(err) =
+                            tokens.lock().await.verify(worker_id, item.token.as_deref())
+                        {
+                            results.push(BatchItem::failed(
+                                index,
+                                err.code(),
+                                err.message().to_owned(),
+                            ));
+                            continue;
+                        }
+                    }
+                    if !ingest.deregister(worker_id, req.archive).await {
+                        let msg = format!("unknown worker: {worker_id}");
+                        results.push(BatchItem::failed(index, ErrorCode::UnknownWorker, msg));
+                        continue;
+                    }
+                    if let Some(tokens) = &tokens {
+                        tokens.lock().await.revoke(worker_id);
+                    }
+                    results.push(BatchItem::<()>::succeeded(index, 200, None));
+                }
+                let res = BatchResponse::new(results);
+                info!(
+                    deregistered = res.summary.succeeded,
+                    failed = res.summary.failed,
+                    "workers deregistered"
+                );
+                with_status(
+                    serde_json::to_string(&res).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+            .instrument(span)
+        });
+
+    let groups = groups_config.clone();
+    let archive = ingest.archive.clone();
+    let archived_workers_get = warp::path!("archived-workers")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<ArchivedWorkersGetParams>()
+                .or(warp::any().map(ArchivedWorkersGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: ArchivedWorkersGetParams, authorization: Option<String>| {
+                let archive = archive.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let workers_filter = params
+                        .workers
+                        .map(|s| s.split(',').map(|s| s.to_owned()).collect::<Vec<_>>());
+                    let archive = archive.lock().await;
+                    let workers = archive.list(|worker_id| {
+                        scope.contains(worker_id)
+                            && workers_filter
+                                .as_ref()
+                                .is_none_or(|f| f.iter().any(|w| w == worker_id))
+                    });
+                    with_status(
+                        serde_json::to_string(&workers).unwrap(),
+                        StatusCode::from_u16(200).unwrap(),
+                    )
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let transitions = ingest.transitions.clone();
+    let live_feed = warp::path!("ws")
+        .and(warp::ws())
+        .and(
+            warp::filters::query::query::<LiveFeedParams>()
+                .or(warp::any().map(LiveFeedParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .map(
+            move |ws: warp::ws::Ws, params: LiveFeedParams, authorization: Option<String>| {
+                let Some(scope) = caller_scope(groups.as_deref(), authorization.as_deref()) else {
+                    return unauthorized_reply().into_response();
+                };
+                let workers = params
+                    .workers
+                    .map(|s| s.split(',').map(str::to_owned).collect());
+                let rx = transitions.subscribe();
+                ws.on_upgrade(move |socket| live::feed(socket, rx, scope, workers))
+                    .into_response()
+            },
+        );
+
+    let groups = groups_config.clone();
+    let error_events = ingest.error_events.clone();
+    let error_events_get = warp::path!("events")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<ErrorEventsParams>()
+                .or(warp::any().map(ErrorEventsParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("last-event-id"))
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |params: ErrorEventsParams,
+                  last_event_id: Option<String>,
+                  authorization: Option<String>| {
+                let error_events = error_events.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    let last_id = last_event_id
+                        .and_then(|id| id.trim().parse().ok())
+                        .or(params.last_event_id);
+                    let (missed, rx) = error_events.lock().await.subscribe(last_id);
+                    let events = live::error_events(missed, rx, scope);
+                    warp::sse::reply(warp::sse::keep_alive().stream(events)).into_response()
+                }
+            },
+        );
+
+    let stats = worker_stats.clone();
+    let liveness = ingest.liveness.clone();
+    let tokens = worker_tokens.clone();
+    let worker_heartbeat_put = warp::path!("worker-heartbeat" / String)
+        .and(warp::put())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .then(move |worker_id: String, token: Option<String>| {
+            let stats = stats.clone();
+            let liveness = liveness.clone();
+            let tokens = tokens.clone();
+            async move {
+                if let Err(res) =
+                    check_worker_token(tokens.as_deref(), &worker_id, token.as_deref()).await
+                {
+                    return res;
+                }
+                if !stats.read().await.contains_key(&worker_id) {
+                    let msg = format!("unknown worker: {worker_id}");
+                    return error_reply(ErrorCode::UnknownWorker, msg).into_response();
+                }
+                liveness.lock().await.seen(&worker_id, timestamp::now());
+                with_status("".to_owned(), StatusCode::from_u16(200).unwrap()).into_response()
+            }
+        });
+
+    let stats = worker_stats.clone();
+    let policy = handshake_policy.clone();
+    let handshake_post = warp::path!("handshake")
+        .and(warp::post())
+        .and(body::json(max_body_size))
+        .then(move |req: HandshakeRequest| {
+            let stats = stats.clone();
+            let policy = policy.clone();
+            let span = info_span!("handshake", worker_id = %req.worker_id);
+            async move {
+                let sessions = stats.read().await.sessions(&req.worker_id).count();
+                let res = policy.check(&req, timestamp::now(), sessions);
+                if !res.accepted {
+                    warn!(problems = ?res.problems, "handshake failed");
+                }
+                let status = if res.accepted { 200 } else { 400 };
+                with_status(
+                    serde_json::to_string(&res).unwrap(),
+                    StatusCode::from_u16(status).unwrap(),
+                )
+            }
+            .instrument(span)
+        });
+
+    let stats = worker_stats.clone();
+    let stats_version = ingest.version.clone();
+    let liveness = ingest.liveness.clone();
+    let skews = ingest.clock_skews.clone();
+    let groups = groups_config.clone();
+    let workers_get = warp::path!("workers")
+        .and(warp::get())
+        .and(
+            warp::filters::query::query::<WorkersGetParams>()
+                .or(warp::any().map(WorkersGetParams::default))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .then(
+            move |params: WorkersGetParams,
+                  authorization: Option<String>,
+                  if_none_match: Option<String>| {
+                let stats = stats.clone();
+                let stats_version = stats_version.clone();
+                let liveness = liveness.clone();
+                let skews = skews.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    let parsed = parse_kinds(params.state.as_deref()).and_then(|kinds| {
+                        let statuses = parse_statuses(params.status.as_deref())?;
+                        Ok((kinds, statuses, parse_workers_sort(params.sort.as_deref())?))
+                    });
+                    let (kinds, statuses, (sort, desc)) = match parsed {
+                        Ok(parsed) => parsed,
+                        Err(msg) => {
+                            return error_reply(ErrorCode::InvalidParameter, msg).into_response();
+                        }
+                    };
+                    let stats = stats.read().await;
+                    let with_summary = params.summary.unwrap_or(false);
+                    let with_liveness = params.liveness.unwrap_or(false) || with_summary;
+                    let with_metadata = params.metadata.unwrap_or(false) || with_summary;
+                    let with_clock_skew = params.clock_skew.unwrap_or(false);
+                    // liveness and clock skews change without the stats
+                    // changing, so those responses aren't tagged.
+                    let etag = (!with_liveness && !with_clock_skew && statuses.is_none())
+                        .then(|| stats_etag(*stats_version.borrow(), None));
+                    if let Some(etag) = etag.as_ref() {
+                        if etag_matches(if_none_match.as_deref(), etag) {
+                            return not_modified_reply(etag.clone());
+                        }
+                    }
+                    let sessions = params
+                        .name
+                        .as_deref()
+                        .map(|name| stats.sessions(name).collect::<HashSet<_>>());
+                    let liveness = liveness.lock().await;
+                    let now = timestamp::now();
+                    let workers = stats.iter().filter(|(k, _)| {
+                        scope.contains(k)
+                            && sessions.as_ref().is_none_or(|s| s.contains(k.as_str()))
+                            && kinds.as_ref().is_none_or(|kinds| {
+                                let kind = stats.latest(k).map(|state| state.kind());
+                                kind.is_some_and(|kind| kinds.contains(&kind))
+                            })
+                            && statuses.as_ref().is_none_or(|statuses| {
+                                statuses.contains(&liveness.get(k, now).status)
+                            })
+                    });
+                    let skews = skews.lock().await;
+                    let mut workers = workers
+                        .map(|(k, states)| WorkerInfo {
+                            worker_id: k,
+                            liveness: with_liveness.then(|| liveness.get(k, now)),
+                            name: stats.name(k).filter(|_| with_metadata),
+                            metadata: states
+                                .iter()
+                                .find_map(|s| s.metadata())
+                                .filter(|_| with_metadata),
+                            clock_skew: skews.get(k).filter(|_| with_clock_skew),
+                            summary: (with_summary || sort != "worker_id")
+                                .then(|| WorkerSummary::new(&stats, k, states)),
+                        })
+                        .collect::<Vec<_>>();
+                    // sorted by id first, so ties keep that order.
+                    workers.sort_by_key(|w| w.worker_id);
+                    let summary = |w: &WorkerInfo<'_>| {
+                        w.summary
+                            .as_ref()
+                            .map(|s| (s.last_event_t, s.state, s.totals.succeeded, s.failed()))
+                    };
+                    match sort {
+                        "last_event_t" => workers.sort_by_key(|w| summary(w).map(|s| s.0)),
+                        "state" => workers.sort_by_key(|w| summary(w).map(|s| s.1)),
+                        "succeeded" => workers.sort_by_key(|w| summary(w).map(|s| s.2)),
+                        "failed" => workers.sort_by_key(|w| summary(w).map(|s| s.3)),
+                        _ => {}
+                    }
+                    if desc {
+                        workers.reverse();
+                    }
+                    let body = if with_liveness || with_metadata || with_clock_skew {
+                        if !with_summary {
+                            workers.iter_mut().for_each(|w| w.summary = None);
+                        }
+                        serde_json::to_string(&workers).unwrap()
+                    } else {
+                        let workers = workers.iter().map(|w| w.worker_id).collect::<Vec<_>>();
+                        serde_json::to_string(&workers).unwrap()
+                    };
+                    let reply = with_status(body, StatusCode::from_u16(200).unwrap());
+                    match etag {
+                        Some(etag) => warp::reply::with_header(reply, "etag", etag).into_response(),
+                        None => reply.into_response(),
+                    }
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let stats_version = ingest.version.clone();
+    let queue = ingest_queue.clone();
+    let worker_stats_get = warp::path!("worker-stats")
+        .and(
+            warp::filters::query::query::<WorkerStatsGetParams>()
+                .or(warp::any().map(WorkerStatsGetParams::default))
+                .unify(),
+        )
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .and(warp::header::optional::<String>("accept"))
+        .and(warp::header::optional::<String>("if-none-match"))
+        .and(warp::ext::optional::<formats::Format>())
+        .then(
+            move |params: WorkerStatsGetParams,
+                  authorization: Option<String>,
+                  accept: Option<String>,
+                  if_none_match: Option<String>,
+                  body_format: Option<formats::Format>| {
+                let shared_stats = stats.clone();
+                let stats = stats.clone();
+                let version = queue.applied();
+                let current_version = stats_version.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                let format = ExportFormat::negotiate(params.format, accept.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply().into_response();
+                    };
+                    if let Some(min_version) = params.min_version {
+                        if !wait_for_version(version, min_version).await {
+                            let msg = format!("stats version {min_version} not reached");
+                            return error_reply(ErrorCode::VersionNotReached, msg).into_response();
+                        }
+                    }
+                    let cursor = match params.cursor.as_deref().map(str::parse::<StatsCursor>) {
+                        Some(Err(err)) => {
+                            return error_reply(ErrorCode::InvalidParameter, err).into_response();
+                        }
+                        cursor => cursor.and_then(Result::ok),
+                    };
+                    let (from_t, to_t) = match time_range(params.from_t, params.to_t) {
+                        Ok(range) => range,
+                        Err(msg) => {
+                            return error_reply(ErrorCode::InvalidParameter, msg).into_response();
+                        }
+                    };
+                    let order = params.order.unwrap_or_default();
+                    let paginate = params.limit.is_some()
+                        || params.per_worker_limit.is_some()
+                        || cursor.is_some();
+                    let kinds_filter = match parse_kinds(params.kinds.as_deref()) {
+                        Ok(kinds) => kinds,
+                        Err(msg) => {
+                            return error_reply(ErrorCode::InvalidParameter, msg).into_response();
+                        }
+                    };
+                    let kind_matches = |state: &SnarkWorkerState| {
+                        kinds_filter
+                            .as_ref()
+                            .is_none_or(|f| f.contains(&state.kind()))
+                    };
+                    let stats = stats.read().await;
+                    // read under the stats lock, so the version covers
+                    // every write the response reflects.
+                    let etag = stats_etag(*current_version.borrow(), format);
+                    if etag_matches(if_none_match.as_deref(), &etag) {
+                        return not_modified_reply(etag);
+                    }
+                    let time_format = params.time_format.unwrap_or_default();
+                    let durations = params.durations.unwrap_or(false);
+                    let workers_filter = params
+                        .workers
+                        .map(|s| s.split(",").map(|s| s.to_owned()).collect::<Vec<_>>());
+
+                    let iter = stats
+                        .iter()
+                        .filter(|(k, _)| scope.contains(k))
+                        .filter(|(k, _)| workers_filter.as_ref().is_none_or(|f| f.contains(k)));
+                    let mut page = None;
+                    // exports aren't paginated.
+                    if paginate && format.is_none_or(|f| f == ExportFormat::Json) {
+                        let mut workers = iter
+                            .clone()
+                            .filter(|(k, _)| cursor.as_ref().is_none_or(|c| **k >= c.worker_id))
+                            .collect::<Vec<_>>();
+                        workers.sort_unstable_by(|a, b| a.0.cmp(b.0));
+                        let mut remaining = params.limit.unwrap_or(usize::MAX);
+                        let per_worker_limit = params.per_worker_limit.unwrap_or(usize::MAX);
+                        let mut res = StatsPage {
+                            workers: BTreeMap::new(),
+                            next: None,
+                        };
+                        for (k, _) in workers {
+                            let mut from = Bound::Included((from_t.unwrap_or(0), 0));
+                            let mut to = to_t.map_or(Bound::Unbounded, |t| Bound::Excluded((t, 0)));
+                            // the cursor's state is the first of the page.
+                            match cursor.as_ref().filter(|c| &c.worker_id == k) {
+                                Some(c) if order == Order::Asc => {
+                                    from = Bound::Included(c.key.max((from_t.unwrap_or(0), 0)));
+                                }
+                                Some(c) if to_t.is_none_or(|t| c.key < (t, 0)) => {
+                                    to = Bound::Included(c.key);
+                                }
+                                _ => {}
+                            }
+                            let entries = stats
+                                .range_by_end_t(k, from, to, order)
+                                .filter(|(_, state)| kind_matches(state))
+                                .take(per_worker_limit);
+                            let mut page_states = vec![];
+                            // copied, so the page is serialized without
+                            // holding up ingestion.
+                            for (key, state) in entries {
+                                if remaining == 0 {
+                                    let next = StatsCursor {
+                                        worker_id: k.clone(),
+                                        key,
+                                    };
+                                    res.next = Some(next.to_string());
+                                    break;
+                                }
+                                remaining -= 1;
+                                page_states.push(StateView::new(state.clone(), durations));
+                            }
+                            if res.next.is_some() {
+                                if !page_states.is_empty() {
+                                    res.workers.insert(k.clone(), page_states);
+                                }
+                                break;
+                            }
+                            res.workers.insert(k.clone(), page_states);
+                        }
+                        page = Some(res);
+                    }
+                    if let Some(format @ (ExportFormat::Parquet | ExportFormat::Csv)) = format {
+                        let rows = iter
+                            .flat_map(|(k, _)| {
+                                let states = stats.in_range(k, from_t, to_t, order);
+                                let states = states.filter(|(_, state)| kind_matches(state));
+                                states.map(move |(_, state)| (k.clone(), state.clone()))
+                            })
+                            .collect::<Vec<_>>();
+                        drop(stats);
+                        let rows = rows.iter().map(|(k, state)| (k.as_str(), state));
+                        let reply = match format {
+                            ExportFormat::Csv => csv_reply(export::lifecycles_csv(rows)),
+                            _ => parquet_reply(export::lifecycles_parquet(rows)),
+                        };
+                        return warp::reply::with_header(reply, "etag", etag).into_response();
+                    }
+                    if let Some(page) = page {
+                        drop(stats);
+                        let body = match time_format {
+                            TimeFormat::Unix => serde_json::to_string(&page).unwrap(),
+                            TimeFormat::Iso8601 => {
+                                let mut value = serde_json::to_value(&page).unwrap();
+                                localize_timestamps(&mut value);
+                                value.to_string()
+                            }
+                        };
+                        let reply = with_status(body, StatusCode::from_u16(200).unwrap());
+                        return warp::reply::with_header(reply, "etag", etag).into_response();
+                    }
+
+                    // unpaginated responses grow with the fleet, so they're
+                    // streamed a chunk at a time rather than buffered.
+                    let workers = iter.map(|(k, _)| k.clone()).collect();
+                    drop(stats);
+                    let kinds_filter =
+                        kinds_filter.map(|f| f.into_iter().map(str::to_owned).collect::<Vec<_>>());
+                    let body = stream::object(
+                        shared_stats,
+                        workers,
+                        body_format,
+                        move |stats: &WorkerStats, k| {
+                            stats.get(k)?;
+                            let v = stats.in_range(k, from_t, to_t, order).filter(|(_, state)| {
+                                kinds_filter
+                                    .as_ref()
+                                    .is_none_or(|f| f.iter().any(|kind| kind == state.kind()))
+                            });
+                            let v = v.map(|(_, s)| StateView::new(s.clone(), durations));
+                            Some(v.collect::<Vec<_>>())
+                        },
+                        move |v, buf| match time_format {
+                            TimeFormat::Unix => stream::write_value(body_format, &v, buf),
+                            TimeFormat::Iso8601 => {
+                                let mut value = serde_json::to_value(&v).unwrap();
+                                localize_timestamps(&mut value);
+                                stream::write_value(body_format, &value, buf);
+                            }
+                        },
+                    );
+                    // streamed in the negotiated format, as it can't be
+                    // transcoded once sent.
+                    let content_type = body_format.map_or("application/json", |f| f.content_type());
+                    let reply = warp::reply::with_header(
+                        warp::reply::Response::new(body),
+                        "content-type",
+                        content_type,
+                    );
+                    let reply = warp::reply::with_header(reply, "vary", "accept");
+                    warp::reply::with_header(reply, "etag", etag).into_response()
+                }
+            },
+        );
+
+    let groups = groups_config.clone();
+    let stats = worker_stats.clone();
+    let worker_stats_get_one = warp::path!("worker-stats" / String)
+        .and(
+            warp::filters::query::query::<WorkerStatsGetOneParams>()
+                .or(warp::any().map(WorkerStatsGetOneParams::default))
+                .unify(),
+        )
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |worker_id: String,
+                  params: WorkerStatsGetOneParams,
+                  authorization: Option<String>| {
+                let stats = stats.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let kinds_filter = match parse_kinds(params.kinds.as_deref()) {
+                        Ok(kinds) => kinds,
+                        Err(msg) => return error_reply(ErrorCode::InvalidParameter, msg),
+                    };
+                    let (from_t, to_t) = match time_range(params.from_t, params.to_t) {
+                        Ok(range) => range,
+                        Err(msg) => return error_reply(ErrorCode::InvalidParameter, msg),
+                    };
+                    let stats = stats.read().await;
+                    // workers outside the caller's scope don't exist for them.
+                    if stats.get(&worker_id).is_none() || !scope.contains(&worker_id) {
+                        let msg = format!("unknown worker: {worker_id}");
+                        return error_reply(ErrorCode::UnknownWorker, msg);
+                    }
+                    let order = params.order.unwrap_or_default();
+                    let durations = params.durations.unwrap_or(false);
+                    let states = (stats.in_range(&worker_id, from_t, to_t, order))
+                        .filter(|(_, state)| {
+                            kinds_filter
+                                .as_ref()
+                                .is_none_or(|k| k.contains(&state.kind()))
+                        })
+                        .take(params.limit.unwrap_or(usize::MAX))
+                        .map(|(_, state)| StateView::new(state.clone(), durations))
+                        .collect::<Vec<_>>();
+                    drop(stats);
+                    let body = match params.time_format.unwrap_or_default() {
+                        TimeFormat::Unix => serde_json::to_string(&states).unwrap(),
+                        TimeFormat::Iso8601 => {
+                            let mut value = serde_json::to_value(&states).unwrap();
+                            localize_timestamps(&mut value);
+                            value.to_string()
+                        }
+                    };
+                    with_status(body, StatusCode::from_u16(200).unwrap())
+                }
+            },
+        );
+
+    let hosts = host_metrics.clone();
+    let tokens = worker_tokens.clone();
+    let worker_metrics_put = warp::path!("worker-metrics" / String)
+        .and(warp::put())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .and(body::json(max_body_size))
+        .then(
+            move |worker_id: String, token: Option<String>, sample: HostSample| {
+                let hosts = hosts.clone();
+                let tokens = tokens.clone();
+                async move {
+                    if let Err(res) =
+                        check_worker_token(tokens.as_deref(), &worker_id, token.as_deref()).await
+                    {
+                        return res;
+                    }
+                    hosts.lock().await.put(worker_id, sample);
+                    with_status("".to_owned(), StatusCode::from_u16(200).unwrap()).into_response()
+                }
+            },
+        );
+
+    let probes = network_probes.clone();
+    let tokens = worker_tokens.clone();
+    let echo_post = warp::path!("echo")
+        .and(warp::post())
+        .and(warp::header::optional::<String>(worker_tokens::HEADER))
+        .and(body::json(max_body_size))
+        .then(move |token: Option<String>, req: EchoRequest| {
+            let received_t = timestamp::now();
+            let probes = probes.clone();
+            let tokens = tokens.clone();
+            async move {
+                if let Some(worker_id) = &req.worker_id {
+                    if let Err(res) =
+                        check_worker_token(tokens.as_deref(), worker_id, token.as_deref()).await
+                    {
+                        return res;
+                    }
+                    probes.lock().await.put(worker_id.clone(), &req, received_t);
+                }
+                let res = EchoResponse {
+                    client_t: req.client_t,
+                    received_t,
+                    responded_t: timestamp::now(),
+                };
+                with_status(
+                    serde_json::to_string(&res).unwrap(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+                .into_response()
+            }
+        });
+
+    let groups = groups_config.clone();
+    let hosts = host_metrics.clone();
+    let worker_metrics_get = warp::path!("worker-metrics" / String)
+        .and(
+            warp::filters::query::query::<WorkerMetricsGetParams>()
+                .or(warp::any().map(WorkerMetricsGetParams::default))
+                .unify(),
+        )
+        .and(warp::get())
+        .and(warp::header::optional::<String>("authorization"))
+        .then(
+            move |worker_id: String,
+                  params: WorkerMetricsGetParams,
+                  authorization: Option<String>| {
+                let hosts = hosts.clone();
+                let scope = caller_scope(groups.as_deref(), authorization.as_deref());
+                async move {
+                    let Some(scope) = scope else {
+                        return unauthorized_reply();
+                    };
+                    let from_t = params.from_t.map(timestamp::normalize);
+                    let to_t = params.to_t.map(timestamp::normalize);
+                    let hosts = hosts.lock().await;
+                    let samples = hosts
+                        .samples(&worker_id, from_t, to_t)
+                        .filter(|_| scope.contains(&worker_id));
+                    let Some(samples) = samples else {
+                        let msg = format!("no samples for worker: {worker_id}");
+                        return error_reply(ErrorCode::UnknownWorker, msg);
+                    };
+                    let body = match params.time_format.unwrap_or_default() {
+                        TimeFormat::Unix => serde_json::to_string(&samples).unwrap(),
+                        TimeFormat::Iso8601 => {
+                            let mut value = serde_json::to_value(&samples).unwrap();
+                            localize_timestamps(&mut value);
+                            value.to_string()
+                        }
+                    };
+                    with_status(body, StatusCode::from_u16(200).unwrap())
+                }
+            },
+        );
+
+    let ingest_ = ingest.clone();
+    let admin_history_delete = warp::path!("admin" / "workers" / String / "history")
+        .and(warp::delete())
+        .then(move |worker_id: String| {
+            let ingest = ingest_.clone();
+            async move {
+                let Some(dropped) = ingest.clear_history(&worker_id).await else {
+                    let msg = format!("no stats for {worker_id}");
+                    return error_reply(ErrorCode::UnknownWorker, msg);
+                };
+                info!(worker_id, dropped, "worker history cleared");
+                with_status(
+                    serde_json::json!({ "dropped": dropped }).to_string(),
+                    StatusCode::from_u16(200).unwrap(),
+                )
+            }
+        });
+
+    worker_stats_put
+        .or(worker_stats_batch_put)
+        .or(worker_stats_stream_post)
+        .or(worker_stats_delete)
+        .or(worker_stats_bulk_delete)
+        .or(archived_workers_get)
+        .or(live_feed)
+        .or(error_events_get)
+        .or(worker_heartbeat_put)
+        .or(handshake_post)
+        .or(workers_get)
+        .or(worker_stats_get)
+        .or(worker_stats_get_one)
+        .or(worker_metrics_put)
+        .or(worker_metrics_get)
+        .or(echo_post)
+        .or(admin_history_delete)
+        .map(Reply::into_response)
+        .boxed()
+}
@@ -0,0 +1,345 @@
+//! JSON-RPC 2.0 endpoint for tooling which doesn't speak the REST API.
+//! Every call is translated into the equivalent REST request and served
+//! by the same routes, so behaviour, auth and metrics are identical.
+
+use std::convert::Infallible;
+
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use snark_coordinator_rs::worker_tokens;
+use warp::hyper::{
+    body::{Bytes, HttpBody},
+    header::{AUTHORIZATION, CONTENT_TYPE},
+    service::Service,
+    Body, Method, Request, Response, StatusCode,
+};
+
+const PARSE_ERROR: i64 = -32700;
+const INVALID_REQUEST: i64 = -32600;
+const METHOD_NOT_FOUND: i64 = -32601;
+const INVALID_PARAMS: i64 = -32602;
+/// Non-2xx response of the underlying route, its status is in `data`.
+const SERVER_ERROR: i64 = -32000;
+
+#[derive(Deserialize)]
+struct RpcRequest {
+    jsonrpc: String,
+    method: String,
+    #[serde(default)]
+    params: Value,
+    /// Absent for notifications, which get no response.
+    id: Option<Value>,
+}
+
+#[derive(Serialize)]
+struct RpcError {
+    code: i64,
+    message: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    data: Option<Value>,
+}
+
+impl RpcError {
+    fn new(code: i64, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            data: None,
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct LockJobParams {
+    key: String,
+    timeout: Option<u16>,
+    timeout_ms: Option<u64>,
+    worker_id: Option<String>,
+    wait: Option<u16>,
+}
+
+#[derive(Deserialize)]
+struct ReleaseJobParams {
+    key: String,
+}
+
+#[derive(Deserialize)]
+struct PutWorkerStatsParams {
+    worker_id: String,
+    stats: Value,
+    /// Session token received on registration.
+    token: Option<String>,
+}
+
+/// Internal REST request a call maps to.
+struct Call {
+    method: Method,
+    path: String,
+    body: Option<Value>,
+    worker_token: Option<String>,
+}
+
+fn params<T: for<'de> Deserialize<'de>>(params: Value) -> Result<T, RpcError> {
+    serde_json::from_value(params).map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))
+}
+
+/// Path segment, refusing values which would change the request target.
+fn segment(s: &str) -> Result<&str, RpcError> {
+    if s.is_empty() || s.contains(['/', '?', '#', '%']) || s.contains(char::is_whitespace) {
+        return Err(RpcError::new(
+            INVALID_PARAMS,
+            format!("invalid path segment: {s:?}"),
+        ));
+    }
+    Ok(s)
+}
+
+fn query(params: &impl Serialize) -> Result<String, RpcError> {
+    let query = serde_urlencoded::to_string(params)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+    Ok(if query.is_empty() {
+        query
+    } else {
+        format!("?{query}")
+    })
+}
+
+fn route(method: &str, p: Value) -> Result<Call, RpcError> {
+    Ok(match method {
+        "lockJob" => {
+            let p: LockJobParams = params(p)?;
+            #[derive(Serialize)]
+            struct Query {
+                #[serde(skip_serializing_if = "Option::is_none")]
+                timeout: Option<u16>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                timeout_ms: Option<u64>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                worker_id: Option<String>,
+                #[serde(skip_serializing_if = "Option::is_none")]
+                wait: Option<u16>,
+            }
+            let key = segment(&p.key)?;
+            let query = query(&Query {
+                timeout: p.timeout,
+                timeout_ms: p.timeout_ms,
+                worker_id: p.worker_id,
+                wait: p.wait,
+            })?;
+            Call {
+                method: Method::PUT,
+                path: format!("/lock-job/{key}{query}"),
+                body: None,
+                worker_token: None,
+            }
+        }
+        "releaseJob" => {
+            let p: ReleaseJobParams = params(p)?;
+            Call {
+                method: Method::DELETE,
+                path: format!("/lock-job/{}", segment(&p.key)?),
+                body: None,
+                worker_token: None,
+            }
+        }
+        "putWorkerStats" => {
+            let p: PutWorkerStatsParams = params(p)?;
+            Call {
+                method: Method::PUT,
+                path: format!("/worker-stats/{}", segment(&p.worker_id)?),
+                body: Some(p.stats),
+                worker_token: p.token,
+            }
+        }
+        "getWorkerStats" => {
+            let p = match p {
+                Value::Null => serde_json::Map::new(),
+                Value::Object(p) => p,
+                _ => return Err(RpcError::new(INVALID_PARAMS, "params must be an object")),
+            };
+            Call {
+                method: Method::GET,
+                path: format!("/worker-stats{}", query(&p)?),
+                body: None,
+                worker_token: None,
+            }
+        }
+        _ => {
+            return Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("unknown method: {method}"),
+            ))
+        }
+    })
+}
+
+async fn read_body(body: Body, max_len: u64) -> Result<Bytes, RpcError> {
+    let mut body = body;
+    let mut buf = Vec::new();
+    while let Some(chunk) = body.data().await {
+        let chunk = chunk.map_err(|err| RpcError::new(PARSE_ERROR, err.to_string()))?;
+        if (buf.len() + chunk.len()) as u64 > max_len {
+            return Err(RpcError::new(
+                INVALID_REQUEST,
+                format!("body too large! max: {max_len}"),
+            ));
+        }
+        buf.extend_from_slice(&chunk);
+    }
+    Ok(buf.into())
+}
+
+/// Serves a JSON-RPC request (single or batch) at `/rpc` through `svc`.
+pub async fn handle<S>(req: Request<Body>, svc: S, max_body_size: u64) -> Response<Body>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+{
+    if req.method() != Method::POST {
+        return response(StatusCode::METHOD_NOT_ALLOWED, Body::empty());
+    }
+    let authorization = req.headers().get(AUTHORIZATION).cloned();
+    let body = match read_body(req.into_body(), max_body_size).await {
+        Ok(body) => body,
+        Err(err) => return reply(&error_response(Value::Null, err)),
+    };
+    let value: Value = match serde_json::from_slice(&body) {
+        Ok(value) => value,
+        Err(err) => {
+            let err = RpcError::new(PARSE_ERROR, err.to_string());
+            return reply(&error_response(Value::Null, err));
+        }
+    };
+
+    match value {
+        Value::Array(calls) if calls.is_empty() => {
+            let err = RpcError::new(INVALID_REQUEST, "empty batch");
+            reply(&error_response(Value::Null, err))
+        }
+        Value::Array(calls) => {
+            let mut responses = vec![];
+            for call in calls {
+                let res = dispatch(call, &svc, authorization.as_ref()).await;
+                responses.extend(res);
+            }
+            if responses.is_empty() {
+                return response(StatusCode::NO_CONTENT, Body::empty());
+            }
+            reply(&Value::Array(responses))
+        }
+        call => match dispatch(call, &svc, authorization.as_ref()).await {
+            Some(res) => reply(&res),
+            None => response(StatusCode::NO_CONTENT, Body::empty()),
+        },
+    }
+}
+
+/// Runs a single call, returns `None` for notifications.
+async fn dispatch<S>(
+    call: Value,
+    svc: &S,
+    authorization: Option<&warp::hyper::header::HeaderValue>,
+) -> Option<Value>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+{
+    let call = match serde_json::from_value::<RpcRequest>(call) {
+        Ok(call) if call.jsonrpc == "2.0" => call,
+        Ok(call) => {
+            let err = RpcError::new(INVALID_REQUEST, "jsonrpc must be \"2.0\"");
+            return Some(error_response(call.id.unwrap_or(Value::Null), err));
+        }
+        Err(err) => {
+            let err = RpcError::new(INVALID_REQUEST, err.to_string());
+            return Some(error_response(Value::Null, err));
+        }
+    };
+    let id = call.id;
+    let res = match route(&call.method, call.params) {
+        Ok(internal) => execute(internal, svc, authorization).await,
+        Err(err) => Err(err),
+    };
+    let id = id?;
+    Some(match res {
+        Ok(result) => json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(err) => error_response(id, err),
+    })
+}
+
+async fn execute<S>(
+    call: Call,
+    svc: &S,
+    authorization: Option<&warp::hyper::header::HeaderValue>,
+) -> Result<Value, RpcError>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible> + Clone,
+{
+    let mut req = Request::builder().method(call.method).uri(call.path);
+    if let Some(authorization) = authorization {
+        req = req.header(AUTHORIZATION, authorization);
+    }
+    if let Some(token) = call.worker_token {
+        req = req.header(worker_tokens::HEADER, token);
+    }
+    let body = match call.body {
+        Some(body) => {
+            req = req.header(CONTENT_TYPE, "application/json");
+            Body::from(body.to_string())
+        }
+        None => Body::empty(),
+    };
+    let req = req
+        .body(body)
+        .map_err(|err| RpcError::new(INVALID_PARAMS, err.to_string()))?;
+
+    let res = match svc.clone().call(req).await {
+        Ok(res) => res,
+        Err(never) => match never {},
+    };
+    let status = res.status();
+    let token = res.headers().get(worker_tokens::HEADER).cloned();
+    let body = warp::hyper::body::to_bytes(res.into_body())
+        .await
+        .unwrap_or_default();
+    let body = match serde_json::from_slice(&body) {
+        Ok(body) => body,
+        Err(_) if body.is_empty() => Value::Null,
+        Err(_) => Value::String(String::from_utf8_lossy(&body).into_owned()),
+    };
+    if status.is_success() {
+        // registration hands out a worker token next to the id.
+        return Ok(match token.as_ref().and_then(|t| t.to_str().ok()) {
+            Some(token) => json!({ "worker_id": body, "token": token }),
+            None => body,
+        });
+    }
+    let message = match &body {
+        Value::String(s) if !s.is_empty() => s.clone(),
+        // coded errors, the code stays available in `data.body`.
+        Value::Object(err) if err.get("message").is_some_and(Value::is_string) => {
+            err["message"].as_str().unwrap_or_default().to_owned()
+        }
+        _ => status.canonical_reason().unwrap_or("error").to_owned(),
+    };
+    Err(RpcError {
+        code: SERVER_ERROR,
+        message,
+        data: Some(json!({ "status": status.as_u16(), "body": body })),
+    })
+}
+
+fn error_response(id: Value, err: RpcError) -> Value {
+    json!({ "jsonrpc": "2.0", "error": err, "id": id })
+}
+
+fn reply(value: &Value) -> Response<Body> {
+    let mut res = response(StatusCode::OK, Body::from(value.to_string()));
+    res.headers_mut()
+        .insert(CONTENT_TYPE, "application/json".parse().unwrap());
+    res
+}
+
+fn response(status: StatusCode, body: Body) -> Response<Body> {
+    let mut res = Response::new(body);
+    *res.status_mut() = status;
+    res
+}
@@ -0,0 +1,117 @@
+//! Periodic background tasks, e.g. the expiry sweep or stats retention.
+//! They're all run by one scheduler, which records how each run went for
+//! `GET /admin/tasks`, and lets operators run a task right away or pause
+//! it, e.g. to keep retention from pruning stats while investigating.
+
+use std::{
+    collections::BTreeMap,
+    future::Future,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use serde::Serialize;
+use snark_coordinator_rs::timestamp;
+use tokio::sync::Notify;
+use tracing::warn;
+
+/// Status of a task, as listed by `GET /admin/tasks`.
+#[derive(Serialize, Debug, Clone)]
+pub struct TaskStatus {
+    pub name: &'static str,
+    pub interval_ms: u64,
+    pub paused: bool,
+    pub runs: u64,
+    pub last_run_t: Option<u64>,
+    pub last_duration_ms: Option<u64>,
+    /// Error of the last run, cleared by the next successful one.
+    pub last_error: Option<String>,
+}
+
+struct Task {
+    status: Mutex<TaskStatus>,
+    trigger: Notify,
+}
+
+#[derive(Default)]
+pub struct Scheduler {
+    tasks: Mutex<BTreeMap<&'static str, Arc<Task>>>,
+}
+
+impl Scheduler {
+    /// Spawns a task calling `run` every `interval`, unless it's paused.
+    pub fn every<F, Fut>(&self, name: &'static str, interval: Duration, mut run: F)
+    where
+        F: FnMut() -> Fut + Send + 'static,
+        Fut: Future<Output = Result<(), String>> + Send,
+    {
+        let task = Arc::new(Task {
+            status: Mutex::new(TaskStatus {
+                name,
+                interval_ms: interval.as_millis() as u64,
+                paused: false,
+                runs: 0,
+                last_run_t: None,
+                last_duration_ms: None,
+                last_error: None,
+            }),
+            trigger: Notify::new(),
+        });
+        let prev = self.tasks.lock().unwrap().insert(name, task.clone());
+        assert!(prev.is_none(), "task {name} scheduled twice");
+        tokio::spawn(async move {
+            loop {
+                tokio::select! {
+                    _ = tokio::time::sleep(interval) => {
+                        if task.status.lock().unwrap().paused {
+                            continue;
+                        }
+                    }
+                    // triggered runs go ahead even when paused.
+                    _ = task.trigger.notified() => {}
+                }
+                let started = Instant::now();
+                let res = run().await;
+                if let Err(err) = &res {
+                    warn!(task = name, %err, "task failed");
+                }
+                let mut status = task.status.lock().unwrap();
+                status.runs += 1;
+                status.last_run_t = Some(timestamp::now());
+                status.last_duration_ms = Some(started.elapsed().as_millis() as u64);
+                status.last_error = res.err();
+            }
+        });
+    }
+
+    pub fn tasks(&self) -> Vec<TaskStatus> {
+        let tasks = self.tasks.lock().unwrap();
+        tasks
+            .values()
+            .map(|task| task.status.lock().unwrap().clone())
+            .collect()
+    }
+
+    pub fn status(&self, name: &str) -> Option<TaskStatus> {
+        let task = self.tasks.lock().unwrap().get(name).cloned()?;
+        let status = task.status.lock().unwrap().clone();
+        Some(status)
+    }
+
+    /// Runs the task without waiting for its interval, returning its
+    /// status before the run.
+    pub fn trigger(&self, name: &str) -> Option<TaskStatus> {
+        let task = self.tasks.lock().unwrap().get(name).cloned()?;
+        task.trigger.notify_one();
+        let status = task.status.lock().unwrap().clone();
+        Some(status)
+    }
+
+    /// Pauses or resumes the task, returning its status.
+    pub fn set_paused(&self, name: &str, paused: bool) -> Option<TaskStatus> {
+        let task = self.tasks.lock().unwrap().get(name).cloned()?;
+        let mut status = task.status.lock().unwrap();
+        status.paused = paused;
+        Some(status.clone())
+    }
+}
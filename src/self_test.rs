@@ -0,0 +1,311 @@
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client, StatusCode,
+};
+
+use snark_coordinator_rs::{
+    lock::{LockJobGranted, LockJobHeld},
+    stats::SnarkWorkerStatsPut,
+    worker_tokens,
+};
+
+type StepResult = Result<(), String>;
+
+struct SelfTest {
+    client: Client,
+    url: String,
+    worker_id: String,
+    lock_key: String,
+    /// Fencing token `lock_key` is held under.
+    fencing_token: Option<u64>,
+    /// Session token, if the coordinator hands them out.
+    worker_token: Option<String>,
+    /// `x-stats-version` of the last stats PUT, for reads to reflect it.
+    stats_version: u64,
+}
+
+/// Runs the scripted smoke test against a live coordinator at `url` and
+/// prints a pass/fail line per capability. Returns `true` if all passed.
+pub async fn run(url: String, api_key: Option<String>) -> bool {
+    let mut headers = HeaderMap::new();
+    if let Some(key) = api_key {
+        match HeaderValue::from_str(&format!("Bearer {key}")) {
+            Ok(value) => headers.insert(AUTHORIZATION, value),
+            Err(err) => {
+                eprintln!("invalid api key: {err}");
+                return false;
+            }
+        };
+    }
+    let client = match Client::builder().default_headers(headers).build() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("failed to build http client: {err}");
+            return false;
+        }
+    };
+    let nonce = now();
+    let mut test = SelfTest {
+        client,
+        url: url.trim_end_matches('/').to_owned(),
+        worker_id: format!("self-test-{nonce}"),
+        lock_key: format!("self-test-{nonce}"),
+        fencing_token: None,
+        worker_token: None,
+        stats_version: 0,
+    };
+
+    println!("self-test against {}", test.url);
+    let mut failed = 0;
+    macro_rules! step {
+        ($name:literal, $f:ident) => {{
+            let started = Instant::now();
+            let res = test.$f().await;
+            let elapsed = started.elapsed().as_millis();
+            match res {
+                Ok(()) => println!("PASS {:<16} ({elapsed}ms)", $name),
+                Err(err) => {
+                    failed += 1;
+                    println!("FAIL {:<16} ({elapsed}ms): {err}", $name);
+                }
+            }
+        }};
+    }
+
+    step!("register", register);
+    step!("lock", lock);
+    step!("renew", renew);
+    step!("stats-lifecycle", stats_lifecycle);
+    step!("queries", queries);
+    step!("release", release);
+
+    println!("{failed} failed");
+    failed == 0
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+fn expect_status(expected: StatusCode, found: StatusCode, body: &str) -> StepResult {
+    if expected == found {
+        Ok(())
+    } else {
+        Err(format!("expected status {expected}, found {found}: {body}"))
+    }
+}
+
+impl SelfTest {
+    async fn put_stats(
+        &mut self,
+        worker_id: &str,
+        req: SnarkWorkerStatsPut,
+    ) -> Result<String, String> {
+        let mut builder = self
+            .client
+            .put(format!("{}/worker-stats/{worker_id}", self.url))
+            .json(&req);
+        if let Some(token) = &self.worker_token {
+            builder = builder.header(worker_tokens::HEADER, token);
+        }
+        // only a `Register` is replied to once applied.
+        let expected = match req {
+            SnarkWorkerStatsPut::Register { .. } => StatusCode::OK,
+            _ => StatusCode::ACCEPTED,
+        };
+        let res = builder.send().await.map_err(|err| err.to_string())?;
+        let status = res.status();
+        if let Some(token) = res.headers().get(worker_tokens::HEADER) {
+            self.worker_token = token.to_str().ok().map(str::to_owned);
+        }
+        let version = res.headers().get("x-stats-version");
+        if let Some(version) = version.and_then(|v| v.to_str().ok()?.parse().ok()) {
+            self.stats_version = version;
+        }
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(expected, status, &body)?;
+        Ok(body)
+    }
+
+    async fn put_lock(&self, key: &str, worker_id: &str) -> Result<(StatusCode, String), String> {
+        let res = self
+            .client
+            .put(format!("{}/lock-job/{key}", self.url))
+            .query(&[("worker_id", worker_id), ("timeout", "30")])
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        Ok((status, body))
+    }
+
+    async fn get(&self, path: &str) -> Result<String, String> {
+        let res = self
+            .client
+            .get(format!("{}{path}", self.url))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(StatusCode::OK, status, &body)?;
+        Ok(body)
+    }
+
+    async fn register(&mut self) -> StepResult {
+        let name = self.worker_id.clone();
+        self.worker_id = self
+            .put_stats(
+                &name,
+                SnarkWorkerStatsPut::Register {
+                    time: now(),
+                    metadata: None,
+                    resume: None,
+                    seq: None,
+                },
+            )
+            .await?;
+        if !self.worker_id.starts_with(&name) {
+            return Err(format!("unexpected worker id: {}", self.worker_id));
+        }
+        Ok(())
+    }
+
+    async fn lock(&mut self) -> StepResult {
+        let (status, body) = self.put_lock(&self.lock_key, &self.worker_id).await?;
+        expect_status(StatusCode::CREATED, status, &body)?;
+        let granted: LockJobGranted = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        self.fencing_token = Some(granted.fencing_token);
+
+        let (status, body) = self.put_lock(&self.lock_key, "self-test-other").await?;
+        expect_status(StatusCode::OK, status, &body)?;
+        let held: LockJobHeld = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        if held.holder.as_deref() != Some(&self.worker_id) {
+            return Err(format!("unexpected lock holder: {:?}", held.holder));
+        }
+        Ok(())
+    }
+
+    /// Extends the lock under the fencing token it's held with.
+    async fn renew(&mut self) -> StepResult {
+        let fencing_token = self.fencing_token.ok_or("lock wasn't granted")?;
+        let res = self
+            .client
+            .put(format!("{}/lock-job/{}", self.url, self.lock_key))
+            .query(&[("worker_id", self.worker_id.as_str()), ("timeout", "30")])
+            .query(&[("bump", "true")])
+            .header("if-match", format!("\"{fencing_token}\""))
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        expect_status(StatusCode::CREATED, status, &body)?;
+        let granted: LockJobGranted = serde_json::from_str(&body).map_err(|err| err.to_string())?;
+        if granted.fencing_token != fencing_token {
+            return Err(format!(
+                "lock renewed under fencing token {}, held under {fencing_token}",
+                granted.fencing_token
+            ));
+        }
+        Ok(())
+    }
+
+    async fn stats_lifecycle(&mut self) -> StepResult {
+        let ids = format!("{}-job", self.lock_key);
+        let (status, body) = self.put_lock(&ids, &self.worker_id).await?;
+        expect_status(StatusCode::CREATED, status, &body)?;
+
+        let events = [
+            SnarkWorkerStatsPut::JobGetInit {
+                time: now(),
+                seq: None,
+            },
+            SnarkWorkerStatsPut::JobGetSuccess {
+                time: now(),
+                job_get_node_received_t: None,
+                job_get_node_request_work_init_t: None,
+                job_get_node_request_work_success_t: None,
+                ids: ids.clone(),
+                seq: None,
+            },
+            SnarkWorkerStatsPut::WorkCreateSuccess {
+                time: now(),
+                ids: ids.clone(),
+                seq: None,
+            },
+            SnarkWorkerStatsPut::WorkSubmitSuccess {
+                time: now(),
+                work_submit_node_received_t: None,
+                work_submit_node_add_work_init_t: None,
+                work_submit_node_add_work_success_t: None,
+                ids: ids.clone(),
+                seq: None,
+            },
+        ];
+        for event in events {
+            let worker_id = self.worker_id.clone();
+            self.put_stats(&worker_id, event).await?;
+        }
+        let path = format!(
+            "/worker-stats?workers={}&min_version={}",
+            self.worker_id, self.stats_version
+        );
+        self.get(&path).await?;
+
+        // the finished job's lock must have been released automatically.
+        let (status, body) = self.put_lock(&ids, &self.worker_id).await?;
+        expect_status(StatusCode::CREATED, status, &body)
+    }
+
+    async fn queries(&mut self) -> StepResult {
+        let workers = self.get("/workers").await?;
+        let workers: Vec<String> = serde_json::from_str(&workers).map_err(|err| err.to_string())?;
+        if !workers.contains(&self.worker_id) {
+            return Err(format!("{} missing from /workers", self.worker_id));
+        }
+
+        let stats = self
+            .get(&format!(
+                "/worker-stats?workers={}&min_version={}",
+                self.worker_id, self.stats_version
+            ))
+            .await?;
+        let stats: serde_json::Value =
+            serde_json::from_str(&stats).map_err(|err| err.to_string())?;
+        let kind = stats
+            .get(&self.worker_id)
+            .and_then(|states| states.get(0))
+            .and_then(|state| state.get("kind"))
+            .and_then(|kind| kind.as_str());
+        if kind != Some("WorkSubmitSuccess") {
+            return Err(format!("unexpected latest state kind: {kind:?}"));
+        }
+        Ok(())
+    }
+
+    async fn release(&mut self) -> StepResult {
+        let fencing_token = self.fencing_token.ok_or("lock wasn't granted")?;
+        let res = self
+            .client
+            .delete(format!("{}/lock-job/{}", self.url, self.lock_key))
+            .query(&[("fencing_token", fencing_token)])
+            .send()
+            .await
+            .map_err(|err| err.to_string())?;
+        let status = res.status();
+        let body = res.text().await.map_err(|err| err.to_string())?;
+        if !status.is_success() {
+            return Err(format!("expected success status, found {status}: {body}"));
+        }
+
+        let (status, body) = self.put_lock(&self.lock_key, &self.worker_id).await?;
+        expect_status(StatusCode::CREATED, status, &body)
+    }
+}
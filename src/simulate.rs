@@ -0,0 +1,428 @@
+//! Load generator for `simulate`: synthetic workers running job
+//! lifecycles and taking job locks against a live coordinator over its
+//! HTTP API, at a configurable rate and with configurable failure odds.
+//! Unlike `--synthetic-fleet`, events go through the network and the
+//! coordinator's validation, so every rejected event or unexpected
+//! status is reported as a failure.
+
+use std::{
+    collections::BTreeMap,
+    sync::Arc,
+    time::{Duration, Instant},
+};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use reqwest::{
+    header::{HeaderMap, HeaderValue, AUTHORIZATION},
+    Client, StatusCode,
+};
+use snark_coordinator_rs::{
+    stats::{SnarkWorkerJobGetError, SnarkWorkerStatsPut},
+    timestamp, worker_tokens,
+};
+use structopt::StructOpt;
+
+const JOB_CLASSES: &[&str] = &["base", "merge"];
+/// Other jobs a worker tries when the one it picked is locked, before
+/// reporting no available job.
+const LOCK_ATTEMPTS: usize = 3;
+/// TTL of the locks simulated workers take on their jobs.
+const LOCK_TIMEOUT_S: &str = "60";
+/// Failures printed in full, the rest are only counted.
+const MAX_PRINTED_FAILURES: usize = 10;
+
+// options of the load test. Not a doc comment, which would replace the
+// about of the subcommand flattening it.
+#[derive(Debug, StructOpt)]
+pub struct SimulateOpts {
+    #[structopt(long, default_value = "http://localhost:8080")]
+    url: String,
+    /// API key sent as `Authorization: Bearer <key>`.
+    #[structopt(long)]
+    api_key: Option<String>,
+    /// Number of simulated workers.
+    #[structopt(long, default_value = "10")]
+    workers: usize,
+    /// Seconds to run for. Lifecycles in progress at the end are
+    /// finished.
+    #[structopt(long, default_value = "60")]
+    duration: u64,
+    /// Lifecycles each worker starts per second, as fast as the
+    /// coordinator answers if 0.
+    #[structopt(long, default_value = "1")]
+    rate: f64,
+    /// Mean milliseconds a worker spends proving a job.
+    #[structopt(long, default_value = "200")]
+    work_ms: u64,
+    /// Number of jobs per job class workers pick from at random, fewer
+    /// making them contend for the job locks more.
+    #[structopt(long, default_value = "1000")]
+    jobs: usize,
+    /// Probability of a job get finding no job.
+    #[structopt(long, default_value = "0.1")]
+    no_job_probability: f64,
+    /// Probability of a job get failing.
+    #[structopt(long, default_value = "0.01")]
+    job_get_error_probability: f64,
+    /// Probability of proving a job failing.
+    #[structopt(long, default_value = "0.02")]
+    work_create_error_probability: f64,
+    /// Probability of the node rejecting a work.
+    #[structopt(long, default_value = "0.02")]
+    work_submit_error_probability: f64,
+    /// Seed of the workers' random choices.
+    #[structopt(long, default_value = "0")]
+    seed: u64,
+}
+
+/// Latencies and failures of one kind of request.
+#[derive(Debug, Default)]
+struct RequestTally {
+    latencies_us: Vec<u64>,
+    failed: u64,
+}
+
+/// What a simulated worker did, merged into the report at the end.
+#[derive(Debug, Default)]
+struct Tally {
+    requests: BTreeMap<&'static str, RequestTally>,
+    /// Lifecycles by the state they ended in.
+    lifecycles: BTreeMap<&'static str, u64>,
+    lock_conflicts: u64,
+    failures: Vec<String>,
+    failed: u64,
+}
+
+impl Tally {
+    fn fail(&mut self, err: String) {
+        self.failed += 1;
+        if self.failures.len() < MAX_PRINTED_FAILURES {
+            self.failures.push(err);
+        }
+    }
+
+    fn merge(&mut self, other: Tally) {
+        for (name, tally) in other.requests {
+            let sum = self.requests.entry(name).or_default();
+            sum.latencies_us.extend(tally.latencies_us);
+            sum.failed += tally.failed;
+        }
+        for (kind, count) in other.lifecycles {
+            *self.lifecycles.entry(kind).or_default() += count;
+        }
+        self.lock_conflicts += other.lock_conflicts;
+        self.failed += other.failed;
+        let room = MAX_PRINTED_FAILURES.saturating_sub(self.failures.len());
+        self.failures.extend(other.failures.into_iter().take(room));
+    }
+}
+
+struct Worker {
+    client: Client,
+    url: String,
+    worker_id: String,
+    /// Session token, if the coordinator hands them out.
+    worker_token: Option<String>,
+    rng: StdRng,
+    tally: Tally,
+}
+
+/// Runs the load test and prints its report. Returns `true` if the
+/// coordinator accepted every event and answered every request as
+/// expected.
+pub async fn run(opts: SimulateOpts) -> bool {
+    let mut headers = HeaderMap::new();
+    if let Some(key) = &opts.api_key {
+        match HeaderValue::from_str(&format!("Bearer {key}")) {
+            Ok(value) => headers.insert(AUTHORIZATION, value),
+            Err(err) => {
+                eprintln!("invalid api key: {err}");
+                return false;
+            }
+        };
+    }
+    let client = match Client::builder().default_headers(headers).build() {
+        Ok(client) => client,
+        Err(err) => {
+            eprintln!("failed to build http client: {err}");
+            return false;
+        }
+    };
+    let url = opts.url.trim_end_matches('/').to_owned();
+    println!(
+        "simulating {} workers for {}s against {url}",
+        opts.workers, opts.duration
+    );
+
+    let started = Instant::now();
+    let deadline = started + Duration::from_secs(opts.duration);
+    let opts = Arc::new(opts);
+    let handles = (0..opts.workers)
+        .map(|i| {
+            let worker = Worker {
+                client: client.clone(),
+                url: url.clone(),
+                worker_id: format!("simulate-{i}"),
+                worker_token: None,
+                rng: StdRng::seed_from_u64(opts.seed.wrapping_add(i as u64)),
+                tally: Tally::default(),
+            };
+            tokio::spawn(worker.run(opts.clone(), deadline))
+        })
+        .collect::<Vec<_>>();
+    let mut tally = Tally::default();
+    for handle in handles {
+        match handle.await {
+            Ok(worker) => tally.merge(worker),
+            Err(err) => tally.fail(format!("worker panicked: {err}")),
+        }
+    }
+
+    let elapsed = started.elapsed().as_secs_f64();
+    let lifecycles = tally.lifecycles.values().sum::<u64>();
+    println!(
+        "{lifecycles} lifecycles in {elapsed:.1}s ({:.1}/s), {} lock conflicts",
+        lifecycles as f64 / elapsed,
+        tally.lock_conflicts
+    );
+    for (kind, count) in &tally.lifecycles {
+        println!("  {kind:<18} {count}");
+    }
+    for (name, requests) in &mut tally.requests {
+        requests.latencies_us.sort_unstable();
+        let len = requests.latencies_us.len();
+        let quantile = |q: f64| {
+            let i = ((len as f64 * q).ceil() as usize).clamp(1, len.max(1)) - 1;
+            requests.latencies_us.get(i).copied().unwrap_or(0) as f64 / 1000.0
+        };
+        println!(
+            "{name:<12} {len} requests ({:.1}/s), {} failed, p50 {:.1}ms, p99 {:.1}ms, max {:.1}ms",
+            len as f64 / elapsed,
+            requests.failed,
+            quantile(0.5),
+            quantile(0.99),
+            quantile(1.0),
+        );
+    }
+    for failure in &tally.failures {
+        println!("FAIL {failure}");
+    }
+    println!("{} failed", tally.failed);
+    tally.failed == 0
+}
+
+impl Worker {
+    /// Sends a request, counting its latency under `name`. Returns the
+    /// status and body, or `None` if it didn't get through.
+    async fn send(
+        &mut self,
+        name: &'static str,
+        req: reqwest::RequestBuilder,
+    ) -> Option<(StatusCode, String)> {
+        let started = Instant::now();
+        let res = match req.send().await {
+            Ok(res) => res,
+            Err(err) => {
+                self.tally.requests.entry(name).or_default().failed += 1;
+                self.tally.fail(format!("{name}: {err}"));
+                return None;
+            }
+        };
+        let status = res.status();
+        if let Some(token) = res.headers().get(worker_tokens::HEADER) {
+            self.worker_token = token.to_str().ok().map(str::to_owned);
+        }
+        let body = res.text().await.unwrap_or_default();
+        let latency_us = started.elapsed().as_micros() as u64;
+        let tally = self.tally.requests.entry(name).or_default();
+        tally.latencies_us.push(latency_us);
+        Some((status, body))
+    }
+
+    /// Sends a stats event, returning the response body if it was
+    /// accepted.
+    async fn put_stats(&mut self, req: SnarkWorkerStatsPut) -> Option<String> {
+        let kind = req.kind();
+        let mut builder = self
+            .client
+            .put(format!("{}/worker-stats/{}", self.url, self.worker_id))
+            .json(&req);
+        if let Some(token) = &self.worker_token {
+            builder = builder.header(worker_tokens::HEADER, token);
+        }
+        let (status, body) = self.send("worker-stats", builder).await?;
+        // events but a `Register` are accepted once queued.
+        if !status.is_success() {
+            self.tally
+                .requests
+                .entry("worker-stats")
+                .or_default()
+                .failed += 1;
+            let worker_id = &self.worker_id;
+            self.tally
+                .fail(format!("{worker_id} {kind} rejected with {status}: {body}"));
+            return None;
+        }
+        Some(body)
+    }
+
+    /// Tries to lock `key`, returning whether it was granted.
+    async fn lock(&mut self, key: &str) -> Option<bool> {
+        let builder = self
+            .client
+            .put(format!("{}/lock-job/{key}", self.url))
+            .query(&[
+                ("worker_id", self.worker_id.as_str()),
+                ("timeout", LOCK_TIMEOUT_S),
+            ]);
+        let (status, body) = self.send("lock-job", builder).await?;
+        match status {
+            StatusCode::CREATED => Some(true),
+            StatusCode::OK => {
+                self.tally.lock_conflicts += 1;
+                Some(false)
+            }
+            status => {
+                self.tally.requests.entry("lock-job").or_default().failed += 1;
+                self.tally
+                    .fail(format!("lock-job {key} answered {status}: {body}"));
+                None
+            }
+        }
+    }
+
+    fn roll(&mut self, probability: f64) -> bool {
+        self.rng.gen_bool(probability.clamp(0.0, 1.0))
+    }
+
+    /// Runs lifecycles until `deadline`, returning what happened.
+    async fn run(mut self, opts: Arc<SimulateOpts>, deadline: Instant) -> Tally {
+        let register = SnarkWorkerStatsPut::Register {
+            time: timestamp::now(),
+            metadata: None,
+            resume: None,
+            seq: None,
+        };
+        match self.put_stats(register).await {
+            Some(session_id) => self.worker_id = session_id,
+            None => return self.tally,
+        }
+        let interval = (opts.rate > 0.0).then(|| Duration::from_secs_f64(1.0 / opts.rate));
+        // spread the workers' first lifecycles over the interval.
+        let mut next_at =
+            Instant::now() + interval.map_or(Duration::ZERO, |i| i.mul_f64(self.rng.gen()));
+        while next_at < deadline {
+            tokio::time::sleep_until(next_at.into()).await;
+            let outcome = self.lifecycle(&opts).await;
+            let Some(outcome) = outcome else {
+                // the coordinator is failing, no point hammering it.
+                break;
+            };
+            *self.tally.lifecycles.entry(outcome).or_default() += 1;
+            next_at = match interval {
+                Some(interval) => (next_at + interval).max(Instant::now()),
+                None => Instant::now(),
+            };
+        }
+        self.tally
+    }
+
+    /// Runs a job lifecycle, returning the kind of state it ended in, or
+    /// `None` if the coordinator didn't answer as expected.
+    async fn lifecycle(&mut self, opts: &SimulateOpts) -> Option<&'static str> {
+        let init = SnarkWorkerStatsPut::JobGetInit {
+            time: timestamp::now(),
+            seq: None,
+        };
+        self.put_stats(init).await?;
+
+        let mut ids = None;
+        let no_job = self.roll(opts.no_job_probability);
+        let job_get_failed = !no_job && self.roll(opts.job_get_error_probability);
+        if !no_job && !job_get_failed {
+            for _ in 0..LOCK_ATTEMPTS {
+                let class = JOB_CLASSES[self.rng.gen_range(0..JOB_CLASSES.len())];
+                let key = format!("{class}:{}", self.rng.gen_range(0..opts.jobs.max(1)));
+                if self.lock(&key).await? {
+                    ids = Some(key);
+                    break;
+                }
+            }
+        }
+        let Some(ids) = ids else {
+            let error = match job_get_failed {
+                true => SnarkWorkerJobGetError::Other {
+                    error: "node request timed out".to_owned(),
+                },
+                false => SnarkWorkerJobGetError::NoAvailableJob,
+            };
+            let req = SnarkWorkerStatsPut::JobGetError {
+                time: timestamp::now(),
+                job_get_node_received_t: None,
+                job_get_node_request_work_init_t: None,
+                job_get_node_request_work_success_t: None,
+                error,
+                seq: None,
+            };
+            self.put_stats(req).await?;
+            return Some("JobGetError");
+        };
+        let req = SnarkWorkerStatsPut::JobGetSuccess {
+            time: timestamp::now(),
+            job_get_node_received_t: None,
+            job_get_node_request_work_init_t: None,
+            job_get_node_request_work_success_t: None,
+            ids: ids.clone(),
+            seq: None,
+        };
+        self.put_stats(req).await?;
+
+        let work_ms = self.rng.gen_range(0..=2 * opts.work_ms);
+        tokio::time::sleep(Duration::from_millis(work_ms)).await;
+        if self.roll(opts.work_create_error_probability) {
+            let req = SnarkWorkerStatsPut::WorkCreateError {
+                time: timestamp::now(),
+                ids,
+                error: "prover exited unexpectedly".to_owned(),
+                seq: None,
+            };
+            self.put_stats(req).await?;
+            return Some("WorkCreateError");
+        }
+        let req = SnarkWorkerStatsPut::WorkCreateSuccess {
+            time: timestamp::now(),
+            ids: ids.clone(),
+            seq: None,
+        };
+        self.put_stats(req).await?;
+
+        let time = timestamp::now();
+        let (req, outcome) = match self.roll(opts.work_submit_error_probability) {
+            true => {
+                let req = SnarkWorkerStatsPut::WorkSubmitError {
+                    time,
+                    work_submit_node_received_t: None,
+                    work_submit_node_add_work_init_t: None,
+                    work_submit_node_add_work_success_t: None,
+                    ids,
+                    error: "snark pool rejected work".to_owned(),
+                    seq: None,
+                };
+                (req, "WorkSubmitError")
+            }
+            false => {
+                let req = SnarkWorkerStatsPut::WorkSubmitSuccess {
+                    time,
+                    work_submit_node_received_t: None,
+                    work_submit_node_add_work_init_t: None,
+                    work_submit_node_add_work_success_t: None,
+                    ids,
+                    seq: None,
+                };
+                (req, "WorkSubmitSuccess")
+            }
+        };
+        self.put_stats(req).await?;
+        Some(outcome)
+    }
+}
@@ -0,0 +1,1994 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, VecDeque},
+    fmt,
+    ops::{Bound, Deref},
+    str::FromStr,
+};
+
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::{
+    compaction::HourlyAggregates,
+    errors::{ApiError, ErrorCode},
+    strict::Violation,
+    timestamp,
+};
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+#[serde(tag = "kind")]
+pub enum SnarkWorkerJobGetError {
+    NoAvailableJob,
+    Other { error: String },
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum SnarkWorkerStatsPut {
+    Register {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<WorkerMetadata>,
+        /// Session id the worker had before restarting. Its history is
+        /// continued instead of starting a new session, if it still exists.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        resume: Option<String>,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    JobGetInit {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    JobGetError {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_received_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_request_work_init_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_request_work_success_t: Option<u64>,
+        error: SnarkWorkerJobGetError,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    JobGetSuccess {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_received_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_request_work_init_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        job_get_node_request_work_success_t: Option<u64>,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    WorkCreateError {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        ids: String,
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    WorkCreateSuccess {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    WorkSubmitError {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_received_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_add_work_init_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_add_work_success_t: Option<u64>,
+        ids: String,
+        error: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+    WorkSubmitSuccess {
+        #[serde(deserialize_with = "timestamp::deserialize")]
+        time: u64,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_received_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_add_work_init_t: Option<u64>,
+        #[serde(default, deserialize_with = "timestamp::option::deserialize")]
+        work_submit_node_add_work_success_t: Option<u64>,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        seq: Option<u64>,
+    },
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone)]
+#[serde(tag = "kind")]
+pub enum SnarkWorkerState {
+    Registered {
+        registered_t: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<WorkerMetadata>,
+    },
+    /// The worker restarted and resumed its session, abandoning the job
+    /// lifecycle it was in.
+    Restarted {
+        restarted_t: u64,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        metadata: Option<WorkerMetadata>,
+    },
+    JobGetPending {
+        job_get_init_t: u64,
+    },
+    // TODO(binier): add separate `SnarkWorkerStatsPut` for it.
+    JobUnavailable {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+    },
+    JobGetError {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_error_t: u64,
+        error: SnarkWorkerJobGetError,
+        /// Number of identical errors in a row collapsed into this state
+        /// with `--collapse-errors`, `None` for a single one.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+        /// When the last of the collapsed errors happened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen_t: Option<u64>,
+    },
+    WorkCreatePending {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+    WorkCreateError {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        work_create_error_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+        error: String,
+        /// Number of identical errors in a row collapsed into this state
+        /// see `JobGetError`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+        /// When the last of the collapsed errors happened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen_t: Option<u64>,
+    },
+    WorkSubmitPending {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        work_create_success_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+    WorkSubmitError {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        work_create_success_t: u64,
+        work_submit_error_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+        error: String,
+        /// Number of identical errors in a row collapsed into this state
+        /// see `JobGetError`.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        count: Option<u64>,
+        /// When the last of the collapsed errors happened.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        last_seen_t: Option<u64>,
+    },
+    /// Job request pending for longer than `--pending-timeout`.
+    JobGetTimeout {
+        job_get_init_t: u64,
+        timed_out_t: u64,
+    },
+    /// Work creation pending for longer than `--pending-timeout`, e.g.
+    /// because the worker died mid-proof.
+    WorkCreateTimeout {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        timed_out_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+    /// Work submission pending for longer than `--pending-timeout`.
+    WorkSubmitTimeout {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        work_create_success_t: u64,
+        timed_out_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+    WorkSubmitSuccess {
+        job_get_init_t: u64,
+        job_get_node_received_t: Option<u64>,
+        job_get_node_request_work_init_t: Option<u64>,
+        job_get_node_request_work_success_t: Option<u64>,
+        job_get_success_t: u64,
+        work_create_success_t: u64,
+        work_submit_node_received_t: Option<u64>,
+        work_submit_node_add_work_init_t: Option<u64>,
+        work_submit_node_add_work_success_t: Option<u64>,
+        work_submit_success_t: u64,
+        ids: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        lease: Option<Lease>,
+    },
+}
+
+/// What a worker tells about itself when registering, to tell workers
+/// apart when debugging the fleet.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Default, PartialEq, Eq)]
+pub struct WorkerMetadata {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hostname: Option<String>,
+    /// Datacenter or region the worker runs in, grouping it with others
+    /// sharing its failure domain.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub datacenter: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub prover_version: Option<String>,
+    /// Threads the prover runs with.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub threads: Option<u32>,
+    /// Fee the worker charges per work, in nanomina.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub fee: Option<u64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub gpu: Option<bool>,
+}
+
+/// Lock lease a job lifecycle ran under.
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Lease {
+    pub fencing_token: u64,
+    /// Worker id given when acquiring the lock.
+    pub holder: Option<String>,
+    pub expires_t: u64,
+    /// Key the lock was taken under, qualified with its lock namespace.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+impl SnarkWorkerStatsPut {
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Register { .. } => "Register",
+            Self::JobGetInit { .. } => "JobGetInit",
+            Self::JobGetError { .. } => "JobGetError",
+            Self::JobGetSuccess { .. } => "JobGetSuccess",
+            Self::WorkCreateError { .. } => "WorkCreateError",
+            Self::WorkCreateSuccess { .. } => "WorkCreateSuccess",
+            Self::WorkSubmitError { .. } => "WorkSubmitError",
+            Self::WorkSubmitSuccess { .. } => "WorkSubmitSuccess",
+        }
+    }
+
+    /// When the event happened, by the worker's clock.
+    pub fn time(&self) -> u64 {
+        match self {
+            Self::Register { time, .. }
+            | Self::JobGetInit { time, .. }
+            | Self::JobGetError { time, .. }
+            | Self::JobGetSuccess { time, .. }
+            | Self::WorkCreateError { time, .. }
+            | Self::WorkCreateSuccess { time, .. }
+            | Self::WorkSubmitError { time, .. }
+            | Self::WorkSubmitSuccess { time, .. } => *time,
+        }
+    }
+
+    pub fn time_mut(&mut self) -> &mut u64 {
+        match self {
+            Self::Register { time, .. }
+            | Self::JobGetInit { time, .. }
+            | Self::JobGetError { time, .. }
+            | Self::JobGetSuccess { time, .. }
+            | Self::WorkCreateError { time, .. }
+            | Self::WorkCreateSuccess { time, .. }
+            | Self::WorkSubmitError { time, .. }
+            | Self::WorkSubmitSuccess { time, .. } => time,
+        }
+    }
+
+    /// Position of the event among those the worker's session sent, if
+    /// it numbers them. `Register` starts the numbering over.
+    pub fn seq(&self) -> Option<u64> {
+        match self {
+            Self::Register { seq, .. }
+            | Self::JobGetInit { seq, .. }
+            | Self::JobGetError { seq, .. }
+            | Self::JobGetSuccess { seq, .. }
+            | Self::WorkCreateError { seq, .. }
+            | Self::WorkCreateSuccess { seq, .. }
+            | Self::WorkSubmitError { seq, .. }
+            | Self::WorkSubmitSuccess { seq, .. } => *seq,
+        }
+    }
+
+    /// Ids of the job this event refers to.
+    pub fn ids(&self) -> Option<&str> {
+        match self {
+            Self::Register { .. } | Self::JobGetInit { .. } | Self::JobGetError { .. } => None,
+            Self::JobGetSuccess { ids, .. }
+            | Self::WorkCreateError { ids, .. }
+            | Self::WorkCreateSuccess { ids, .. }
+            | Self::WorkSubmitError { ids, .. }
+            | Self::WorkSubmitSuccess { ids, .. } => Some(ids),
+        }
+    }
+
+    /// Shortens `ids` and error messages longer than `max_len` bytes, so
+    /// a single oversized event can't bloat the state it's stored in.
+    /// Returns whether anything was truncated.
+    pub fn truncate(&mut self, max_len: usize) -> bool {
+        let (ids, error) = match self {
+            Self::Register { metadata, .. } => {
+                let Some(metadata) = metadata else {
+                    return false;
+                };
+                let hostname = metadata
+                    .hostname
+                    .as_mut()
+                    .is_some_and(|s| truncate(s, max_len));
+                let datacenter =
+                    (metadata.datacenter.as_mut()).is_some_and(|s| truncate(s, max_len));
+                let version =
+                    (metadata.prover_version.as_mut()).is_some_and(|s| truncate(s, max_len));
+                return hostname || datacenter || version;
+            }
+            Self::JobGetInit { .. } => (None, None),
+            Self::JobGetError { error, .. } => match error {
+                SnarkWorkerJobGetError::Other { error } => (None, Some(error)),
+                SnarkWorkerJobGetError::NoAvailableJob => (None, None),
+            },
+            Self::JobGetSuccess { ids, .. }
+            | Self::WorkCreateSuccess { ids, .. }
+            | Self::WorkSubmitSuccess { ids, .. } => (Some(ids), None),
+            Self::WorkCreateError { ids, error, .. } | Self::WorkSubmitError { ids, error, .. } => {
+                (Some(ids), Some(error))
+            }
+        };
+        let ids = ids.is_some_and(|s| truncate(s, max_len));
+        let error = error.is_some_and(|s| truncate(s, max_len));
+        ids || error
+    }
+
+    /// Ids of the job whose lifecycle is terminated by this event.
+    pub fn terminal_ids(&self) -> Option<&str> {
+        match self {
+            Self::WorkCreateError { ids, .. }
+            | Self::WorkSubmitError { ids, .. }
+            | Self::WorkSubmitSuccess { ids, .. } => Some(ids),
+            _ => None,
+        }
+    }
+}
+
+impl SnarkWorkerState {
+    /// Serialized `kind`s of all states.
+    pub const KINDS: &'static [&'static str] = &[
+        "Registered",
+        "Restarted",
+        "JobGetPending",
+        "JobUnavailable",
+        "JobGetError",
+        "WorkCreatePending",
+        "WorkCreateError",
+        "WorkSubmitPending",
+        "WorkSubmitError",
+        "JobGetTimeout",
+        "WorkCreateTimeout",
+        "WorkSubmitTimeout",
+        "WorkSubmitSuccess",
+    ];
+
+    pub fn init(time: u64) -> Self {
+        Self::JobGetPending {
+            job_get_init_t: time,
+        }
+    }
+
+    pub fn kind(&self) -> &'static str {
+        match self {
+            Self::Registered { .. } => "Registered",
+            Self::Restarted { .. } => "Restarted",
+            Self::JobGetPending { .. } => "JobGetPending",
+            Self::JobUnavailable { .. } => "JobUnavailable",
+            Self::JobGetError { .. } => "JobGetError",
+            Self::WorkCreatePending { .. } => "WorkCreatePending",
+            Self::WorkCreateError { .. } => "WorkCreateError",
+            Self::WorkSubmitPending { .. } => "WorkSubmitPending",
+            Self::WorkSubmitError { .. } => "WorkSubmitError",
+            Self::JobGetTimeout { .. } => "JobGetTimeout",
+            Self::WorkCreateTimeout { .. } => "WorkCreateTimeout",
+            Self::WorkSubmitTimeout { .. } => "WorkSubmitTimeout",
+            Self::WorkSubmitSuccess { .. } => "WorkSubmitSuccess",
+        }
+    }
+
+    pub fn start_time(&self) -> u64 {
+        match self {
+            Self::Registered { registered_t, .. } => *registered_t,
+            Self::Restarted { restarted_t, .. } => *restarted_t,
+            Self::JobGetPending { job_get_init_t }
+            | Self::JobUnavailable { job_get_init_t, .. }
+            | Self::JobGetError { job_get_init_t, .. }
+            | Self::WorkCreatePending { job_get_init_t, .. }
+            | Self::WorkCreateError { job_get_init_t, .. }
+            | Self::WorkSubmitPending { job_get_init_t, .. }
+            | Self::WorkSubmitError { job_get_init_t, .. }
+            | Self::JobGetTimeout { job_get_init_t, .. }
+            | Self::WorkCreateTimeout { job_get_init_t, .. }
+            | Self::WorkSubmitTimeout { job_get_init_t, .. }
+            | Self::WorkSubmitSuccess { job_get_init_t, .. } => *job_get_init_t,
+        }
+    }
+
+    /// Ids of the job this state refers to, if a job was received.
+    pub fn ids(&self) -> Option<&str> {
+        match self {
+            Self::Registered { .. }
+            | Self::Restarted { .. }
+            | Self::JobGetPending { .. }
+            | Self::JobUnavailable { .. }
+            | Self::JobGetError { .. }
+            | Self::JobGetTimeout { .. } => None,
+            Self::WorkCreatePending { ids, .. }
+            | Self::WorkCreateError { ids, .. }
+            | Self::WorkSubmitPending { ids, .. }
+            | Self::WorkSubmitError { ids, .. }
+            | Self::WorkCreateTimeout { ids, .. }
+            | Self::WorkSubmitTimeout { ids, .. }
+            | Self::WorkSubmitSuccess { ids, .. } => Some(ids),
+        }
+    }
+
+    /// Phase which ended by entering this state and its duration in ms.
+    pub fn completed_phase(&self) -> Option<(&'static str, u64)> {
+        let (phase, start_t) = match self {
+            Self::Registered { .. }
+            | Self::Restarted { .. }
+            | Self::JobGetPending { .. }
+            | Self::JobGetTimeout { .. }
+            | Self::WorkCreateTimeout { .. }
+            | Self::WorkSubmitTimeout { .. } => return None,
+            Self::JobUnavailable { job_get_init_t, .. }
+            | Self::JobGetError { job_get_init_t, .. }
+            | Self::WorkCreatePending { job_get_init_t, .. } => ("job_get", job_get_init_t),
+            Self::WorkCreateError {
+                job_get_success_t, ..
+            }
+            | Self::WorkSubmitPending {
+                job_get_success_t, ..
+            } => ("work_create", job_get_success_t),
+            Self::WorkSubmitError {
+                work_create_success_t,
+                ..
+            }
+            | Self::WorkSubmitSuccess {
+                work_create_success_t,
+                ..
+            } => ("work_submit", work_create_success_t),
+        };
+        Some((phase, self.end_time().saturating_sub(*start_t)))
+    }
+
+    /// Time from receiving the job until the work was submitted, in ms.
+    /// That's how long the job's lock needs to be held.
+    pub fn job_duration(&self) -> Option<u64> {
+        match self {
+            Self::WorkSubmitSuccess {
+                job_get_success_t,
+                work_submit_success_t,
+                ..
+            } => Some(work_submit_success_t.saturating_sub(*job_get_success_t)),
+            _ => None,
+        }
+    }
+
+    /// Metadata the worker registered or resumed with.
+    pub fn metadata(&self) -> Option<&WorkerMetadata> {
+        match self {
+            Self::Registered { metadata, .. } | Self::Restarted { metadata, .. } => {
+                metadata.as_ref()
+            }
+            _ => None,
+        }
+    }
+
+    pub fn lease(&self) -> Option<&Lease> {
+        match self {
+            Self::WorkCreatePending { lease, .. }
+            | Self::WorkCreateError { lease, .. }
+            | Self::WorkSubmitPending { lease, .. }
+            | Self::WorkSubmitError { lease, .. }
+            | Self::WorkCreateTimeout { lease, .. }
+            | Self::WorkSubmitTimeout { lease, .. }
+            | Self::WorkSubmitSuccess { lease, .. } => lease.as_ref(),
+            _ => None,
+        }
+    }
+
+    /// Records the lease of the job this lifecycle received.
+    pub fn set_lease(&mut self, new_lease: Lease) {
+        if let Self::WorkCreatePending { lease, .. } = self {
+            *lease = Some(new_lease);
+        }
+    }
+
+    /// Whether the lock lease expired before the work got submitted.
+    pub fn lease_expired_before_submit(&self) -> bool {
+        let submit_t = match self {
+            Self::WorkSubmitError {
+                work_submit_error_t,
+                ..
+            } => *work_submit_error_t,
+            Self::WorkSubmitSuccess {
+                work_submit_success_t,
+                ..
+            } => *work_submit_success_t,
+            _ => return false,
+        };
+        self.lease().is_some_and(|lease| lease.expires_t < submit_t)
+    }
+
+    /// Number of errors this state stands for, more than one if identical
+    /// errors were collapsed into it.
+    pub fn count(&self) -> u64 {
+        match self {
+            Self::JobGetError { count, .. }
+            | Self::WorkCreateError { count, .. }
+            | Self::WorkSubmitError { count, .. } => count.unwrap_or(1),
+            _ => 1,
+        }
+    }
+
+    /// When the last error collapsed into this state happened.
+    pub fn last_seen_t(&self) -> Option<u64> {
+        match self {
+            Self::JobGetError { last_seen_t, .. }
+            | Self::WorkCreateError { last_seen_t, .. }
+            | Self::WorkSubmitError { last_seen_t, .. } => *last_seen_t,
+            _ => None,
+        }
+    }
+
+    /// Collapses `newer` into this state if both are the same error,
+    /// returns whether it did.
+    fn collapse(&mut self, newer: &Self) -> bool {
+        let same = match (&*self, newer) {
+            (Self::JobGetError { error: a, .. }, Self::JobGetError { error: b, .. }) => a == b,
+            (
+                Self::WorkCreateError {
+                    ids: a_ids,
+                    error: a,
+                    ..
+                },
+                Self::WorkCreateError {
+                    ids: b_ids,
+                    error: b,
+                    ..
+                },
+            )
+            | (
+                Self::WorkSubmitError {
+                    ids: a_ids,
+                    error: a,
+                    ..
+                },
+                Self::WorkSubmitError {
+                    ids: b_ids,
+                    error: b,
+                    ..
+                },
+            ) => a_ids == b_ids && a == b,
+            _ => false,
+        };
+        if !same {
+            return false;
+        }
+        let new_count = self.count() + newer.count();
+        let new_last_seen_t = newer.last_seen_t().unwrap_or(newer.end_time());
+        match self {
+            Self::JobGetError {
+                count, last_seen_t, ..
+            }
+            | Self::WorkCreateError {
+                count, last_seen_t, ..
+            }
+            | Self::WorkSubmitError {
+                count, last_seen_t, ..
+            } => {
+                *count = Some(new_count);
+                *last_seen_t = Some(new_last_seen_t);
+            }
+            _ => {}
+        }
+        true
+    }
+
+    pub fn end_time(&self) -> u64 {
+        match self {
+            Self::Registered { registered_t, .. } => *registered_t,
+            Self::Restarted { restarted_t, .. } => *restarted_t,
+            Self::JobGetPending { job_get_init_t } => *job_get_init_t,
+            Self::JobUnavailable {
+                job_get_success_t, ..
+            } => *job_get_success_t,
+            Self::JobGetError {
+                job_get_error_t, ..
+            } => *job_get_error_t,
+            Self::WorkCreatePending {
+                job_get_success_t, ..
+            } => *job_get_success_t,
+            Self::WorkCreateError {
+                work_create_error_t,
+                ..
+            } => *work_create_error_t,
+            Self::WorkSubmitPending {
+                work_create_success_t,
+                ..
+            } => *work_create_success_t,
+            Self::WorkSubmitError {
+                work_submit_error_t,
+                ..
+            } => *work_submit_error_t,
+            Self::JobGetTimeout { timed_out_t, .. }
+            | Self::WorkCreateTimeout { timed_out_t, .. }
+            | Self::WorkSubmitTimeout { timed_out_t, .. } => *timed_out_t,
+            Self::WorkSubmitSuccess {
+                work_submit_success_t,
+                ..
+            } => *work_submit_success_t,
+        }
+    }
+
+    /// Moves a pending state into its timeout state at `t`, returns
+    /// whether it was pending.
+    pub fn time_out(&mut self, t: u64) -> bool {
+        *self = match std::mem::take(self) {
+            Self::JobGetPending { job_get_init_t } => Self::JobGetTimeout {
+                job_get_init_t,
+                timed_out_t: t,
+            },
+            Self::WorkCreatePending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                ids,
+                lease,
+            } => Self::WorkCreateTimeout {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                timed_out_t: t,
+                ids,
+                lease,
+            },
+            Self::WorkSubmitPending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                ids,
+                lease,
+            } => Self::WorkSubmitTimeout {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                timed_out_t: t,
+                ids,
+                lease,
+            },
+            state => {
+                *self = state;
+                return false;
+            }
+        };
+        true
+    }
+
+    /// Applies `v` to the state in place. If `v` isn't a valid transition
+    /// from the current state, the state is left untouched and `v` is
+    /// handed back.
+    #[allow(clippy::result_large_err)]
+    pub fn apply(&mut self, v: SnarkWorkerStatsPut) -> Result<(), SnarkWorkerStatsPut> {
+        *self = match (std::mem::take(self), v) {
+            (
+                Self::JobGetPending { job_get_init_t },
+                SnarkWorkerStatsPut::JobGetError {
+                    time,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    error,
+                    ..
+                },
+            ) => match error {
+                SnarkWorkerJobGetError::NoAvailableJob => Self::JobUnavailable {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_success_t: time,
+                },
+                error => Self::JobGetError {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_error_t: time,
+                    error,
+                    count: None,
+                    last_seen_t: None,
+                },
+            },
+            (
+                Self::JobGetPending { job_get_init_t },
+                SnarkWorkerStatsPut::JobGetSuccess {
+                    time,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    ids,
+                    ..
+                },
+            ) => Self::WorkCreatePending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t: time,
+                ids,
+                lease: None,
+            },
+            (
+                Self::WorkCreatePending {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_success_t,
+                    ids: expected_ids,
+                    lease,
+                },
+                SnarkWorkerStatsPut::WorkCreateError {
+                    time, error, ids, ..
+                },
+            ) if ids == expected_ids => Self::WorkCreateError {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_error_t: time,
+                ids,
+                error,
+                lease,
+                count: None,
+                last_seen_t: None,
+            },
+            (
+                Self::WorkCreatePending {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_success_t,
+                    ids: expected_ids,
+                    lease,
+                },
+                SnarkWorkerStatsPut::WorkCreateSuccess { time, ids, .. },
+            ) if ids == expected_ids => Self::WorkSubmitPending {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t: time,
+                ids,
+                lease,
+            },
+            (
+                Self::WorkSubmitPending {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_success_t,
+                    work_create_success_t,
+                    ids: expected_ids,
+                    lease,
+                },
+                SnarkWorkerStatsPut::WorkSubmitError {
+                    time, error, ids, ..
+                },
+            ) if ids == expected_ids => Self::WorkSubmitError {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                work_submit_error_t: time,
+                ids,
+                error,
+                lease,
+                count: None,
+                last_seen_t: None,
+            },
+            (
+                Self::WorkSubmitPending {
+                    job_get_init_t,
+                    job_get_node_received_t,
+                    job_get_node_request_work_init_t,
+                    job_get_node_request_work_success_t,
+                    job_get_success_t,
+                    work_create_success_t,
+                    ids: expected_ids,
+                    lease,
+                },
+                SnarkWorkerStatsPut::WorkSubmitSuccess {
+                    time,
+                    work_submit_node_received_t,
+                    work_submit_node_add_work_init_t,
+                    work_submit_node_add_work_success_t,
+                    ids,
+                    ..
+                },
+            ) if ids == expected_ids => Self::WorkSubmitSuccess {
+                job_get_init_t,
+                job_get_node_received_t,
+                job_get_node_request_work_init_t,
+                job_get_node_request_work_success_t,
+                job_get_success_t,
+                work_create_success_t,
+                work_submit_node_received_t,
+                work_submit_node_add_work_init_t,
+                work_submit_node_add_work_success_t,
+                work_submit_success_t: time,
+                ids,
+                lease,
+            },
+            (state, v) => {
+                *self = state;
+                return Err(v);
+            }
+        };
+
+        Ok(())
+    }
+}
+
+/// Truncates `s` to at most `max_len` bytes, marking it with a trailing
+/// ellipsis. Since truncation is deterministic, truncated `ids` of the
+/// same job still match.
+fn truncate(s: &mut String, max_len: usize) -> bool {
+    const MARKER: &str = "…";
+    if s.len() <= max_len {
+        return false;
+    }
+    let mut end = max_len.saturating_sub(MARKER.len());
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    s.truncate(end);
+    s.push_str(MARKER);
+    true
+}
+
+impl Default for SnarkWorkerState {
+    fn default() -> Self {
+        Self::JobGetPending { job_get_init_t: 0 }
+    }
+}
+
+/// A worker-stats event applied to a worker, as streamed to live
+/// subscribers.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Transition {
+    pub worker_id: String,
+    /// Kind of the state the event applied to before the event, for
+    /// events starting a lifecycle of the worker's latest state. `None`
+    /// for new workers.
+    pub old_kind: Option<String>,
+    pub new_kind: String,
+    /// The state the event applied to, after the event.
+    pub state: SnarkWorkerState,
+    /// When the event was applied.
+    pub applied_t: u64,
+}
+
+/// Max number of sessions registered under the same name.
+pub const MAX_SESSIONS_PER_NAME: usize = 4095;
+/// Max number of job lifecycles tracked in flight per worker, beyond
+/// which the oldest ones are considered abandoned.
+const MAX_IN_FLIGHT: usize = 64;
+
+/// A worker's job lifecycles in flight, by the position of their state in
+/// the worker's history counted from the oldest state, which stays valid
+/// as states are added.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+struct InFlight {
+    /// `JobGetPending` states, oldest first. Job gets are answered in the
+    /// order they were made.
+    job_gets: VecDeque<usize>,
+    /// Lifecycles which received a job, by their `ids`.
+    jobs: HashMap<String, usize>,
+    /// State the worker's latest event was applied to.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    latest: Option<usize>,
+}
+
+impl InFlight {
+    fn len(&self) -> usize {
+        self.job_gets.len() + self.jobs.len()
+    }
+
+    /// Whether the lifecycle at `pos` is tracked.
+    fn contains(&self, pos: usize) -> bool {
+        self.job_gets.contains(&pos) || self.jobs.values().any(|p| *p == pos)
+    }
+
+    /// Stops tracking the lifecycle at `pos`.
+    fn remove(&mut self, pos: usize) {
+        self.job_gets.retain(|p| *p != pos);
+        self.jobs.retain(|_, p| *p != pos);
+    }
+
+    /// Stops tracking the oldest lifecycles while there are too many.
+    fn evict(&mut self) {
+        while self.len() > MAX_IN_FLIGHT {
+            let oldest = self
+                .job_gets
+                .iter()
+                .chain(self.jobs.values())
+                .min()
+                .copied()
+                .unwrap_or_default();
+            self.remove(oldest);
+        }
+    }
+}
+
+/// States evicted from a worker's history by `--max-states-per-worker`,
+/// `--max-total-states` or `--stats-retention`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct EvictedStates {
+    /// Number of evicted states.
+    pub states: usize,
+    /// Lifecycles of the evicted states per state kind, counting
+    /// collapsed errors. Only kept with `--fold-evicted-states`.
+    pub kinds: BTreeMap<String, u64>,
+    /// Sum of the end-to-end durations of evicted `WorkSubmitSuccess`
+    /// states, only kept with `--fold-evicted-states`.
+    pub end_to_end_ms: u64,
+}
+
+impl EvictedStates {
+    fn fold(&mut self, state: &SnarkWorkerState) {
+        *self.kinds.entry(state.kind().to_owned()).or_default() += state.count();
+        if let SnarkWorkerState::WorkSubmitSuccess { .. } = state {
+            self.end_to_end_ms += state.end_time().saturating_sub(state.start_time());
+        }
+    }
+}
+
+/// Number of states evicted so far across all workers, by what evicted
+/// them.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Evictions {
+    /// Beyond `--max-states-per-worker`.
+    pub max_states_per_worker: u64,
+    /// Beyond `--max-total-states`.
+    pub max_total_states: u64,
+    /// Older than `--stats-retention`.
+    pub retention: u64,
+    /// Compacted into hourly aggregates with `--compact-after`.
+    #[serde(default)]
+    pub compacted: u64,
+}
+
+/// Everything [`WorkerStats`] holds, for moving it to another instance or
+/// analysing it offline.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WorkerStatsSnapshot {
+    pub workers: BTreeMap<String, VecDeque<SnarkWorkerState>>,
+    /// Session id -> name it was registered under.
+    pub names: BTreeMap<String, String>,
+    pub evicted: BTreeMap<String, EvictedStates>,
+    pub evictions: Evictions,
+    /// States compacted out of all workers' histories.
+    #[serde(default)]
+    pub compacted: HourlyAggregates,
+    /// Lifecycles in flight per worker, by position in its history.
+    in_flight: BTreeMap<String, InFlight>,
+    /// Sequence numbers of the workers' events.
+    #[serde(default)]
+    pub seqs: BTreeMap<String, EventSeq>,
+}
+
+/// Sequence numbers a worker session's events came with. Numbers it
+/// skipped tell events lost in transit from a worker which went quiet.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct EventSeq {
+    /// Highest number seen, later events have to exceed it.
+    pub last: u64,
+    /// Number of times numbers were skipped.
+    pub gaps: u64,
+    /// Numbers skipped in total, i.e. events presumably lost.
+    pub missing: u64,
+}
+
+impl EventSeq {
+    /// Rejects `seq` unless it's past the last one.
+    fn check(&self, seq: u64) -> Result<(), PutError> {
+        if seq <= self.last {
+            return Err(PutError::OutOfOrder(format!(
+                "event seq {seq} isn't past the last one seen, {}",
+                self.last
+            )));
+        }
+        Ok(())
+    }
+
+    /// Records `seq` of an applied event, see [`Self::check`].
+    fn observe(&mut self, seq: u64) {
+        if seq > self.last + 1 {
+            self.gaps += 1;
+            self.missing += seq - self.last - 1;
+        }
+        self.last = seq;
+    }
+}
+
+/// Outcome of [`WorkerStats::prune`].
+#[derive(Debug, Default)]
+pub struct Pruned {
+    /// Number of dropped states.
+    pub states: usize,
+    /// Workers left without any state.
+    pub emptied: Vec<String>,
+}
+
+/// Position of a state in time-range queries: its end time, then its
+/// 1-based position counted from the worker's oldest state, including
+/// evicted ones.
+pub type EndKey = (u64, usize);
+
+/// Order of states in time-range queries, by [`EndKey`].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Order {
+    Asc,
+    /// Most recent first.
+    #[default]
+    Desc,
+}
+
+/// Per-worker state history, most recent state first, keyed by worker
+/// id. Registered workers get a session id of the name they registered
+/// with and a random UUID, e.g. `gpu-1_1b4e28ba-2fa1-41d2-883f-0016d3cca427`,
+/// so id prefixes still tell the workers' teams and hosts apart.
+///
+/// Workers may run several job lifecycles concurrently. Events apply to
+/// the lifecycle of their `ids`, job get results to the oldest pending
+/// job get.
+#[derive(Debug, Default)]
+pub struct WorkerStats {
+    workers: HashMap<String, VecDeque<SnarkWorkerState>>,
+    /// Each worker's states ordered by end time. Lifecycles interleave, so
+    /// the history itself isn't.
+    by_end_t: HashMap<String, BTreeSet<EndKey>>,
+    in_flight: HashMap<String, InFlight>,
+    evicted: HashMap<String, EvictedStates>,
+    /// Name given on `Register` -> ids of the sessions registered under it.
+    sessions: HashMap<String, BTreeSet<String>>,
+    /// Session id -> name it was registered under.
+    names: HashMap<String, String>,
+    /// Sequence numbers of workers numbering their events.
+    seqs: HashMap<String, EventSeq>,
+    /// Whether identical errors in a row are collapsed into one state.
+    collapse_errors: bool,
+    /// Max number of states kept per worker, `None` if unbounded.
+    max_states: Option<usize>,
+    /// Max number of states kept across all workers, `None` if unbounded.
+    max_total_states: Option<usize>,
+    evictions: Evictions,
+    /// Whether evicted states are folded into [`EvictedStates`] totals.
+    fold_evicted: bool,
+    /// Whether evicted states are folded into `compacted`.
+    compact: bool,
+    compacted: HourlyAggregates,
+}
+
+impl Deref for WorkerStats {
+    type Target = HashMap<String, VecDeque<SnarkWorkerState>>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.workers
+    }
+}
+
+impl<'a> IntoIterator for &'a WorkerStats {
+    type Item = (&'a String, &'a VecDeque<SnarkWorkerState>);
+    type IntoIter = std::collections::hash_map::Iter<'a, String, VecDeque<SnarkWorkerState>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.workers.iter()
+    }
+}
+
+impl WorkerStats {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Collapses identical errors a worker hits in a row, e.g. in a crash
+    /// loop, into a single state counting them.
+    pub fn with_collapsed_errors(mut self, collapse_errors: bool) -> Self {
+        self.collapse_errors = collapse_errors;
+        self
+    }
+
+    /// Keeps at most `max_states` states per worker, evicting the oldest
+    /// ones. If `fold` is set, evicted states are counted in the worker's
+    /// [`EvictedStates`] first.
+    pub fn with_max_states(mut self, max_states: Option<usize>, fold: bool) -> Self {
+        self.max_states = max_states.map(|n| n.max(1));
+        self.fold_evicted = fold;
+        self
+    }
+
+    /// Folds evicted states into hourly aggregates before dropping them,
+    /// and allows compacting old states with [`WorkerStats::compact`].
+    pub fn with_compaction(mut self, compact: bool) -> Self {
+        self.compact = compact;
+        self
+    }
+
+    /// Keeps at most `max_total_states` states across all workers,
+    /// evicting the oldest completed ones of any worker.
+    pub fn with_max_total_states(mut self, max_total_states: Option<usize>) -> Self {
+        self.max_total_states = max_total_states.map(|n| n.max(1));
+        self
+    }
+
+    /// Removes the worker's states, and its session if it registered.
+    pub fn remove(&mut self, worker_id: &str) -> Option<VecDeque<SnarkWorkerState>> {
+        if let Some(name) = self.names.remove(worker_id) {
+            if let Some(sessions) = self.sessions.get_mut(&name) {
+                sessions.remove(worker_id);
+                if sessions.is_empty() {
+                    self.sessions.remove(&name);
+                }
+            }
+        }
+        self.in_flight.remove(worker_id);
+        self.evicted.remove(worker_id);
+        self.seqs.remove(worker_id);
+        self.by_end_t.remove(worker_id);
+        self.workers.remove(worker_id)
+    }
+
+    /// Drops all workers, e.g. before taking over another instance's
+    /// stats. Limits stay.
+    pub fn clear(&mut self) {
+        self.workers.clear();
+        self.by_end_t.clear();
+        self.in_flight.clear();
+        self.evicted.clear();
+        self.sessions.clear();
+        self.names.clear();
+        self.seqs.clear();
+        self.evictions = Evictions::default();
+        self.compacted = HourlyAggregates::default();
+    }
+
+    /// Drops the worker's states but its most recent one, along with what
+    /// was folded of evicted ones. Returns the number of dropped states,
+    /// `None` for an unknown worker.
+    pub fn clear_history(&mut self, worker_id: &str) -> Option<usize> {
+        self.workers.get(worker_id)?;
+        let dropped = self.evict_while(worker_id, |states| states.len() > 1);
+        if let Some(evicted) = self.evicted.get_mut(worker_id) {
+            // the count stays, it offsets the positions of in-flight
+            // lifecycles.
+            evicted.kinds.clear();
+            evicted.end_to_end_ms = 0;
+        }
+        Some(dropped)
+    }
+
+    /// What was evicted from the worker's history, `None` if nothing.
+    pub fn evicted(&self, worker_id: &str) -> Option<&EvictedStates> {
+        self.evicted.get(worker_id)
+    }
+
+    /// Sequence numbers of the worker's events, `None` if it doesn't number
+    /// them.
+    pub fn seq(&self, worker_id: &str) -> Option<&EventSeq> {
+        self.seqs.get(worker_id)
+    }
+
+    /// Number of states evicted from the worker's history, which positions
+    /// of its states are offset by.
+    fn offset(&self, worker_id: &str) -> usize {
+        self.evicted.get(worker_id).map_or(0, |e| e.states)
+    }
+
+    /// Index in the worker's history of the state at `pos`, `None` if it
+    /// was evicted.
+    fn index(&self, worker_id: &str, pos: usize) -> Option<usize> {
+        let len = self.workers.get(worker_id)?.len();
+        (len + self.offset(worker_id)).checked_sub(pos + 1)
+    }
+
+    /// The worker's states with end keys within `from..to`, e.g. ended
+    /// within `(Included((from_t, 0)), Excluded((to_t, 0)))`, in `order`.
+    pub fn range_by_end_t(
+        &self,
+        worker_id: &str,
+        from: Bound<EndKey>,
+        to: Bound<EndKey>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = (EndKey, &SnarkWorkerState)> + '_> {
+        let empty = match (from, to) {
+            (Bound::Included(a), Bound::Included(b)) => a > b,
+            (Bound::Included(a) | Bound::Excluded(a), Bound::Included(b) | Bound::Excluded(b)) => {
+                a >= b
+            }
+            _ => false,
+        };
+        let Some(keys) = self.by_end_t.get(worker_id).filter(|_| !empty) else {
+            return Box::new(std::iter::empty());
+        };
+        let keys = keys.range((from, to));
+        let keys: Box<dyn Iterator<Item = &EndKey>> = match order {
+            Order::Asc => Box::new(keys),
+            Order::Desc => Box::new(keys.rev()),
+        };
+        let Some(states) = self.workers.get(worker_id) else {
+            return Box::new(std::iter::empty());
+        };
+        let len = self.offset(worker_id) + states.len();
+        Box::new(keys.filter_map(move |&(end_t, seq)| {
+            let state = states.get(len.checked_sub(seq)?)?;
+            Some(((end_t, seq), state))
+        }))
+    }
+
+    /// The worker's states which ended within `from_t..to_t`, `from_t`
+    /// inclusive and `to_t` exclusive, in `order`.
+    pub fn in_range(
+        &self,
+        worker_id: &str,
+        from_t: Option<u64>,
+        to_t: Option<u64>,
+        order: Order,
+    ) -> Box<dyn Iterator<Item = (EndKey, &SnarkWorkerState)> + '_> {
+        let from = Bound::Included((from_t.unwrap_or(0), 0));
+        let to = to_t.map_or(Bound::Unbounded, |t| Bound::Excluded((t, 0)));
+        self.range_by_end_t(worker_id, from, to, order)
+    }
+
+    /// Rebuilds the worker's [`EndKey`]s from its history.
+    fn reindex(&mut self, worker_id: &str) {
+        let Some(states) = self.workers.get(worker_id) else {
+            self.by_end_t.remove(worker_id);
+            return;
+        };
+        let len = self.offset(worker_id) + states.len();
+        let keys = (states.iter().enumerate())
+            .map(|(i, state)| (state.end_time(), len - i))
+            .collect();
+        self.by_end_t.insert(worker_id.to_owned(), keys);
+    }
+
+    /// Number of states kept across all workers.
+    pub fn total_states(&self) -> usize {
+        self.workers.values().map(VecDeque::len).sum()
+    }
+
+    /// Number of states evicted so far.
+    pub fn evictions(&self) -> &Evictions {
+        &self.evictions
+    }
+
+    /// Hourly aggregates of the compacted states.
+    pub fn compacted(&self) -> &HourlyAggregates {
+        &self.compacted
+    }
+
+    pub fn snapshot(&self) -> WorkerStatsSnapshot {
+        WorkerStatsSnapshot {
+            workers: self.workers.clone().into_iter().collect(),
+            names: self.names.clone().into_iter().collect(),
+            evicted: self.evicted.clone().into_iter().collect(),
+            evictions: self.evictions.clone(),
+            compacted: self.compacted.clone(),
+            in_flight: self.in_flight.clone().into_iter().collect(),
+            seqs: self.seqs.clone().into_iter().collect(),
+        }
+    }
+
+    /// Takes over the workers of `snapshot`, replacing those with the same
+    /// ids, then evicts states beyond this instance's limits.
+    pub fn restore(&mut self, snapshot: WorkerStatsSnapshot) {
+        for (worker_id, name) in snapshot.names {
+            let sessions = self.sessions.entry(name.clone()).or_default();
+            sessions.insert(worker_id.clone());
+            self.names.insert(worker_id, name);
+        }
+        self.evicted.extend(snapshot.evicted);
+        self.in_flight.extend(snapshot.in_flight);
+        self.seqs.extend(snapshot.seqs);
+        let Evictions {
+            max_states_per_worker,
+            max_total_states,
+            retention,
+            compacted,
+        } = snapshot.evictions;
+        self.evictions.max_states_per_worker += max_states_per_worker;
+        self.evictions.max_total_states += max_total_states;
+        self.evictions.retention += retention;
+        self.evictions.compacted += compacted;
+        self.compacted.merge(snapshot.compacted);
+        let worker_ids = snapshot.workers.keys().cloned().collect::<Vec<_>>();
+        self.workers.extend(snapshot.workers);
+        for worker_id in worker_ids {
+            self.reindex(&worker_id);
+            self.evict(&worker_id);
+        }
+    }
+
+    /// Drops the worker's oldest states beyond the max number of states,
+    /// then the oldest ones of any worker beyond the max total.
+    fn evict(&mut self, worker_id: &str) {
+        if let Some(max_states) = self.max_states {
+            let evicted = self.evict_while(worker_id, |states| states.len() > max_states);
+            self.evictions.max_states_per_worker += evicted as u64;
+        }
+        self.evict_total();
+    }
+
+    /// Drops the oldest states across all workers while there are more
+    /// than the max total. Only a worker's oldest state can be evicted, so
+    /// workers whose oldest state is still in flight are skipped.
+    fn evict_total(&mut self) {
+        let Some(max_total_states) = self.max_total_states else {
+            return;
+        };
+        let mut total = self.total_states();
+        while total > max_total_states {
+            let oldest = self
+                .workers
+                .iter()
+                .filter_map(|(worker_id, states)| Some((worker_id, states.back()?)))
+                .filter(|(worker_id, _)| {
+                    let pos = self.offset(worker_id);
+                    !self
+                        .in_flight
+                        .get(*worker_id)
+                        .is_some_and(|f| f.contains(pos))
+                })
+                .min_by_key(|(_, state)| state.end_time())
+                .map(|(worker_id, _)| worker_id.clone());
+            let Some(worker_id) = oldest else {
+                break;
+            };
+            let len = self.workers[&worker_id].len();
+            self.evict_while(&worker_id, |states| states.len() >= len);
+            self.evictions.max_total_states += 1;
+            total -= 1;
+        }
+    }
+
+    /// Drops the worker's oldest states while `evict` holds for its
+    /// history, folding them into its [`EvictedStates`] first if enabled.
+    /// Returns the number of dropped states.
+    fn evict_while(
+        &mut self,
+        worker_id: &str,
+        evict: impl Fn(&VecDeque<SnarkWorkerState>) -> bool,
+    ) -> usize {
+        let Some(states) = self.workers.get_mut(worker_id).filter(|v| evict(v)) else {
+            return 0;
+        };
+        let evicted = self.evicted.entry(worker_id.to_owned()).or_default();
+        let mut dropped = 0;
+        while evict(states) {
+            let Some(state) = states.pop_back() else {
+                break;
+            };
+            if let Some(in_flight) = self.in_flight.get_mut(worker_id) {
+                in_flight.remove(evicted.states);
+            }
+            if let Some(keys) = self.by_end_t.get_mut(worker_id) {
+                keys.remove(&(state.end_time(), evicted.states + 1));
+            }
+            if self.fold_evicted {
+                evicted.fold(&state);
+            }
+            if self.compact {
+                self.compacted.fold(&state);
+            }
+            evicted.states += 1;
+            dropped += 1;
+        }
+        dropped
+    }
+
+    /// Drops states which ended before `min_t`, oldest first, the same way
+    /// as evicted ones. With `prefix`, only those of workers whose id starts
+    /// with it.
+    pub fn prune(&mut self, min_t: u64, prefix: Option<&str>) -> Pruned {
+        let expired = |states: &VecDeque<SnarkWorkerState>| {
+            states.back().is_some_and(|s| s.end_time() < min_t)
+        };
+        let worker_ids = self
+            .workers
+            .iter()
+            .filter(|(worker_id, _)| prefix.is_none_or(|p| worker_id.starts_with(p)))
+            .filter(|(_, states)| expired(states))
+            .map(|(worker_id, _)| worker_id.clone())
+            .collect::<Vec<_>>();
+        let mut pruned = Pruned::default();
+        for worker_id in worker_ids {
+            pruned.states += self.evict_while(&worker_id, expired);
+            if self.workers.get(&worker_id).is_some_and(VecDeque::is_empty) {
+                pruned.emptied.push(worker_id);
+            }
+        }
+        self.evictions.retention += pruned.states as u64;
+        pruned
+    }
+
+    /// Folds states which ended before `min_t` into hourly aggregates,
+    /// oldest first, keeping each worker's most recent state. Returns the
+    /// number of compacted states, none unless compaction is enabled.
+    pub fn compact(&mut self, min_t: u64) -> usize {
+        if !self.compact {
+            return 0;
+        }
+        let old = |states: &VecDeque<SnarkWorkerState>| {
+            states.len() > 1 && states.back().is_some_and(|s| s.end_time() < min_t)
+        };
+        let worker_ids = (self.workers.iter())
+            .filter(|(_, states)| old(states))
+            .map(|(worker_id, _)| worker_id.clone())
+            .collect::<Vec<_>>();
+        let mut compacted = 0;
+        for worker_id in worker_ids {
+            compacted += self.evict_while(&worker_id, old);
+        }
+        self.evictions.compacted += compacted as u64;
+        compacted
+    }
+
+    /// State the worker's latest event was applied to, which isn't the
+    /// most recent one if the event continued an older lifecycle.
+    pub fn latest(&self, worker_id: &str) -> Option<&SnarkWorkerState> {
+        let states = self.workers.get(worker_id)?;
+        match self.in_flight.get(worker_id).and_then(|f| f.latest) {
+            Some(pos) => states.get(self.index(worker_id, pos)?),
+            None => states.front(),
+        }
+    }
+
+    pub fn latest_mut(&mut self, worker_id: &str) -> Option<&mut SnarkWorkerState> {
+        match self.in_flight.get(worker_id).and_then(|f| f.latest) {
+            Some(pos) => {
+                let i = self.index(worker_id, pos)?;
+                self.workers.get_mut(worker_id)?.get_mut(i)
+            }
+            None => self.workers.get_mut(worker_id)?.front_mut(),
+        }
+    }
+
+    /// The worker's lifecycles in flight, i.e. pending a job or its work.
+    pub fn in_flight<'a>(
+        &'a self,
+        worker_id: &'a str,
+    ) -> impl Iterator<Item = &'a SnarkWorkerState> {
+        let positions = self
+            .in_flight
+            .get(worker_id)
+            .into_iter()
+            .flat_map(|f| f.job_gets.iter().chain(f.jobs.values()).copied());
+        positions.filter_map(move |pos| {
+            self.workers
+                .get(worker_id)?
+                .get(self.index(worker_id, pos)?)
+        })
+    }
+
+    /// State `req` applies to, `None` if it doesn't continue a lifecycle
+    /// in flight.
+    pub fn target(&self, worker_id: &str, req: &SnarkWorkerStatsPut) -> Option<&SnarkWorkerState> {
+        let pos = self.target_pos(worker_id, req)?;
+        self.workers
+            .get(worker_id)?
+            .get(self.index(worker_id, pos)?)
+    }
+
+    fn target_pos(&self, worker_id: &str, req: &SnarkWorkerStatsPut) -> Option<usize> {
+        let in_flight = self.in_flight.get(worker_id)?;
+        match req {
+            SnarkWorkerStatsPut::JobGetError { .. } | SnarkWorkerStatsPut::JobGetSuccess { .. } => {
+                in_flight.job_gets.front().copied()
+            }
+            req => in_flight.jobs.get(req.ids()?).copied(),
+        }
+    }
+
+    /// Moves lifecycles pending since before `now - timeout_ms` into their
+    /// timeout states. Returns the worker ids, the kinds they were pending
+    /// in and the new states.
+    pub fn time_out_pending(
+        &mut self,
+        timeout_ms: u64,
+        now: u64,
+    ) -> Vec<(String, &'static str, SnarkWorkerState)> {
+        let mut timed_out = vec![];
+        for (worker_id, in_flight) in &mut self.in_flight {
+            let Some(states) = self.workers.get_mut(worker_id) else {
+                continue;
+            };
+            let len = states.len() + self.evicted.get(worker_id).map_or(0, |e| e.states);
+            let positions = in_flight
+                .job_gets
+                .iter()
+                .chain(in_flight.jobs.values())
+                .copied()
+                .collect::<Vec<_>>();
+            for pos in positions {
+                let Some(state) = len.checked_sub(pos + 1).and_then(|i| states.get_mut(i)) else {
+                    continue;
+                };
+                let end_t = state.end_time();
+                if now.saturating_sub(end_t) < timeout_ms {
+                    continue;
+                }
+                let old_kind = state.kind();
+                if state.time_out(now) {
+                    if let Some(keys) = self.by_end_t.get_mut(worker_id) {
+                        keys.remove(&(end_t, pos + 1));
+                        keys.insert((state.end_time(), pos + 1));
+                    }
+                    timed_out.push((worker_id.clone(), old_kind, state.clone()));
+                    in_flight.remove(pos);
+                }
+            }
+        }
+        timed_out
+    }
+
+    /// Name the worker registered under, `None` for workers which never
+    /// registered.
+    pub fn name(&self, worker_id: &str) -> Option<&str> {
+        self.names.get(worker_id).map(String::as_str)
+    }
+
+    /// Ids of the sessions registered under `name`.
+    pub fn sessions(&self, name: &str) -> impl Iterator<Item = &str> {
+        self.sessions
+            .get(name)
+            .into_iter()
+            .flatten()
+            .map(String::as_str)
+    }
+
+    /// Fee the worker registered with, `worker_id` being a session id or
+    /// the name of its sessions.
+    pub fn fee(&self, worker_id: &str) -> Option<u64> {
+        let fee = |worker_id: &str| {
+            self.workers
+                .get(worker_id)?
+                .iter()
+                .find_map(|s| s.metadata())?
+                .fee
+        };
+        fee(worker_id).or_else(|| self.sessions(worker_id).find_map(fee))
+    }
+
+    /// Starts a session for a worker registering under `name`, with
+    /// `session_id` if given and a new one otherwise.
+    fn register(
+        &mut self,
+        name: String,
+        registered: SnarkWorkerState,
+        session_id: Option<String>,
+    ) -> Result<String, PutError> {
+        let sessions = self.sessions.entry(name.clone()).or_default();
+        if sessions.len() >= MAX_SESSIONS_PER_NAME {
+            return Err(PutError::TooManyWorkers(format!(
+                "too many workers under same worker_id: {name}"
+            )));
+        }
+        let id = session_id.unwrap_or_else(|| format!("{name}_{}", session_uuid()));
+        sessions.insert(id.clone());
+        self.names.insert(id.clone(), name);
+        self.workers
+            .insert(id.clone(), std::iter::once(registered).collect());
+        self.reindex(&id);
+        Ok(id)
+    }
+}
+
+/// Random (version 4) UUID.
+fn session_uuid() -> String {
+    let mut bytes = [0u8; 16];
+    rand::thread_rng().fill_bytes(&mut bytes);
+    bytes[6] = (bytes[6] & 0x0f) | 0x40;
+    bytes[8] = (bytes[8] & 0x3f) | 0x80;
+    let hex = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+    format!(
+        "{}-{}-{}-{}-{}",
+        &hex[..8],
+        &hex[8..12],
+        &hex[12..16],
+        &hex[16..20],
+        &hex[20..]
+    )
+}
+
+/// Position in a paginated worker-stats listing, which orders workers by
+/// id and each worker's states by [`EndKey`]. `key` is that of the next
+/// state, positions count evicted states too, so a cursor stays valid
+/// while new states are added and old ones evicted. States which end
+/// again while paging, e.g. pending ones, move to their new place.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatsCursor {
+    pub worker_id: String,
+    pub key: EndKey,
+}
+
+impl fmt::Display for StatsCursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (end_t, seq) = self.key;
+        write!(f, "{end_t}.{seq}:{}", self.worker_id)
+    }
+}
+
+impl FromStr for StatsCursor {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("invalid cursor: {s}");
+        let (key, worker_id) = s.split_once(':').ok_or_else(invalid)?;
+        let (end_t, seq) = key.split_once('.').ok_or_else(invalid)?;
+        Ok(Self {
+            worker_id: worker_id.to_owned(),
+            key: (
+                end_t.parse().map_err(|_| invalid())?,
+                seq.parse().map_err(|_| invalid())?,
+            ),
+        })
+    }
+}
+
+/// Rejected worker-stats event.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum PutError {
+    /// Too many sessions registered under the name of a `Register`.
+    TooManyWorkers(String),
+    /// The event doesn't follow from the worker's current state.
+    InvalidTransition(String),
+    /// The event's `seq` isn't past the last one of its session.
+    OutOfOrder(String),
+    /// The event breaks a rule of `--strict`.
+    Strict(Violation),
+}
+
+impl PutError {
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            Self::TooManyWorkers(_) => ErrorCode::WorkerQuotaExceeded,
+            Self::InvalidTransition(_) => ErrorCode::InvalidTransition,
+            Self::OutOfOrder(_) => ErrorCode::OutOfOrderEvent,
+            Self::Strict(_) => ErrorCode::StrictViolation,
+        }
+    }
+
+    pub fn message(&self) -> &str {
+        match self {
+            Self::TooManyWorkers(msg) | Self::InvalidTransition(msg) | Self::OutOfOrder(msg) => msg,
+            Self::Strict(violation) => &violation.message,
+        }
+    }
+
+    /// Body of the error response, with the violation of `Strict`.
+    pub fn to_api_error(&self) -> ApiError {
+        let mut err = ApiError::new(self.code(), self.message());
+        if let Self::Strict(violation) = self {
+            err.violation = Some(violation.clone());
+        }
+        err
+    }
+}
+
+impl fmt::Display for PutError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.message())
+    }
+}
+
+/// Ingests a single worker-stats event.
+///
+/// On success returns the response body, which is the assigned or resumed
+/// session id for `Register` and empty otherwise. Resuming needs the
+/// session to be registered under the same name, otherwise a new one is
+/// started.
+pub fn put(
+    stats: &mut WorkerStats,
+    worker_id: String,
+    req: SnarkWorkerStatsPut,
+) -> Result<String, PutError> {
+    put_with_session_id(stats, worker_id, req, None)
+}
+
+/// Like [`put`], but a `Register` which starts a new session gets
+/// `session_id` instead of a new one, e.g. when replaying a journal.
+pub fn put_with_session_id(
+    stats: &mut WorkerStats,
+    worker_id: String,
+    req: SnarkWorkerStatsPut,
+    session_id: Option<String>,
+) -> Result<String, PutError> {
+    // a `Register` restarts the numbering instead.
+    let seq = match &req {
+        SnarkWorkerStatsPut::Register { .. } => None,
+        req => req.seq(),
+    };
+    let Some(seq) = seq else {
+        return put_unsequenced(stats, worker_id, req, session_id);
+    };
+    let seqs = stats.seqs.get(&worker_id).copied().unwrap_or_default();
+    seqs.check(seq)?;
+    // only applied events count, so rejected ones can be sent again.
+    let res = put_unsequenced(stats, worker_id.clone(), req, session_id)?;
+    stats.seqs.entry(worker_id).or_default().observe(seq);
+    Ok(res)
+}
+
+fn put_unsequenced(
+    stats: &mut WorkerStats,
+    worker_id: String,
+    req: SnarkWorkerStatsPut,
+    session_id: Option<String>,
+) -> Result<String, PutError> {
+    match req {
+        SnarkWorkerStatsPut::Register {
+            time,
+            metadata,
+            resume,
+            seq,
+        } => {
+            let resumable = resume.filter(|id| stats.name(id) == Some(worker_id.as_str()));
+            // restarted processes number their events from scratch.
+            let restart_seq = |stats: &mut WorkerStats, id: &str| match seq {
+                Some(last) => {
+                    let seqs = stats.seqs.entry(id.to_owned()).or_default();
+                    seqs.last = last;
+                }
+                None => {
+                    stats.seqs.remove(id);
+                }
+            };
+            if let Some(id) = resumable {
+                restart_seq(stats, &id);
+                let restarted = SnarkWorkerState::Restarted {
+                    restarted_t: time,
+                    metadata,
+                };
+                let offset = stats.offset(&id);
+                if let Some(states) = stats.workers.get_mut(&id) {
+                    let keys = stats.by_end_t.entry(id.clone()).or_default();
+                    keys.insert((restarted.end_time(), offset + states.len() + 1));
+                    states.push_front(restarted);
+                }
+                // lifecycles of the previous process are abandoned.
+                stats.in_flight.remove(&id);
+                stats.evict(&id);
+                return Ok(id);
+            }
+            let registered = SnarkWorkerState::Registered {
+                registered_t: time,
+                metadata,
+            };
+            let id = stats.register(worker_id, registered, session_id)?;
+            restart_seq(stats, &id);
+            stats.evict_total();
+            Ok(id)
+        }
+        SnarkWorkerStatsPut::JobGetInit { time, .. } => {
+            let offset = stats.offset(&worker_id);
+            let states = stats.workers.entry(worker_id.clone()).or_default();
+            let pos = offset + states.len();
+            states.push_front(SnarkWorkerState::init(time));
+            let keys = stats.by_end_t.entry(worker_id.clone()).or_default();
+            keys.insert((time, pos + 1));
+            let in_flight = stats.in_flight.entry(worker_id.clone()).or_default();
+            in_flight.job_gets.push_back(pos);
+            in_flight.latest = Some(pos);
+            in_flight.evict();
+            stats.evict(&worker_id);
+            Ok(String::new())
+        }
+        req => {
+            let collapse_errors = stats.collapse_errors;
+            let pos = stats.target_pos(&worker_id, &req);
+            let offset = stats.offset(&worker_id);
+            let Some(states) = stats.workers.get_mut(&worker_id) else {
+                return Err(PutError::InvalidTransition(format!(
+                    "unexpected worker_stats/put\nstate: None\nrequest: {:?}",
+                    req
+                )));
+            };
+            let len = offset + states.len();
+            let Some((pos, state)) = pos.and_then(|pos| {
+                let state = states.get_mut(len.checked_sub(pos + 1)?)?;
+                Some((pos, state))
+            }) else {
+                return Err(PutError::InvalidTransition(format!(
+                    "unexpected worker_stats/put\nno lifecycle in flight for request: {:?}",
+                    req
+                )));
+            };
+            let end_t = state.end_time();
+            if let Err(req) = state.apply(req) {
+                return Err(PutError::InvalidTransition(format!(
+                    "unexpected worker_stats/put\nstate: {:?}\nrequest: {:?}",
+                    state, req
+                )));
+            }
+            let new_end_t = state.end_time();
+            let keys = stats.by_end_t.entry(worker_id.clone()).or_default();
+            keys.remove(&(end_t, pos + 1));
+            keys.insert((new_end_t, pos + 1));
+            let in_flight = stats.in_flight.entry(worker_id).or_default();
+            in_flight.remove(pos);
+            in_flight.latest = Some(pos);
+            match state {
+                SnarkWorkerState::WorkCreatePending { ids, .. }
+                | SnarkWorkerState::WorkSubmitPending { ids, .. } => {
+                    in_flight.jobs.insert(ids.clone(), pos);
+                }
+                _ => {}
+            }
+            // only the most recent state can be collapsed into the one
+            // before it.
+            if collapse_errors && pos + 1 == len && collapse_front(states) {
+                in_flight.latest = Some(pos - 1);
+                keys.remove(&(new_end_t, pos + 1));
+            }
+            Ok(String::new())
+        }
+    }
+}
+
+/// Collapses the worker's latest state into the one before if both are
+/// the same error, returns whether it did.
+fn collapse_front(states: &mut VecDeque<SnarkWorkerState>) -> bool {
+    let Some(newer) = states.pop_front() else {
+        return false;
+    };
+    if !states.front_mut().is_some_and(|prev| prev.collapse(&newer)) {
+        states.push_front(newer);
+        return false;
+    }
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn event(json: &str) -> SnarkWorkerStatsPut {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn job_get_init(seq: u64) -> SnarkWorkerStatsPut {
+        event(&format!(
+            r#"{{"kind":"JobGetInit","time":1700000000000,"seq":{seq}}}"#
+        ))
+    }
+
+    #[test]
+    fn skipped_seqs_count_as_gaps() {
+        let mut stats = WorkerStats::new();
+        put(&mut stats, "w1".into(), job_get_init(1)).unwrap();
+        put(&mut stats, "w1".into(), job_get_init(4)).unwrap();
+        let seq = stats.seq("w1").unwrap();
+        assert_eq!((seq.last, seq.gaps, seq.missing), (4, 1, 2));
+
+        let err = put(&mut stats, "w1".into(), job_get_init(4)).unwrap_err();
+        assert!(matches!(err, PutError::OutOfOrder(_)));
+    }
+
+    #[test]
+    fn rejected_events_keep_their_seq() {
+        let mut stats = WorkerStats::new();
+        put(&mut stats, "w1".into(), job_get_init(1)).unwrap();
+        // no job was got, so there's nothing to create work for.
+        let create = r#"{"kind":"WorkCreateSuccess","time":1700000000001,"ids":"j1","seq":2}"#;
+        assert!(put(&mut stats, "w1".into(), event(create)).is_err());
+        assert_eq!(stats.seq("w1").unwrap().last, 1);
+        put(&mut stats, "w1".into(), job_get_init(2)).unwrap();
+        assert_eq!(stats.seq("w1").unwrap().gaps, 0);
+
+        // nor do unknown workers get numbers.
+        assert!(put(&mut stats, "w2".into(), event(create)).is_err());
+        assert!(stats.seq("w2").is_none());
+    }
+
+    #[test]
+    fn seqs_are_snapshotted() {
+        let mut stats = WorkerStats::new();
+        put(&mut stats, "w1".into(), job_get_init(3)).unwrap();
+        let snapshot = serde_json::to_string(&stats.snapshot()).unwrap();
+        let mut restored = WorkerStats::new();
+        restored.restore(serde_json::from_str(&snapshot).unwrap());
+        assert_eq!(restored.seq("w1"), stats.seq("w1"));
+        let err = put(&mut restored, "w1".into(), job_get_init(3)).unwrap_err();
+        assert!(matches!(err, PutError::OutOfOrder(_)));
+    }
+}
@@ -0,0 +1,146 @@
+use std::sync::Arc;
+
+use serde::Serialize;
+use tokio::sync::RwLock;
+use warp::hyper::{body::Bytes, Body};
+
+use crate::formats::Format;
+
+/// Streamed responses are sent in chunks of about this size.
+pub const CHUNK_SIZE: usize = 32 * 1024;
+
+/// Streams an object with an entry per key, as JSON or in `format`.
+/// `select` copies the entry out of `state` under a read lock taken per
+/// entry, and `write` serializes the copy into the chunk being filled
+/// after the lock is released, so neither serialization nor a slow client
+/// keeps writers of `state` waiting, nor holds more than a chunk of
+/// memory. Entries reflect `state` as of their own copy, keys `select`
+/// returns `None` for, e.g. removed ones, are left out, except in
+/// MessagePack, whose map length is sent first, where their entry is an
+/// empty list.
+pub fn object<S, T, F, W>(
+    state: Arc<RwLock<S>>,
+    keys: Vec<String>,
+    format: Option<Format>,
+    mut select: F,
+    mut write: W,
+) -> Body
+where
+    S: Send + Sync + 'static,
+    F: FnMut(&S, &str) -> Option<T> + Send + 'static,
+    W: FnMut(T, &mut Vec<u8>) + Send + 'static,
+{
+    let (mut tx, body) = Body::channel();
+    tokio::spawn(async move {
+        let mut buf = Vec::with_capacity(CHUNK_SIZE);
+        match format {
+            None => buf.push(b'{'),
+            Some(Format::MessagePack) => msgpack_map_header(keys.len(), &mut buf),
+            // a map of indefinite length.
+            Some(Format::Cbor) => buf.push(0xbf),
+        }
+        let mut first = true;
+        let mut keys = keys.into_iter().peekable();
+        while keys.peek().is_some() {
+            while buf.len() < CHUNK_SIZE {
+                let Some(key) = keys.next() else {
+                    break;
+                };
+                let entry = select(&*state.read().await, &key);
+                if entry.is_none() && format != Some(Format::MessagePack) {
+                    continue;
+                }
+                if !first && format.is_none() {
+                    buf.push(b',');
+                }
+                first = false;
+                write_value(format, &key, &mut buf);
+                if format.is_none() {
+                    buf.push(b':');
+                }
+                match entry {
+                    Some(entry) => write(entry, &mut buf),
+                    // an empty array.
+                    None => buf.push(0x90),
+                }
+            }
+            let chunk = Bytes::from(std::mem::replace(&mut buf, Vec::with_capacity(CHUNK_SIZE)));
+            // waits for the client to take the previous chunk.
+            if tx.send_data(chunk).await.is_err() {
+                tracing::debug!("client went away mid response");
+                return;
+            }
+        }
+        match format {
+            None => buf.push(b'}'),
+            Some(Format::MessagePack) => {}
+            Some(Format::Cbor) => buf.push(0xff),
+        }
+        let _ = tx.send_data(Bytes::from(buf)).await;
+    });
+    body
+}
+
+/// Serializes `value` as JSON or in `format`, structs as maps with field
+/// names.
+pub fn write_value(format: Option<Format>, value: &impl Serialize, buf: &mut Vec<u8>) {
+    match format {
+        None => serde_json::to_writer(buf, value).unwrap(),
+        Some(Format::MessagePack) => rmp_serde::encode::write_named(buf, value).unwrap(),
+        Some(Format::Cbor) => ciborium::into_writer(value, buf).unwrap(),
+    }
+}
+
+fn msgpack_map_header(len: usize, buf: &mut Vec<u8>) {
+    match len {
+        0..=15 => buf.push(0x80 | len as u8),
+        16..=0xffff => {
+            buf.push(0xde);
+            buf.extend_from_slice(&(len as u16).to_be_bytes());
+        }
+        _ => {
+            buf.push(0xdf);
+            buf.extend_from_slice(&(len as u32).to_be_bytes());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use warp::hyper::body::to_bytes;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn objects_stream_in_every_format() {
+        // enough entries for a few chunks, and one missing.
+        let state: BTreeMap<String, Vec<u64>> = (0..10_000)
+            .map(|i| (format!("w{i}"), vec![i, i + 1]))
+            .collect();
+        let mut keys = state.keys().cloned().collect::<Vec<_>>();
+        keys.push("removed".to_owned());
+        let state = Arc::new(RwLock::new(state));
+
+        for format in [None, Some(Format::MessagePack), Some(Format::Cbor)] {
+            let body = object(
+                state.clone(),
+                keys.clone(),
+                format,
+                |state: &BTreeMap<String, Vec<u64>>, k| state.get(k).cloned(),
+                move |v, buf| write_value(format, &v, buf),
+            );
+            let body = to_bytes(body).await.unwrap();
+            let mut decoded: BTreeMap<String, Vec<u64>> = match format {
+                None => serde_json::from_slice(&body).unwrap(),
+                Some(Format::MessagePack) => rmp_serde::from_slice(&body).unwrap(),
+                Some(Format::Cbor) => ciborium::from_reader(&body[..]).unwrap(),
+            };
+            if format == Some(Format::MessagePack) {
+                assert_eq!(decoded.remove("removed"), Some(vec![]));
+            }
+            assert_eq!(decoded, *state.read().await, "{format:?}");
+        }
+    }
+}
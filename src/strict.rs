@@ -0,0 +1,131 @@
+//! Checks of stats events beyond being valid transitions, applied with
+//! `--strict`: timestamps have to be consistent with the worker's state
+//! and each other, and ids well formed. Rejections name the violated rule
+//! and field, so client bugs are found without reading free form text.
+
+use serde::{Deserialize, Serialize};
+use utoipa::ToSchema;
+
+use crate::stats::{SnarkWorkerState, SnarkWorkerStatsPut};
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Rule {
+    /// The event's `time` is before the state it applies to ended.
+    TimeWentBackwards,
+    /// `ids` is empty, has empty parts or whitespace.
+    MalformedIds,
+    /// The node's timestamps of a request aren't in the order the node
+    /// handles it in.
+    NodeTimesOutOfOrder,
+}
+
+#[derive(Serialize, Deserialize, ToSchema, Debug, Clone, PartialEq, Eq)]
+pub struct Violation {
+    pub rule: Rule,
+    pub field: String,
+    pub message: String,
+}
+
+impl Violation {
+    fn new(rule: Rule, field: &str, message: String) -> Self {
+        Self {
+            rule,
+            field: field.to_owned(),
+            message,
+        }
+    }
+}
+
+/// Checks `req` against the state it applies to, if any.
+pub fn check(req: &SnarkWorkerStatsPut, state: Option<&SnarkWorkerState>) -> Result<(), Violation> {
+    if let Some(ids) = req.ids() {
+        check_ids(ids)?;
+    }
+    let time = req.time();
+    if let Some(state) = state.filter(|s| time < s.end_time()) {
+        let msg = format!(
+            "time {time} is before {}, the end of the {} state",
+            state.end_time(),
+            state.kind()
+        );
+        return Err(Violation::new(Rule::TimeWentBackwards, "time", msg));
+    }
+    match *req {
+        SnarkWorkerStatsPut::JobGetError {
+            job_get_node_received_t,
+            job_get_node_request_work_init_t,
+            job_get_node_request_work_success_t,
+            ..
+        }
+        | SnarkWorkerStatsPut::JobGetSuccess {
+            job_get_node_received_t,
+            job_get_node_request_work_init_t,
+            job_get_node_request_work_success_t,
+            ..
+        } => check_node_times(&[
+            ("job_get_node_received_t", job_get_node_received_t),
+            (
+                "job_get_node_request_work_init_t",
+                job_get_node_request_work_init_t,
+            ),
+            (
+                "job_get_node_request_work_success_t",
+                job_get_node_request_work_success_t,
+            ),
+        ]),
+        SnarkWorkerStatsPut::WorkSubmitError {
+            work_submit_node_received_t,
+            work_submit_node_add_work_init_t,
+            work_submit_node_add_work_success_t,
+            ..
+        }
+        | SnarkWorkerStatsPut::WorkSubmitSuccess {
+            work_submit_node_received_t,
+            work_submit_node_add_work_init_t,
+            work_submit_node_add_work_success_t,
+            ..
+        } => check_node_times(&[
+            ("work_submit_node_received_t", work_submit_node_received_t),
+            (
+                "work_submit_node_add_work_init_t",
+                work_submit_node_add_work_init_t,
+            ),
+            (
+                "work_submit_node_add_work_success_t",
+                work_submit_node_add_work_success_t,
+            ),
+        ]),
+        _ => Ok(()),
+    }
+}
+
+fn check_ids(ids: &str) -> Result<(), Violation> {
+    let problem = if ids.is_empty() {
+        "is empty"
+    } else if ids.chars().any(|c| c.is_whitespace() || c.is_control()) {
+        "contains whitespace or control characters"
+    } else if ids.split(',').any(str::is_empty) {
+        "has an empty part"
+    } else {
+        return Ok(());
+    };
+    let msg = format!("ids {ids:?} {problem}");
+    Err(Violation::new(Rule::MalformedIds, "ids", msg))
+}
+
+/// Checks the given ones of `times` don't decrease.
+fn check_node_times(times: &[(&str, Option<u64>)]) -> Result<(), Violation> {
+    let mut prev: Option<(&str, u64)> = None;
+    for &(field, t) in times {
+        let Some(t) = t else {
+            continue;
+        };
+        if let Some((prev_field, prev_t)) = prev.filter(|(_, prev_t)| t < *prev_t) {
+            let msg = format!("{field} {t} is before {prev_field} {prev_t}");
+            return Err(Violation::new(Rule::NodeTimesOutOfOrder, field, msg));
+        }
+        prev = Some((field, t));
+    }
+    Ok(())
+}
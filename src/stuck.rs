@@ -0,0 +1,69 @@
+//! Workers whose work creation or submission has been pending for too
+//! long, e.g. because the prover or the node hangs.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::WorkerStats;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct StuckWorker {
+    pub worker_id: String,
+    /// `WorkCreatePending` or `WorkSubmitPending`.
+    pub kind: String,
+    pub ids: String,
+    /// When the worker entered the pending state.
+    pub since_t: u64,
+    pub pending_ms: u64,
+}
+
+/// Finds stuck workers, reporting each pending state only once.
+#[derive(Debug)]
+pub struct StuckDetector {
+    threshold_ms: u64,
+    /// Lifecycles reported stuck, by worker id and `ids`, and when their
+    /// pending state began.
+    reported: HashMap<(String, String), u64>,
+}
+
+impl StuckDetector {
+    pub fn new(threshold_ms: u64) -> Self {
+        Self {
+            threshold_ms,
+            reported: HashMap::new(),
+        }
+    }
+
+    /// Workers which became stuck since the last check, once per stuck
+    /// lifecycle of workers running several.
+    pub fn check(&mut self, stats: &WorkerStats, now: u64) -> Vec<StuckWorker> {
+        let mut stuck = vec![];
+        let mut reported = HashMap::new();
+        for worker_id in stats.keys() {
+            for state in stats.in_flight(worker_id) {
+                let pending = matches!(state.kind(), "WorkCreatePending" | "WorkSubmitPending");
+                let since_t = state.end_time();
+                let pending_ms = now.saturating_sub(since_t);
+                if !pending || pending_ms < self.threshold_ms {
+                    continue;
+                }
+                let ids = state.ids().unwrap_or_default().to_owned();
+                let key = (worker_id.clone(), ids.clone());
+                if self.reported.get(&key) != Some(&since_t) {
+                    stuck.push(StuckWorker {
+                        worker_id: worker_id.clone(),
+                        kind: state.kind().to_owned(),
+                        ids,
+                        since_t,
+                        pending_ms,
+                    });
+                }
+                reported.insert(key, since_t);
+            }
+        }
+        // lifecycles which moved on can be reported again.
+        self.reported = reported;
+        stuck
+    }
+}
@@ -0,0 +1,211 @@
+//! Lifecycle totals per worker and fleet-wide, so dashboards don't need
+//! to pull and recount every state.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{EventSeq, EvictedStates, SnarkWorkerState};
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct LifecycleTotals {
+    /// Job lifecycles started, i.e. every state but `Registered`.
+    pub attempted: u64,
+    pub succeeded: u64,
+    pub failed: FailedByPhase,
+    /// Job requests answered with `NoAvailableJob`.
+    pub no_available_job: u64,
+    /// Lifecycles which haven't finished yet.
+    pub in_progress: u64,
+    /// Mean time from requesting the job until the work was submitted, of
+    /// succeeded lifecycles.
+    pub avg_end_to_end_ms: Option<f64>,
+    /// Times workers numbering their events skipped numbers, see
+    /// [`EventSeq`].
+    #[serde(default)]
+    pub seq_gaps: u64,
+    /// Events presumably lost in transit, i.e. numbers skipped in total.
+    #[serde(default)]
+    pub events_lost: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct FailedByPhase {
+    pub job_get: u64,
+    pub work_create: u64,
+    pub work_submit: u64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Summary {
+    pub total: LifecycleTotals,
+    pub workers: BTreeMap<String, LifecycleTotals>,
+}
+
+impl LifecycleTotals {
+    fn add(&mut self, state: &SnarkWorkerState, e2e_sum: &mut u64) {
+        match state {
+            SnarkWorkerState::Registered { .. } | SnarkWorkerState::Restarted { .. } => return,
+            SnarkWorkerState::JobGetPending { .. }
+            | SnarkWorkerState::WorkCreatePending { .. }
+            | SnarkWorkerState::WorkSubmitPending { .. } => self.in_progress += 1,
+            SnarkWorkerState::JobUnavailable { .. } => self.no_available_job += 1,
+            SnarkWorkerState::JobGetError { .. } | SnarkWorkerState::JobGetTimeout { .. } => {
+                self.failed.job_get += state.count()
+            }
+            SnarkWorkerState::WorkCreateError { .. }
+            | SnarkWorkerState::WorkCreateTimeout { .. } => {
+                self.failed.work_create += state.count()
+            }
+            SnarkWorkerState::WorkSubmitError { .. }
+            | SnarkWorkerState::WorkSubmitTimeout { .. } => {
+                self.failed.work_submit += state.count()
+            }
+            SnarkWorkerState::WorkSubmitSuccess { .. } => {
+                self.succeeded += 1;
+                *e2e_sum += state.end_time().saturating_sub(state.start_time());
+            }
+        }
+        self.attempted += state.count();
+    }
+
+    /// Adds lifecycles evicted from the worker's history.
+    fn add_evicted(&mut self, evicted: &EvictedStates, e2e_sum: &mut u64) {
+        for (kind, &count) in &evicted.kinds {
+            match kind.as_str() {
+                "JobGetPending" | "WorkCreatePending" | "WorkSubmitPending" => {
+                    self.in_progress += count
+                }
+                "JobUnavailable" => self.no_available_job += count,
+                "JobGetError" | "JobGetTimeout" => self.failed.job_get += count,
+                "WorkCreateError" | "WorkCreateTimeout" => self.failed.work_create += count,
+                "WorkSubmitError" | "WorkSubmitTimeout" => self.failed.work_submit += count,
+                "WorkSubmitSuccess" => self.succeeded += count,
+                _ => continue,
+            }
+            self.attempted += count;
+        }
+        *e2e_sum += evicted.end_to_end_ms;
+    }
+
+    fn merge(&mut self, other: &Self) {
+        self.attempted += other.attempted;
+        self.succeeded += other.succeeded;
+        self.failed.job_get += other.failed.job_get;
+        self.failed.work_create += other.failed.work_create;
+        self.failed.work_submit += other.failed.work_submit;
+        self.no_available_job += other.no_available_job;
+        self.in_progress += other.in_progress;
+        self.seq_gaps += other.seq_gaps;
+        self.events_lost += other.events_lost;
+    }
+}
+
+/// Totals of `stats`, including the states `evicted` from them if those
+/// were folded, and gaps in the `seq`s of their events.
+pub fn summarize<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    evicted: impl Fn(&str) -> Option<&'a EvictedStates>,
+    seq: impl Fn(&str) -> Option<&'a EventSeq>,
+) -> Summary {
+    let mut summary = Summary::default();
+    let mut total_e2e_sum = 0;
+    for (worker_id, states) in stats {
+        let mut totals = LifecycleTotals::default();
+        let mut e2e_sum = 0;
+        for state in states {
+            totals.add(state, &mut e2e_sum);
+        }
+        if let Some(evicted) = evicted(worker_id) {
+            totals.add_evicted(evicted, &mut e2e_sum);
+        }
+        if let Some(seq) = seq(worker_id) {
+            totals.seq_gaps = seq.gaps;
+            totals.events_lost = seq.missing;
+        }
+        totals.avg_end_to_end_ms =
+            (totals.succeeded > 0).then(|| e2e_sum as f64 / totals.succeeded as f64);
+        summary.total.merge(&totals);
+        total_e2e_sum += e2e_sum;
+        summary.workers.insert(worker_id.clone(), totals);
+    }
+    let succeeded = summary.total.succeeded;
+    summary.total.avg_end_to_end_ms =
+        (succeeded > 0).then(|| total_e2e_sum as f64 / succeeded as f64);
+    summary
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(json: &str) -> SnarkWorkerState {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn succeeded(init_t: u64, end_t: u64) -> SnarkWorkerState {
+        state(&format!(
+            r#"{{"kind":"WorkSubmitSuccess","job_get_init_t":{init_t},"job_get_success_t":{init_t},
+                "work_create_success_t":{init_t},"work_submit_success_t":{end_t},"ids":"j"}}"#
+        ))
+    }
+
+    #[test]
+    fn totals_count_lifecycles_by_outcome() {
+        let w1 = VecDeque::from([
+            state(r#"{"kind":"Registered","registered_t":0}"#),
+            succeeded(0, 100),
+            state(
+                r#"{"kind":"JobGetError","job_get_init_t":100,"job_get_error_t":110,
+                    "error":{"kind":"Other","error":"boom"},"count":3}"#,
+            ),
+            state(r#"{"kind":"JobUnavailable","job_get_init_t":110,"job_get_success_t":120}"#),
+            state(r#"{"kind":"JobGetPending","job_get_init_t":120}"#),
+        ]);
+        let w2 = VecDeque::from([succeeded(0, 300)]);
+        let stats = [("w1".to_owned(), w1), ("w2".to_owned(), w2)];
+        let summary = summarize(stats.iter().map(|(k, v)| (k, v)), |_| None, |_| None);
+
+        let w1 = &summary.workers["w1"];
+        // collapsed errors count once per error, registering not at all.
+        assert_eq!((w1.attempted, w1.succeeded, w1.failed.job_get), (6, 1, 3));
+        assert_eq!((w1.no_available_job, w1.in_progress), (1, 1));
+        assert_eq!(w1.avg_end_to_end_ms, Some(100.0));
+
+        let total = &summary.total;
+        assert_eq!((total.attempted, total.succeeded), (7, 2));
+        assert_eq!(total.avg_end_to_end_ms, Some(200.0));
+    }
+
+    #[test]
+    fn totals_include_evicted_states_and_seq_gaps() {
+        let stats = [("w1".to_owned(), VecDeque::from([succeeded(0, 100)]))];
+        let evicted = EvictedStates {
+            states: 3,
+            kinds: BTreeMap::from([
+                ("WorkSubmitSuccess".to_owned(), 2),
+                ("WorkCreateTimeout".to_owned(), 1),
+            ]),
+            end_to_end_ms: 500,
+        };
+        let seq = EventSeq {
+            last: 10,
+            gaps: 2,
+            missing: 4,
+        };
+        let summary = summarize(
+            stats.iter().map(|(k, v)| (k, v)),
+            |_| Some(&evicted),
+            |_| Some(&seq),
+        );
+
+        let w1 = &summary.workers["w1"];
+        assert_eq!(
+            (w1.attempted, w1.succeeded, w1.failed.work_create),
+            (4, 3, 1)
+        );
+        assert_eq!(w1.avg_end_to_end_ms, Some(200.0));
+        assert_eq!((w1.seq_gaps, w1.events_lost), (2, 4));
+        assert_eq!(summary.total.events_lost, 4);
+    }
+}
@@ -0,0 +1,215 @@
+//! Synthetic fleet for `--synthetic-fleet`, which keeps the coordinator
+//! busy without any real workers, e.g. for developing dashboards against.
+//! The sequence of events each worker goes through only depends on the
+//! seed and the worker's index, their timing on how fast the coordinator
+//! applies them.
+
+use std::time::{Duration, Instant};
+
+use rand::{rngs::StdRng, Rng, SeedableRng};
+use snark_coordinator_rs::{
+    lock::JobLock,
+    stats::{SnarkWorkerJobGetError, SnarkWorkerStatsPut, WorkerMetadata},
+    timestamp,
+};
+use tracing::{info, warn};
+
+use crate::StatsIngest;
+
+/// How often due workers are advanced.
+const TICK: Duration = Duration::from_millis(100);
+/// TTL of the locks synthetic workers take on their jobs.
+const LOCK_TTL: Duration = Duration::from_secs(120);
+const JOB_CLASSES: &[&str] = &["base", "merge"];
+
+/// Where a synthetic worker is in its job lifecycle.
+#[derive(Debug, Clone, Copy)]
+enum Step {
+    JobGet,
+    WorkCreate,
+    WorkSubmit,
+}
+
+struct Worker {
+    id: String,
+    /// Index in the fleet, part of the job ids so they're unique.
+    index: usize,
+    rng: StdRng,
+    step: Step,
+    /// Number of jobs received so far.
+    jobs: u64,
+    ids: String,
+    next_at: Instant,
+}
+
+/// Registers `size` synthetic workers and runs their lifecycles forever.
+pub async fn run(ingest: StatsIngest, size: usize, seed: u64) {
+    let mut workers = Vec::with_capacity(size);
+    for i in 0..size {
+        let metadata = WorkerMetadata {
+            hostname: Some(format!("synthetic-host-{}", i / 4)),
+            datacenter: Some(format!("synthetic-dc-{}", i / 16)),
+            prover_version: Some("synthetic".to_owned()),
+            threads: Some(8),
+            fee: Some(1_000_000),
+            gpu: Some(i % 4 == 0),
+        };
+        let register = SnarkWorkerStatsPut::Register {
+            time: timestamp::now(),
+            metadata: Some(metadata),
+            resume: None,
+            seq: None,
+        };
+        let id = match ingest
+            .apply(&format!("synthetic-{i}"), register, None, None)
+            .await
+        {
+            Ok(applied) => applied.body,
+            Err(err) => {
+                warn!(%err, "synthetic worker failed to register");
+                continue;
+            }
+        };
+        let mut worker = Worker {
+            id,
+            index: i,
+            rng: StdRng::seed_from_u64(seed.wrapping_add(i as u64)),
+            step: Step::JobGet,
+            jobs: 0,
+            ids: String::new(),
+            next_at: Instant::now(),
+        };
+        job_get_init(&ingest, &mut worker).await;
+        workers.push(worker);
+    }
+    info!(workers = workers.len(), seed, "synthetic fleet started");
+
+    loop {
+        tokio::time::sleep(TICK).await;
+
+        let now = Instant::now();
+        for worker in workers.iter_mut().filter(|w| w.next_at <= now) {
+            advance(&ingest, worker).await;
+        }
+    }
+}
+
+async fn apply(ingest: &StatsIngest, worker: &Worker, req: SnarkWorkerStatsPut) {
+    if let Err(err) = ingest.apply(&worker.id, req, None, None).await {
+        warn!(worker_id = worker.id, %err, "synthetic event rejected");
+    }
+}
+
+/// Starts a new lifecycle, which gets a job after a short while.
+async fn job_get_init(ingest: &StatsIngest, worker: &mut Worker) {
+    let req = SnarkWorkerStatsPut::JobGetInit {
+        time: timestamp::now(),
+        seq: None,
+    };
+    apply(ingest, worker, req).await;
+    worker.step = Step::JobGet;
+    worker.next_at = Instant::now() + Duration::from_millis(worker.rng.gen_range(50..500));
+}
+
+/// Moves `worker` to the next state of its lifecycle. Each phase fails
+/// every now and then, job gets often find no job.
+async fn advance(ingest: &StatsIngest, worker: &mut Worker) {
+    let time = timestamp::now();
+    let rng = &mut worker.rng;
+    match worker.step {
+        Step::JobGet => {
+            let roll = rng.gen_range(0..100);
+            if roll < 15 {
+                let error = match roll {
+                    0..=2 => SnarkWorkerJobGetError::Other {
+                        error: "node request timed out".to_owned(),
+                    },
+                    _ => SnarkWorkerJobGetError::NoAvailableJob,
+                };
+                let req = SnarkWorkerStatsPut::JobGetError {
+                    time,
+                    job_get_node_received_t: None,
+                    job_get_node_request_work_init_t: None,
+                    job_get_node_request_work_success_t: None,
+                    error,
+                    seq: None,
+                };
+                apply(ingest, worker, req).await;
+                return job_get_init(ingest, worker).await;
+            }
+            let class = JOB_CLASSES[rng.gen_range(0..JOB_CLASSES.len())];
+            let proving_ms = match class {
+                "merge" => rng.gen_range(5_000..20_000),
+                _ => rng.gen_range(2_000..10_000),
+            };
+            worker.jobs += 1;
+            worker.ids = format!("{class}:{}-{}", worker.index, worker.jobs);
+            let now = Instant::now();
+            let lock = JobLock::new(now + LOCK_TTL, Some(worker.id.clone()));
+            let _ = ingest.kv.lock_key(&worker.ids).await.try_acquire_all(
+                vec![worker.ids.clone()],
+                lock,
+                now,
+            );
+            let req = SnarkWorkerStatsPut::JobGetSuccess {
+                time,
+                job_get_node_received_t: Some(time.saturating_sub(20)),
+                job_get_node_request_work_init_t: Some(time.saturating_sub(15)),
+                job_get_node_request_work_success_t: Some(time.saturating_sub(5)),
+                ids: worker.ids.clone(),
+                seq: None,
+            };
+            apply(ingest, worker, req).await;
+            worker.step = Step::WorkCreate;
+            worker.next_at = Instant::now() + Duration::from_millis(proving_ms);
+        }
+        Step::WorkCreate => {
+            let ids = worker.ids.clone();
+            if rng.gen_range(0..100) < 2 {
+                let error = "prover exited unexpectedly".to_owned();
+                let req = SnarkWorkerStatsPut::WorkCreateError {
+                    time,
+                    ids,
+                    error,
+                    seq: None,
+                };
+                apply(ingest, worker, req).await;
+                return job_get_init(ingest, worker).await;
+            }
+            let req = SnarkWorkerStatsPut::WorkCreateSuccess {
+                time,
+                ids,
+                seq: None,
+            };
+            apply(ingest, worker, req).await;
+            worker.step = Step::WorkSubmit;
+            let submit_ms = worker.rng.gen_range(100..1_000);
+            worker.next_at = Instant::now() + Duration::from_millis(submit_ms);
+        }
+        Step::WorkSubmit => {
+            let ids = worker.ids.clone();
+            let req = if rng.gen_range(0..100) < 2 {
+                SnarkWorkerStatsPut::WorkSubmitError {
+                    time,
+                    work_submit_node_received_t: Some(time.saturating_sub(30)),
+                    work_submit_node_add_work_init_t: None,
+                    work_submit_node_add_work_success_t: None,
+                    ids,
+                    error: "snark pool rejected work".to_owned(),
+                    seq: None,
+                }
+            } else {
+                SnarkWorkerStatsPut::WorkSubmitSuccess {
+                    time,
+                    work_submit_node_received_t: Some(time.saturating_sub(30)),
+                    work_submit_node_add_work_init_t: Some(time.saturating_sub(20)),
+                    work_submit_node_add_work_success_t: Some(time.saturating_sub(5)),
+                    ids,
+                    seq: None,
+                }
+            };
+            apply(ingest, worker, req).await;
+            job_get_init(ingest, worker).await;
+        }
+    }
+}
@@ -0,0 +1,275 @@
+//! Confines requests made with a tenant's API key to the tenant, see
+//! [`tenants`]: worker ids in their paths, `worker_id` and `workers`
+//! parameters and `X-Worker-Id` header are qualified with the tenant, their
+//! lock namespace is the tenant's, and its prefixes are stripped from JSON
+//! responses. Event streams and websocket messages are scoped to the
+//! tenant but keep the qualified ids. Fleet-wide reports are refused.
+
+use std::{convert::Infallible, sync::Arc};
+
+use futures_util::{stream, StreamExt};
+use snark_coordinator_rs::{
+    errors::ErrorCode,
+    lock_namespaces,
+    tenants::{self, JsonStripper, Tenants},
+};
+use warp::{
+    hyper::{
+        body::{to_bytes, Bytes, HttpBody},
+        header::{HeaderValue, AUTHORIZATION, CONTENT_LENGTH, CONTENT_TYPE},
+        service::Service,
+        Body, Method, Request, Response, StatusCode,
+    },
+    Reply,
+};
+
+use crate::{admin_port, error_reply, formats::Format, set_path_and_query};
+
+/// Routes whose second path segment is a worker id.
+const WORKER_ROUTES: &[&str] = &[
+    "worker-stats",
+    "worker-stats-stream",
+    "worker-heartbeat",
+    "worker-metrics",
+];
+
+/// Routes reporting on the whole fleet or all lock keys, which can't be
+/// narrowed to a tenant.
+const FLEET_ROUTES: &[&str] = &[
+    "annotations",
+    "anomalies",
+    "durations",
+    "lock-stats",
+    "lock-ttl",
+];
+
+/// Calls `svc` with `req`, confined to the tenant of its API key if it's
+/// a tenant's.
+pub async fn serve<S>(
+    mut svc: S,
+    tenants: Option<Arc<Tenants>>,
+    mut req: Request<Body>,
+) -> Response<Body>
+where
+    S: Service<Request<Body>, Response = Response<Body>, Error = Infallible>,
+{
+    let authorization = req.headers().get(AUTHORIZATION);
+    let authorization = authorization.and_then(|v| v.to_str().ok());
+    let tenant = tenants
+        .as_deref()
+        .and_then(|t| t.authenticate(authorization));
+    let Some(tenant) = tenant.map(str::to_owned) else {
+        return svc.call(req).await.unwrap_or_else(|err| match err {});
+    };
+    if let Some(res) = confine(&tenant, &mut req) {
+        return res;
+    }
+    // streamed in another format, responses couldn't be stripped.
+    req.extensions_mut().remove::<Format>();
+    let res = svc.call(req).await.unwrap_or_else(|err| match err {});
+    strip(&tenant, res).await
+}
+
+/// Rewrites `req` to the ids `tenant`'s are kept under. Returns the error
+/// response if it can't be confined to the tenant.
+fn confine(tenant: &str, req: &mut Request<Body>) -> Option<Response<Body>> {
+    let path = req.uri().path().to_owned();
+    let bulk_delete = req.method() == Method::DELETE && path == "/worker-stats";
+    let route = path.trim_start_matches('/').split('/').next();
+    let fleet_wide = route.is_some_and(|route| FLEET_ROUTES.contains(&route));
+    if admin_port::is_admin_path(&path) || route == Some("replication") || fleet_wide || bulk_delete
+    {
+        let msg = format!("not available to tenant {tenant}");
+        return Some(error_reply(ErrorCode::Forbidden, msg).into_response());
+    }
+    let headers = req.headers_mut();
+    if let Some(namespace) = headers.get(lock_namespaces::HEADER) {
+        if namespace.as_bytes() != tenant.as_bytes() {
+            let msg = format!("tenant {tenant} can only use lock namespace {tenant}");
+            return Some(error_reply(ErrorCode::Forbidden, msg).into_response());
+        }
+    }
+    // tenant names are valid lock namespace names, hence header values.
+    let namespace = HeaderValue::from_str(tenant).unwrap();
+    headers.insert(lock_namespaces::HEADER, namespace);
+    let worker_id = headers.get("x-worker-id").and_then(|v| v.to_str().ok());
+    if let Some(worker_id) = worker_id.map(|id| tenants::qualify(tenant, id)) {
+        if let Ok(worker_id) = HeaderValue::from_str(&worker_id) {
+            headers.insert("x-worker-id", worker_id);
+        }
+    }
+
+    let mut segments = path.split('/').map(str::to_owned).collect::<Vec<_>>();
+    if let [_, route, worker_id, ..] = segments.as_mut_slice() {
+        if WORKER_ROUTES.contains(&route.as_str()) && !worker_id.is_empty() {
+            *worker_id = tenants::qualify(tenant, worker_id);
+        }
+    }
+    let lists_locks = matches!(route, Some("lock-jobs" | "lock-history"));
+    let mut path_and_query = segments.join("/");
+    let query = req.uri().query().unwrap_or_default();
+    // malformed queries are left to the routes to reject.
+    let query = match serde_urlencoded::from_str::<Vec<(String, String)>>(query) {
+        Ok(mut params) => {
+            let namespace = params.iter().find(|(name, _)| name == "namespace");
+            if let Some((_, namespace)) = namespace.filter(|(_, ns)| ns != tenant) {
+                let msg = format!("tenant {tenant} can't list lock namespace {namespace}");
+                return Some(error_reply(ErrorCode::Forbidden, msg).into_response());
+            }
+            if lists_locks && namespace.is_none() {
+                params.push(("namespace".to_owned(), tenant.to_owned()));
+            }
+            for (name, value) in &mut params {
+                match name.as_str() {
+                    "worker_id" | "workers" if value.is_empty() => {}
+                    "worker_id" => *value = tenants::qualify(tenant, value),
+                    "workers" => {
+                        *value = value
+                            .split(',')
+                            .map(|id| tenants::qualify(tenant, id.trim()))
+                            .collect::<Vec<_>>()
+                            .join(",")
+                    }
+                    _ => {}
+                }
+            }
+            serde_urlencoded::to_string(params).unwrap_or_else(|_| query.to_owned())
+        }
+        Err(_) => query.to_owned(),
+    };
+    if !query.is_empty() {
+        path_and_query = format!("{path_and_query}?{query}");
+    }
+    set_path_and_query(req, path_and_query);
+    None
+}
+
+/// Strips `tenant`'s prefixes from a JSON response, as it's sent if it's
+/// streamed.
+async fn strip(tenant: &str, res: Response<Body>) -> Response<Body> {
+    // JSON is sent as text/plain by most routes.
+    let is_json = res.headers().get(CONTENT_TYPE).is_none_or(|v| {
+        v.as_bytes().starts_with(b"application/json") || v.as_bytes().starts_with(b"text/plain")
+    });
+    if !is_json || res.status() == StatusCode::SWITCHING_PROTOCOLS {
+        return res;
+    }
+    let (mut parts, body) = res.into_parts();
+    parts.headers.remove(CONTENT_LENGTH);
+    let mut stripper = JsonStripper::new(tenant);
+    if body.size_hint().exact().is_some() {
+        // kept buffered, so it can still be transcoded.
+        let mut body = match to_bytes(body).await {
+            Ok(body) => stripper.push(&body),
+            Err(err) => {
+                tracing::warn!(%err, "failed to read response body");
+                return Response::from_parts(parts, Body::empty());
+            }
+        };
+        body.extend(stripper.finish());
+        return Response::from_parts(parts, Body::from(body));
+    }
+    let body = stream::unfold(Some((body, stripper)), |state| async move {
+        let (mut body, mut stripper) = state?;
+        match body.next().await {
+            Some(Ok(chunk)) => Some((
+                Ok(Bytes::from(stripper.push(&chunk))),
+                Some((body, stripper)),
+            )),
+            Some(Err(err)) => Some((Err(err), None)),
+            None => Some((Ok(Bytes::from(stripper.finish())), None)),
+        }
+    });
+    Response::from_parts(parts, Body::wrap_stream(body))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = Request::builder().method(method).uri(uri);
+        for (name, value) in headers {
+            req = req.header(*name, *value);
+        }
+        req.body(Body::empty()).unwrap()
+    }
+
+    fn confined(method: Method, uri: &str, headers: &[(&str, &str)]) -> Request<Body> {
+        let mut req = request(method, uri, headers);
+        assert!(confine("acme", &mut req).is_none(), "{uri} refused");
+        req
+    }
+
+    fn refused(method: Method, uri: &str, headers: &[(&str, &str)]) -> StatusCode {
+        let mut req = request(method, uri, headers);
+        confine("acme", &mut req).expect("confined").status()
+    }
+
+    #[test]
+    fn worker_ids_are_qualified() {
+        let req = confined(Method::PUT, "/worker-stats/w1", &[("x-worker-id", "w1")]);
+        assert_eq!(req.uri(), "/worker-stats/acme:w1");
+        assert_eq!(req.headers()["x-worker-id"], "acme:w1");
+        assert_eq!(req.headers()[lock_namespaces::HEADER], "acme");
+
+        let req = confined(Method::GET, "/workers?workers=w1,w2&limit=5", &[]);
+        assert_eq!(req.uri(), "/workers?workers=acme%3Aw1%2Cacme%3Aw2&limit=5");
+        let req = confined(Method::PUT, "/lock-job/j1?worker_id=w1", &[]);
+        assert_eq!(req.uri(), "/lock-job/j1?worker_id=acme%3Aw1");
+    }
+
+    #[test]
+    fn lock_listings_are_scoped_to_the_namespace() {
+        let req = confined(Method::GET, "/lock-jobs", &[]);
+        assert_eq!(req.uri(), "/lock-jobs?namespace=acme");
+        let req = confined(Method::GET, "/lock-history?namespace=acme", &[]);
+        assert_eq!(req.uri(), "/lock-history?namespace=acme");
+        let status = refused(Method::GET, "/lock-jobs?namespace=globex", &[]);
+        assert_eq!(status, StatusCode::FORBIDDEN);
+        let status = refused(
+            Method::PUT,
+            "/lock-job/j1",
+            &[(lock_namespaces::HEADER, "globex")],
+        );
+        assert_eq!(status, StatusCode::FORBIDDEN);
+    }
+
+    #[test]
+    fn fleet_routes_are_refused() {
+        for (method, uri) in [
+            (Method::POST, "/admin/reset"),
+            (Method::GET, "/replication"),
+            (Method::DELETE, "/worker-stats"),
+            (Method::GET, "/lock-stats/hot"),
+            (Method::GET, "/durations"),
+            (Method::GET, "/anomalies/fleet"),
+        ] {
+            assert_eq!(refused(method, uri, &[]), StatusCode::FORBIDDEN, "{uri}");
+        }
+    }
+
+    #[tokio::test]
+    async fn responses_are_stripped_as_streamed() {
+        let chunks = ["[{\"worker_id\":\"ac", "me:w1\",\"ids\":\"acme/j1\"}]"];
+        let body = stream::iter(chunks.map(Ok::<_, std::io::Error>));
+        let res = Response::builder()
+            .header(CONTENT_TYPE, "application/json")
+            .header(CONTENT_LENGTH, "40")
+            .body(Body::wrap_stream(body))
+            .unwrap();
+        let res = strip("acme", res).await;
+        assert!(res.headers().get(CONTENT_LENGTH).is_none());
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"[{"worker_id":"w1","ids":"j1"}]"#);
+    }
+
+    #[tokio::test]
+    async fn buffered_responses_stay_buffered() {
+        let res = Response::new(Body::from(r#"{"acme:w1":[]}"#));
+        let res = strip("acme", res).await;
+        assert_eq!(res.body().size_hint().exact(), Some(9));
+        let body = warp::hyper::body::to_bytes(res.into_body()).await.unwrap();
+        assert_eq!(&body[..], br#"{"w1":[]}"#);
+    }
+}
@@ -0,0 +1,257 @@
+//! Tenants sharing a coordinator, loaded from `--tenants-file`. A tenant's
+//! API key confines its requests to the lock namespace named after it and
+//! to its own worker stats: its worker ids are kept prefixed with the
+//! tenant as `{tenant}:{worker_id}`, like keys of lock namespaces are, and
+//! both prefixes are stripped from its responses. Worker ids are taken from
+//! request paths, which can't hold a `/`, so `:` separates them.
+
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+use crate::lock_namespaces;
+
+/// Tenants by name, which is also their lock namespace.
+///
+/// ```json
+/// {
+///   "acme": { "api_key": "secret-acme", "max_keys": 1000, "max_workers": 50, "retention_ms": 86400000 }
+/// }
+/// ```
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+#[serde(transparent)]
+pub struct Tenants {
+    tenants: HashMap<String, Tenant>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct Tenant {
+    pub api_key: String,
+    /// Max number of locks held at once, the `max_keys` of its namespace.
+    #[serde(default)]
+    pub max_keys: Option<usize>,
+    /// Max number of workers with stats, sessions counting separately.
+    #[serde(default)]
+    pub max_workers: Option<usize>,
+    /// How long states are kept after they ended. `--stats-retention`
+    /// applies too.
+    #[serde(default)]
+    pub retention_ms: Option<u64>,
+}
+
+impl Tenants {
+    pub fn load(path: &str) -> Result<Self, String> {
+        let s = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        let tenants: Self = serde_json::from_str(&s).map_err(|err| format!("{path}: {err}"))?;
+        let mut keys = HashMap::new();
+        for (name, tenant) in &tenants.tenants {
+            match lock_namespaces::parse(Some(name)) {
+                Ok(Some(_)) => {}
+                Ok(None) => return Err(format!("{path}: tenant can't be named {name:?}")),
+                Err(err) => return Err(format!("{path}: {err}")),
+            }
+            if tenant.api_key.trim().is_empty() {
+                return Err(format!("{path}: tenant {name} has an empty api_key"));
+            }
+            if let Some(other) = keys.insert(tenant.api_key.trim(), name) {
+                return Err(format!(
+                    "{path}: tenants {other} and {name} share an api_key"
+                ));
+            }
+        }
+        Ok(tenants)
+    }
+
+    pub fn get(&self, name: &str) -> Option<&Tenant> {
+        self.tenants.get(name)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Tenant)> {
+        self.tenants.iter().map(|(name, t)| (name.as_str(), t))
+    }
+
+    /// Tenant presenting the `Authorization` header (`Bearer <key>`), if
+    /// the key is a tenant's.
+    pub fn authenticate(&self, authorization: Option<&str>) -> Option<&str> {
+        let key = authorization?;
+        let key = key.strip_prefix("Bearer ").unwrap_or(key).trim();
+        self.iter()
+            .find(|(_, tenant)| tenant.api_key.trim() == key)
+            .map(|(name, _)| name)
+    }
+
+    /// Error if adding the kept `worker_id` would exceed the `max_workers`
+    /// of its tenant, given the ids of the workers kept already.
+    pub fn check_new_worker<'a>(
+        &self,
+        worker_id: &str,
+        workers: impl IntoIterator<Item = &'a String>,
+    ) -> Result<(), String> {
+        let Some((name, tenant)) = self.of_worker(worker_id) else {
+            return Ok(());
+        };
+        let Some(max_workers) = tenant.max_workers else {
+            return Ok(());
+        };
+        let prefix = prefix(name);
+        let kept = workers.into_iter().filter(|id| id.starts_with(&prefix));
+        match kept.count() < max_workers {
+            true => Ok(()),
+            false => Err(format!("tenant {name} has max {max_workers} workers")),
+        }
+    }
+
+    /// Tenant owning the kept `worker_id`, if any.
+    pub fn of_worker(&self, worker_id: &str) -> Option<(&str, &Tenant)> {
+        let (name, _) = worker_id.split_once(SEPARATOR)?;
+        self.tenants
+            .get_key_value(name)
+            .map(|(n, t)| (n.as_str(), t))
+    }
+}
+
+/// Separates the tenant from the worker id in kept ids. Tenant names, being
+/// lock namespace names, can't contain it.
+pub const SEPARATOR: char = ':';
+
+/// Prefix of the worker ids kept for `tenant`.
+pub fn prefix(tenant: &str) -> String {
+    format!("{tenant}{SEPARATOR}")
+}
+
+/// Id `worker_id` of `tenant` is kept under.
+pub fn qualify(tenant: &str, worker_id: &str) -> String {
+    format!("{tenant}{SEPARATOR}{worker_id}")
+}
+
+/// Strips the prefixes of `tenant` from the strings and object keys of a
+/// JSON response, which hold its worker ids and lock keys, as it's sent.
+/// A string starts at a quote which isn't escaped, so the bytes are
+/// rewritten without parsing them, holding back only what may be the
+/// start of a prefix split between chunks.
+pub struct JsonStripper {
+    prefixes: Vec<Vec<u8>>,
+    /// Bytes from a quote on which may start a prefix.
+    pending: Vec<u8>,
+    /// Whether the byte before `pending` is escaped by a backslash.
+    escaped: bool,
+}
+
+impl JsonStripper {
+    pub fn new(tenant: &str) -> Self {
+        let mut prefixes = vec![prefix(tenant)];
+        prefixes.extend(lock_namespaces::prefix(tenant));
+        Self {
+            prefixes: prefixes.into_iter().map(String::into_bytes).collect(),
+            pending: vec![],
+            escaped: false,
+        }
+    }
+
+    /// Rewrites the next chunk of the response.
+    pub fn push(&mut self, chunk: &[u8]) -> Vec<u8> {
+        self.rewrite(chunk, false)
+    }
+
+    /// Bytes held back at the end of the response.
+    pub fn finish(&mut self) -> Vec<u8> {
+        self.rewrite(&[], true)
+    }
+
+    fn rewrite(&mut self, chunk: &[u8], last: bool) -> Vec<u8> {
+        let mut buf = std::mem::take(&mut self.pending);
+        buf.extend_from_slice(chunk);
+        let mut out = Vec::with_capacity(buf.len());
+        let mut i = 0;
+        while i < buf.len() {
+            let byte = buf[i];
+            if byte == b'"' && !self.escaped {
+                let rest = &buf[i + 1..];
+                if let Some(prefix) = self.prefixes.iter().find(|p| rest.starts_with(p)) {
+                    out.push(b'"');
+                    i += 1 + prefix.len();
+                    continue;
+                }
+                if !last && self.prefixes.iter().any(|p| p.starts_with(rest)) {
+                    self.pending = buf[i..].to_vec();
+                    return out;
+                }
+            }
+            self.escaped = byte == b'\\' && !self.escaped;
+            out.push(byte);
+            i += 1;
+        }
+        out
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tenants() -> Tenants {
+        serde_json::from_str(
+            r#"{
+                "acme": { "api_key": "k-acme", "max_workers": 2 },
+                "globex": { "api_key": "k-globex" }
+            }"#,
+        )
+        .unwrap()
+    }
+
+    /// `json` stripped for acme, pushed in two chunks split at `at`.
+    fn strip_split(json: &str, at: usize) -> String {
+        let mut stripper = JsonStripper::new("acme");
+        let mut out = stripper.push(&json.as_bytes()[..at]);
+        out.extend(stripper.push(&json.as_bytes()[at..]));
+        out.extend(stripper.finish());
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn prefixes_are_stripped_across_chunks() {
+        let json = r#"{"acme:w1":[{"ids":"acme/j1","holder":"acme:w1"}],"n":"acme"}"#;
+        let stripped = r#"{"w1":[{"ids":"j1","holder":"w1"}],"n":"acme"}"#;
+        for at in 0..=json.len() {
+            assert_eq!(strip_split(json, at), stripped, "split at {at}");
+        }
+    }
+
+    #[test]
+    fn only_string_starts_are_stripped() {
+        // inside strings, after escaped quotes, and other tenants' ids.
+        let json = r#"["x acme:w1","\"acme:w1","\\","acme:w1","globex:w1","acme:"]"#;
+        let stripped = r#"["x acme:w1","\"acme:w1","\\","w1","globex:w1",""]"#;
+        for at in 0..=json.len() {
+            assert_eq!(strip_split(json, at), stripped, "split at {at}");
+        }
+    }
+
+    #[test]
+    fn held_back_prefix_start_is_flushed() {
+        assert_eq!(strip_split(r#"["acm"#, 5), r#"["acm"#);
+    }
+
+    #[test]
+    fn keys_authenticate_their_tenant() {
+        let tenants = tenants();
+        assert_eq!(tenants.authenticate(Some("Bearer k-acme")), Some("acme"));
+        assert_eq!(tenants.authenticate(Some("k-globex")), Some("globex"));
+        assert_eq!(tenants.authenticate(Some("Bearer k-other")), None);
+        assert_eq!(tenants.authenticate(None), None);
+        assert_eq!(tenants.of_worker("acme:w1").unwrap().0, "acme");
+        assert!(tenants.of_worker("w1").is_none());
+    }
+
+    #[test]
+    fn workers_are_limited_per_tenant() {
+        let tenants = tenants();
+        let workers = ["acme:w1", "globex:w1", "globex:w2", "w1"].map(str::to_owned);
+        assert!(tenants.check_new_worker("acme:w2", &workers).is_ok());
+        let workers = ["acme:w1", "acme:w2"].map(str::to_owned);
+        assert!(tenants.check_new_worker("acme:w3", &workers).is_err());
+        // without a limit, or no tenant's.
+        assert!(tenants.check_new_worker("globex:w3", &workers).is_ok());
+        assert!(tenants.check_new_worker("w3", &workers).is_ok());
+    }
+}
@@ -0,0 +1,225 @@
+use std::{net::SocketAddr, str::FromStr, sync::Arc, time::Instant};
+
+use serde::Deserialize;
+use snark_coordinator_rs::{
+    errors::{ApiError, ErrorCode},
+    metrics::Metrics,
+    rate_limit::RateLimiter,
+};
+use tokio::sync::Mutex;
+use warp::{
+    hyper::{Method, StatusCode},
+    path::FullPath,
+    reject::{Reject, Rejection},
+    reply::{with_header, with_status, Reply, Response},
+    Filter,
+};
+
+use crate::auth;
+
+/// Peer address of the connection a request came in on, stored in the
+/// request extensions. Absent for unix socket connections.
+#[derive(Debug, Clone, Copy)]
+pub struct ClientAddr(pub SocketAddr);
+
+/// What mutating requests are rate limited by.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RateLimitBy {
+    WorkerId,
+    Ip,
+}
+
+impl FromStr for RateLimitBy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "worker_id" => Ok(Self::WorkerId),
+            "ip" => Ok(Self::Ip),
+            _ => Err(format!("expected `worker_id` or `ip`, found: {s}")),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Limited {
+    retry_after_s: u64,
+}
+
+impl Reject for Limited {}
+
+#[derive(Deserialize)]
+struct WorkerIdQuery {
+    worker_id: Option<String>,
+}
+
+/// Worker id a request is made for: the id in a worker-stats or
+/// worker-metrics path, or the lock holder from the `worker_id` parameter
+/// or `X-Worker-Id` header.
+pub(crate) fn worker_id(path: &str, query: Option<&str>, header: Option<String>) -> Option<String> {
+    let id = path
+        .strip_prefix("/worker-stats/")
+        .or_else(|| path.strip_prefix("/worker-metrics/"));
+    if let Some(id) = id {
+        return id.split('/').next().map(str::to_owned);
+    }
+    let query = query.and_then(|q| serde_urlencoded::from_str::<WorkerIdQuery>(q).ok());
+    query.and_then(|q| q.worker_id).or(header)
+}
+
+/// Rate limits writes per client, see [`auth::access_method`]. Requests without a
+/// worker id are limited by IP.
+pub fn filter(
+    limiter: Option<Arc<Mutex<RateLimiter>>>,
+    by: RateLimitBy,
+    metrics: Arc<Metrics>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::method()
+        .and(warp::path::full())
+        .and(
+            warp::query::raw()
+                .map(Some)
+                .or(warp::any().map(|| None))
+                .unify(),
+        )
+        .and(warp::header::optional::<String>("x-worker-id"))
+        .and(warp::ext::optional::<ClientAddr>())
+        .and_then(
+            move |method: Method,
+                  path: FullPath,
+                  query: Option<String>,
+                  header: Option<String>,
+                  addr: Option<ClientAddr>| {
+                let limiter = limiter.clone();
+                let metrics = metrics.clone();
+                async move {
+                    let Some(limiter) = limiter else {
+                        return Ok(());
+                    };
+                    let method = auth::access_method(method, path.as_str());
+                    if matches!(method, Method::GET | Method::HEAD | Method::OPTIONS) {
+                        return Ok(());
+                    }
+                    let worker_id = match by {
+                        RateLimitBy::WorkerId => worker_id(path.as_str(), query.as_deref(), header),
+                        RateLimitBy::Ip => None,
+                    };
+                    let client = match (worker_id, addr) {
+                        (Some(worker_id), _) => format!("worker:{worker_id}"),
+                        (None, Some(ClientAddr(addr))) => format!("ip:{}", addr.ip()),
+                        (None, None) => "ip:local".to_owned(),
+                    };
+                    let res = limiter.lock().await.check(&client, Instant::now());
+                    res.map_err(|retry_after| {
+                        tracing::debug!(%client, ?retry_after, "rate limited");
+                        metrics.rate_limited.inc();
+                        let retry_after_s = retry_after.as_secs_f64().ceil().max(1.0) as u64;
+                        warp::reject::custom(Limited { retry_after_s })
+                    })
+                }
+            },
+        )
+        .untuple_one()
+}
+
+/// Turns rejections of [`filter`] into 429 responses with `Retry-After`.
+pub async fn recover(rejection: Rejection) -> Result<Response, Rejection> {
+    let Some(Limited { retry_after_s }) = rejection.find::<Limited>() else {
+        return Err(rejection);
+    };
+    let body = ApiError::new(ErrorCode::RateLimited, "rate limited");
+    let reply = with_status(
+        serde_json::to_string(&body).unwrap(),
+        StatusCode::TOO_MANY_REQUESTS,
+    );
+    Ok(with_header(reply, "retry-after", retry_after_s.to_string()).into_response())
+}
+
+#[cfg(test)]
+mod tests {
+    use snark_coordinator_rs::job_pool;
+
+    use super::*;
+
+    fn route(by: RateLimitBy) -> impl Filter<Extract = (Response,), Error = Rejection> + Clone {
+        // one request, refilled in a minute.
+        let limiter = Arc::new(Mutex::new(RateLimiter::new(1.0 / 60.0, 1.0)));
+        filter(Some(limiter), by, Arc::new(Metrics::new()))
+            .map(|| "served".into_response())
+            .recover(recover)
+            .unify()
+    }
+
+    async fn status(
+        route: &(impl Filter<Extract = (Response,), Error = Rejection> + Clone + 'static),
+        method: &str,
+        path: &str,
+    ) -> StatusCode {
+        let req = warp::test::request().method(method).path(path);
+        req.reply(route).await.status()
+    }
+
+    #[test]
+    fn worker_ids_come_from_the_path_query_or_header() {
+        let header = || Some("h".to_owned());
+        assert_eq!(
+            worker_id("/worker-stats/w/batch", Some("worker_id=q"), header()),
+            Some("w".to_owned())
+        );
+        assert_eq!(
+            worker_id("/worker-metrics/w", None, None),
+            Some("w".to_owned())
+        );
+        assert_eq!(
+            worker_id("/lock-job/j1", Some("worker_id=q"), header()),
+            Some("q".to_owned())
+        );
+        assert_eq!(
+            worker_id("/lock-job/j1", Some("timeout=1"), header()),
+            Some("h".to_owned())
+        );
+        assert_eq!(worker_id("/lock-job/j1", None, None), None);
+    }
+
+    #[tokio::test]
+    async fn writes_are_limited_per_worker() {
+        let route = route(RateLimitBy::WorkerId);
+        assert_eq!(
+            status(&route, "PUT", "/worker-stats/a").await,
+            StatusCode::OK
+        );
+        let req = warp::test::request().method("PUT").path("/worker-stats/a");
+        let res = req.reply(&route).await;
+        assert_eq!(res.status(), StatusCode::TOO_MANY_REQUESTS);
+        assert_eq!(res.headers()["retry-after"], "60");
+
+        // other workers and reads have their own budget.
+        assert_eq!(
+            status(&route, "PUT", "/worker-stats/b").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&route, "GET", "/worker-stats/a").await,
+            StatusCode::OK
+        );
+        // handing out a job is a write.
+        let job = format!("{}?worker_id=a", job_pool::PATH);
+        assert_eq!(
+            status(&route, "GET", &job).await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+
+    #[tokio::test]
+    async fn writes_are_limited_per_ip() {
+        let route = route(RateLimitBy::Ip);
+        assert_eq!(
+            status(&route, "PUT", "/worker-stats/a").await,
+            StatusCode::OK
+        );
+        assert_eq!(
+            status(&route, "PUT", "/worker-stats/b").await,
+            StatusCode::TOO_MANY_REQUESTS
+        );
+    }
+}
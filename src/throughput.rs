@@ -0,0 +1,146 @@
+//! Completed proofs and errors per time bucket, for charting prover
+//! throughput.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::SnarkWorkerState;
+
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ThroughputBucket {
+    pub start_t: u64,
+    /// Lifecycles ending with `WorkSubmitSuccess`.
+    pub completed: u64,
+    /// Lifecycles ending with an error in any phase.
+    pub errors: u64,
+}
+
+/// Buckets with neither completions nor errors are left out.
+#[derive(Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ThroughputReport {
+    pub bucket_ms: u64,
+    pub total: Vec<ThroughputBucket>,
+    pub workers: BTreeMap<String, Vec<ThroughputBucket>>,
+}
+
+#[derive(Default)]
+struct Buckets(BTreeMap<u64, ThroughputBucket>);
+
+impl Buckets {
+    fn add(&mut self, start_t: u64, completed: bool, count: u64) {
+        let bucket = self.0.entry(start_t).or_insert_with(|| ThroughputBucket {
+            start_t,
+            ..ThroughputBucket::default()
+        });
+        if completed {
+            bucket.completed += count;
+        } else {
+            bucket.errors += count;
+        }
+    }
+
+    fn finish(self) -> Vec<ThroughputBucket> {
+        self.0.into_values().collect()
+    }
+}
+
+/// Buckets lifecycles by their end, keeping those ending within
+/// `from_t..=to_t`.
+pub fn report<'a>(
+    stats: impl IntoIterator<Item = (&'a String, &'a VecDeque<SnarkWorkerState>)>,
+    bucket_ms: u64,
+    from_t: Option<u64>,
+    to_t: Option<u64>,
+) -> ThroughputReport {
+    let bucket_ms = bucket_ms.max(1);
+    let mut total = Buckets::default();
+    let mut workers = BTreeMap::<&String, Buckets>::new();
+    for (worker_id, states) in stats {
+        for state in states {
+            let completed = match state {
+                SnarkWorkerState::WorkSubmitSuccess { .. } => true,
+                SnarkWorkerState::JobGetError { .. }
+                | SnarkWorkerState::WorkCreateError { .. }
+                | SnarkWorkerState::WorkSubmitError { .. }
+                | SnarkWorkerState::JobGetTimeout { .. }
+                | SnarkWorkerState::WorkCreateTimeout { .. }
+                | SnarkWorkerState::WorkSubmitTimeout { .. } => false,
+                _ => continue,
+            };
+            let end_t = state.end_time();
+            if from_t.is_some_and(|t| end_t < t) || to_t.is_some_and(|t| end_t > t) {
+                continue;
+            }
+            let start_t = end_t - end_t % bucket_ms;
+            total.add(start_t, completed, state.count());
+            workers
+                .entry(worker_id)
+                .or_default()
+                .add(start_t, completed, state.count());
+        }
+    }
+    ThroughputReport {
+        bucket_ms,
+        total: total.finish(),
+        workers: workers
+            .into_iter()
+            .map(|(id, buckets)| (id.clone(), buckets.finish()))
+            .collect(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(json: &str) -> SnarkWorkerState {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn succeeded(end_t: u64) -> SnarkWorkerState {
+        state(&format!(
+            r#"{{"kind":"WorkSubmitSuccess","job_get_init_t":0,"job_get_success_t":0,
+                "work_create_success_t":0,"work_submit_success_t":{end_t},"ids":"j"}}"#
+        ))
+    }
+
+    #[test]
+    fn buckets_lifecycles_by_their_end() {
+        let w1 = VecDeque::from([
+            state(r#"{"kind":"Registered","registered_t":0}"#),
+            succeeded(100),
+            succeeded(999),
+            state(
+                r#"{"kind":"JobGetError","job_get_init_t":1000,"job_get_error_t":1500,
+                    "error":{"kind":"Other","error":"boom"},"count":3}"#,
+            ),
+            state(r#"{"kind":"JobGetTimeout","job_get_init_t":1500,"timed_out_t":3500}"#),
+            state(r#"{"kind":"JobGetPending","job_get_init_t":4000}"#),
+        ]);
+        let w2 = VecDeque::from([succeeded(1200), succeeded(5000)]);
+        let stats = [("w1".to_owned(), w1), ("w2".to_owned(), w2)];
+        let stats = || stats.iter().map(|(k, v)| (k, v));
+        let by_second = report(stats(), 1000, None, Some(4000));
+
+        let buckets = |buckets: &[ThroughputBucket]| {
+            (buckets.iter())
+                .map(|b| (b.start_t, b.completed, b.errors))
+                .collect::<Vec<_>>()
+        };
+        // collapsed errors count once per error, empty buckets are left
+        // out and so is w2's lifecycle ending past `to_t`.
+        assert_eq!(
+            buckets(&by_second.total),
+            [(0, 2, 0), (1000, 1, 3), (3000, 0, 1)]
+        );
+        assert_eq!(buckets(&by_second.workers["w2"]), [(1000, 1, 0)]);
+
+        // buckets are at least a millisecond long.
+        let by_ms = report(stats(), 0, Some(100), Some(100));
+        assert_eq!(
+            (by_ms.bucket_ms, buckets(&by_ms.total)),
+            (1, vec![(100, 1, 0)])
+        );
+    }
+}
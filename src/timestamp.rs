@@ -0,0 +1,50 @@
+//! Timestamps are unix milliseconds. Older workers report unix seconds,
+//! which are told apart by magnitude and converted on deserialization.
+
+use std::time::{Instant, SystemTime, UNIX_EPOCH};
+
+use serde::{Deserialize, Deserializer};
+
+/// Values below this are taken to be seconds. As milliseconds it would
+/// be March 1973, as seconds it's year 5138.
+const SECONDS_THRESHOLD: u64 = 100_000_000_000;
+
+/// Converts a timestamp in either seconds or milliseconds to milliseconds.
+pub fn normalize(t: u64) -> u64 {
+    if t < SECONDS_THRESHOLD {
+        t.saturating_mul(1000)
+    } else {
+        t
+    }
+}
+
+pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+    u64::deserialize(deserializer).map(normalize)
+}
+
+pub mod option {
+    use super::*;
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<u64>, D::Error> {
+        Option::<u64>::deserialize(deserializer).map(|t| t.map(normalize))
+    }
+}
+
+/// Current unix time in milliseconds.
+pub fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
+
+/// Converts a monotonic instant into unix milliseconds.
+pub fn from_instant(at: Instant) -> u64 {
+    let (unix_now, now) = (self::now(), Instant::now());
+    match at.checked_duration_since(now) {
+        Some(ahead) => unix_now + ahead.as_millis() as u64,
+        None => unix_now.saturating_sub(now.duration_since(at).as_millis() as u64),
+    }
+}
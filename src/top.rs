@@ -0,0 +1,304 @@
+//! Most frequent error classes and slowest jobs, maintained as events
+//! arrive so queries take the same time however long the history is.
+//!
+//! Error classes are counted in a count-min sketch, with a bounded set of
+//! candidates for the top classes. Slowest jobs are kept in a bounded
+//! min-heap, which is exact for the top [`MAX_K`].
+
+use std::{
+    cmp::Reverse,
+    collections::{hash_map::DefaultHasher, BinaryHeap, HashMap},
+    hash::{Hash, Hasher},
+};
+
+use serde::{Deserialize, Serialize};
+
+use crate::stats::{SnarkWorkerJobGetError, SnarkWorkerState};
+
+/// Largest `k` which can be queried.
+pub const MAX_K: usize = 100;
+/// Candidates tracked for the top error classes, a few times [`MAX_K`]
+/// so classes on the verge of the top don't get evicted too early.
+const MAX_CANDIDATES: usize = 4 * MAX_K;
+const SKETCH_DEPTH: usize = 4;
+const SKETCH_WIDTH: usize = 2048;
+/// Error messages are cut to this many chars before classifying.
+const MAX_CLASS_LEN: usize = 200;
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct ErrorClassCount {
+    /// `job_get`, `work_create` or `work_submit`.
+    pub phase: String,
+    /// Error message with numbers replaced by `#`.
+    pub class: String,
+    /// Estimated number of errors, never less than the actual one.
+    pub count: u64,
+    /// Latest message of the class.
+    pub example: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+pub struct SlowJob {
+    pub duration_ms: u64,
+    pub work_submit_success_t: u64,
+    pub worker_id: String,
+    pub ids: String,
+}
+
+#[derive(Debug)]
+struct CountMin {
+    counters: Vec<u64>,
+}
+
+impl Default for CountMin {
+    fn default() -> Self {
+        Self {
+            counters: vec![0; SKETCH_DEPTH * SKETCH_WIDTH],
+        }
+    }
+}
+
+impl CountMin {
+    /// Counts `key` and returns its estimated count.
+    fn add(&mut self, key: &str) -> u64 {
+        let mut estimate = u64::MAX;
+        for row in 0..SKETCH_DEPTH {
+            let mut hasher = DefaultHasher::new();
+            (row, key).hash(&mut hasher);
+            let i = row * SKETCH_WIDTH + (hasher.finish() as usize % SKETCH_WIDTH);
+            self.counters[i] += 1;
+            estimate = estimate.min(self.counters[i]);
+        }
+        estimate
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct TopK {
+    errors: CountMin,
+    candidates: HashMap<(&'static str, String), ErrorClassCount>,
+    slow_jobs: BinaryHeap<Reverse<SlowJob>>,
+}
+
+/// Class of an error message, which groups messages differing only in
+/// numbers, e.g. heights or ids.
+pub fn error_class(message: &str) -> String {
+    let mut class = String::with_capacity(message.len().min(MAX_CLASS_LEN));
+    for c in message.chars().take(MAX_CLASS_LEN) {
+        if !c.is_ascii_digit() {
+            class.push(c);
+        } else if !class.ends_with('#') {
+            class.push('#');
+        }
+    }
+    class
+}
+
+impl TopK {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Records a state right after it changed.
+    pub fn observe(&mut self, worker_id: &str, state: &SnarkWorkerState) {
+        let (phase, message) = match state {
+            SnarkWorkerState::JobGetError {
+                error: SnarkWorkerJobGetError::Other { error },
+                ..
+            } => ("job_get", error),
+            SnarkWorkerState::WorkCreateError { error, .. } => ("work_create", error),
+            SnarkWorkerState::WorkSubmitError { error, .. } => ("work_submit", error),
+            SnarkWorkerState::WorkSubmitSuccess {
+                work_submit_success_t,
+                ids,
+                ..
+            } => {
+                let Some(duration_ms) = state.job_duration() else {
+                    return;
+                };
+                self.observe_job(SlowJob {
+                    duration_ms,
+                    work_submit_success_t: *work_submit_success_t,
+                    worker_id: worker_id.to_owned(),
+                    ids: ids.clone(),
+                });
+                return;
+            }
+            _ => return,
+        };
+        let class = error_class(message);
+        let count = self.errors.add(&format!("{phase}:{class}"));
+        let key = (phase, class);
+        if let Some(candidate) = self.candidates.get_mut(&key) {
+            candidate.count = count;
+            candidate.example.clone_from(message);
+            return;
+        }
+        if self.candidates.len() >= MAX_CANDIDATES {
+            let min = self
+                .candidates
+                .iter()
+                .min_by_key(|(_, c)| c.count)
+                .map(|(key, c)| (key.clone(), c.count));
+            match min {
+                Some((min_key, min_count)) if min_count < count => {
+                    self.candidates.remove(&min_key);
+                }
+                _ => return,
+            }
+        }
+        let candidate = ErrorClassCount {
+            phase: phase.to_owned(),
+            class: key.1.clone(),
+            count,
+            example: message.clone(),
+        };
+        self.candidates.insert(key, candidate);
+    }
+
+    fn observe_job(&mut self, job: SlowJob) {
+        if self.slow_jobs.len() < MAX_K {
+            self.slow_jobs.push(Reverse(job));
+        } else if self
+            .slow_jobs
+            .peek()
+            .is_some_and(|Reverse(fastest)| *fastest < job)
+        {
+            self.slow_jobs.pop();
+            self.slow_jobs.push(Reverse(job));
+        }
+    }
+
+    /// Most frequent error classes, most frequent first.
+    pub fn top_errors(&self, k: usize) -> Vec<&ErrorClassCount> {
+        let mut top = self.candidates.values().collect::<Vec<_>>();
+        top.sort_unstable_by(|a, b| b.count.cmp(&a.count).then_with(|| a.class.cmp(&b.class)));
+        top.truncate(k.min(MAX_K));
+        top
+    }
+
+    /// Slowest successful jobs matching `filter`, slowest first.
+    pub fn slowest_jobs(&self, k: usize, filter: impl Fn(&SlowJob) -> bool) -> Vec<&SlowJob> {
+        let mut top = self
+            .slow_jobs
+            .iter()
+            .map(|Reverse(job)| job)
+            .filter(|job| filter(job))
+            .collect::<Vec<_>>();
+        top.sort_unstable_by(|a, b| b.cmp(a));
+        top.truncate(k.min(MAX_K));
+        top
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn state(json: &str) -> SnarkWorkerState {
+        serde_json::from_str(json).unwrap()
+    }
+
+    fn create_error(message: &str) -> SnarkWorkerState {
+        state(&format!(
+            r#"{{"kind":"WorkCreateError","job_get_init_t":0,"job_get_success_t":0,
+                "work_create_error_t":1,"ids":"j","error":"{message}"}}"#
+        ))
+    }
+
+    fn succeeded(ids: u64, duration_ms: u64) -> SnarkWorkerState {
+        state(&format!(
+            r#"{{"kind":"WorkSubmitSuccess","job_get_init_t":0,"job_get_success_t":10,
+                "work_create_success_t":10,"work_submit_success_t":{},"ids":"{ids}"}}"#,
+            10 + duration_ms
+        ))
+    }
+
+    /// Name of the `i`th error class, spelled in letters since digits
+    /// don't tell classes apart.
+    fn class_name(i: usize) -> String {
+        i.to_string()
+            .bytes()
+            .map(|d| (b'a' + d - b'0') as char)
+            .collect()
+    }
+
+    #[test]
+    fn classes_group_messages_differing_in_numbers() {
+        assert_eq!(
+            error_class("no job at height 123, slot 7"),
+            "no job at height #, slot #"
+        );
+        assert_eq!(error_class(&"x".repeat(500)).len(), MAX_CLASS_LEN);
+    }
+
+    #[test]
+    fn sketch_never_underestimates_and_keeps_the_top_classes() {
+        // class `i` of many more than the candidates fails 5000 / (i + 1)
+        // times, interleaved so the top classes don't come first.
+        let classes = 4 * MAX_CANDIDATES;
+        let actual = (0..classes)
+            .map(|i| 5000 / (i as u64 + 1))
+            .collect::<Vec<_>>();
+        let total = actual.iter().sum::<u64>();
+        let mut top = TopK::new();
+        for round in 0..actual[0] {
+            for (i, &count) in actual.iter().enumerate().rev() {
+                if round < count {
+                    let message = format!("{} at height {round}", class_name(i));
+                    top.observe("w1", &create_error(&message));
+                }
+            }
+        }
+
+        let top_errors = top.top_errors(MAX_K);
+        assert_eq!(top_errors.len(), MAX_K);
+        // count-min overestimates by at most e * total / width, with a
+        // probability of 1 - e^-depth.
+        let bound = (std::f64::consts::E * total as f64 / SKETCH_WIDTH as f64) as u64;
+        for error in &top_errors {
+            assert_eq!(error.phase, "work_create");
+            let i = (0..classes)
+                .find(|&i| error.class == format!("{} at height #", class_name(i)))
+                .unwrap();
+            assert!(
+                error.count >= actual[i],
+                "{error:?} undercounts {}",
+                actual[i]
+            );
+            assert!(
+                error.count - actual[i] <= bound,
+                "{error:?} overcounts {}",
+                actual[i]
+            );
+        }
+        // classes further apart than the bound keep their order.
+        for (i, error) in top_errors.iter().take(8).enumerate() {
+            assert_eq!(error.class, format!("{} at height #", class_name(i)));
+        }
+        assert_eq!(
+            top_errors[0].example,
+            format!("{} at height 4999", class_name(0))
+        );
+    }
+
+    #[test]
+    fn slowest_jobs_are_exact() {
+        let mut top = TopK::new();
+        // durations in a scrambled order, each once.
+        for i in 0..1000u64 {
+            top.observe("w1", &succeeded(i, i * 7919 % 1000));
+        }
+        top.observe("w1", &create_error("not a job"));
+
+        let slowest = top.slowest_jobs(5, |_| true);
+        let durations = slowest.iter().map(|j| j.duration_ms).collect::<Vec<_>>();
+        assert_eq!(durations, [999, 998, 997, 996, 995]);
+        assert_eq!(top.slowest_jobs(usize::MAX, |_| true).len(), MAX_K);
+        let odd = top.slowest_jobs(2, |j| j.duration_ms % 2 == 1);
+        assert_eq!(
+            odd.iter().map(|j| j.duration_ms).collect::<Vec<_>>(),
+            [999, 997]
+        );
+    }
+}
@@ -0,0 +1,108 @@
+use std::sync::{Arc, RwLock};
+
+use minijinja::{Environment, Value};
+use serde::Serialize;
+
+const TEMPLATE_NAME: &str = "webhook";
+/// Template formatting notifications as Slack incoming webhook messages.
+const SLACK_TEMPLATE: &str = r#"{%- if event == "fleet_anomaly" -%}
+{"text": {{ (":warning: fleet anomaly in " ~ anomaly.series ~ ": " ~ anomaly.value ~ " (mean " ~ anomaly.mean ~ ", z-score " ~ anomaly.z_score ~ ")") | tojson }}}
+{%- elif event == "worker_error" -%}
+{"text": {{ (":red_circle: " ~ failure.worker_id ~ " hit " ~ failure.kind ~ (" on " ~ failure.ids if failure.ids else "") ~ ": " ~ failure.error | tojson) | tojson }}}
+{%- elif event == "lock_key_hot" -%}
+{"text": {{ (":fire: lock key " ~ hot_key.key ~ " is hot, " ~ hot_key.conflicts ~ " refused attempts to " ~ hot_key.acquisitions ~ " acquisitions: " ~ hot_key.suggestion) | tojson }}}
+{%- elif event == "worker_stuck" -%}
+{"text": {{ (":hourglass: " ~ stuck.worker_id ~ " has been in " ~ stuck.kind ~ " for " ~ (stuck.pending_ms // 1000) ~ "s on " ~ stuck.ids) | tojson }}}
+{%- else -%}
+{"text": {{ event | tojson }}}
+{%- endif -%}"#;
+
+/// Posts JSON notifications to an operator-provided URL.
+#[derive(Debug, Clone)]
+pub struct Webhook {
+    client: reqwest::Client,
+    /// Shared by clones, so [`Webhook::set_url`] applies to all of them.
+    url: Arc<RwLock<String>>,
+    /// Renders the request body from the notification, see
+    /// [`Webhook::with_template`].
+    template: Option<Arc<Environment<'static>>>,
+}
+
+impl Webhook {
+    pub fn new(url: String) -> Self {
+        Self {
+            client: reqwest::Client::new(),
+            url: Arc::new(RwLock::new(url)),
+            template: None,
+        }
+    }
+
+    pub fn url(&self) -> String {
+        self.url.read().unwrap().clone()
+    }
+
+    /// Sends later notifications to `url`, from this and cloned webhooks.
+    pub fn set_url(&self, url: String) {
+        *self.url.write().unwrap() = url;
+    }
+
+    /// Formats notifications with the minijinja template at `path`, e.g.
+    /// for Slack or Discord payloads. The notification's fields, like
+    /// `event` and `anomaly`, are the template's context. Output which is
+    /// valid JSON is sent as JSON, anything else as plain text.
+    pub fn with_template(self, path: &str) -> Result<Self, String> {
+        let source = std::fs::read_to_string(path).map_err(|err| format!("{path}: {err}"))?;
+        self.with_template_source(source)
+            .map_err(|err| format!("{path}: {err}"))
+    }
+
+    /// Formats notifications as Slack messages, with a `text` summing up
+    /// the event.
+    pub fn slack(self) -> Self {
+        self.with_template_source(SLACK_TEMPLATE.to_owned())
+            .expect("slack template is valid")
+    }
+
+    fn with_template_source(mut self, source: String) -> Result<Self, String> {
+        let mut env = Environment::new();
+        env.add_template_owned(TEMPLATE_NAME, source)
+            .map_err(|err| err.to_string())?;
+        self.template = Some(Arc::new(env));
+        Ok(self)
+    }
+
+    /// Sends `payload` in the background. Failures are only logged.
+    pub fn send<T: Serialize>(&self, payload: &T) {
+        let url = self.url();
+        let req = self.client.post(&url);
+        let req = match self.render(payload) {
+            Some(body) if serde_json::from_str::<serde::de::IgnoredAny>(&body).is_ok() => {
+                req.header("content-type", "application/json").body(body)
+            }
+            Some(body) => req.header("content-type", "text/plain").body(body),
+            None => req.json(payload),
+        };
+        tokio::spawn(async move {
+            let res = req.send().await.and_then(|res| res.error_for_status());
+            if let Err(err) = res {
+                tracing::warn!(%url, %err, "webhook failed");
+            }
+        });
+    }
+
+    /// Templated body, `None` without a template or if rendering failed,
+    /// in which case the plain JSON payload is sent instead.
+    fn render<T: Serialize>(&self, payload: &T) -> Option<String> {
+        let env = self.template.as_ref()?;
+        let res = env
+            .get_template(TEMPLATE_NAME)
+            .and_then(|t| t.render(Value::from_serialize(payload)));
+        match res {
+            Ok(body) => Some(body),
+            Err(err) => {
+                tracing::warn!(%err, "webhook template failed, sending plain payload");
+                None
+            }
+        }
+    }
+}
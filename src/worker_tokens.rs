@@ -0,0 +1,64 @@
+use std::collections::HashMap;
+
+use rand::RngCore;
+
+use crate::api_keys::AuthError;
+
+/// Header carrying the session token of a worker.
+pub const HEADER: &str = "x-worker-token";
+
+/// Random session tokens handed out on registration. Once a worker id
+/// has a token, stats for that id are only accepted along with it.
+#[derive(Debug, Default)]
+pub struct WorkerTokens {
+    tokens: HashMap<String, String>,
+}
+
+impl WorkerTokens {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a token for a newly registered worker id.
+    pub fn issue(&mut self, worker_id: &str) -> String {
+        let mut bytes = [0u8; 32];
+        rand::thread_rng().fill_bytes(&mut bytes);
+        let token = bytes.iter().map(|b| format!("{b:02x}")).collect::<String>();
+        self.tokens.insert(worker_id.to_owned(), token.clone());
+        token
+    }
+
+    /// Session id the token was issued to, if it's still valid.
+    pub fn session(&self, token: &str) -> Option<&str> {
+        self.tokens
+            .iter()
+            .find(|(_, expected)| constant_time_eq(expected.as_bytes(), token.trim().as_bytes()))
+            .map(|(worker_id, _)| worker_id.as_str())
+    }
+
+    /// Invalidates the token of a deregistered worker.
+    pub fn revoke(&mut self, worker_id: &str) {
+        self.tokens.remove(worker_id);
+    }
+
+    pub fn verify(&self, worker_id: &str, token: Option<&str>) -> Result<(), AuthError> {
+        let Some(token) = token else {
+            return Err(AuthError::Unauthorized("missing worker token".to_owned()));
+        };
+        let Some(expected) = self.tokens.get(worker_id) else {
+            return Err(AuthError::Forbidden(format!(
+                "worker {worker_id:?} isn't registered"
+            )));
+        };
+        if !constant_time_eq(expected.as_bytes(), token.trim().as_bytes()) {
+            return Err(AuthError::Forbidden(format!(
+                "invalid token for worker {worker_id:?}"
+            )));
+        }
+        Ok(())
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (a, b)| acc | (a ^ b)) == 0
+}
@@ -0,0 +1,573 @@
+//! Round-trips through the coordinator binary's other protocols: a
+//! worker reporting and locking over gRPC, and reading the results back
+//! over GraphQL, so they're checked against the routes actually serving
+//! them rather than the translation alone. A worker following the
+//! OpenAPI document is checked to only get the responses it documents,
+//! and worker stats to be compressed as negotiated.
+//!
+//! Run with `cargo test --test protocols`.
+
+use std::{
+    io::Read,
+    net::{SocketAddr, TcpListener},
+    process::Stdio,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use serde_json::{json, Value};
+use snark_coordinator_rs::{
+    batch::BatchResponse,
+    errors::ApiError,
+    handshake::HandshakeResponse,
+    lock::{LockJobGranted, LockJobHeld, LockJobsConflict},
+    stats::SnarkWorkerState,
+};
+use tokio::process::{Child, Command};
+
+mod proto {
+    tonic::include_proto!("snark_coordinator.v1");
+}
+
+use proto::{
+    coordinator_client::CoordinatorClient, lock_job_response, LockJobRequest,
+    PutWorkerStatsRequest, ReleaseJobRequest, WatchStatsRequest,
+};
+
+/// How long the coordinator gets to start listening, and events to be
+/// applied.
+const DEADLINE: Duration = Duration::from_secs(10);
+
+/// A coordinator started from the built binary, killed when dropped.
+struct Coordinator {
+    _process: Child,
+    url: String,
+    grpc_addr: SocketAddr,
+    client: reqwest::Client,
+}
+
+fn free_addr() -> SocketAddr {
+    TcpListener::bind("127.0.0.1:0")
+        .unwrap()
+        .local_addr()
+        .unwrap()
+}
+
+fn now_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap()
+        .as_millis() as u64
+}
+
+impl Coordinator {
+    async fn start() -> Self {
+        let (addr, grpc_addr) = (free_addr(), free_addr());
+        let process = Command::new(env!("CARGO_BIN_EXE_snark-coordinator-rs"))
+            .args(["--listen", &addr.to_string()])
+            .args(["--grpc-listen", &grpc_addr.to_string()])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .kill_on_drop(true)
+            .spawn()
+            .unwrap();
+        let coordinator = Self {
+            _process: process,
+            url: format!("http://{addr}"),
+            grpc_addr,
+            client: reqwest::Client::new(),
+        };
+        let deadline = Instant::now() + DEADLINE;
+        while coordinator.get("/healthz").await.is_err() {
+            assert!(Instant::now() < deadline, "coordinator didn't start");
+            tokio::time::sleep(Duration::from_millis(50)).await;
+        }
+        coordinator
+    }
+
+    async fn get(&self, path: &str) -> reqwest::Result<reqwest::Response> {
+        self.client.get(format!("{}{path}", self.url)).send().await
+    }
+
+    async fn grpc(&self) -> CoordinatorClient<tonic::transport::Channel> {
+        let deadline = Instant::now() + DEADLINE;
+        loop {
+            match CoordinatorClient::connect(format!("http://{}", self.grpc_addr)).await {
+                Ok(client) => return client,
+                Err(err) if Instant::now() >= deadline => panic!("gRPC didn't start: {err}"),
+                Err(_) => tokio::time::sleep(Duration::from_millis(50)).await,
+            }
+        }
+    }
+
+    async fn graphql(&self, query: &str) -> Value {
+        let res = (self.client.post(format!("{}/graphql", self.url)))
+            .json(&json!({ "query": query }))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), 200);
+        let res = res.json::<Value>().await.unwrap();
+        assert!(res.get("errors").is_none(), "{res}");
+        res["data"].clone()
+    }
+
+    /// Waits for the GraphQL `query` to answer with what `done` accepts.
+    async fn graphql_until(&self, query: &str, done: impl Fn(&Value) -> bool) -> Value {
+        let deadline = Instant::now() + DEADLINE;
+        loop {
+            let data = self.graphql(query).await;
+            if done(&data) {
+                return data;
+            }
+            assert!(Instant::now() < deadline, "gave up waiting on {data}");
+            tokio::time::sleep(Duration::from_millis(20)).await;
+        }
+    }
+}
+
+fn event(worker_id: &str, event: Value) -> PutWorkerStatsRequest {
+    PutWorkerStatsRequest {
+        worker_id: worker_id.to_owned(),
+        event_json: event.to_string(),
+        ..Default::default()
+    }
+}
+
+fn lock_job(key: &str, worker_id: &str) -> LockJobRequest {
+    LockJobRequest {
+        key: key.to_owned(),
+        timeout_ms: Some(60_000),
+        worker_id: Some(worker_id.to_owned()),
+        ..Default::default()
+    }
+}
+
+#[tokio::test]
+async fn grpc_calls_are_served_by_the_routes() {
+    let coordinator = Coordinator::start().await;
+    let mut grpc = coordinator.grpc().await;
+    let t = now_ms();
+
+    let register = json!({ "kind": "Register", "time": t });
+    let res = grpc
+        .put_worker_stats(futures_util::stream::iter([event("w1", register)]))
+        .await
+        .unwrap()
+        .into_inner();
+    assert_eq!((res.applied, res.errors.len()), (1, 0));
+    let session = res.session_id.unwrap();
+
+    let mut transitions = grpc
+        .watch_stats(WatchStatsRequest {
+            workers: vec![session.clone()],
+        })
+        .await
+        .unwrap()
+        .into_inner();
+    let events = [
+        event(&session, json!({ "kind": "JobGetInit", "time": t + 1 })),
+        // nothing was got to create work for.
+        event(
+            &session,
+            json!({ "kind": "WorkCreateSuccess", "time": t + 2, "ids": "j1" }),
+        ),
+        PutWorkerStatsRequest {
+            worker_id: session.clone(),
+            event_json: "{".to_owned(),
+            ..Default::default()
+        },
+    ];
+    let res = grpc
+        .put_worker_stats(futures_util::stream::iter(events))
+        .await
+        .unwrap()
+        .into_inner();
+    assert!(res.session_id.is_none());
+    // events are only checked for transitions once applied, but
+    // malformed ones are refused upfront.
+    let refused = res.errors.iter().map(|e| e.index).collect::<Vec<_>>();
+    assert_eq!((res.applied, refused), (2, vec![2]));
+    assert_eq!(res.errors[0].code, "INVALID_BODY");
+
+    let transition = transitions.message().await.unwrap().unwrap();
+    assert_eq!(transition.worker_id, session);
+    assert_eq!(transition.new_kind, "JobGetPending");
+    let state = serde_json::from_str::<Value>(&transition.state_json).unwrap();
+    assert_eq!(state["job_get_init_t"], t + 1);
+
+    let granted = grpc.lock_job(lock_job("j1", &session)).await.unwrap();
+    let Some(lock_job_response::Result::Granted(granted)) = granted.into_inner().result else {
+        panic!("j1 wasn't locked");
+    };
+    let held = grpc.lock_job(lock_job("j1", "w2")).await.unwrap();
+    let Some(lock_job_response::Result::Held(held)) = held.into_inner().result else {
+        panic!("j1 was locked twice");
+    };
+    assert_eq!(held.holder.as_deref(), Some(&session[..]));
+
+    let release = |fencing_token| ReleaseJobRequest {
+        key: "j1".to_owned(),
+        fencing_token: Some(fencing_token),
+        ..Default::default()
+    };
+    let stale = grpc.release_job(release(granted.fencing_token + 1)).await;
+    assert_eq!(stale.unwrap_err().code(), tonic::Code::FailedPrecondition);
+    grpc.release_job(release(granted.fencing_token))
+        .await
+        .unwrap();
+    let gone = grpc.release_job(release(granted.fencing_token)).await;
+    assert_eq!(gone.unwrap_err().code(), tonic::Code::NotFound);
+
+    // keys which would change the route are refused.
+    let err = grpc.lock_job(lock_job("j1/validate", &session)).await;
+    assert_eq!(err.unwrap_err().code(), tonic::Code::InvalidArgument);
+}
+
+#[tokio::test]
+async fn graphql_reads_what_workers_reported() {
+    let coordinator = Coordinator::start().await;
+    let mut grpc = coordinator.grpc().await;
+    let t = now_ms();
+
+    let register = json!({
+        "kind": "Register",
+        "time": t,
+        "metadata": { "hostname": "prover-1" },
+    });
+    let res = grpc
+        .put_worker_stats(futures_util::stream::iter([event("w1", register)]))
+        .await
+        .unwrap()
+        .into_inner();
+    let session = res.session_id.unwrap();
+    let events = [
+        event(&session, json!({ "kind": "JobGetInit", "time": t + 1 })),
+        event(
+            &session,
+            json!({ "kind": "JobGetSuccess", "time": t + 2, "ids": "j1" }),
+        ),
+    ];
+    grpc.put_worker_stats(futures_util::stream::iter(events))
+        .await
+        .unwrap();
+    grpc.lock_job(lock_job("j1", &session)).await.unwrap();
+
+    let query = r#"{
+        workers(name: "w1") {
+            id
+            name
+            metadata { hostname }
+            latest { kind ids }
+            states { kind startT endT }
+            locks { key holder }
+        }
+        locks { key fencingToken }
+    }"#;
+    let data = coordinator
+        .graphql_until(query, |data| {
+            data["workers"][0]["latest"]["kind"] == "WorkCreatePending"
+        })
+        .await;
+    let worker = &data["workers"][0];
+    assert_eq!(worker["id"], session);
+    assert_eq!(worker["name"], "w1");
+    assert_eq!(worker["metadata"]["hostname"], "prover-1");
+    assert_eq!(worker["latest"]["ids"], "j1");
+    assert_eq!(
+        worker["states"],
+        json!([
+            { "kind": "WorkCreatePending", "startT": t + 1, "endT": t + 2 },
+            { "kind": "Registered", "startT": t, "endT": t },
+        ])
+    );
+    assert_eq!(worker["locks"], json!([{ "key": "j1", "holder": session }]));
+    assert_eq!(data["locks"][0]["key"], "j1");
+
+    // the same query over GET, and errors in the GraphQL format.
+    let res = coordinator
+        .get(&format!(
+            "/graphql?query={}",
+            "%7B%20workers%20%7B%20id%20%7D%20%7D"
+        ))
+        .await
+        .unwrap();
+    let res = res.json::<Value>().await.unwrap();
+    assert_eq!(res["data"]["workers"], json!([{ "id": session }]));
+    let nobody = coordinator
+        .graphql(r#"{ worker(id: "nobody") { id } }"#)
+        .await;
+    assert_eq!(nobody["worker"], Value::Null);
+    let res = (coordinator
+        .client
+        .post(format!("{}/graphql", coordinator.url)))
+    .json(&json!({ "query": "{ nothing }" }))
+    .send()
+    .await
+    .unwrap();
+    let res = res.json::<Value>().await.unwrap();
+    assert!(res["errors"][0]["message"].is_string());
+}
+
+/// Asserts `status` is one of the responses the OpenAPI document
+/// describes for `method` on `path`, exactly or by its class, e.g. `4XX`.
+fn assert_documented(spec: &Value, method: &str, path: &str, status: reqwest::StatusCode) {
+    let responses = &spec["paths"][path][method]["responses"];
+    let responses = responses
+        .as_object()
+        .unwrap_or_else(|| panic!("{method} {path} isn't documented"));
+    let class = format!("{}XX", status.as_u16() / 100);
+    assert!(
+        responses.contains_key(status.as_str()) || responses.contains_key(&class),
+        "{method} {path} answered {status}, documented are {:?}",
+        responses.keys().collect::<Vec<_>>(),
+    );
+}
+
+#[tokio::test]
+async fn workers_following_the_openapi_document_get_documented_responses() {
+    let coordinator = Coordinator::start().await;
+    let spec = coordinator.get("/openapi.json").await.unwrap();
+    assert_eq!(spec.headers()["content-type"], "application/json");
+    let spec = spec.json::<Value>().await.unwrap();
+    let prefix = spec["servers"][0]["url"].as_str().unwrap().to_owned();
+    let client = &coordinator.client;
+    let url = |path: &str| format!("{}{prefix}{path}", coordinator.url);
+    let t = now_ms();
+
+    let handshake = json!({ "worker_id": "w1", "time": t });
+    let res = client.post(url("/handshake")).json(&handshake).send().await;
+    let res = res.unwrap();
+    assert_documented(&spec, "post", "/handshake", res.status());
+    assert!(res.json::<HandshakeResponse>().await.unwrap().accepted);
+
+    let register = json!({ "kind": "Register", "time": t });
+    let res = client
+        .put(url("/worker-stats/w1"))
+        .json(&register)
+        .send()
+        .await;
+    let res = res.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_documented(&spec, "put", "/worker-stats/{worker_id}", res.status());
+    let session = res.text().await.unwrap();
+
+    let lock = |key: &str, worker_id: &str| {
+        let query = [("worker_id", worker_id), ("timeout_ms", "60000")];
+        client.put(url(&format!("/lock-job/{key}"))).query(&query)
+    };
+    let res = lock("j1", &session).send().await.unwrap();
+    assert_eq!(res.status(), 201);
+    assert_documented(&spec, "put", "/lock-job/{key}", res.status());
+    let granted = res.json::<LockJobGranted>().await.unwrap();
+    let res = lock("j1", "w2").send().await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_documented(&spec, "put", "/lock-job/{key}", res.status());
+    let held = res.json::<LockJobHeld>().await.unwrap();
+    assert_eq!(held.holder.as_deref(), Some(&session[..]));
+    let res = lock(&"j".repeat(1000), "w2").send().await.unwrap();
+    assert!(res.status().is_client_error());
+    assert_documented(&spec, "put", "/lock-job/{key}", res.status());
+    res.json::<ApiError>().await.unwrap();
+
+    for (token, status) in [
+        (granted.fencing_token, 200),
+        (granted.fencing_token + 1, 409),
+    ] {
+        let res = client
+            .get(url("/lock-job/j1/validate"))
+            .query(&[("fencing_token", token)])
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(res.status(), status);
+        assert_documented(&spec, "get", "/lock-job/{key}/validate", res.status());
+    }
+
+    let init = json!({ "kind": "JobGetInit", "time": t + 1 });
+    let res = client.put(url(&format!("/worker-stats/{session}")));
+    let res = res.json(&init).send().await.unwrap();
+    assert_eq!(res.status(), 202);
+    assert_documented(&spec, "put", "/worker-stats/{worker_id}", res.status());
+    assert!(res.headers().contains_key("x-stats-version"));
+
+    let batch = json!([
+        { "kind": "JobGetSuccess", "time": t + 2, "ids": "j1" },
+        { "kind": "WorkCreateSuccess", "time": t + 3, "ids": "j1" },
+    ]);
+    let res = client.put(url(&format!("/worker-stats/{session}/batch")));
+    let res = res.json(&batch).send().await.unwrap();
+    assert_documented(
+        &spec,
+        "put",
+        "/worker-stats/{worker_id}/batch",
+        res.status(),
+    );
+    let res = res.json::<BatchResponse<Value>>().await.unwrap();
+    assert_eq!((res.summary.succeeded, res.summary.failed), (2, 0));
+
+    // events are applied after they're acknowledged.
+    let states = |query: &'static [(&'static str, &'static str)]| {
+        let path = format!("/worker-stats/{session}");
+        client.get(url(&path)).query(query).send()
+    };
+    let deadline = Instant::now() + DEADLINE;
+    loop {
+        let res = states(&[]).await.unwrap();
+        assert_documented(&spec, "get", "/worker-stats/{worker_id}", res.status());
+        let states = res.json::<Vec<SnarkWorkerState>>().await.unwrap();
+        if states[0].kind() == "WorkSubmitPending" {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "events weren't applied: {states:?}"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+    let res = states(&[("kinds", "Bogus")]).await.unwrap();
+    assert_eq!(res.status(), 400);
+    assert_documented(&spec, "get", "/worker-stats/{worker_id}", res.status());
+    let res = client
+        .get(url("/worker-stats/nobody"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.status(), 404);
+    assert_documented(&spec, "get", "/worker-stats/{worker_id}", res.status());
+
+    let lock_jobs = |keys: Value| client.put(url("/lock-jobs")).json(&keys).send();
+    let res = lock_jobs(json!(["j2", "j3"])).await.unwrap();
+    assert_eq!(res.status(), 201);
+    assert_documented(&spec, "put", "/lock-jobs", res.status());
+    res.json::<LockJobGranted>().await.unwrap();
+    let res = lock_jobs(json!(["j1", "j4"])).await.unwrap();
+    assert_eq!(res.status(), 200);
+    assert_documented(&spec, "put", "/lock-jobs", res.status());
+    res.json::<LockJobsConflict>().await.unwrap();
+    let res = lock_jobs(json!([])).await.unwrap();
+    assert_documented(&spec, "put", "/lock-jobs", res.status());
+
+    let release = |token: u64| {
+        let query = [("fencing_token", token)];
+        client.delete(url("/lock-job/j1")).query(&query).send()
+    };
+    for status in [200, 404] {
+        let res = release(granted.fencing_token).await.unwrap();
+        assert_eq!(res.status(), status);
+        assert_documented(&spec, "delete", "/lock-job/{key}", res.status());
+    }
+
+    // job distribution isn't enabled without a node.
+    let res = client.get(url("/job")).send().await.unwrap();
+    assert_eq!(res.status(), 404);
+    assert_documented(&spec, "get", "/job", res.status());
+}
+
+/// Decodes a body compressed with `encoding`.
+fn decompress(encoding: &str, body: &[u8]) -> Vec<u8> {
+    let mut decoded = vec![];
+    match encoding {
+        "gzip" => flate2::read::GzDecoder::new(body).read_to_end(&mut decoded),
+        "deflate" => flate2::read::ZlibDecoder::new(body).read_to_end(&mut decoded),
+        other => panic!("unexpected encoding {other}"),
+    }
+    .unwrap();
+    decoded
+}
+
+#[tokio::test]
+async fn worker_stats_are_compressed_as_negotiated() {
+    let coordinator = Coordinator::start().await;
+    let client = &coordinator.client;
+    let url = |path: &str| format!("{}{path}", coordinator.url);
+    let t = now_ms();
+
+    let register = json!({ "kind": "Register", "time": t });
+    let res = client
+        .put(url("/worker-stats/w1"))
+        .json(&register)
+        .send()
+        .await;
+    let session = res.unwrap().text().await.unwrap();
+    // enough lifecycles for the states to be worth compressing.
+    let events = (0..40u64)
+        .flat_map(|i| {
+            let (t, ids) = (t + i * 4, format!("job-{i}"));
+            [
+                json!({ "kind": "JobGetInit", "time": t + 1 }),
+                json!({ "kind": "JobGetSuccess", "time": t + 2, "ids": ids }),
+                json!({ "kind": "WorkCreateSuccess", "time": t + 3, "ids": ids }),
+                json!({ "kind": "WorkSubmitSuccess", "time": t + 4, "ids": ids }),
+            ]
+        })
+        .collect::<Vec<_>>();
+    let batch = client.put(url(&format!("/worker-stats/{session}/batch")));
+    batch.json(&events).send().await.unwrap();
+    let deadline = Instant::now() + DEADLINE;
+    loop {
+        let res = client.get(url(&format!("/worker-stats/{session}"))).send();
+        let states = res.await.unwrap().json::<Vec<Value>>().await.unwrap();
+        if states.len() == 41 {
+            break;
+        }
+        assert!(
+            Instant::now() < deadline,
+            "events weren't applied: {states:?}"
+        );
+        tokio::time::sleep(Duration::from_millis(20)).await;
+    }
+
+    let get = |path: &str, accept_encoding: &str| {
+        let req = client
+            .get(url(path))
+            .header("accept-encoding", accept_encoding);
+        async move {
+            let res = req.send().await.unwrap();
+            assert_eq!(res.status(), 200);
+            let encoding = res.headers().get("content-encoding").cloned();
+            let vary = res.headers().get_all("vary").iter();
+            let vary = vary
+                .map(|v| v.to_str().unwrap().to_owned())
+                .collect::<Vec<_>>();
+            let body = res.bytes().await.unwrap().to_vec();
+            (encoding.map(|e| e.to_str().unwrap().to_owned()), vary, body)
+        }
+    };
+    // streamed, and buffered when paginated.
+    for (path, accept_encoding, encoding) in [
+        ("/worker-stats", "gzip", "gzip"),
+        ("/worker-stats", "deflate, gzip;q=0.5", "deflate"),
+        ("/worker-stats?limit=100", "br, gzip", "gzip"),
+        ("/worker-stats?limit=100", "deflate", "deflate"),
+    ] {
+        let (got, vary, body) = get(path, accept_encoding).await;
+        assert_eq!(got.as_deref(), Some(encoding), "{path} {accept_encoding}");
+        assert!(
+            vary.iter().any(|v| v.contains("accept-encoding")),
+            "{vary:?}"
+        );
+        let body = serde_json::from_slice::<Value>(&decompress(encoding, &body)).unwrap();
+        assert!(body.to_string().contains("job-39"), "{path}: {body}");
+    }
+
+    // refused encodings, and small responses, are sent as they are.
+    let (encoding, _, body) = get("/worker-stats", "gzip;q=0, identity").await;
+    assert_eq!(encoding, None);
+    serde_json::from_slice::<Value>(&body).unwrap();
+    let (encoding, _, _) = get(&format!("/worker-stats/{session}?limit=1"), "gzip").await;
+    assert_eq!(encoding, None);
+
+    // a negotiated format is compressed too.
+    let res = client
+        .get(url("/worker-stats"))
+        .header("accept", "application/msgpack")
+        .header("accept-encoding", "gzip")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(res.headers()["content-type"], "application/msgpack");
+    assert_eq!(res.headers()["content-encoding"], "gzip");
+    let body = decompress("gzip", &res.bytes().await.unwrap());
+    let stats = rmp_serde::from_slice::<Value>(&body).unwrap();
+    assert_eq!(stats[&session].as_array().unwrap().len(), 41);
+}
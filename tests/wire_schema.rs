@@ -0,0 +1,522 @@
+//! Round-trips of the worker-stats wire schema, guarding it against
+//! accidental breaking changes: whatever the coordinator serializes has
+//! to deserialize back to the same thing, and events as workers send them
+//! have to keep parsing.
+//!
+//! Run with `cargo test --test wire_schema`.
+
+use proptest::{option, prelude::*};
+use serde::{de::DeserializeOwned, Serialize};
+use serde_json::Value;
+use snark_coordinator_rs::{
+    handshake::HandshakeRequest,
+    stats::{Lease, SnarkWorkerJobGetError, SnarkWorkerState, SnarkWorkerStatsPut, WorkerMetadata},
+};
+
+/// `kind`s of all stats events.
+const PUT_KINDS: &[&str] = &[
+    "Register",
+    "JobGetInit",
+    "JobGetError",
+    "JobGetSuccess",
+    "WorkCreateError",
+    "WorkCreateSuccess",
+    "WorkSubmitError",
+    "WorkSubmitSuccess",
+];
+
+/// Smallest timestamp taken as milliseconds, see `timestamp::normalize`.
+const MIN_MS: u64 = 100_000_000_000;
+
+/// Timestamp in milliseconds, which deserializes unchanged.
+fn time() -> impl Strategy<Value = u64> {
+    MIN_MS..=u64::MAX
+}
+
+fn opt_time() -> impl Strategy<Value = Option<u64>> {
+    option::of(time())
+}
+
+fn seq() -> impl Strategy<Value = Option<u64>> {
+    option::of(any::<u64>())
+}
+
+fn ids() -> impl Strategy<Value = String> {
+    prop_oneof![any::<String>(), "[0-9]{1,6}(,[0-9]{1,6}){0,1}"]
+}
+
+fn metadata() -> impl Strategy<Value = WorkerMetadata> {
+    (
+        option::of(any::<String>()),
+        option::of(any::<String>()),
+        option::of("[0-9]{1,2}\\.[0-9]{1,2}\\.[0-9]{1,2}"),
+        option::of(any::<u32>()),
+        option::of(any::<u64>()),
+        option::of(any::<bool>()),
+    )
+        .prop_map(
+            |(hostname, datacenter, prover_version, threads, fee, gpu)| WorkerMetadata {
+                hostname,
+                datacenter,
+                prover_version,
+                threads,
+                fee,
+                gpu,
+            },
+        )
+}
+
+fn lease() -> impl Strategy<Value = Lease> {
+    (
+        any::<u64>(),
+        option::of(any::<String>()),
+        any::<u64>(),
+        option::of(any::<String>()),
+    )
+        .prop_map(|(fencing_token, holder, expires_t, key)| Lease {
+            fencing_token,
+            holder,
+            expires_t,
+            key,
+        })
+}
+
+fn job_get_error() -> impl Strategy<Value = SnarkWorkerJobGetError> {
+    prop_oneof![
+        Just(SnarkWorkerJobGetError::NoAvailableJob),
+        any::<String>().prop_map(|error| SnarkWorkerJobGetError::Other { error }),
+    ]
+}
+
+fn stats_put() -> impl Strategy<Value = SnarkWorkerStatsPut> {
+    prop_oneof![
+        (
+            time(),
+            option::of(metadata()),
+            option::of(any::<String>()),
+            seq()
+        )
+            .prop_map(
+                |(time, metadata, resume, seq)| SnarkWorkerStatsPut::Register {
+                    time,
+                    metadata,
+                    resume,
+                    seq,
+                }
+            ),
+        (time(), seq()).prop_map(|(time, seq)| SnarkWorkerStatsPut::JobGetInit { time, seq }),
+        (
+            time(),
+            opt_time(),
+            opt_time(),
+            opt_time(),
+            job_get_error(),
+            seq()
+        )
+            .prop_map(|(time, received_t, init_t, success_t, error, seq)| {
+                SnarkWorkerStatsPut::JobGetError {
+                    time,
+                    job_get_node_received_t: received_t,
+                    job_get_node_request_work_init_t: init_t,
+                    job_get_node_request_work_success_t: success_t,
+                    error,
+                    seq,
+                }
+            }),
+        (time(), opt_time(), opt_time(), opt_time(), ids(), seq()).prop_map(
+            |(time, received_t, init_t, success_t, ids, seq)| SnarkWorkerStatsPut::JobGetSuccess {
+                time,
+                job_get_node_received_t: received_t,
+                job_get_node_request_work_init_t: init_t,
+                job_get_node_request_work_success_t: success_t,
+                ids,
+                seq,
+            }
+        ),
+        (time(), ids(), any::<String>(), seq()).prop_map(|(time, ids, error, seq)| {
+            SnarkWorkerStatsPut::WorkCreateError {
+                time,
+                ids,
+                error,
+                seq,
+            }
+        }),
+        (time(), ids(), seq()).prop_map(|(time, ids, seq)| {
+            SnarkWorkerStatsPut::WorkCreateSuccess { time, ids, seq }
+        }),
+        (
+            time(),
+            opt_time(),
+            opt_time(),
+            opt_time(),
+            ids(),
+            any::<String>(),
+            seq()
+        )
+            .prop_map(|(time, received_t, init_t, success_t, ids, error, seq)| {
+                SnarkWorkerStatsPut::WorkSubmitError {
+                    time,
+                    work_submit_node_received_t: received_t,
+                    work_submit_node_add_work_init_t: init_t,
+                    work_submit_node_add_work_success_t: success_t,
+                    ids,
+                    error,
+                    seq,
+                }
+            }),
+        (time(), opt_time(), opt_time(), opt_time(), ids(), seq()).prop_map(
+            |(time, received_t, init_t, success_t, ids, seq)| {
+                SnarkWorkerStatsPut::WorkSubmitSuccess {
+                    time,
+                    work_submit_node_received_t: received_t,
+                    work_submit_node_add_work_init_t: init_t,
+                    work_submit_node_add_work_success_t: success_t,
+                    ids,
+                    seq,
+                }
+            }
+        ),
+    ]
+}
+
+/// Timestamps shared by the states of a lifecycle which got a job.
+#[derive(Debug, Clone)]
+struct JobGot {
+    init_t: u64,
+    received_t: Option<u64>,
+    request_init_t: Option<u64>,
+    request_success_t: Option<u64>,
+    success_t: u64,
+}
+
+fn job_got() -> impl Strategy<Value = JobGot> {
+    (
+        any::<u64>(),
+        option::of(any::<u64>()),
+        option::of(any::<u64>()),
+        option::of(any::<u64>()),
+        any::<u64>(),
+    )
+        .prop_map(
+            |(init_t, received_t, request_init_t, request_success_t, success_t)| JobGot {
+                init_t,
+                received_t,
+                request_init_t,
+                request_success_t,
+                success_t,
+            },
+        )
+}
+
+fn state() -> impl Strategy<Value = SnarkWorkerState> {
+    let collapsed = || (option::of(any::<u64>()), option::of(any::<u64>()));
+    prop_oneof![
+        (any::<u64>(), option::of(metadata())).prop_map(|(registered_t, metadata)| {
+            SnarkWorkerState::Registered {
+                registered_t,
+                metadata,
+            }
+        }),
+        (any::<u64>(), option::of(metadata())).prop_map(|(restarted_t, metadata)| {
+            SnarkWorkerState::Restarted {
+                restarted_t,
+                metadata,
+            }
+        }),
+        any::<u64>().prop_map(|job_get_init_t| SnarkWorkerState::JobGetPending { job_get_init_t }),
+        job_got().prop_map(|j| SnarkWorkerState::JobUnavailable {
+            job_get_init_t: j.init_t,
+            job_get_node_received_t: j.received_t,
+            job_get_node_request_work_init_t: j.request_init_t,
+            job_get_node_request_work_success_t: j.request_success_t,
+            job_get_success_t: j.success_t,
+        }),
+        (job_got(), job_get_error(), collapsed()).prop_map(|(j, error, (count, last_seen_t))| {
+            SnarkWorkerState::JobGetError {
+                job_get_init_t: j.init_t,
+                job_get_node_received_t: j.received_t,
+                job_get_node_request_work_init_t: j.request_init_t,
+                job_get_node_request_work_success_t: j.request_success_t,
+                job_get_error_t: j.success_t,
+                error,
+                count,
+                last_seen_t,
+            }
+        }),
+        (job_got(), ids(), option::of(lease())).prop_map(|(j, ids, lease)| {
+            SnarkWorkerState::WorkCreatePending {
+                job_get_init_t: j.init_t,
+                job_get_node_received_t: j.received_t,
+                job_get_node_request_work_init_t: j.request_init_t,
+                job_get_node_request_work_success_t: j.request_success_t,
+                job_get_success_t: j.success_t,
+                ids,
+                lease,
+            }
+        }),
+        (
+            job_got(),
+            any::<u64>(),
+            ids(),
+            option::of(lease()),
+            any::<String>(),
+            collapsed()
+        )
+            .prop_map(|(j, error_t, ids, lease, error, (count, last_seen_t))| {
+                SnarkWorkerState::WorkCreateError {
+                    job_get_init_t: j.init_t,
+                    job_get_node_received_t: j.received_t,
+                    job_get_node_request_work_init_t: j.request_init_t,
+                    job_get_node_request_work_success_t: j.request_success_t,
+                    job_get_success_t: j.success_t,
+                    work_create_error_t: error_t,
+                    ids,
+                    lease,
+                    error,
+                    count,
+                    last_seen_t,
+                }
+            }),
+        (job_got(), any::<u64>(), ids(), option::of(lease())).prop_map(
+            |(j, created_t, ids, lease)| SnarkWorkerState::WorkSubmitPending {
+                job_get_init_t: j.init_t,
+                job_get_node_received_t: j.received_t,
+                job_get_node_request_work_init_t: j.request_init_t,
+                job_get_node_request_work_success_t: j.request_success_t,
+                job_get_success_t: j.success_t,
+                work_create_success_t: created_t,
+                ids,
+                lease,
+            }
+        ),
+        (
+            job_got(),
+            (any::<u64>(), any::<u64>()),
+            ids(),
+            option::of(lease()),
+            any::<String>(),
+            collapsed()
+        )
+            .prop_map(
+                |(j, (created_t, error_t), ids, lease, error, (count, last_seen_t))| {
+                    SnarkWorkerState::WorkSubmitError {
+                        job_get_init_t: j.init_t,
+                        job_get_node_received_t: j.received_t,
+                        job_get_node_request_work_init_t: j.request_init_t,
+                        job_get_node_request_work_success_t: j.request_success_t,
+                        job_get_success_t: j.success_t,
+                        work_create_success_t: created_t,
+                        work_submit_error_t: error_t,
+                        ids,
+                        lease,
+                        error,
+                        count,
+                        last_seen_t,
+                    }
+                }
+            ),
+        (any::<u64>(), any::<u64>()).prop_map(|(job_get_init_t, timed_out_t)| {
+            SnarkWorkerState::JobGetTimeout {
+                job_get_init_t,
+                timed_out_t,
+            }
+        }),
+        (job_got(), any::<u64>(), ids(), option::of(lease())).prop_map(
+            |(j, timed_out_t, ids, lease)| SnarkWorkerState::WorkCreateTimeout {
+                job_get_init_t: j.init_t,
+                job_get_node_received_t: j.received_t,
+                job_get_node_request_work_init_t: j.request_init_t,
+                job_get_node_request_work_success_t: j.request_success_t,
+                job_get_success_t: j.success_t,
+                timed_out_t,
+                ids,
+                lease,
+            }
+        ),
+        (
+            job_got(),
+            (any::<u64>(), any::<u64>()),
+            ids(),
+            option::of(lease())
+        )
+            .prop_map(|(j, (created_t, timed_out_t), ids, lease)| {
+                SnarkWorkerState::WorkSubmitTimeout {
+                    job_get_init_t: j.init_t,
+                    job_get_node_received_t: j.received_t,
+                    job_get_node_request_work_init_t: j.request_init_t,
+                    job_get_node_request_work_success_t: j.request_success_t,
+                    job_get_success_t: j.success_t,
+                    work_create_success_t: created_t,
+                    timed_out_t,
+                    ids,
+                    lease,
+                }
+            }),
+        (
+            job_got(),
+            (any::<u64>(), any::<u64>()),
+            (
+                option::of(any::<u64>()),
+                option::of(any::<u64>()),
+                option::of(any::<u64>())
+            ),
+            ids(),
+            option::of(lease())
+        )
+            .prop_map(
+                |(j, (created_t, submitted_t), (received_t, init_t, success_t), ids, lease)| {
+                    SnarkWorkerState::WorkSubmitSuccess {
+                        job_get_init_t: j.init_t,
+                        job_get_node_received_t: j.received_t,
+                        job_get_node_request_work_init_t: j.request_init_t,
+                        job_get_node_request_work_success_t: j.request_success_t,
+                        job_get_success_t: j.success_t,
+                        work_create_success_t: created_t,
+                        work_submit_node_received_t: received_t,
+                        work_submit_node_add_work_init_t: init_t,
+                        work_submit_node_add_work_success_t: success_t,
+                        work_submit_success_t: submitted_t,
+                        ids,
+                        lease,
+                    }
+                }
+            ),
+    ]
+}
+
+/// Serializes `value`, deserializes it back and checks that serializes
+/// the same. Returns the JSON.
+fn round_trip<T: Serialize + DeserializeOwned>(value: &T) -> Result<Value, TestCaseError> {
+    let json = serde_json::to_value(value).map_err(|err| TestCaseError::fail(err.to_string()))?;
+    let text = serde_json::to_string(&json).unwrap();
+    let parsed = serde_json::from_str::<T>(&text)
+        .map_err(|err| TestCaseError::fail(format!("{err}, in {text}")))?;
+    prop_assert_eq!(serde_json::to_value(&parsed).unwrap(), json.clone());
+    Ok(json)
+}
+
+/// Removes the fields which hold `null`, like a worker leaving optional
+/// fields out.
+fn strip_nulls(value: &mut Value) {
+    if let Value::Object(fields) = value {
+        fields.retain(|_, v| !v.is_null());
+        fields.values_mut().for_each(strip_nulls);
+    }
+}
+
+proptest! {
+    #[test]
+    fn stats_put_round_trips(put in stats_put()) {
+        let json = round_trip(&put)?;
+        prop_assert_eq!(json["kind"].as_str(), Some(put.kind()));
+    }
+
+    #[test]
+    fn stats_put_parses_without_optional_fields(put in stats_put()) {
+        let mut json = serde_json::to_value(&put).unwrap();
+        strip_nulls(&mut json);
+        let parsed = serde_json::from_value::<SnarkWorkerStatsPut>(json.clone());
+        prop_assert!(parsed.is_ok(), "{:?} in {}", parsed.err(), json);
+    }
+
+    #[test]
+    fn stats_put_normalizes_seconds(put in stats_put(), secs in 0..MIN_MS) {
+        let mut json = serde_json::to_value(&put).unwrap();
+        json["time"] = secs.into();
+        let parsed = serde_json::from_value::<SnarkWorkerStatsPut>(json).unwrap();
+        let time = serde_json::to_value(&parsed).unwrap()["time"].as_u64();
+        prop_assert_eq!(time, Some(secs * 1000));
+    }
+
+    #[test]
+    fn stats_put_transcodes_from_msgpack_and_cbor(put in stats_put()) {
+        // the coordinator turns these bodies into JSON, see `formats.rs`.
+        let json = serde_json::to_value(&put).unwrap();
+        let msgpack = rmp_serde::to_vec_named(&put).unwrap();
+        prop_assert_eq!(rmp_serde::from_slice::<Value>(&msgpack).unwrap(), json.clone());
+        let mut cbor = vec![];
+        ciborium::into_writer(&put, &mut cbor).unwrap();
+        prop_assert_eq!(ciborium::from_reader::<Value, _>(&cbor[..]).unwrap(), json);
+    }
+
+    #[test]
+    fn state_round_trips(state in state()) {
+        let json = round_trip(&state)?;
+        prop_assert_eq!(json["kind"].as_str(), Some(state.kind()));
+    }
+
+    #[test]
+    fn state_history_round_trips(states in prop::collection::vec(state(), 0..16)) {
+        round_trip(&states)?;
+    }
+
+    #[test]
+    fn handshake_request_round_trips(
+        worker_id in any::<String>(),
+        time in time(),
+        schema_version in any::<u32>(),
+        metadata in option::of(metadata()),
+    ) {
+        round_trip(&HandshakeRequest { worker_id, time, schema_version, metadata })?;
+    }
+
+    #[test]
+    fn arbitrary_json_parses_or_errs(json in any_json()) {
+        let text = json.to_string();
+        let _ = serde_json::from_str::<SnarkWorkerStatsPut>(&text);
+        let _ = serde_json::from_str::<Vec<SnarkWorkerStatsPut>>(&text);
+        let _ = serde_json::from_str::<HandshakeRequest>(&text);
+    }
+}
+
+/// JSON values, with objects shaped like stats events half of the time so
+/// parsing gets past the `kind` tag.
+fn any_json() -> impl Strategy<Value = Value> {
+    let leaf = prop_oneof![
+        Just(Value::Null),
+        any::<bool>().prop_map(Value::from),
+        any::<u64>().prop_map(Value::from),
+        any::<i64>().prop_map(Value::from),
+        any::<f64>().prop_map(Value::from),
+        any::<String>().prop_map(Value::from),
+    ];
+    let kinds = prop::sample::select(PUT_KINDS);
+    leaf.prop_recursive(4, 32, 8, move |inner| {
+        let fields = prop::collection::btree_map(field_name(), inner.clone(), 0..8);
+        prop_oneof![
+            prop::collection::vec(inner, 0..8).prop_map(Value::from),
+            fields
+                .clone()
+                .prop_map(|f| Value::Object(f.into_iter().collect())),
+            (kinds.clone(), fields).prop_map(|(kind, f)| {
+                let mut f = f.into_iter().collect::<serde_json::Map<_, _>>();
+                f.insert("kind".to_owned(), kind.into());
+                Value::Object(f)
+            }),
+        ]
+    })
+}
+
+/// Field names of the schema, or anything else.
+fn field_name() -> BoxedStrategy<String> {
+    prop_oneof![
+        prop::sample::select(
+            &[
+                "time",
+                "ids",
+                "error",
+                "metadata",
+                "resume",
+                "job_get_node_received_t",
+                "job_get_node_request_work_init_t",
+                "job_get_node_request_work_success_t",
+                "work_submit_node_received_t",
+                "work_submit_node_add_work_init_t",
+                "work_submit_node_add_work_success_t",
+            ][..]
+        )
+        .prop_map(str::to_owned),
+        any::<String>(),
+    ]
+    .boxed()
+}